@@ -0,0 +1,631 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Copyright (c) 2011 The LevelDB Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file. See the AUTHORS file for names of contributors.
+
+//! A benchmark tool like `db_bench` in LevelDB.
+//!
+//! Usage: `cargo run --release --example db_bench -- [--flag=value]...`
+//!
+//! Benchmarks (`--benchmarks`, comma separated and run in order):
+//!   * fillseq    -- write `num` values in sequential key order
+//!   * fillrandom -- write `num` values in random key order
+//!   * overwrite  -- overwrite `num` values in random key order
+//!   * readrandom -- read `reads` times in random key order
+//!   * mixed      -- read or write `num` times, a read happens in the ratio of `read_ratio`
+//!   * stats      -- print the `wickdb.stats` property of the db
+//!
+//! Random keys follow `key_distribution` (uniform or zipfian) and values
+//! sizes follow `value_size_distribution` (fixed, uniform or normal).
+//! With `qps` set, every thread issues operations at a fixed pace and the
+//! latencies are measured from the scheduled time instead of the actual time
+//! so the delay of the operations behind the schedule is not hidden.
+//!
+//! Other flags (see `Flags::default` for the default values):
+//!   * num, reads, threads           -- number of entries, reads and threads
+//!   * value_size, value_size_min, value_size_max
+//!                                   -- mean and bounds of the value size
+//!   * zipfian_constant, read_ratio  -- skewness of zipfian keys and read ratio of `mixed`
+//!   * compression_ratio             -- the compressibility of values
+//!   * histogram                     -- print the latency histogram of every benchmark
+//!   * sync                          -- sync every write
+//!   * db, use_existing_db           -- the db path and whether to keep the existing data
+//!   * use_memory_storage            -- run on `MemStorage` instead of the file system
+//!   * write_buffer_size, max_background_compactions
+//!                                   -- the same as the options of the db
+//!   * seed                          -- the seed of the random generators
+
+use rand::distributions::{Distribution, Normal};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::env;
+use std::fmt;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use wickdb::storage::mem::MemStorage;
+use wickdb::{Histogram, Options, ReadOptions, Slice, WickDB, WriteOptions, DB};
+
+const KEY_SIZE: usize = 16;
+
+#[derive(Clone, Copy, PartialEq)]
+enum KeyDistribution {
+    Uniform,
+    Zipfian,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ValueSizeDistribution {
+    Fixed,
+    Uniform,
+    Normal,
+}
+
+#[derive(Clone)]
+struct Flags {
+    benchmarks: Vec<String>,
+    num: u64,
+    reads: u64,
+    threads: usize,
+    value_size: usize,
+    value_size_min: usize,
+    value_size_max: usize,
+    value_size_distribution: ValueSizeDistribution,
+    key_distribution: KeyDistribution,
+    zipfian_constant: f64,
+    read_ratio: f64,
+    qps: u64,
+    compression_ratio: f64,
+    histogram: bool,
+    sync: bool,
+    db: String,
+    use_existing_db: bool,
+    use_memory_storage: bool,
+    write_buffer_size: usize,
+    max_background_compactions: usize,
+    seed: u64,
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Flags {
+            benchmarks: "fillseq,fillrandom,overwrite,readrandom,mixed"
+                .split(',')
+                .map(String::from)
+                .collect(),
+            num: 1_000_000,
+            reads: 0,
+            threads: 1,
+            value_size: 100,
+            value_size_min: 16,
+            value_size_max: 1024,
+            value_size_distribution: ValueSizeDistribution::Fixed,
+            key_distribution: KeyDistribution::Uniform,
+            zipfian_constant: 0.99,
+            read_ratio: 0.5,
+            qps: 0,
+            compression_ratio: 0.5,
+            histogram: false,
+            sync: false,
+            db: "/tmp/wickdb_bench".to_owned(),
+            use_existing_db: false,
+            use_memory_storage: false,
+            write_buffer_size: 4 << 20,
+            max_background_compactions: 1,
+            seed: 301,
+        }
+    }
+}
+
+impl Flags {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut flags = Flags::default();
+        for arg in args {
+            let arg = arg.trim_start_matches("--");
+            let mut kv = arg.splitn(2, '=');
+            let name = kv.next().unwrap();
+            let value = kv
+                .next()
+                .ok_or_else(|| format!("invalid flag '{}', expect '--name=value'", arg))?;
+            match name {
+                "benchmarks" => {
+                    flags.benchmarks = value
+                        .split(',')
+                        .filter(|s| !s.is_empty())
+                        .map(String::from)
+                        .collect()
+                }
+                "num" => flags.num = parse(name, value)?,
+                "reads" => flags.reads = parse(name, value)?,
+                "threads" => flags.threads = parse(name, value)?,
+                "value_size" => flags.value_size = parse(name, value)?,
+                "value_size_min" => flags.value_size_min = parse(name, value)?,
+                "value_size_max" => flags.value_size_max = parse(name, value)?,
+                "value_size_distribution" => {
+                    flags.value_size_distribution = match value {
+                        "fixed" => ValueSizeDistribution::Fixed,
+                        "uniform" => ValueSizeDistribution::Uniform,
+                        "normal" => ValueSizeDistribution::Normal,
+                        _ => return Err(format!("unknown value size distribution '{}'", value)),
+                    }
+                }
+                "key_distribution" => {
+                    flags.key_distribution = match value {
+                        "uniform" => KeyDistribution::Uniform,
+                        "zipfian" => KeyDistribution::Zipfian,
+                        _ => return Err(format!("unknown key distribution '{}'", value)),
+                    }
+                }
+                "zipfian_constant" => flags.zipfian_constant = parse(name, value)?,
+                "read_ratio" => flags.read_ratio = parse(name, value)?,
+                "qps" => flags.qps = parse(name, value)?,
+                "compression_ratio" => flags.compression_ratio = parse(name, value)?,
+                "histogram" => flags.histogram = parse_bool(name, value)?,
+                "sync" => flags.sync = parse_bool(name, value)?,
+                "db" => flags.db = value.to_owned(),
+                "use_existing_db" => flags.use_existing_db = parse_bool(name, value)?,
+                "use_memory_storage" => flags.use_memory_storage = parse_bool(name, value)?,
+                "write_buffer_size" => flags.write_buffer_size = parse(name, value)?,
+                "max_background_compactions" => {
+                    flags.max_background_compactions = parse(name, value)?
+                }
+                "seed" => flags.seed = parse(name, value)?,
+                _ => return Err(format!("unknown flag '{}'", name)),
+            }
+        }
+        if flags.reads == 0 {
+            flags.reads = flags.num;
+        }
+        if flags.threads == 0 {
+            return Err("'threads' should be greater than 0".to_owned());
+        }
+        if flags.value_size_min > flags.value_size_max {
+            return Err("'value_size_min' should not be greater than 'value_size_max'".to_owned());
+        }
+        if flags.read_ratio < 0.0 || flags.read_ratio > 1.0 {
+            return Err("'read_ratio' should be in [0, 1]".to_owned());
+        }
+        if flags.zipfian_constant <= 0.0 || flags.zipfian_constant >= 1.0 {
+            return Err("'zipfian_constant' should be in (0, 1)".to_owned());
+        }
+        Ok(flags)
+    }
+}
+
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse::<T>()
+        .map_err(|_| format!("invalid value '{}' for flag '{}'", value, name))
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => Err(format!("invalid value '{}' for flag '{}'", value, name)),
+    }
+}
+
+/// Generates the ranks of items following a zipfian distribution where the
+/// item of rank 0 is the most popular one. The algorithm is from
+/// "Quickly Generating Billion-Record Synthetic Databases", Jim Gray et al.,
+/// SIGMOD 1994, which is also used by YCSB.
+struct ZipfianGenerator {
+    items: u64,
+    theta: f64,
+    alpha: f64,
+    zeta_n: f64,
+    eta: f64,
+}
+
+impl ZipfianGenerator {
+    fn new(items: u64, theta: f64) -> Self {
+        let zeta_2 = Self::zeta(2, theta);
+        let zeta_n = Self::zeta(items, theta);
+        let alpha = 1.0 / (1.0 - theta);
+        let eta = (1.0 - (2.0 / items as f64).powf(1.0 - theta)) / (1.0 - zeta_2 / zeta_n);
+        Self {
+            items,
+            theta,
+            alpha,
+            zeta_n,
+            eta,
+        }
+    }
+
+    fn zeta(n: u64, theta: f64) -> f64 {
+        (1..=n).fold(0.0, |sum, i| sum + 1.0 / (i as f64).powf(theta))
+    }
+
+    fn next<R: Rng>(&self, rng: &mut R) -> u64 {
+        let u: f64 = rng.gen();
+        let uz = u * self.zeta_n;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1;
+        }
+        let rank = (self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64;
+        rank.min(self.items - 1)
+    }
+}
+
+// FNV-1a hash for scattering the popular zipfian items over the key space
+fn fnv_hash(v: u64) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for b in v.to_le_bytes().iter() {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Generates keys in the range `[0, num)` following the configured distribution
+struct KeyGenerator {
+    num: u64,
+    zipfian: Option<Arc<ZipfianGenerator>>,
+}
+
+impl KeyGenerator {
+    fn next<R: Rng>(&self, rng: &mut R) -> u64 {
+        match &self.zipfian {
+            Some(z) => fnv_hash(z.next(rng)) % self.num,
+            None => rng.gen_range(0, self.num),
+        }
+    }
+}
+
+/// Generates values whose sizes follow the configured distribution. The
+/// contents are cut from a random buffer that compresses to about
+/// `compression_ratio` of its size.
+struct ValueGenerator {
+    data: Vec<u8>,
+    pos: usize,
+    distribution: ValueSizeDistribution,
+    value_size: usize,
+    min: usize,
+    max: usize,
+    normal: Normal,
+}
+
+impl ValueGenerator {
+    fn new<R: Rng>(flags: &Flags, rng: &mut R) -> Self {
+        let mut data = Vec::with_capacity(1 << 20);
+        while data.len() < 1 << 20 {
+            // a 100 bytes piece with `compression_ratio` random bytes repeated
+            let raw = ((100.0 * flags.compression_ratio) as usize).max(1);
+            let piece = (0..raw)
+                .map(|_| rng.gen_range(b' ', b'~'))
+                .collect::<Vec<u8>>();
+            while data.len() < 1 << 20 && data.len() % 100 != 99 {
+                data.push(piece[data.len() % 100 % raw]);
+            }
+            data.push(b'\n');
+        }
+        Self {
+            data,
+            pos: 0,
+            distribution: flags.value_size_distribution,
+            value_size: flags.value_size,
+            min: flags.value_size_min,
+            max: flags.value_size_max.min(1 << 20),
+            normal: Normal::new(flags.value_size as f64, flags.value_size as f64 / 4.0),
+        }
+    }
+
+    fn next<R: Rng>(&mut self, rng: &mut R) -> &[u8] {
+        let size = match self.distribution {
+            ValueSizeDistribution::Fixed => self.value_size.min(1 << 20),
+            ValueSizeDistribution::Uniform => rng.gen_range(self.min, self.max + 1),
+            ValueSizeDistribution::Normal => {
+                let size = self.normal.sample(rng).round().max(0.0) as usize;
+                size.max(self.min).min(self.max)
+            }
+        };
+        if self.pos + size > self.data.len() {
+            self.pos = 0;
+        }
+        self.pos += size;
+        &self.data[self.pos - size..self.pos]
+    }
+}
+
+/// The result of a benchmark in a single thread
+#[derive(Default)]
+struct Stats {
+    ops: u64,
+    bytes: u64,
+    reads: u64,
+    found: u64,
+    writes: u64,
+    errors: u64,
+    // the max time in micros an operation is issued behind its schedule in the qps mode
+    max_lag_micros: u64,
+    elapsed: Duration,
+    latencies: Histogram,
+}
+
+impl Stats {
+    fn merge(&mut self, other: &Stats) {
+        self.ops += other.ops;
+        self.bytes += other.bytes;
+        self.reads += other.reads;
+        self.found += other.found;
+        self.writes += other.writes;
+        self.errors += other.errors;
+        self.max_lag_micros = self.max_lag_micros.max(other.max_lag_micros);
+        self.elapsed = self.elapsed.max(other.elapsed);
+        self.latencies.merge(&other.latencies);
+    }
+}
+
+struct Report<'a> {
+    name: &'a str,
+    stats: &'a Stats,
+    flags: &'a Flags,
+}
+
+impl<'a> fmt::Display for Report<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stats = self.stats;
+        let secs = stats.elapsed.as_secs_f64().max(1e-9);
+        let ops = stats.ops.max(1);
+        write!(
+            f,
+            "{:<12} : {:>11.3} micros/op; {:>10.0} ops/sec;",
+            self.name,
+            secs * 1e6 * self.flags.threads as f64 / ops as f64,
+            stats.ops as f64 / secs,
+        )?;
+        if stats.bytes > 0 {
+            write!(f, " {:>6.1} MB/s;", stats.bytes as f64 / 1_048_576.0 / secs)?;
+        }
+        if stats.reads > 0 {
+            write!(f, " ({} of {} found)", stats.found, stats.reads)?;
+        }
+        if stats.errors > 0 {
+            write!(f, " ({} errors)", stats.errors)?;
+        }
+        writeln!(f)?;
+        if self.flags.qps > 0 {
+            writeln!(
+                f,
+                "{:<12} : target {} ops/sec, max behind schedule {:.3} ms",
+                "",
+                self.flags.qps,
+                stats.max_lag_micros as f64 / 1000.0,
+            )?;
+        }
+        write!(
+            f,
+            "{:<12} : latency micros P50: {:.2} P95: {:.2} P99: {:.2} P99.9: {:.2} P99.99: {:.2} Max: {:.2}",
+            "",
+            stats.latencies.percentile(50.0),
+            stats.latencies.percentile(95.0),
+            stats.latencies.percentile(99.0),
+            stats.latencies.percentile(99.9),
+            stats.latencies.percentile(99.99),
+            stats.latencies.max(),
+        )?;
+        if self.flags.histogram {
+            write!(f, "\n{}", stats.latencies)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    FillSeq,
+    FillRandom,
+    ReadRandom,
+    Mixed,
+}
+
+// Paces the operations in a thread to match the target qps
+struct Pacer {
+    start: Instant,
+    interval: Option<Duration>,
+    issued: u32,
+}
+
+impl Pacer {
+    fn new(qps: u64, threads: usize) -> Self {
+        let interval = if qps > 0 {
+            Some(Duration::from_nanos(
+                1_000_000_000 * threads as u64 / qps.max(1),
+            ))
+        } else {
+            None
+        };
+        Self {
+            start: Instant::now(),
+            interval,
+            issued: 0,
+        }
+    }
+
+    // Waits for the scheduled time of next operation and returns it
+    fn wait(&mut self) -> Instant {
+        let now = Instant::now();
+        match self.interval {
+            Some(interval) => {
+                let scheduled = self.start + interval * self.issued;
+                self.issued += 1;
+                if scheduled > now {
+                    thread::sleep(scheduled - now);
+                }
+                scheduled
+            }
+            None => now,
+        }
+    }
+}
+
+fn format_key(k: u64) -> String {
+    format!("{:0width$}", k, width = KEY_SIZE)
+}
+
+fn run_thread(db: &WickDB, flags: &Flags, op: Op, keys: &KeyGenerator, seed: u64) -> Stats {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut values = ValueGenerator::new(flags, &mut rng);
+    let mut stats = Stats::default();
+    let count = match op {
+        Op::ReadRandom => flags.reads,
+        _ => flags.num,
+    };
+    let mut pacer = Pacer::new(flags.qps, flags.threads);
+    for i in 0..count {
+        let scheduled = pacer.wait();
+        let lag = Instant::now().duration_since(scheduled).as_micros() as u64;
+        stats.max_lag_micros = stats.max_lag_micros.max(lag);
+        let is_read = match op {
+            Op::ReadRandom => true,
+            Op::Mixed => rng.gen::<f64>() < flags.read_ratio,
+            _ => false,
+        };
+        let key = match op {
+            Op::FillSeq => format_key(i),
+            _ => format_key(keys.next(&mut rng)),
+        };
+        if is_read {
+            stats.reads += 1;
+            match db.get(ReadOptions::default(), Slice::from(key.as_str())) {
+                Ok(Some(v)) => {
+                    stats.found += 1;
+                    stats.bytes += (key.len() + v.len()) as u64;
+                }
+                Ok(None) => {}
+                Err(_) => stats.errors += 1,
+            }
+        } else {
+            stats.writes += 1;
+            let value = values.next(&mut rng);
+            stats.bytes += (key.len() + value.len()) as u64;
+            if db
+                .put(
+                    WriteOptions {
+                        sync: flags.sync,
+                        ..WriteOptions::default()
+                    },
+                    Slice::from(key.as_str()),
+                    Slice::from(value),
+                )
+                .is_err()
+            {
+                stats.errors += 1;
+            }
+        }
+        stats
+            .latencies
+            .add(scheduled.elapsed().as_nanos() as f64 / 1000.0);
+        stats.ops += 1;
+    }
+    stats.elapsed = pacer.start.elapsed();
+    stats
+}
+
+fn run_benchmark(db: &WickDB, flags: &Flags, op: Op, keys: &Arc<KeyGenerator>) -> Stats {
+    let mut handles = Vec::with_capacity(flags.threads);
+    for t in 0..flags.threads {
+        let db = db.clone();
+        let flags = flags.clone();
+        let keys = keys.clone();
+        let seed = flags.seed + t as u64;
+        handles.push(thread::spawn(move || {
+            run_thread(&db, &flags, op, keys.as_ref(), seed)
+        }));
+    }
+    let mut stats = Stats::default();
+    for h in handles {
+        stats.merge(&h.join().expect("benchmark thread panicked"));
+    }
+    stats
+}
+
+fn open_db(flags: &Flags, fresh: bool) -> WickDB {
+    let mut options = Options::default();
+    options.write_buffer_size = flags.write_buffer_size;
+    options.max_background_compactions = flags.max_background_compactions;
+    if flags.use_memory_storage {
+        options.env = Arc::new(MemStorage::default());
+    } else if fresh {
+        let _ = options.env.remove_dir(&flags.db, true);
+    }
+    WickDB::open_db(options, flags.db.clone()).unwrap_or_else(|e| {
+        eprintln!("open db {} failed: {:?}", flags.db, e);
+        process::exit(1)
+    })
+}
+
+fn main() {
+    let flags = Flags::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1)
+    });
+    let keys = Arc::new(KeyGenerator {
+        num: flags.num.max(1),
+        zipfian: match flags.key_distribution {
+            KeyDistribution::Zipfian => Some(Arc::new(ZipfianGenerator::new(
+                flags.num.max(2),
+                flags.zipfian_constant,
+            ))),
+            KeyDistribution::Uniform => None,
+        },
+    });
+    println!("Keys:       {} bytes each", KEY_SIZE);
+    println!(
+        "Values:     {} bytes each ({} bytes after compression)",
+        flags.value_size,
+        (flags.value_size as f64 * flags.compression_ratio) as usize
+    );
+    println!("Entries:    {}", flags.num);
+    println!("Threads:    {}", flags.threads);
+    println!("------------------------------------------------");
+
+    let mut db = open_db(&flags, !flags.use_existing_db);
+    for name in flags.benchmarks.iter() {
+        let op = match name.as_str() {
+            "fillseq" => Op::FillSeq,
+            "fillrandom" | "overwrite" => Op::FillRandom,
+            "readrandom" => Op::ReadRandom,
+            "mixed" => Op::Mixed,
+            "stats" => {
+                println!("{}", db.get_property("wickdb.stats").unwrap_or_default());
+                continue;
+            }
+            _ => {
+                eprintln!("unknown benchmark '{}'", name);
+                continue;
+            }
+        };
+        if (name == "fillseq" || name == "fillrandom") && !flags.use_existing_db {
+            // every fill benchmark starts with an empty db
+            let _ = db.close();
+            db = open_db(&flags, true);
+        }
+        let stats = run_benchmark(&db, &flags, op, &keys);
+        println!(
+            "{}",
+            Report {
+                name,
+                stats: &stats,
+                flags: &flags,
+            }
+        );
+    }
+}
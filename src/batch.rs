@@ -16,11 +16,12 @@
 // found in the LICENSE file.
 
 use crate::db::format::ValueType;
+use crate::iterator::Iterator;
 use crate::mem::{MemTable, MemoryTable};
 use crate::util::coding::{decode_fixed_32, decode_fixed_64, encode_fixed_32, encode_fixed_64};
 use crate::util::slice::Slice;
 use crate::util::status::{Result, Status, WickErr};
-use crate::util::varint::VarintU32;
+use crate::util::varint::{VarintU32, MAX_VARINT_LEN_U32};
 
 pub const HEADER_SIZE: usize = 12;
 
@@ -88,6 +89,38 @@ impl WriteBatch {
         self.contents.extend_from_slice(key);
     }
 
+    /// Appends a deletion for every key yielded by `iter` from its current position
+    /// until the batch grows up to `limit` bytes. At least one key is appended
+    /// if the batch is empty so that a chunked cleanup always makes progress.
+    ///
+    /// Returns the key to resume from when the batch is full, at which `iter` is
+    /// left positioned, or `None` if `iter` is exhausted.
+    pub fn delete_all_in(
+        &mut self,
+        iter: &mut dyn Iterator,
+        limit: usize,
+    ) -> Result<Option<Vec<u8>>> {
+        while iter.valid() {
+            let key = iter.key();
+            if self.get_count() > 0
+                && self.approximate_size() + Self::deletion_size(key.size()) > limit
+            {
+                return Ok(Some(key.copy()));
+            }
+            self.delete(key.as_slice());
+            iter.next();
+        }
+        iter.status()?;
+        Ok(None)
+    }
+
+    // Returns the bytes a deletion record of a key with `key_len` takes
+    #[inline]
+    fn deletion_size(key_len: usize) -> usize {
+        let mut buf = [0; MAX_VARINT_LEN_U32];
+        1 + VarintU32::write(&mut buf, key_len as u32) + key_len
+    }
+
     /// The size of the database changes caused by this batch.
     #[inline]
     pub fn approximate_size(&self) -> usize {
@@ -170,6 +203,76 @@ impl WriteBatch {
         Ok(())
     }
 
+    // Calls `f` with the key and the bytes of the key and the value of every
+    // record in order. Stops at the first malformed record.
+    pub(crate) fn for_each_record(&self, mut f: impl FnMut(&[u8], usize)) {
+        if self.contents.len() < HEADER_SIZE {
+            return;
+        }
+        let mut s = Slice::from(&self.contents.as_slice()[HEADER_SIZE..]);
+        while !s.is_empty() {
+            let tag = s[0];
+            s.remove_prefix(1);
+            let key = match VarintU32::get_varint_prefixed_slice(&mut s) {
+                Some(key) => key,
+                None => return,
+            };
+            let value_size = match ValueType::from(u64::from(tag)) {
+                ValueType::Value => match VarintU32::get_varint_prefixed_slice(&mut s) {
+                    Some(value) => value.size(),
+                    None => return,
+                },
+                ValueType::Deletion => 0,
+                ValueType::Unknown => return,
+            };
+            f(key.as_slice(), key.size() + value_size);
+        }
+    }
+
+    // Splits the records into `n` batches by the index `shard_of` returns for each key,
+    // keeping the order of the records in every batch
+    pub(crate) fn partition(
+        &self,
+        n: usize,
+        shard_of: impl Fn(&[u8]) -> usize,
+    ) -> Result<Vec<WriteBatch>> {
+        let mut batches = vec![WriteBatch::new(); n];
+        if self.contents.len() < HEADER_SIZE {
+            return Err(WickErr::new(
+                Status::Corruption,
+                Some("[batch] malformed WriteBatch (too small)"),
+            ));
+        }
+        let mut s = Slice::from(&self.contents.as_slice()[HEADER_SIZE..]);
+        while !s.is_empty() {
+            let tag = s[0];
+            s.remove_prefix(1);
+            let key = VarintU32::get_varint_prefixed_slice(&mut s).ok_or_else(|| {
+                WickErr::new(Status::Corruption, Some("[batch] bad WriteBatch record"))
+            })?;
+            let batch = &mut batches[shard_of(key.as_slice())];
+            match ValueType::from(u64::from(tag)) {
+                ValueType::Value => match VarintU32::get_varint_prefixed_slice(&mut s) {
+                    Some(value) => batch.put(key.as_slice(), value.as_slice()),
+                    None => {
+                        return Err(WickErr::new(
+                            Status::Corruption,
+                            Some("[batch] bad WriteBatch put"),
+                        ))
+                    }
+                },
+                ValueType::Deletion => batch.delete(key.as_slice()),
+                ValueType::Unknown => {
+                    return Err(WickErr::new(
+                        Status::Corruption,
+                        Some("[batch] unknown WriteBatch value type"),
+                    ))
+                }
+            }
+        }
+        Ok(batches)
+    }
+
     #[inline]
     pub(crate) fn set_contents(&mut self, src: &mut Vec<u8>) {
         self.contents.clear();
@@ -204,9 +307,11 @@ impl WriteBatch {
 
 #[cfg(test)]
 mod tests {
-    use crate::batch::WriteBatch;
+    use crate::batch::{WriteBatch, HEADER_SIZE};
     use crate::db::format::{InternalKeyComparator, ParsedInternalKey, ValueType};
+    use crate::iterator::Iterator;
     use crate::mem::{MemTable, MemoryTable};
+    use crate::sstable::block::{Block, BlockBuilder};
     use crate::util::comparator::BytewiseComparator;
     use std::sync::Arc;
 
@@ -323,4 +428,57 @@ mod tests {
         let post_delete_size = b.approximate_size();
         assert!(two_keys_size < post_delete_size);
     }
+
+    fn new_user_key_iter(keys: &[&str]) -> Box<dyn Iterator> {
+        let cmp = Arc::new(BytewiseComparator::new());
+        let mut builder = BlockBuilder::new(16, cmp.clone());
+        for key in keys {
+            builder.add(key.as_bytes(), b"");
+        }
+        let block = Block::new(builder.finish().to_vec()).expect("");
+        block.iter(cmp)
+    }
+
+    #[test]
+    fn test_delete_all_in() {
+        let keys = ["a", "b", "c", "d", "e"];
+        let mut iter = new_user_key_iter(&keys);
+        iter.seek_to_first();
+        let mut deleted = vec![];
+        let mut resume_times = 0;
+        loop {
+            let mut b = WriteBatch::new();
+            // room for two deletions of 1 byte keys
+            let resume = b
+                .delete_all_in(iter.as_mut(), HEADER_SIZE + 6)
+                .expect("delete_all_in should work");
+            assert!(b.get_count() <= 2);
+            b.set_sequence(100);
+            deleted.push(print_contents(&b));
+            match resume {
+                Some(key) => {
+                    resume_times += 1;
+                    assert_eq!(iter.key().as_slice(), key.as_slice());
+                }
+                None => break,
+            }
+        }
+        assert_eq!(resume_times, 2);
+        assert_eq!(
+            deleted,
+            vec![
+                "Delete(a)@100|Delete(b)@101|",
+                "Delete(c)@100|Delete(d)@101|",
+                "Delete(e)@100|"
+            ]
+        );
+
+        // a batch makes progress even if the limit is too small
+        let mut iter = new_user_key_iter(&keys);
+        iter.seek_to_first();
+        let mut b = WriteBatch::new();
+        let resume = b.delete_all_in(iter.as_mut(), 0).expect("");
+        assert_eq!(b.get_count(), 1);
+        assert_eq!(resume, Some(b"b".to_vec()));
+    }
 }
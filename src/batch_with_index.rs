@@ -0,0 +1,519 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::batch::WriteBatch;
+use crate::db::DB;
+use crate::iterator::Iterator;
+use crate::options::ReadOptions;
+use crate::util::comparator::Comparator;
+use crate::util::slice::Slice;
+use crate::util::status::Result;
+use crate::util::varint::{VarintU32, MAX_VARINT_LEN_U32};
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+// The position of the newest update of a key in the contents of the batch
+#[derive(Clone, Copy)]
+struct IndexEntry {
+    key_offset: usize,
+    key_len: usize,
+    // `None` means a deletion
+    value: Option<(usize, usize)>,
+}
+
+/// `WriteBatchWithIndex` is a `WriteBatch` with a sorted index over the keys in
+/// it so that the uncommitted updates are able to be read by `get_from_batch`,
+/// `get_from_batch_and_db` and `iter_with_base` before the batch is written.
+///
+/// Only the newest update of each key is indexed, so reading a key always yields
+/// the last value put into the batch or nothing if the last update is a deletion.
+/// The keys are ordered by the given comparator, which must be the same as the
+/// one of the DB to read with.
+pub struct WriteBatchWithIndex {
+    batch: WriteBatch,
+    cmp: Arc<dyn Comparator>,
+    // Sorted by the user key
+    index: Vec<IndexEntry>,
+}
+
+impl WriteBatchWithIndex {
+    pub fn new(cmp: Arc<dyn Comparator>) -> Self {
+        Self {
+            batch: WriteBatch::new(),
+            cmp,
+            index: vec![],
+        }
+    }
+
+    /// Stores the mapping "key -> value" in the batch
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        let key_offset = self.batch.approximate_size() + 1 + varint_len(key.len());
+        let value_offset = key_offset + key.len() + varint_len(value.len());
+        self.batch.put(key, value);
+        self.add_index(IndexEntry {
+            key_offset,
+            key_len: key.len(),
+            value: Some((value_offset, value.len())),
+        });
+    }
+
+    /// Erases the mapping for "key" in the batch
+    pub fn delete(&mut self, key: &[u8]) {
+        let key_offset = self.batch.approximate_size() + 1 + varint_len(key.len());
+        self.batch.delete(key);
+        self.add_index(IndexEntry {
+            key_offset,
+            key_len: key.len(),
+            value: None,
+        });
+    }
+
+    /// Clears all the updates in the batch
+    pub fn clear(&mut self) {
+        self.batch.clear();
+        self.index.clear();
+    }
+
+    /// Returns the number of the distinct keys in the batch
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Returns the underlying `WriteBatch` containing all the updates
+    #[inline]
+    pub fn batch(&self) -> &WriteBatch {
+        &self.batch
+    }
+
+    /// Consumes the index and returns the underlying `WriteBatch` for writing
+    pub fn into_batch(self) -> WriteBatch {
+        self.batch
+    }
+
+    /// Looks up `key` in the batch only. Returns `None` if the batch doesn't
+    /// update `key`, `Some(None)` if `key` is deleted and `Some(Some(value))`
+    /// if `key` is put.
+    pub fn get_from_batch(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+        self.find(key).ok().map(|i| self.value_of(&self.index[i]))
+    }
+
+    /// Looks up `key` in the batch and falls back to `db` if the batch doesn't
+    /// update `key`. `read_opt` only applies to the read from `db`.
+    pub fn get_from_batch_and_db(
+        &self,
+        db: &dyn DB,
+        read_opt: ReadOptions,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        match self.get_from_batch(key) {
+            Some(value) => Ok(value.map(|v| v.to_vec())),
+            None => db.get(read_opt, Slice::from(key)),
+        }
+    }
+
+    /// Returns an iterator yielding the user keys of `base`, usually created by
+    /// `DB::iter`, overlaid with the updates in the batch. The updates made after
+    /// this call are invisible to the returned iterator.
+    pub fn iter_with_base(&self, base: Box<dyn Iterator>) -> Box<dyn Iterator> {
+        let delta = self
+            .index
+            .iter()
+            .map(|e| {
+                (
+                    self.key_of(e).to_vec(),
+                    self.value_of(e).map(|v| v.to_vec()),
+                )
+            })
+            .collect();
+        Box::new(BaseDeltaIterator::new(base, delta, self.cmp.clone()))
+    }
+
+    fn add_index(&mut self, entry: IndexEntry) {
+        let key = self.key_of(&entry);
+        match self.find(key) {
+            Ok(i) => self.index[i] = entry,
+            Err(i) => self.index.insert(i, entry),
+        }
+    }
+
+    // Binary searches the index for `key`
+    fn find(&self, key: &[u8]) -> std::result::Result<usize, usize> {
+        self.index
+            .binary_search_by(|e| self.cmp.compare(self.key_of(e), key))
+    }
+
+    #[inline]
+    fn key_of(&self, entry: &IndexEntry) -> &[u8] {
+        &self.batch.data()[entry.key_offset..entry.key_offset + entry.key_len]
+    }
+
+    #[inline]
+    fn value_of(&self, entry: &IndexEntry) -> Option<&[u8]> {
+        entry
+            .value
+            .map(|(offset, len)| &self.batch.data()[offset..offset + len])
+    }
+}
+
+// Returns the bytes the varint encoded `n` takes
+#[inline]
+fn varint_len(n: usize) -> usize {
+    let mut buf = [0; MAX_VARINT_LEN_U32];
+    VarintU32::write(&mut buf, n as u32)
+}
+
+#[derive(Eq, PartialEq)]
+enum Direction {
+    Forward,
+    Reverse,
+}
+
+/// An iterator merging the entries of `base` with the updates in `delta`. An update
+/// in `delta` hides the entry with the same key in `base`, and a deletion in `delta`
+/// hides the key entirely.
+///
+/// When moving forward, both `base` and `delta` are positioned at their smallest
+/// entries not less than the current key. When moving backward, both are positioned
+/// at their largest entries not greater than the current key.
+struct BaseDeltaIterator {
+    base: Box<dyn Iterator>,
+    // Sorted updates. `None` means a deletion.
+    delta: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    // `delta.len()` means the delta part is exhausted
+    delta_pos: usize,
+    cmp: Arc<dyn Comparator>,
+    direction: Direction,
+    valid: bool,
+    // Whether the current entry comes from `base`
+    current_at_base: bool,
+}
+
+impl BaseDeltaIterator {
+    fn new(
+        base: Box<dyn Iterator>,
+        delta: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        cmp: Arc<dyn Comparator>,
+    ) -> Self {
+        let delta_pos = delta.len();
+        Self {
+            base,
+            delta,
+            delta_pos,
+            cmp,
+            direction: Direction::Forward,
+            valid: false,
+            current_at_base: false,
+        }
+    }
+
+    #[inline]
+    fn delta_valid(&self) -> bool {
+        self.delta_pos < self.delta.len()
+    }
+
+    #[inline]
+    fn valid_or_panic(&self) {
+        assert!(self.valid, "[base delta iterator] invalid iterator")
+    }
+
+    // Positions the delta part at the first update not less than `target`
+    fn seek_delta(&mut self, target: &[u8]) {
+        let cmp = &self.cmp;
+        self.delta_pos = match self
+            .delta
+            .binary_search_by(|(k, _)| cmp.compare(k.as_slice(), target))
+        {
+            Ok(i) | Err(i) => i,
+        };
+    }
+
+    fn step_delta(&mut self) {
+        match self.direction {
+            Direction::Forward => self.delta_pos += 1,
+            Direction::Reverse => {
+                if self.delta_pos == 0 {
+                    self.delta_pos = self.delta.len()
+                } else {
+                    self.delta_pos -= 1
+                }
+            }
+        }
+    }
+
+    fn step_base(&mut self) {
+        match self.direction {
+            Direction::Forward => self.base.next(),
+            Direction::Reverse => self.base.prev(),
+        }
+    }
+
+    // Moves away from the current entry in the current direction
+    fn advance(&mut self) {
+        if self.current_at_base {
+            self.step_base();
+        } else {
+            if self.base.valid()
+                && self.cmp.compare(
+                    self.base.key().as_slice(),
+                    self.delta[self.delta_pos].0.as_slice(),
+                ) == Ordering::Equal
+            {
+                self.step_base();
+            }
+            self.step_delta();
+        }
+    }
+
+    // Picks the current entry from `base` and `delta` and skips the deletions
+    fn update_current(&mut self) {
+        loop {
+            let (base_valid, delta_valid) = (self.base.valid(), self.delta_valid());
+            if !base_valid && !delta_valid {
+                self.valid = false;
+                return;
+            }
+            let from_delta = if !base_valid {
+                true
+            } else if !delta_valid {
+                false
+            } else {
+                let ord = self.cmp.compare(
+                    self.delta[self.delta_pos].0.as_slice(),
+                    self.base.key().as_slice(),
+                );
+                match self.direction {
+                    Direction::Forward => ord != Ordering::Greater,
+                    Direction::Reverse => ord != Ordering::Less,
+                }
+            };
+            self.current_at_base = !from_delta;
+            if from_delta && self.delta[self.delta_pos].1.is_none() {
+                self.advance();
+                continue;
+            }
+            self.valid = true;
+            return;
+        }
+    }
+}
+
+impl Iterator for BaseDeltaIterator {
+    fn valid(&self) -> bool {
+        self.valid
+    }
+
+    fn seek_to_first(&mut self) {
+        self.direction = Direction::Forward;
+        self.base.seek_to_first();
+        self.delta_pos = 0;
+        self.update_current();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.direction = Direction::Reverse;
+        self.base.seek_to_last();
+        self.delta_pos = self.delta.len().saturating_sub(1);
+        self.update_current();
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        self.direction = Direction::Forward;
+        self.base.seek(target);
+        self.seek_delta(target.as_slice());
+        self.update_current();
+    }
+
+    fn next(&mut self) {
+        self.valid_or_panic();
+        if self.direction == Direction::Reverse {
+            // Position both parts at the entries not less than the current key
+            let key = self.key().copy();
+            self.direction = Direction::Forward;
+            self.base.seek(&Slice::from(key.as_slice()));
+            self.seek_delta(key.as_slice());
+        }
+        self.advance();
+        self.update_current();
+    }
+
+    fn prev(&mut self) {
+        self.valid_or_panic();
+        if self.direction == Direction::Forward {
+            // Position both parts at the entries not greater than the current key
+            let key = self.key().copy();
+            self.direction = Direction::Reverse;
+            self.base.seek(&Slice::from(key.as_slice()));
+            if !self.base.valid() {
+                self.base.seek_to_last();
+            } else if self.cmp.compare(self.base.key().as_slice(), key.as_slice())
+                != Ordering::Equal
+            {
+                self.base.prev();
+            }
+            self.seek_delta(key.as_slice());
+            if !self.delta_valid()
+                || self
+                    .cmp
+                    .compare(self.delta[self.delta_pos].0.as_slice(), key.as_slice())
+                    != Ordering::Equal
+            {
+                self.step_delta();
+            }
+        }
+        self.advance();
+        self.update_current();
+    }
+
+    fn key(&self) -> Slice {
+        self.valid_or_panic();
+        if self.current_at_base {
+            self.base.key()
+        } else {
+            Slice::from(self.delta[self.delta_pos].0.as_slice())
+        }
+    }
+
+    fn value(&self) -> Slice {
+        self.valid_or_panic();
+        if self.current_at_base {
+            self.base.value()
+        } else {
+            Slice::from(self.delta[self.delta_pos].1.as_ref().unwrap().as_slice())
+        }
+    }
+
+    fn status(&mut self) -> Result<()> {
+        self.base.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::WickDB;
+    use crate::options::{Options, WriteOptions};
+    use crate::storage::mem::MemStorage;
+    use crate::util::comparator::BytewiseComparator;
+
+    fn new_batch() -> WriteBatchWithIndex {
+        WriteBatchWithIndex::new(Arc::new(BytewiseComparator::new()))
+    }
+
+    fn collect(iter: &mut dyn Iterator, forward: bool) -> Vec<String> {
+        let mut res = vec![];
+        if forward {
+            iter.seek_to_first();
+        } else {
+            iter.seek_to_last();
+        }
+        while iter.valid() {
+            res.push(format!(
+                "{}={}",
+                String::from_utf8_lossy(iter.key().as_slice()),
+                String::from_utf8_lossy(iter.value().as_slice())
+            ));
+            if forward {
+                iter.next();
+            } else {
+                iter.prev();
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn test_get_from_batch() {
+        let mut b = new_batch();
+        assert!(b.is_empty());
+        b.put(b"b", b"1");
+        b.put(b"a", b"2");
+        b.delete(b"c");
+        b.put(b"b", b"3");
+        assert_eq!(b.len(), 3);
+        assert_eq!(b.batch().get_count(), 4);
+        assert_eq!(b.get_from_batch(b"a"), Some(Some(b"2".as_ref())));
+        assert_eq!(b.get_from_batch(b"b"), Some(Some(b"3".as_ref())));
+        assert_eq!(b.get_from_batch(b"c"), Some(None));
+        assert_eq!(b.get_from_batch(b"d"), None);
+        b.put(b"c", b"");
+        assert_eq!(b.get_from_batch(b"c"), Some(Some(b"".as_ref())));
+        b.clear();
+        assert!(b.is_empty());
+        assert_eq!(b.get_from_batch(b"a"), None);
+    }
+
+    #[test]
+    fn test_read_through_batch_and_db() {
+        let mut options = Options::default();
+        options.env = Arc::new(MemStorage::default());
+        let db = WickDB::open_db(options, "test_read_through_batch_and_db".to_owned()).unwrap();
+        for key in ["a", "c", "e", "g"].iter() {
+            db.put(
+                WriteOptions::default(),
+                Slice::from(*key),
+                Slice::from("db"),
+            )
+            .unwrap();
+        }
+        let mut b = new_batch();
+        b.put(b"b", b"batch");
+        b.put(b"c", b"batch");
+        b.delete(b"e");
+        b.delete(b"f");
+        b.put(b"h", b"batch");
+
+        let get = |key: &str| {
+            b.get_from_batch_and_db(&db, ReadOptions::default(), key.as_bytes())
+                .unwrap()
+                .map(|v| String::from_utf8(v).unwrap())
+        };
+        assert_eq!(get("a"), Some("db".to_owned()));
+        assert_eq!(get("c"), Some("batch".to_owned()));
+        assert_eq!(get("e"), None);
+        assert_eq!(get("z"), None);
+
+        let expected = vec!["a=db", "b=batch", "c=batch", "g=db", "h=batch"];
+        let mut iter = b.iter_with_base(db.iter(ReadOptions::default()));
+        assert_eq!(collect(iter.as_mut(), true), expected);
+        let mut reversed = expected.clone();
+        reversed.reverse();
+        assert_eq!(collect(iter.as_mut(), false), reversed);
+
+        // Seek and switch the direction
+        iter.seek(&Slice::from("d"));
+        assert_eq!(iter.key().as_str(), "g");
+        iter.prev();
+        assert_eq!(iter.key().as_str(), "c");
+        iter.prev();
+        assert_eq!(iter.key().as_str(), "b");
+        iter.next();
+        assert_eq!(iter.key().as_str(), "c");
+        iter.next();
+        assert_eq!(iter.key().as_str(), "g");
+        iter.next();
+        assert_eq!(iter.key().as_str(), "h");
+        iter.next();
+        assert!(!iter.valid());
+        iter.seek(&Slice::from("e"));
+        assert_eq!(iter.key().as_str(), "g");
+        iter.seek(&Slice::from("i"));
+        assert!(!iter.valid());
+        assert!(iter.status().is_ok());
+    }
+}
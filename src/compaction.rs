@@ -17,10 +17,12 @@
 
 use crate::db::format::{InternalKey, InternalKeyComparator};
 use crate::iterator::{ConcatenateIterator, Iterator, MergingIterator};
+use crate::listener::CompactionReason;
 use crate::options::{Options, ReadOptions};
 use crate::sstable::table::TableBuilder;
 use crate::table_cache::TableCache;
 use crate::util::comparator::Comparator;
+use crate::util::histogram::Histogram;
 use crate::util::slice::Slice;
 use crate::version::version_edit::{FileMetaData, VersionEdit};
 use crate::version::version_set::{FileIterFactory, VersionSet};
@@ -30,12 +32,48 @@ use std::cmp::Ordering as CmpOrdering;
 use std::rc::Rc;
 use std::sync::Arc;
 
+/// The decision made by a `CompactionFilter` on a key/value pair
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompactionDecision {
+    /// Keeps the pair unchanged
+    Keep,
+    /// Removes the key as if it was deleted by the user
+    Remove,
+    /// Replaces the value of the key with the given one
+    ChangeValue(Vec<u8>),
+}
+
+/// A `CompactionFilter` allows an application to drop or modify key/value
+/// pairs during major compactions, e.g. to expire the values by a TTL
+/// stored in them.
+///
+/// The filter is only invoked on the newest version of each user key and
+/// only when no live snapshot could observe the change. Deletions are never
+/// passed to the filter, and the keys still in the memtables are not
+/// filtered until they are compacted out of level 0.
+pub trait CompactionFilter: Send + Sync {
+    /// The name of the filter
+    fn name(&self) -> &str;
+
+    /// Makes a decision on the key/value pair compacted from `level` to `level + 1`
+    fn filter(&self, level: usize, key: &[u8], value: &[u8]) -> CompactionDecision;
+
+    /// Makes a decision by the key alone before the value is loaded, or returns
+    /// `None` to fall back to `filter`.
+    ///
+    /// The value of a key decided here is never loaded unless the key is kept in
+    /// the output, which saves the work of reading the values that are dropped.
+    fn filter_by_key(&self, _level: usize, _key: &[u8]) -> Option<CompactionDecision> {
+        None
+    }
+}
+
 /// Information for a manual compaction
 pub struct ManualCompaction {
     pub level: usize,
     pub done: bool,
-    pub begin: Option<Rc<InternalKey>>, // None means beginning of key range
-    pub end: Option<Rc<InternalKey>>,   // None means end of key range
+    pub begin: Option<Arc<InternalKey>>, // None means beginning of key range
+    pub end: Option<Arc<InternalKey>>,   // None means end of key range
 }
 
 /// A helper enum describing relations between the indexes of `inputs` in `Compaction`
@@ -45,11 +83,93 @@ pub enum CompactionInputsRelation {
     Parent = 1, // level n + 1
 }
 
+// A copy of a `FileMetaData` owning its keys, which is free to move between threads
+struct FileCopy {
+    number: u64,
+    file_size: u64,
+    smallest: Vec<u8>,
+    largest: Vec<u8>,
+}
+
+impl FileCopy {
+    fn new(f: &FileMetaData) -> Self {
+        Self {
+            number: f.number,
+            file_size: f.file_size,
+            smallest: f.smallest.data().to_vec(),
+            largest: f.largest.data().to_vec(),
+        }
+    }
+
+    fn to_file(&self) -> FileMetaData {
+        FileMetaData {
+            number: self.number,
+            file_size: self.file_size,
+            smallest: Arc::new(InternalKey::decoded_from(self.smallest.as_slice())),
+            largest: Arc::new(InternalKey::decoded_from(self.largest.as_slice())),
+            ..FileMetaData::default()
+        }
+    }
+}
+
+/// The input of a subcompaction deep copied from its parent compaction by
+/// `Compaction::new_subcompaction`. It shares no file metadata or version with
+/// the parent, so it's sent to the thread running the subcompaction and turned
+/// into a `Compaction` there by `into_compaction`.
+pub struct SubcompactionInput {
+    level: usize,
+    reason: CompactionReason,
+    inputs: [Vec<FileCopy>; 2],
+    grand_parents: Vec<FileCopy>,
+    // the files of the levels deeper than level n + 1 in the input version by level
+    deeper_files: Vec<Vec<FileCopy>>,
+    oldest_snapshot_alive: u64,
+    newest_snapshot_alive: Option<u64>,
+    start: Option<Vec<u8>>,
+    end: Option<Vec<u8>>,
+}
+
+impl SubcompactionInput {
+    /// Builds the subcompaction of its own `FileMetaData`s and input version
+    pub fn into_compaction(
+        self,
+        options: Arc<Options>,
+        icmp: Arc<InternalKeyComparator>,
+    ) -> Compaction {
+        let to_files = |files: Vec<FileCopy>| -> Vec<Arc<FileMetaData>> {
+            files.iter().map(|f| Arc::new(f.to_file())).collect()
+        };
+        let [source, parent] = self.inputs;
+        let version_files = self.deeper_files.into_iter().map(to_files).collect();
+        let mut c = Compaction::new(options.clone(), self.level);
+        c.reason = self.reason;
+        c.input_version = Some(Arc::new(Version::with_files(options, icmp, version_files)));
+        c.inputs = [to_files(source), to_files(parent)];
+        c.grand_parents = to_files(self.grand_parents);
+        c.oldest_snapshot_alive = self.oldest_snapshot_alive;
+        c.newest_snapshot_alive = self.newest_snapshot_alive;
+        c.start = self.start;
+        c.end = self.end;
+        c
+    }
+}
+
+/// The outputs of a subcompaction sent back to the thread of its parent, see
+/// `Compaction::merge_subcompaction`
+pub struct SubcompactionOutput {
+    outputs: Vec<FileCopy>,
+    total_bytes: u64,
+    versions_per_key: Histogram,
+    dropped_versions: u64,
+}
+
 /// A Compaction encapsulates information about a compaction
 pub struct Compaction {
     options: Arc<Options>,
     // Target level to be compacted
     pub level: usize,
+    // What triggers this compaction
+    pub reason: CompactionReason,
     pub input_version: Option<Arc<Version>>,
     // Summary of the compaction result
     pub edit: VersionEdit,
@@ -80,6 +200,10 @@ pub struct Compaction {
     // we can drop all entries for the same key with sequence numbers < S
     pub oldest_snapshot_alive: u64,
 
+    // The sequence of the newest live snapshot, `None` if there is no snapshot.
+    // Only the versions newer than it are passed to the compaction filter.
+    pub newest_snapshot_alive: Option<u64>,
+
     // all output files information
     pub outputs: Vec<FileMetaData>,
 
@@ -90,6 +214,16 @@ pub struct Compaction {
 
     // total bytes has been written
     pub total_bytes: u64,
+
+    // The user key range `[start, end)` this compaction processes. `None` means
+    // unbounded. Only a subcompaction has a bounded range.
+    pub start: Option<Vec<u8>>,
+    pub end: Option<Vec<u8>>,
+
+    // The number of the versions of every user key in the inputs
+    pub versions_per_key: Histogram,
+    // The number of the entries dropped as they are shadowed by newer versions
+    pub dropped_versions: u64,
 }
 
 impl Compaction {
@@ -102,6 +236,7 @@ impl Compaction {
         Self {
             options: options.clone(),
             level,
+            reason: CompactionReason::LevelSize,
             input_version: None,
             edit: VersionEdit::new(options.clone().max_levels),
             inputs: [vec![], vec![]],
@@ -111,14 +246,132 @@ impl Compaction {
             overlapped_bytes: 0,
             level_ptrs,
             oldest_snapshot_alive: 0,
+            newest_snapshot_alive: None,
             outputs: vec![],
             builder: None,
             total_bytes: 0,
+            start: None,
+            end: None,
+            versions_per_key: Histogram::default(),
+            dropped_versions: 0,
+        }
+    }
+
+    /// Returns true if the compaction filter should be invoked on the entry
+    /// with sequence `seq`, which must be the newest one of its user key
+    #[inline]
+    pub fn should_filter(&self, seq: u64) -> bool {
+        match self.newest_snapshot_alive {
+            Some(newest) => seq > newest,
+            None => true,
+        }
+    }
+
+    /// Creates the input of a subcompaction processing the user keys in
+    /// `[start, end)` of the same inputs. The subcompaction has its own outputs
+    /// and the caller is responsible for collecting them back into this
+    /// compaction by `merge_subcompaction`.
+    pub fn new_subcompaction(
+        &self,
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+    ) -> SubcompactionInput {
+        let copy_all =
+            |files: &[Arc<FileMetaData>]| files.iter().map(|f| FileCopy::new(f)).collect();
+        let max_levels = self.options.max_levels as usize;
+        // only the levels deeper than level n + 1 are looked up in the input version
+        let mut deeper_files: Vec<Vec<FileCopy>> = (0..max_levels).map(|_| vec![]).collect();
+        if let Some(v) = &self.input_version {
+            for (level, files) in deeper_files.iter_mut().enumerate().skip(self.level + 2) {
+                *files = copy_all(v.get_level_files(level));
+            }
+        }
+        SubcompactionInput {
+            level: self.level,
+            reason: self.reason,
+            inputs: [copy_all(&self.inputs[0]), copy_all(&self.inputs[1])],
+            grand_parents: copy_all(&self.grand_parents),
+            deeper_files,
+            oldest_snapshot_alive: self.oldest_snapshot_alive,
+            newest_snapshot_alive: self.newest_snapshot_alive,
+            start,
+            end,
+        }
+    }
+
+    /// Takes the outputs of a finished subcompaction. The output file still
+    /// being built after a failure is closed.
+    pub fn into_subcompaction_output(mut self) -> SubcompactionOutput {
+        if let Some(builder) = self.builder.as_mut() {
+            builder.close()
+        }
+        SubcompactionOutput {
+            outputs: self.outputs.iter().map(FileCopy::new).collect(),
+            total_bytes: self.total_bytes,
+            versions_per_key: self.versions_per_key,
+            dropped_versions: self.dropped_versions,
+        }
+    }
+
+    /// Collects the outputs of a subcompaction into this compaction. The
+    /// subcompactions must be merged in the order of their key ranges.
+    pub fn merge_subcompaction(&mut self, sub: SubcompactionOutput) {
+        self.outputs
+            .extend(sub.outputs.iter().map(FileCopy::to_file));
+        self.total_bytes += sub.total_bytes;
+        self.versions_per_key.merge(&sub.versions_per_key);
+        self.dropped_versions += sub.dropped_versions;
+    }
+
+    /// Returns the level the outputs go to. The last level has no next level so
+    /// its files are rewritten in place.
+    #[inline]
+    pub fn output_level(&self) -> usize {
+        (self.level + 1).min(self.options.max_levels as usize - 1)
+    }
+
+    /// Returns at most `n - 1` user keys splitting the key range of the inputs
+    /// into `n` subranges for the subcompactions. Only a level 0 compaction,
+    /// whose inputs can't be split by files, is worth being divided.
+    ///
+    /// The boundaries are picked evenly from the ordered smallest and largest
+    /// user keys of the input files so the subranges roughly contain the
+    /// same number of files.
+    pub fn subcompaction_boundaries(&self, ucmp: &dyn Comparator, n: usize) -> Vec<Vec<u8>> {
+        if self.level != 0 || n <= 1 {
+            return vec![];
+        }
+        let mut keys = vec![];
+        for files in self.inputs.iter() {
+            for f in files.iter() {
+                keys.push(f.smallest.user_key());
+                keys.push(f.largest.user_key());
+            }
+        }
+        keys.sort_by(|a, b| ucmp.compare(a, b));
+        keys.dedup_by(|a, b| ucmp.compare(a, b) == CmpOrdering::Equal);
+        // the smallest and the largest key can't split the range
+        if keys.len() <= 2 {
+            return vec![];
+        }
+        let candidates = &keys[1..keys.len() - 1];
+        let n = n.min(candidates.len() + 1);
+        (1..n)
+            .map(|i| candidates[i * candidates.len() / n].to_vec())
+            .collect()
+    }
+
+    /// Returns true if the user key is behind the range of this (sub)compaction
+    #[inline]
+    pub fn is_after_end(&self, ucmp: &dyn Comparator, ukey: &[u8]) -> bool {
+        match &self.end {
+            Some(end) => ucmp.compare(ukey, end.as_slice()) != CmpOrdering::Less,
+            None => false,
         }
     }
 
     /// Returns the minimal range that covers all entries in `self.inputs[0]`
-    pub fn base_range(&self, icmp: &InternalKeyComparator) -> (Rc<InternalKey>, Rc<InternalKey>) {
+    pub fn base_range(&self, icmp: &InternalKeyComparator) -> (Arc<InternalKey>, Arc<InternalKey>) {
         let files = &self.inputs[CompactionInputsRelation::Source as usize];
         assert!(
             !files.is_empty(),
@@ -147,7 +400,10 @@ impl Compaction {
     }
 
     /// Returns the minimal range that covers all entries in `self.inputs`
-    pub fn total_range(&self, icmp: &InternalKeyComparator) -> (Rc<InternalKey>, Rc<InternalKey>) {
+    pub fn total_range(
+        &self,
+        icmp: &InternalKeyComparator,
+    ) -> (Arc<InternalKey>, Arc<InternalKey>) {
         let (mut smallest, mut largest) = self.base_range(icmp);
         let files = &self.inputs[CompactionInputsRelation::Parent as usize];
         if !files.is_empty() {
@@ -163,10 +419,33 @@ impl Compaction {
         (smallest, largest)
     }
 
+    /// Returns true if no file in the levels deeper than level n + 1 overlaps
+    /// the key range of this compaction
+    pub fn is_bottommost(&self, icmp: &InternalKeyComparator) -> bool {
+        let (smallest, largest) = self.total_range(icmp);
+        let ucmp = icmp.user_comparator.as_ref();
+        let v = self.input_version.as_ref().unwrap();
+        for level in self.level + 2..self.options.max_levels as usize {
+            for f in v.get_level_files(level) {
+                if ucmp.compare(f.largest.user_key(), smallest.user_key()) != CmpOrdering::Less
+                    && ucmp.compare(f.smallest.user_key(), largest.user_key())
+                        != CmpOrdering::Greater
+                {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
     /// Is this a trivial compaction that can be implemented by just
-    /// moving a single input file to the next level (no merging or splitting)
+    /// moving a single input file to the next level (no merging or splitting).
+    /// A file compacted for its deletions or its age is always rewritten.
     pub fn is_trivial_move(&self) -> bool {
-        self.inputs[CompactionInputsRelation::Source as usize].len() == 1
+        self.reason != CompactionReason::BottommostDeletions
+            && self.reason != CompactionReason::Periodic
+            && self.output_level() != self.level
+            && self.inputs[CompactionInputsRelation::Source as usize].len() == 1
             && self.inputs[CompactionInputsRelation::Parent as usize].is_empty()
             && VersionSet::total_file_size(self.grand_parents.as_slice())
                 <= self.options.max_grandparent_overlap_bytes()
@@ -189,7 +468,7 @@ impl Compaction {
         let read_options = Rc::new(ReadOptions {
             verify_checksums: self.options.paranoid_checks,
             fill_cache: false,
-            snapshot: None,
+            ..ReadOptions::default()
         });
         // Level-0 files have to be merged together so we generate a merging iterator includes iterators for each level 0 file.
         // For other levels, we will make a concatenating iterator per level.
@@ -205,15 +484,20 @@ impl Compaction {
                     // level0
                     for file in self.inputs[CompactionInputsRelation::Source as usize].iter() {
                         // all the level0 tables are guaranteed being added into the table_cache via minor compaction
-                        iter_list.push(Rc::new(RefCell::new(table_cache.clone().new_iter(
-                            read_options.clone(),
-                            file.number,
-                            file.file_size,
-                        ))));
+                        iter_list.push(Rc::new(RefCell::new(
+                            table_cache.clone().new_compaction_iter(
+                                read_options.clone(),
+                                file.number,
+                                file.file_size,
+                            ),
+                        )));
                     }
                 } else {
                     let origin = LevelFileNumIterator::new(icmp.clone(), self.inputs[i].clone());
-                    let factory = FileIterFactory::new(read_options.clone(), table_cache.clone());
+                    let factory = FileIterFactory::new_for_compaction(
+                        read_options.clone(),
+                        table_cache.clone(),
+                    );
                     iter_list.push(Rc::new(RefCell::new(Box::new(ConcatenateIterator::new(
                         Box::new(origin),
                         Box::new(factory),
@@ -284,8 +568,10 @@ impl Compaction {
                 self.edit.delete_file(self.level + delta, file.number)
             }
         }
-        for output in self.outputs.drain(..) {
-            self.edit.new_files.push((self.level + 1, Rc::new(output)))
+        for output in self.outputs.iter() {
+            self.edit
+                .new_files
+                .push((self.output_level(), Arc::new(output.clone())))
         }
     }
 
@@ -293,22 +579,24 @@ impl Compaction {
     #[inline]
     pub fn bytes_read(&self) -> u64 {
         self.inputs.iter().fold(0, |accumulate, files| {
-            accumulate + files.iter().fold(0, |sum, file| sum + file.number)
+            accumulate + files.iter().fold(0, |sum, file| sum + file.file_size)
         })
     }
 
     /// Calculate the written bytes
     #[inline]
     pub fn bytes_written(&self) -> u64 {
-        self.outputs.iter().fold(0, |sum, file| sum + file.number)
+        self.outputs
+            .iter()
+            .fold(0, |sum, file| sum + file.file_size)
     }
 }
 
 /// A helper struct for recording the statistics in compactions
 pub struct CompactionStats {
-    micros: u64,
-    bytes_read: u64,
-    bytes_written: u64,
+    pub micros: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
 }
 
 impl CompactionStats {
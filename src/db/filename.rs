@@ -17,8 +17,9 @@
 
 use crate::storage::{do_write_string_to_file, Storage};
 use crate::util::status::Result;
+use std::collections::HashSet;
 use std::ffi::OsStr;
-use std::path::{Path, MAIN_SEPARATOR};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
 use std::sync::Arc;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -27,7 +28,9 @@ pub enum FileType {
     Log,
     /// `LOCK` file. Only one `DB` instance may acquire the file lock.
     Lock,
-    /// `*.sst` file.
+    /// `LOCK.lease` file records the owner of the `LOCK` and when it renewed the lease.
+    LockLease,
+    /// `*.sst` file. See `TableFileNaming` for the customized names.
     Table,
     /// `MANIFEST-*` file.
     Manifest,
@@ -47,6 +50,7 @@ pub fn generate_filename(dirname: &str, filetype: FileType, seq: u64) -> String
     match filetype {
         FileType::Log => format!("{}{}{:06}.log", dirname, MAIN_SEPARATOR, seq),
         FileType::Lock => format!("{}{}LOCK", dirname, MAIN_SEPARATOR),
+        FileType::LockLease => format!("{}{}LOCK.lease", dirname, MAIN_SEPARATOR),
         FileType::Table => format!("{}{}{:06}.sst", dirname, MAIN_SEPARATOR, seq),
         FileType::Manifest => format!("{}{}MANIFEST-{:06}", dirname, MAIN_SEPARATOR, seq),
         FileType::Current => format!("{}{}CURRENT", dirname, MAIN_SEPARATOR),
@@ -64,7 +68,11 @@ pub fn parse_filename<P: AsRef<Path>>(filename: P) -> Option<(FileType, u64)> {
     let file_stem = path.file_stem().unwrap_or_else(|| OsStr::new(invalid));
     match file_stem.to_str() {
         Some("CURRENT") => Some((FileType::Current, 0)),
-        Some("LOCK") => Some((FileType::Lock, 0)),
+        Some("LOCK") => match path.file_name().unwrap_or_else(|| OsStr::new("")).to_str() {
+            Some("LOCK") => Some((FileType::Lock, 0)),
+            Some("LOCK.lease") => Some((FileType::LockLease, 0)),
+            _ => None,
+        },
         Some("LOG") => match path.file_name().unwrap_or_else(|| OsStr::new("")).to_str() {
             Some("LOG") => Some((FileType::InfoLog, 0)),
             Some("LOG.old") => Some((FileType::OldInfoLog, 0)),
@@ -107,14 +115,161 @@ pub fn parse_filename<P: AsRef<Path>>(filename: P) -> Option<(FileType, u64)> {
     }
 }
 
+/// The naming scheme of the table files.
+///
+/// The default scheme names a table like `000123.sst` directly in the db
+/// directory. Since a directory containing hundreds of thousands of files hurts
+/// some filesystems, the tables can be sharded into the subdirectories `000`,
+/// `001`, ... by their file numbers.
+///
+/// The tables named by another scheme, e.g. created before the scheme is
+/// changed, are still found when the db is opened.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableFileNaming {
+    /// The extension of the table files without the leading dot.
+    /// It can't be `log` or `dbtmp`.
+    /// Default: "sst"
+    pub extension: String,
+    /// The file number is padded with zeros to at least this many digits.
+    /// Default: 6
+    pub number_width: usize,
+    /// The number of the subdirectories the tables are sharded into by
+    /// `file number % shards`. Zero means no sharding.
+    /// Default: 0
+    pub shards: u64,
+}
+
+impl Default for TableFileNaming {
+    fn default() -> Self {
+        Self {
+            extension: "sst".to_owned(),
+            number_width: 6,
+            shards: 0,
+        }
+    }
+}
+
+impl TableFileNaming {
+    /// Returns true if the scheme doesn't conflict with the other files of db
+    pub fn is_valid(&self) -> bool {
+        !self.extension.is_empty()
+            && self.extension != "log"
+            && self.extension != "dbtmp"
+            && !self.extension.contains(&['.', '/', MAIN_SEPARATOR][..])
+            && self.shards <= 1000
+    }
+
+    /// Returns the shard directory of the table `number` or `None` if the
+    /// tables are not sharded
+    pub fn shard_dir(&self, dirname: &str, number: u64) -> Option<String> {
+        if self.shards == 0 {
+            None
+        } else {
+            Some(format!(
+                "{}{}{:03}",
+                dirname,
+                MAIN_SEPARATOR,
+                number % self.shards
+            ))
+        }
+    }
+
+    /// Returns the path of the table `number` named by this scheme
+    pub fn table_file_name(&self, dirname: &str, number: u64) -> String {
+        let dir = match self.shard_dir(dirname, number) {
+            Some(dir) => dir,
+            None => dirname.to_owned(),
+        };
+        format!(
+            "{}{}{:0width$}.{}",
+            dir,
+            MAIN_SEPARATOR,
+            number,
+            self.extension,
+            width = self.number_width
+        )
+    }
+
+    /// Returns the number of the table at `path` named by any scheme, i.e.
+    /// a number with an extension which is not of the logs or temp files.
+    pub fn parse_table_number<P: AsRef<Path>>(&self, path: P) -> Option<u64> {
+        let path = path.as_ref();
+        let ext = path.extension()?.to_str()?;
+        if ext.is_empty() || ext == "log" || ext == "dbtmp" {
+            return None;
+        }
+        let stem = path.file_stem()?.to_str()?;
+        if stem.is_empty() || !stem.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        stem.parse::<u64>().ok()
+    }
+
+    /// Creates all the shard directories
+    pub fn create_dirs(&self, env: &dyn Storage, dirname: &str) -> Result<()> {
+        for shard in 0..self.shards {
+            env.mkdir_all(self.shard_dir(dirname, shard).unwrap().as_str())?;
+        }
+        Ok(())
+    }
+
+    /// Lists all the table files in `dirname` and the shard directories of
+    /// any scheme. Returns the file numbers with the paths.
+    pub fn list_table_files(
+        &self,
+        env: &dyn Storage,
+        dirname: &str,
+    ) -> Result<Vec<(u64, PathBuf)>> {
+        let root = Path::new(dirname);
+        // a table lives in the db directory or a shard directory of it
+        let in_table_dir = |path: &Path| match path.parent() {
+            Some(dir) => dir == root || (dir.parent() == Some(root) && is_shard_dir_name(dir)),
+            None => false,
+        };
+        let mut tables = vec![];
+        let mut seen = HashSet::new();
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            // a storage may list the files in the subdirectories or other dbs
+            for path in env.list(dir.to_str().unwrap_or(dirname))? {
+                if !in_table_dir(&path) || !seen.insert(path.clone()) {
+                    continue;
+                }
+                if let Some(number) = self.parse_table_number(&path) {
+                    tables.push((number, path));
+                } else if dir == root && is_shard_dir_name(&path) {
+                    dirs.push(path);
+                }
+            }
+        }
+        Ok(tables)
+    }
+}
+
+// A shard directory is named by 3 digits
+fn is_shard_dir_name(path: &Path) -> bool {
+    path.extension().is_none()
+        && path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| {
+                name.len() == 3 && name.bytes().all(|b| b.is_ascii_digit())
+            })
+}
+
 /// Update the CURRENT file to point to new MANIFEST file
-pub fn update_current(env: Arc<dyn Storage>, dbname: &str, manifest_file_num: u64) -> Result<()> {
+pub fn update_current(
+    env: Arc<dyn Storage>,
+    dbname: &str,
+    manifest_file_num: u64,
+    sync: bool,
+) -> Result<()> {
     // Remove leading "dbname/" and add newline to manifest file nam
     let mut manifest = generate_filename(dbname, FileType::Manifest, manifest_file_num);
     manifest.drain(0..=dbname.len());
     // write into tmp first then rename it as CURRENT
     let tmp = generate_filename(dbname, FileType::Temp, manifest_file_num);
-    let result = do_write_string_to_file(env.clone(), manifest, tmp.as_str(), true);
+    let result = do_write_string_to_file(env.clone(), manifest, tmp.as_str(), sync);
     match &result {
         Ok(()) => env.rename(
             tmp.as_str(),
@@ -136,6 +291,7 @@ mod tests {
             vec![
                 (FileType::Log, 10, "test\\000010.log"),
                 (FileType::Lock, 1, "test\\LOCK"),
+                (FileType::LockLease, 1, "test\\LOCK.lease"),
                 (FileType::Table, 123, "test\\000123.sst"),
                 (FileType::Manifest, 9, "test\\MANIFEST-000009"),
                 (FileType::Current, 1, "test\\CURRENT"),
@@ -147,6 +303,7 @@ mod tests {
             vec![
                 (FileType::Log, 10, "test/000010.log"),
                 (FileType::Lock, 1, "test/LOCK"),
+                (FileType::LockLease, 1, "test/LOCK.lease"),
                 (FileType::Table, 123, "test/000123.sst"),
                 (FileType::Manifest, 9, "test/MANIFEST-000009"),
                 (FileType::Current, 1, "test/CURRENT"),
@@ -162,12 +319,49 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_table_file_naming() {
+        let naming = TableFileNaming::default();
+        assert!(naming.is_valid());
+        assert_eq!(
+            naming.table_file_name("test", 123),
+            generate_filename("test", FileType::Table, 123)
+        );
+        let naming = TableFileNaming {
+            extension: "ldb".to_owned(),
+            number_width: 10,
+            shards: 16,
+        };
+        assert!(naming.is_valid());
+        let name = naming.table_file_name("test", 123);
+        let expect = if cfg!(windows) {
+            "test\\011\\0000000123.ldb"
+        } else {
+            "test/011/0000000123.ldb"
+        };
+        assert_eq!(name.as_str(), expect);
+        assert_eq!(naming.parse_table_number(name), Some(123));
+        assert_eq!(naming.parse_table_number("test/000123.sst"), Some(123));
+        assert_eq!(naming.parse_table_number("test/000123.log"), None);
+        assert_eq!(naming.parse_table_number("test/000123.dbtmp"), None);
+        assert_eq!(naming.parse_table_number("test/LOG.old"), None);
+        assert_eq!(naming.parse_table_number("test/abc.ldb"), None);
+        for extension in &["", "log", "dbtmp", "a.b"] {
+            let naming = TableFileNaming {
+                extension: extension.to_string(),
+                ..TableFileNaming::default()
+            };
+            assert!(!naming.is_valid());
+        }
+    }
+
     #[test]
     fn test_parse_filename() {
         let mut tests = if cfg!(windows) {
             vec![
                 ("a\\b\\c\\000123.log", Some((FileType::Log, 123))),
                 ("a\\b\\c\\LOCK", Some((FileType::Lock, 0))),
+                ("a\\b\\c\\LOCK.lease", Some((FileType::LockLease, 0))),
                 ("a\\b\\c\\010666.sst", Some((FileType::Table, 10666))),
                 ("a\\b\\c\\MANIFEST-000009", Some((FileType::Manifest, 9))),
                 ("a\\b\\c\\000123.dbtmp", Some((FileType::Temp, 123))),
@@ -186,6 +380,8 @@ mod tests {
             vec![
                 ("a/b/c/000123.log", Some((FileType::Log, 123))),
                 ("a/b/c/LOCK", Some((FileType::Lock, 0))),
+                ("a/b/c/LOCK.lease", Some((FileType::LockLease, 0))),
+                ("a/b/c/LOCK.new", None),
                 ("a/b/c/010666.sst", Some((FileType::Table, 10666))),
                 ("a/b/c/MANIFEST-000009", Some((FileType::Manifest, 9))),
                 ("a/b/c/000123.dbtmp", Some((FileType::Temp, 123))),
@@ -22,7 +22,6 @@ use crate::util::slice::Slice;
 use crate::util::varint::VarintU32;
 use std::cmp::Ordering;
 use std::fmt::{Debug, Error, Formatter};
-use std::rc::Rc;
 use std::sync::Arc;
 
 /// The max key sequence number. The value is 2^56 - 1 because the seq number
@@ -298,18 +297,43 @@ impl Comparator for InternalKeyComparator {
         "leveldb.InternalKeyComparator"
     }
 
-    fn separator(&self, _a: &[u8], _b: &[u8]) -> Vec<u8> {
-        unimplemented!()
+    fn separator(&self, a: &[u8], b: &[u8]) -> Vec<u8> {
+        let ua = extract_user_key(a);
+        let ub = extract_user_key(b);
+        let mut sep = self.user_comparator.separator(ua.as_slice(), ub.as_slice());
+        if sep.len() < ua.size()
+            && self.user_comparator.compare(ua.as_slice(), sep.as_slice()) == Ordering::Less
+        {
+            // User key has become shorter physically, but larger logically.
+            // Tack on the earliest possible number to the shortened user key.
+            put_fixed_64(
+                &mut sep,
+                pack_seq_and_type(MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK),
+            );
+            return sep;
+        }
+        a.to_vec()
     }
 
-    fn successor(&self, _s: &[u8]) -> Vec<u8> {
-        unimplemented!()
+    fn successor(&self, s: &[u8]) -> Vec<u8> {
+        let us = extract_user_key(s);
+        let mut succ = self.user_comparator.successor(us.as_slice());
+        if succ.len() < us.size()
+            && self.user_comparator.compare(us.as_slice(), succ.as_slice()) == Ordering::Less
+        {
+            put_fixed_64(
+                &mut succ,
+                pack_seq_and_type(MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK),
+            );
+            return succ;
+        }
+        s.to_vec()
     }
 }
 
 /// A wrapper for the internal key filter policy
 pub struct InternalFilterPolicy {
-    user_policy: Rc<dyn FilterPolicy>,
+    user_policy: Arc<dyn FilterPolicy>,
 }
 
 impl FilterPolicy for InternalFilterPolicy {
@@ -372,6 +396,7 @@ fn pack_seq_and_type(seq: u64, v_type: ValueType) -> u64 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::util::comparator::BytewiseComparator;
 
     #[test]
     fn test_pack_seq_and_type() {
@@ -429,4 +454,52 @@ mod tests {
             }
         }
     }
+
+    fn ikey(key: &str, seq: u64, vt: ValueType) -> Vec<u8> {
+        InternalKey::new(&Slice::from(key), seq, vt).data().to_vec()
+    }
+
+    fn shorten(a: &[u8], b: &[u8]) -> Vec<u8> {
+        InternalKeyComparator::new(Arc::new(BytewiseComparator::new())).separator(a, b)
+    }
+
+    #[test]
+    fn test_internal_key_short_separator() {
+        // When user keys are same
+        let k = ikey("foo", 100, ValueType::Value);
+        for b in [
+            ikey("foo", 99, ValueType::Value),
+            ikey("foo", 101, ValueType::Value),
+            ikey("foo", 100, ValueType::Value),
+            ikey("foo", 100, ValueType::Deletion),
+            // When user keys are misordered
+            ikey("bar", 99, ValueType::Value),
+            // When user keys are different, but correctly ordered
+            // but the prefix can not be shortened
+            ikey("foobar", 200, ValueType::Value),
+            ikey("foo", 200, ValueType::Value),
+        ]
+        .iter()
+        {
+            assert_eq!(k, shorten(k.as_slice(), b.as_slice()));
+        }
+        assert_eq!(
+            ikey("g", MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK),
+            shorten(
+                ikey("foo", 100, ValueType::Value).as_slice(),
+                ikey("hello", 200, ValueType::Value).as_slice()
+            )
+        );
+    }
+
+    #[test]
+    fn test_internal_key_short_successor() {
+        let icmp = InternalKeyComparator::new(Arc::new(BytewiseComparator::new()));
+        assert_eq!(
+            ikey("g", MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK),
+            icmp.successor(ikey("foo", 100, ValueType::Value).as_slice())
+        );
+        let k = InternalKey::new(&Slice::from(&[0xff, 0xff][..]), 100, ValueType::Value);
+        assert_eq!(k.data(), icmp.successor(k.data()).as_slice());
+    }
 }
@@ -12,14 +12,22 @@
 // limitations under the License.
 
 use crate::db::format::ValueType;
-use crate::db::format::{extract_user_key, ParsedInternalKey, VALUE_TYPE_FOR_SEEK};
+use crate::db::format::{
+    extract_user_key, ParsedInternalKey, MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK,
+};
 use crate::db::DBImpl;
-use crate::iterator::Iterator;
+use crate::iterator::{Iterator, MergingIterator};
+use crate::options::{CancellationToken, ReadOptions};
 use crate::util::comparator::Comparator;
 use crate::util::slice::Slice;
 use crate::util::status::{Result, Status, WickErr};
+use crate::version::Version;
 use rand::Rng;
+use std::cell::{Cell, RefCell};
 use std::cmp::Ordering;
+use std::mem;
+use std::rc::Rc;
+use std::sync::atomic::Ordering as AtomicOrdering;
 use std::sync::Arc;
 
 #[derive(Eq, PartialEq)]
@@ -40,6 +48,8 @@ enum Direction {
 pub struct DBIterator {
     valid: bool,
     db: Arc<DBImpl>,
+    // Keeps the table files read by `inner` from being deleted
+    _version: Arc<Version>,
     ucmp: Arc<dyn Comparator>,
     // The newest sequence acquired.
     // Any key newer than this will be ignored
@@ -50,15 +60,31 @@ pub struct DBIterator {
     // used for randomly picking a yielded key to record read stats
     bytes_util_read_sampling: u64,
 
-    // If we guarantee that the inner iterator's lifetime is not shorter than DBIterator, we
-    // could use Slice instead Vec<u8> for saved_key and saved_value here.
-    // (This seems to be sure because we owns the Arc<DBImpl> and inner iter's lifecycle
-    // is depending on DBImpl)
+    // `saved_key` and `saved_value` are owned copies because the block the inner iter
+    // points at could be released once the inner iter moves to another block.
 
     // Current key when direction is Reverse
-    saved_key: Slice,
+    saved_key: Vec<u8>,
     // Current value when direction is Reverse
-    saved_value: Slice,
+    saved_value: Vec<u8>,
+
+    cancel_token: Option<CancellationToken>,
+    cancel_check_interval: usize,
+    // The number of the internal entries to step over until the next check of `cancel_token`
+    steps_until_cancel_check: usize,
+
+    // The user keys out of [lower_bound, upper_bound) are never yielded
+    lower_bound: Option<Vec<u8>>,
+    upper_bound: Option<Vec<u8>>,
+
+    // The options `inner` is built with, which is `None` if the iterator reads
+    // a snapshot and is unable to be refreshed
+    read_opt: Option<ReadOptions>,
+    // Whether the values are skipped, see `ReadOptions::keys_only` and `value_size_only`
+    keys_only: bool,
+    value_size_only: bool,
+    // The size of the value when direction is Reverse
+    saved_value_size: usize,
 }
 
 impl Iterator for DBIterator {
@@ -69,6 +95,14 @@ impl Iterator for DBIterator {
     fn seek_to_first(&mut self) {
         self.direction = Direction::Forward;
         self.saved_value.clear();
+        if self.cancelled_before_seek() {
+            return;
+        }
+        if let Some(lower) = &self.lower_bound {
+            let target = Slice::from(lower.as_slice());
+            self.seek(&target);
+            return;
+        }
         self.inner.seek_to_first();
         if self.inner.valid() {
             self.find_next_user_entry(false);
@@ -80,7 +114,28 @@ impl Iterator for DBIterator {
     fn seek_to_last(&mut self) {
         self.direction = Direction::Reverse;
         self.saved_value.clear();
-        self.inner.seek_to_last();
+        if self.cancelled_before_seek() {
+            self.direction = Direction::Forward;
+            return;
+        }
+        match &self.upper_bound {
+            Some(upper) => {
+                // the last entry before the upper bound
+                let ikey = ParsedInternalKey::new(
+                    Slice::from(upper.as_slice()),
+                    MAX_KEY_SEQUENCE,
+                    VALUE_TYPE_FOR_SEEK,
+                )
+                .encode();
+                self.inner.seek(&Slice::from(ikey.data()));
+                if self.inner.valid() {
+                    self.inner.prev();
+                } else {
+                    self.inner.seek_to_last();
+                }
+            }
+            None => self.inner.seek_to_last(),
+        }
         self.find_prev_user_key();
     }
 
@@ -88,25 +143,31 @@ impl Iterator for DBIterator {
         self.direction = Direction::Forward;
         self.saved_value.clear();
         self.saved_key.clear();
-        let ikey =
-            ParsedInternalKey::new(target.clone(), self.sequence, VALUE_TYPE_FOR_SEEK).encode();
-        self.saved_key = Slice::from(ikey.data());
-        self.inner.seek(&self.saved_key);
+        if self.cancelled_before_seek() {
+            return;
+        }
+        let target = match &self.lower_bound {
+            Some(lower) if self.ucmp.compare(target.as_slice(), lower) == Ordering::Less => {
+                Slice::from(lower.as_slice())
+            }
+            _ => target.clone(),
+        };
+        let ikey = ParsedInternalKey::new(target, self.sequence, VALUE_TYPE_FOR_SEEK).encode();
+        self.inner.seek(&Slice::from(ikey.data()));
         if self.inner.valid() {
             self.find_next_user_entry(false)
         } else {
             self.valid = false;
         }
-        self.saved_key.clear(); // avoid dangling ptr
     }
 
     fn next(&mut self) {
         self.valid_or_panic();
         match self.direction {
             Direction::Forward => {
-                self.saved_key = extract_user_key(self.inner.key().as_slice());
+                self.save_key(extract_user_key(self.inner.key().as_slice()));
                 self.inner.next();
-                if !self.inner.valid() {
+                if !self.inner.valid() || self.check_cancelled() {
                     self.valid = false;
                     self.saved_key.clear();
                     return;
@@ -118,7 +179,7 @@ impl Iterator for DBIterator {
                 // so advance into the range of entries for inner.key() and then
                 // use the normal skipping code below
                 if !self.inner.valid() {
-                    self.inner.seek_to_first();
+                    self.seek_inner_to_first();
                 } else {
                     self.inner.next()
                 }
@@ -136,10 +197,10 @@ impl Iterator for DBIterator {
         // inner iter is pointing at the current entry.  Scan backwards until
         // the key changes so we can use the normal reverse scanning code.
         if self.direction == Direction::Forward {
-            self.saved_key = extract_user_key(self.inner.key().as_slice());
+            self.save_key(extract_user_key(self.inner.key().as_slice()));
             loop {
                 self.inner.prev();
-                if !self.inner.valid() {
+                if !self.inner.valid() || self.check_cancelled() {
                     self.valid = false;
                     self.saved_key.clear();
                     self.saved_value.clear();
@@ -162,15 +223,29 @@ impl Iterator for DBIterator {
         self.valid_or_panic();
         match self.direction {
             Direction::Forward => extract_user_key(self.inner.key().as_slice()),
-            Direction::Reverse => self.saved_key.clone(),
+            Direction::Reverse => Slice::from(self.saved_key.as_slice()),
         }
     }
 
     fn value(&self) -> Slice {
         self.valid_or_panic();
+        if self.keys_only || self.value_size_only {
+            return Slice::from(&[][..]);
+        }
         match self.direction {
             Direction::Forward => self.inner.value(),
-            Direction::Reverse => self.saved_value.clone(),
+            Direction::Reverse => Slice::from(self.saved_value.as_slice()),
+        }
+    }
+
+    fn value_size(&self) -> usize {
+        self.valid_or_panic();
+        if self.keys_only {
+            return 0;
+        }
+        match self.direction {
+            Direction::Forward => self.inner.value().size(),
+            Direction::Reverse => self.saved_value_size,
         }
     }
 
@@ -181,26 +256,192 @@ impl Iterator for DBIterator {
             self.inner.status()
         }
     }
+
+    fn refresh(&mut self) -> Result<()> {
+        let read_opt = match &self.read_opt {
+            Some(read_opt) => read_opt.clone(),
+            None => {
+                return Err(WickErr::new(
+                    Status::NotSupported,
+                    Some("an iterator reading a snapshot is not refreshable"),
+                ))
+            }
+        };
+        let position = if self.valid {
+            Some(self.key().as_slice().to_vec())
+        } else {
+            None
+        };
+        let sequence = self.db.read_sequence(&read_opt);
+        let (inner, version) = self.db.internal_iter(read_opt);
+        // The old version is released along with the tables only it refers to
+        self.inner = Box::new(inner);
+        self._version = version;
+        self.reset(sequence);
+        if let Some(key) = position {
+            self.seek(&Slice::from(key.as_slice()));
+        }
+        Ok(())
+    }
 }
 
 impl DBIterator {
     pub fn new(
         iter: Box<dyn Iterator>,
         db: Arc<DBImpl>,
+        version: Arc<Version>,
         sequence: u64,
         ucmp: Arc<dyn Comparator>,
     ) -> Self {
         Self {
             valid: false,
             db: db.clone(),
+            _version: version,
             ucmp,
             sequence,
             err: None,
             inner: iter,
             direction: Direction::Forward,
-            bytes_util_read_sampling: Self::random_compaction_period(db.options.read_bytes_period),
+            bytes_util_read_sampling: Self::random_compaction_period(Self::read_bytes_period(&db)),
             saved_key: Default::default(),
             saved_value: Default::default(),
+            cancel_token: None,
+            cancel_check_interval: 0,
+            steps_until_cancel_check: 0,
+            lower_bound: None,
+            upper_bound: None,
+            read_opt: None,
+            keys_only: false,
+            value_size_only: false,
+            saved_value_size: 0,
+        }
+    }
+
+    /// Applies `keys_only` and `value_size_only` of `read_opt`, and makes the iterator
+    /// able to be refreshed by building the internal iterator again with `read_opt`
+    /// unless it reads a snapshot
+    pub fn with_read_options(mut self, read_opt: &ReadOptions) -> Self {
+        self.set_read_options(read_opt);
+        self
+    }
+
+    fn set_read_options(&mut self, read_opt: &ReadOptions) {
+        self.keys_only = read_opt.keys_only;
+        self.value_size_only = read_opt.value_size_only;
+        self.read_opt = match read_opt.snapshot {
+            Some(_) => None,
+            None => Some(read_opt.clone()),
+        };
+    }
+
+    /// Makes the iterator only yield the user keys in `[lower, upper)`
+    pub fn with_bounds(mut self, lower: Option<Vec<u8>>, upper: Option<Vec<u8>>) -> Self {
+        self.set_bounds(lower, upper);
+        self
+    }
+
+    fn set_bounds(&mut self, lower: Option<Vec<u8>>, upper: Option<Vec<u8>>) {
+        self.lower_bound = lower;
+        self.upper_bound = upper;
+    }
+
+    // Points the inner iter to the first entry not less than the lower bound
+    fn seek_inner_to_first(&mut self) {
+        match &self.lower_bound {
+            Some(lower) => {
+                let ikey = ParsedInternalKey::new(
+                    Slice::from(lower.as_slice()),
+                    MAX_KEY_SEQUENCE,
+                    VALUE_TYPE_FOR_SEEK,
+                )
+                .encode();
+                self.inner.seek(&Slice::from(ikey.data()));
+            }
+            None => self.inner.seek_to_first(),
+        }
+    }
+
+    #[inline]
+    fn reach_upper_bound(&self, user_key: &Slice) -> bool {
+        match &self.upper_bound {
+            Some(upper) => self.ucmp.compare(user_key.as_slice(), upper) != Ordering::Less,
+            None => false,
+        }
+    }
+
+    #[inline]
+    fn below_lower_bound(&self, user_key: &Slice) -> bool {
+        match &self.lower_bound {
+            Some(lower) => self.ucmp.compare(user_key.as_slice(), lower) == Ordering::Less,
+            None => false,
+        }
+    }
+
+    /// Makes the iterator give up with `Status::Cancelled` once `token` is cancelled.
+    /// The token is checked at every seek and every `check_interval` internal entries
+    /// stepped over.
+    pub fn with_cancel_token(
+        mut self,
+        token: Option<CancellationToken>,
+        check_interval: usize,
+    ) -> Self {
+        self.set_cancel_token(token, check_interval);
+        self
+    }
+
+    fn set_cancel_token(&mut self, token: Option<CancellationToken>, check_interval: usize) {
+        self.cancel_token = token;
+        self.cancel_check_interval = check_interval.max(1);
+        self.steps_until_cancel_check = self.cancel_check_interval;
+    }
+
+    // Prepares the iterator for another scan at `sequence`. The buffers are cleared
+    // but keep their capacity.
+    fn reset(&mut self, sequence: u64) {
+        self.valid = false;
+        self.sequence = sequence;
+        self.err = None;
+        self.direction = Direction::Forward;
+        self.saved_key.clear();
+        self.saved_value.clear();
+    }
+
+    // Counts an internal entry stepped over and checks `cancel_token` if a check point
+    // is reached. Returns true and records the error if the iteration is cancelled.
+    fn check_cancelled(&mut self) -> bool {
+        if self.cancel_token.is_none() {
+            return false;
+        }
+        self.steps_until_cancel_check -= 1;
+        if self.steps_until_cancel_check > 0 {
+            return false;
+        }
+        self.steps_until_cancel_check = self.cancel_check_interval;
+        self.record_if_cancelled()
+    }
+
+    // Invalidates the iterator without seeking if `cancel_token` is cancelled
+    fn cancelled_before_seek(&mut self) -> bool {
+        if self.record_if_cancelled() {
+            self.valid = false;
+            self.saved_key.clear();
+            self.saved_value.clear();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn record_if_cancelled(&mut self) -> bool {
+        match &self.cancel_token {
+            Some(token) if token.is_cancelled() => {
+                self.err = Some(WickErr::new(
+                    Status::Cancelled,
+                    Some("the iteration is cancelled"),
+                ));
+                true
+            }
+            _ => false,
         }
     }
 
@@ -209,6 +450,12 @@ impl DBIterator {
         assert!(self.valid(), "invalid iterator")
     }
 
+    #[inline]
+    fn save_key(&mut self, key: Slice) {
+        self.saved_key.clear();
+        self.saved_key.extend_from_slice(key.as_slice());
+    }
+
     // Parse internal key from inner iterator into a ParsedInternalKey
     // otherwise records a corruption error
     fn parse_key(&mut self) -> Option<ParsedInternalKey> {
@@ -216,7 +463,7 @@ impl DBIterator {
         let bytes_read = k.size() + self.inner.value().size();
         while self.bytes_util_read_sampling < bytes_read as u64 {
             self.bytes_util_read_sampling +=
-                Self::random_compaction_period(self.db.options.read_bytes_period);
+                Self::random_compaction_period(Self::read_bytes_period(&self.db));
             self.db.record_read_sample(k.clone());
         }
         self.bytes_util_read_sampling -= bytes_read as u64;
@@ -235,6 +482,9 @@ impl DBIterator {
     fn find_next_user_entry(&mut self, mut skipping: bool) {
         loop {
             if let Some(pkey) = self.parse_key() {
+                if self.reach_upper_bound(&pkey.user_key) {
+                    break;
+                }
                 if pkey.seq <= self.sequence {
                     match pkey.value_type {
                         ValueType::Value => {
@@ -257,7 +507,7 @@ impl DBIterator {
                         ValueType::Deletion => {
                             // Arrange to skip all upcoming entries for this key since
                             // they are hidden by this deletion.
-                            self.saved_key = pkey.user_key.clone();
+                            self.save_key(pkey.user_key);
                             skipping = true;
                         }
                         _ => { /* ignore the unknown value type */ }
@@ -265,7 +515,7 @@ impl DBIterator {
                 }
             }
             self.inner.next();
-            if !self.inner.valid() {
+            if !self.inner.valid() || self.check_cancelled() {
                 break;
             }
         }
@@ -284,6 +534,10 @@ impl DBIterator {
         if self.inner.valid() {
             loop {
                 if let Some(pkey) = self.parse_key() {
+                    if self.below_lower_bound(&pkey.user_key) {
+                        // the entries before are all below the bound too
+                        break;
+                    }
                     if pkey.seq <= self.sequence {
                         if value_type == ValueType::Value
                             && self
@@ -302,9 +556,14 @@ impl DBIterator {
                             }
                             ValueType::Value => {
                                 // record the current key for later comparing
-                                self.saved_key = extract_user_key(self.inner.key().as_slice());
+                                self.save_key(pkey.user_key);
                                 // record the current value for later yielding
-                                self.saved_value = self.inner.value();
+                                let value = self.inner.value();
+                                self.saved_value_size = value.size();
+                                self.saved_value.clear();
+                                if !self.keys_only && !self.value_size_only {
+                                    self.saved_value.extend_from_slice(value.as_slice());
+                                }
                             }
                             _ => { /* ignore the unknown value type */ }
                         }
@@ -314,6 +573,10 @@ impl DBIterator {
                 if !self.inner.valid() {
                     break;
                 }
+                if self.check_cancelled() {
+                    value_type = ValueType::Deletion;
+                    break;
+                }
             }
         }
         if value_type != ValueType::Value {
@@ -327,8 +590,416 @@ impl DBIterator {
         }
     }
 
+    #[inline]
+    fn read_bytes_period(db: &DBImpl) -> u64 {
+        if db.options.seek_compaction {
+            db.options.read_bytes_period
+        } else {
+            0
+        }
+    }
+
     // Picks the number of bytes that can be read until a compaction is scheduled
     fn random_compaction_period(read_bytes_period: u64) -> u64 {
+        if read_bytes_period == 0 {
+            // never samples
+            return u64::max_value();
+        }
         rand::thread_rng().gen_range(0, 2 * read_bytes_period)
     }
 }
+
+// An idle iterator in `IteratorPool` and the state its internal iterator is built on
+struct IdleIterator {
+    iter: DBIterator,
+    memtable_generation: u64,
+    verify_checksums: bool,
+    fill_cache: bool,
+    ignore_filters: bool,
+    bounds: (Option<Vec<u8>>, Option<Vec<u8>>),
+}
+
+/// A pool of the iterators dropped by the short scans, created by
+/// `WickDB::iterator_pool`.
+///
+/// An iterator taken from the pool reuses the whole tree of the child iterators
+/// of an idle one if the memtables and the version it's built on are still the
+/// current ones and it has the same `verify_checksums`, `fill_cache`,
+/// `ignore_filters` and iterate bounds.
+/// Otherwise only the scratch buffers of an idle iterator are reused. The
+/// snapshot and the cancel token of the `ReadOptions` are applied on every reuse.
+///
+/// The idle iterators keep their version alive, which delays the deletion of
+/// the obsolete table files until they are replaced. A pool is used by a single
+/// thread.
+pub struct IteratorPool {
+    db: Arc<DBImpl>,
+    max_idle: usize,
+    idle: RefCell<Vec<IdleIterator>>,
+    created: Cell<usize>,
+    reused: Cell<usize>,
+}
+
+impl IteratorPool {
+    pub(crate) fn new(db: Arc<DBImpl>, max_idle: usize) -> Self {
+        Self {
+            db,
+            max_idle,
+            idle: RefCell::new(vec![]),
+            created: Cell::new(0),
+            reused: Cell::new(0),
+        }
+    }
+
+    /// Returns an iterator over the contents of the database like `DB::iter`,
+    /// which goes back to the pool when dropped
+    pub fn iter(&self, mut read_opt: ReadOptions) -> PooledIterator<'_> {
+        self.db.apply_mutable_options(&mut read_opt);
+        let ignore_filters = read_opt.ignore_filters;
+        let sequence = self.db.read_sequence(&read_opt);
+        let memtable_generation = self.db.memtable_generation.load(AtomicOrdering::Acquire);
+        let current = self.db.versions.lock().unwrap().current();
+        let (verify_checksums, fill_cache) = (read_opt.verify_checksums, read_opt.fill_cache);
+        let bounds = (
+            read_opt.iterate_lower_bound.clone(),
+            read_opt.iterate_upper_bound.clone(),
+        );
+        let (cancel_token, cancel_check_interval) = (
+            read_opt.cancel_token.clone(),
+            read_opt.cancel_check_interval,
+        );
+        let refresh_opt = read_opt.clone();
+        let mut idle = self.idle.borrow_mut();
+        let reusable = idle.iter().position(|i| {
+            i.memtable_generation == memtable_generation
+                && Arc::ptr_eq(&i.iter._version, &current)
+                && i.verify_checksums == verify_checksums
+                && i.fill_cache == fill_cache
+                && i.ignore_filters == ignore_filters
+                && i.bounds == bounds
+        });
+        let mut iter = match reusable {
+            Some(pos) => {
+                self.reused.set(self.reused.get() + 1);
+                idle.swap_remove(pos).iter
+            }
+            None => {
+                self.created.set(self.created.get() + 1);
+                let (inner, version) = self.db.internal_iter(read_opt);
+                match idle.pop() {
+                    Some(IdleIterator { mut iter, .. }) => {
+                        iter.inner = Box::new(inner);
+                        iter._version = version;
+                        iter
+                    }
+                    None => DBIterator::new(
+                        Box::new(inner),
+                        self.db.clone(),
+                        version,
+                        sequence,
+                        self.db.internal_comparator.user_comparator.clone(),
+                    ),
+                }
+            }
+        };
+        iter.reset(sequence);
+        iter.set_cancel_token(cancel_token, cancel_check_interval);
+        iter.set_bounds(bounds.0.clone(), bounds.1.clone());
+        iter.set_read_options(&refresh_opt);
+        PooledIterator {
+            pool: self,
+            iter: Some(iter),
+            memtable_generation,
+            verify_checksums,
+            fill_cache,
+            ignore_filters,
+            bounds,
+        }
+    }
+
+    /// Returns the number of the iterators whose child iterators are built anew
+    pub fn num_created(&self) -> usize {
+        self.created.get()
+    }
+
+    /// Returns the number of the iterators reusing the child iterators of an idle one
+    pub fn num_reused(&self) -> usize {
+        self.reused.get()
+    }
+
+    /// Returns the number of the idle iterators in the pool
+    pub fn num_idle(&self) -> usize {
+        self.idle.borrow().len()
+    }
+}
+
+/// An iterator taken from an `IteratorPool`
+pub struct PooledIterator<'a> {
+    pool: &'a IteratorPool,
+    // always `Some` until dropped
+    iter: Option<DBIterator>,
+    memtable_generation: u64,
+    verify_checksums: bool,
+    fill_cache: bool,
+    ignore_filters: bool,
+    bounds: (Option<Vec<u8>>, Option<Vec<u8>>),
+}
+
+impl<'a> PooledIterator<'a> {
+    #[inline]
+    fn iter(&self) -> &DBIterator {
+        self.iter.as_ref().unwrap()
+    }
+
+    #[inline]
+    fn iter_mut(&mut self) -> &mut DBIterator {
+        self.iter.as_mut().unwrap()
+    }
+}
+
+impl<'a> Iterator for PooledIterator<'a> {
+    fn valid(&self) -> bool {
+        self.iter().valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.iter_mut().seek_to_first()
+    }
+
+    fn seek_to_last(&mut self) {
+        self.iter_mut().seek_to_last()
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        self.iter_mut().seek(target)
+    }
+
+    fn next(&mut self) {
+        self.iter_mut().next()
+    }
+
+    fn prev(&mut self) {
+        self.iter_mut().prev()
+    }
+
+    fn key(&self) -> Slice {
+        self.iter().key()
+    }
+
+    fn value(&self) -> Slice {
+        self.iter().value()
+    }
+
+    fn value_size(&self) -> usize {
+        self.iter().value_size()
+    }
+
+    fn status(&mut self) -> Result<()> {
+        self.iter_mut().status()
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        let memtable_generation = self
+            .pool
+            .db
+            .memtable_generation
+            .load(AtomicOrdering::Acquire);
+        self.iter_mut().refresh()?;
+        // The child iterators are built on the memtables of the new generation at least
+        self.memtable_generation = memtable_generation;
+        Ok(())
+    }
+}
+
+impl<'a> Drop for PooledIterator<'a> {
+    fn drop(&mut self) {
+        let mut iter = self.iter.take().unwrap();
+        let bounds = mem::take(&mut self.bounds);
+        // an iterator ending up with an error is never reused
+        if iter.err.is_some() || iter.inner.status().is_err() {
+            return;
+        }
+        let mut idle = self.pool.idle.borrow_mut();
+        if idle.len() < self.pool.max_idle {
+            idle.push(IdleIterator {
+                iter,
+                memtable_generation: self.memtable_generation,
+                verify_checksums: self.verify_checksums,
+                fill_cache: self.fill_cache,
+                ignore_filters: self.ignore_filters,
+                bounds,
+            });
+        }
+    }
+}
+
+/// An iterator following the newest writes, created by `DB::iter` with
+/// `ReadOptions::tailing` set.
+///
+/// Every seek refreshes the iterator to the latest state of the db first. The
+/// refresh is cheap: only the iterators over the memtables are created again
+/// if any write happens, and the iterators over the tables are created again
+/// only if the version changes, e.g. after a flush or a compaction.
+/// A tailing iterator always reads the latest data so `ReadOptions::snapshot`
+/// is ignored.
+pub struct TailingIterator {
+    db: Arc<DBImpl>,
+    read_opt: Rc<ReadOptions>,
+    iter: DBIterator,
+    memtable_generation: u64,
+    // The iterators over the tables of the version `iter` is built on
+    table_iters: Vec<Rc<RefCell<Box<dyn Iterator>>>>,
+}
+
+impl TailingIterator {
+    pub(crate) fn new(db: Arc<DBImpl>, mut read_opt: ReadOptions) -> Self {
+        read_opt.snapshot = None;
+        let (cancel_token, cancel_check_interval) = (
+            read_opt.cancel_token.clone(),
+            read_opt.cancel_check_interval,
+        );
+        let (lower, upper) = (
+            read_opt.iterate_lower_bound.clone(),
+            read_opt.iterate_upper_bound.clone(),
+        );
+        let read_opt = Rc::new(read_opt);
+        let memtable_generation = db.memtable_generation.load(AtomicOrdering::Acquire);
+        let sequence = db.read_sequence(&read_opt);
+        let memtable_iters = db.memtable_iters(&read_opt);
+        let (version, table_iters) = Self::version_iters(&db, &read_opt);
+        let iter = DBIterator::new(
+            Self::merge(&db, memtable_iters, &table_iters),
+            db.clone(),
+            version,
+            sequence,
+            db.internal_comparator.user_comparator.clone(),
+        )
+        .with_cancel_token(cancel_token, cancel_check_interval)
+        .with_bounds(lower, upper)
+        .with_read_options(&read_opt);
+        Self {
+            db,
+            read_opt,
+            iter,
+            memtable_generation,
+            table_iters,
+        }
+    }
+
+    // Makes the iterator see the data written since it's created or caught up
+    // last time. The iterator is invalid until it seeks again.
+    fn catch_up(&mut self) {
+        let memtable_generation = self.db.memtable_generation.load(AtomicOrdering::Acquire);
+        let sequence = self.db.read_sequence(&self.read_opt);
+        let current = self.db.versions.lock().unwrap().current();
+        if sequence == self.iter.sequence
+            && memtable_generation == self.memtable_generation
+            && Arc::ptr_eq(&current, &self.iter._version)
+        {
+            self.iter.reset(sequence);
+            return;
+        }
+        let memtable_iters = self.db.memtable_iters(&self.read_opt);
+        let (version, table_iters) = {
+            let versions = self.db.versions.lock().unwrap();
+            let current = versions.current();
+            if Arc::ptr_eq(&current, &self.iter._version) {
+                (current, None)
+            } else {
+                let iters =
+                    versions.current_iters(self.read_opt.clone(), self.db.table_cache.clone());
+                (current, Some(iters))
+            }
+        };
+        if let Some(iters) = table_iters {
+            self.table_iters = iters
+                .into_iter()
+                .map(|iter| Rc::new(RefCell::new(iter)))
+                .collect();
+            self.iter._version = version;
+        }
+        self.iter.inner = Self::merge(&self.db, memtable_iters, &self.table_iters);
+        self.iter.reset(sequence);
+        self.memtable_generation = memtable_generation;
+    }
+
+    fn version_iters(
+        db: &DBImpl,
+        read_opt: &Rc<ReadOptions>,
+    ) -> (Arc<Version>, Vec<Rc<RefCell<Box<dyn Iterator>>>>) {
+        let versions = db.versions.lock().unwrap();
+        let iters = versions
+            .current_iters(read_opt.clone(), db.table_cache.clone())
+            .into_iter()
+            .map(|iter| Rc::new(RefCell::new(iter)))
+            .collect();
+        (versions.current(), iters)
+    }
+
+    fn merge(
+        db: &DBImpl,
+        mut children: Vec<Rc<RefCell<Box<dyn Iterator>>>>,
+        table_iters: &[Rc<RefCell<Box<dyn Iterator>>>],
+    ) -> Box<dyn Iterator> {
+        children.extend(table_iters.iter().cloned());
+        Box::new(MergingIterator::new(
+            db.internal_comparator.clone(),
+            children,
+        ))
+    }
+}
+
+impl Iterator for TailingIterator {
+    fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.catch_up();
+        self.iter.seek_to_first()
+    }
+
+    fn seek_to_last(&mut self) {
+        self.catch_up();
+        self.iter.seek_to_last()
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        self.catch_up();
+        self.iter.seek(target)
+    }
+
+    fn next(&mut self) {
+        self.iter.next()
+    }
+
+    fn prev(&mut self) {
+        self.iter.prev()
+    }
+
+    fn key(&self) -> Slice {
+        self.iter.key()
+    }
+
+    fn value(&self) -> Slice {
+        self.iter.value()
+    }
+
+    fn value_size(&self) -> usize {
+        self.iter.value_size()
+    }
+
+    fn status(&mut self) -> Result<()> {
+        self.iter.status()
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        if self.iter.valid() {
+            let key = self.iter.key().as_slice().to_vec();
+            self.seek(&Slice::from(key.as_slice()));
+        } else {
+            self.catch_up();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::db::filename::{generate_filename, FileType};
+use crate::storage::{do_write_string_to_file, Storage};
+use crate::util::status::Result;
+use rand::Rng;
+use std::sync::Arc;
+
+/// The lease of the `LOCK` of a db, saved in the `LOCK.lease` file when
+/// `Options::lock_lease_expiry_micros` is set.
+///
+/// The owner renews the lease periodically. A lease not renewed for longer than
+/// the expiry is stale, e.g. left by a container killed on another host while
+/// holding the lock on a shared volume, and `WickDB::open_with_takeover` is able
+/// to take over the lock.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockLease {
+    /// The process id of the owner
+    pub pid: u32,
+    /// The host name of the owner
+    pub host: String,
+    /// A random id telling the different opens of the db apart, since the
+    /// processes in different containers may share a same pid
+    pub owner_id: u64,
+    /// When the lease is acquired or renewed last time in micros
+    pub renewed_at_micros: u64,
+}
+
+impl LockLease {
+    // Creates a lease owned by the current process
+    pub(crate) fn new(now_micros: u64) -> Self {
+        Self {
+            pid: std::process::id(),
+            host: host_name(),
+            owner_id: rand::thread_rng().gen(),
+            renewed_at_micros: now_micros,
+        }
+    }
+
+    /// Reads the lease of the db at `db_name`, or returns `None` if there is no
+    /// lease or it's malformed
+    pub fn read(env: &dyn Storage, db_name: &str) -> Result<Option<Self>> {
+        let name = generate_filename(db_name, FileType::LockLease, 0);
+        if !env.exists(name.as_str()) {
+            return Ok(None);
+        }
+        let mut file = env.open(name.as_str())?;
+        let mut buf = vec![];
+        file.read_all(&mut buf)?;
+        Ok(String::from_utf8(buf)
+            .ok()
+            .and_then(|s| Self::decode(s.as_str())))
+    }
+
+    // Saves the lease by renaming a temp file so a reader never sees a partial one
+    pub(crate) fn write(&self, env: Arc<dyn Storage>, db_name: &str) -> Result<()> {
+        let name = generate_filename(db_name, FileType::LockLease, 0);
+        let tmp = name.clone() + ".tmp";
+        do_write_string_to_file(env.clone(), self.encode(), tmp.as_str(), true)?;
+        env.rename(tmp.as_str(), name.as_str())
+    }
+
+    /// Returns whether the lease isn't renewed for longer than `expiry_micros`
+    pub fn is_expired(&self, now_micros: u64, expiry_micros: u64) -> bool {
+        now_micros.saturating_sub(self.renewed_at_micros) > expiry_micros
+    }
+
+    fn encode(&self) -> String {
+        format!(
+            "pid={}\nhost={}\nowner_id={}\nrenewed_at_micros={}\n",
+            self.pid, self.host, self.owner_id, self.renewed_at_micros
+        )
+    }
+
+    fn decode(s: &str) -> Option<Self> {
+        let mut lines = s.lines();
+        let mut field = |name: &str| {
+            lines
+                .next()
+                .and_then(|line| line.strip_prefix(name))
+                .and_then(|line| line.strip_prefix('='))
+        };
+        Some(Self {
+            pid: field("pid")?.parse().ok()?,
+            host: field("host")?.to_owned(),
+            owner_id: field("owner_id")?.parse().ok()?,
+            renewed_at_micros: field("renewed_at_micros")?.parse().ok()?,
+        })
+    }
+}
+
+// Returns the host name from `HOSTNAME` or `/etc/hostname`
+fn host_name() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem::MemStorage;
+
+    #[test]
+    fn test_lock_lease_encoding() {
+        let lease = LockLease::new(123);
+        assert_eq!(lease.pid, std::process::id());
+        assert_eq!(LockLease::decode(lease.encode().as_str()), Some(lease));
+        assert_eq!(LockLease::decode("pid=1\nhost=a\n"), None);
+        assert_eq!(
+            LockLease::decode("pid=x\nhost=a\nowner_id=1\nrenewed_at_micros=1\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_lock_lease_read_write() {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        env.mkdir_all("db").unwrap();
+        assert_eq!(LockLease::read(env.as_ref(), "db").unwrap(), None);
+        let lease = LockLease::new(100);
+        lease.write(env.clone(), "db").unwrap();
+        assert_eq!(
+            LockLease::read(env.as_ref(), "db").unwrap(),
+            Some(lease.clone())
+        );
+        assert!(!lease.is_expired(150, 50));
+        assert!(lease.is_expired(151, 50));
+        assert!(!lease.is_expired(0, 50));
+    }
+}
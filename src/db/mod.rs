@@ -14,40 +14,66 @@
 pub mod filename;
 pub mod format;
 pub mod iterator;
+pub mod lease;
+pub mod secondary_index;
+pub mod sharded;
+pub mod transaction;
 
 use crate::batch::{WriteBatch, HEADER_SIZE};
-use crate::compaction::{Compaction, CompactionInputsRelation};
+use crate::compaction::{Compaction, CompactionDecision, CompactionInputsRelation};
 use crate::db::filename::{generate_filename, parse_filename, update_current, FileType};
 use crate::db::format::{
-    InternalKey, InternalKeyComparator, LookupKey, ParsedInternalKey, ValueType,
+    extract_user_key, InternalKey, InternalKeyComparator, LookupKey, ParsedInternalKey, ValueType,
+    MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK,
 };
-use crate::db::iterator::DBIterator;
+use crate::db::iterator::{DBIterator, IteratorPool, TailingIterator};
+use crate::db::lease::LockLease;
 use crate::iterator::{Iterator, MergingIterator};
-use crate::mem::{MemTable, MemoryTable};
-use crate::options::{Options, ReadOptions, WriteOptions};
+use crate::listener::{
+    CompactionJobInfo, CompactionObsoleteBlocksInfo, CompactionReason, FifoDeletionInfo,
+    FlushJobInfo, ObsoleteBlock, TableFileCreationInfo, TableFileCreationReason,
+    TableFileDeletionInfo, WriteStallCause, WriteStallInfo,
+};
+use crate::mem::{BlockArena, MemTable, MemoryTable};
+use crate::options::{
+    ConsistencyCheckLevel, MutableOptions, Options, ReadOptions, SizeApproximationOptions,
+    SyncPolicy, WriteOptions,
+};
 use crate::record::reader::Reader;
 use crate::record::writer::Writer;
+use crate::remote_compaction::{
+    CompactionInput, CompactionJob, CompactionJobResult, CompactionService,
+};
 use crate::snapshot::Snapshot;
-use crate::sstable::table::TableBuilder;
-use crate::storage::{File, Storage};
+use crate::sstable::properties::TableProperties;
+use crate::sstable::table::{read_footer, verify_table_checksums, TableBuilder};
+use crate::sstable::LATEST_FORMAT_VERSION;
+use crate::statistics::{record_tick, HistogramType, LevelTicker, StopWatch, Ticker};
+use crate::storage::{File, LockKind, Storage};
 use crate::table_cache::TableCache;
+use crate::util::comparator::{BytewiseComparator, Comparator};
+use crate::util::crc32;
+use crate::util::histogram::Histogram;
 use crate::util::reporter::LogReporter;
 use crate::util::slice::Slice;
-use crate::util::status::{Result, Status, WickErr};
+use crate::util::status::{CorruptionReport, Result, Status, WickErr};
+use crate::util::varint::VarintU32;
 use crate::version::version_edit::{FileMetaData, VersionEdit};
 use crate::version::version_set::VersionSet;
+use crate::version::Version;
+use crate::write_hook::{WriteDecision, WriteInfo};
 use crossbeam_channel::{Receiver, Sender};
 use crossbeam_utils::sync::ShardedLock;
 use std::cell::RefCell;
-use std::cmp::Ordering as CmpOrdering;
+use std::cmp::{self, Ordering as CmpOrdering};
 use std::collections::vec_deque::VecDeque;
+use std::collections::HashSet;
 use std::mem;
-use std::path::MAIN_SEPARATOR;
 use std::rc::Rc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
 
 /// A `DB` is a persistent ordered map from keys to values.
 /// A `DB` is safe for concurrent access from multiple threads without
@@ -59,7 +85,23 @@ pub trait DB {
 
     /// `get` gets the value for the given key. It returns `None` if the DB
     /// does not contain the key.
-    fn get(&self, read_opt: ReadOptions, key: Slice) -> Result<Option<Slice>>;
+    fn get(&self, read_opt: ReadOptions, key: Slice) -> Result<Option<Vec<u8>>>;
+
+    /// `multi_get` gets the values for all the given keys in the same order as `get`
+    /// does for each of them, but every table is probed once for the whole batch and
+    /// the tables whose filters rule out all the keys are skipped without reading
+    /// any data block.
+    fn multi_get(&self, read_opt: ReadOptions, keys: &[Slice]) -> Result<Vec<Option<Vec<u8>>>>;
+
+    /// Returns the approximate number of the entries with the user keys in
+    /// `[start, end)`, where `None` stands for an unbounded side, without scanning
+    /// the tables. The overwritten and the deleted entries not compacted yet are
+    /// counted as well, so this is an upper bound estimation for costing range
+    /// predicates.
+    ///
+    /// The entries in a table are interpolated by the offsets the keys fall into in
+    /// its index block and the density of the entries sampled from one data block.
+    fn estimate_keys_in_range(&self, start: Option<Slice>, end: Option<Slice>) -> Result<u64>;
 
     /// Return an iterator over the contents of the database.
     fn iter(&self, read_opt: ReadOptions) -> Box<dyn Iterator>;
@@ -71,9 +113,12 @@ pub trait DB {
     /// `write` applies the operations contained in the `WriteBatch` to the DB atomically.
     fn write(&self, write_opt: WriteOptions, batch: WriteBatch) -> Result<()>;
 
-    /// `close` shuts down the current WickDB by waiting util all the background tasks are complete
-    /// and then releases the file lock. A closed db should never be used again and is able to be
-    /// dropped safely.
+    /// `close` shuts down the current WickDB deterministically: the new writes are rejected,
+    /// the memtables are flushed or abandoned as `Options::flush_on_close` says, the running
+    /// compactions are cancelled at their next key without installing their outputs and waited
+    /// for, then the WAL and the MANIFEST are synced as their `SyncPolicy` says and the file
+    /// lock is released. A closed db should never be used again and is able to be dropped
+    /// safely.
     fn close(&mut self) -> Result<()>;
 
     /// `destroy` shuts down the current WickDB and delete all relative files and the db directory.
@@ -81,6 +126,178 @@ pub trait DB {
 
     /// Acquire a `Snapshot` for reading DB
     fn snapshot(&self) -> Arc<Snapshot>;
+
+    /// Returns the value of a DB property or `None` if the `property` is
+    /// not understood by the implementation.
+    ///
+    /// Valid property names include:
+    ///
+    /// * "wickdb.num-files-at-level<N>" - return the number of files at level <N>,
+    ///   where <N> is an ASCII representation of a level number (e.g. "0").
+    /// * "wickdb.stats" - returns a multi-line string that describes statistics
+    ///   about the internal operation of the DB.
+    /// * "wickdb.sstables" - returns a multi-line string that describes all
+    ///   of the sstables that make up the db contents.
+    /// * "wickdb.table-seek-telemetry" - returns a multi-line string that describes
+    ///   the average binary search depth of the index block seeks, and the binary
+    ///   search depth and scan distance of the data block seeks in each cached
+    ///   sstable. Only collected when `Options::statistics` is set.
+    /// * "wickdb.approximate-memory-usage" - returns the approximate number of
+    ///   bytes of memory in use by the DB.
+    /// * "wickdb.cur-size-active-mem-table" - returns the bytes allocated by the
+    ///   entries of the active memtable.
+    /// * "wickdb.cur-size-all-mem-tables" - returns the bytes allocated by the
+    ///   entries of the active and the immutable memtables.
+    /// * "wickdb.size-all-mem-tables" - returns the bytes the arenas of the active
+    ///   and the immutable memtables reserve, including the unused parts of the blocks.
+    /// * "wickdb.num-running-compactions" - returns the number of the running
+    ///   compactions.
+    /// * "wickdb.estimate-pending-compaction-bytes" - returns the estimated
+    ///   bytes compactions need to rewrite to bring every level under its target size.
+    /// * "wickdb.estimate-num-keys" - returns the estimated number of the keys in
+    ///   the db, see `WickDB::estimate_num_keys`.
+    /// * "wickdb.num-obsolete-sst-files" - returns the number of the sstables no
+    ///   longer in the current version but not deleted yet, e.g. held by the
+    ///   iterators or left after a background error.
+    /// * "wickdb.obsolete-sst-files-size" - returns the total bytes of the obsolete
+    ///   sstables above.
+    /// * "wickdb.num-obsolete-wal-files" - returns the number of the log files whose
+    ///   entries are all flushed into the sstables but not deleted yet.
+    /// * "wickdb.obsolete-wal-files-size" - returns the total bytes of the obsolete
+    ///   log files above.
+    fn get_property(&self, property: &str) -> Option<String>;
+
+    /// Returns a machine-readable JSON dump of the current version including the
+    /// files with their key ranges and sizes in every level, the target sizes and
+    /// the compaction scores of the levels and the compaction pointers like:
+    ///
+    /// ```text
+    /// {"last_sequence":9,"next_file_number":12,"log_number":10,"prev_log_number":0,
+    ///  "manifest_number":2,"compaction_level":0,"compaction_score":0.5,
+    ///  "file_to_compact":null,"levels":[{"level":0,"num_files":1,"total_size":1024,
+    ///  "max_bytes":0,"compaction_score":0.25,"compaction_pointer":null,"files":[{"number":5,
+    ///  "size":1024,"allowed_seeks":100,"being_compacted":false,
+    ///  "smallest":{"user_key":"a","sequence":1,"value_type":"Value"},"largest":...}]}]}
+    /// ```
+    ///
+    /// The user keys are JSON strings with the bytes out of printable ASCII escaped
+    /// as `\u00XX`, so every code point stands for exactly one byte.
+    fn version_debug_json(&self) -> String;
+
+    /// Lists the immediate children of `prefix` in the hierarchical namespace
+    /// formed by splitting the keys with `delimiter`, like listing a directory
+    /// of an object store. A key under `prefix` containing `delimiter` after
+    /// `prefix` yields its leading part ending with the first such `delimiter`,
+    /// and any other key under `prefix` yields itself. The children are in
+    /// order without duplicates.
+    ///
+    /// Each subtree is skipped by a seek with the default comparator instead
+    /// of scanning all the keys in it.
+    fn list_prefixes(
+        &self,
+        read_opt: ReadOptions,
+        prefix: Slice,
+        delimiter: Slice,
+    ) -> Result<Vec<Vec<u8>>>;
+
+    /// `update` reads the current values of `keys`, calls `f` with them and writes the
+    /// values returned by `f` back in one `WriteBatch`. The values passed to and
+    /// returned by `f` are in the same order as `keys`, and `None` stands for a missing
+    /// key or a deletion.
+    ///
+    /// The keys are locked during the whole read-modify-write so the concurrent
+    /// `update`s on any of the same keys are serialized. Other writes never take the
+    /// locks and are free to interleave.
+    fn update<F>(&self, write_opt: WriteOptions, keys: &[Slice], f: F) -> Result<()>
+    where
+        Self: Sized,
+        F: FnOnce(&[Option<Vec<u8>>]) -> Result<Vec<Option<Vec<u8>>>>;
+}
+
+// The max size of a `WriteBatch` written by `WickDB::migrate_comparator`
+const MIGRATION_BATCH_SIZE: usize = 1 << 20;
+
+/// The summary of the deletions in the table files of a level, returned by
+/// `WickDB::tombstone_stats`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LevelTombstoneStats {
+    pub level: usize,
+    pub num_files: usize,
+    /// The number of the files containing any deletion
+    pub num_files_with_deletions: usize,
+    pub num_entries: u64,
+    pub num_deletions: u64,
+    /// The smallest and the largest user keys of the deletions in the level.
+    /// wickdb has no range deletion, so this is the range the point deletions
+    /// spread over.
+    pub deleted_range: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// A range of the user keys `[start, limit)`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Range<'a> {
+    pub start: &'a [u8],
+    pub limit: &'a [u8],
+}
+
+impl<'a> Range<'a> {
+    pub fn new(start: &'a [u8], limit: &'a [u8]) -> Self {
+        Self { start, limit }
+    }
+}
+
+/// The approximate sizes of a key range, returned by `WickDB::get_approximate_sizes`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ApproximateSize {
+    /// The bytes the range takes in the table files
+    pub on_disk_bytes: u64,
+    /// The bytes left once the deletions and the entries they delete are compacted,
+    /// which equals `on_disk_bytes` unless `SizeApproximationOptions::estimate_live_bytes`
+    /// is set.
+    pub live_bytes: u64,
+    /// The bytes of the internal keys and the values in the memtables, counted only
+    /// if `SizeApproximationOptions::include_memtables` is set
+    pub memtable_bytes: u64,
+}
+
+/// The digest of the entries in a key range, returned by `WickDB::digest_range`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RangeDigest {
+    /// The number of the live entries in the range
+    pub num_entries: u64,
+    /// The CRC32C of the lengths and the bytes of the keys and the values in order
+    pub digest: u32,
+}
+
+impl ApproximateSize {
+    /// Returns the bytes in both the table files and the memtables
+    pub fn total_bytes(&self) -> u64 {
+        self.on_disk_bytes + self.memtable_bytes
+    }
+}
+
+/// The distributions of the sizes of the keys and the values in the table files,
+/// returned by `WickDB::kv_size_histograms`
+#[derive(Clone, Debug, Default)]
+pub struct KvSizeHistograms {
+    /// The sizes of the user keys
+    pub key_sizes: Histogram,
+    pub value_sizes: Histogram,
+    /// The number of the tables recording the histograms
+    pub num_tables: usize,
+    /// The number of the tables built without `Options::record_kv_size_histograms`
+    pub num_tables_without_histograms: usize,
+}
+
+impl LevelTombstoneStats {
+    /// Returns the ratio of the deletions to all the entries in the level
+    pub fn deletion_ratio(&self) -> f64 {
+        if self.num_entries == 0 {
+            0.0
+        } else {
+            self.num_deletions as f64 / self.num_entries as f64
+        }
+    }
 }
 
 /// The wrapper of `DBImpl` for concurrency control.
@@ -96,38 +313,37 @@ impl DB for WickDB {
         self.write(options, batch)
     }
 
-    fn get(&self, options: ReadOptions, key: Slice) -> Result<Option<Slice>> {
+    fn get(&self, options: ReadOptions, key: Slice) -> Result<Option<Vec<u8>>> {
         self.inner.get(options, key)
     }
 
-    fn iter(&self, read_opt: ReadOptions) -> Box<dyn Iterator> {
+    fn multi_get(&self, options: ReadOptions, keys: &[Slice]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.inner.multi_get(options, keys)
+    }
+
+    fn estimate_keys_in_range(&self, start: Option<Slice>, end: Option<Slice>) -> Result<u64> {
+        self.inner.estimate_keys_in_range(start, end)
+    }
+
+    fn iter(&self, mut read_opt: ReadOptions) -> Box<dyn Iterator> {
+        self.inner.apply_mutable_options(&mut read_opt);
         let ucmp = self.inner.internal_comparator.user_comparator.clone();
-        let sequence = if let Some(snapshot) = &read_opt.snapshot {
-            snapshot.sequence()
-        } else {
-            self.inner.versions.lock().unwrap().last_sequence()
-        };
-        let mut children = vec![];
-        children.push(Rc::new(RefCell::new(self.inner.mem.read().unwrap().iter())));
-        if let Some(im_mem) = self.inner.im_mem.read().unwrap().as_ref() {
-            children.push(Rc::new(RefCell::new(im_mem.iter())));
-        }
-        let mut table_iters = self
-            .inner
-            .versions
-            .lock()
-            .unwrap()
-            .current_iters(Rc::new(read_opt), self.inner.table_cache.clone());
-        for iter in table_iters.drain(..) {
-            children.push(Rc::new(RefCell::new(iter)));
+        let sequence = self.inner.read_sequence(&read_opt);
+        let cancel_token = read_opt.cancel_token.clone();
+        let cancel_check_interval = read_opt.cancel_check_interval;
+        if read_opt.tailing {
+            return Box::new(TailingIterator::new(self.inner.clone(), read_opt));
         }
-        let iter = MergingIterator::new(self.inner.internal_comparator.clone(), children);
-        Box::new(DBIterator::new(
-            Box::new(iter),
-            self.inner.clone(),
-            sequence,
-            ucmp,
-        ))
+        let lower = read_opt.iterate_lower_bound.clone();
+        let upper = read_opt.iterate_upper_bound.clone();
+        let refresh_opt = read_opt.clone();
+        let (iter, version) = self.inner.internal_iter(read_opt);
+        Box::new(
+            DBIterator::new(Box::new(iter), self.inner.clone(), version, sequence, ucmp)
+                .with_cancel_token(cancel_token, cancel_check_interval)
+                .with_bounds(lower, upper)
+                .with_read_options(&refresh_opt),
+        )
     }
 
     fn delete(&self, options: WriteOptions, key: Slice) -> Result<()> {
@@ -141,14 +357,11 @@ impl DB for WickDB {
     }
 
     fn close(&mut self) -> Result<()> {
-        self.inner.is_shutting_down.store(true, Ordering::Release);
-        match &self.inner.db_lock {
-            Some(lock) => lock.unlock(),
-            None => Ok(()),
-        }
+        self.inner.close()
     }
 
     fn destroy(&mut self) -> Result<()> {
+        self.inner.check_writable()?;
         let db = self.inner.clone();
         db.is_shutting_down.store(true, Ordering::Release);
         db.options.env.remove_dir(&db.db_name, true)
@@ -157,21 +370,255 @@ impl DB for WickDB {
     fn snapshot(&self) -> Arc<Snapshot> {
         self.inner.snapshot()
     }
+
+    fn get_property(&self, property: &str) -> Option<String> {
+        self.inner.get_property(property)
+    }
+
+    fn version_debug_json(&self) -> String {
+        self.inner.versions.lock().unwrap().debug_json()
+    }
+
+    fn list_prefixes(
+        &self,
+        read_opt: ReadOptions,
+        prefix: Slice,
+        delimiter: Slice,
+    ) -> Result<Vec<Vec<u8>>> {
+        let (prefix, delimiter) = (prefix.as_slice(), delimiter.as_slice());
+        if delimiter.is_empty() {
+            return Err(WickErr::new(
+                Status::InvalidArgument,
+                Some("empty delimiter"),
+            ));
+        }
+        // Only the keys sharing a prefix are adjacent to each other under the
+        // bytewise order so that a subtree could be skipped by a seek
+        let seekable = self.inner.internal_comparator.user_comparator.name()
+            == BytewiseComparator::new().name();
+        let mut children = vec![];
+        let mut seen = HashSet::new();
+        let mut iter = self.iter(read_opt);
+        if seekable && !prefix.is_empty() {
+            iter.seek(&Slice::from(prefix));
+        } else {
+            iter.seek_to_first();
+        }
+        while iter.valid() {
+            let key = iter.key();
+            let key = key.as_slice();
+            if !key.starts_with(prefix) {
+                if seekable {
+                    break;
+                }
+                iter.next();
+                continue;
+            }
+            let (child, is_subtree) = match find_subslice(&key[prefix.len()..], delimiter) {
+                Some(pos) => (key[..prefix.len() + pos + delimiter.len()].to_vec(), true),
+                None => (key.to_vec(), false),
+            };
+            if seen.insert(child.clone()) {
+                children.push(child.clone());
+            }
+            if seekable && is_subtree {
+                match prefix_successor(&child) {
+                    Some(next) => iter.seek(&Slice::from(next.as_slice())),
+                    None => break,
+                }
+            } else {
+                iter.next();
+            }
+        }
+        iter.status()?;
+        Ok(children)
+    }
+
+    fn update<F>(&self, write_opt: WriteOptions, keys: &[Slice], f: F) -> Result<()>
+    where
+        F: FnOnce(&[Option<Vec<u8>>]) -> Result<Vec<Option<Vec<u8>>>>,
+    {
+        let _guard = self.inner.lock_keys(keys);
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            values.push(self.inner.get(ReadOptions::default(), key.clone())?);
+        }
+        let new_values = f(&values)?;
+        if new_values.len() != keys.len() {
+            return Err(WickErr::new(
+                Status::InvalidArgument,
+                Some("the number of the updated values mismatches the keys"),
+            ));
+        }
+        let mut batch = WriteBatch::new();
+        for (key, value) in keys.iter().zip(new_values.iter()) {
+            match value {
+                Some(v) => batch.put(key.as_slice(), v.as_slice()),
+                None => batch.delete(key.as_slice()),
+            }
+        }
+        self.write(write_opt, batch)
+    }
+}
+
+// Releases the keys locked by `DBImpl::lock_keys` when dropped
+struct KeyLockGuard<'a> {
+    db: &'a DBImpl,
+    keys: Vec<Vec<u8>>,
+}
+
+impl Drop for KeyLockGuard<'_> {
+    fn drop(&mut self) {
+        let mut locked = self.db.locked_keys.lock().unwrap();
+        for key in self.keys.iter() {
+            locked.remove(key);
+        }
+        self.db.locked_keys_released.notify_all();
+    }
+}
+
+// Returns the position of the first occurrence of `needle` in `haystack`
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// Returns the smallest key greater than all the keys starting with `prefix`
+// under the bytewise order, or `None` if there is no such key
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut next = prefix.to_vec();
+    while let Some(last) = next.pop() {
+        if last != 0xff {
+            next.push(last + 1);
+            return Some(next);
+        }
+    }
+    None
 }
 
 impl WickDB {
     /// Create a new WickDB
-    pub fn open_db(mut options: Options, db_name: String) -> Result<Self> {
+    pub fn open_db(options: Options, db_name: String) -> Result<Self> {
+        Self::open_with_threads(options, db_name, false)
+    }
+
+    /// Opens the db like `open_db` but takes over the lock held by another
+    /// instance if its lease isn't renewed for `Options::lock_lease_expiry_micros`,
+    /// which recovers a db whose owner is gone without releasing the lock, e.g.
+    /// a container killed on another host sharing the volume.
+    ///
+    /// The `LOCK` file is replaced so the stale owner never gets it back. If the
+    /// owner is actually alive, its writes fail once it notices the lease is taken.
+    pub fn open_with_takeover(options: Options, db_name: String) -> Result<Self> {
+        Self::open_with_threads(options, db_name, true)
+    }
+
+    fn open_with_threads(options: Options, db_name: String, takeover: bool) -> Result<Self> {
+        let wick_db = WickDB {
+            inner: Arc::new(Self::open_without_threads(options, db_name, takeover)?),
+        };
+        wick_db.process_compaction();
+        wick_db.process_batch();
+        wick_db.process_wal_sync();
+        wick_db.process_idle_work();
+        wick_db.process_periodic_compaction();
+        wick_db.process_lock_lease_renewal();
+        wick_db.inner.maybe_schedule_compaction();
+        Ok(wick_db)
+    }
+
+    /// Compacts the closed db at `db_name` in the calling thread until all the
+    /// table files are in the last level, which leaves a read-optimized db with no
+    /// overlapping files, no overwritten values and no deletion markers.
+    ///
+    /// The db is locked during the compaction so it must not be opened by others.
+    /// The WAL files are flushed into table files first. This is meant for
+    /// preparing artifacts in build pipelines rather than for a serving db.
+    pub fn compact_offline(mut options: Options, db_name: String) -> Result<()> {
+        // Every WAL file should be flushed during the recovery
+        options.reuse_logs = false;
+        let db = Self::open_without_threads(options, db_name, false)?;
+        for level in 0..db.options.max_levels as usize - 1 {
+            db.compact_level_fully(level)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites all the entries of the closed db at `src_name`, which is created with
+    /// `src_options.comparator`, into a new db at `dst_name` ordered by
+    /// `dst_options.comparator`. Returns the number of the migrated entries.
+    ///
+    /// The entries are streamed in small batches into the new db, whose memtable
+    /// flushes and compactions re-sort them in the new order, so the memory usage is
+    /// bounded by the options of the new db however large the source is. The source
+    /// db is opened read only and left untouched, so it could be removed once the new
+    /// one is verified. It should not be written during the migration.
+    pub fn migrate_comparator(
+        src_options: Options,
+        src_name: String,
+        mut dst_options: Options,
+        dst_name: String,
+    ) -> Result<u64> {
+        if src_name == dst_name {
+            return Err(WickErr::new(
+                Status::InvalidArgument,
+                Some("could not migrate a db into itself"),
+            ));
+        }
+        let src = Self::open_read_only(src_options, src_name)?;
+        dst_options.create_if_missing = true;
+        dst_options.error_if_exists = true;
+        let mut dst = Self::open_db(dst_options, dst_name)?;
+        let mut iter = src.iter(ReadOptions::default());
+        iter.seek_to_first();
+        let mut batch = WriteBatch::new();
+        let mut migrated = 0;
+        while iter.valid() {
+            batch.put(iter.key().as_slice(), iter.value().as_slice());
+            migrated += 1;
+            iter.next();
+            if batch.approximate_size() >= MIGRATION_BATCH_SIZE || !iter.valid() {
+                // Makes all the entries durable by the last write
+                let write_opt = WriteOptions {
+                    sync: !iter.valid(),
+                    ..WriteOptions::default()
+                };
+                dst.write(write_opt, mem::replace(&mut batch, WriteBatch::new()))?;
+            }
+        }
+        iter.status()?;
+        dst.close()?;
+        Ok(migrated)
+    }
+
+    // Opens the db like `open_db` but starts no background thread
+    fn open_without_threads(
+        mut options: Options,
+        db_name: String,
+        takeover: bool,
+    ) -> Result<DBImpl> {
+        if !options.table_file_naming.is_valid() {
+            return Err(WickErr::new(
+                Status::InvalidArgument,
+                Some("invalid table file naming"),
+            ));
+        }
+        if options.format_version > LATEST_FORMAT_VERSION {
+            return Err(WickErr::new(
+                Status::InvalidArgument,
+                Some("unsupported table format version"),
+            ));
+        }
         let env = options.env.clone();
-        options.initialize(db_name.clone());
+        options.initialize(Some(&db_name));
         let mut db = DBImpl::new(options, db_name.clone());
-        let (mut edit, should_save_manifest) = db.recover()?;
+        let (mut edit, should_save_manifest) = db.recover(takeover)?;
         let mut versions = db.versions.lock().unwrap();
         if versions.record_writer.is_none() {
             let new_log_number = versions.inc_next_file_number();
             let log_file =
                 env.create(generate_filename(&db_name, FileType::Log, new_log_number).as_str())?;
             versions.record_writer = Some(Writer::new(log_file));
+            versions.record_writer_number = new_log_number;
             edit.set_log_number(new_log_number);
             versions.set_log_number(new_log_number);
         }
@@ -181,107 +628,463 @@ impl WickDB {
             versions.log_and_apply(&mut edit)?;
         }
 
+        // The cap might be lowered since the last time
+        db.delete_fifo_files(&mut versions)?;
         db.delete_obsolete_files(versions);
-        let wick_db = WickDB {
+        Ok(db)
+    }
+
+    /// Opens the db at `db_name` for reading only. The db could be opened by a
+    /// primary instance in another process at the same time, but the updates
+    /// made by the primary after opening are invisible to the read-only one.
+    ///
+    /// No file is created or modified, so the writes are rejected with
+    /// `Status::NotSupported`, the WAL files are replayed into the memtable
+    /// without flushing and no compaction is ever scheduled.
+    pub fn open_read_only(mut options: Options, db_name: String) -> Result<Self> {
+        options.initialize(None);
+        Self::open_without_writing(options, db_name, OpenMode::ReadOnly)
+    }
+
+    /// Opens the db at `db_name` as a secondary instance, which is read-only like
+    /// the one opened by `open_read_only` but catches up with the primary by
+    /// reloading the MANIFEST and the WAL files every
+    /// `Options::secondary_catch_up_interval_micros` or when
+    /// `try_catch_up_with_primary` is called.
+    ///
+    /// `secondary_path` is the directory of the files owned by the secondary
+    /// instance, such as the `LOG` file.
+    pub fn open_as_secondary(
+        mut options: Options,
+        db_name: String,
+        secondary_path: String,
+    ) -> Result<Self> {
+        options.initialize(Some(&secondary_path));
+        let db = Self::open_without_writing(options, db_name, OpenMode::Secondary)?;
+        let interval = db.inner.options.secondary_catch_up_interval_micros;
+        if interval > 0 {
+            // Holds a weak reference so that the thread exits once the db is dropped
+            let weak = Arc::downgrade(&db.inner);
+            let clock = db.inner.options.clock.clone();
+            thread::spawn(move || loop {
+                clock.sleep_micros(interval);
+                match weak.upgrade() {
+                    Some(db) if !db.is_shutting_down.load(Ordering::Acquire) => {
+                        if let Err(e) = db.load_read_only() {
+                            warn!("[secondary] Fail catching up with the primary: {}", e);
+                        }
+                    }
+                    _ => break,
+                }
+            });
+        }
+        Ok(db)
+    }
+
+    fn open_without_writing(options: Options, db_name: String, mode: OpenMode) -> Result<Self> {
+        if !options.table_file_naming.is_valid() {
+            return Err(WickErr::new(
+                Status::InvalidArgument,
+                Some("invalid table file naming"),
+            ));
+        }
+        let lock_read_only = options.lock_read_only;
+        let mut db = DBImpl::new(options, db_name);
+        db.mode = mode;
+        if mode == OpenMode::ReadOnly && lock_read_only {
+            db.acquire_read_only_lock()?;
+        }
+        db.load_read_only()?;
+        db.check_table_files(&db.versions.lock().unwrap())?;
+        Ok(WickDB {
             inner: Arc::new(db),
-        };
-        wick_db.process_compaction();
-        wick_db.process_batch();
-        wick_db.inner.maybe_schedule_compaction();
-        Ok(wick_db)
+        })
+    }
+
+    /// Makes a secondary instance see the latest state of the primary by reloading
+    /// the MANIFEST and the WAL files. The reads running concurrently might see the
+    /// state before or after catching up.
+    ///
+    /// This could fail when the primary removes some files being loaded, which is
+    /// fine to retry later.
+    pub fn try_catch_up_with_primary(&self) -> Result<()> {
+        if self.inner.mode != OpenMode::Secondary {
+            return Err(WickErr::new(
+                Status::NotSupported,
+                Some("not a secondary instance"),
+            ));
+        }
+        self.inner.load_read_only()
+    }
+
+    /// Drops all the table files whose keys are fully contained in the user key
+    /// range `[begin, end]` without any compaction, and returns the number of the
+    /// dropped files. `None` means unbounded.
+    ///
+    /// This is a fast way to reclaim the space of a key range retired in bulk.
+    /// The keys in the range are not guaranteed to be gone since the ones in the
+    /// memtables, the files partially overlapping the range and the files being
+    /// compacted are kept. And the older versions of a key in the kept files
+    /// might become visible again. The snapshots are not protected either.
+    pub fn delete_files_in_range(&self, begin: Option<Slice>, end: Option<Slice>) -> Result<usize> {
+        self.inner.delete_files_in_range(begin, end)
+    }
+
+    /// Returns the approximate sizes of the `ranges` in the table files of the current
+    /// version, interpolated by the offsets the bounds fall into in the index blocks,
+    /// which tells when a shard is big enough to split. The memtables are counted
+    /// only with `SizeApproximationOptions::include_memtables`.
+    ///
+    /// The raw on-disk bytes overestimate a range a lot after deleting in bulk until the
+    /// deletions are compacted. With `SizeApproximationOptions::estimate_live_bytes`,
+    /// the live bytes are estimated by assuming every deletion deletes an older entry
+    /// of the same size, with the deletions in a table spreading evenly over its
+    /// deleted range. wickdb has no range deletion, so only the point deletions
+    /// are accounted.
+    pub fn get_approximate_sizes(
+        &self,
+        ranges: &[Range],
+        options: SizeApproximationOptions,
+    ) -> Result<Vec<ApproximateSize>> {
+        self.inner.get_approximate_sizes(ranges, options)
+    }
+
+    /// Summarizes the deletions in the table files of every level of the current
+    /// version, which helps decide whether a compaction is worth forcing to reclaim
+    /// the space after deleting in bulk. The deletions in the memtables are not
+    /// counted.
+    ///
+    /// Every table is scanned once for its deletions, and the results are cached
+    /// until the table is deleted.
+    pub fn tombstone_stats(&self) -> Result<Vec<LevelTombstoneStats>> {
+        self.inner.tombstone_stats()
+    }
+
+    /// Returns the estimated number of the keys in the db by the entry counts in the
+    /// properties blocks of the table files and in the memtables, where every deletion
+    /// is assumed to hide one entry. The overwritten entries not compacted yet are
+    /// counted repeatedly, so the estimation is accurate only when there are few
+    /// overwrites.
+    pub fn estimate_num_keys(&self) -> Result<u64> {
+        self.inner.estimate_num_keys()
+    }
+
+    /// Returns the CRC32C of the value of `key`, or `None` if the db doesn't contain
+    /// the key. The digests of a value only depend on its bytes, so the replicas are
+    /// able to check their values against each other by exchanging the digests
+    /// instead of the values. The value is still read from the db but never leaves it.
+    pub fn get_value_digest(&self, read_opt: ReadOptions, key: Slice) -> Result<Option<u32>> {
+        Ok(self
+            .get(read_opt, key)?
+            .map(|value| crc32::value(value.as_slice())))
+    }
+
+    /// Returns the digest of the live entries with the user keys in `[start, end)` seen
+    /// by `read_opt`, where `None` stands for an unbounded side. The bounds override
+    /// `ReadOptions::iterate_lower_bound` and `ReadOptions::iterate_upper_bound`.
+    ///
+    /// Two replicas holding the same entries in the range get the same digest no matter
+    /// how the entries are laid out in the memtables and the tables, so a mismatched
+    /// range could be narrowed down by comparing the digests of its halves.
+    pub fn digest_range(
+        &self,
+        mut read_opt: ReadOptions,
+        start: Option<Slice>,
+        end: Option<Slice>,
+    ) -> Result<RangeDigest> {
+        read_opt.iterate_lower_bound = start.map(|s| s.as_slice().to_vec());
+        read_opt.iterate_upper_bound = end.map(|e| e.as_slice().to_vec());
+        let lower = read_opt.iterate_lower_bound.clone();
+        let mut iter = self.iter(read_opt);
+        match lower {
+            Some(lower) => iter.seek(&Slice::from(lower.as_slice())),
+            None => iter.seek_to_first(),
+        }
+        let mut result = RangeDigest::default();
+        let mut lengths = vec![];
+        while iter.valid() {
+            let (key, value) = (iter.key(), iter.value());
+            lengths.clear();
+            VarintU32::put_varint(&mut lengths, key.size() as u32);
+            VarintU32::put_varint(&mut lengths, value.size() as u32);
+            result.digest = crc32::extend(result.digest, lengths.as_slice());
+            result.digest = crc32::extend(result.digest, key.as_slice());
+            result.digest = crc32::extend(result.digest, value.as_slice());
+            result.num_entries += 1;
+            iter.next();
+        }
+        iter.status()?;
+        Ok(result)
+    }
+
+    /// Returns the file numbers and the `TableProperties` of all the table files in
+    /// the current version from level 0 to the last level, which tell what takes the
+    /// space of the db. The properties of a table are read once and kept in memory
+    /// until the table is deleted.
+    pub fn get_properties_of_all_tables(&self) -> Result<Vec<(u64, TableProperties)>> {
+        let current = self.inner.versions.lock().unwrap().current();
+        let mut result = vec![];
+        for level in 0..self.inner.options.max_levels as usize {
+            for f in current.get_level_files(level).iter() {
+                let properties = self.inner.table_cache.properties(f.number, f.file_size)?;
+                result.push((f.number, properties));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Aggregates the histograms of the key sizes and the value sizes recorded in
+    /// the table files of the current version when `Options::record_kv_size_histograms`
+    /// is set. The entries in the memtables are not counted, and the obsolete versions
+    /// of a key in different tables are counted separately.
+    pub fn kv_size_histograms(&self) -> Result<KvSizeHistograms> {
+        self.inner.kv_size_histograms()
+    }
+
+    /// Returns the comparator ordering the user keys of the db
+    pub fn comparator(&self) -> Arc<dyn Comparator> {
+        self.inner.internal_comparator.user_comparator.clone()
+    }
+
+    /// Returns an iterator merging the db with the `overlays` supplied by the caller,
+    /// e.g. the uncommitted changes, whose keys must be sorted by `comparator`.
+    /// A key in several iterators is yielded by each of them, from the overlays in
+    /// order to the db when iterating forward.
+    pub fn iter_with_overlays(
+        &self,
+        read_opt: ReadOptions,
+        mut overlays: Vec<Box<dyn Iterator>>,
+    ) -> MergingIterator {
+        overlays.push(self.iter(read_opt));
+        MergingIterator::from_iters(self.comparator(), overlays)
+    }
+
+    /// Creates an `IteratorPool` keeping up to `max_idle` dropped iterators for reuse
+    pub fn iterator_pool(&self, max_idle: usize) -> IteratorPool {
+        IteratorPool::new(self.inner.clone(), max_idle)
+    }
+
+    /// Returns the options set by `set_options` currently in effect
+    pub fn mutable_options(&self) -> MutableOptions {
+        *self.inner.mutable_options.read().unwrap()
+    }
+
+    /// Replaces the `MutableOptions` of the db, which take effect on the reads
+    /// started afterwards without reopening the db
+    pub fn set_options(&self, options: MutableOptions) {
+        info!("Set mutable options: {:?}", options);
+        *self.inner.mutable_options.write().unwrap() = options;
+    }
+
+    /// Stops scheduling the memtable flushes and the compactions and waits for the
+    /// scheduled ones to finish, so that the table files stay unchanged, e.g. while
+    /// they are copied for a backup. The calls are counted and the background work
+    /// resumes once every call is matched by `continue_background_work`.
+    ///
+    /// Note the writes stall once the memtables are full, and `flush_memtable` and
+    /// `compact_range` block until the background work resumes.
+    pub fn pause_background_work(&self) -> Result<()> {
+        let db = &self.inner;
+        let mut versions = db.versions.lock().unwrap();
+        db.bg_work_paused.fetch_add(1, Ordering::AcqRel);
+        while db.background_compactions_scheduled.load(Ordering::Acquire) > 0 {
+            versions = db.background_work_finished_signal.wait(versions).unwrap();
+        }
+        info!("Background work paused");
+        Ok(())
+    }
+
+    /// Resumes the background work paused by `pause_background_work`. Returns
+    /// `Status::InvalidArgument` if the background work is not paused.
+    pub fn continue_background_work(&self) -> Result<()> {
+        let db = &self.inner;
+        {
+            let _versions = db.versions.lock().unwrap();
+            let paused = db.bg_work_paused.load(Ordering::Acquire);
+            if paused == 0 {
+                return Err(WickErr::new(
+                    Status::InvalidArgument,
+                    Some("the background work is not paused"),
+                ));
+            }
+            db.bg_work_paused.store(paused - 1, Ordering::Release);
+            if paused > 1 {
+                return Ok(());
+            }
+        }
+        info!("Background work continued");
+        // the flushes and the compactions skipped during the pause
+        db.maybe_schedule_compaction();
+        Ok(())
+    }
+
+    /// Syncs the WAL so that all the logged writes so far survive a machine crash
+    pub fn flush_wal(&self) -> Result<()> {
+        self.inner.check_writable()?;
+        let mut versions = self.inner.versions.lock().unwrap();
+        match versions.record_writer.as_mut() {
+            Some(writer) => {
+                record_tick(&self.inner.options.statistics, Ticker::WalSync, 1);
+                writer.sync()
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Flushes the memtable into a level0 table and waits until the table is
+    /// installed, which makes all the writes so far durable including the ones
+    /// written with `WriteOptions::disable_wal`.
+    pub fn flush_memtable(&self) -> Result<()> {
+        self.inner.flush_memtable()
+    }
+
+    /// Returns the first and the last sequences of the writes with
+    /// `WriteOptions::disable_wal` that are not flushed yet and would be lost by a
+    /// crash, or `None` if there is no such write.
+    pub fn unlogged_sequence_range(&self) -> Option<(u64, u64)> {
+        let mem = *self.inner.mem_unlogged.lock().unwrap();
+        let im_mem = self.inner.im_mem.read().unwrap();
+        // from the oldest memtable to the newest one
+        im_mem
+            .iter()
+            .map(|m| m.unlogged)
+            .chain(std::iter::once(mem))
+            .flatten()
+            .fold(None, |range, (first, last)| {
+                Some((range.map_or(first, |(first, _)| first), last))
+            })
     }
 
-    // The thread take batches from the queue and apples them into memtable and WAL.
+    // The threads take batches from the queue and apply them into the WAL and the memtable
+    // in a pipeline: the WAL stage appends the next write groups into the WAL while the
+    // memtable stage is still inserting the previous ones.
     //
-    // Steps:
+    // Steps of the WAL stage:
     // 1. Grouping the batches in the queue into a big enough batch
     // 2. Make sure there is enough space in the memtable. This might trigger a minor compaction
     //    or even several major compaction.
-    // 3. Write into WAL (.log file)
-    // 4. Write into Memtable
-    // 5. Update sequence of version set
+    // 3. Assign the sequences and write into WAL (.log file)
+    // 4. Hand the groups over to the memtable stage
+    //
+    // Steps of the memtable stage:
+    // 1. Write into Memtable
+    // 2. Update sequence of version set in the order of the groups
+    // 3. Notify the writers
     fn process_batch(&self) {
         let db = self.inner.clone();
+        let (send, recv) = crossbeam_channel::unbounded();
+        self.process_memtable_insert(recv);
         thread::spawn(move || {
+            // The last sequence assigned, which is ahead of the last sequence of the
+            // version set while the groups are in the memtable stage
+            let mut assigned_seq = 0;
             loop {
                 if db.is_shutting_down.load(Ordering::Acquire) {
                     break;
                 }
                 let mut queue = db.batch_queue.lock().unwrap();
-                while queue.is_empty() {
+                while queue.is_empty() && !db.is_shutting_down.load(Ordering::Acquire) {
                     queue = db.process_batch_sem.wait(queue).unwrap();
                 }
-                let first = queue.pop_front().unwrap();
-                let mut size = first.batch.approximate_size();
-
-                // Allow the group to grow up to a maximum size, but if the
-                // original write is small, limit the growth so we do not slow
-                // down the small write too much
-                let mut max_size = 1 << 20;
-                if size <= 128 << 10 {
-                    max_size = size + (128 << 10)
-                }
-                let mut signals = vec![];
-                signals.push(first.signal.clone());
-                let mut grouped = first;
-
-                // Group several batches from queue
-                while !queue.is_empty() {
-                    let current = queue.pop_front().unwrap();
-                    if current.options.sync && !grouped.options.sync {
-                        // Do not include a sync write into a batch handled by a non-sync write.
-                        queue.push_front(current);
-                        break;
-                    }
-                    size += current.batch.approximate_size();
-                    if size > max_size {
-                        // Do not make batch too big
-                        break;
+                if queue.is_empty() {
+                    // Woken up by `close`
+                    break;
+                }
+                let mut groups = vec![Self::group_batches(&mut queue)];
+                let max_delay = db.options.wal_sync_max_delay_micros;
+                if groups[0].0.options.sync && max_delay > 0 {
+                    // Keep taking the groups arriving before the deadline so that
+                    // they are synced together with the first one
+                    let deadline = Instant::now() + Duration::from_micros(max_delay);
+                    loop {
+                        while !queue.is_empty() {
+                            groups.push(Self::group_batches(&mut queue));
+                        }
+                        let now = Instant::now();
+                        if now >= deadline {
+                            break;
+                        }
+                        queue = db
+                            .process_batch_sem
+                            .wait_timeout(queue, deadline - now)
+                            .unwrap()
+                            .0;
                     }
-                    grouped.batch.append(current.batch);
-                    signals.push(current.signal.clone());
                 }
                 // Release the queue lock
                 mem::drop(queue);
                 match db.make_room_for_write(false) {
                     Ok(mut versions) => {
-                        let mut last_seq = versions.last_sequence();
-                        grouped.batch.set_sequence(last_seq + 1);
-                        last_seq += u64::from(grouped.batch.get_count());
+                        let _w = StopWatch::new(
+                            db.options.clock.as_ref(),
+                            db.options.statistics.as_deref(),
+                            HistogramType::WriteMicros,
+                        );
+                        let mut last_seq = cmp::max(assigned_seq, versions.last_sequence());
                         // must initialize the WAL writer after `make_room_for_write`
                         let writer = versions.record_writer.as_mut().unwrap();
-                        let mut status = writer.add_record(&Slice::from(grouped.batch.data()));
-                        let mut sync_err = false;
-                        if status.is_ok() && grouped.options.sync {
-                            status = writer.sync();
-                            if status.is_err() {
-                                sync_err = true;
+                        let mut status = Ok(());
+                        let mut written = 0;
+                        // `last_seq` only advances through the groups logged
+                        for (grouped, _) in groups.iter_mut() {
+                            let first_seq = last_seq + 1;
+                            let end_seq = last_seq + u64::from(grouped.batch.get_count());
+                            grouped.batch.set_sequence(first_seq);
+                            if grouped.options.disable_wal {
+                                db.record_unlogged(first_seq, end_seq);
+                            } else {
+                                if grouped.options.sync {
+                                    if let Some(limiter) = &db.options.rate_limiter {
+                                        limiter.request(grouped.batch.data().len());
+                                    }
+                                }
+                                status = writer.add_record(&Slice::from(grouped.batch.data()));
+                                if status.is_err() {
+                                    break;
+                                }
                             }
+                            last_seq = end_seq;
+                            written += 1;
                         }
-                        if status.is_ok() {
-                            let memtable = db.mem.read().unwrap();
-                            status = grouped.batch.insert_into(&*memtable);
-                        }
-
-                        for signal in signals.iter() {
-                            if let Err(e) = signal.send(status.clone()) {
-                                error!(
-                                    "[process batch] Fail sending finshing signal to waiting batch: {}", e
-                                )
+                        let bytes_per_sync = db.options.wal_bytes_per_sync;
+                        let need_sync = match db.options.wal_sync_policy {
+                            SyncPolicy::Always => true,
+                            SyncPolicy::OnClose => {
+                                groups[0].0.options.sync
+                                    || (bytes_per_sync > 0
+                                        && writer.unsynced_bytes() >= bytes_per_sync)
                             }
-                        }
-                        if let Err(e) = status {
-                            if sync_err {
+                            SyncPolicy::Never => false,
+                        };
+                        // The groups logged before a failed one are synced as well
+                        if written > 0 && need_sync {
+                            record_tick(&db.options.statistics, Ticker::WalSync, 1);
+                            if let Err(e) = writer.sync() {
                                 // The state of the log file is indeterminate: the log record we
                                 // just added may or may not show up when the DB is re-opened.
                                 // So we force the DB into a mode where all future writes fail.
                                 db.record_bg_error(e.clone());
+                                status = Err(e);
+                                written = 0;
                             }
                         }
-                        versions.set_last_sequence(last_seq);
+                        assigned_seq = last_seq;
+                        // Counted before releasing the lock of the version set so that the
+                        // memtable is never rotated before the groups are inserted
+                        db.pending_memtable_inserts.fetch_add(1, Ordering::AcqRel);
+                        let task = MemTableInsertTask {
+                            groups,
+                            status,
+                            written,
+                            last_seq,
+                        };
+                        if send.send(task).is_err() {
+                            error!("[process batch] The memtable insertion thread has exited");
+                            break;
+                        }
                     }
                     Err(e) => {
-                        for signal in signals.iter() {
+                        for signal in groups.iter().flat_map(|(_, signals)| signals.iter()) {
                             if let Err(e) = signal.send(Err(e.clone())) {
                                 error!(
                                     "[process batch] Fail sending finishing signal to waiting batch: {}", e
@@ -294,47 +1097,303 @@ impl WickDB {
         });
     }
 
-    // Process a compaction work when receiving the signal.
-    // The compaction might run recursively since we produce new table files.
-    fn process_compaction(&self) {
+    // The memtable stage of `process_batch`. The groups are inserted in the order in
+    // which they are written into the WAL, and their sequences are visible to the
+    // reads only after they are completely inserted.
+    fn process_memtable_insert(&self, recv: Receiver<MemTableInsertTask>) {
         let db = self.inner.clone();
         thread::spawn(move || {
-            while let Ok(()) = db.do_compaction.1.recv() {
-                if db.is_shutting_down.load(Ordering::Acquire) {
-                    // No more background work when shutting down
-                    break;
-                } else if db.bg_error.read().unwrap().is_some() {
-                    // Non more background work after a background error
-                } else {
-                    db.background_compaction();
+            while let Ok(task) = recv.recv() {
+                let MemTableInsertTask {
+                    groups,
+                    mut status,
+                    mut written,
+                    last_seq,
+                } = task;
+                if written > 0 {
+                    let memtable = db.mem.read().unwrap();
+                    for (i, (grouped, _)) in groups[..written].iter().enumerate() {
+                        if let Err(e) = grouped.batch.insert_into(&memtable) {
+                            status = Err(e);
+                            written = i;
+                            break;
+                        }
+                    }
+                }
+                {
+                    let mut versions = db.versions.lock().unwrap();
+                    if versions.last_sequence() < last_seq {
+                        versions.set_last_sequence(last_seq);
+                    }
+                    db.pending_memtable_inserts.fetch_sub(1, Ordering::AcqRel);
+                }
+                db.memtable_inserted_signal.notify_all();
+                for (i, (_, signals)) in groups.iter().enumerate() {
+                    // The groups after the failed one are not written at all
+                    let res = match i.cmp(&written) {
+                        CmpOrdering::Less => Ok(()),
+                        CmpOrdering::Equal => status.clone(),
+                        CmpOrdering::Greater => Err(WickErr::new(
+                            Status::IOError,
+                            Some("a previous write in the same WAL sync failed"),
+                        )),
+                    };
+                    for signal in signals.iter() {
+                        if let Err(e) = signal.send(res.clone()) {
+                            error!(
+                                "[process batch] Fail sending finshing signal to waiting batch: {}",
+                                e
+                            )
+                        }
+                    }
                 }
-                db.background_compaction_scheduled
-                    .store(false, Ordering::Release);
-
-                // Previous compaction may have produced too many files in a level,
-                // so reschedule another compaction if needed
-                db.maybe_schedule_compaction();
-                db.background_work_finished_signal.notify_all();
             }
         });
     }
-}
 
-impl Clone for WickDB {
-    fn clone(&self) -> Self {
-        Self {
-            inner: self.inner.clone(),
+    // Pops the first batch in the queue and groups the following ones into it.
+    // Returns the grouped batch and the signals of all the grouped tasks.
+    fn group_batches(queue: &mut VecDeque<BatchTask>) -> (BatchTask, Vec<Sender<Result<()>>>) {
+        let first = queue.pop_front().unwrap();
+        let mut size = first.batch.approximate_size();
+
+        // Allow the group to grow up to a maximum size, but if the
+        // original write is small, limit the growth so we do not slow
+        // down the small write too much
+        let mut max_size = 1 << 20;
+        if size <= 128 << 10 {
+            max_size = size + (128 << 10)
+        }
+        let mut signals = vec![];
+        signals.push(first.signal.clone());
+        let mut grouped = first;
+
+        // Group several batches from queue
+        while !queue.is_empty() {
+            let current = queue.pop_front().unwrap();
+            if current.options.sync && !grouped.options.sync {
+                // Do not include a sync write into a batch handled by a non-sync write.
+                queue.push_front(current);
+                break;
+            }
+            if current.options.disable_wal != grouped.options.disable_wal {
+                // The batch is either logged as a whole or not at all
+                queue.push_front(current);
+                break;
+            }
+            size += current.batch.approximate_size();
+            if size > max_size {
+                // Do not make batch too big
+                queue.push_front(current);
+                break;
+            }
+            grouped.batch.append(current.batch);
+            signals.push(current.signal.clone());
+        }
+        (grouped, signals)
+    }
+
+    // Syncs the WAL every `wal_sync_interval_micros` if it has unsynced writes so that
+    // the non-sync writes become durable in a bounded time
+    fn process_wal_sync(&self) {
+        let interval = self.inner.options.wal_sync_interval_micros;
+        if interval == 0 || self.inner.options.wal_sync_policy == SyncPolicy::Never {
+            return;
+        }
+        // Holds a weak reference so that the thread exits once the db is dropped
+        let weak = Arc::downgrade(&self.inner);
+        let clock = self.inner.options.clock.clone();
+        thread::spawn(move || loop {
+            clock.sleep_micros(interval);
+            let db = match weak.upgrade() {
+                Some(db) if !db.is_shutting_down.load(Ordering::Acquire) => db,
+                _ => break,
+            };
+            let mut versions = db.versions.lock().unwrap();
+            if let Some(writer) = versions.record_writer.as_mut() {
+                if writer.unsynced_bytes() > 0 {
+                    record_tick(&db.options.statistics, Ticker::WalSync, 1);
+                    if let Err(e) = writer.sync() {
+                        // Same as a failed sync write, the state of the log is indeterminate
+                        warn!("[wal sync] Fail syncing the WAL: {}", e);
+                        db.record_bg_error(e);
+                    }
+                }
+            }
+        });
+    }
+
+    // Checks the write rate every `idle_check_interval_micros` and does the idle
+    // work once it's below `idle_write_bytes_per_sec`
+    fn process_idle_work(&self) {
+        let interval = self.inner.options.idle_check_interval_micros;
+        if interval == 0 {
+            return;
+        }
+        // Holds a weak reference so that the thread exits once the db is dropped
+        let weak = Arc::downgrade(&self.inner);
+        let clock = self.inner.options.clock.clone();
+        let mut last_written = self.inner.bytes_written.load(Ordering::Acquire);
+        thread::spawn(move || loop {
+            clock.sleep_micros(interval);
+            let db = match weak.upgrade() {
+                Some(db) if !db.is_shutting_down.load(Ordering::Acquire) => db,
+                _ => break,
+            };
+            let written = db.bytes_written.load(Ordering::Acquire);
+            let rate = (written - last_written) * 1_000_000 / interval;
+            last_written = written;
+            if rate < db.options.idle_write_bytes_per_sec {
+                db.do_idle_work();
+            }
+        });
+    }
+
+    // Checks the files older than `periodic_compaction_seconds` every
+    // `min(periodic_compaction_seconds, 3600)` seconds so that they are compacted
+    // even if no write or compaction happens
+    fn process_periodic_compaction(&self) {
+        let period = self.inner.options.periodic_compaction_seconds;
+        if period == 0 {
+            return;
+        }
+        let interval = period.min(3600) * 1_000_000;
+        // Holds a weak reference so that the thread exits once the db is dropped
+        let weak = Arc::downgrade(&self.inner);
+        let clock = self.inner.options.clock.clone();
+        thread::spawn(move || loop {
+            clock.sleep_micros(interval);
+            let db = match weak.upgrade() {
+                Some(db) if !db.is_shutting_down.load(Ordering::Acquire) => db,
+                _ => break,
+            };
+            db.maybe_schedule_compaction();
+        });
+    }
+
+    // Renews the lease of the lock every third of `lock_lease_expiry_micros`
+    fn process_lock_lease_renewal(&self) {
+        if self.inner.lock_lease.is_none() {
+            return;
+        }
+        let interval = (self.inner.options.lock_lease_expiry_micros / 3).max(1);
+        // Holds a weak reference so that the thread exits once the db is dropped
+        let weak = Arc::downgrade(&self.inner);
+        let clock = self.inner.options.clock.clone();
+        thread::spawn(move || loop {
+            clock.sleep_micros(interval);
+            let db = match weak.upgrade() {
+                Some(db) if !db.is_shutting_down.load(Ordering::Acquire) => db,
+                _ => break,
+            };
+            match db.renew_lock_lease() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => warn!("[lock lease] Fail renewing the lease: {}", e),
+            }
+        });
+    }
+
+    // Process a compaction work when receiving the signal.
+    // The compaction might run recursively since we produce new table files.
+    // There are `max_background_compactions` workers sharing the signal channel
+    // so that compactions which don't conflict with each other run concurrently.
+    fn process_compaction(&self) {
+        for _ in 0..self.inner.options.max_background_compactions {
+            let db = self.inner.clone();
+            thread::spawn(move || {
+                while let Ok(()) = db.do_compaction.1.recv() {
+                    let mut has_done_work = false;
+                    let shutting_down = db.is_shutting_down.load(Ordering::Acquire);
+                    if shutting_down {
+                        // No more background work when shutting down
+                    } else if db.bg_error.read().unwrap().is_some() {
+                        // Non more background work after a background error
+                    } else if db.bg_work_paused.load(Ordering::Acquire) > 0 {
+                        // Rescheduled by `continue_background_work`
+                    } else {
+                        has_done_work = db.background_compaction();
+                    }
+                    {
+                        // Decreased with the lock held so that the signal is never missed by
+                        // the waiters checking the counter with the lock
+                        let _versions = db.versions.lock().unwrap();
+                        db.background_compactions_scheduled
+                            .fetch_sub(1, Ordering::AcqRel);
+                    }
+                    if shutting_down {
+                        db.background_work_finished_signal.notify_all();
+                        break;
+                    }
+
+                    // Previous compaction may have produced too many files in a level,
+                    // so reschedule another compaction if needed.
+                    // If nothing has been done because of the conflicts, the running
+                    // compactions will reschedule when they finish.
+                    if has_done_work {
+                        db.maybe_schedule_compaction();
+                    }
+                    db.background_work_finished_signal.notify_all();
+                }
+            });
         }
     }
 }
 
+impl Clone for WickDB {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+// The region of the `LOCK` file locked exclusively by the writer and shared by
+// the read-only instances with `Options::lock_read_only`, which makes a writer and
+// a locked read-only instance exclude each other with `Status::Busy`, as well as
+// two writers on the storages supporting the region locks
+const WRITER_LOCK_REGION: (u64, u64) = (0, 1);
+
+// The number of the newest table files checked by `ConsistencyCheckLevel::Quick`
+const QUICK_CHECK_SAMPLE_FILES: usize = 8;
+
+// Locks the `WRITER_LOCK_REGION` of the `LOCK` file for a writer. The storages not
+// supporting the region locks rely on `File::lock` only.
+fn lock_writer_region(lock_file: &dyn File) -> Result<()> {
+    let (offset, len) = WRITER_LOCK_REGION;
+    match lock_file.lock_region(offset, len, LockKind::Exclusive) {
+        Err(e) if e.status() == Status::NotSupported => Ok(()),
+        Err(e) if e.status() == Status::Busy => Err(WickErr::new(
+            Status::Busy,
+            Some("the db is opened by another writer or a locked read-only instance"),
+        )),
+        r => r,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OpenMode {
+    ReadWrite,
+    // Opened by `WickDB::open_read_only`
+    ReadOnly,
+    // Opened by `WickDB::open_as_secondary`
+    Secondary,
+}
+
 pub struct DBImpl {
     env: Arc<dyn Storage>,
+    mode: OpenMode,
     internal_comparator: Arc<InternalKeyComparator>,
     options: Arc<Options>,
     // The physical path of wickdb
     db_name: String,
+    // The `LOCK` file held by the writer, or by the read-only instance with
+    // `Options::lock_read_only`
     db_lock: Option<Box<dyn File>>,
+    // The lease of `db_lock` acquired by this instance if `lock_lease_expiry_micros` is set
+    lock_lease: Option<LockLease>,
+    // Whether the lock has been taken over by another instance
+    lock_lost: AtomicBool,
 
     /*
      * Fields for write batch scheduling
@@ -350,44 +1409,153 @@ pub struct DBImpl {
 
     // signal of compaction finished
     background_work_finished_signal: Condvar,
-    // whether we have a compaction running
-    background_compaction_scheduled: AtomicBool,
-    // signal of schedule a compaction
-    do_compaction: (Sender<()>, Receiver<()>),
+    // The write groups written into the WAL but not inserted into the memtable yet
+    pending_memtable_inserts: AtomicUsize,
+    // Signaled with the lock of `versions` when a write group is inserted into the memtable
+    memtable_inserted_signal: Condvar,
+    // the number of scheduled background compactions
+    background_compactions_scheduled: AtomicUsize,
+    // signal of schedule a compaction. The sender is dropped by `close` so that
+    // the compaction workers exit and release the db.
+    do_compaction: (Mutex<Option<Sender<()>>>, Receiver<()>),
     // Though Memtable is thread safe with multiple readers and single writers and
     // all relative methods are using immutable borrowing,
     // we still need to mutate the field `mem` and `im_mem` in few situations.
     mem: ShardedLock<MemTable>,
-    im_mem: ShardedLock<Option<MemTable>>, // There is a compacted immutable table or not
+    // The immutable memtables waiting for the flush from the oldest to the newest,
+    // up to `max_write_buffer_number - 1`
+    im_mem: ShardedLock<Vec<ImmutableMemTable>>,
+    // Whether a worker is flushing the oldest immutable memtable
+    flushing_mem: AtomicBool,
+    // The bytes of the batches written so far, which tells the write rate
+    bytes_written: AtomicU64,
+    // Whether a compaction of all the level0 files is requested since the db is idle
+    idle_compaction_requested: AtomicBool,
+    // The (number, size) of the level0 tables flushed from the immutable memtable but
+    // not installed into the current version yet, which serve the reads in place of
+    // the released immutable memtable until the version edit is applied
+    flushed_tables: ShardedLock<Vec<(u64, u64)>>,
+    // Increased whenever `mem`, `im_mem` or `flushed_tables` is replaced, which tells
+    // whether an internal iterator still covers the latest memtables
+    memtable_generation: AtomicU64,
+    // The first and the last sequences of the writes skipping the WAL in `mem`,
+    // which are lost by a crash until the memtable is flushed
+    mem_unlogged: Mutex<Option<(u64, u64)>>,
     // Have we encountered a background error in paranoid mode
     bg_error: RwLock<Option<WickErr>>,
     // Whether the db is closing
     is_shutting_down: AtomicBool,
+    // Whether `close` is called, after which no write is accepted
+    is_closing: AtomicBool,
+    // The number of the `pause_background_work` calls not matched by
+    // `continue_background_work`. Nothing is scheduled while it's positive.
+    bg_work_paused: AtomicUsize,
+    // The keys being updated by `DB::update`
+    locked_keys: Mutex<HashSet<Vec<u8>>>,
+    // signal of some keys in `locked_keys` released
+    locked_keys_released: Condvar,
+    // The options changed by `WickDB::set_options`
+    mutable_options: RwLock<MutableOptions>,
 }
 
 unsafe impl Sync for DBImpl {}
 unsafe impl Send for DBImpl {}
 
+// A memtable waiting for the flush
+struct ImmutableMemTable {
+    mem: MemTable,
+    // The number of the log created when the memtable became immutable, which is
+    // the oldest log needed once the memtable is flushed
+    next_log_number: u64,
+    // The first and the last sequences of the writes skipping the WAL
+    unlogged: Option<(u64, u64)>,
+}
+
 impl Drop for DBImpl {
     #[allow(unused_must_use)]
     fn drop(&mut self) {
         self.is_shutting_down.store(true, Ordering::Release);
-        if let Some(lock) = self.db_lock.as_ref() {
-            lock.unlock();
-        }
+        self.sync_on_close();
+        self.release_lock();
     }
 }
 
 impl DBImpl {
+    // See `DB::close`. The lock is released even if the flush or the sync fails.
+    fn close(&self) -> Result<()> {
+        if self.is_closing.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let mut flushed = Ok(());
+        if self.mode == OpenMode::ReadWrite
+            && self.options.flush_on_close
+            && !self.is_shutting_down.load(Ordering::Acquire)
+        {
+            // the paused background work is resumed to flush the memtables
+            self.bg_work_paused.store(0, Ordering::Release);
+            flushed = self.flush_all_memtables();
+            if let Err(e) = &flushed {
+                warn!("[close] Fail flushing the memtables: {}", e);
+            }
+        }
+        self.is_shutting_down.store(true, Ordering::Release);
+        // The running compactions give up at their next key
+        {
+            let mut versions = self.versions.lock().unwrap();
+            while self
+                .background_compactions_scheduled
+                .load(Ordering::Acquire)
+                > 0
+            {
+                versions = self.background_work_finished_signal.wait(versions).unwrap();
+            }
+        }
+        // The idle compaction workers exit once the channel is disconnected, and
+        // the WAL stage exits with the memtable stage following it
+        self.do_compaction.0.lock().unwrap().take();
+        {
+            let _queue = self.batch_queue.lock().unwrap();
+            self.process_batch_sem.notify_all();
+        }
+        // The lock is released even if the sync fails
+        let synced = self.sync_on_close();
+        let unlocked = self.release_lock();
+        info!("[close] DB {} closed", self.db_name);
+        flushed.and(synced).and(unlocked)
+    }
+
+    // Syncs the WAL and the MANIFEST whose `SyncPolicy` is `OnClose`
+    fn sync_on_close(&self) -> Result<()> {
+        let mut versions = match self.versions.lock() {
+            Ok(versions) => versions,
+            Err(_) => return Ok(()),
+        };
+        if self.options.wal_sync_policy == SyncPolicy::OnClose {
+            if let Some(writer) = versions.record_writer.as_mut() {
+                if writer.unsynced_bytes() > 0 {
+                    record_tick(&self.options.statistics, Ticker::WalSync, 1);
+                    writer.sync()?;
+                }
+            }
+        }
+        if self.options.manifest_sync_policy == SyncPolicy::OnClose {
+            versions.sync_manifest()?;
+        }
+        Ok(())
+    }
+
     fn new(options: Options, db_name: String) -> Self {
         let o = Arc::new(options);
         let icmp = Arc::new(InternalKeyComparator::new(o.comparator.clone()));
         Self {
             env: o.env.clone(),
+            mode: OpenMode::ReadWrite,
             internal_comparator: icmp.clone(),
             options: o.clone(),
             db_name: db_name.clone(),
             db_lock: None,
+            lock_lease: None,
+            lock_lost: AtomicBool::new(false),
             batch_queue: Mutex::new(VecDeque::new()),
             process_batch_sem: Condvar::new(),
             table_cache: Arc::new(TableCache::new(
@@ -397,25 +1565,145 @@ impl DBImpl {
             )),
             versions: Mutex::new(VersionSet::new(db_name.clone(), o.clone())),
             background_work_finished_signal: Condvar::new(),
-            background_compaction_scheduled: AtomicBool::new(false),
-            do_compaction: crossbeam_channel::unbounded(),
-            mem: ShardedLock::new(MemTable::new(icmp)),
-            im_mem: ShardedLock::new(None),
+            pending_memtable_inserts: AtomicUsize::new(0),
+            memtable_inserted_signal: Condvar::new(),
+            background_compactions_scheduled: AtomicUsize::new(0),
+            do_compaction: {
+                let (sender, receiver) = crossbeam_channel::unbounded();
+                (Mutex::new(Some(sender)), receiver)
+            },
+            mem: ShardedLock::new(MemTable::with_rep_type(
+                icmp,
+                o.memtable_rep,
+                BlockArena::with_block_size(o.arena_block_size, o.memtable_huge_page_size),
+            )),
+            im_mem: ShardedLock::new(vec![]),
+            flushing_mem: AtomicBool::new(false),
+            bytes_written: AtomicU64::new(0),
+            idle_compaction_requested: AtomicBool::new(false),
+            flushed_tables: ShardedLock::new(vec![]),
+            memtable_generation: AtomicU64::new(0),
+            mem_unlogged: Mutex::new(None),
             bg_error: RwLock::new(None),
             is_shutting_down: AtomicBool::new(false),
+            is_closing: AtomicBool::new(false),
+            bg_work_paused: AtomicUsize::new(0),
+            locked_keys: Mutex::new(HashSet::new()),
+            locked_keys_released: Condvar::new(),
+            mutable_options: RwLock::new(MutableOptions::default()),
+        }
+    }
+
+    // Locks all the given keys at once, which avoids the deadlocks between the callers
+    // locking overlapping keys in different orders.
+    fn lock_keys(&self, keys: &[Slice]) -> KeyLockGuard<'_> {
+        let mut keys: Vec<Vec<u8>> = keys.iter().map(|k| k.as_slice().to_vec()).collect();
+        keys.sort();
+        keys.dedup();
+        let mut locked = self.locked_keys.lock().unwrap();
+        while keys.iter().any(|k| locked.contains(k)) {
+            locked = self.locked_keys_released.wait(locked).unwrap();
         }
+        for key in keys.iter() {
+            locked.insert(key.clone());
+        }
+        KeyLockGuard { db: self, keys }
     }
+
     fn snapshot(&self) -> Arc<Snapshot> {
         self.versions.lock().unwrap().new_snapshot()
     }
 
-    fn get(&self, options: ReadOptions, key: Slice) -> Result<Option<Slice>> {
+    // Returns an iterator yielding all the internal keys in the memtables and the current
+    // version, along with the version which must outlive the iterator
+    // Returns the sequence the reads with `options` see
+    // Applies the `MutableOptions` concerning the reads to `options`
+    pub(crate) fn apply_mutable_options(&self, options: &mut ReadOptions) {
+        if self.mutable_options.read().unwrap().ignore_filters {
+            options.ignore_filters = true;
+        }
+    }
+
+    fn read_sequence(&self, options: &ReadOptions) -> u64 {
+        match &options.snapshot {
+            Some(snapshot) => snapshot.sequence(),
+            None => self.versions.lock().unwrap().last_sequence(),
+        }
+    }
+
+    fn internal_iter(&self, read_opt: ReadOptions) -> (MergingIterator, Arc<Version>) {
+        let read_opt = Rc::new(read_opt);
+        let mut children = self.memtable_iters(&read_opt);
+        let (version, table_iters) = {
+            let versions = self.versions.lock().unwrap();
+            (
+                versions.current(),
+                versions.current_iters(read_opt, self.table_cache.clone()),
+            )
+        };
+        for iter in table_iters {
+            children.push(Rc::new(RefCell::new(iter)));
+        }
+        (
+            MergingIterator::new(self.internal_comparator.clone(), children),
+            version,
+        )
+    }
+
+    // Returns the iterators over the memtables and the flushed tables not installed
+    // into the version yet. They must be created before the iterators over the
+    // current version so that no flush in between is missed.
+    fn memtable_iters(&self, read_opt: &Rc<ReadOptions>) -> Vec<Rc<RefCell<Box<dyn Iterator>>>> {
+        let mut children = vec![];
+        children.push(Rc::new(RefCell::new(self.mem.read().unwrap().iter())));
+        for im_mem in self.im_mem.read().unwrap().iter().rev() {
+            children.push(Rc::new(RefCell::new(im_mem.mem.iter())));
+        }
+        for &(number, size) in self.flushed_tables.read().unwrap().iter() {
+            let iter = self.table_cache.new_iter(read_opt.clone(), number, size);
+            children.push(Rc::new(RefCell::new(iter)));
+        }
+        children
+    }
+
+    // Returns the sequence number of the newest entry of the user key `key`,
+    // including deletions, or `None` if there is no entry of `key`.
+    // The deletions are possible to be dropped by compactions unless a snapshot
+    // older than them is alive.
+    pub(crate) fn latest_sequence(&self, key: &[u8]) -> Result<Option<u64>> {
+        let (mut iter, _version) = self.internal_iter(ReadOptions::default());
+        let lkey = LookupKey::new(key, MAX_KEY_SEQUENCE);
+        iter.seek(&lkey.internal_key());
+        if iter.valid() {
+            if let Some(pkey) = ParsedInternalKey::decode_from(iter.key()) {
+                if self
+                    .internal_comparator
+                    .user_comparator
+                    .compare(pkey.user_key.as_slice(), key)
+                    == CmpOrdering::Equal
+                {
+                    return Ok(Some(pkey.seq));
+                }
+            }
+        }
+        iter.status()?;
+        Ok(None)
+    }
+
+    fn get(&self, mut options: ReadOptions, key: Slice) -> Result<Option<Vec<u8>>> {
         if self.is_shutting_down.load(Ordering::Acquire) {
             return Err(WickErr::new(
                 Status::NotSupported,
                 Some("Try to operate a closed db"),
             ));
         }
+        let _w = StopWatch::new(
+            self.options.clock.as_ref(),
+            self.options.statistics.as_deref(),
+            HistogramType::GetMicros,
+        );
+        options.check_cancelled()?;
+        self.apply_mutable_options(&mut options);
         let snapshot = match &options.snapshot {
             Some(snapshot) => snapshot.sequence(),
             None => self.versions.lock().unwrap().last_sequence(),
@@ -424,20 +1712,24 @@ impl DBImpl {
         // search the memtable
         if let Some(result) = self.mem.read().unwrap().get(&lookup_key) {
             match result {
-                Ok(value) => return Ok(Some(value)),
+                Ok(value) => return Ok(Some(value.copy())),
                 // mem.get only returns Err() when it get a Deletion of the key
                 Err(_) => return Ok(None),
             }
         }
-        // search the immutable memtable
-        if let Some(im_mem) = self.im_mem.read().unwrap().as_ref() {
-            if let Some(result) = im_mem.get(&lookup_key) {
+        // search the immutable memtables from the newest one
+        for im_mem in self.im_mem.read().unwrap().iter().rev() {
+            if let Some(result) = im_mem.mem.get(&lookup_key) {
                 match result {
-                    Ok(value) => return Ok(Some(value)),
+                    Ok(value) => return Ok(Some(value.copy())),
                     Err(_) => return Ok(None),
                 }
             }
         }
+        // search the flushed tables which are not installed yet
+        if let Some(result) = self.get_from_flushed_tables(&options, &lookup_key)? {
+            return Ok(result);
+        }
         let current = self.versions.lock().unwrap().current();
         let (value, seek_stats) = current.get(options, lookup_key, self.table_cache.clone())?;
         if current.update_stats(seek_stats) {
@@ -446,104 +1738,720 @@ impl DBImpl {
         Ok(value)
     }
 
-    // Record a sample of bytes read at the specified internal key
-    // Might schedule a background compaction.
-    fn record_read_sample(&self, key: Slice) {
-        if self
-            .versions
-            .lock()
-            .unwrap()
-            .current()
-            .record_read_sample(key)
+    fn multi_get(&self, mut options: ReadOptions, keys: &[Slice]) -> Result<Vec<Option<Vec<u8>>>> {
+        if self.is_shutting_down.load(Ordering::Acquire) {
+            return Err(WickErr::new(
+                Status::NotSupported,
+                Some("Try to operate a closed db"),
+            ));
+        }
+        options.check_cancelled()?;
+        self.apply_mutable_options(&mut options);
+        let snapshot = match &options.snapshot {
+            Some(snapshot) => snapshot.sequence(),
+            None => self.versions.lock().unwrap().last_sequence(),
+        };
+        let mut results = vec![None; keys.len()];
+        // The indexes of the keys not found in the memtables
+        let mut pending = vec![];
         {
-            self.maybe_schedule_compaction()
+            let mem = self.mem.read().unwrap();
+            let im_mem = self.im_mem.read().unwrap();
+            for (i, key) in keys.iter().enumerate() {
+                let lookup_key = LookupKey::new(key.as_slice(), snapshot);
+                let result = mem
+                    .get(&lookup_key)
+                    .or_else(|| im_mem.iter().rev().find_map(|m| m.mem.get(&lookup_key)));
+                match result {
+                    Some(Ok(value)) => results[i] = Some(value.copy()),
+                    // a deletion of the key
+                    Some(Err(_)) => {}
+                    None => pending.push(i),
+                }
+            }
+        }
+        let mut in_versions = vec![];
+        let mut version_keys = vec![];
+        for i in pending {
+            let lookup_key = LookupKey::new(keys[i].as_slice(), snapshot);
+            match self.get_from_flushed_tables(&options, &lookup_key)? {
+                Some(result) => results[i] = result,
+                None => {
+                    in_versions.push(i);
+                    version_keys.push(lookup_key);
+                }
+            }
+        }
+        if !version_keys.is_empty() {
+            let current = self.versions.lock().unwrap().current();
+            let (values, seek_stats) =
+                current.multi_get(options, &version_keys, self.table_cache.clone())?;
+            let mut needs_compaction = false;
+            for stats in seek_stats {
+                needs_compaction |= current.update_stats(stats);
+            }
+            if needs_compaction {
+                self.maybe_schedule_compaction()
+            }
+            for (i, value) in in_versions.into_iter().zip(values) {
+                results[i] = value;
+            }
         }
+        Ok(results)
     }
 
-    // Recover DB from `db_name`.
-    // Returns the newest VersionEdit and whether we need to persistent VersionEdit to Manifest
-    fn recover(&mut self) -> Result<(VersionEdit, bool)> {
-        let env = self.options.env.clone();
-
-        // Ignore error from `mkdir_all` since the creation of the DB is
-        // committed only when the descriptor is created, and this directory
-        // may already exist from a previous failed creation attempt.
-        let _ = env.mkdir_all(self.db_name.as_str());
-
-        // Try acquire file lock
-        let lock_file =
-            env.create(generate_filename(self.db_name.as_str(), FileType::Lock, 0).as_str())?;
-        lock_file.lock()?;
-        self.db_lock = Some(lock_file);
-        if !env.exists(generate_filename(self.db_name.as_str(), FileType::Current, 0).as_str()) {
-            if self.options.create_if_missing {
-                // Create new necessary files for DB
-                let mut new_db = VersionEdit::new(self.options.max_levels);
-                new_db.set_comparator_name(self.options.comparator.name().to_owned());
-                new_db.set_log_number(0);
-                new_db.set_next_file(2);
-                new_db.set_last_sequence(0);
-                let manifest_filenum = 1;
-                let manifest_filename =
-                    generate_filename(self.db_name.as_str(), FileType::Manifest, manifest_filenum);
-                let manifest = env.create(manifest_filename.as_str())?;
-                let mut manifest_writer = Writer::new(manifest);
-                let mut record = vec![];
-                new_db.encode_to(&mut record);
-                match manifest_writer.add_record(&Slice::from(&record)) {
-                    Ok(()) => update_current(env.clone(), self.db_name.as_str(), manifest_filenum)?,
-                    Err(e) => {
-                        env.remove(manifest_filename.as_str())?;
-                        return Err(e);
+    // Searches the key in the flushed tables not installed into the current version.
+    // Returns `None` if no entry of the key is found.
+    fn get_from_flushed_tables(
+        &self,
+        options: &ReadOptions,
+        lookup_key: &LookupKey,
+    ) -> Result<Option<Option<Vec<u8>>>> {
+        let tables = self.flushed_tables.read().unwrap();
+        if tables.is_empty() {
+            return Ok(None);
+        }
+        // the snapshot has been encoded in the lookup key
+        let opt = Rc::new(ReadOptions {
+            verify_checksums: options.verify_checksums,
+            fill_cache: options.fill_cache,
+            ignore_filters: options.ignore_filters,
+            ..ReadOptions::default()
+        });
+        let ikey = lookup_key.internal_key();
+        let ukey = lookup_key.user_key();
+        // the newer table comes later
+        for &(number, size) in tables.iter().rev() {
+            if let Some((encoded_key, value)) =
+                self.table_cache.get(opt.clone(), &ikey, number, size)?
+            {
+                match ParsedInternalKey::decode_from(Slice::from(&encoded_key)) {
+                    None => return Err(WickErr::new(Status::Corruption, Some("bad internal key"))),
+                    Some(parsed_key) => {
+                        if self
+                            .internal_comparator
+                            .user_comparator
+                            .compare(parsed_key.user_key.as_slice(), ukey.as_slice())
+                            == CmpOrdering::Equal
+                        {
+                            match parsed_key.value_type {
+                                ValueType::Value => return Ok(Some(Some(value))),
+                                ValueType::Deletion => return Ok(Some(None)),
+                                _ => {}
+                            }
+                        }
                     }
                 }
-            } else {
-                return Err(WickErr::new(
-                    Status::InvalidArgument,
-                    Some(Box::leak(
-                        (self.db_name.clone() + " does not exist (create_if_missing is false)")
-                            .into_boxed_str(),
-                    )),
-                ));
             }
-        } else if self.options.error_if_exists {
-            return Err(WickErr::new(
-                Status::InvalidArgument,
-                Some(Box::leak(
-                    (self.db_name.clone() + " exists (error_if_exists is true)").into_boxed_str(),
-                )),
-            ));
+        }
+        Ok(None)
+    }
+
+    fn get_property(&self, property: &str) -> Option<String> {
+        let property = property.strip_prefix("wickdb.")?;
+        if property == "estimate-num-keys" {
+            return self.estimate_num_keys().ok().map(|n| n.to_string());
         }
         let mut versions = self.versions.lock().unwrap();
-        let mut should_save_manifest = versions.recover()?;
+        if let Some(level) = property.strip_prefix("num-files-at-level") {
+            let level = level.parse::<usize>().ok()?;
+            if level >= self.options.max_levels as usize {
+                return None;
+            }
+            return Some(versions.level_files_count(level).to_string());
+        }
+        match property {
+            "stats" => {
+                let mut s = String::from(
+                    "                               Compactions\n\
+                     Level  Files Size(MB) Time(sec) Read(MB) Write(MB)\n\
+                     --------------------------------------------------\n",
+                );
+                for (level, stats) in versions.compaction_stats.iter().enumerate() {
+                    let files = versions.level_files_count(level);
+                    if stats.micros > 0 || files > 0 {
+                        s.push_str(
+                            format!(
+                                "{:>3} {:>8} {:>8.0} {:>9.0} {:>8.0} {:>9.0}\n",
+                                level,
+                                files,
+                                versions.level_files_size(level) as f64 / 1048576.0,
+                                stats.micros as f64 / 1e6,
+                                stats.bytes_read as f64 / 1048576.0,
+                                stats.bytes_written as f64 / 1048576.0
+                            )
+                            .as_str(),
+                        );
+                    }
+                }
+                Some(s)
+            }
+            "sstables" => Some(versions.current().debug_string()),
+            "table-seek-telemetry" => {
+                let mut s = String::from(
+                    "Level   File   Seeks IndexSteps DataSteps DataScanned\n\
+                     --------------------------------------------------\n",
+                );
+                let current = versions.current();
+                for level in 0..self.options.max_levels as usize {
+                    for f in current.get_level_files(level) {
+                        if let Some(report) = self.table_cache.seek_report(f.number) {
+                            s.push_str(
+                                format!(
+                                    "{:>5} {:>6} {:>7} {:>10.2} {:>9.2} {:>11.2}\n",
+                                    level,
+                                    f.number,
+                                    report.index.seeks,
+                                    report.index.avg_search_steps(),
+                                    report.data.avg_search_steps(),
+                                    report.data.avg_scanned_entries(),
+                                )
+                                .as_str(),
+                            );
+                        }
+                    }
+                }
+                Some(s)
+            }
+            "num-running-compactions" => Some(versions.running_compactions_count().to_string()),
+            "num-obsolete-sst-files" => Some(
+                self.obsolete_files_summary(&mut versions, FileType::Table)
+                    .0
+                    .to_string(),
+            ),
+            "obsolete-sst-files-size" => Some(
+                self.obsolete_files_summary(&mut versions, FileType::Table)
+                    .1
+                    .to_string(),
+            ),
+            "num-obsolete-wal-files" => Some(
+                self.obsolete_files_summary(&mut versions, FileType::Log)
+                    .0
+                    .to_string(),
+            ),
+            "obsolete-wal-files-size" => Some(
+                self.obsolete_files_summary(&mut versions, FileType::Log)
+                    .1
+                    .to_string(),
+            ),
+            "estimate-pending-compaction-bytes" => {
+                Some(versions.pending_compaction_bytes().to_string())
+            }
+            "approximate-memory-usage" => {
+                let mut total_usage = 0;
+                if let Some(cache) = &self.options.block_cache {
+                    total_usage += cache.total_charge();
+                }
+                total_usage += self.mem.read().unwrap().approximate_memory_usage();
+                for im_mem in self.im_mem.read().unwrap().iter() {
+                    total_usage += im_mem.mem.approximate_memory_usage();
+                }
+                Some(total_usage.to_string())
+            }
+            "cur-size-active-mem-table" => Some(
+                self.mem
+                    .read()
+                    .unwrap()
+                    .approximate_memory_usage()
+                    .to_string(),
+            ),
+            "cur-size-all-mem-tables" | "size-all-mem-tables" => {
+                let size_of = |m: &MemTable| {
+                    if property == "size-all-mem-tables" {
+                        m.approximate_memory_allocated()
+                    } else {
+                        m.approximate_memory_usage()
+                    }
+                };
+                let mut total = size_of(&self.mem.read().unwrap());
+                for im_mem in self.im_mem.read().unwrap().iter() {
+                    total += size_of(&im_mem.mem);
+                }
+                Some(total.to_string())
+            }
+            _ => None,
+        }
+    }
 
-        // Recover from all newer log files than the ones named in the
-        // MANIFEST (new log files may have been added by the previous
-        // incarnation without registering them in the MANIFEST).
-        //
-        // Note that PrevLogNumber() is no longer used, but we pay
-        // attention to it in case we are recovering a database
-        // produced by an older version of leveldb.
-        let min_log = versions.log_number();
-        let prev_log = versions.prev_log_number();
-        let all_files = env.list(self.db_name.as_str())?;
-        let mut logs_to_recover = vec![];
-        for filename in all_files.iter() {
-            if let Some((file_type, file_number)) = parse_filename(filename) {
-                if file_type == FileType::Log && (file_number >= min_log || file_number == prev_log)
-                {
-                    logs_to_recover.push(file_number);
+    fn kv_size_histograms(&self) -> Result<KvSizeHistograms> {
+        let current = self.versions.lock().unwrap().current();
+        let mut result = KvSizeHistograms::default();
+        for level in 0..self.options.max_levels as usize {
+            for f in current.get_level_files(level).iter() {
+                match self.table_cache.kv_size_histograms(f.number, f.file_size)? {
+                    Some((key_sizes, value_sizes)) => {
+                        result.key_sizes.merge(&key_sizes);
+                        result.value_sizes.merge(&value_sizes);
+                        result.num_tables += 1;
+                    }
+                    None => result.num_tables_without_histograms += 1,
                 }
             }
         }
+        Ok(result)
+    }
 
-        // Recover in the order in which the logs were generated
-        logs_to_recover.sort();
-        let mut max_sequence = 0;
-        let mut edit = VersionEdit::new(self.options.max_levels);
-        for (i, log_number) in logs_to_recover.iter().enumerate() {
-            let last_seq = self.replay_log_file(
-                &mut versions,
+    fn estimate_num_keys(&self) -> Result<u64> {
+        let (mut entries, mut deletions) = {
+            let mem = self.mem.read().unwrap();
+            (mem.num_entries(), mem.num_deletions())
+        };
+        for im_mem in self.im_mem.read().unwrap().iter() {
+            entries += im_mem.mem.num_entries();
+            deletions += im_mem.mem.num_deletions();
+        }
+        let current = self.versions.lock().unwrap().current();
+        for level in 0..self.options.max_levels as usize {
+            for f in current.get_level_files(level).iter() {
+                let properties = self.table_cache.properties(f.number, f.file_size)?;
+                entries += properties.num_entries;
+                deletions += properties.num_deletions;
+            }
+        }
+        // A deletion is not a key and most likely hides another entry
+        Ok(entries.saturating_sub(deletions * 2))
+    }
+
+    fn tombstone_stats(&self) -> Result<Vec<LevelTombstoneStats>> {
+        let current = self.versions.lock().unwrap().current();
+        let ucmp = self.internal_comparator.user_comparator.as_ref();
+        let mut result = vec![];
+        for level in 0..self.options.max_levels as usize {
+            let files = current.get_level_files(level);
+            let mut stats = LevelTombstoneStats {
+                level,
+                num_files: files.len(),
+                ..LevelTombstoneStats::default()
+            };
+            for f in files.iter() {
+                let table = self.table_cache.tombstone_stats(f.number, f.file_size)?;
+                stats.num_entries += table.num_entries;
+                stats.num_deletions += table.num_deletions;
+                if let Some((smallest, largest)) = table.deleted_range {
+                    stats.num_files_with_deletions += 1;
+                    stats.deleted_range = match stats.deleted_range.take() {
+                        Some((s, l)) => Some((
+                            cmp::min_by(s, smallest, |a, b| ucmp.compare(a, b)),
+                            cmp::max_by(l, largest, |a, b| ucmp.compare(a, b)),
+                        )),
+                        None => Some((smallest, largest)),
+                    };
+                }
+            }
+            result.push(stats);
+        }
+        Ok(result)
+    }
+
+    fn get_approximate_sizes(
+        &self,
+        ranges: &[Range],
+        options: SizeApproximationOptions,
+    ) -> Result<Vec<ApproximateSize>> {
+        let current = self.versions.lock().unwrap().current();
+        let ucmp = self.internal_comparator.user_comparator.as_ref();
+        let mut sizes = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            let start_ikey = InternalKey::new(
+                &Slice::from(range.start),
+                MAX_KEY_SEQUENCE,
+                VALUE_TYPE_FOR_SEEK,
+            );
+            let limit_ikey = InternalKey::new(
+                &Slice::from(range.limit),
+                MAX_KEY_SEQUENCE,
+                VALUE_TYPE_FOR_SEEK,
+            );
+            let (mut on_disk_bytes, mut entries, mut deletions) = (0, 0.0, 0.0);
+            for level in 0..self.options.max_levels as usize {
+                for f in current.get_level_files(level) {
+                    if ucmp.compare(f.largest.user_key(), range.start) == CmpOrdering::Less
+                        || ucmp.compare(f.smallest.user_key(), range.limit) != CmpOrdering::Less
+                    {
+                        continue;
+                    }
+                    // the bounds out of the table are replaced by its boundary
+                    let from = Some(start_ikey.data()).filter(|_| {
+                        ucmp.compare(range.start, f.smallest.user_key()) == CmpOrdering::Greater
+                    });
+                    let to = Some(limit_ikey.data()).filter(|_| {
+                        ucmp.compare(range.limit, f.largest.user_key()) != CmpOrdering::Greater
+                    });
+                    let (bytes, fraction) = if from.is_none() && to.is_none() {
+                        (f.file_size, 1.0)
+                    } else {
+                        let (bytes, data_size) =
+                            self.table_cache
+                                .approximate_size(f.number, f.file_size, from, to)?;
+                        (bytes, bytes as f64 / data_size.max(1) as f64)
+                    };
+                    on_disk_bytes += bytes;
+                    if !options.estimate_live_bytes {
+                        continue;
+                    }
+                    let stats = self.table_cache.tombstone_stats(f.number, f.file_size)?;
+                    entries += stats.num_entries as f64 * fraction;
+                    if let Some((smallest, largest)) = &stats.deleted_range {
+                        if ucmp.compare(largest, range.start) != CmpOrdering::Less
+                            && ucmp.compare(smallest, range.limit) == CmpOrdering::Less
+                        {
+                            deletions += stats.num_deletions as f64 * fraction;
+                        }
+                    }
+                }
+            }
+            let live_bytes = if options.estimate_live_bytes && entries > 0.0 {
+                let live_ratio = ((entries - 2.0 * deletions) / entries).max(0.0);
+                (on_disk_bytes as f64 * live_ratio).round() as u64
+            } else {
+                on_disk_bytes
+            };
+            let memtable_bytes = if options.include_memtables {
+                self.memtable_bytes_in_range(range)
+            } else {
+                0
+            };
+            sizes.push(ApproximateSize {
+                on_disk_bytes,
+                live_bytes,
+                memtable_bytes,
+            });
+        }
+        Ok(sizes)
+    }
+
+    // Sums the sizes of the entries in `range` in the active and the immutable memtables
+    fn memtable_bytes_in_range(&self, range: &Range) -> u64 {
+        let ucmp = self.internal_comparator.user_comparator.as_ref();
+        let start = InternalKey::new(
+            &Slice::from(range.start),
+            MAX_KEY_SEQUENCE,
+            VALUE_TYPE_FOR_SEEK,
+        );
+        let mut total = 0;
+        let mut sum = |mut iter: Box<dyn Iterator>| {
+            iter.seek(&Slice::from(start.data()));
+            while iter.valid()
+                && ucmp.compare(
+                    extract_user_key(iter.key().as_slice()).as_slice(),
+                    range.limit,
+                ) == CmpOrdering::Less
+            {
+                total += (iter.key().size() + iter.value().size()) as u64;
+                iter.next();
+            }
+        };
+        sum(self.mem.read().unwrap().iter());
+        for im_mem in self.im_mem.read().unwrap().iter() {
+            sum(im_mem.mem.iter());
+        }
+        total
+    }
+
+    fn estimate_keys_in_range(&self, start: Option<Slice>, end: Option<Slice>) -> Result<u64> {
+        let ucmp = self.internal_comparator.user_comparator.as_ref();
+        let seek_key = |k: &Slice| InternalKey::new(k, MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK);
+        let (start_ikey, end_ikey) = (start.as_ref().map(seek_key), end.as_ref().map(seek_key));
+        let before_end = |ukey: &[u8]| match &end {
+            Some(e) => ucmp.compare(ukey, e.as_slice()) == CmpOrdering::Less,
+            None => true,
+        };
+        // count the entries in the memtables, which are cheap to walk
+        let mut total = 0;
+        let mut count_in_mem = |mut iter: Box<dyn Iterator>| {
+            match &start_ikey {
+                Some(k) => iter.seek(&Slice::from(k.data())),
+                None => iter.seek_to_first(),
+            }
+            while iter.valid() && before_end(extract_user_key(iter.key().as_slice()).as_slice()) {
+                total += 1;
+                iter.next();
+            }
+        };
+        count_in_mem(self.mem.read().unwrap().iter());
+        for im_mem in self.im_mem.read().unwrap().iter() {
+            count_in_mem(im_mem.mem.iter());
+        }
+        let current = self.versions.lock().unwrap().current();
+        for level in 0..self.options.max_levels as usize {
+            for f in current.get_level_files(level) {
+                if let Some(s) = &start {
+                    if ucmp.compare(f.largest.user_key(), s.as_slice()) == CmpOrdering::Less {
+                        continue;
+                    }
+                }
+                if !before_end(f.smallest.user_key()) {
+                    continue;
+                }
+                // the keys out of the table are replaced by its boundary
+                let from = start_ikey.as_ref().filter(|_| {
+                    ucmp.compare(start.as_ref().unwrap().as_slice(), f.smallest.user_key())
+                        == CmpOrdering::Greater
+                });
+                let to = end_ikey
+                    .as_ref()
+                    .filter(|_| !before_end(f.largest.user_key()));
+                total += self.table_cache.estimate_entries(
+                    f.number,
+                    f.file_size,
+                    from.map(|k| k.data()),
+                    to.map(|k| k.data()),
+                )?;
+            }
+        }
+        Ok(total)
+    }
+
+    fn flush_memtable(&self) -> Result<()> {
+        self.check_writable()?;
+        self.flush_all_memtables()
+    }
+
+    // Rotates the memtable and waits until all the memtables are flushed
+    fn flush_all_memtables(&self) -> Result<()> {
+        let mut versions = self.make_room_for_write(true)?;
+        loop {
+            if let Some(e) = self.bg_error.write().unwrap().take() {
+                return Err(e);
+            }
+            if self.im_mem.read().unwrap().is_empty()
+                && self.flushed_tables.read().unwrap().is_empty()
+            {
+                return Ok(());
+            }
+            versions = self.background_work_finished_signal.wait(versions).unwrap();
+        }
+    }
+
+    // Extends the range of the sequences not logged in the memtable
+    fn record_unlogged(&self, first: u64, last: u64) {
+        let mut range = self.mem_unlogged.lock().unwrap();
+        *range = Some(match *range {
+            Some((f, _)) => (f, last),
+            None => (first, last),
+        });
+    }
+
+    // Record a sample of bytes read at the specified internal key
+    // Might schedule a background compaction.
+    fn record_read_sample(&self, key: Slice) {
+        if self
+            .versions
+            .lock()
+            .unwrap()
+            .current()
+            .record_read_sample(key)
+        {
+            self.maybe_schedule_compaction()
+        }
+    }
+
+    fn delete_files_in_range(&self, begin: Option<Slice>, end: Option<Slice>) -> Result<usize> {
+        if self.is_shutting_down.load(Ordering::Acquire) {
+            return Err(WickErr::new(
+                Status::NotSupported,
+                Some("Try to operate a closed db"),
+            ));
+        }
+        self.check_writable()?;
+        if let (Some(b), Some(e)) = (&begin, &end) {
+            if self
+                .internal_comparator
+                .user_comparator
+                .compare(b.as_slice(), e.as_slice())
+                == CmpOrdering::Greater
+            {
+                return Err(WickErr::new(
+                    Status::InvalidArgument,
+                    Some("begin key is greater than end key"),
+                ));
+            }
+        }
+        let mut versions = self.versions.lock().unwrap();
+        let count = versions.delete_files_in_range(
+            begin.as_ref().map(|b| b.as_slice()),
+            end.as_ref().map(|e| e.as_slice()),
+        )?;
+        if count > 0 {
+            info!("Deleted {} files in range", count);
+            self.delete_obsolete_files(versions);
+        }
+        Ok(count)
+    }
+
+    // Acquires the file lock, taking over a lock whose lease is expired if `takeover`,
+    // and saves a new lease if `lock_lease_expiry_micros` is set
+    fn acquire_lock(&mut self, takeover: bool) -> Result<()> {
+        let env = self.options.env.clone();
+        let lock_name = generate_filename(self.db_name.as_str(), FileType::Lock, 0);
+        let mut lock_file = env.create(lock_name.as_str())?;
+        if let Err(e) = lock_writer_region(lock_file.as_ref()).and_then(|_| lock_file.lock()) {
+            let expiry = self.options.lock_lease_expiry_micros;
+            if !takeover || expiry == 0 {
+                return Err(e);
+            }
+            let now = self.options.clock.now_micros();
+            match LockLease::read(env.as_ref(), self.db_name.as_str())? {
+                Some(lease) if lease.is_expired(now, expiry) => {
+                    info!(
+                        "[lock lease] Take over the lock of pid {} on {} renewed at {}",
+                        lease.pid, lease.host, lease.renewed_at_micros
+                    );
+                }
+                _ => return Err(e),
+            }
+            // The stale owner keeps locking the removed file
+            env.remove(lock_name.as_str())?;
+            lock_file = env.create(lock_name.as_str())?;
+            lock_writer_region(lock_file.as_ref())?;
+            lock_file.lock()?;
+        }
+        self.db_lock = Some(lock_file);
+        if self.options.lock_lease_expiry_micros > 0 {
+            let lease = LockLease::new(self.options.clock.now_micros());
+            lease.write(env, self.db_name.as_str())?;
+            self.lock_lease = Some(lease);
+        }
+        Ok(())
+    }
+
+    // Locks the writer region of the `LOCK` file shared so that no writer opens the
+    // db while this read-only instance is open
+    fn acquire_read_only_lock(&mut self) -> Result<()> {
+        let lock_name = generate_filename(self.db_name.as_str(), FileType::Lock, 0);
+        let lock_file = self.env.open(lock_name.as_str())?;
+        let (offset, len) = WRITER_LOCK_REGION;
+        match lock_file.lock_region(offset, len, LockKind::Shared) {
+            Err(e) if e.status() == Status::Busy => {
+                return Err(WickErr::new(
+                    Status::Busy,
+                    Some("the db is opened by a writer"),
+                ))
+            }
+            r => r?,
+        }
+        self.db_lock = Some(lock_file);
+        Ok(())
+    }
+
+    // Releases the locks of `db_lock`
+    fn release_lock(&self) -> Result<()> {
+        let lock = match &self.db_lock {
+            Some(lock) => lock,
+            None => return Ok(()),
+        };
+        let (offset, len) = WRITER_LOCK_REGION;
+        match lock.unlock_region(offset, len) {
+            Err(e) if e.status() != Status::NotSupported => return Err(e),
+            _ => {}
+        }
+        // Only the writer holds the whole file lock
+        if self.mode == OpenMode::ReadWrite {
+            lock.unlock()
+        } else {
+            Ok(())
+        }
+    }
+
+    // Saves the lease with the current time. Returns false and fails the following
+    // writes if the lease has been taken over by another instance.
+    fn renew_lock_lease(&self) -> Result<bool> {
+        let lease = match &self.lock_lease {
+            Some(lease) => lease,
+            None => return Ok(false),
+        };
+        match LockLease::read(self.env.as_ref(), self.db_name.as_str())? {
+            Some(current) if current.owner_id != lease.owner_id => {
+                error!(
+                    "[lock lease] The lock is taken over by pid {} on {}",
+                    current.pid, current.host
+                );
+                self.lock_lost.store(true, Ordering::Release);
+                self.record_bg_error(WickErr::new(
+                    Status::IOError,
+                    Some("the lock of the db is taken over by another instance"),
+                ));
+                return Ok(false);
+            }
+            _ => {}
+        }
+        let renewed = LockLease {
+            renewed_at_micros: self.options.clock.now_micros(),
+            ..lease.clone()
+        };
+        renewed.write(self.env.clone(), self.db_name.as_str())?;
+        Ok(true)
+    }
+
+    // Recover DB from `db_name`.
+    // Returns the newest VersionEdit and whether we need to persistent VersionEdit to Manifest
+    fn recover(&mut self, takeover: bool) -> Result<(VersionEdit, bool)> {
+        let env = self.options.env.clone();
+
+        // Ignore error from `mkdir_all` since the creation of the DB is
+        // committed only when the descriptor is created, and this directory
+        // may already exist from a previous failed creation attempt.
+        let _ = env.mkdir_all(self.db_name.as_str());
+
+        self.acquire_lock(takeover)?;
+        if !env.exists(generate_filename(self.db_name.as_str(), FileType::Current, 0).as_str()) {
+            if self.options.create_if_missing {
+                // Create new necessary files for DB
+                let mut new_db = VersionEdit::new(self.options.max_levels);
+                new_db.set_comparator_name(self.options.comparator.name().to_owned());
+                new_db.set_log_number(0);
+                new_db.set_next_file(2);
+                new_db.set_last_sequence(0);
+                let manifest_filenum = 1;
+                let manifest_filename =
+                    generate_filename(self.db_name.as_str(), FileType::Manifest, manifest_filenum);
+                let manifest = env.create(manifest_filename.as_str())?;
+                let mut manifest_writer = Writer::new(manifest);
+                let mut record = vec![];
+                new_db.encode_to(&mut record);
+                let sync = self.options.manifest_sync_policy != SyncPolicy::Never;
+                match manifest_writer
+                    .add_record(&Slice::from(&record))
+                    .and_then(|_| if sync { manifest_writer.sync() } else { Ok(()) })
+                {
+                    Ok(()) => {
+                        update_current(env.clone(), self.db_name.as_str(), manifest_filenum, sync)?
+                    }
+                    Err(e) => {
+                        env.remove(manifest_filename.as_str())?;
+                        return Err(e);
+                    }
+                }
+            } else {
+                return Err(WickErr::new(
+                    Status::InvalidArgument,
+                    Some(Box::leak(
+                        (self.db_name.clone() + " does not exist (create_if_missing is false)")
+                            .into_boxed_str(),
+                    )),
+                ));
+            }
+        } else if self.options.error_if_exists {
+            return Err(WickErr::new(
+                Status::InvalidArgument,
+                Some(Box::leak(
+                    (self.db_name.clone() + " exists (error_if_exists is true)").into_boxed_str(),
+                )),
+            ));
+        }
+        let mut versions = self.versions.lock().unwrap();
+        let mut should_save_manifest = versions.recover()?;
+        self.options
+            .table_file_naming
+            .create_dirs(self.env.as_ref(), self.db_name.as_str())?;
+        self.recover_table_files(&versions)?;
+        self.check_table_files(&versions)?;
+        let logs_to_recover = self.logs_to_recover(&versions)?;
+        let mut max_sequence = 0;
+        let mut edit = VersionEdit::new(self.options.max_levels);
+        for (i, log_number) in logs_to_recover.iter().enumerate() {
+            let last_seq = self.replay_log_file(
+                &mut versions,
                 *log_number,
                 i == logs_to_recover.len() - 1,
                 &mut should_save_manifest,
@@ -565,47 +2473,103 @@ impl DBImpl {
         Ok((edit, should_save_manifest))
     }
 
-    // Replays the edits in the named log file and returns the last sequence of insertions
-    fn replay_log_file(
-        &self,
-        versions: &mut MutexGuard<VersionSet>,
-        log_number: u64,
-        last_log: bool,
-        save_manifest: &mut bool,
-        edit: &mut VersionEdit,
-    ) -> Result<u64> {
-        let file_name = generate_filename(self.db_name.as_str(), FileType::Log, log_number);
+    // Returns the numbers of the log files to recover in the order in which the
+    // logs were generated
+    fn logs_to_recover(&self, versions: &VersionSet) -> Result<Vec<u64>> {
+        // Recover from all newer log files than the ones named in the
+        // MANIFEST (new log files may have been added by the previous
+        // incarnation without registering them in the MANIFEST).
+        //
+        // Note that PrevLogNumber() is no longer used, but we pay
+        // attention to it in case we are recovering a database
+        // produced by an older version of leveldb.
+        let min_log = versions.log_number();
+        let prev_log = versions.prev_log_number();
+        let all_files = self.env.list(self.db_name.as_str())?;
+        let mut logs_to_recover = vec![];
+        for filename in all_files.iter() {
+            if let Some((file_type, file_number)) = parse_filename(filename) {
+                if file_type == FileType::Log && (file_number >= min_log || file_number == prev_log)
+                {
+                    logs_to_recover.push(file_number);
+                }
+            }
+        }
+        logs_to_recover.sort();
+        Ok(logs_to_recover)
+    }
 
-        // Open the log file
+    // Loads the current state of the db from the MANIFEST and the WAL files without
+    // creating or modifying any file, which is how a read-only or a secondary db
+    // opens and how a secondary db catches up with the primary.
+    fn load_read_only(&self) -> Result<()> {
+        if !self
+            .env
+            .exists(generate_filename(self.db_name.as_str(), FileType::Current, 0).as_str())
+        {
+            return Err(WickErr::new(
+                Status::InvalidArgument,
+                Some(Box::leak(
+                    (self.db_name.clone() + " does not exist").into_boxed_str(),
+                )),
+            ));
+        }
+        let mut versions = VersionSet::new(self.db_name.clone(), self.options.clone());
+        versions.recover()?;
+        self.recover_table_files(&versions)?;
+        let mem = self.new_memtable();
+        let mut max_sequence = versions.last_sequence();
+        for log_number in self.logs_to_recover(&versions)? {
+            max_sequence = max_sequence.max(self.replay_log_file_into_mem(log_number, &mem)?);
+        }
+        versions.set_last_sequence(max_sequence);
+        // Installs the new version before the memtable so that a concurrent read
+        // never misses the entries flushed from the old memtable
+        let mut current = self.versions.lock().unwrap();
+        *current = versions;
+        *self.mem.write().unwrap() = mem;
+        self.memtable_generation.fetch_add(1, Ordering::AcqRel);
+        Ok(())
+    }
+
+    // Creates an empty memtable backed by the configured rep
+    fn new_memtable(&self) -> MemTable {
+        let arena = BlockArena::with_block_size(
+            self.options.arena_block_size,
+            self.options.memtable_huge_page_size,
+        );
+        MemTable::with_rep_type(
+            self.internal_comparator.clone(),
+            self.options.memtable_rep,
+            arena,
+        )
+    }
+
+    // Replays the named log file into `mem` without flushing and returns the last
+    // sequence of insertions. A missing log file is ignored since the primary might
+    // have removed it after flushing.
+    fn replay_log_file_into_mem(&self, log_number: u64, mem: &MemTable) -> Result<u64> {
+        let file_name = generate_filename(self.db_name.as_str(), FileType::Log, log_number);
         let log_file = match self.env.open(file_name.as_str()) {
             Ok(f) => f,
             Err(e) => {
-                if self.options.paranoid_checks {
-                    return Err(e);
-                } else {
-                    info!("ignore errors when replaying log file : {:?}", e);
-                    return Ok(0);
-                }
+                info!("ignore errors when replaying log file : {:?}", e);
+                return Ok(0);
             }
         };
-
-        // We intentionally make Reader do checksumming even if
-        // paranoid_checks is false so that corruptions cause entire commits
-        // to be skipped instead of propagating bad information (like overly
-        // large sequence numbers).
-        let reporter = LogReporter::new();
+        let reporter = LogReporter::new(file_name.as_str());
         let mut reader = Reader::new(log_file, Some(Box::new(reporter.clone())), true, 0);
-        info!("Recovering log #{}", log_number);
-
-        // Read all the records and add to a memtable
-        let mut mem = None;
         let mut record_buf = vec![];
         let mut batch = WriteBatch::new();
         let mut max_sequence = 0;
-        let mut have_compacted = false; // indicates that maybe we need
         while reader.read_record(&mut record_buf) {
+            // The tail of the log might be being written by the primary
             if let Err(e) = reporter.result() {
-                return Err(e);
+                if self.options.paranoid_checks {
+                    return Err(e);
+                }
+                info!("ignore errors when replaying log file : {:?}", e);
+                break;
             }
             if record_buf.len() < HEADER_SIZE {
                 return Err(WickErr::new(
@@ -613,13 +2577,177 @@ impl DBImpl {
                     Some("log record too small"),
                 ));
             }
-            if mem.is_none() {
-                mem = Some(MemTable::new(self.internal_comparator.clone()))
-            }
-            let mem_ref = mem.as_ref().unwrap();
             batch.set_contents(&mut record_buf);
-            let last_seq = batch.get_sequence() + u64::from(batch.get_count()) - 1;
-            if let Err(e) = batch.insert_into(&mem_ref) {
+            batch.insert_into(mem)?;
+            max_sequence =
+                max_sequence.max(batch.get_sequence() + u64::from(batch.get_count()) - 1);
+        }
+        Ok(max_sequence)
+    }
+
+    #[inline]
+    fn check_writable(&self) -> Result<()> {
+        if self.is_closing.load(Ordering::Acquire) {
+            Err(WickErr::new(
+                Status::NotSupported,
+                Some("Try to write a closed db"),
+            ))
+        } else if self.lock_lost.load(Ordering::Acquire) {
+            Err(WickErr::new(
+                Status::IOError,
+                Some("the lock of the db is taken over by another instance"),
+            ))
+        } else if self.mode == OpenMode::ReadWrite {
+            Ok(())
+        } else {
+            Err(WickErr::new(
+                Status::NotSupported,
+                Some("Try to write a read-only db"),
+            ))
+        }
+    }
+
+    // Finds the table files named by any scheme and checks that all the live
+    // tables exist
+    fn recover_table_files(&self, versions: &VersionSet) -> Result<()> {
+        let naming = &self.options.table_file_naming;
+        let mut found = HashSet::new();
+        for (number, path) in naming.list_table_files(self.env.as_ref(), self.db_name.as_str())? {
+            if let Some(path) = path.to_str() {
+                if path != naming.table_file_name(self.db_name.as_str(), number) {
+                    self.table_cache.relocate(number, path.to_owned());
+                }
+                found.insert(number);
+            }
+        }
+        let current = versions.current();
+        let mut missing = vec![];
+        for level in 0..self.options.max_levels as usize {
+            for f in current.get_level_files(level) {
+                if !found.contains(&f.number) {
+                    missing.push(f.number);
+                }
+            }
+        }
+        if !missing.is_empty() {
+            return Err(WickErr::new(
+                Status::Corruption,
+                Some(Box::leak(
+                    format!(
+                        "{} missing files; e.g.: {}",
+                        missing.len(),
+                        naming.table_file_name(self.db_name.as_str(), missing[0])
+                    )
+                    .into_boxed_str(),
+                )),
+            ));
+        }
+        Ok(())
+    }
+
+    // Validates the table files of the current version as thoroughly as
+    // `Options::consistency_check_level` tells
+    fn check_table_files(&self, versions: &VersionSet) -> Result<()> {
+        let level = self.options.consistency_check_level;
+        if level == ConsistencyCheckLevel::Off {
+            return Ok(());
+        }
+        let current = versions.current();
+        let mut files = vec![];
+        for l in 0..self.options.max_levels as usize {
+            for f in current.get_level_files(l) {
+                files.push((f.number, f.file_size));
+            }
+        }
+        if level == ConsistencyCheckLevel::Quick {
+            // the newer files are the ones written right before a crash
+            files.sort_by_key(|f| std::cmp::Reverse(f.0));
+            files.truncate(QUICK_CHECK_SAMPLE_FILES);
+        }
+        for (number, file_size) in files {
+            let file_name = self.table_cache.table_file_name(number);
+            let check = || -> Result<()> {
+                let file = self.env.open(file_name.as_str())?;
+                let len = file.len()?;
+                if level != ConsistencyCheckLevel::Quick && len != file_size {
+                    return Err(WickErr::new_corruption(
+                        Some("table file size mismatches the MANIFEST"),
+                        CorruptionReport::new("table file size mismatches the MANIFEST", 0, len)
+                            .with_context(format!("size {} in the MANIFEST", file_size)),
+                    ));
+                }
+                let footer =
+                    read_footer(file.as_ref(), file_size, self.options.table_magic_number)?;
+                if level == ConsistencyCheckLevel::Full {
+                    verify_table_checksums(
+                        file.as_ref(),
+                        &footer,
+                        self.internal_comparator.clone(),
+                    )?;
+                }
+                Ok(())
+            };
+            check().map_err(|e| e.with_corrupted_file(file_name.as_str()))?;
+        }
+        info!("Checked the table files of {} by {:?}", self.db_name, level);
+        Ok(())
+    }
+
+    // Replays the edits in the named log file and returns the last sequence of insertions
+    fn replay_log_file(
+        &self,
+        versions: &mut MutexGuard<VersionSet>,
+        log_number: u64,
+        last_log: bool,
+        save_manifest: &mut bool,
+        edit: &mut VersionEdit,
+    ) -> Result<u64> {
+        let file_name = generate_filename(self.db_name.as_str(), FileType::Log, log_number);
+
+        // Open the log file
+        let log_file = match self.env.open(file_name.as_str()) {
+            Ok(f) => f,
+            Err(e) => {
+                if self.options.paranoid_checks {
+                    return Err(e);
+                } else {
+                    info!("ignore errors when replaying log file : {:?}", e);
+                    return Ok(0);
+                }
+            }
+        };
+
+        // We intentionally make Reader do checksumming even if
+        // paranoid_checks is false so that corruptions cause entire commits
+        // to be skipped instead of propagating bad information (like overly
+        // large sequence numbers).
+        let reporter = LogReporter::new(file_name.as_str());
+        let mut reader = Reader::new(log_file, Some(Box::new(reporter.clone())), true, 0);
+        info!("Recovering log #{}", log_number);
+
+        // Read all the records and add to a memtable
+        let mut mem = None;
+        let mut record_buf = vec![];
+        let mut batch = WriteBatch::new();
+        let mut max_sequence = 0;
+        let mut have_compacted = false; // indicates that maybe we need
+        while reader.read_record(&mut record_buf) {
+            if let Err(e) = reporter.result() {
+                return Err(e);
+            }
+            if record_buf.len() < HEADER_SIZE {
+                return Err(WickErr::new(
+                    Status::Corruption,
+                    Some("log record too small"),
+                ));
+            }
+            if mem.is_none() {
+                mem = Some(self.new_memtable())
+            }
+            let mem_ref = mem.as_ref().unwrap();
+            batch.set_contents(&mut record_buf);
+            let last_seq = batch.get_sequence() + u64::from(batch.get_count()) - 1;
+            if let Err(e) = batch.insert_into(&mem_ref) {
                 if self.options.paranoid_checks {
                     return Err(e);
                 } else {
@@ -629,7 +2757,14 @@ impl DBImpl {
             if last_seq > max_sequence {
                 max_sequence = last_seq
             }
-            if mem_ref.approximate_memory_usage() > self.options.write_buffer_size {
+            // Flushes the memtable once it's full so that the memory used by
+            // replaying a huge log is bounded by `write_buffer_size`
+            if mem_ref.approximate_memory_usage() >= self.options.write_buffer_size {
+                info!(
+                    "Flush the memtable of {} bytes while recovering log #{}",
+                    mem_ref.approximate_memory_usage(),
+                    log_number
+                );
                 have_compacted = true;
                 *save_manifest = true;
                 let iter = mem_ref.iter();
@@ -638,6 +2773,7 @@ impl DBImpl {
                     self.table_cache.clone(),
                     iter,
                     edit,
+                    None,
                 )?;
                 mem = None;
             }
@@ -647,12 +2783,13 @@ impl DBImpl {
             let log_file = reader.into_file();
             info!("Reusing old log file : {}", file_name);
             versions.record_writer = Some(Writer::new(log_file));
+            versions.record_writer_number = log_number;
             versions.set_log_number(log_number);
             if let Some(m) = mem {
                 *self.mem.write().unwrap() = m;
                 mem = None;
             } else {
-                *self.mem.write().unwrap() = MemTable::new(self.internal_comparator.clone());
+                *self.mem.write().unwrap() = self.new_memtable();
             }
         }
         if let Some(m) = &mem {
@@ -662,11 +2799,74 @@ impl DBImpl {
                 self.table_cache.clone(),
                 m.iter(),
                 edit,
+                None,
             )?;
         }
         Ok(max_sequence)
     }
 
+    // Returns the number and the total size of the obsolete files of `file_type`
+    // (only tables and logs) still on the disk, which `delete_obsolete_files` is
+    // not allowed to delete yet.
+    fn obsolete_files_summary(
+        &self,
+        versions: &mut VersionSet,
+        file_type: FileType,
+    ) -> (usize, u64) {
+        let file_size = |path: &str| self.env.open(path).and_then(|f| f.len()).ok();
+        let mut summary = (0, 0);
+        match file_type {
+            FileType::Table => {
+                // held by the older versions
+                for size in versions.obsolete_files().values() {
+                    summary.0 += 1;
+                    summary.1 += size;
+                }
+                // referenced by no version but left on the disk
+                let live = versions.live_files();
+                let naming = &self.options.table_file_naming;
+                let tables = naming
+                    .list_table_files(self.env.as_ref(), self.db_name.as_str())
+                    .unwrap_or_default();
+                for (number, path) in tables {
+                    if live.contains(&number)
+                        || versions.pending_outputs.contains(&number)
+                        || self
+                            .flushed_tables
+                            .read()
+                            .unwrap()
+                            .iter()
+                            .any(|(n, _)| *n == number)
+                    {
+                        continue;
+                    }
+                    if let Some(size) = path.to_str().and_then(file_size) {
+                        summary.0 += 1;
+                        summary.1 += size;
+                    }
+                }
+            }
+            FileType::Log => {
+                let files = self.env.list(self.db_name.as_str()).unwrap_or_default();
+                for file in files.iter() {
+                    if let Some((FileType::Log, number)) = parse_filename(file) {
+                        if number >= versions.log_number() || number == versions.prev_log_number() {
+                            continue;
+                        }
+                        let file_path =
+                            generate_filename(self.db_name.as_str(), FileType::Log, number);
+                        if let Some(size) = file_size(file_path.as_str()) {
+                            summary.0 += 1;
+                            summary.1 += size;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        summary
+    }
+
     // Delete any unneeded files and stale in-memory entries.
     #[allow(unused_must_use)]
     fn delete_obsolete_files(&self, mut versions: MutexGuard<VersionSet>) {
@@ -675,38 +2875,109 @@ impl DBImpl {
             // or may not have been committed, so we cannot safely garbage collect
             return;
         }
-        versions.lock_live_files();
+        let live = versions.live_files();
         // ignore IO error on purpose
         if let Ok(files) = self.env.list(self.db_name.as_str()) {
             for file in files.iter() {
                 if let Some((file_type, number)) = parse_filename(file) {
-                    let mut keep = true;
-                    match file_type {
+                    let keep = match file_type {
                         FileType::Log => {
-                            keep = number >= versions.log_number()
-                                || number == versions.prev_log_number()
+                            number >= versions.log_number() || number == versions.prev_log_number()
                         }
-                        FileType::Manifest => keep = number >= versions.manifest_number(),
-                        FileType::Table => keep = versions.pending_outputs.contains(&number),
+                        FileType::Manifest => number >= versions.manifest_number(),
                         // Any temp files that are currently being written to must
                         // be recorded in pending_outputs
-                        FileType::Temp => keep = versions.pending_outputs.contains(&number),
-                        _ => {}
-                    }
+                        FileType::Temp => versions.pending_outputs.contains(&number),
+                        // the tables are listed by the naming scheme below
+                        _ => true,
+                    };
                     if !keep {
-                        if file_type == FileType::Table {
-                            self.table_cache.evict(number)
-                        }
                         info!("Delete type={:?} #{}", file_type, number);
+                        let file_path = generate_filename(self.db_name.as_str(), file_type, number);
                         // ignore the IO error here
-                        self.env.remove(
-                            format!("{}{}{:?}", self.db_name.as_str(), MAIN_SEPARATOR, file)
-                                .as_str(),
-                        );
+                        let _ = self.env.remove(file_path.as_str());
+                    }
+                }
+            }
+        }
+        let naming = &self.options.table_file_naming;
+        if let Ok(tables) = naming.list_table_files(self.env.as_ref(), self.db_name.as_str()) {
+            for (number, path) in tables {
+                if live.contains(&number)
+                    || versions.pending_outputs.contains(&number)
+                    || self
+                        .flushed_tables
+                        .read()
+                        .unwrap()
+                        .iter()
+                        .any(|(n, _)| *n == number)
+                {
+                    continue;
+                }
+                let file_path = match path.to_str() {
+                    Some(p) => p.to_owned(),
+                    None => continue,
+                };
+                self.table_cache.evict(number);
+                info!("Delete type={:?} #{}", FileType::Table, number);
+                // ignore the IO error here
+                if self.env.remove(file_path.as_str()).is_ok() {
+                    let info = TableFileDeletionInfo {
+                        db_name: self.db_name.clone(),
+                        file_number: number,
+                        file_path,
+                    };
+                    for listener in self.options.listeners.iter() {
+                        listener.on_table_file_deleted(&info);
+                    }
+                }
+            }
+        }
+    }
+
+    // Asks every `pre_write_hooks` about the part of `batch` owned by each tenant,
+    // sleeping for the delays. Returns `Status::Busy` once a hook rejects.
+    fn run_pre_write_hooks(&self, batch: &WriteBatch) -> Result<()> {
+        if self.options.pre_write_hooks.is_empty() {
+            return Ok(());
+        }
+        // (tenant, batch_size, num_keys)
+        let mut tenants: Vec<(Vec<u8>, usize, usize)> = vec![];
+        batch.for_each_record(|key, size| {
+            let prefix_len = match &self.options.key_classifier {
+                Some(classifier) => classifier.tenant_prefix_len(key).min(key.len()),
+                None => 0,
+            };
+            let tenant = &key[..prefix_len];
+            match tenants.iter_mut().find(|t| t.0.as_slice() == tenant) {
+                Some(t) => {
+                    t.1 += size;
+                    t.2 += 1;
+                }
+                None => tenants.push((tenant.to_vec(), size, 1)),
+            }
+        });
+        for (tenant, batch_size, num_keys) in tenants.iter() {
+            let info = WriteInfo {
+                tenant: tenant.as_slice(),
+                batch_size: *batch_size,
+                num_keys: *num_keys,
+            };
+            for hook in self.options.pre_write_hooks.iter() {
+                match hook.before_write(&info) {
+                    WriteDecision::Accept => {}
+                    WriteDecision::Delay(micros) => self.options.clock.sleep_micros(micros),
+                    WriteDecision::Reject(reason) => {
+                        return Err(WickErr::new_from_raw(
+                            Status::Busy,
+                            Some("the write is rejected by a pre-write hook"),
+                            format!("{}: {}", hook.name(), reason).into(),
+                        ));
                     }
                 }
             }
         }
+        Ok(())
     }
 
     // Schedule the WriteBatch and wait for the result from the receiver.
@@ -718,9 +2989,19 @@ impl DBImpl {
                 Some("Try to operate a closed db"),
             ));
         }
+        self.check_writable()?;
+        if options.sync && options.disable_wal {
+            return Err(WickErr::new(
+                Status::InvalidArgument,
+                Some("sync writes must not disable the WAL"),
+            ));
+        }
         if batch.is_empty() {
             return Ok(());
         }
+        self.run_pre_write_hooks(&batch)?;
+        self.bytes_written
+            .fetch_add(batch.approximate_size() as u64, Ordering::AcqRel);
         let (send, recv) = crossbeam_channel::bounded(0);
         let task = BatchTask::new(batch, send, options);
         self.batch_queue.lock().unwrap().push_back(task);
@@ -733,22 +3014,49 @@ impl DBImpl {
 
     // Make sure there is enough space in memtable.
     // This method acquires the mutex of VersionSet and deliver it to the caller.
+    // Returns the reason to delay a write if any soft limit is reached
+    fn slowdown_cause(&self, versions: &VersionSet) -> Option<WriteStallCause> {
+        if self.options.fifo_max_table_files_size > 0 {
+            // The files are deleted instead of being compacted in the FIFO mode
+            None
+        } else if versions.level_files_count(0) >= self.options.l0_slowdown_writes_threshold {
+            Some(WriteStallCause::Level0Slowdown)
+        } else if self.options.soft_pending_compaction_bytes_limit > 0
+            && versions.pending_compaction_bytes()
+                >= self.options.soft_pending_compaction_bytes_limit
+        {
+            Some(WriteStallCause::PendingCompactionBytesSlowdown)
+        } else {
+            None
+        }
+    }
+
     fn make_room_for_write(&self, mut force: bool) -> Result<MutexGuard<VersionSet>> {
         let mut allow_delay = !force;
         let mut versions = self.versions.lock().unwrap();
         loop {
-            if let Some(e) = { self.bg_error.write().unwrap().take() } {
+            // The guard must be dropped here instead of living through the whole
+            // `if let` chain since `maybe_schedule_compaction` reads `bg_error`
+            let bg_error = self.bg_error.write().unwrap().take();
+            let slowdown = if allow_delay {
+                self.slowdown_cause(&versions)
+            } else {
+                None
+            };
+            if let Some(e) = bg_error {
                 return Err(e);
-            } else if allow_delay
-                && versions.level_files_count(0) >= self.options.l0_slowdown_writes_threshold
-            {
+            } else if let Some(cause) = slowdown {
                 // We are getting close to hitting a hard limit on the number of
-                // L0 files.  Rather than delaying a single write by several
-                // seconds when we hit the hard limit, start delaying each
-                // individual write by 1ms to reduce latency variance.  Also,
-                // this delay hands over some CPU to the compaction thread in
-                // case it is sharing the same core as the writer.
-                thread::sleep(Duration::from_micros(1000));
+                // L0 files or the pending compaction bytes.  Rather than delaying
+                // a single write by several seconds when we hit the hard limit,
+                // start delaying each individual write by `write_slowdown_micros`
+                // to reduce latency variance.  Also, this delay hands over some
+                // CPU to the compaction thread in case it is sharing the same
+                // core as the writer.
+                let _w = self.write_stall(cause);
+                self.options
+                    .clock
+                    .sleep_micros(self.options.write_slowdown_micros);
                 allow_delay = false; // do not delay a single write more than once
             } else if !force
                 && self.mem.read().unwrap().approximate_memory_usage()
@@ -756,26 +3064,60 @@ impl DBImpl {
             {
                 // There is room in current memtable
                 break;
-            } else if self.im_mem.read().unwrap().is_some() {
+            } else if self.im_mem.read().unwrap().len() + 1 >= self.options.max_write_buffer_number
+            {
                 info!("Current memtable full; waiting...");
+                // retries the memtables left by a failed flush, whose error has been taken
+                self.maybe_schedule_compaction();
+                let _w = self.write_stall(WriteStallCause::MemtableLimit);
                 versions = self.background_work_finished_signal.wait(versions).unwrap();
-            } else if versions.level_files_count(0) >= self.options.l0_stop_writes_threshold {
+            } else if self.options.fifo_max_table_files_size == 0
+                && versions.level_files_count(0) >= self.options.l0_stop_writes_threshold
+            {
                 info!("Too many L0 files; waiting...");
+                let _w = self.write_stall(WriteStallCause::Level0Stop);
+                versions = self.background_work_finished_signal.wait(versions).unwrap();
+            } else if self.options.fifo_max_table_files_size == 0
+                && self.options.hard_pending_compaction_bytes_limit > 0
+                && versions.pending_compaction_bytes()
+                    >= self.options.hard_pending_compaction_bytes_limit
+            {
+                info!("Too many pending compaction bytes; waiting...");
+                let _w = self.write_stall(WriteStallCause::PendingCompactionBytesStop);
                 versions = self.background_work_finished_signal.wait(versions).unwrap();
+            } else if self.pending_memtable_inserts.load(Ordering::Acquire) > 0 {
+                // The groups written into the current log must be inserted into the current
+                // memtable before rotating it since the log is dropped along with it
+                versions = self.memtable_inserted_signal.wait(versions).unwrap();
             } else {
                 // there must be no prev log
+                // sync the current log first so that the sync writes to the new log
+                // never survive a crash which drops the writes before them
+                if let Some(writer) = versions.record_writer.as_mut() {
+                    if self.options.wal_sync_policy != SyncPolicy::Never {
+                        record_tick(&self.options.statistics, Ticker::WalSync, 1);
+                        writer.sync()?;
+                    }
+                }
                 let new_log_num = versions.get_next_file_number();
                 let log_file = self.env.create(
                     generate_filename(self.db_name.as_str(), FileType::Log, new_log_num).as_str(),
                 )?;
                 versions.set_next_file_number(new_log_num + 1);
                 versions.record_writer = Some(Writer::new(log_file));
+                versions.record_writer_number = new_log_num;
                 // rotate the mem to immutable mem
-                let mut mem = self.mem.write().unwrap();
-                let memtable =
-                    mem::replace(&mut *mem, MemTable::new(self.internal_comparator.clone()));
-                let mut im_mem = self.im_mem.write().unwrap();
-                *im_mem = Some(memtable);
+                {
+                    let mut mem = self.mem.write().unwrap();
+                    let memtable = mem::replace(&mut *mem, self.new_memtable());
+                    // release the locks before scheduling the compaction which reads them
+                    self.im_mem.write().unwrap().push(ImmutableMemTable {
+                        mem: memtable,
+                        next_log_number: new_log_num,
+                        unlogged: self.mem_unlogged.lock().unwrap().take(),
+                    });
+                    self.memtable_generation.fetch_add(1, Ordering::AcqRel);
+                }
                 force = false; // do not force another compaction if have room
                 self.maybe_schedule_compaction();
             }
@@ -783,16 +3125,78 @@ impl DBImpl {
         Ok(versions)
     }
 
-    // Compact immutable memory table to level0 files
-    fn compact_mem_table(&self) {
+    // Notifies the listeners of a write stall and starts a watch recording
+    // the stall into the statistics
+    fn write_stall(&self, cause: WriteStallCause) -> StopWatch<'_> {
+        let info = WriteStallInfo {
+            db_name: self.db_name.clone(),
+            cause,
+        };
+        for listener in self.options.listeners.iter() {
+            listener.on_write_stall(&info);
+        }
+        let stats = self.options.statistics.as_deref();
+        if let Some(s) = stats {
+            s.record_tick(Ticker::WriteStall, 1);
+        }
+        StopWatch::new(
+            self.options.clock.as_ref(),
+            stats,
+            HistogramType::WriteStallMicros,
+        )
+        .with_elapsed_ticker(Ticker::WriteStallMicros)
+    }
+
+    // Compacts the oldest immutable memtable to level0 files.
+    // Returns false if there is no immutable memtable or another worker is flushing,
+    // since the memtables must be flushed one by one in order.
+    fn compact_mem_table(&self) -> bool {
+        if self.flushing_mem.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        let flushed = self.flush_oldest_mem_table();
+        self.flushing_mem.store(false, Ordering::Release);
+        flushed
+    }
+
+    fn flush_oldest_mem_table(&self) -> bool {
+        let start = self.options.clock.monotonic_micros();
+        let (im_mem, next_log_number, mut meta) = {
+            let mut versions = self.versions.lock().unwrap();
+            let (im_mem, next_log_number) = match self.im_mem.read().unwrap().first() {
+                Some(m) => (m.mem.clone(), m.next_log_number),
+                // the immutable memtables have been flushed by another worker
+                None => return false,
+            };
+            let meta = FileMetaData {
+                number: versions.inc_next_file_number(),
+                ..FileMetaData::default()
+            };
+            versions.pending_outputs.insert(meta.number);
+            (im_mem, next_log_number, meta)
+        };
+        // The table is built without the lock of `versions` so that the writes
+        // keep going into the current memtable. The immutable memtable stays
+        // readable while flushing it.
+        info!("Level-0 table #{} : started", meta.number);
+        let build_result = build_table(
+            self.options.clone(),
+            self.db_name.as_str(),
+            self.table_cache.clone(),
+            im_mem.iter(),
+            &mut meta,
+        );
         let mut versions = self.versions.lock().unwrap();
+        versions.pending_outputs.remove(&meta.number);
         let mut edit = VersionEdit::new(self.options.max_levels);
-        let mut im_mem = self.im_mem.write().unwrap();
-        match versions.write_level0_files(
+        let base = versions.current();
+        match versions.add_level0_file(
             self.db_name.as_str(),
-            self.table_cache.clone(),
-            im_mem.as_ref().unwrap().iter(),
+            &meta,
+            build_result,
+            start,
             &mut edit,
+            Some(base),
         ) {
             Ok(()) => {
                 if self.is_shutting_down.load(Ordering::Acquire) {
@@ -801,14 +3205,49 @@ impl DBImpl {
                         Some("Deleting DB during memtable compaction"),
                     ))
                 } else {
+                    // The flushed table has been synced, so it serves the reads in place of
+                    // the immutable memtable, which is released before writing the MANIFEST
+                    *self.flushed_tables.write().unwrap() = edit
+                        .new_files
+                        .iter()
+                        .map(|(_, f)| (f.number, f.file_size))
+                        .collect();
+                    let flushed = self.im_mem.write().unwrap().remove(0);
+                    self.memtable_generation.fetch_add(1, Ordering::AcqRel);
                     edit.prev_log_number = Some(0);
-                    edit.log_number = Some(versions.log_number());
+                    // the earlier logs are only needed by the flushed memtable
+                    edit.log_number = Some(next_log_number);
                     match versions.log_and_apply(&mut edit) {
                         Ok(()) => {
-                            *im_mem = None;
+                            self.flushed_tables.write().unwrap().clear();
+                            if !self.options.listeners.is_empty() {
+                                let (level, file_number, file_size) =
+                                    edit.new_files.first().map_or((0, 0, 0), |(level, f)| {
+                                        (*level, f.number, f.file_size)
+                                    });
+                                let info = FlushJobInfo {
+                                    db_name: self.db_name.clone(),
+                                    file_number,
+                                    level,
+                                    file_size,
+                                    micros: self.options.clock.monotonic_micros() - start,
+                                };
+                                for listener in self.options.listeners.iter() {
+                                    listener.on_flush_completed(&info);
+                                }
+                            }
+                            if let Err(e) = self.delete_fifo_files(&mut versions) {
+                                self.record_bg_error(e);
+                            }
                             self.delete_obsolete_files(versions);
                         }
                         Err(e) => {
+                            // The table is in no version and would be deleted as an
+                            // obsolete file, so the memtable is put back to serve the
+                            // reads and to be flushed again by the next try
+                            self.im_mem.write().unwrap().insert(0, flushed);
+                            self.flushed_tables.write().unwrap().clear();
+                            self.memtable_generation.fetch_add(1, Ordering::AcqRel);
                             self.record_bg_error(e);
                         }
                     }
@@ -818,172 +3257,760 @@ impl DBImpl {
                 self.record_bg_error(e);
             }
         }
+        true
     }
 
-    // The complete compaction process
-    fn background_compaction(&self) {
-        if self.im_mem.read().unwrap().is_some() {
-            // minor compaction
-            self.compact_mem_table();
-        } else {
-            let mut is_manual = false;
-            let mut versions = self.versions.lock().unwrap();
-            if let Some(mut compaction) = {
-                match versions.manual_compaction.take() {
-                    // manul compaction
-                    Some(mut manual) => {
-                        if manual.done {
-                            versions.pick_compaction()
-                        } else {
-                            let compaction = versions.compact_range(
-                                manual.level,
-                                manual.begin.clone(),
-                                manual.end.clone(),
-                            );
-                            manual.done = compaction.is_none();
-                            let begin = if let Some(begin) = &manual.begin {
-                                format!("{:?}", begin)
-                            } else {
-                                "(begin)".to_owned()
-                            };
-                            let end = if let Some(end) = &manual.end {
-                                format!("{:?}", end)
-                            } else {
-                                "(end)".to_owned()
-                            };
-                            let stop = if let Some(c) = &compaction {
-                                format!(
-                                    "{:?}",
-                                    c.inputs[CompactionInputsRelation::Source as usize]
-                                        .last()
-                                        .unwrap()
-                                        .largest
-                                        .clone()
-                                )
-                            } else {
-                                "(end)".to_owned()
-                            };
-                            info!(
-                                "Manual compaction at level-{} from {} .. {}; will stop at {}",
-                                manual.level, begin, end, stop
-                            );
-                            is_manual = true;
-                            versions.manual_compaction = Some(manual);
-                            compaction
-                        }
-                    }
-                    None => versions.pick_compaction(),
-                }
-            } {
-                if is_manual && compaction.is_trivial_move() {
-                    // just move file to next level
-                    let f = compaction.inputs[CompactionInputsRelation::Source as usize]
-                        .first()
-                        .unwrap();
-                    compaction.edit.delete_file(compaction.level, f.number);
-                    compaction.edit.add_file(
-                        compaction.level + 1,
-                        f.number,
-                        f.file_size,
-                        f.smallest.clone(),
-                        f.largest.clone(),
-                    );
-                    if let Err(e) = versions.log_and_apply(&mut compaction.edit) {
-                        debug!("Error in compaction: {:?}", &e);
-                        self.record_bg_error(e);
-                    }
-                    let current_summary = versions.current().level_summary();
-                    info!(
-                        "Moved #{} to level-{} {} bytes, current level summary: {}",
-                        f.number,
-                        compaction.level + 1,
-                        f.file_size,
-                        current_summary
-                    )
+    // Deletes the oldest table files in the FIFO mode if the total size exceeds the cap
+    fn delete_fifo_files(&self, versions: &mut VersionSet) -> Result<()> {
+        let deleted = versions.delete_fifo_files()?;
+        if deleted.is_empty() {
+            return Ok(());
+        }
+        let info = FifoDeletionInfo {
+            db_name: self.db_name.clone(),
+            deleted_files: deleted.iter().map(|(_, number, _)| *number).collect(),
+            deleted_bytes: deleted.iter().map(|(_, _, size)| *size).sum(),
+            total_size: (0..self.options.max_levels as usize)
+                .map(|level| versions.level_files_size(level))
+                .sum(),
+            watermark: deleted.iter().map(|(_, number, _)| *number).max().unwrap(),
+        };
+        info!(
+            "[fifo] Delete {} files with {} bytes, {} bytes remain",
+            info.deleted_files.len(),
+            info.deleted_bytes,
+            info.total_size
+        );
+        for listener in self.options.listeners.iter() {
+            listener.on_fifo_deletion(&info);
+        }
+        Ok(())
+    }
+
+    // The complete compaction process.
+    // Returns false if there is no work that doesn't conflict with the running compactions.
+    fn background_compaction(&self) -> bool {
+        // minor compaction, unless another worker is flushing
+        if !self.im_mem.read().unwrap().is_empty() && self.compact_mem_table() {
+            return true;
+        }
+        let mut is_manual = false;
+        let mut versions = self.versions.lock().unwrap();
+        let compaction = match versions.manual_compaction.take() {
+            // manul compaction
+            Some(mut manual) => {
+                if manual.done {
+                    versions.pick_compaction()
                 } else {
-                    let level = compaction.level;
+                    let compaction = versions.compact_range(
+                        manual.level,
+                        manual.begin.clone(),
+                        manual.end.clone(),
+                    );
+                    manual.done = compaction.is_none();
+                    let begin = if let Some(begin) = &manual.begin {
+                        format!("{:?}", begin)
+                    } else {
+                        "(begin)".to_owned()
+                    };
+                    let end = if let Some(end) = &manual.end {
+                        format!("{:?}", end)
+                    } else {
+                        "(end)".to_owned()
+                    };
+                    let stop = if let Some(c) = &compaction {
+                        format!(
+                            "{:?}",
+                            c.inputs[CompactionInputsRelation::Source as usize]
+                                .last()
+                                .unwrap()
+                                .largest
+                                .clone()
+                        )
+                    } else {
+                        "(end)".to_owned()
+                    };
                     info!(
-                        "Compacting {}@{} + {}@{} files",
-                        compaction.inputs[CompactionInputsRelation::Source as usize].len(),
-                        level,
-                        compaction.inputs[CompactionInputsRelation::Parent as usize].len(),
-                        level + 1
+                        "Manual compaction at level-{} from {} .. {}; will stop at {}",
+                        manual.level, begin, end, stop
                     );
-                    {
-                        let snapshots = &mut versions.snapshots;
-                        // Cleanup all redundant snapshots first
-                        snapshots.gc();
-                        if snapshots.is_empty() {
-                            compaction.oldest_snapshot_alive = versions.last_sequence();
-                        } else {
-                            compaction.oldest_snapshot_alive = snapshots.oldest().sequence();
-                        }
-                    }
-                    self.delete_obsolete_files(self.do_compaction(&mut compaction));
+                    is_manual = true;
+                    versions.manual_compaction = Some(manual);
+                    compaction
                 }
-                if !self.is_shutting_down.load(Ordering::Acquire) {
-                    if let Some(e) = self.bg_error.read().unwrap().as_ref() {
-                        info!("Compaction error: {:?}", e)
-                    }
+            }
+            None => versions.pick_compaction(),
+        };
+        // the bottommost files with too many deletions and then the files too old
+        // go after the other triggers
+        let compaction = match compaction {
+            None if !is_manual => self
+                .pick_deletion_compaction(&mut versions)
+                .or_else(|| self.pick_periodic_compaction(&mut versions)),
+            c => c,
+        };
+        let mut is_idle = false;
+        let compaction = compaction.or_else(|| {
+            if self.idle_compaction_requested.swap(false, Ordering::AcqRel) {
+                // compact all the level0 files at lowest priority
+                is_idle = true;
+                versions.compact_range(0, None, None).map(|mut c| {
+                    c.reason = CompactionReason::Idle;
+                    c
+                })
+            } else {
+                None
+            }
+        });
+        let mut compaction = match compaction {
+            Some(c) => c,
+            None => return false,
+        };
+        if is_manual && versions.is_compaction_conflicting(&compaction) {
+            // retry the manual compaction after the running ones finished
+            return false;
+        }
+        if is_idle && versions.is_compaction_conflicting(&compaction) {
+            // the next idle period requests it again
+            return false;
+        }
+        if !is_manual && compaction.is_trivial_move() {
+            // just move file to next level
+            let f = compaction.inputs[CompactionInputsRelation::Source as usize]
+                .first()
+                .unwrap();
+            compaction.edit.delete_file(compaction.level, f.number);
+            compaction.edit.add_file(
+                compaction.output_level(),
+                f.number,
+                f.file_size,
+                f.smallest.clone(),
+                f.largest.clone(),
+            );
+            let succeeded = match versions.log_and_apply(&mut compaction.edit) {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!("Error in compaction: {:?}", &e);
+                    self.record_bg_error(e);
+                    false
+                }
+            };
+            if !self.options.listeners.is_empty() {
+                let info = CompactionJobInfo {
+                    db_name: self.db_name.clone(),
+                    level: compaction.level,
+                    output_level: compaction.output_level(),
+                    input_files: vec![f.number],
+                    output_files: vec![f.number],
+                    bytes_read: 0,
+                    bytes_written: 0,
+                    micros: 0,
+                    is_trivial_move: true,
+                    succeeded,
+                    versions_per_key: Histogram::default(),
+                    dropped_versions: 0,
+                    reason: compaction.reason,
+                };
+                for listener in self.options.listeners.iter() {
+                    listener.on_compaction_completed(&info);
                 }
-                if is_manual {
-                    versions.manual_compaction.as_mut().unwrap().done = true;
+            }
+            let current_summary = versions.current().level_summary();
+            info!(
+                "Moved #{} to level-{} {} bytes, current level summary: {}",
+                f.number,
+                compaction.output_level(),
+                f.file_size,
+                current_summary
+            )
+        } else {
+            let level = compaction.level;
+            info!(
+                "Compacting {}@{} + {}@{} files",
+                compaction.inputs[CompactionInputsRelation::Source as usize].len(),
+                level,
+                compaction.inputs[CompactionInputsRelation::Parent as usize].len(),
+                compaction.output_level()
+            );
+            {
+                let snapshots = &mut versions.snapshots;
+                // Cleanup all redundant snapshots first
+                snapshots.gc();
+                if snapshots.is_empty() {
+                    compaction.oldest_snapshot_alive = versions.last_sequence();
+                } else {
+                    compaction.oldest_snapshot_alive = snapshots.oldest().sequence();
+                    compaction.newest_snapshot_alive = Some(snapshots.newest().sequence());
                 }
             }
+            // Release the lock during the compaction so that other compactions
+            // could be picked by the other workers
+            versions.register_compaction(&compaction);
+            mem::drop(versions);
+            versions = self.do_compaction(&mut compaction);
+            versions.release_compaction(&compaction);
+        }
+        if !self.is_shutting_down.load(Ordering::Acquire) {
+            if let Some(e) = self.bg_error.read().unwrap().as_ref() {
+                info!("Compaction error: {:?}", e)
+            }
+        }
+        if is_manual {
+            versions.manual_compaction.as_mut().unwrap().done = true;
         }
+        self.delete_obsolete_files(versions);
+        true
     }
 
-    // Merging files in level n into file in level n + 1 and
-    // keep the still-in-use files
-    fn do_compaction(&self, c: &mut Compaction) -> MutexGuard<VersionSet> {
-        let now = SystemTime::now();
-        let mut input_iter =
-            c.new_input_iterator(self.internal_comparator.clone(), self.table_cache.clone());
-        let mut mem_compaction_duration = 0;
-        input_iter.seek_to_first();
+    // Picks a compaction of a bottommost file with too many deletions.
+    // See `Options::bottommost_deletion_ratio`.
+    fn pick_deletion_compaction(&self, versions: &mut VersionSet) -> Option<Compaction> {
+        if self.options.fifo_max_table_files_size > 0 {
+            return None;
+        }
+        let current = versions.current();
+        for (level, file) in current.bottommost_deletion_files(&self.table_cache) {
+            let number = file.number;
+            if let Some(c) =
+                versions.pick_file_compaction(level, file, CompactionReason::BottommostDeletions)
+            {
+                info!("Compacting #{}@{} for its deletions", number, level);
+                record_tick(
+                    &self.options.statistics,
+                    Ticker::DeletionCompactionTriggered,
+                    1,
+                );
+                return Some(c);
+            }
+        }
+        None
+    }
 
-        // the current user key to be compacted
+    // Picks a compaction of a file older than `Options::periodic_compaction_seconds`
+    fn pick_periodic_compaction(&self, versions: &mut VersionSet) -> Option<Compaction> {
+        if self.options.fifo_max_table_files_size > 0 {
+            return None;
+        }
+        let current = versions.current();
+        let now_secs = self.options.clock.now_micros() / 1_000_000;
+        for (level, file) in current.periodic_compaction_files(&self.table_cache, now_secs) {
+            let number = file.number;
+            if let Some(c) = versions.pick_file_compaction(level, file, CompactionReason::Periodic)
+            {
+                info!("Compacting #{}@{} for its age", number, level);
+                record_tick(
+                    &self.options.statistics,
+                    Ticker::PeriodicCompactionTriggered,
+                    1,
+                );
+                return Some(c);
+            }
+        }
+        None
+    }
 
-        let mut current_ukey = Slice::default();
-        let mut has_current_ukey = false;
-        let mut last_sequence_for_key = u64::max_value();
+    // Returns true if the current version needs a compaction, including the
+    // ones of the bottommost files with too many deletions and the files too old
+    // not being compacted
+    fn needs_compaction(&self, versions: &VersionSet) -> bool {
+        if versions.needs_compaction() {
+            return true;
+        }
+        if self.options.fifo_max_table_files_size > 0 {
+            return false;
+        }
+        let current = versions.current();
+        let now_secs = self.options.clock.now_micros() / 1_000_000;
+        current
+            .bottommost_deletion_files(&self.table_cache)
+            .iter()
+            .chain(
+                current
+                    .periodic_compaction_files(&self.table_cache, now_secs)
+                    .iter(),
+            )
+            .any(|(_, f)| !versions.is_being_compacted(f.number))
+    }
 
-        let icmp = self.internal_comparator.clone();
-        let ucmp = icmp.user_comparator.as_ref();
-        let mut status = Ok(());
-        // Iterate every key
-        while input_iter.valid() && !self.is_shutting_down.load(Ordering::Acquire) {
-            // Prioritize immutable compaction work
-            if self.im_mem.read().unwrap().is_some() {
-                let imm_start = SystemTime::now();
-                self.compact_mem_table();
-                mem_compaction_duration = imm_start.elapsed().unwrap().as_micros() as u64;
-            }
+    // Requests a compaction of all the level0 files and opens the tables of the current
+    // version in the table cache while the db is idle. Nothing is done if there is
+    // any other background work, which always goes first.
+    fn do_idle_work(&self) {
+        if self.mode != OpenMode::ReadWrite
+            || self
+                .background_compactions_scheduled
+                .load(Ordering::Acquire)
+                > 0
+            || !self.im_mem.read().unwrap().is_empty()
+        {
+            return;
+        }
+        let current = {
+            let versions = self.versions.lock().unwrap();
+            if self.needs_compaction(&versions) {
+                return;
+            }
+            versions.current()
+        };
+        // The files are deleted instead of being compacted in the FIFO mode
+        let compact_level0 =
+            self.options.fifo_max_table_files_size == 0 && !current.get_level_files(0).is_empty();
+        if compact_level0 {
+            info!(
+                "[idle] Compacting {} level0 files",
+                current.get_level_files(0).len()
+            );
+            record_tick(&self.options.statistics, Ticker::IdleCompactionTriggered, 1);
+            self.idle_compaction_requested
+                .store(true, Ordering::Release);
+            self.maybe_schedule_compaction();
+        }
+        // The level0 files being compacted are not worth warming
+        let first_level = if compact_level0 { 1 } else { 0 };
+        let mut budget = self.options.table_cache_size();
+        for level in first_level..self.options.max_levels as usize {
+            for f in current.get_level_files(level).iter() {
+                if budget == 0 || self.is_shutting_down.load(Ordering::Acquire) {
+                    return;
+                }
+                budget -= 1;
+                if let Err(e) = self.table_cache.warm(f.number, f.file_size) {
+                    warn!("[idle] Fail warming table #{}: {}", f.number, e);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Collects the data blocks of all the input files of `c` by their index blocks.
+    // A file whose index block can't be read is reported as a single block
+    // covering the whole file.
+    fn collect_obsolete_blocks(&self, c: &Compaction) -> Vec<ObsoleteBlock> {
+        let mut files: Vec<&Arc<FileMetaData>> = c.inputs.iter().flatten().collect();
+        files.sort_by_key(|f| f.number);
+        let mut blocks = vec![];
+        for f in files {
+            let smallest = f.smallest.user_key().to_vec();
+            let largest = f.largest.user_key().to_vec();
+            match self.table_cache.data_blocks(f.number, f.file_size) {
+                Ok(handles) => {
+                    let last_index_key = handles.last().map(|(_, k)| k.clone());
+                    // An index entry may point to several blocks that share the bounds
+                    let mut lower = smallest;
+                    let mut prev_index_key: Option<Vec<u8>> = None;
+                    for (h, index_key) in handles {
+                        if let Some(prev) = &prev_index_key {
+                            if *prev != index_key {
+                                lower = extract_user_key(prev.as_slice()).copy();
+                            }
+                        }
+                        // The last index key may be a short successor beyond the file
+                        let upper = if Some(&index_key) == last_index_key.as_ref() {
+                            largest.clone()
+                        } else {
+                            extract_user_key(index_key.as_slice()).copy()
+                        };
+                        blocks.push(ObsoleteBlock {
+                            file_number: f.number,
+                            offset: h.offset(),
+                            size: h.size(),
+                            smallest_key: lower.clone(),
+                            largest_key: upper,
+                        });
+                        prev_index_key = Some(index_key);
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to read the index block of #{}: {:?}", f.number, e);
+                    blocks.push(ObsoleteBlock {
+                        file_number: f.number,
+                        offset: 0,
+                        size: f.file_size,
+                        smallest_key: smallest,
+                        largest_key: largest,
+                    });
+                }
+            }
+        }
+        blocks
+    }
+
+    // Compacts all the files in `level` into `level + 1` in the calling thread.
+    // Only used when no background compaction runs.
+    fn compact_level_fully(&self, level: usize) -> Result<()> {
+        loop {
+            let mut versions = self.versions.lock().unwrap();
+            let mut compaction = match versions.compact_range(level, None, None) {
+                Some(c) => c,
+                None => return Ok(()),
+            };
+            // No snapshot is alive
+            compaction.oldest_snapshot_alive = versions.last_sequence();
+            versions.register_compaction(&compaction);
+            mem::drop(versions);
+            versions = self.do_compaction(&mut compaction);
+            versions.release_compaction(&compaction);
+            if let Some(e) = self.bg_error.read().unwrap().as_ref() {
+                return Err(e.clone());
+            }
+            self.delete_obsolete_files(versions);
+        }
+    }
+
+    // Merging files in level n into file in level n + 1 and
+    // keep the still-in-use files
+    fn do_compaction(&self, c: &mut Compaction) -> MutexGuard<VersionSet> {
+        let start = self.options.clock.monotonic_micros();
+        let (mut status, mem_compaction_duration) = match &self.options.compaction_service {
+            // The tweaks of the encrypted blocks depend on the output file numbers
+            Some(service)
+                if self.options.compaction_filter.is_none()
+                    && self.options.block_cipher.is_none() =>
+            {
+                self.run_remote_compaction(service.as_ref(), c)
+            }
+            _ => self.run_local_compaction(c),
+        };
+        // The input files are still alive before the edit is applied
+        let obsolete_blocks = if status.is_ok() && !self.options.listeners.is_empty() {
+            self.collect_obsolete_blocks(c)
+        } else {
+            vec![]
+        };
+        // Calculate the stats of this compaction
+        let mut versions = self.versions.lock().unwrap();
+        let micros =
+            (self.options.clock.monotonic_micros() - start).saturating_sub(mem_compaction_duration);
+        let (bytes_read, bytes_written) = (c.bytes_read(), c.bytes_written());
+        versions.compaction_stats[c.output_level()].accumulate(micros, bytes_read, bytes_written);
+        if let Some(stats) = &self.options.statistics {
+            stats.measure_time(HistogramType::CompactionMicros, micros);
+            stats.record_tick(Ticker::CompactionBytesRead, bytes_read);
+            stats.record_tick(Ticker::CompactionBytesWritten, bytes_written);
+            stats.merge_histogram(HistogramType::CompactionVersionsPerKey, &c.versions_per_key);
+            stats.record_tick(Ticker::CompactionVersionsDropped, c.dropped_versions);
+            for (i, files) in c.inputs.iter().enumerate() {
+                let bytes = files.iter().map(|f| f.file_size).sum();
+                stats.record_level_tick(c.level + i, LevelTicker::CompactionBytesRead, bytes);
+            }
+            stats.record_level_tick(
+                c.output_level(),
+                LevelTicker::CompactionBytesWritten,
+                bytes_written,
+            );
+        }
+        if status.is_ok() {
+            info!(
+                "Compacted {}@{} + {}@{} files => {} bytes",
+                c.inputs[CompactionInputsRelation::Source as usize].len(),
+                c.level,
+                c.inputs[CompactionInputsRelation::Parent as usize].len(),
+                c.output_level(),
+                c.total_bytes,
+            );
+            c.apply_to_edit();
+            status = versions.log_and_apply(&mut c.edit);
+        }
+        if !self.options.listeners.is_empty() {
+            let info = CompactionJobInfo {
+                db_name: self.db_name.clone(),
+                level: c.level,
+                output_level: c.output_level(),
+                input_files: c
+                    .inputs
+                    .iter()
+                    .flat_map(|files| files.iter().map(|f| f.number))
+                    .collect(),
+                output_files: c.outputs.iter().map(|f| f.number).collect(),
+                bytes_read,
+                bytes_written,
+                micros,
+                is_trivial_move: false,
+                succeeded: status.is_ok(),
+                versions_per_key: c.versions_per_key.clone(),
+                dropped_versions: c.dropped_versions,
+                reason: c.reason,
+            };
+            for listener in self.options.listeners.iter() {
+                listener.on_compaction_completed(&info);
+            }
+            if status.is_ok() {
+                let info = CompactionObsoleteBlocksInfo {
+                    db_name: self.db_name.clone(),
+                    level: c.level,
+                    output_level: c.output_level(),
+                    blocks: obsolete_blocks,
+                };
+                for listener in self.options.listeners.iter() {
+                    listener.on_compaction_obsoleted_blocks(&info);
+                }
+            }
+        }
+        if let Err(e) = status {
+            self.record_bg_error(e)
+        }
+
+        let summary = versions.current().level_summary();
+        info!("compacted to : {}", summary);
+
+        // Close unclosed table builder and remove files in `pending_outputs`
+        if let Some(builder) = c.builder.as_mut() {
+            builder.close()
+        }
+        for output in c.outputs.iter() {
+            versions.pending_outputs.remove(&output.number);
+        }
+        versions
+    }
+
+    // Runs the compaction in this process, splitting it into subcompactions if possible
+    fn run_local_compaction(&self, c: &mut Compaction) -> (Result<()>, u64) {
+        let boundaries = c.subcompaction_boundaries(
+            self.internal_comparator.user_comparator.as_ref(),
+            self.options.max_subcompactions,
+        );
+        if boundaries.is_empty() {
+            self.run_compaction(c)
+        } else {
+            self.run_subcompactions(c, boundaries)
+        }
+    }
+
+    // Delegates the compaction to the `CompactionService` and collects the
+    // output tables into `c`. Falls back to the local compaction on failure.
+    fn run_remote_compaction(
+        &self,
+        service: &dyn CompactionService,
+        c: &mut Compaction,
+    ) -> (Result<()>, u64) {
+        let job = {
+            let mut versions = self.versions.lock().unwrap();
+            let job_id = versions.inc_next_file_number();
+            let table_options = self.options.table_options_for_level(c.output_level());
+            let level = c.level;
+            let bottommost = c.is_bottommost(&self.internal_comparator);
+            CompactionJob {
+                job_id,
+                db_name: self.db_name.clone(),
+                comparator: self.options.comparator.name().to_owned(),
+                level: c.level,
+                output_level: c.output_level(),
+                inputs: c
+                    .inputs
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(delta, files)| {
+                        files.iter().map(move |f| CompactionInput {
+                            level: level + delta,
+                            path: self.table_cache.table_file_name(f.number),
+                            number: f.number,
+                            file_size: f.file_size,
+                        })
+                    })
+                    .collect(),
+                output_dir: format!("{}/compaction-{}", self.db_name, job_id),
+                smallest_snapshot: c.oldest_snapshot_alive,
+                bottommost,
+                skip_filters: self.options.skip_bottommost_filters && bottommost,
+                paranoid_checks: self.options.paranoid_checks,
+                max_file_size: self.options.max_file_size,
+                block_size: self.options.block_size,
+                compression: self.options.compression,
+                min_compression_ratio: self.options.min_compression_ratio,
+                table_options,
+            }
+        };
+        info!(
+            "Delegating compaction job #{} of {} files to the compaction service",
+            job.job_id,
+            job.inputs.len()
+        );
+        let status = service
+            .compact(&job)
+            .and_then(|result| self.install_remote_outputs(c, result));
+        let _ = self.env.remove_dir(job.output_dir.as_str(), true);
+        match status {
+            Ok(()) => (Ok(()), 0),
+            Err(e) => {
+                warn!(
+                    "Compaction job #{} failed: {:?}, compacting locally",
+                    job.job_id, e
+                );
+                let mut versions = self.versions.lock().unwrap();
+                for output in c.outputs.drain(..) {
+                    versions.pending_outputs.remove(&output.number);
+                    let _ = self
+                        .env
+                        .remove(self.table_cache.table_file_name(output.number).as_str());
+                    self.table_cache.evict(output.number);
+                }
+                mem::drop(versions);
+                c.total_bytes = 0;
+                self.run_local_compaction(c)
+            }
+        }
+    }
+
+    // Moves the output tables of a remote compaction into the db
+    fn install_remote_outputs(
+        &self,
+        c: &mut Compaction,
+        result: CompactionJobResult,
+    ) -> Result<()> {
+        for output in result.outputs {
+            let number = {
+                let mut versions = self.versions.lock().unwrap();
+                let number = versions.inc_next_file_number();
+                versions.pending_outputs.insert(number);
+                number
+            };
+            c.outputs.push(FileMetaData {
+                number,
+                file_size: output.file_size,
+                smallest: Arc::new(InternalKey::decoded_from(output.smallest.as_slice())),
+                largest: Arc::new(InternalKey::decoded_from(output.largest.as_slice())),
+                ..FileMetaData::default()
+            });
+            self.env.rename(
+                output.path.as_str(),
+                self.options
+                    .table_file_naming
+                    .table_file_name(self.db_name.as_str(), number)
+                    .as_str(),
+            )?;
+            c.total_bytes += output.file_size;
+            // make sure that the new file is valid and in the cache
+            let mut it = self.table_cache.new_iter(
+                Rc::new(ReadOptions::default()),
+                number,
+                output.file_size,
+            );
+            it.status()?;
+            info!(
+                "Installed table #{}@{} from compaction job: {} bytes",
+                number,
+                c.output_level(),
+                output.file_size
+            );
+            let info = TableFileCreationInfo {
+                db_name: self.db_name.clone(),
+                file_number: number,
+                file_size: output.file_size,
+                reason: TableFileCreationReason::Compaction,
+            };
+            for listener in self.options.listeners.iter() {
+                listener.on_table_file_created(&info);
+            }
+        }
+        Ok(())
+    }
+
+    // Runs the compaction over the input entries in the key range of `c`.
+    // Returns the status and the time spent on the memtable compactions meanwhile.
+    fn run_compaction(&self, c: &mut Compaction) -> (Result<()>, u64) {
+        let mut input_iter =
+            c.new_input_iterator(self.internal_comparator.clone(), self.table_cache.clone());
+        let mut mem_compaction_duration = 0;
+        match &c.start {
+            Some(start) => {
+                let ikey = InternalKey::new(
+                    &Slice::from(start.as_slice()),
+                    MAX_KEY_SEQUENCE,
+                    VALUE_TYPE_FOR_SEEK,
+                );
+                input_iter.seek(&Slice::from(ikey.data()));
+            }
+            None => input_iter.seek_to_first(),
+        }
+
+        // the current user key to be compacted
+        let mut current_ukey = vec![];
+        let mut has_current_ukey = false;
+        let mut last_sequence_for_key = u64::max_value();
+        // the versions of the current user key seen so far
+        let mut versions_of_key = 0;
+
+        let icmp = self.internal_comparator.clone();
+        let ucmp = icmp.user_comparator.as_ref();
+        let mut status = Ok(());
+        // Iterate every key
+        while input_iter.valid() && !self.is_shutting_down.load(Ordering::Acquire) {
+            // Prioritize immutable compaction work
+            if !self.im_mem.read().unwrap().is_empty() {
+                let imm_start = self.options.clock.monotonic_micros();
+                self.compact_mem_table();
+                mem_compaction_duration = self.options.clock.monotonic_micros() - imm_start;
+            }
             let ikey = input_iter.key();
             // Checkout whether we need rotate a new output file
             if c.should_stop_before(&ikey, icmp.clone()) && c.builder.is_some() {
-                status = self.finish_output_file(c, input_iter.valid());
+                status = self.finish_output_file(c, input_iter.status().is_ok());
                 if status.is_err() {
                     break;
                 }
             }
             let mut drop = false;
             match ParsedInternalKey::decode_from(ikey.clone()) {
-                Some(key) => {
+                Some(mut key) => {
+                    if c.is_after_end(ucmp, key.user_key.as_slice()) {
+                        break;
+                    }
+                    let mut ikey = ikey;
+                    // The value is loaded only when it's needed by the filter or copied
+                    // to the output, so that the dropped entries never load theirs
+                    let mut value = None;
+                    // The entry rewritten by the compaction filter
+                    let filtered_key;
+                    let filtered_value;
                     if !has_current_ukey
                         || ucmp.compare(key.user_key.as_slice(), current_ukey.as_slice())
                             != CmpOrdering::Equal
                     {
                         // First occurrence of this user key
-                        current_ukey = key.user_key.clone();
+                        if has_current_ukey {
+                            c.versions_per_key.add(versions_of_key as f64);
+                        }
+                        versions_of_key = 0;
+                        current_ukey = key.user_key.as_slice().to_vec();
                         has_current_ukey = true;
                         last_sequence_for_key = u64::max_value();
+                        if let Some(filter) = &self.options.compaction_filter {
+                            if key.value_type == ValueType::Value && c.should_filter(key.seq) {
+                                let decision = match filter.filter_by_key(c.level, &current_ukey) {
+                                    Some(decision) => decision,
+                                    None => filter.filter(
+                                        c.level,
+                                        &current_ukey,
+                                        value.get_or_insert_with(|| input_iter.value()).as_slice(),
+                                    ),
+                                };
+                                match decision {
+                                    CompactionDecision::Keep => {}
+                                    CompactionDecision::Remove => {
+                                        // Turns the entry into a deletion so that the older
+                                        // versions in the deeper levels stay hidden
+                                        key.value_type = ValueType::Deletion;
+                                        filtered_key = key.encode();
+                                        ikey = Slice::from(filtered_key.data());
+                                        value = Some(Slice::from(&[][..]));
+                                    }
+                                    CompactionDecision::ChangeValue(v) => {
+                                        filtered_value = v;
+                                        value = Some(Slice::from(filtered_value.as_slice()));
+                                    }
+                                }
+                            }
+                        }
                     }
+                    versions_of_key += 1;
                     // Keep the still-in-use old key or not
-                    if last_sequence_for_key <= c.oldest_snapshot_alive
-                        || (key.value_type == ValueType::Deletion
-                            && key.seq <= c.oldest_snapshot_alive
-                            && !c.key_exist_in_deeper_level(&key.user_key))
+                    if last_sequence_for_key <= c.oldest_snapshot_alive {
+                        // Hidden by a newer entry for the same user key
+                        c.dropped_versions += 1;
+                        drop = true
+                    } else if key.value_type == ValueType::Deletion
+                        && key.seq <= c.oldest_snapshot_alive
+                        && !c.key_exist_in_deeper_level(&key.user_key)
                     {
                         // For this user key:
                         // (1) there is no data in higher levels
@@ -1009,20 +4036,21 @@ impl DBImpl {
                         if c.builder.as_ref().unwrap().num_entries() == 0 {
                             // We have a brand new builder so use current key as smallest
                             c.outputs[last].smallest =
-                                Rc::new(InternalKey::decoded_from(ikey.as_slice()));
+                                Arc::new(InternalKey::decoded_from(ikey.as_slice()));
                         }
                         // Keep updating the largest
                         c.outputs[last].largest =
-                            Rc::new(InternalKey::decoded_from(ikey.as_slice()));
+                            Arc::new(InternalKey::decoded_from(ikey.as_slice()));
+                        let value = value.unwrap_or_else(|| input_iter.value());
                         let _ = c
                             .builder
                             .as_mut()
                             .unwrap()
-                            .add(ikey.as_slice(), input_iter.value().as_slice());
+                            .add(ikey.as_slice(), value.as_slice());
                         let builder = c.builder.as_ref().unwrap();
                         // Rotate a new output file if the current one is big enough
                         if builder.file_size() >= self.options.max_file_size {
-                            status = self.finish_output_file(c, input_iter.valid());
+                            status = self.finish_output_file(c, input_iter.status().is_ok());
                             if status.is_err() {
                                 break;
                             }
@@ -1030,13 +4058,20 @@ impl DBImpl {
                     }
                 }
                 None => {
-                    current_ukey = Slice::default();
+                    if has_current_ukey {
+                        c.versions_per_key.add(versions_of_key as f64);
+                    }
+                    versions_of_key = 0;
+                    current_ukey.clear();
                     has_current_ukey = false;
                     last_sequence_for_key = u64::max_value();
                 }
             }
             input_iter.next();
         }
+        if has_current_ukey {
+            c.versions_per_key.add(versions_of_key as f64);
+        }
         // TODO: simplify the implementation
         if status.is_ok() && self.is_shutting_down.load(Ordering::Acquire) {
             status = Err(WickErr::new(
@@ -1045,46 +4080,68 @@ impl DBImpl {
             ))
         }
         if status.is_ok() && c.builder.is_some() {
-            status = self.finish_output_file(c, input_iter.valid())
+            status = self.finish_output_file(c, input_iter.status().is_ok())
         }
 
         if status.is_ok() {
             status = input_iter.status()
         }
-        // Calculate the stats of this compaction
-        let mut versions = self.versions.lock().unwrap();
-        versions.compaction_stats[c.level + 1].accumulate(
-            now.elapsed().unwrap().as_micros() as u64 - mem_compaction_duration,
-            c.bytes_read(),
-            c.bytes_written(),
-        );
-        if status.is_ok() {
-            info!(
-                "Compacted {}@{} + {}@{} files => {} bytes",
-                c.inputs[CompactionInputsRelation::Source as usize].len(),
-                c.level,
-                c.inputs[CompactionInputsRelation::Parent as usize].len(),
-                c.level + 1,
-                c.total_bytes,
-            );
-            c.apply_to_edit();
-            status = versions.log_and_apply(&mut c.edit);
-        }
-        if let Err(e) = status {
-            self.record_bg_error(e)
-        }
-
-        let summary = versions.current().level_summary();
-        info!("compacted to : {}", summary);
+        (status, mem_compaction_duration)
+    }
 
-        // Close unclosed table builder and remove files in `pending_outputs`
-        if let Some(builder) = c.builder.as_mut() {
-            builder.close()
-        }
-        for output in c.outputs.iter() {
-            versions.pending_outputs.remove(&output.number);
+    // Splits `c` into subcompactions by the user keys in `boundaries` and runs
+    // them in parallel. The outputs are collected into `c` in the key order so
+    // that they are installed by one `VersionEdit`.
+    fn run_subcompactions(
+        &self,
+        c: &mut Compaction,
+        boundaries: Vec<Vec<u8>>,
+    ) -> (Result<()>, u64) {
+        let mut starts = vec![None];
+        starts.extend(boundaries.iter().cloned().map(Some));
+        let mut ends = boundaries.into_iter().map(Some).collect::<Vec<_>>();
+        ends.push(None);
+        let jobs = starts
+            .into_iter()
+            .zip(ends)
+            .map(|(start, end)| c.new_subcompaction(start, end))
+            .collect::<Vec<_>>();
+        info!(
+            "Compaction at level-{} is split into {} subcompactions",
+            c.level,
+            jobs.len()
+        );
+        let results = crossbeam_utils::thread::scope(|s| {
+            let handles = jobs
+                .into_iter()
+                .map(|job| {
+                    s.spawn(move |_| {
+                        let mut sub = job.into_compaction(
+                            self.options.clone(),
+                            self.internal_comparator.clone(),
+                        );
+                        let (status, micros) = self.run_compaction(&mut sub);
+                        (sub.into_subcompaction_output(), status, micros)
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("subcompaction panicked"))
+                .collect::<Vec<_>>()
+        })
+        .expect("subcompaction panicked");
+        let mut status = Ok(());
+        let mut mem_compaction_duration = 0;
+        for (output, s, micros) in results {
+            c.merge_subcompaction(output);
+            // the subcompactions wait for the same memtable compactions
+            mem_compaction_duration = mem_compaction_duration.max(micros);
+            if status.is_ok() {
+                status = s;
+            }
         }
-        versions
+        (status, mem_compaction_duration)
     }
 
     // Replace the `bg_error` with new WickErr if it's None
@@ -1099,25 +4156,31 @@ impl DBImpl {
     }
 
     // Check whether db needs to run a compaction. DB will run a compaction when:
-    // 1. no background compaction is running
+    // 1. not all the compaction workers are scheduled
     // 2. DB is not shutting down
     // 3. no error has been encountered
     // 4. there is an immutable table or a manual compaction request or current version needs to be compacted
     fn maybe_schedule_compaction(&self) {
-        if self.background_compaction_scheduled.load(Ordering::Acquire)
-            // Already scheduled
+        if self.mode != OpenMode::ReadWrite
+            // No compaction in a read-only db
+            || self.background_compactions_scheduled.load(Ordering::Acquire)
+            >= self.options.max_background_compactions
+            // All the workers are scheduled
         || self.is_shutting_down.load(Ordering::Acquire)
             // DB is being shutting down
+        || self.bg_work_paused.load(Ordering::Acquire) > 0
+            // Paused by `pause_background_work`
         || self.bg_error.read().unwrap().is_some()
             // Got err
-        ||  (self.im_mem.read().unwrap().is_none()
-            && !self.versions.lock().unwrap().needs_compaction())
+        ||  (self.im_mem.read().unwrap().is_empty()
+            && !self.idle_compaction_requested.load(Ordering::Acquire)
+            && !self.needs_compaction(&self.versions.lock().unwrap()))
         {
             // No work needs to be done
-        } else {
-            self.background_compaction_scheduled
-                .store(true, Ordering::Release);
-            if let Err(e) = self.do_compaction.0.send(()) {
+        } else if let Some(sender) = self.do_compaction.0.lock().unwrap().as_ref() {
+            self.background_compactions_scheduled
+                .fetch_add(1, Ordering::AcqRel);
+            if let Err(e) = sender.send(()) {
                 error!(
                     "[schedule compaction] Fail sending signal to compaction channel: {}",
                     e
@@ -1127,11 +4190,11 @@ impl DBImpl {
     }
 
     // Finish the current output file by calling `buidler.finish` and insert it into the table cache
-    fn finish_output_file(&self, compact: &mut Compaction, input_iter_valid: bool) -> Result<()> {
+    fn finish_output_file(&self, compact: &mut Compaction, input_iter_ok: bool) -> Result<()> {
         assert!(!compact.outputs.is_empty());
         assert!(compact.builder.is_some());
         let current_entries = compact.builder.as_ref().unwrap().num_entries();
-        let status = if input_iter_valid {
+        let status = if input_iter_ok {
             compact.builder.as_mut().unwrap().finish(true)
         } else {
             compact.builder.as_mut().unwrap().close();
@@ -1156,11 +4219,33 @@ impl DBImpl {
                 "Generated table #{}@{}: {} keys, {} bytes",
                 output_number, compact.level, current_entries, current_bytes
             );
+            let info = TableFileCreationInfo {
+                db_name: self.db_name.clone(),
+                file_number: output_number,
+                file_size: current_bytes,
+                reason: TableFileCreationReason::Compaction,
+            };
+            for listener in self.options.listeners.iter() {
+                listener.on_table_file_created(&info);
+            }
         }
         status
     }
 }
 
+// The write groups handed over from the WAL stage to the memtable stage of
+// `WickDB::process_batch` along with the result of writing them into the WAL
+struct MemTableInsertTask {
+    groups: Vec<(BatchTask, Vec<Sender<Result<()>>>)>,
+    // the error of the group at `written` if any
+    status: Result<()>,
+    // the number of the groups written into the WAL, which are all inserted
+    // into the memtable
+    written: usize,
+    // the last sequence of the written groups
+    last_seq: u64,
+}
+
 // A wrapper struct for scheduling `WriteBatch`
 struct BatchTask {
     batch: WriteBatch,
@@ -1192,11 +4277,16 @@ pub(crate) fn build_table<'a>(
 ) -> Result<()> {
     meta.file_size = 0;
     iter.seek_to_first();
-    let file_name = generate_filename(db_name, FileType::Table, meta.number);
+    let file_name = options
+        .table_file_naming
+        .table_file_name(db_name, meta.number);
     let mut status = Ok(());
     if iter.valid() {
-        let file = options.env.create(file_name.as_str())?;
-        let mut builder = TableBuilder::new(file, options.clone());
+        let file = options.create_table_file(file_name.as_str())?;
+        let icmp = Arc::new(InternalKeyComparator::new(options.comparator.clone()));
+        let mut builder = TableBuilder::new_with_comparator(file, options.clone(), icmp, 0)
+            .with_file_number(meta.number)
+            .with_internal_keys();
         let mut prev_key = Slice::default();
         let smallest_key = iter.key();
         while iter.valid() {
@@ -1211,8 +4301,8 @@ pub(crate) fn build_table<'a>(
             iter.next();
         }
         if status.is_ok() {
-            meta.smallest = Rc::new(InternalKey::decoded_from(smallest_key.as_slice()));
-            meta.largest = Rc::new(InternalKey::decoded_from(prev_key.as_slice()));
+            meta.smallest = Arc::new(InternalKey::decoded_from(smallest_key.as_slice()));
+            meta.largest = Arc::new(InternalKey::decoded_from(prev_key.as_slice()));
             status = builder.finish(true).and_then(|_| {
                 meta.file_size = builder.file_size();
                 // make sure that the new file is in the cache
@@ -1237,3 +4327,4007 @@ pub(crate) fn build_table<'a>(
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::lru::SharedLRUCache;
+    use crate::cache::Cache;
+    use crate::compaction::CompactionFilter;
+    use crate::db::filename::TableFileNaming;
+    use crate::filter::bloom::BloomFilter;
+    use crate::filter::FixedPrefixExtractor;
+    use crate::listener::{EventListener, VersionEditInfo};
+    use crate::options::{CancellationToken, CompressionType, MemtableRepType};
+    use crate::sstable::encryption::{BlockCipher, BLOCK_TWEAK_SIZE};
+    use crate::sstable::BLOCK_TRAILER_SIZE;
+    use crate::statistics::Statistics;
+    use crate::storage::file::FileStorage;
+    use crate::storage::mem::{MemStorage, MemStorageEvent};
+    use crate::testing::FaultInjectionStorage;
+    use crate::util::clock::{Clock, MockClock};
+    use crate::write_hook::{FixedPrefixClassifier, PreWriteHook};
+    use rand::Rng;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    fn new_test_db(name: &str) -> WickDB {
+        new_test_db_with_options(name, Options::default())
+    }
+
+    fn new_test_db_with_options(name: &str, mut options: Options) -> WickDB {
+        options.env = Arc::new(MemStorage::default());
+        WickDB::open_db(options, name.to_owned()).expect("could not open db")
+    }
+
+    #[test]
+    fn test_get_property() {
+        let db = new_test_db("test_get_property");
+        for i in 0..100 {
+            let key = format!("key{}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(b"value".as_ref()),
+            )
+            .expect("put should work");
+        }
+        assert_eq!(
+            db.get_property("wickdb.num-files-at-level0"),
+            Some("0".to_owned())
+        );
+        assert!(db.get_property("wickdb.num-files-at-level100").is_none());
+        assert!(db.get_property("wickdb.num-files-at-levelx").is_none());
+        let usage = db
+            .get_property("wickdb.approximate-memory-usage")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap();
+        assert!(usage > 0);
+        let sstables = db.get_property("wickdb.sstables").unwrap();
+        assert!(sstables.starts_with("--- level 0 ---\n"));
+        assert!(db.get_property("wickdb.stats").is_some());
+        assert!(db.get_property("wickdb.unknown").is_none());
+        assert!(db.get_property("leveldb.stats").is_none());
+    }
+
+    // Puts 100KB so that the 64KB memtable is rotated once
+    fn put_over_one_memtable(db: &WickDB) {
+        let value = vec![b'v'; 1024];
+        for i in 0..100 {
+            let key = format!("key{}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(value.as_slice()),
+            )
+            .expect("put should work");
+        }
+    }
+
+    #[test]
+    fn test_memtable_rotation() {
+        let mut options = Options::default();
+        options.write_buffer_size = 64 << 10;
+        let db = new_test_db_with_options("test_memtable_rotation", options);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let writer = db.clone();
+        thread::spawn(move || {
+            put_over_one_memtable(&writer);
+            tx.send(()).unwrap();
+        });
+        rx.recv_timeout(Duration::from_secs(10))
+            .expect("the writes should not deadlock on the memtable rotation");
+    }
+
+    #[test]
+    fn test_delete_obsolete_files() {
+        let env = Arc::new(MemStorage::default());
+        let mut options = Options::default();
+        options.env = env.clone();
+        let name = "test_delete_obsolete_files";
+        env.mkdir_all(name).unwrap();
+        // a table left by a crashed compaction
+        let stray = generate_filename(name, FileType::Table, 999);
+        env.create(stray.as_str()).unwrap();
+        let _db = WickDB::open_db(options, name.to_owned()).unwrap();
+        assert!(!env.exists(stray.as_str()));
+    }
+
+    #[test]
+    fn test_obsolete_files_properties() {
+        let mut options = Options::default();
+        options.max_mem_compact_level = 0;
+        let db = new_test_db_with_options("test_obsolete_files_properties", options);
+        let property = |name: &str| db.get_property(name).unwrap().parse::<u64>().unwrap();
+        for round in 0..2 {
+            for i in 0..100 {
+                db.put(
+                    WriteOptions::default(),
+                    Slice::from(format!("key{}", i).as_bytes()),
+                    Slice::from(format!("value{}", round).as_bytes()),
+                )
+                .unwrap();
+            }
+            db.flush_memtable().unwrap();
+        }
+        assert_eq!(property("wickdb.num-obsolete-sst-files"), 0);
+        assert_eq!(property("wickdb.obsolete-sst-files-size"), 0);
+        let level0_size = db.inner.versions.lock().unwrap().level_files_size(0);
+
+        // The iterator holds the compacted level0 tables
+        let iter = db.iter(ReadOptions::default());
+        db.inner.compact_level_fully(0).unwrap();
+        assert_eq!(property("wickdb.num-obsolete-sst-files"), 2);
+        assert_eq!(property("wickdb.obsolete-sst-files-size"), level0_size);
+        // Still on the disk until the next deletion
+        drop(iter);
+        assert_eq!(property("wickdb.num-obsolete-sst-files"), 2);
+        db.put(
+            WriteOptions::default(),
+            Slice::from("key"),
+            Slice::from("value"),
+        )
+        .unwrap();
+        db.flush_memtable().unwrap();
+        assert_eq!(property("wickdb.num-obsolete-sst-files"), 0);
+        assert_eq!(property("wickdb.obsolete-sst-files-size"), 0);
+        assert_eq!(property("wickdb.num-obsolete-wal-files"), 0);
+        assert_eq!(property("wickdb.obsolete-wal-files-size"), 0);
+    }
+
+    #[test]
+    fn test_statistics() {
+        let stats = Arc::new(Statistics::new());
+        let mut options = Options::default();
+        options.statistics = Some(stats.clone());
+        let db = new_test_db_with_options("test_statistics", options);
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(b"value".as_ref()),
+            )
+            .expect("put should work");
+        }
+        for i in 0..5 {
+            let key = format!("key{}", i);
+            let value = db
+                .get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                .expect("get should work");
+            assert_eq!(value.unwrap().as_slice(), b"value");
+        }
+        assert_eq!(stats.histogram_data(HistogramType::GetMicros).count(), 5);
+        assert!(stats.histogram_data(HistogramType::WriteMicros).count() > 0);
+        assert_eq!(stats.get_ticker_count(Ticker::WriteStall), 0);
+    }
+
+    #[test]
+    fn test_table_seek_telemetry() {
+        let stats = Arc::new(Statistics::new());
+        let mut options = Options::default();
+        options.write_buffer_size = 32 << 10;
+        options.statistics = Some(stats.clone());
+        let db = new_test_db_with_options("test_table_seek_telemetry", options);
+        let value = vec![b'v'; 1000];
+        for i in 0..200 {
+            let key = format!("key{:03}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(value.as_slice()),
+            )
+            .expect("put should work");
+        }
+        wait_and_count_entries(&db);
+        for i in 0..200 {
+            let key = format!("key{:03}", i);
+            assert!(db
+                .get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                .expect("get should work")
+                .is_some());
+        }
+        assert!(stats.get_ticker_count(Ticker::IndexBlockSeek) > 0);
+        assert!(stats.get_ticker_count(Ticker::DataBlockSeek) > 0);
+        let telemetry = db.get_property("wickdb.table-seek-telemetry").unwrap();
+        let rows: Vec<&str> = telemetry.lines().skip(2).collect();
+        assert!(!rows.is_empty());
+        let seeks: u64 = rows
+            .iter()
+            .map(|row| {
+                row.split_whitespace()
+                    .nth(2)
+                    .unwrap()
+                    .parse::<u64>()
+                    .unwrap()
+            })
+            .sum();
+        assert!(seeks > 0);
+        assert!(seeks <= stats.get_ticker_count(Ticker::IndexBlockSeek));
+    }
+
+    #[test]
+    fn test_batched_wal_sync() {
+        for max_delay in [0, 20_000].iter() {
+            let stats = Arc::new(Statistics::new());
+            let mut options = Options::default();
+            options.wal_sync_max_delay_micros = *max_delay;
+            options.statistics = Some(stats.clone());
+            let db = Arc::new(new_test_db_with_options("test_batched_wal_sync", options));
+            let mut handles = vec![];
+            for t in 0..8 {
+                let db = db.clone();
+                handles.push(thread::spawn(move || {
+                    for i in 0..5 {
+                        let key = format!("key{}_{}", t, i);
+                        db.put(
+                            WriteOptions {
+                                sync: true,
+                                ..WriteOptions::default()
+                            },
+                            Slice::from(key.as_bytes()),
+                            Slice::from(b"value".as_ref()),
+                        )
+                        .expect("put should work");
+                    }
+                }));
+            }
+            for h in handles {
+                h.join().unwrap();
+            }
+            for t in 0..8 {
+                for i in 0..5 {
+                    let key = format!("key{}_{}", t, i);
+                    assert_eq!(
+                        db.get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                            .unwrap(),
+                        Some(b"value".to_vec())
+                    );
+                }
+            }
+            let syncs = stats.get_ticker_count(Ticker::WalSync);
+            assert!(syncs > 0);
+            if *max_delay > 0 {
+                // every thread has at most one write in flight, so each fsync
+                // covers the pending writes of several threads
+                assert!(syncs <= 20, "{} syncs", syncs);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pipelined_writes() {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let new_options = || {
+            let mut options = Options::default();
+            options.env = env.clone();
+            // rotate the memtable while the groups are in the pipeline
+            options.write_buffer_size = 32 << 10;
+            options
+        };
+        let name = "test_pipelined_writes".to_owned();
+        let mut db = WickDB::open_db(new_options(), name.clone()).unwrap();
+        let mut handles = vec![];
+        for t in 0..8 {
+            let db = db.clone();
+            handles.push(thread::spawn(move || {
+                for i in 0..500 {
+                    let key = format!("key{}_{:03}", t, i);
+                    db.put(
+                        WriteOptions::default(),
+                        Slice::from(key.as_bytes()),
+                        Slice::from(key.as_bytes()),
+                    )
+                    .unwrap();
+                    // a write is visible once it returns
+                    assert_eq!(
+                        db.get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                            .unwrap(),
+                        Some(key.into_bytes())
+                    );
+                }
+            }));
+        }
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(db.inner.versions.lock().unwrap().last_sequence(), 8 * 500);
+        db.close().unwrap();
+
+        // every write is recovered from the logs it's written into
+        let db = WickDB::open_db(new_options(), name).unwrap();
+        assert_eq!(count_entries(db.iter(ReadOptions::default())), 8 * 500);
+    }
+
+    #[test]
+    fn test_partially_logged_write_groups() {
+        let db = new_test_db("test_partially_logged_write_groups");
+        let (send, recv) = crossbeam_channel::unbounded();
+        db.process_memtable_insert(recv);
+        let last_seq = db.inner.versions.lock().unwrap().last_sequence();
+        let mut groups = vec![];
+        let mut results = vec![];
+        for (i, key) in ["a", "b", "c"].iter().enumerate() {
+            let mut batch = WriteBatch::new();
+            batch.put(key.as_bytes(), key.as_bytes());
+            batch.set_sequence(last_seq + 1 + i as u64);
+            let (signal, result) = crossbeam_channel::bounded(1);
+            let task = BatchTask::new(batch, signal.clone(), WriteOptions::default());
+            groups.push((task, vec![signal]));
+            results.push(result);
+        }
+        // The WAL stage fails to log the second group
+        db.inner
+            .pending_memtable_inserts
+            .fetch_add(1, Ordering::AcqRel);
+        send.send(MemTableInsertTask {
+            groups,
+            status: Err(WickErr::new(Status::IOError, Some("injected"))),
+            written: 1,
+            last_seq: last_seq + 1,
+        })
+        .unwrap();
+        let results: Vec<Result<()>> = results.iter().map(|r| r.recv().unwrap()).collect();
+        assert!(results[0].is_ok());
+        assert!(format!("{}", results[1].as_ref().unwrap_err()).contains("injected"));
+        assert!(format!("{}", results[2].as_ref().unwrap_err()).contains("previous write"));
+        assert_eq!(
+            db.inner.versions.lock().unwrap().last_sequence(),
+            last_seq + 1
+        );
+        let get = |key: &str| {
+            db.get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                .unwrap()
+        };
+        assert_eq!(get("a"), Some(b"a".to_vec()));
+        assert_eq!(get("b"), None);
+        assert_eq!(get("c"), None);
+    }
+
+    #[derive(Default)]
+    struct CountingListener {
+        flushes: AtomicUsize,
+        created: AtomicUsize,
+    }
+
+    impl EventListener for CountingListener {
+        fn on_flush_completed(&self, info: &FlushJobInfo) {
+            assert_eq!(info.db_name, "test_event_listener");
+            assert!(info.file_size > 0);
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_table_file_created(&self, info: &TableFileCreationInfo) {
+            assert_eq!(info.reason, TableFileCreationReason::Flush);
+            self.created.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_event_listener() {
+        let listener = Arc::new(CountingListener::default());
+        let mut options = Options::default();
+        // the minimum write buffer size after sanitizing
+        options.write_buffer_size = 64 << 10;
+        options.listeners.push(listener.clone());
+        let db = new_test_db_with_options("test_event_listener", options);
+        let value = vec![b'v'; 1024];
+        for i in 0..100 {
+            let key = format!("key{}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(value.as_slice()),
+            )
+            .expect("put should work");
+        }
+        // wait for the background flush
+        for _ in 0..100 {
+            if listener.flushes.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(listener.flushes.load(Ordering::SeqCst) > 0);
+        assert!(listener.created.load(Ordering::SeqCst) >= listener.flushes.load(Ordering::SeqCst));
+    }
+
+    // Mirrors the files of the db by the version edits
+    #[derive(Default)]
+    struct MirrorListener {
+        files: Mutex<HashSet<(usize, u64)>>,
+        last_sequence: AtomicUsize,
+    }
+
+    impl EventListener for MirrorListener {
+        fn on_version_edit_applied(&self, info: &VersionEditInfo) {
+            let mut files = self.files.lock().unwrap();
+            for f in info.deleted_files.iter() {
+                assert!(files.remove(f), "deleting unknown file {:?}", f);
+            }
+            for f in info.new_files.iter() {
+                assert!(f.file_size > 0 && f.smallest_key <= f.largest_key);
+                files.insert((f.level, f.file_number));
+            }
+            assert!(info.log_number < info.next_file_number);
+            let prev = self
+                .last_sequence
+                .swap(info.last_sequence as usize, Ordering::SeqCst);
+            assert!(prev <= info.last_sequence as usize);
+        }
+    }
+
+    #[test]
+    fn test_version_edit_listener() {
+        let listener = Arc::new(MirrorListener::default());
+        let mut options = Options::default();
+        options.write_buffer_size = 64 << 10;
+        options.listeners.push(listener.clone());
+        let db = new_test_db_with_options("test_version_edit_listener", options);
+        let total = 5000;
+        for round in 0..3 {
+            for i in 0..total {
+                let key = format!("key{:08}", (i * 7919) % total);
+                let value = format!("value{}-{}", round, i).repeat(10);
+                db.put(
+                    WriteOptions::default(),
+                    Slice::from(key.as_bytes()),
+                    Slice::from(value.as_bytes()),
+                )
+                .expect("put should work");
+            }
+        }
+        assert!(wait_and_count_entries(&db) >= total);
+        let version = db.inner.versions.lock().unwrap().current();
+        let mut expected = HashSet::new();
+        for level in 0..db.inner.options.max_levels as usize {
+            for f in version.get_level_files(level) {
+                expected.insert((level, f.number));
+            }
+        }
+        assert!(expected.len() > 1);
+        assert_eq!(*listener.files.lock().unwrap(), expected);
+    }
+
+    fn count_entries(mut iter: Box<dyn Iterator>) -> usize {
+        let mut count = 0;
+        iter.seek_to_first();
+        while iter.valid() {
+            count += 1;
+            iter.next();
+        }
+        count
+    }
+
+    // Waits for all the background work and returns the number of entries in
+    // the memtable and the tables
+    fn wait_and_count_entries(db: &WickDB) -> usize {
+        let inner = db.inner.clone();
+        for _ in 0..500 {
+            if inner
+                .background_compactions_scheduled
+                .load(Ordering::Acquire)
+                == 0
+                && inner.im_mem.read().unwrap().is_empty()
+                && !inner.needs_compaction(&inner.versions.lock().unwrap())
+            {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(inner.bg_error.read().unwrap().is_none());
+        let versions = inner.versions.lock().unwrap();
+        let mut entries = count_entries(inner.mem.read().unwrap().iter());
+        let current = versions.current();
+        for level in 0..inner.options.max_levels as usize {
+            for f in current.get_level_files(level) {
+                entries += count_entries(inner.table_cache.new_iter(
+                    Rc::new(ReadOptions::default()),
+                    f.number,
+                    f.file_size,
+                ));
+            }
+        }
+        entries
+    }
+
+    #[test]
+    fn test_concurrent_compactions() {
+        let mut options = Options::default();
+        options.write_buffer_size = 64 << 10;
+        options.l1_max_bytes = 128 << 10;
+        options.max_background_compactions = 4;
+        let db = new_test_db_with_options("test_concurrent_compactions", options);
+        let total = 10000;
+        for i in 0..total {
+            // distinct keys in a scattered order
+            let key = format!("key{:08}", (i * 7919) % total);
+            let value = format!("value{}", i).repeat(20);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(value.as_bytes()),
+            )
+            .expect("put should work");
+        }
+        // no entry is lost by the compactions
+        assert_eq!(wait_and_count_entries(&db), total);
+        assert_eq!(
+            db.get_property("wickdb.num-running-compactions"),
+            Some("0".to_owned())
+        );
+        let versions = db.inner.versions.lock().unwrap();
+        assert!(versions.compaction_stats[1].bytes_written > 0);
+    }
+
+    #[test]
+    fn test_subcompactions() {
+        let mut options = Options::default();
+        options.write_buffer_size = 64 << 10;
+        options.max_subcompactions = 4;
+        let db = new_test_db_with_options("test_subcompactions", options);
+        let total = 10000;
+        for round in 0..2 {
+            for i in 0..total {
+                let key = format!("key{:08}", (i * 7919) % total);
+                let value = format!("value{}-{}", round, i).repeat(10);
+                db.put(
+                    WriteOptions::default(),
+                    Slice::from(key.as_bytes()),
+                    Slice::from(value.as_bytes()),
+                )
+                .expect("put should work");
+            }
+        }
+        let entries = wait_and_count_entries(&db);
+        assert!(entries >= total);
+        {
+            let versions = db.inner.versions.lock().unwrap();
+            assert!(versions.compaction_stats[1].bytes_written > 0);
+            // the outputs of the subcompactions don't overlap
+            let current = versions.current();
+            for pair in current.get_level_files(1).windows(2) {
+                assert_eq!(
+                    db.inner
+                        .internal_comparator
+                        .compare(pair[0].largest.data(), pair[1].smallest.data()),
+                    CmpOrdering::Less
+                );
+            }
+        }
+        for i in 0..total {
+            let key = format!("key{:08}", (i * 7919) % total);
+            let value = db
+                .get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                .expect("get should work");
+            assert_eq!(value, Some(format!("value1-{}", i).repeat(10).into_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_close_releasing_compaction_workers() {
+        let options = Options {
+            max_background_compactions: 4,
+            ..Options::default()
+        };
+        let mut db = new_test_db_with_options("test_close_releasing_compaction_workers", options);
+        db.put(WriteOptions::default(), Slice::from("k"), Slice::from("v"))
+            .unwrap();
+        db.close().unwrap();
+        // the background threads exit and drop their references to the db
+        for _ in 0..1000 {
+            if Arc::strong_count(&db.inner) == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(Arc::strong_count(&db.inner), 1);
+    }
+
+    // A compaction service running the jobs in this process
+    struct LocalCompactionService {
+        env: Arc<dyn Storage>,
+        jobs: AtomicUsize,
+        fail: bool,
+    }
+
+    impl CompactionService for LocalCompactionService {
+        fn compact(&self, job: &CompactionJob) -> Result<CompactionJobResult> {
+            self.jobs.fetch_add(1, Ordering::SeqCst);
+            if self.fail {
+                return Err(WickErr::new(Status::IOError, Some("worker unavailable")));
+            }
+            // go through the serialization like a real remote worker
+            let mut buf = vec![];
+            job.encode_to(&mut buf);
+            let job = CompactionJob::decoded_from(buf.as_slice())?;
+            let mut options = Options::default();
+            options.env = self.env.clone();
+            let result = crate::remote_compaction::run_compaction_job(&job, options)?;
+            buf.clear();
+            result.encode_to(&mut buf);
+            CompactionJobResult::decoded_from(buf.as_slice())
+        }
+    }
+
+    fn test_compaction_service(name: &str, fail: bool) {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let service = Arc::new(LocalCompactionService {
+            env: env.clone(),
+            jobs: AtomicUsize::new(0),
+            fail,
+        });
+        let mut options = Options::default();
+        options.write_buffer_size = 64 << 10;
+        options.compaction_service = Some(service.clone());
+        options.env = env;
+        let db = WickDB::open_db(options, name.to_owned()).unwrap();
+        let total = 5000;
+        for round in 0..2 {
+            for i in 0..total {
+                let key = format!("key{:08}", (i * 7919) % total);
+                let value = format!("value{}-{}", round, i).repeat(10);
+                db.put(
+                    WriteOptions::default(),
+                    Slice::from(key.as_bytes()),
+                    Slice::from(value.as_bytes()),
+                )
+                .expect("put should work");
+            }
+        }
+        for i in 0..2 {
+            db.delete(
+                WriteOptions::default(),
+                Slice::from(format!("key{:08}", i).as_bytes()),
+            )
+            .unwrap();
+        }
+        assert!(wait_and_count_entries(&db) >= total - 2);
+        assert!(service.jobs.load(Ordering::SeqCst) > 0);
+        assert!(db.inner.versions.lock().unwrap().compaction_stats[1].bytes_written > 0);
+        for i in 0..total {
+            let key = format!("key{:08}", (i * 7919) % total);
+            let value = db
+                .get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                .expect("get should work");
+            if (i * 7919) % total < 2 {
+                assert!(value.is_none());
+            } else {
+                assert_eq!(value, Some(format!("value1-{}", i).repeat(10).into_bytes()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_remote_compaction() {
+        test_compaction_service("test_remote_compaction", false);
+    }
+
+    #[test]
+    fn test_remote_compaction_fallback() {
+        test_compaction_service("test_remote_compaction_fallback", true);
+    }
+
+    // Removes the keys whose number is a multiple of 3 and upper cases the
+    // values of the keys whose number is 1 modulo 3
+    struct ModuloFilter {
+        enabled: AtomicBool,
+        calls: AtomicUsize,
+    }
+
+    impl CompactionFilter for ModuloFilter {
+        fn name(&self) -> &str {
+            "ModuloFilter"
+        }
+
+        fn filter(&self, _level: usize, key: &[u8], value: &[u8]) -> CompactionDecision {
+            if !self.enabled.load(Ordering::SeqCst) {
+                return CompactionDecision::Keep;
+            }
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let n = std::str::from_utf8(&key[3..])
+                .unwrap()
+                .parse::<usize>()
+                .unwrap();
+            match n % 3 {
+                0 => CompactionDecision::Remove,
+                1 => CompactionDecision::ChangeValue(value.to_ascii_uppercase()),
+                _ => CompactionDecision::Keep,
+            }
+        }
+    }
+
+    fn test_compaction_filter(name: &str, with_snapshot: bool) {
+        let filter = Arc::new(ModuloFilter {
+            enabled: AtomicBool::new(!with_snapshot),
+            calls: AtomicUsize::new(0),
+        });
+        let mut options = Options::default();
+        options.write_buffer_size = 64 << 10;
+        options.compaction_filter = Some(filter.clone());
+        let db = new_test_db_with_options(name, options);
+        let total = 5000;
+        let mut snapshot = None;
+        for round in 0..2 {
+            for i in 0..total {
+                let key = format!("key{:08}", (i * 7919) % total);
+                let value = format!("value{}-{}", round, i).repeat(10);
+                db.put(
+                    WriteOptions::default(),
+                    Slice::from(key.as_bytes()),
+                    Slice::from(value.as_bytes()),
+                )
+                .expect("put should work");
+            }
+            if with_snapshot && round == 0 {
+                snapshot = Some(db.snapshot());
+                filter.enabled.store(true, Ordering::SeqCst);
+            }
+        }
+        wait_and_count_entries(&db);
+        assert!(filter.calls.load(Ordering::SeqCst) > 0);
+        let mut filtered = 0;
+        for i in 0..total {
+            let n = (i * 7919) % total;
+            let key = format!("key{:08}", n);
+            let value = db
+                .get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                .expect("get should work");
+            let expected = format!("value1-{}", i).repeat(10);
+            // The old versions are never exposed by the filter
+            match n % 3 {
+                0 if value.is_none() => filtered += 1,
+                1 if value == Some(expected.to_ascii_uppercase().into_bytes()) => filtered += 1,
+                _ => assert_eq!(value, Some(expected.into_bytes())),
+            }
+            // The versions visible to the snapshot are never filtered
+            if let Some(snapshot) = &snapshot {
+                let read_opt = ReadOptions {
+                    snapshot: Some(snapshot.as_ref().clone()),
+                    ..ReadOptions::default()
+                };
+                let value = db
+                    .get(read_opt, Slice::from(key.as_bytes()))
+                    .expect("get should work");
+                assert_eq!(value, Some(format!("value0-{}", i).repeat(10).into_bytes()));
+            }
+        }
+        assert!(filtered > 0);
+    }
+
+    #[test]
+    fn test_compaction_filter_applied() {
+        test_compaction_filter("test_compaction_filter_applied", false);
+    }
+
+    #[test]
+    fn test_compaction_filter_with_snapshot() {
+        test_compaction_filter("test_compaction_filter_with_snapshot", true);
+    }
+
+    // Drops the keys with the prefix "drop" by the keys alone
+    struct DropByKeyFilter {
+        value_loads: AtomicUsize,
+        dropped_value_loads: AtomicUsize,
+    }
+
+    impl CompactionFilter for DropByKeyFilter {
+        fn name(&self) -> &str {
+            "DropByKeyFilter"
+        }
+
+        fn filter(&self, _level: usize, key: &[u8], _value: &[u8]) -> CompactionDecision {
+            self.value_loads.fetch_add(1, Ordering::SeqCst);
+            if key.starts_with(b"drop") {
+                self.dropped_value_loads.fetch_add(1, Ordering::SeqCst);
+            }
+            CompactionDecision::Keep
+        }
+
+        fn filter_by_key(&self, _level: usize, key: &[u8]) -> Option<CompactionDecision> {
+            if key.starts_with(b"drop") {
+                Some(CompactionDecision::Remove)
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn test_compaction_filter_by_key() {
+        let filter = Arc::new(DropByKeyFilter {
+            value_loads: AtomicUsize::new(0),
+            dropped_value_loads: AtomicUsize::new(0),
+        });
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let new_options = || {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.write_buffer_size = 64 << 10;
+            options.compaction_filter = Some(filter.clone());
+            options
+        };
+        let name = "test_compaction_filter_by_key".to_owned();
+        let mut db = WickDB::open_db(new_options(), name.clone()).unwrap();
+        let total = 3000;
+        for i in 0..total {
+            for prefix in &["drop", "keep"] {
+                let key = format!("{}{:08}", prefix, i);
+                db.put(
+                    WriteOptions::default(),
+                    Slice::from(key.as_bytes()),
+                    Slice::from(vec![b'v'; 100].as_slice()),
+                )
+                .unwrap();
+            }
+        }
+        wait_and_count_entries(&db);
+        db.close().unwrap();
+
+        WickDB::compact_offline(new_options(), name.clone()).unwrap();
+        assert!(filter.value_loads.load(Ordering::SeqCst) > 0);
+        // The values of the keys decided by the keys alone are never loaded
+        assert_eq!(filter.dropped_value_loads.load(Ordering::SeqCst), 0);
+        let db = WickDB::open_db(new_options(), name.clone()).unwrap();
+        for i in 0..total {
+            let dropped = format!("drop{:08}", i);
+            let kept = format!("keep{:08}", i);
+            assert!(db
+                .get(ReadOptions::default(), Slice::from(dropped.as_bytes()))
+                .unwrap()
+                .is_none());
+            assert!(db
+                .get(ReadOptions::default(), Slice::from(kept.as_bytes()))
+                .unwrap()
+                .is_some());
+        }
+    }
+
+    #[test]
+    fn test_delete_files_in_range() {
+        let mut options = Options::default();
+        options.write_buffer_size = 64 << 10;
+        let db = new_test_db_with_options("test_delete_files_in_range", options);
+        let total = 10000;
+        for i in 0..total {
+            let key = format!("key{:08}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(key.repeat(10).as_bytes()),
+            )
+            .expect("put should work");
+        }
+        wait_and_count_entries(&db);
+        let (begin, end) = ("key00002000", "key00006000");
+        assert!(db
+            .delete_files_in_range(
+                Some(Slice::from(end.as_bytes())),
+                Some(Slice::from(begin.as_bytes()))
+            )
+            .is_err());
+        // don't hold the old version, otherwise its files are kept
+        let before: Vec<_> = {
+            let current = db.inner.versions.lock().unwrap().current();
+            (0..db.inner.options.max_levels as usize)
+                .map(|level| current.get_level_files(level).to_vec())
+                .collect()
+        };
+        let deleted = db
+            .delete_files_in_range(
+                Some(Slice::from(begin.as_bytes())),
+                Some(Slice::from(end.as_bytes())),
+            )
+            .unwrap();
+        assert!(deleted > 0);
+        let after = db.inner.versions.lock().unwrap().current();
+        let mut removed = 0;
+        for (level, files) in before.iter().enumerate() {
+            for f in files.iter() {
+                if after
+                    .get_level_files(level)
+                    .iter()
+                    .any(|a| a.number == f.number)
+                {
+                    // the kept files are not contained in the range
+                    assert!(
+                        f.smallest.user_key() < begin.as_bytes()
+                            || f.largest.user_key() > end.as_bytes()
+                    );
+                } else {
+                    assert!(f.smallest.user_key() >= begin.as_bytes());
+                    assert!(f.largest.user_key() <= end.as_bytes());
+                    let name = db.inner.table_cache.table_file_name(f.number);
+                    assert!(!db.inner.env.exists(name.as_str()));
+                    removed += 1;
+                }
+            }
+        }
+        assert_eq!(removed, deleted);
+        let mut gone = 0;
+        for i in 0..total {
+            let key = format!("key{:08}", i);
+            let value = db
+                .get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                .expect("get should work");
+            if key.as_str() < begin || key.as_str() > end {
+                assert_eq!(value, Some(key.repeat(10).into_bytes()));
+            } else if value.is_none() {
+                gone += 1;
+            }
+        }
+        assert!(gone > 0);
+        // nothing left to delete
+        assert_eq!(
+            db.delete_files_in_range(
+                Some(Slice::from(begin.as_bytes())),
+                Some(Slice::from(end.as_bytes())),
+            )
+            .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_update() {
+        let db = Arc::new(new_test_db("test_update"));
+        let keys = [Slice::from(b"a".as_ref()), Slice::from(b"b".as_ref())];
+        // Moves one unit from `a` to `b` in each update
+        let transfer = |values: &[Option<Vec<u8>>]| {
+            let parse = |v: &Option<Vec<u8>>| {
+                v.as_ref()
+                    .map_or(0, |v| String::from_utf8_lossy(v).parse::<i64>().unwrap())
+            };
+            let (a, b) = (parse(&values[0]), parse(&values[1]));
+            Ok(vec![
+                Some((a - 1).to_string().into_bytes()),
+                Some((b + 1).to_string().into_bytes()),
+            ])
+        };
+        let mut handles = vec![];
+        for _ in 0..4 {
+            let db = db.clone();
+            handles.push(thread::spawn(move || {
+                let keys = [Slice::from(b"a".as_ref()), Slice::from(b"b".as_ref())];
+                for _ in 0..50 {
+                    db.update(WriteOptions::default(), &keys, transfer).unwrap();
+                }
+            }));
+        }
+        for h in handles {
+            h.join().unwrap();
+        }
+        for (key, expected) in [("a", "-200"), ("b", "200")].iter() {
+            assert_eq!(
+                db.get(ReadOptions::default(), Slice::from(*key)).unwrap(),
+                Some(expected.as_bytes().to_vec())
+            );
+        }
+
+        // `None` deletes the key
+        db.update(WriteOptions::default(), &keys[..1], |values| {
+            assert_eq!(values, &[Some(b"-200".to_vec())]);
+            Ok(vec![None])
+        })
+        .unwrap();
+        assert_eq!(
+            db.get(ReadOptions::default(), keys[0].clone()).unwrap(),
+            None
+        );
+
+        // The values returned must match the keys
+        let res = db.update(WriteOptions::default(), &keys, |_| Ok(vec![None]));
+        assert_eq!(res.unwrap_err().status(), Status::InvalidArgument);
+        assert_eq!(
+            db.get(ReadOptions::default(), keys[1].clone()).unwrap(),
+            Some(b"200".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_compact_offline() {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let new_options = || {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.write_buffer_size = 64 << 10;
+            options
+        };
+        let name = "test_compact_offline".to_owned();
+        let mut db = WickDB::open_db(new_options(), name.clone()).unwrap();
+        let value = vec![b'v'; 1000];
+        for round in 0..2 {
+            for i in 0..300 {
+                let key = format!("key{:03}", i);
+                db.put(
+                    WriteOptions::default(),
+                    Slice::from(key.as_bytes()),
+                    Slice::from(value.as_slice()),
+                )
+                .unwrap();
+                if round == 1 && i % 3 == 0 {
+                    db.delete(WriteOptions::default(), Slice::from(key.as_bytes()))
+                        .unwrap();
+                }
+            }
+        }
+        assert!(wait_and_count_entries(&db) > 400);
+        db.close().unwrap();
+
+        WickDB::compact_offline(new_options(), name.clone()).unwrap();
+        let db = WickDB::open_db(new_options(), name).unwrap();
+        let last_level = db.inner.options.max_levels as usize - 1;
+        for level in 0..last_level {
+            let files = db.get_property(&format!("wickdb.num-files-at-level{}", level));
+            assert_eq!(files, Some("0".to_owned()), "level {}", level);
+        }
+        // Only the latest values of the live keys are left
+        assert_eq!(wait_and_count_entries(&db), 200);
+        for i in 0..300 {
+            let key = format!("key{:03}", i);
+            let got = db
+                .get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                .unwrap();
+            assert_eq!(got.is_some(), i % 3 != 0, "{}", key);
+        }
+    }
+
+    // Adds the low byte of the tweak sum to every byte
+    struct AddCipher;
+
+    impl BlockCipher for AddCipher {
+        fn name(&self) -> &str {
+            "AddCipher"
+        }
+
+        fn encrypt(&self, tweak: &[u8; BLOCK_TWEAK_SIZE], data: &mut [u8]) -> Result<()> {
+            let k = tweak.iter().fold(1u8, |a, b| a.wrapping_add(*b));
+            data.iter_mut().for_each(|b| *b = b.wrapping_add(k));
+            Ok(())
+        }
+
+        fn decrypt(&self, tweak: &[u8; BLOCK_TWEAK_SIZE], data: &mut [u8]) -> Result<()> {
+            let k = tweak.iter().fold(1u8, |a, b| a.wrapping_add(*b));
+            data.iter_mut().for_each(|b| *b = b.wrapping_sub(k));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_block_cipher() {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let new_options = || {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.write_buffer_size = 64 << 10;
+            options.block_cipher = Some(Arc::new(AddCipher));
+            options
+        };
+        let name = "test_block_cipher".to_owned();
+        let mut db = WickDB::open_db(new_options(), name.clone()).unwrap();
+        let value = vec![b'v'; 1000];
+        for i in 0..500 {
+            let key = format!("key{:03}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(value.as_slice()),
+            )
+            .unwrap();
+        }
+        assert_eq!(wait_and_count_entries(&db), 500);
+        db.close().unwrap();
+
+        WickDB::compact_offline(new_options(), name.clone()).unwrap();
+        let db = WickDB::open_db(new_options(), name.clone()).unwrap();
+        for i in 0..500 {
+            let key = format!("key{:03}", i);
+            let got = db
+                .get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                .unwrap();
+            assert_eq!(got.as_deref(), Some(value.as_slice()));
+        }
+        assert_eq!(wait_and_count_entries(&db), 500);
+    }
+
+    #[test]
+    fn test_current_synced_before_renamed() {
+        let mem = MemStorage::default();
+        let mut options = Options::default();
+        options.env = Arc::new(mem.clone());
+        let name = "test_current_synced_before_renamed";
+        let db = WickDB::open_db(options, name.to_owned()).unwrap();
+        db.put(WriteOptions::default(), Slice::from("k"), Slice::from("v"))
+            .unwrap();
+        let events = mem.events();
+        let current = generate_filename(name, FileType::Current, 0);
+        let manifest = generate_filename(name, FileType::Manifest, 1);
+        let position = |event: &MemStorageEvent| events.iter().position(|e| e == event);
+        let renamed = events
+            .iter()
+            .position(|e| matches!(e, MemStorageEvent::Rename(_, new) if *new == current))
+            .expect("CURRENT should be renamed from a temp file");
+        // Both the temp file and the MANIFEST it points to are durable before the rename
+        if let MemStorageEvent::Rename(tmp, _) = &events[renamed] {
+            assert!(position(&MemStorageEvent::Sync(tmp.clone())).unwrap() < renamed);
+        }
+        assert!(position(&MemStorageEvent::Sync(manifest)).unwrap() < renamed);
+    }
+
+    fn current_log_file(db: &WickDB) -> String {
+        let number = db.inner.versions.lock().unwrap().record_writer_number;
+        generate_filename(db.inner.db_name.as_str(), FileType::Log, number)
+    }
+
+    #[test]
+    fn test_wal_bytes_per_sync() {
+        let mem = MemStorage::default();
+        let mut options = Options::default();
+        options.env = Arc::new(mem.clone());
+        options.wal_bytes_per_sync = 4096;
+        let db = WickDB::open_db(options, "test_wal_bytes_per_sync".to_owned()).unwrap();
+        let log = current_log_file(&db);
+        for i in 0..1000 {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from("value"),
+            )
+            .unwrap();
+            // the unsynced tail never grows beyond the limit plus a write
+            let len = mem.open(&log).unwrap().len().unwrap();
+            assert!(len - mem.synced_len(&log).unwrap() < 4096 + 64);
+        }
+        assert!(mem.synced_len(&log).unwrap() > 0);
+    }
+
+    #[test]
+    fn test_wal_sync_interval() {
+        let mem = MemStorage::default();
+        let mut options = Options::default();
+        options.env = Arc::new(mem.clone());
+        options.wal_sync_interval_micros = 1000;
+        let db = WickDB::open_db(options, "test_wal_sync_interval".to_owned()).unwrap();
+        let log = current_log_file(&db);
+        db.put(WriteOptions::default(), Slice::from("k"), Slice::from("v"))
+            .unwrap();
+        let len = mem.open(&log).unwrap().len().unwrap();
+        let mut retries = 0;
+        while mem.synced_len(&log).unwrap() < len {
+            retries += 1;
+            assert!(retries < 1000, "the WAL is never synced in background");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_disable_wal() {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let new_options = || {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options
+        };
+        let name = "test_disable_wal".to_owned();
+        let unlogged = || WriteOptions {
+            disable_wal: true,
+            ..WriteOptions::default()
+        };
+        let mut db = WickDB::open_db(new_options(), name.clone()).unwrap();
+        let res = db.put(
+            WriteOptions {
+                sync: true,
+                disable_wal: true,
+            },
+            Slice::from("k"),
+            Slice::from("v"),
+        );
+        assert_eq!(res.unwrap_err().status(), Status::InvalidArgument);
+        db.put(
+            WriteOptions::default(),
+            Slice::from("logged"),
+            Slice::from("v"),
+        )
+        .unwrap();
+        for key in &["a", "b", "c"] {
+            db.put(unlogged(), Slice::from(*key), Slice::from("v"))
+                .unwrap();
+        }
+        assert_eq!(db.unlogged_sequence_range(), Some((2, 4)));
+        db.close().unwrap();
+
+        // the writes skipping the WAL are lost by a crash
+        let mut db = WickDB::open_db(new_options(), name.clone()).unwrap();
+        assert!(db
+            .get(ReadOptions::default(), Slice::from("logged"))
+            .unwrap()
+            .is_some());
+        assert!(db
+            .get(ReadOptions::default(), Slice::from("a"))
+            .unwrap()
+            .is_none());
+        assert_eq!(db.unlogged_sequence_range(), None);
+        for key in &["a", "b", "c"] {
+            db.put(unlogged(), Slice::from(*key), Slice::from("v"))
+                .unwrap();
+        }
+        assert!(db.unlogged_sequence_range().is_some());
+        db.flush_memtable().unwrap();
+        assert_eq!(db.unlogged_sequence_range(), None);
+        db.close().unwrap();
+
+        let db = WickDB::open_db(new_options(), name).unwrap();
+        for key in &["logged", "a", "b", "c"] {
+            assert!(db
+                .get(ReadOptions::default(), Slice::from(*key))
+                .unwrap()
+                .is_some());
+        }
+    }
+
+    #[test]
+    fn test_flush_wal() {
+        let mem = MemStorage::default();
+        let mut options = Options::default();
+        options.env = Arc::new(mem.clone());
+        let db = WickDB::open_db(options, "test_flush_wal".to_owned()).unwrap();
+        let log = current_log_file(&db);
+        db.put(WriteOptions::default(), Slice::from("k"), Slice::from("v"))
+            .unwrap();
+        let len = mem.open(&log).unwrap().len().unwrap();
+        assert!(mem.synced_len(&log).unwrap() < len);
+        db.flush_wal().unwrap();
+        assert_eq!(mem.synced_len(&log).unwrap(), len);
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let stats = Arc::new(Statistics::new());
+        let mut options = Options::default();
+        options.filter_policy = Some(Arc::new(BloomFilter::new(10)));
+        options.statistics = Some(stats.clone());
+        let db = new_test_db_with_options("test_multi_get", options);
+        for i in (0..1000).step_by(2) {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(key.as_bytes()),
+            )
+            .unwrap();
+        }
+        for i in (0..100).step_by(4) {
+            let key = format!("key{:04}", i);
+            db.delete(WriteOptions::default(), Slice::from(key.as_bytes()))
+                .unwrap();
+        }
+        drop(db.inner.make_room_for_write(true).unwrap());
+        wait_and_count_entries(&db);
+        // the newer entries in the memtable shadow the ones in the tables
+        db.put(
+            WriteOptions::default(),
+            Slice::from("key0002"),
+            Slice::from("new"),
+        )
+        .unwrap();
+        db.delete(WriteOptions::default(), Slice::from("key0006"))
+            .unwrap();
+        db.put(
+            WriteOptions::default(),
+            Slice::from("key2000"),
+            Slice::from("v"),
+        )
+        .unwrap();
+
+        let keys: Vec<String> = (0..1000)
+            .rev()
+            .map(|i| format!("key{:04}", i))
+            .chain(vec!["key2000".to_owned(), "missing".to_owned()])
+            .collect();
+        let keys: Vec<Slice> = keys.iter().map(|k| Slice::from(k.as_bytes())).collect();
+        let values = db.multi_get(ReadOptions::default(), &keys).unwrap();
+        assert_eq!(values.len(), keys.len());
+        for (key, value) in keys.iter().zip(values) {
+            assert_eq!(
+                value,
+                db.get(ReadOptions::default(), key.clone()).unwrap(),
+                "{}",
+                key.as_str()
+            );
+        }
+
+        // the table is skipped if no key of the batch could be in it
+        let block_reads = stats.get_ticker_count(Ticker::BlockRead);
+        let missing: Vec<Slice> = vec![Slice::from("key0001"), Slice::from("key0503")];
+        let values = db.multi_get(ReadOptions::default(), &missing).unwrap();
+        assert_eq!(values, vec![None, None]);
+        assert!(stats.get_ticker_count(Ticker::MultiGetTableSkipped) > 0);
+        assert_eq!(stats.get_ticker_count(Ticker::BlockRead), block_reads);
+    }
+
+    #[test]
+    fn test_estimate_keys_in_range() {
+        let mut options = Options::default();
+        options.write_buffer_size = 64 << 10;
+        let db = new_test_db_with_options("test_estimate_keys_in_range", options);
+        for i in 0..10000 {
+            let key = format!("key{:05}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(vec![b'v'; 50].as_slice()),
+            )
+            .unwrap();
+        }
+        drop(db.inner.make_room_for_write(true).unwrap());
+        wait_and_count_entries(&db);
+        let estimate = |start: Option<&str>, end: Option<&str>| {
+            db.estimate_keys_in_range(start.map(Slice::from), end.map(Slice::from))
+                .unwrap()
+        };
+        let assert_near = |estimated: u64, expected: u64| {
+            let diff = (estimated as f64 - expected as f64).abs();
+            assert!(
+                diff <= expected as f64 * 0.2 + 100.0,
+                "estimated {} but expected {}",
+                estimated,
+                expected
+            );
+        };
+        assert_near(estimate(None, None), 10000);
+        assert_near(estimate(Some("key02000"), Some("key04000")), 2000);
+        assert_near(estimate(Some("key09000"), None), 1000);
+        assert_near(estimate(None, Some("key00500")), 500);
+        assert_eq!(estimate(Some("key2"), None), 0);
+        assert_eq!(estimate(Some("key05000"), Some("key05000")), 0);
+
+        // the entries in the memtable are counted exactly
+        for i in 0..100 {
+            let key = format!("mem{:03}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from("v"),
+            )
+            .unwrap();
+        }
+        assert_eq!(estimate(Some("mem010"), Some("mem020")), 10);
+    }
+
+    #[test]
+    fn test_iterator_pool() {
+        let db = new_test_db("test_iterator_pool");
+        let put = |key: &str| {
+            db.put(WriteOptions::default(), Slice::from(key), Slice::from("v"))
+                .unwrap()
+        };
+        for i in 0..100 {
+            put(format!("key{:03}", i).as_str());
+        }
+        let pool = db.iterator_pool(2);
+        let scan = |read_opt: ReadOptions| {
+            let mut iter = pool.iter(read_opt);
+            let mut count = 0;
+            iter.seek_to_first();
+            while iter.valid() {
+                count += 1;
+                iter.next();
+            }
+            iter.status().unwrap();
+            count
+        };
+        assert_eq!(scan(ReadOptions::default()), 100);
+        assert_eq!(pool.num_idle(), 1);
+        // the child iterators are reused and see the new writes in the same memtable
+        put("key100");
+        assert_eq!(scan(ReadOptions::default()), 101);
+        assert_eq!((pool.num_created(), pool.num_reused()), (1, 1));
+        let snapshot = db.snapshot();
+        put("key101");
+        let read_opt = ReadOptions {
+            snapshot: Some(snapshot.as_ref().clone()),
+            ..ReadOptions::default()
+        };
+        assert_eq!(scan(read_opt), 101);
+        assert_eq!(pool.num_reused(), 2);
+
+        // the child iterators are rebuilt after the memtable is rotated
+        drop(db.inner.make_room_for_write(true).unwrap());
+        wait_and_count_entries(&db);
+        assert_eq!(scan(ReadOptions::default()), 102);
+        assert_eq!(pool.num_created(), 2);
+        {
+            let _a = pool.iter(ReadOptions::default());
+            let _b = pool.iter(ReadOptions::default());
+            let _c = pool.iter(ReadOptions::default());
+        }
+        assert_eq!(pool.num_idle(), 2);
+    }
+
+    #[test]
+    fn test_tombstone_stats() {
+        let db = new_test_db("test_tombstone_stats");
+        for i in 0..1000 {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from("v"),
+            )
+            .unwrap();
+        }
+        for i in 100..400 {
+            let key = format!("key{:04}", i);
+            db.delete(WriteOptions::default(), Slice::from(key.as_bytes()))
+                .unwrap();
+        }
+        // the deletions in the memtable are not counted
+        let stats = db.tombstone_stats().unwrap();
+        assert_eq!(stats.len(), db.inner.options.max_levels as usize);
+        assert!(stats.iter().all(|s| s.num_entries == 0));
+
+        drop(db.inner.make_room_for_write(true).unwrap());
+        let entries = wait_and_count_entries(&db) as u64;
+        let stats = db.tombstone_stats().unwrap();
+        assert_eq!(stats.iter().map(|s| s.num_entries).sum::<u64>(), entries);
+        assert_eq!(stats.iter().map(|s| s.num_deletions).sum::<u64>(), 300);
+        let level = stats.iter().find(|s| s.num_deletions > 0).unwrap();
+        assert_eq!(level.num_files_with_deletions, 1);
+        assert_eq!(level.deletion_ratio(), 300.0 / 1300.0);
+        assert_eq!(
+            level.deleted_range,
+            Some((b"key0100".to_vec(), b"key0399".to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_cancel_reads() {
+        let db = new_test_db("test_cancel_reads");
+        for i in 0..1000 {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from("v"),
+            )
+            .unwrap();
+        }
+        drop(db.inner.make_room_for_write(true).unwrap());
+        let token = CancellationToken::new();
+        let read_opt = || ReadOptions {
+            cancel_token: Some(token.clone()),
+            cancel_check_interval: 10,
+            ..ReadOptions::default()
+        };
+        assert!(db
+            .get(read_opt(), Slice::from("key0000"))
+            .unwrap()
+            .is_some());
+        let mut iter = db.iter(read_opt());
+        iter.seek_to_first();
+        for _ in 0..100 {
+            iter.next();
+        }
+        assert!(iter.valid());
+        token.cancel();
+        // the scan stops at the next check point
+        let mut scanned = 0;
+        while iter.valid() {
+            scanned += 1;
+            iter.next();
+        }
+        assert!(scanned <= 10);
+        assert_eq!(iter.status().unwrap_err().status(), Status::Cancelled);
+        iter.seek(&Slice::from("key0500"));
+        assert!(!iter.valid());
+        assert_eq!(iter.status().unwrap_err().status(), Status::Cancelled);
+
+        let res = db.get(read_opt(), Slice::from("key0000"));
+        assert_eq!(res.unwrap_err().status(), Status::Cancelled);
+        let res = db.list_prefixes(read_opt(), Slice::from("key"), Slice::from("0"));
+        assert_eq!(res.unwrap_err().status(), Status::Cancelled);
+        // the reads without the token are not affected
+        assert_eq!(count_entries(db.iter(ReadOptions::default())), 1000);
+    }
+
+    #[test]
+    fn test_read_flushed_tables_before_installed() {
+        let db = new_test_db("test_read_flushed_tables_before_installed");
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(key.as_bytes()),
+            )
+            .unwrap();
+        }
+        db.delete(WriteOptions::default(), Slice::from("key000"))
+            .unwrap();
+        let inner = db.inner.clone();
+        // Flushes the memtable without installing the table like `compact_mem_table`
+        // does before writing the MANIFEST
+        let mut edit = VersionEdit::new(inner.options.max_levels);
+        {
+            let mut versions = inner.versions.lock().unwrap();
+            let mem = inner.mem.read().unwrap();
+            versions
+                .write_level0_files(
+                    inner.db_name.as_str(),
+                    inner.table_cache.clone(),
+                    mem.iter(),
+                    &mut edit,
+                    None,
+                )
+                .unwrap();
+        }
+        *inner.flushed_tables.write().unwrap() = edit
+            .new_files
+            .iter()
+            .map(|(_, f)| (f.number, f.file_size))
+            .collect();
+        *inner.mem.write().unwrap() = inner.new_memtable();
+        inner.delete_obsolete_files(inner.versions.lock().unwrap());
+
+        let check = || {
+            assert_eq!(
+                db.get(ReadOptions::default(), Slice::from("key000"))
+                    .unwrap(),
+                None
+            );
+            for i in 1..100 {
+                let key = format!("key{:03}", i);
+                let got = db
+                    .get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                    .unwrap();
+                assert_eq!(got.as_deref(), Some(key.as_bytes()));
+            }
+            assert_eq!(count_entries(db.iter(ReadOptions::default())), 99);
+        };
+        check();
+        inner
+            .versions
+            .lock()
+            .unwrap()
+            .log_and_apply(&mut edit)
+            .unwrap();
+        inner.flushed_tables.write().unwrap().clear();
+        check();
+    }
+
+    // Deactivates the storage once the table of the first flush is built, which
+    // fails the MANIFEST write of the flush
+    struct FailingManifestListener {
+        storage: Arc<FaultInjectionStorage>,
+        failed: AtomicBool,
+    }
+
+    impl EventListener for FailingManifestListener {
+        fn on_table_file_created(&self, _info: &TableFileCreationInfo) {
+            if !self.failed.swap(true, Ordering::SeqCst) {
+                self.storage.set_active(false);
+            }
+        }
+    }
+
+    #[test]
+    fn test_flush_failing_manifest_write() {
+        let storage = Arc::new(FaultInjectionStorage::new(Arc::new(MemStorage::default())));
+        let new_options = || Options {
+            env: storage.clone(),
+            listeners: vec![Arc::new(FailingManifestListener {
+                storage: storage.clone(),
+                failed: AtomicBool::new(false),
+            })],
+            ..Options::default()
+        };
+        let name = "test_flush_failing_manifest_write".to_owned();
+        let mut db = WickDB::open_db(new_options(), name.clone()).unwrap();
+        let get = |db: &WickDB, key: &str| {
+            db.get(ReadOptions::default(), Slice::from(key))
+                .unwrap()
+                .map(|v| v.to_vec())
+        };
+        db.put(
+            WriteOptions::default(),
+            Slice::from("k1"),
+            Slice::from("v1"),
+        )
+        .unwrap();
+        assert!(db.flush_memtable().is_err());
+        storage.set_active(true);
+        assert_eq!(get(&db, "k1"), Some(b"v1".to_vec()));
+        // The memtable whose table failed to be installed is flushed again first
+        db.put(
+            WriteOptions::default(),
+            Slice::from("k2"),
+            Slice::from("v2"),
+        )
+        .unwrap();
+        db.flush_memtable().unwrap();
+        assert_eq!(get(&db, "k1"), Some(b"v1".to_vec()));
+        assert_eq!(get(&db, "k2"), Some(b"v2".to_vec()));
+        db.close().unwrap();
+
+        let db = WickDB::open_db(new_options(), name).unwrap();
+        assert_eq!(get(&db, "k1"), Some(b"v1".to_vec()));
+        assert_eq!(get(&db, "k2"), Some(b"v2".to_vec()));
+    }
+
+    #[test]
+    fn test_direct_io_for_flush_and_compaction() {
+        let new_options = || {
+            let mut options = Options::default();
+            options.env = Arc::new(FileStorage);
+            options.write_buffer_size = 64 << 10;
+            options.use_direct_io_for_flush_and_compaction = true;
+            options
+        };
+        let name = "test_direct_io_for_flush_and_compaction".to_owned();
+        let _ = FileStorage.remove_dir(name.as_str(), true);
+        let mut db = WickDB::open_db(new_options(), name.clone()).unwrap();
+        let value = vec![b'v'; 1000];
+        for i in 0..500 {
+            let key = format!("key{:03}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(value.as_slice()),
+            )
+            .unwrap();
+        }
+        assert_eq!(wait_and_count_entries(&db), 500);
+        db.close().unwrap();
+
+        // The compaction inputs are read by the direct IO too
+        WickDB::compact_offline(new_options(), name.clone()).unwrap();
+        let mut db = WickDB::open_db(new_options(), name).unwrap();
+        assert_eq!(wait_and_count_entries(&db), 500);
+        for i in 0..500 {
+            let key = format!("key{:03}", i);
+            let got = db
+                .get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                .unwrap();
+            assert_eq!(got.as_deref(), Some(value.as_slice()));
+        }
+        db.destroy().unwrap();
+    }
+
+    struct ReverseComparator;
+
+    impl Comparator for ReverseComparator {
+        fn compare(&self, a: &[u8], b: &[u8]) -> CmpOrdering {
+            b.cmp(a)
+        }
+
+        fn name(&self) -> &str {
+            "test.ReverseComparator"
+        }
+
+        fn separator(&self, a: &[u8], _b: &[u8]) -> Vec<u8> {
+            a.to_vec()
+        }
+
+        fn successor(&self, key: &[u8]) -> Vec<u8> {
+            key.to_vec()
+        }
+    }
+
+    #[test]
+    fn test_migrate_comparator() {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let new_options = |reverse: bool| {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.write_buffer_size = 64 << 10;
+            if reverse {
+                options.comparator = Arc::new(ReverseComparator);
+            }
+            options
+        };
+        let mut src = WickDB::open_db(new_options(false), "src".to_owned()).unwrap();
+        let value = vec![b'v'; 500];
+        for i in 0..500 {
+            let key = format!("key{:03}", i);
+            src.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(value.as_slice()),
+            )
+            .unwrap();
+            if i % 5 == 0 {
+                src.delete(WriteOptions::default(), Slice::from(key.as_bytes()))
+                    .unwrap();
+            }
+        }
+        wait_and_count_entries(&src);
+        src.close().unwrap();
+
+        let migrate = || {
+            WickDB::migrate_comparator(
+                new_options(false),
+                "src".to_owned(),
+                new_options(true),
+                "dst".to_owned(),
+            )
+        };
+        assert_eq!(migrate().unwrap(), 400);
+        // The destination must be a new db
+        assert!(migrate().is_err());
+        assert!(WickDB::migrate_comparator(
+            new_options(false),
+            "src".to_owned(),
+            new_options(true),
+            "src".to_owned(),
+        )
+        .is_err());
+
+        assert!(WickDB::open_db(new_options(false), "dst".to_owned()).is_err());
+        let dst = WickDB::open_db(new_options(true), "dst".to_owned()).unwrap();
+        let mut iter = dst.iter(ReadOptions::default());
+        iter.seek_to_first();
+        let mut expected = (0..500).rev().filter(|i| i % 5 != 0);
+        while iter.valid() {
+            let key = format!("key{:03}", expected.next().unwrap());
+            assert_eq!(iter.key().as_slice(), key.as_bytes());
+            assert_eq!(iter.value().as_slice(), value.as_slice());
+            iter.next();
+        }
+        assert!(expected.next().is_none());
+        // The source is untouched
+        let src = WickDB::open_read_only(new_options(false), "src".to_owned()).unwrap();
+        assert_eq!(count_entries(src.iter(ReadOptions::default())), 400);
+    }
+
+    #[test]
+    fn test_read_only_and_secondary() {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let new_options = || {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.write_buffer_size = 64 << 10;
+            options
+        };
+        let put = |db: &WickDB, key: &str, value: &str| {
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key),
+                Slice::from(value),
+            )
+        };
+        let get = |db: &WickDB, key: &str| {
+            db.get(ReadOptions::default(), Slice::from(key))
+                .unwrap()
+                .map(|v| String::from_utf8(v).unwrap())
+        };
+        let primary = WickDB::open_db(new_options(), "primary".to_owned()).unwrap();
+        // Some entries are flushed into the tables and the others are in the WAL
+        let value = "v".repeat(1000);
+        for i in 0..200 {
+            put(&primary, &format!("key{}", i), &value).unwrap();
+        }
+        put(&primary, "foo", "v1").unwrap();
+
+        assert!(WickDB::open_read_only(new_options(), "missing".to_owned()).is_err());
+        let read_only = WickDB::open_read_only(new_options(), "primary".to_owned()).unwrap();
+        let mut secondary_options = new_options();
+        secondary_options.secondary_catch_up_interval_micros = 0;
+        let secondary = WickDB::open_as_secondary(
+            secondary_options,
+            "primary".to_owned(),
+            "secondary".to_owned(),
+        )
+        .unwrap();
+        for db in [&read_only, &secondary].iter() {
+            assert_eq!(count_entries(db.iter(ReadOptions::default())), 201);
+            assert_eq!(get(db, "key42"), Some(value.clone()));
+            assert_eq!(get(db, "foo"), Some("v1".to_owned()));
+            let res = put(db, "foo", "v2");
+            assert_eq!(res.unwrap_err().status(), Status::NotSupported);
+        }
+        let res = read_only.try_catch_up_with_primary();
+        assert_eq!(res.unwrap_err().status(), Status::NotSupported);
+
+        put(&primary, "foo", "v2").unwrap();
+        primary
+            .delete(WriteOptions::default(), Slice::from("key42"))
+            .unwrap();
+        for i in 200..400 {
+            put(&primary, &format!("key{}", i), &value).unwrap();
+        }
+        assert_eq!(get(&secondary, "foo"), Some("v1".to_owned()));
+        secondary.try_catch_up_with_primary().unwrap();
+        assert_eq!(get(&secondary, "foo"), Some("v2".to_owned()));
+        assert_eq!(get(&secondary, "key42"), None);
+        assert_eq!(count_entries(secondary.iter(ReadOptions::default())), 400);
+        // The read-only db never changes
+        assert_eq!(get(&read_only, "foo"), Some("v1".to_owned()));
+        assert_eq!(count_entries(read_only.iter(ReadOptions::default())), 201);
+
+        // Catches up periodically
+        let mut secondary_options = new_options();
+        secondary_options.secondary_catch_up_interval_micros = 10_000;
+        let secondary = WickDB::open_as_secondary(
+            secondary_options,
+            "primary".to_owned(),
+            "secondary2".to_owned(),
+        )
+        .unwrap();
+        put(&primary, "foo", "v3").unwrap();
+        for _ in 0..100 {
+            if get(&secondary, "foo") == Some("v3".to_owned()) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(get(&secondary, "foo"), Some("v3".to_owned()));
+    }
+
+    #[test]
+    fn test_lock_read_only() {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let new_options = |lock_read_only| {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.lock_read_only = lock_read_only;
+            options
+        };
+        let mut writer = WickDB::open_db(new_options(false), "db".to_owned()).unwrap();
+        let err = WickDB::open_db(new_options(false), "db".to_owned())
+            .err()
+            .unwrap();
+        assert_eq!(err.status(), Status::Busy);
+        // The unlocked read-only instance coexists with the writer
+        assert!(WickDB::open_read_only(new_options(false), "db".to_owned()).is_ok());
+        let err = WickDB::open_read_only(new_options(true), "db".to_owned())
+            .err()
+            .unwrap();
+        assert_eq!(err.status(), Status::Busy);
+        writer.close().unwrap();
+        drop(writer);
+
+        // The locked read-only instances share the lock
+        let mut r1 = WickDB::open_read_only(new_options(true), "db".to_owned()).unwrap();
+        let r2 = WickDB::open_read_only(new_options(true), "db".to_owned()).unwrap();
+        let err = WickDB::open_db(new_options(false), "db".to_owned())
+            .err()
+            .unwrap();
+        assert_eq!(err.status(), Status::Busy);
+        r1.close().unwrap();
+        assert!(WickDB::open_db(new_options(false), "db".to_owned()).is_err());
+        drop(r2);
+        assert!(WickDB::open_db(new_options(false), "db".to_owned()).is_ok());
+    }
+
+    #[test]
+    fn test_bloom_filters_and_bottommost_filters() {
+        let stats = Arc::new(Statistics::new());
+        let mut options = Options::default();
+        options.bloom_bits_per_key = 10;
+        options.skip_bottommost_filters = true;
+        options.max_mem_compact_level = 0;
+        options.statistics = Some(stats.clone());
+        let db = new_test_db_with_options("test_bloom_filters_and_bottommost_filters", options);
+        for i in (0..200).step_by(2) {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(key.as_bytes()),
+            )
+            .unwrap();
+        }
+        db.flush_memtable().unwrap();
+        let filter_size = |level| {
+            let f = db
+                .inner
+                .versions
+                .lock()
+                .unwrap()
+                .current()
+                .get_level_files(level)[0]
+                .clone();
+            db.inner
+                .table_cache
+                .properties(f.number, f.file_size)
+                .unwrap()
+                .filter_size
+        };
+        let data_block_seeks = || stats.get_ticker_count(Ticker::DataBlockSeek);
+        let check_gets = || {
+            for i in 0..200 {
+                let key = format!("key{:04}", i);
+                let expected = if i % 2 == 0 {
+                    Some(key.as_bytes().to_vec())
+                } else {
+                    None
+                };
+                assert_eq!(
+                    db.get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                        .unwrap(),
+                    expected
+                );
+            }
+        };
+        assert!(filter_size(0) > 0);
+        check_gets();
+        // The filters of the user keys rule out the missing keys
+        let seeks = data_block_seeks();
+        for i in (1..200).step_by(20) {
+            let key = format!("key{:04}", i);
+            assert!(db
+                .get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                .unwrap()
+                .is_none());
+        }
+        assert_eq!(data_block_seeks(), seeks);
+
+        // The bottommost tables carry no filters
+        db.inner.compact_level_fully(0).unwrap();
+        assert_eq!(filter_size(1), 0);
+        check_gets();
+    }
+
+    #[test]
+    fn test_set_options_ignore_filters() {
+        let stats = Arc::new(Statistics::new());
+        let mut options = Options::default();
+        options.bloom_bits_per_key = 10;
+        options.statistics = Some(stats.clone());
+        let db = new_test_db_with_options("test_set_options_ignore_filters", options);
+        for i in (0..100).step_by(2) {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(key.as_bytes()),
+            )
+            .unwrap();
+        }
+        db.flush_memtable().unwrap();
+        let get_missing = |read_opt: ReadOptions| {
+            let seeks = stats.get_ticker_count(Ticker::DataBlockSeek);
+            for i in (1..98).step_by(2) {
+                let key = format!("key{:04}", i);
+                assert!(db
+                    .get(read_opt.clone(), Slice::from(key.as_bytes()))
+                    .unwrap()
+                    .is_none());
+            }
+            stats.get_ticker_count(Ticker::DataBlockSeek) - seeks
+        };
+        // barely any data block is read for the keys ruled out by the filters
+        assert!(get_missing(ReadOptions::default()) < 5);
+        let read_opt = ReadOptions {
+            ignore_filters: true,
+            ..ReadOptions::default()
+        };
+        assert_eq!(get_missing(read_opt), 49);
+
+        assert_eq!(db.mutable_options(), MutableOptions::default());
+        db.set_options(MutableOptions {
+            ignore_filters: true,
+        });
+        assert!(db.mutable_options().ignore_filters);
+        assert_eq!(get_missing(ReadOptions::default()), 49);
+        assert_eq!(
+            db.get(ReadOptions::default(), Slice::from("key0042"))
+                .unwrap(),
+            Some(b"key0042".to_vec())
+        );
+        db.set_options(MutableOptions::default());
+        // barely any data block is read for the keys ruled out by the filters
+        assert!(get_missing(ReadOptions::default()) < 5);
+    }
+
+    #[test]
+    fn test_consistency_check_level() {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let new_options = |level| {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.consistency_check_level = level;
+            options
+        };
+        let mut db =
+            WickDB::open_db(new_options(ConsistencyCheckLevel::Full), "db".to_owned()).unwrap();
+        for i in 0..100 {
+            db.put(
+                WriteOptions::default(),
+                Slice::from(format!("key{}", i).as_bytes()),
+                Slice::from(format!("value{}", i).as_bytes()),
+            )
+            .unwrap();
+        }
+        db.flush_memtable().unwrap();
+        db.close().unwrap();
+        drop(db);
+        let table = env
+            .list("db")
+            .unwrap()
+            .into_iter()
+            .find(|f| matches!(parse_filename(f), Some((FileType::Table, _))))
+            .unwrap();
+        let table = table.to_str().unwrap().to_owned();
+        let mut contents = vec![];
+        env.open(table.as_str())
+            .unwrap()
+            .read_all(&mut contents)
+            .unwrap();
+        let rewrite = |contents: &[u8]| {
+            env.remove(table.as_str()).unwrap();
+            env.create(table.as_str()).unwrap().write(contents).unwrap();
+        };
+        let open = |level| {
+            WickDB::open_db(new_options(level), "db".to_owned()).map(|mut db| db.close().unwrap())
+        };
+
+        // A corrupted data block is only found by checking the checksums
+        let mut corrupted = contents.clone();
+        corrupted[0] ^= 0xff;
+        rewrite(&corrupted);
+        open(ConsistencyCheckLevel::Standard).unwrap();
+        let err = open(ConsistencyCheckLevel::Full).unwrap_err();
+        assert_eq!(err.status(), Status::Corruption);
+        assert_eq!(
+            err.corruption_report().unwrap().file.as_deref(),
+            Some(table.as_str())
+        );
+
+        // The size is checked since `Standard`
+        let mut appended = contents.clone();
+        appended.push(0);
+        rewrite(&appended);
+        open(ConsistencyCheckLevel::Quick).unwrap();
+        let err = open(ConsistencyCheckLevel::Standard).unwrap_err();
+        assert_eq!(err.status(), Status::Corruption);
+
+        // The footer is checked since `Quick`
+        let mut bad_magic = contents.clone();
+        *bad_magic.last_mut().unwrap() ^= 0xff;
+        rewrite(&bad_magic);
+        open(ConsistencyCheckLevel::Off).unwrap();
+        let err = open(ConsistencyCheckLevel::Quick).unwrap_err();
+        assert_eq!(err.status(), Status::Corruption);
+        let err =
+            WickDB::open_read_only(new_options(ConsistencyCheckLevel::Quick), "db".to_owned())
+                .err()
+                .unwrap();
+        assert_eq!(err.status(), Status::Corruption);
+
+        rewrite(&contents);
+        open(ConsistencyCheckLevel::Full).unwrap();
+    }
+
+    #[derive(Default)]
+    struct FifoListener {
+        deletions: Mutex<Vec<FifoDeletionInfo>>,
+    }
+
+    impl EventListener for FifoListener {
+        fn on_fifo_deletion(&self, info: &FifoDeletionInfo) {
+            self.deletions.lock().unwrap().push(info.clone());
+        }
+    }
+
+    #[test]
+    fn test_fifo_mode() {
+        let listener = Arc::new(FifoListener::default());
+        let mut options = Options::default();
+        options.write_buffer_size = 64 << 10;
+        options.compression = CompressionType::NoCompression;
+        options.fifo_max_table_files_size = 512 << 10;
+        // The writes would stop forever without the FIFO mode
+        options.l0_slowdown_writes_threshold = 2;
+        options.l0_stop_writes_threshold = 4;
+        options.listeners.push(listener.clone());
+        let db = new_test_db_with_options("test_fifo_mode", options);
+        let mut rnd = rand::thread_rng();
+        let total = 3000;
+        for i in 0..total {
+            let value: Vec<u8> = (0..1000).map(|_| rnd.gen_range(b'a', b'z')).collect();
+            db.put(
+                WriteOptions::default(),
+                Slice::from(format!("key{:08}", i).as_bytes()),
+                Slice::from(value.as_slice()),
+            )
+            .expect("put should work");
+        }
+        wait_and_count_entries(&db);
+        let versions = db.inner.versions.lock().unwrap();
+        let level0_files = versions.level_files_count(0);
+        // All the files stay in level 0 without stalling the writes
+        assert!(level0_files > db.inner.options.l0_stop_writes_threshold);
+        for level in 1..db.inner.options.max_levels as usize {
+            assert_eq!(versions.level_files_count(level), 0);
+        }
+        assert!(versions.level_files_size(0) <= 512 << 10);
+        mem::drop(versions);
+
+        let deletions = listener.deletions.lock().unwrap();
+        assert!(!deletions.is_empty());
+        for w in deletions.windows(2) {
+            assert!(w[0].watermark < w[1].watermark);
+        }
+        assert!(deletions.iter().all(|d| d.total_size <= 512 << 10));
+        // The oldest data is dropped while the newest remains
+        let get = |i: usize| {
+            db.get(
+                ReadOptions::default(),
+                Slice::from(format!("key{:08}", i).as_bytes()),
+            )
+            .unwrap()
+        };
+        assert_eq!(get(0), None);
+        assert!(get(total - 1).is_some());
+    }
+
+    #[test]
+    fn test_list_prefixes() {
+        let mut options = Options::default();
+        options.write_buffer_size = 64 << 10;
+        let db = new_test_db_with_options("test_list_prefixes", options);
+        let mut keys = vec![
+            "a".to_owned(),
+            "docs/".to_owned(),
+            "docs/a.txt".to_owned(),
+            "docs/c".to_owned(),
+            "docs0".to_owned(),
+            "img/x.png".to_owned(),
+            "img/y.png".to_owned(),
+            "img::1".to_owned(),
+            "img::2::3".to_owned(),
+            "zz/".to_owned(),
+        ];
+        for i in 0..2000 {
+            keys.push(format!("docs/b/{:05}", i));
+        }
+        for key in keys.iter() {
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(b"value".as_ref()),
+            )
+            .expect("put should work");
+        }
+        db.delete(WriteOptions::default(), Slice::from(b"docs/c".as_ref()))
+            .unwrap();
+        let list = |prefix: &str, delimiter: &str| -> Vec<String> {
+            db.list_prefixes(
+                ReadOptions::default(),
+                Slice::from(prefix),
+                Slice::from(delimiter),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|c| String::from_utf8(c).unwrap())
+            .collect()
+        };
+        assert_eq!(
+            list("", "/"),
+            vec!["a", "docs/", "docs0", "img/", "img::1", "img::2::3", "zz/"]
+        );
+        assert_eq!(list("docs/", "/"), vec!["docs/", "docs/a.txt", "docs/b/"]);
+        assert_eq!(list("docs/b/0001", "/").len(), 10);
+        assert_eq!(list("img", "::"), vec!["img/x.png", "img/y.png", "img::"]);
+        assert_eq!(list("img::", "::"), vec!["img::1", "img::2::"]);
+        assert!(list("x", "/").is_empty());
+        assert!(db
+            .list_prefixes(ReadOptions::default(), Slice::from(""), Slice::from(""))
+            .is_err());
+        assert_eq!(prefix_successor(b"ab/"), Some(b"ab0".to_vec()));
+        assert_eq!(prefix_successor(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_successor(b"\xff"), None);
+    }
+
+    fn test_seek_compaction(name: &str, seek_compaction: bool, by_multi_get: bool) {
+        let stats = Arc::new(Statistics::new());
+        let mut options = Options::default();
+        options.write_buffer_size = 64 << 10;
+        options.seek_compaction = seek_compaction;
+        options.bytes_per_seek = u64::max_value();
+        options.min_allowed_seeks = 10;
+        options.statistics = Some(stats.clone());
+        let db = new_test_db_with_options(name, options);
+        let value = vec![b'v'; 40 << 10];
+        // ["a", "z"] is flushed into level 2, then ["b", "y"] into level 1
+        for key in &["a", "z", "b", "y", "~"] {
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(value.as_slice()),
+            )
+            .expect("put should work");
+        }
+        wait_and_count_entries(&db);
+        assert_eq!(
+            db.get_property("wickdb.num-files-at-level1"),
+            Some("1".to_owned())
+        );
+        assert_eq!(
+            db.get_property("wickdb.num-files-at-level2"),
+            Some("1".to_owned())
+        );
+        // every miss of "m" goes through both files
+        for _ in 0..20 {
+            let key = Slice::from(b"m".as_ref());
+            if by_multi_get {
+                assert_eq!(
+                    db.multi_get(ReadOptions::default(), &[key]).unwrap(),
+                    vec![None]
+                );
+            } else {
+                assert!(db.get(ReadOptions::default(), key).unwrap().is_none());
+            }
+        }
+        assert_eq!(wait_and_count_entries(&db), 5);
+        let expected = if seek_compaction { "0" } else { "1" };
+        assert_eq!(
+            db.get_property("wickdb.num-files-at-level1"),
+            Some(expected.to_owned())
+        );
+        assert_eq!(
+            stats.get_ticker_count(Ticker::SeekCompactionTriggered),
+            seek_compaction as u64
+        );
+        for key in &["a", "z", "b", "y", "~"] {
+            assert_eq!(
+                db.get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                    .unwrap(),
+                Some(value.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn test_seek_compaction_enabled() {
+        test_seek_compaction("test_seek_compaction_enabled", true, false);
+    }
+
+    #[test]
+    fn test_seek_compaction_disabled() {
+        test_seek_compaction("test_seek_compaction_disabled", false, false);
+    }
+
+    fn test_bottommost_deletion_compaction(name: &str, ratio: f64) {
+        let stats = Arc::new(Statistics::new());
+        let mut options = Options::default();
+        options.bottommost_deletion_ratio = ratio;
+        options.statistics = Some(stats.clone());
+        let db = new_test_db_with_options(name, options);
+        for i in 0..100 {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(key.as_bytes()),
+            )
+            .unwrap();
+        }
+        for i in 0..80 {
+            let key = format!("key{:04}", i);
+            db.delete(WriteOptions::default(), Slice::from(key.as_bytes()))
+                .unwrap();
+        }
+        // the only file holds 80 deletions out of 180 entries
+        db.flush_memtable().unwrap();
+        let entries = wait_and_count_entries(&db);
+        let current = db.inner.versions.lock().unwrap().current();
+        let triggered = stats.get_ticker_count(Ticker::DeletionCompactionTriggered);
+        if ratio > 0.0 {
+            assert_eq!(entries, 20);
+            assert_eq!(triggered, 1);
+            assert!(current.get_level_files(2).is_empty());
+            let f = current.get_level_files(3)[0].clone();
+            let properties = db.inner.table_cache.properties(f.number, f.file_size);
+            assert_eq!(properties.unwrap().num_deletions, 0);
+        } else {
+            assert_eq!(entries, 180);
+            assert_eq!(triggered, 0);
+            assert_eq!(current.get_level_files(2).len(), 1);
+        }
+        for i in 0..100 {
+            let key = format!("key{:04}", i);
+            let expected = if i < 80 {
+                None
+            } else {
+                Some(key.as_bytes().to_vec())
+            };
+            assert_eq!(
+                db.get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                    .unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_bottommost_deletion_compaction_enabled() {
+        test_bottommost_deletion_compaction("test_bottommost_deletion_compaction_enabled", 0.4);
+    }
+
+    #[test]
+    fn test_bottommost_deletion_compaction_disabled() {
+        test_bottommost_deletion_compaction("test_bottommost_deletion_compaction_disabled", 0.0);
+    }
+
+    #[test]
+    fn test_dynamic_level_bytes_migration() {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let new_options = |dynamic| {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.level_compaction_dynamic_level_bytes = dynamic;
+            options
+        };
+        let mut db = WickDB::open_db(new_options(false), "db".to_owned()).unwrap();
+        for i in 0..100 {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(key.as_bytes()),
+            )
+            .unwrap();
+        }
+        db.flush_memtable().unwrap();
+        wait_and_count_entries(&db);
+        assert_eq!(
+            db.get_property("wickdb.num-files-at-level2"),
+            Some("1".to_owned())
+        );
+        db.close().unwrap();
+        drop(db);
+
+        // the file is moved down to the last level by the compactions after reopening
+        let db = WickDB::open_db(new_options(true), "db".to_owned()).unwrap();
+        assert_eq!(wait_and_count_entries(&db), 100);
+        for level in 0..6 {
+            assert_eq!(
+                db.get_property(&format!("wickdb.num-files-at-level{}", level)),
+                Some("0".to_owned())
+            );
+        }
+        assert_eq!(
+            db.get_property("wickdb.num-files-at-level6"),
+            Some("1".to_owned())
+        );
+        assert!(db
+            .version_debug_json()
+            .contains("\"level\":6,\"num_files\":1,"));
+        assert_eq!(
+            db.get(ReadOptions::default(), Slice::from("key0042"))
+                .unwrap(),
+            Some(b"key0042".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_pause_background_work() {
+        let mut options = Options::default();
+        options.write_buffer_size = 64 << 10;
+        let db = new_test_db_with_options("test_pause_background_work", options);
+        assert_eq!(
+            db.continue_background_work().unwrap_err().status(),
+            Status::InvalidArgument
+        );
+        db.pause_background_work().unwrap();
+        db.pause_background_work().unwrap();
+        // the second put rotates the full memtable, which is not flushed
+        for i in 0..2 {
+            db.put(
+                WriteOptions::default(),
+                Slice::from(format!("key{}", i).as_bytes()),
+                Slice::from(vec![b'v'; 80 << 10].as_slice()),
+            )
+            .unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(db.inner.im_mem.read().unwrap().len(), 1);
+        assert_eq!(
+            db.inner
+                .background_compactions_scheduled
+                .load(Ordering::Acquire),
+            0
+        );
+        // still paused by the first call
+        db.continue_background_work().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(db.inner.im_mem.read().unwrap().len(), 1);
+
+        db.continue_background_work().unwrap();
+        assert_eq!(wait_and_count_entries(&db), 2);
+        assert!(db.inner.im_mem.read().unwrap().is_empty());
+        let files: usize = (0..7)
+            .map(|level| {
+                db.get_property(&format!("wickdb.num-files-at-level{}", level))
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap()
+            })
+            .sum();
+        assert!(files > 0);
+        assert_eq!(
+            db.get(ReadOptions::default(), Slice::from("key0")).unwrap(),
+            Some(vec![b'v'; 80 << 10])
+        );
+    }
+
+    fn test_close(flush_on_close: bool) {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let new_options = || {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.flush_on_close = flush_on_close;
+            options
+        };
+        let mut db = WickDB::open_db(new_options(), "db".to_owned()).unwrap();
+        db.put(
+            WriteOptions::default(),
+            Slice::from("logged"),
+            Slice::from("v1"),
+        )
+        .unwrap();
+        let mut write_opt = WriteOptions::default();
+        write_opt.disable_wal = true;
+        db.put(write_opt, Slice::from("unlogged"), Slice::from("v2"))
+            .unwrap();
+        db.close().unwrap();
+        // closing again is a no-op
+        db.close().unwrap();
+        assert_eq!(
+            db.put(
+                WriteOptions::default(),
+                Slice::from("logged"),
+                Slice::from("v3"),
+            )
+            .unwrap_err()
+            .status(),
+            Status::NotSupported
+        );
+        assert_eq!(
+            db.inner
+                .background_compactions_scheduled
+                .load(Ordering::Acquire),
+            0
+        );
+        // the lock is released before the db is dropped
+        let db2 = WickDB::open_db(new_options(), "db".to_owned()).unwrap();
+        assert_eq!(
+            db2.get(ReadOptions::default(), Slice::from("logged"))
+                .unwrap(),
+            Some(b"v1".to_vec())
+        );
+        let unlogged = db2
+            .get(ReadOptions::default(), Slice::from("unlogged"))
+            .unwrap();
+        if flush_on_close {
+            assert_eq!(unlogged, Some(b"v2".to_vec()));
+        } else {
+            assert_eq!(unlogged, None);
+        }
+        drop(db);
+    }
+
+    #[test]
+    fn test_close_flushing_memtables() {
+        test_close(true);
+    }
+
+    #[test]
+    fn test_close_abandoning_memtables() {
+        test_close(false);
+    }
+
+    #[test]
+    fn test_close_cancelling_compactions() {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let new_options = || {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.write_buffer_size = 64 << 10;
+            options
+        };
+        let mut db = WickDB::open_db(new_options(), "db".to_owned()).unwrap();
+        let total = 2000;
+        for i in 0..total {
+            let key = format!("key{:08}", (i * 7919) % total);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(key.repeat(20).as_bytes()),
+            )
+            .unwrap();
+        }
+        db.close().unwrap();
+        assert_eq!(
+            db.inner
+                .background_compactions_scheduled
+                .load(Ordering::Acquire),
+            0
+        );
+        drop(db);
+        // nothing is lost by the cancelled compactions
+        let db = WickDB::open_db(new_options(), "db".to_owned()).unwrap();
+        assert_eq!(wait_and_count_entries(&db), total);
+    }
+
+    #[test]
+    fn test_periodic_compaction() {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let new_options = || {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.level_compaction_dynamic_level_bytes = true;
+            options
+        };
+        let mut db = WickDB::open_db(new_options(), "db".to_owned()).unwrap();
+        for i in 0..100 {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(key.as_bytes()),
+            )
+            .unwrap();
+        }
+        db.flush_memtable().unwrap();
+        assert_eq!(wait_and_count_entries(&db), 100);
+        db.close().unwrap();
+        drop(db);
+
+        // the file in the last level is rewritten in place once it's old enough
+        let stats = Arc::new(Statistics::new());
+        let filter = Arc::new(ModuloFilter {
+            enabled: AtomicBool::new(true),
+            calls: AtomicUsize::new(0),
+        });
+        let mut options = new_options();
+        options.periodic_compaction_seconds = 1;
+        options.statistics = Some(stats.clone());
+        options.compaction_filter = Some(filter.clone());
+        let db = WickDB::open_db(options, "db".to_owned()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while stats.get_ticker_count(Ticker::PeriodicCompactionTriggered) == 0
+            || filter.calls.load(Ordering::SeqCst) < 100
+        {
+            assert!(Instant::now() < deadline, "no periodic compaction");
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(
+            db.get(ReadOptions::default(), Slice::from("key0042"))
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            db.get(ReadOptions::default(), Slice::from("key0043"))
+                .unwrap(),
+            Some(b"KEY0043".to_vec())
+        );
+        assert_eq!(
+            db.get_property("wickdb.num-files-at-level6"),
+            Some("1".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_level_bytes_accounting() {
+        let stats = Arc::new(Statistics::new());
+        let mut options = Options::default();
+        options.statistics = Some(stats.clone());
+        let db = new_test_db_with_options("test_level_bytes_accounting", options);
+        for i in 0..100 {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(key.as_bytes()),
+            )
+            .unwrap();
+        }
+        db.flush_memtable().unwrap();
+        let level_files_size = |level| -> u64 {
+            let current = db.inner.versions.lock().unwrap().current();
+            current
+                .get_level_files(level)
+                .iter()
+                .map(|f| f.file_size)
+                .sum()
+        };
+        let count = |level, ticker| stats.get_level_ticker_count(level, ticker);
+        // the flushed file is pushed to level 2
+        let flushed = level_files_size(2);
+        assert!(flushed > 0);
+        assert_eq!(count(2, LevelTicker::FlushBytesWritten), flushed);
+        assert_eq!(count(0, LevelTicker::FlushBytesWritten), 0);
+
+        db.inner.compact_level_fully(2).unwrap();
+        assert_eq!(count(2, LevelTicker::CompactionBytesRead), flushed);
+        assert_eq!(count(3, LevelTicker::CompactionBytesRead), 0);
+        assert_eq!(
+            count(3, LevelTicker::CompactionBytesWritten),
+            level_files_size(3)
+        );
+        // compaction reads are not user reads
+        assert_eq!(count(2, LevelTicker::UserBytesRead), 0);
+        assert_eq!(count(3, LevelTicker::UserBytesRead), 0);
+
+        let read_opt = ReadOptions {
+            fill_cache: false,
+            ..ReadOptions::default()
+        };
+        let mut iter = db.iter(read_opt.clone());
+        iter.seek_to_first();
+        while iter.valid() {
+            iter.next();
+        }
+        let scanned = count(3, LevelTicker::UserBytesRead);
+        assert!(scanned > 0);
+        assert_eq!(
+            db.get(read_opt, Slice::from("key0042")).unwrap(),
+            Some(b"key0042".to_vec())
+        );
+        assert!(count(3, LevelTicker::UserBytesRead) > scanned);
+        assert_eq!(count(2, LevelTicker::UserBytesRead), 0);
+        assert!(stats
+            .to_string()
+            .contains("wickdb.level3.user.bytes.read COUNT"));
+    }
+
+    #[test]
+    fn test_seek_compaction_by_multi_get() {
+        test_seek_compaction("test_seek_compaction_by_multi_get", true, true);
+    }
+
+    #[test]
+    fn test_table_file_naming() {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let name = "test_table_file_naming";
+        let sharded = TableFileNaming {
+            extension: "ldb".to_owned(),
+            number_width: 8,
+            shards: 4,
+        };
+        let open = |naming: TableFileNaming| {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.write_buffer_size = 64 << 10;
+            options.table_file_naming = naming;
+            WickDB::open_db(options, name.to_owned())
+        };
+        let check = |db: &WickDB, round: usize| {
+            for i in 0..1000 {
+                let key = format!("key{:08}", i);
+                let value = db
+                    .get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                    .unwrap();
+                assert_eq!(
+                    value,
+                    Some(format!("value{}-{}", round, i).repeat(20).into_bytes())
+                );
+            }
+        };
+        let mut db = open(sharded.clone()).unwrap();
+        for i in 0..1000 {
+            let key = format!("key{:08}", i);
+            let value = format!("value0-{}", i).repeat(20);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(value.as_bytes()),
+            )
+            .unwrap();
+        }
+        let tables = sharded.list_table_files(env.as_ref(), name).unwrap();
+        assert!(!tables.is_empty());
+        for (number, path) in tables {
+            assert_eq!(
+                path.to_str().unwrap(),
+                sharded.table_file_name(name, number).as_str()
+            );
+        }
+        db.close().unwrap();
+
+        // the tables of the previous scheme are found
+        let mut db = open(TableFileNaming::default()).unwrap();
+        check(&db, 0);
+        db.close().unwrap();
+        let mut db = open(sharded.clone()).unwrap();
+        check(&db, 0);
+        db.close().unwrap();
+
+        let invalid = TableFileNaming {
+            extension: "log".to_owned(),
+            ..TableFileNaming::default()
+        };
+        assert!(open(invalid).is_err());
+        // a missing table is detected
+        let (number, path) = sharded.list_table_files(env.as_ref(), name).unwrap()[0].clone();
+        env.remove(path.to_str().unwrap()).unwrap();
+        match open(sharded) {
+            Err(e) => assert!(e.to_string().contains("missing files")),
+            Ok(_) => panic!("table #{} is missing", number),
+        }
+    }
+
+    #[test]
+    fn test_memtable_reps() {
+        for rep in [
+            MemtableRepType::Skiplist,
+            MemtableRepType::HashSkiplist { bucket_count: 64 },
+            MemtableRepType::Vector,
+        ] {
+            let mut options = Options::default();
+            options.memtable_rep = rep;
+            let db = new_test_db_with_options("test_memtable_reps", options);
+            for i in (0..200).rev() {
+                let key = format!("key{:03}", i);
+                db.put(
+                    WriteOptions::default(),
+                    Slice::from(key.as_bytes()),
+                    Slice::from(key.as_bytes()),
+                )
+                .unwrap();
+            }
+            for i in (0..200).step_by(10) {
+                let key = format!("key{:03}", i);
+                db.delete(WriteOptions::default(), Slice::from(key.as_bytes()))
+                    .unwrap();
+            }
+            let check = |db: &WickDB| {
+                for i in 0..200 {
+                    let key = format!("key{:03}", i);
+                    let value = db
+                        .get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                        .unwrap();
+                    if i % 10 == 0 {
+                        assert!(value.is_none(), "{:?} {}", rep, key);
+                    } else {
+                        assert_eq!(value.unwrap().as_slice(), key.as_bytes(), "{:?}", rep);
+                    }
+                }
+                assert_eq!(count_entries(db.iter(ReadOptions::default())), 180);
+            };
+            check(&db);
+            drop(db.inner.make_room_for_write(true).unwrap());
+            wait_and_count_entries(&db);
+            check(&db);
+        }
+    }
+
+    #[derive(Default)]
+    struct ObsoleteBlocksListener {
+        compacted_files: Mutex<Vec<u64>>,
+        blocks: Mutex<Vec<ObsoleteBlock>>,
+    }
+
+    impl EventListener for ObsoleteBlocksListener {
+        fn on_compaction_completed(&self, info: &CompactionJobInfo) {
+            if !info.is_trivial_move && info.succeeded {
+                self.compacted_files
+                    .lock()
+                    .unwrap()
+                    .extend(info.input_files.iter());
+            }
+        }
+
+        fn on_compaction_obsoleted_blocks(&self, info: &CompactionObsoleteBlocksInfo) {
+            self.blocks
+                .lock()
+                .unwrap()
+                .extend(info.blocks.iter().cloned());
+        }
+    }
+
+    #[test]
+    fn test_compaction_obsoleted_blocks() {
+        let listener = Arc::new(ObsoleteBlocksListener::default());
+        let mut options = Options::default();
+        options.write_buffer_size = 64 << 10;
+        options.block_size = 1024;
+        options.listeners.push(listener.clone());
+        let db = new_test_db_with_options("test_compaction_obsoleted_blocks", options);
+        let total = 3000;
+        for round in 0..3 {
+            for i in 0..total {
+                let key = format!("key{:08}", (i * 7919) % total);
+                let value = format!("value{}-{}", round, i).repeat(10);
+                db.put(
+                    WriteOptions::default(),
+                    Slice::from(key.as_bytes()),
+                    Slice::from(value.as_bytes()),
+                )
+                .unwrap();
+            }
+        }
+        wait_and_count_entries(&db);
+        let mut compacted_files = listener.compacted_files.lock().unwrap().clone();
+        assert!(!compacted_files.is_empty());
+        compacted_files.sort();
+        let blocks = listener.blocks.lock().unwrap();
+        let mut files: Vec<u64> = blocks.iter().map(|b| b.file_number).collect();
+        files.sort();
+        files.dedup();
+        assert_eq!(files, compacted_files);
+        for pair in blocks.windows(2) {
+            let (prev, next) = (&pair[0], &pair[1]);
+            if prev.file_number == next.file_number {
+                // the blocks of a file are adjacent and their key ranges are ordered
+                assert_eq!(
+                    prev.offset + prev.size + BLOCK_TRAILER_SIZE as u64,
+                    next.offset
+                );
+                assert!(prev.largest_key <= next.smallest_key);
+            }
+        }
+        for b in blocks.iter() {
+            assert!(b.smallest_key <= b.largest_key);
+        }
+        // the first block of every file starts at the beginning
+        let mut first_blocks: Vec<u64> = blocks
+            .iter()
+            .filter(|b| b.offset == 0)
+            .map(|b| b.file_number)
+            .collect();
+        first_blocks.sort();
+        assert_eq!(first_blocks, files);
+    }
+
+    #[test]
+    fn test_memtable_memory_usage() {
+        let mut options = Options::default();
+        options.arena_block_size = 1 << 20;
+        let db = new_test_db_with_options("test_memtable_memory_usage", options);
+        let property = |name: &str| -> usize { db.get_property(name).unwrap().parse().unwrap() };
+        // only the head of the skiplist is allocated
+        let empty_usage = property("wickdb.cur-size-active-mem-table");
+        assert!(empty_usage < 1024, "{}", empty_usage);
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(key.as_bytes()),
+            )
+            .unwrap();
+        }
+        // only the allocated bytes are counted instead of the whole block
+        let usage = property("wickdb.cur-size-active-mem-table");
+        assert!(
+            usage > empty_usage + 100 * 6 && usage < 64 << 10,
+            "{}",
+            usage
+        );
+        assert_eq!(property("wickdb.cur-size-all-mem-tables"), usage);
+        assert!(property("wickdb.size-all-mem-tables") >= 1 << 20);
+        assert!(property("wickdb.approximate-memory-usage") >= usage);
+        // the memtable is not flushed until the entries fill the write buffer
+        assert_eq!(
+            db.inner
+                .versions
+                .lock()
+                .unwrap()
+                .current()
+                .get_level_files(0)
+                .len(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_kv_size_histograms() {
+        let mut options = Options::default();
+        options.record_kv_size_histograms = true;
+        let db = new_test_db_with_options("test_kv_size_histograms", options);
+        for i in 0..1000 {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from("v".repeat(i % 100).as_bytes()),
+            )
+            .unwrap();
+        }
+        let histograms = db.kv_size_histograms().unwrap();
+        assert_eq!(histograms.num_tables, 0);
+        drop(db.inner.make_room_for_write(true).unwrap());
+        wait_and_count_entries(&db);
+        let histograms = db.kv_size_histograms().unwrap();
+        assert!(histograms.num_tables > 0);
+        assert_eq!(histograms.num_tables_without_histograms, 0);
+        // the sizes of the user keys without the sequences
+        assert_eq!(histograms.key_sizes.count(), 1000);
+        assert_eq!(histograms.key_sizes.max(), 7.0);
+        assert_eq!(histograms.value_sizes.max(), 99.0);
+        assert_eq!(
+            histograms.value_sizes.sum(),
+            (0..100).sum::<i32>() as f64 * 10.0
+        );
+    }
+
+    #[test]
+    fn test_multiple_immutable_memtables() {
+        let env = Arc::new(MemStorage::default());
+        let new_options = || {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.max_write_buffer_number = 4;
+            options
+        };
+        let name = "test_multiple_immutable_memtables".to_owned();
+        let mut db = WickDB::open_db(new_options(), name.clone()).unwrap();
+        // hold the flushes as if a slow flush is running
+        db.inner.flushing_mem.store(true, Ordering::Release);
+        for round in 0..3 {
+            for i in 0..100 {
+                let key = format!("key{:03}", i);
+                let value = format!("value{}", round);
+                db.put(
+                    WriteOptions::default(),
+                    Slice::from(key.as_bytes()),
+                    Slice::from(value.as_bytes()),
+                )
+                .unwrap();
+            }
+            // the rotations don't wait for the flushes
+            drop(db.inner.make_room_for_write(true).unwrap());
+        }
+        assert_eq!(db.inner.im_mem.read().unwrap().len(), 3);
+        assert_eq!(
+            db.get(ReadOptions::default(), Slice::from("key050"))
+                .unwrap(),
+            Some(b"value2".to_vec())
+        );
+        assert_eq!(count_entries(db.iter(ReadOptions::default())), 100);
+        assert_eq!(
+            db.inner
+                .versions
+                .lock()
+                .unwrap()
+                .current()
+                .get_level_files(0)
+                .len(),
+            0
+        );
+        db.close().unwrap();
+
+        // the logs of the queued memtables are recovered
+        let db = WickDB::open_db(new_options(), name).unwrap();
+        assert_eq!(
+            db.get(ReadOptions::default(), Slice::from("key050"))
+                .unwrap(),
+            Some(b"value2".to_vec())
+        );
+        db.inner.flushing_mem.store(true, Ordering::Release);
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from("value3"),
+            )
+            .unwrap();
+            if i % 50 == 49 {
+                drop(db.inner.make_room_for_write(true).unwrap());
+            }
+        }
+        // the queued memtables are flushed in order
+        db.inner.flushing_mem.store(false, Ordering::Release);
+        db.flush_memtable().unwrap();
+        assert!(db.inner.im_mem.read().unwrap().is_empty());
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            assert_eq!(
+                db.get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                    .unwrap(),
+                Some(b"value3".to_vec())
+            );
+        }
+        assert_eq!(wait_and_count_entries(&db), 100);
+    }
+
+    #[test]
+    fn test_iter_with_overlays() {
+        let db = new_test_db("test_iter_with_overlays");
+        let overlay = new_test_db("test_iter_with_overlays_overlay");
+        for (i, key) in ["a", "c", "e"].iter().enumerate() {
+            let value = format!("db{}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(*key),
+                Slice::from(value.as_bytes()),
+            )
+            .unwrap();
+        }
+        for key in ["b", "c", "f"].iter() {
+            overlay
+                .put(
+                    WriteOptions::default(),
+                    Slice::from(*key),
+                    Slice::from("overlay"),
+                )
+                .unwrap();
+        }
+        let collect = |iter: &mut MergingIterator, forward: bool| {
+            let mut entries = vec![];
+            while iter.valid() {
+                entries.push(format!("{}={}", iter.key().as_str(), iter.value().as_str()));
+                if forward {
+                    iter.next();
+                } else {
+                    iter.prev();
+                }
+            }
+            entries
+        };
+        let mut iter = db.iter_with_overlays(
+            ReadOptions::default(),
+            vec![overlay.iter(ReadOptions::default())],
+        );
+        iter.seek_to_first();
+        // the entry in the overlay comes before the one in the db
+        assert_eq!(
+            collect(&mut iter, true),
+            vec![
+                "a=db0",
+                "b=overlay",
+                "c=overlay",
+                "c=db1",
+                "e=db2",
+                "f=overlay"
+            ]
+        );
+        iter.seek(&Slice::from("d"));
+        assert_eq!(collect(&mut iter, true), vec!["e=db2", "f=overlay"]);
+        iter.seek_to_last();
+        assert_eq!(collect(&mut iter, false).len(), 6);
+        assert!(iter.status().is_ok());
+    }
+
+    #[test]
+    fn test_idle_compaction() {
+        let stats = Arc::new(Statistics::new());
+        let mut options = Options::default();
+        options.statistics = Some(stats.clone());
+        // keep the flushed tables in level0
+        options.max_mem_compact_level = 0;
+        options.idle_check_interval_micros = 10_000;
+        let db = new_test_db_with_options("test_idle_compaction", options);
+        for round in 0..2 {
+            for i in 0..100 {
+                let key = format!("key{:03}", i);
+                let value = format!("value{}", round);
+                db.put(
+                    WriteOptions::default(),
+                    Slice::from(key.as_bytes()),
+                    Slice::from(value.as_bytes()),
+                )
+                .unwrap();
+            }
+            db.flush_memtable().unwrap();
+        }
+        // the level0 files are below `l0_compaction_trigger` but compacted while idle
+        let files_at = |level: usize| {
+            db.inner
+                .versions
+                .lock()
+                .unwrap()
+                .current()
+                .get_level_files(level)
+                .len()
+        };
+        for _ in 0..500 {
+            if files_at(0) == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(files_at(0), 0);
+        assert_eq!(files_at(1), 1);
+        assert!(stats.get_ticker_count(Ticker::IdleCompactionTriggered) >= 1);
+        assert_eq!(
+            db.get(ReadOptions::default(), Slice::from("key050"))
+                .unwrap(),
+            Some(b"value1".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_version_debug_json() {
+        let mut options = Options::default();
+        // keep the flushed table in level0
+        options.max_mem_compact_level = 0;
+        let db = new_test_db_with_options("test_version_debug_json", options);
+        let json = db.version_debug_json();
+        assert!(json.starts_with("{\"last_sequence\":0,"), "{}", json);
+        assert!(
+            json.contains("\"num_files\":0,\"total_size\":0,"),
+            "{}",
+            json
+        );
+        db.put(
+            WriteOptions::default(),
+            Slice::from("a\"b"),
+            Slice::from("v"),
+        )
+        .unwrap();
+        db.put(
+            WriteOptions::default(),
+            Slice::from(&[b'z', 0xff][..]),
+            Slice::from("v"),
+        )
+        .unwrap();
+        db.flush_memtable().unwrap();
+        let json = db.version_debug_json();
+        let current = db.inner.versions.lock().unwrap().current();
+        let f = &current.get_level_files(0)[0];
+        assert!(json.starts_with("{\"last_sequence\":2,"), "{}", json);
+        assert!(
+            json.contains(
+                format!(
+                    "{{\"level\":0,\"num_files\":1,\"total_size\":{},",
+                    f.file_size
+                )
+                .as_str()
+            ),
+            "{}",
+            json
+        );
+        assert!(
+            json.contains(
+                format!(
+                    "{{\"number\":{},\"size\":{},\"allowed_seeks\":{},\"being_compacted\":false,\
+                     \"smallest\":{{\"user_key\":\"a\\\"b\",\"sequence\":1,\"value_type\":\"Value\"}},\
+                     \"largest\":{{\"user_key\":\"z\\u00ff\",\"sequence\":2,\"value_type\":\"Value\"}}}}",
+                    f.number,
+                    f.file_size,
+                    f.allowed_seeks.load(Ordering::Acquire)
+                )
+                .as_str()
+            ),
+            "{}",
+            json
+        );
+        assert!(json.ends_with("\"files\":[]}]}"), "{}", json);
+        // the brackets are balanced
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+        assert_eq!(json.matches('[').count(), json.matches(']').count());
+    }
+
+    #[test]
+    fn test_iterate_bounds() {
+        let db = new_test_db("test_iterate_bounds");
+        for i in 0..50 {
+            let key = format!("k{:02}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_str()),
+                Slice::from(key.as_str()),
+            )
+            .unwrap();
+        }
+        db.flush_memtable().unwrap();
+        db.delete(WriteOptions::default(), Slice::from("k15"))
+            .unwrap();
+        db.put(
+            WriteOptions::default(),
+            Slice::from("k12"),
+            Slice::from("new"),
+        )
+        .unwrap();
+        let bounded = || ReadOptions {
+            iterate_lower_bound: Some(b"k10".to_vec()),
+            iterate_upper_bound: Some(b"k20".to_vec()),
+            ..ReadOptions::default()
+        };
+        let expected: Vec<String> = (10..20)
+            .filter(|i| *i != 15)
+            .map(|i| format!("k{:02}", i))
+            .collect();
+
+        let mut iter = db.iter(bounded());
+        let mut keys = vec![];
+        iter.seek_to_first();
+        while iter.valid() {
+            keys.push(iter.key().as_str().to_owned());
+            iter.next();
+        }
+        assert_eq!(keys, expected);
+
+        let mut keys = vec![];
+        iter.seek_to_last();
+        while iter.valid() {
+            keys.push(iter.key().as_str().to_owned());
+            iter.prev();
+        }
+        keys.reverse();
+        assert_eq!(keys, expected);
+
+        // seeks are clamped to the lower bound
+        iter.seek(&Slice::from("k05"));
+        assert_eq!(iter.key().as_str(), "k10");
+        iter.seek(&Slice::from("k12"));
+        assert_eq!(iter.value().as_str(), "new");
+        iter.seek(&Slice::from("k20"));
+        assert!(!iter.valid());
+
+        // change the direction at the bounds
+        iter.seek_to_first();
+        iter.next();
+        iter.prev();
+        assert_eq!(iter.key().as_str(), "k10");
+        iter.seek_to_last();
+        iter.prev();
+        iter.next();
+        assert_eq!(iter.key().as_str(), "k19");
+        assert!(iter.status().is_ok());
+
+        // the pooled iterators are reused only with the same bounds
+        let pool = db.iterator_pool(1);
+        let scan = |read_opt: ReadOptions| {
+            let mut iter = pool.iter(read_opt);
+            let mut count = 0;
+            iter.seek_to_first();
+            while iter.valid() {
+                count += 1;
+                iter.next();
+            }
+            count
+        };
+        assert_eq!(scan(bounded()), 9);
+        assert_eq!(scan(ReadOptions::default()), 49);
+        assert_eq!(scan(bounded()), 9);
+        assert_eq!(pool.num_reused(), 0);
+        assert_eq!(scan(bounded()), 9);
+        assert_eq!(pool.num_reused(), 1);
+    }
+
+    // Allows up to `quota` keys written by each tenant and delays the writes of
+    // tenant "a:" by 100 micros
+    struct QuotaHook {
+        quota: usize,
+        written: Mutex<Vec<(Vec<u8>, usize, usize)>>,
+    }
+
+    impl PreWriteHook for QuotaHook {
+        fn name(&self) -> &str {
+            "QuotaHook"
+        }
+
+        fn before_write(&self, info: &WriteInfo) -> WriteDecision {
+            let mut written = self.written.lock().unwrap();
+            let pos = match written.iter().position(|w| w.0.as_slice() == info.tenant) {
+                Some(pos) => pos,
+                None => {
+                    written.push((info.tenant.to_vec(), 0, 0));
+                    written.len() - 1
+                }
+            };
+            let w = &mut written[pos];
+            if w.2 + info.num_keys > self.quota {
+                return WriteDecision::Reject(format!(
+                    "tenant {:?} is over quota",
+                    String::from_utf8_lossy(info.tenant)
+                ));
+            }
+            w.1 += info.batch_size;
+            w.2 += info.num_keys;
+            if info.tenant == b"a:" {
+                WriteDecision::Delay(100)
+            } else {
+                WriteDecision::Accept
+            }
+        }
+    }
+
+    #[test]
+    fn test_pre_write_hooks() {
+        let hook = Arc::new(QuotaHook {
+            quota: 3,
+            written: Mutex::new(vec![]),
+        });
+        let clock = Arc::new(MockClock::new(0));
+        let mut options = Options::default();
+        options.clock = clock.clone();
+        options.key_classifier = Some(Arc::new(FixedPrefixClassifier(2)));
+        options.pre_write_hooks = vec![hook.clone()];
+        let db = new_test_db_with_options("test_pre_write_hooks", options);
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"a:1", b"v");
+        batch.put(b"b:1", b"vv");
+        batch.delete(b"a:2");
+        db.write(WriteOptions::default(), batch).unwrap();
+        assert!(clock.now_micros() >= 100);
+        assert_eq!(
+            *hook.written.lock().unwrap(),
+            vec![(b"a:".to_vec(), 7, 2), (b"b:".to_vec(), 5, 1)]
+        );
+
+        // the whole batch is rejected if any tenant is over quota
+        let mut batch = WriteBatch::new();
+        batch.put(b"b:2", b"v");
+        batch.put(b"a:3", b"v");
+        batch.put(b"a:4", b"v");
+        let mut err = db.write(WriteOptions::default(), batch).unwrap_err();
+        assert_eq!(err.status(), Status::Busy);
+        assert_eq!(
+            err.take_raw().unwrap().to_string(),
+            "QuotaHook: tenant \"a:\" is over quota"
+        );
+        assert_eq!(
+            db.get(ReadOptions::default(), Slice::from("b:2")).unwrap(),
+            None
+        );
+        db.put(
+            WriteOptions::default(),
+            Slice::from("b:2"),
+            Slice::from("v"),
+        )
+        .unwrap();
+        assert_eq!(
+            db.get(ReadOptions::default(), Slice::from("b:2")).unwrap(),
+            Some(b"v".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_prefix_seek_skips_tables() {
+        let stats = Arc::new(Statistics::new());
+        let mut options = Options::default();
+        options.filter_policy = Some(Arc::new(BloomFilter::new(10)));
+        options.prefix_extractor = Some(Arc::new(FixedPrefixExtractor::new(3)));
+        options.statistics = Some(stats.clone());
+        // keep the flushed tables in level0
+        options.max_mem_compact_level = 0;
+        let db = new_test_db_with_options("test_prefix_seek_skips_tables", options);
+        let put_prefix = |prefix: &str| {
+            for i in 0..10 {
+                let key = format!("{}{:02}", prefix, i);
+                db.put(
+                    WriteOptions::default(),
+                    Slice::from(key.as_str()),
+                    Slice::from(key.as_str()),
+                )
+                .unwrap();
+            }
+        };
+        put_prefix("aa:");
+        put_prefix("cc:");
+        db.flush_memtable().unwrap();
+        put_prefix("bb:");
+        db.flush_memtable().unwrap();
+        let scan = |upper: &str, target: &str| {
+            let mut iter = db.iter(ReadOptions {
+                iterate_upper_bound: Some(upper.as_bytes().to_vec()),
+                ..ReadOptions::default()
+            });
+            let mut keys = vec![];
+            iter.seek(&Slice::from(target));
+            while iter.valid() {
+                keys.push(iter.key().as_str().to_owned());
+                iter.next();
+            }
+            assert!(iter.status().is_ok());
+            keys
+        };
+        let expected: Vec<String> = (3..10).map(|i| format!("bb:{:02}", i)).collect();
+
+        // the upper bound shares the prefix of the target
+        assert_eq!(scan("bb:99", "bb:03"), expected);
+        let skipped = stats.get_ticker_count(Ticker::PrefixSeekSkipped);
+        assert_eq!(skipped, 1);
+        // the upper bound is right after the prefix
+        assert_eq!(scan("bb;", "bb:03"), expected);
+        assert_eq!(stats.get_ticker_count(Ticker::PrefixSeekSkipped), 2);
+        // the bounded range covers other prefixes
+        let mut all = expected.clone();
+        all.extend((0..10).map(|i| format!("cc:{:02}", i)));
+        assert_eq!(scan("cd", "bb:03"), all);
+        assert_eq!(stats.get_ticker_count(Ticker::PrefixSeekSkipped), 2);
+
+        // moving backward after a skipped seek
+        let mut iter = db.iter(ReadOptions {
+            iterate_upper_bound: Some(b"bb;".to_vec()),
+            ..ReadOptions::default()
+        });
+        iter.seek(&Slice::from("bb:05"));
+        assert_eq!(iter.key().as_str(), "bb:05");
+        iter.prev();
+        assert_eq!(iter.key().as_str(), "bb:04");
+        iter.seek_to_last();
+        assert_eq!(iter.key().as_str(), "bb:09");
+        iter.prev();
+        assert_eq!(iter.key().as_str(), "bb:08");
+    }
+
+    #[test]
+    fn test_lock_lease_takeover() {
+        let clock = Arc::new(MockClock::new(1000));
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let name = "test_lock_lease_takeover".to_owned();
+        // opens without threads so that the lease is only renewed by hand
+        let open = |takeover: bool| {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.clock = clock.clone();
+            options.lock_lease_expiry_micros = 300;
+            WickDB::open_without_threads(options, name.clone(), takeover)
+        };
+        let db1 = open(false).unwrap();
+        let lease1 = LockLease::read(env.as_ref(), &name).unwrap().unwrap();
+        assert_eq!(Some(&lease1), db1.lock_lease.as_ref());
+        assert_eq!(lease1.renewed_at_micros, 1000);
+
+        // the lock is held by a fresh lease
+        assert!(open(false).is_err());
+        clock.advance(300);
+        assert!(open(true).is_err());
+        assert!(db1.renew_lock_lease().unwrap());
+        clock.advance(300);
+        assert!(open(true).is_err());
+
+        // the lease renewed at 1300 expires after 1600
+        clock.advance(1);
+        assert!(open(false).is_err());
+        let db2 = open(true).unwrap();
+        let lease2 = LockLease::read(env.as_ref(), &name).unwrap().unwrap();
+        assert_ne!(lease1.owner_id, lease2.owner_id);
+        assert_eq!(lease2.renewed_at_micros, 1601);
+
+        // the stale owner stops writing once it finds the lease taken over
+        assert!(db1.check_writable().is_ok());
+        assert!(!db1.renew_lock_lease().unwrap());
+        assert!(db1.check_writable().is_err());
+        assert!(db2.check_writable().is_ok());
+        assert!(db2.renew_lock_lease().unwrap());
+    }
+
+    #[test]
+    fn test_recover_huge_log_with_bounded_memtable() {
+        let env: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let new_options = |write_buffer_size: usize| {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.write_buffer_size = write_buffer_size;
+            options.max_mem_compact_level = 0;
+            options
+        };
+        let name = "test_recover_huge_log_with_bounded_memtable".to_owned();
+        let mut db = WickDB::open_db(new_options(64 << 20), name.clone()).unwrap();
+        let value = vec![b'v'; 1000];
+        for i in 0..1000 {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(value.as_slice()),
+            )
+            .unwrap();
+        }
+        db.close().unwrap();
+        drop(db);
+
+        // replaying the log of about 1MB flushes a memtable every 64KB
+        let db = WickDB::open_without_threads(new_options(64 << 10), name, false).unwrap();
+        let files = db
+            .versions
+            .lock()
+            .unwrap()
+            .current()
+            .get_level_files(0)
+            .to_vec();
+        assert!(files.len() >= 10, "{} files", files.len());
+        for i in (0..1000).step_by(99) {
+            let key = format!("key{:04}", i);
+            assert_eq!(
+                db.get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                    .unwrap(),
+                Some(value.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn test_tailing_iterator() {
+        let db = new_test_db("test_tailing_iterator");
+        let put = |key: &str| {
+            db.put(WriteOptions::default(), Slice::from(key), Slice::from(key))
+                .unwrap()
+        };
+        let scan_from = |iter: &mut dyn Iterator, start: &str| {
+            let mut keys = vec![];
+            iter.seek(&Slice::from(start));
+            while iter.valid() {
+                keys.push(String::from_utf8(iter.key().as_slice().to_vec()).unwrap());
+                iter.next();
+            }
+            iter.status().unwrap();
+            keys
+        };
+        put("a");
+        put("b");
+        let snapshot = db.snapshot();
+        let mut iter = db.iter(ReadOptions {
+            tailing: true,
+            snapshot: Some(snapshot.as_ref().clone()),
+            ..ReadOptions::default()
+        });
+        assert_eq!(scan_from(iter.as_mut(), "a"), vec!["a", "b"]);
+
+        // the new writes are seen by the next seek in spite of the snapshot
+        put("c");
+        db.delete(WriteOptions::default(), Slice::from("a"))
+            .unwrap();
+        assert_eq!(scan_from(iter.as_mut(), "b"), vec!["b", "c"]);
+
+        // and so are the writes flushed into the tables
+        put("d");
+        db.flush_memtable().unwrap();
+        put("e");
+        assert_eq!(scan_from(iter.as_mut(), "c"), vec!["c", "d", "e"]);
+        iter.seek_to_last();
+        assert_eq!(iter.key().as_slice(), b"e");
+        iter.seek_to_first();
+        assert_eq!(iter.key().as_slice(), b"b");
+    }
+
+    #[test]
+    fn test_sync_policies() {
+        let count_syncs = |mem: &MemStorage, file_type: FileType| {
+            mem.events()
+                .iter()
+                .filter(|e| match e {
+                    MemStorageEvent::Sync(name) => {
+                        let base = name.rsplit('/').next().unwrap();
+                        matches!(parse_filename(base), Some((t, _)) if t == file_type)
+                    }
+                    _ => false,
+                })
+                .count()
+        };
+        let open = |name: &str, wal: SyncPolicy, manifest: SyncPolicy, table: SyncPolicy| {
+            let mem = MemStorage::default();
+            let mut options = Options::default();
+            options.env = Arc::new(mem.clone());
+            options.wal_sync_policy = wal;
+            options.manifest_sync_policy = manifest;
+            options.table_sync_policy = table;
+            (WickDB::open_db(options, name.to_owned()).unwrap(), mem)
+        };
+
+        let (db, mem) = open(
+            "test_sync_policies_relaxed",
+            SyncPolicy::Never,
+            SyncPolicy::Always,
+            SyncPolicy::Never,
+        );
+        db.put(
+            WriteOptions {
+                sync: true,
+                ..WriteOptions::default()
+            },
+            Slice::from("k"),
+            Slice::from("v"),
+        )
+        .unwrap();
+        db.flush_memtable().unwrap();
+        assert_eq!(count_syncs(&mem, FileType::Log), 0);
+        assert_eq!(count_syncs(&mem, FileType::Table), 0);
+        // the MANIFEST is synced for both the creation and the flush
+        assert!(count_syncs(&mem, FileType::Manifest) >= 2);
+
+        let (db, mem) = open(
+            "test_sync_policies_strict",
+            SyncPolicy::Always,
+            SyncPolicy::Never,
+            SyncPolicy::Always,
+        );
+        db.put(WriteOptions::default(), Slice::from("k"), Slice::from("v"))
+            .unwrap();
+        assert_eq!(count_syncs(&mem, FileType::Log), 1);
+        db.flush_memtable().unwrap();
+        // a data block and the finished table
+        assert_eq!(count_syncs(&mem, FileType::Table), 2);
+        assert_eq!(count_syncs(&mem, FileType::Manifest), 0);
+        assert_eq!(count_syncs(&mem, FileType::Temp), 0);
+
+        let (mut db, mem) = open(
+            "test_sync_policies_on_close",
+            SyncPolicy::OnClose,
+            SyncPolicy::OnClose,
+            SyncPolicy::OnClose,
+        );
+        db.put(WriteOptions::default(), Slice::from("k"), Slice::from("v"))
+            .unwrap();
+        db.flush_memtable().unwrap();
+        assert_eq!(count_syncs(&mem, FileType::Table), 1);
+        let manifest_syncs = count_syncs(&mem, FileType::Manifest);
+        db.put(WriteOptions::default(), Slice::from("k2"), Slice::from("v"))
+            .unwrap();
+        let log_syncs = count_syncs(&mem, FileType::Log);
+        // the files are synced when the db is closed
+        db.close().unwrap();
+        assert_eq!(count_syncs(&mem, FileType::Log), log_syncs + 1);
+        assert_eq!(count_syncs(&mem, FileType::Manifest), manifest_syncs + 1);
+    }
+
+    #[test]
+    fn test_iterator_refresh() {
+        let db = new_test_db("test_iterator_refresh");
+        let put = |key: &str| {
+            db.put(WriteOptions::default(), Slice::from(key), Slice::from(key))
+                .unwrap()
+        };
+        for key in &["a", "b", "c", "d"] {
+            put(key);
+        }
+        db.flush_memtable().unwrap();
+        let mut iter = db.iter(ReadOptions::default());
+        iter.seek(&Slice::from("b"));
+        let old_version = db.inner.versions.lock().unwrap().current();
+
+        put("bb");
+        db.delete(WriteOptions::default(), Slice::from("c"))
+            .unwrap();
+        db.flush_memtable().unwrap();
+        assert!(!Arc::ptr_eq(
+            &old_version,
+            &db.inner.versions.lock().unwrap().current()
+        ));
+        // the iterator stays at "b" and sees the new writes
+        let holders = Arc::strong_count(&old_version);
+        iter.refresh().unwrap();
+        // and stops holding the old version
+        assert_eq!(Arc::strong_count(&old_version), holders - 1);
+        let mut keys = vec![];
+        while iter.valid() {
+            keys.push(iter.key().as_slice().to_vec());
+            iter.next();
+        }
+        iter.status().unwrap();
+        assert_eq!(keys, vec![b"b".to_vec(), b"bb".to_vec(), b"d".to_vec()]);
+
+        // an invalid iterator stays invalid
+        iter.refresh().unwrap();
+        assert!(!iter.valid());
+        iter.seek_to_first();
+        assert_eq!(iter.key().as_slice(), b"a");
+
+        let snapshot = db.snapshot();
+        let mut iter = db.iter(ReadOptions {
+            snapshot: Some(snapshot.as_ref().clone()),
+            ..ReadOptions::default()
+        });
+        assert_eq!(iter.refresh().unwrap_err().status(), Status::NotSupported);
+    }
+
+    #[test]
+    fn test_keys_only_and_value_size_only() {
+        let db = new_test_db("test_keys_only_and_value_size_only");
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(vec![b'v'; i].as_slice()),
+            )
+            .unwrap();
+        }
+        db.flush_memtable().unwrap();
+        for &(keys_only, value_size_only) in &[(true, false), (false, true)] {
+            let mut iter = db.iter(ReadOptions {
+                keys_only,
+                value_size_only,
+                ..ReadOptions::default()
+            });
+            // in both directions
+            let mut sizes = vec![];
+            iter.seek_to_first();
+            while iter.valid() {
+                assert!(iter.value().as_slice().is_empty());
+                sizes.push(iter.value_size());
+                iter.next();
+            }
+            iter.seek_to_last();
+            while iter.valid() {
+                assert!(iter.value().as_slice().is_empty());
+                sizes.push(iter.value_size());
+                iter.prev();
+            }
+            iter.status().unwrap();
+            let mut expected: Vec<usize> = (0..10).collect();
+            expected.extend((0..10).rev());
+            if keys_only {
+                expected = vec![0; 20];
+            }
+            assert_eq!(sizes, expected);
+        }
+    }
+
+    #[test]
+    fn test_get_approximate_sizes() {
+        let mut options = Options::default();
+        options.compression = CompressionType::NoCompression;
+        let db = new_test_db_with_options("test_get_approximate_sizes", options);
+        let value = vec![b'v'; 100];
+        for i in 0..1000 {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(value.as_slice()),
+            )
+            .unwrap();
+        }
+        db.flush_memtable().unwrap();
+        let ranges = [
+            Range::new(b"key0000", b"key0500"),
+            Range::new(b"key0500", b"key1000"),
+            Range::new(b"a", b"z"),
+            Range::new(b"x", b"z"),
+        ];
+        let sizes = db
+            .get_approximate_sizes(&ranges, SizeApproximationOptions::default())
+            .unwrap();
+        let total = sizes[2].on_disk_bytes;
+        assert!(total > 100_000, "{}", total);
+        for size in &sizes[..2] {
+            assert!(size.on_disk_bytes > total / 3 && size.on_disk_bytes < total * 2 / 3);
+            assert_eq!(size.live_bytes, size.on_disk_bytes);
+        }
+        assert_eq!(sizes[3], ApproximateSize::default());
+
+        for i in 0..500 {
+            let key = format!("key{:04}", i);
+            db.delete(WriteOptions::default(), Slice::from(key.as_bytes()))
+                .unwrap();
+        }
+        db.flush_memtable().unwrap();
+        let options = SizeApproximationOptions {
+            estimate_live_bytes: true,
+            ..SizeApproximationOptions::default()
+        };
+        let sizes = db.get_approximate_sizes(&ranges, options).unwrap();
+        // the deleted half has nothing live but still takes the disk
+        assert!(sizes[0].on_disk_bytes > total / 3);
+        assert!(
+            sizes[0].live_bytes < sizes[0].on_disk_bytes / 5,
+            "{:?}",
+            sizes[0]
+        );
+        assert_eq!(sizes[1].live_bytes, sizes[1].on_disk_bytes);
+        assert!(sizes[2].live_bytes < sizes[2].on_disk_bytes);
+        assert_eq!(sizes[2].memtable_bytes, 0);
+
+        // the memtables are counted on demand
+        for i in 0..100 {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(value.as_slice()),
+            )
+            .unwrap();
+        }
+        let options = SizeApproximationOptions {
+            include_memtables: true,
+            ..SizeApproximationOptions::default()
+        };
+        let sizes = db.get_approximate_sizes(&ranges, options).unwrap();
+        // 15 bytes for an internal key and 100 bytes for a value
+        assert_eq!(sizes[0].memtable_bytes, 100 * 115);
+        assert_eq!(sizes[1].memtable_bytes, 0);
+        assert_eq!(
+            sizes[0].total_bytes(),
+            sizes[0].on_disk_bytes + sizes[0].memtable_bytes
+        );
+    }
+
+    #[test]
+    fn test_erase_blocks_of_deleted_tables() {
+        let stats = Arc::new(Statistics::new());
+        let block_cache = Arc::new(SharedLRUCache::new(8 << 20));
+        let mut options = Options::default();
+        options.statistics = Some(stats.clone());
+        options.block_cache = Some(block_cache.clone());
+        options.max_mem_compact_level = 0;
+        let db = new_test_db_with_options("test_erase_blocks_of_deleted_tables", options);
+        let value = vec![b'v'; 100];
+        for i in 0..1000 {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(value.as_slice()),
+            )
+            .unwrap();
+        }
+        db.flush_memtable().unwrap();
+        assert_eq!(count_entries(db.iter(ReadOptions::default())), 1000);
+        assert!(block_cache.total_charge() > 50_000);
+
+        assert_eq!(db.delete_files_in_range(None, None).unwrap(), 1);
+        assert_eq!(block_cache.total_charge(), 0);
+        assert!(stats.get_ticker_count(Ticker::BlockCacheEraseObsolete) > 10);
+    }
+
+    #[test]
+    fn test_estimate_num_keys() {
+        let db = new_test_db("test_estimate_num_keys");
+        for i in 0..1000 {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(b"value".as_ref()),
+            )
+            .unwrap();
+        }
+        assert_eq!(db.estimate_num_keys().unwrap(), 1000);
+        db.flush_memtable().unwrap();
+        assert_eq!(db.estimate_num_keys().unwrap(), 1000);
+        for i in 0..100 {
+            let key = format!("key{:04}", i);
+            db.delete(WriteOptions::default(), Slice::from(key.as_bytes()))
+                .unwrap();
+        }
+        assert_eq!(db.estimate_num_keys().unwrap(), 900);
+        db.flush_memtable().unwrap();
+        // the deletions are counted from the properties block of the new table
+        assert_eq!(db.estimate_num_keys().unwrap(), 900);
+        assert_eq!(
+            db.get_property("wickdb.estimate-num-keys"),
+            Some("900".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_get_properties_of_all_tables() {
+        let mut options = Options::default();
+        options.clock = Arc::new(MockClock::new(1_600_000_000_000_000));
+        let db = new_test_db_with_options("test_get_properties_of_all_tables", options);
+        for i in 0..100 {
+            let key = format!("key{:04}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(b"value".as_ref()),
+            )
+            .unwrap();
+        }
+        db.delete(WriteOptions::default(), Slice::from("key0000"))
+            .unwrap();
+        db.flush_memtable().unwrap();
+        let tables = db.get_properties_of_all_tables().unwrap();
+        assert_eq!(tables.len(), 1);
+        let properties = &tables[0].1;
+        assert_eq!(properties.num_entries, 101);
+        assert_eq!(properties.num_deletions, 1);
+        assert_eq!(properties.raw_key_size, 101 * 7);
+        assert_eq!(properties.raw_value_size, 100 * 5);
+        assert_eq!(properties.num_data_blocks, 1);
+        assert_eq!(properties.comparator_name, "leveldb.BytewiseComparator");
+        assert_eq!(properties.creation_time, 1_600_000_000);
+        assert_eq!(properties.oldest_sequence, 1);
+        assert_eq!(properties.newest_sequence, 101);
+    }
+
+    #[test]
+    fn test_value_and_range_digests() {
+        let put_all = |db: &WickDB, n: usize| {
+            for i in 0..n {
+                let key = format!("key{:04}", i);
+                db.put(
+                    WriteOptions::default(),
+                    Slice::from(key.as_bytes()),
+                    Slice::from(key.as_bytes()),
+                )
+                .unwrap();
+            }
+        };
+        let db1 = new_test_db("test_value_and_range_digests1");
+        let db2 = new_test_db("test_value_and_range_digests2");
+        put_all(&db1, 100);
+        db1.flush_memtable().unwrap();
+        // the same entries laid out differently
+        put_all(&db2, 50);
+        put_all(&db2, 100);
+        assert_eq!(
+            db1.get_value_digest(ReadOptions::default(), Slice::from("key0001"))
+                .unwrap(),
+            Some(crc32::value(b"key0001"))
+        );
+        assert_eq!(
+            db1.get_value_digest(ReadOptions::default(), Slice::from("missing"))
+                .unwrap(),
+            None
+        );
+        let digest = |db: &WickDB, start: Option<&str>, end: Option<&str>| {
+            db.digest_range(
+                ReadOptions::default(),
+                start.map(Slice::from),
+                end.map(Slice::from),
+            )
+            .unwrap()
+        };
+        let all = digest(&db1, None, None);
+        assert_eq!(all.num_entries, 100);
+        assert_eq!(all, digest(&db2, None, None));
+        let half = digest(&db1, Some("key0010"), Some("key0060"));
+        assert_eq!(half.num_entries, 50);
+        assert_eq!(half, digest(&db2, Some("key0010"), Some("key0060")));
+        assert_ne!(half, all);
+
+        db2.put(
+            WriteOptions::default(),
+            Slice::from("key0020"),
+            Slice::from("changed"),
+        )
+        .unwrap();
+        assert_ne!(half, digest(&db2, Some("key0010"), Some("key0060")));
+        assert_eq!(
+            digest(&db1, Some("key0060"), None),
+            digest(&db2, Some("key0060"), None)
+        );
+    }
+
+    #[derive(Default)]
+    struct VersionsPerKeyListener {
+        versions_per_key: Mutex<Histogram>,
+        dropped_versions: AtomicU64,
+    }
+
+    impl EventListener for VersionsPerKeyListener {
+        fn on_compaction_completed(&self, info: &CompactionJobInfo) {
+            self.versions_per_key
+                .lock()
+                .unwrap()
+                .merge(&info.versions_per_key);
+            self.dropped_versions
+                .fetch_add(info.dropped_versions, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_compaction_versions_per_key() {
+        let listener = Arc::new(VersionsPerKeyListener::default());
+        let stats = Arc::new(Statistics::new());
+        let mut options = Options::default();
+        options.listeners.push(listener.clone());
+        options.statistics = Some(stats.clone());
+        let db = new_test_db_with_options("test_compaction_versions_per_key", options);
+        for round in 0..8 {
+            for i in 0..100 {
+                let key = format!("key{:04}", i);
+                let value = format!("value{}", round);
+                db.put(
+                    WriteOptions::default(),
+                    Slice::from(key.as_bytes()),
+                    Slice::from(value.as_bytes()),
+                )
+                .unwrap();
+            }
+            db.flush_memtable().unwrap();
+        }
+        wait_and_count_entries(&db);
+        let versions_per_key = listener.versions_per_key.lock().unwrap().clone();
+        let dropped_versions = listener.dropped_versions.load(Ordering::Relaxed);
+        assert!(versions_per_key.count() > 0);
+        assert!(versions_per_key.max() > 1.0);
+        // every version but the newest one of a key is dropped
+        assert_eq!(
+            versions_per_key.sum() as u64 - versions_per_key.count(),
+            dropped_versions
+        );
+        assert_eq!(
+            stats.get_ticker_count(Ticker::CompactionVersionsDropped),
+            dropped_versions
+        );
+        let recorded = stats.histogram_data(HistogramType::CompactionVersionsPerKey);
+        assert_eq!(recorded.count(), versions_per_key.count());
+        assert_eq!(recorded.sum(), versions_per_key.sum());
+    }
+}
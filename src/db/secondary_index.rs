@@ -0,0 +1,411 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::batch::WriteBatch;
+use crate::compaction::{CompactionDecision, CompactionFilter};
+use crate::db::{WickDB, DB};
+use crate::options::{Options, ReadOptions, WriteOptions};
+use crate::util::clock::Clock;
+use crate::util::coding::{decode_fixed_32, decode_fixed_64, put_fixed_32, put_fixed_64};
+use crate::util::slice::Slice;
+use crate::util::status::{Result, Status, WickErr};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// The first byte of the keys of the records
+const RECORD_TAG: u8 = b'r';
+// The first byte of the keys of the index entries
+const INDEX_TAG: u8 = b'i';
+// The size of the expiration time heading the values
+const EXPIRATION_SIZE: usize = 8;
+
+/// `IndexExtractor` gives the index terms of a record in a `SecondaryIndexDB`,
+/// e.g. the values of some fields of the record.
+///
+/// The terms of a record must be decided by the record alone since the index
+/// entries of an old record are found by extracting its terms again.
+pub trait IndexExtractor: Send + Sync {
+    /// The name of the extractor
+    fn name(&self) -> &str;
+
+    /// Returns the index terms of the record
+    fn terms(&self, key: &[u8], value: &[u8]) -> Vec<Vec<u8>>;
+}
+
+/// The `CompactionFilter` installed by `SecondaryIndexDB::open`, which removes the
+/// expired records and index entries and passes the live records to the filter
+/// set in the `Options` if any.
+pub struct IndexCompactionFilter {
+    clock: Arc<dyn Clock>,
+    inner: Option<Arc<dyn CompactionFilter>>,
+}
+
+impl CompactionFilter for IndexCompactionFilter {
+    fn name(&self) -> &str {
+        "wickdb.IndexCompactionFilter"
+    }
+
+    fn filter(&self, level: usize, key: &[u8], value: &[u8]) -> CompactionDecision {
+        match key.first() {
+            Some(&RECORD_TAG) | Some(&INDEX_TAG) if value.len() >= EXPIRATION_SIZE => {
+                if is_expired(decode_fixed_64(value), self.clock.now_micros()) {
+                    return CompactionDecision::Remove;
+                }
+            }
+            _ => return CompactionDecision::Keep,
+        }
+        match &self.inner {
+            Some(inner) if key[0] == RECORD_TAG => {
+                match inner.filter(level, &key[1..], &value[EXPIRATION_SIZE..]) {
+                    CompactionDecision::ChangeValue(v) => {
+                        CompactionDecision::ChangeValue(encode_record(decode_fixed_64(value), &v))
+                    }
+                    decision => decision,
+                }
+            }
+            _ => CompactionDecision::Keep,
+        }
+    }
+}
+
+/// A db maintaining an inverted index of its records, so that the records could
+/// be looked up by the prefixes of their index terms without an external
+/// indexing layer.
+///
+/// The terms of a record are given by an `IndexExtractor`. Every index entry is a
+/// key in the index keyspace of the db made of a term and the key of the record,
+/// written in the same `WriteBatch` as the record so that both are always updated
+/// atomically. A record may be given a TTL, after which the record and its index
+/// entries are hidden from the reads and removed by the compactions through the
+/// `IndexCompactionFilter`.
+///
+/// The keyspaces are distinguished by a leading byte, so the underlying `WickDB`
+/// should only be written through a `SecondaryIndexDB`.
+pub struct SecondaryIndexDB {
+    db: WickDB,
+    extractor: Arc<dyn IndexExtractor>,
+    clock: Arc<dyn Clock>,
+    // Serializes the writes which read the old record to clean up its index entries
+    write_lock: Mutex<()>,
+}
+
+impl SecondaryIndexDB {
+    /// Opens the db at `db_name` with the `IndexCompactionFilter` wrapping the
+    /// `Options::compaction_filter`
+    pub fn open(
+        mut options: Options,
+        db_name: String,
+        extractor: Arc<dyn IndexExtractor>,
+    ) -> Result<Self> {
+        let clock = options.clock.clone();
+        options.compaction_filter = Some(Arc::new(IndexCompactionFilter {
+            clock: clock.clone(),
+            inner: options.compaction_filter.take(),
+        }));
+        Ok(Self {
+            db: WickDB::open_db(options, db_name)?,
+            extractor,
+            clock,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    /// Returns the underlying `WickDB`
+    pub fn db(&self) -> &WickDB {
+        &self.db
+    }
+
+    /// Sets the value for the given key and replaces the index entries of the old
+    /// value with the ones of the new value in one `WriteBatch`. The record expires
+    /// after `ttl` if given.
+    pub fn put(
+        &self,
+        write_opt: WriteOptions,
+        key: &[u8],
+        value: &[u8],
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        let expiration = match ttl {
+            Some(ttl) => self.clock.now_micros() + (ttl.as_micros() as u64).max(1),
+            None => 0,
+        };
+        let terms = self.extractor.terms(key, value);
+        let _lock = self.write_lock.lock().unwrap();
+        let mut batch = WriteBatch::new();
+        if let Some(old) = self.get(ReadOptions::default(), key)? {
+            for term in self.extractor.terms(key, &old) {
+                if !terms.contains(&term) {
+                    batch.delete(&index_key(&term, key));
+                }
+            }
+        }
+        batch.put(&record_key(key), &encode_record(expiration, value));
+        let mut entry = vec![];
+        put_fixed_64(&mut entry, expiration);
+        for term in terms.iter() {
+            batch.put(&index_key(term, key), &entry);
+        }
+        self.db.write(write_opt, batch)
+    }
+
+    /// Gets the value for the given key, or `None` if the record is expired
+    pub fn get(&self, read_opt: ReadOptions, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let record = self
+            .db
+            .get(read_opt, Slice::from(record_key(key).as_slice()))?;
+        match record {
+            Some(record) => self.decode_record(record),
+            None => Ok(None),
+        }
+    }
+
+    /// Deletes the given key together with its index entries
+    pub fn delete(&self, write_opt: WriteOptions, key: &[u8]) -> Result<()> {
+        let _lock = self.write_lock.lock().unwrap();
+        let mut batch = WriteBatch::new();
+        if let Some(old) = self.get(ReadOptions::default(), key)? {
+            for term in self.extractor.terms(key, &old) {
+                batch.delete(&index_key(&term, key));
+            }
+        }
+        batch.delete(&record_key(key));
+        self.db.write(write_opt, batch)
+    }
+
+    /// Returns the keys and the values of the records with any index term starting
+    /// with `prefix`, in order of the terms and then the keys. A record is returned
+    /// once even if several of its terms match. The index entries and the records
+    /// are read from the same snapshot, which is taken if `read_opt` has none.
+    pub fn query_index(
+        &self,
+        mut read_opt: ReadOptions,
+        prefix: &[u8],
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let _snapshot = if read_opt.snapshot.is_none() {
+            let snapshot = self.db.snapshot();
+            read_opt.snapshot = Some(snapshot.as_ref().clone());
+            Some(snapshot)
+        } else {
+            None
+        };
+        let mut start = vec![INDEX_TAG];
+        start.extend_from_slice(prefix);
+        let mut iter = self.db.iter(read_opt.clone());
+        iter.seek(&Slice::from(start.as_slice()));
+        let mut seen = HashSet::new();
+        let mut records = vec![];
+        while iter.valid() && iter.key().as_slice().starts_with(&start) {
+            let entry_key = iter.key();
+            let (term, key) = decode_index_key(entry_key.as_slice())?;
+            if !seen.contains(key) {
+                // An entry left by a concurrent write is checked against the record
+                if let Some(value) = self.get(read_opt.clone(), key)? {
+                    if self.extractor.terms(key, &value).iter().any(|t| t == term) {
+                        seen.insert(key.to_vec());
+                        records.push((key.to_vec(), value));
+                    }
+                }
+            }
+            iter.next();
+        }
+        iter.status()?;
+        Ok(records)
+    }
+
+    /// Closes the underlying `WickDB`
+    pub fn close(&mut self) -> Result<()> {
+        self.db.close()
+    }
+
+    // Strips the expiration time of a record, or returns `None` if it's expired
+    fn decode_record(&self, record: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if record.len() < EXPIRATION_SIZE {
+            return Err(WickErr::new(Status::Corruption, Some("bad indexed record")));
+        }
+        if is_expired(decode_fixed_64(&record), self.clock.now_micros()) {
+            return Ok(None);
+        }
+        Ok(Some(record[EXPIRATION_SIZE..].to_vec()))
+    }
+}
+
+#[inline]
+fn is_expired(expiration: u64, now_micros: u64) -> bool {
+    expiration != 0 && expiration <= now_micros
+}
+
+fn record_key(key: &[u8]) -> Vec<u8> {
+    let mut k = Vec::with_capacity(key.len() + 1);
+    k.push(RECORD_TAG);
+    k.extend_from_slice(key);
+    k
+}
+
+fn encode_record(expiration: u64, value: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(EXPIRATION_SIZE + value.len());
+    put_fixed_64(&mut record, expiration);
+    record.extend_from_slice(value);
+    record
+}
+
+// The key of an index entry is the term followed by the key of the record and
+// the fixed 32-bit length of the term, so the entries are ordered by the terms
+fn index_key(term: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut k = Vec::with_capacity(term.len() + key.len() + 5);
+    k.push(INDEX_TAG);
+    k.extend_from_slice(term);
+    k.extend_from_slice(key);
+    put_fixed_32(&mut k, term.len() as u32);
+    k
+}
+
+// Returns the term and the key of the record of an index entry
+fn decode_index_key(k: &[u8]) -> Result<(&[u8], &[u8])> {
+    let corrupted = || WickErr::new(Status::Corruption, Some("bad index entry"));
+    if k.len() < 5 {
+        return Err(corrupted());
+    }
+    let body = &k[1..k.len() - 4];
+    let term_len = decode_fixed_32(&k[k.len() - 4..]) as usize;
+    if term_len > body.len() {
+        return Err(corrupted());
+    }
+    Ok(body.split_at(term_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem::MemStorage;
+    use crate::util::clock::MockClock;
+
+    // Indexes the records by the comma separated fields of the values
+    struct FieldsExtractor;
+
+    impl IndexExtractor for FieldsExtractor {
+        fn name(&self) -> &str {
+            "FieldsExtractor"
+        }
+
+        fn terms(&self, _key: &[u8], value: &[u8]) -> Vec<Vec<u8>> {
+            value.split(|b| *b == b',').map(|t| t.to_vec()).collect()
+        }
+    }
+
+    fn keys(records: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<String> {
+        records
+            .into_iter()
+            .map(|(k, _)| String::from_utf8(k).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_index_key() {
+        let k = index_key(b"color:red", b"apple");
+        assert_eq!(
+            decode_index_key(&k).unwrap(),
+            (&b"color:red"[..], &b"apple"[..])
+        );
+        let k = index_key(b"", b"");
+        assert_eq!(decode_index_key(&k).unwrap(), (&b""[..], &b""[..]));
+        assert!(decode_index_key(b"i\x09\x00\x00\x00").is_err());
+    }
+
+    #[test]
+    fn test_query_index() {
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let mut options = Options::default();
+        options.env = Arc::new(MemStorage::default());
+        options.clock = clock.clone();
+        let db =
+            SecondaryIndexDB::open(options, "index".to_owned(), Arc::new(FieldsExtractor)).unwrap();
+        let w = WriteOptions::default;
+        db.put(w(), b"apple", b"color:red,shape:round", None)
+            .unwrap();
+        db.put(w(), b"banana", b"color:yellow,shape:long", None)
+            .unwrap();
+        db.put(w(), b"cherry", b"color:red,shape:round", None)
+            .unwrap();
+        let r = ReadOptions::default;
+        assert_eq!(
+            keys(db.query_index(r(), b"color:red").unwrap()),
+            vec!["apple", "cherry"]
+        );
+        assert_eq!(
+            keys(db.query_index(r(), b"color:").unwrap()),
+            vec!["apple", "cherry", "banana"]
+        );
+        // A record matching several terms is returned once
+        assert_eq!(keys(db.query_index(r(), b"").unwrap()).len(), 3);
+        assert!(db.query_index(r(), b"size:").unwrap().is_empty());
+
+        // The entries of the old terms are removed by the update
+        db.put(w(), b"apple", b"color:green,shape:round", None)
+            .unwrap();
+        assert_eq!(
+            keys(db.query_index(r(), b"color:red").unwrap()),
+            vec!["cherry"]
+        );
+        assert_eq!(
+            db.query_index(r(), b"color:green").unwrap(),
+            vec![(b"apple".to_vec(), b"color:green,shape:round".to_vec())]
+        );
+        db.delete(w(), b"cherry").unwrap();
+        assert!(db.query_index(r(), b"color:red").unwrap().is_empty());
+        assert_eq!(db.get(r(), b"cherry").unwrap(), None);
+
+        // The expired records are hidden
+        db.put(w(), b"kiwi", b"color:green", Some(Duration::from_secs(10)))
+            .unwrap();
+        assert_eq!(
+            keys(db.query_index(r(), b"color:green").unwrap()),
+            vec!["apple", "kiwi"]
+        );
+        clock.advance(10_000_000);
+        assert_eq!(
+            keys(db.query_index(r(), b"color:green").unwrap()),
+            vec!["apple"]
+        );
+        assert_eq!(db.get(r(), b"kiwi").unwrap(), None);
+    }
+
+    #[test]
+    fn test_index_compaction_filter() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let filter = IndexCompactionFilter {
+            clock: clock.clone(),
+            inner: None,
+        };
+        let live = encode_record(2_000, b"v");
+        let forever = encode_record(0, b"v");
+        let record = record_key(b"k");
+        let entry = index_key(b"t", b"k");
+        assert_eq!(filter.filter(1, &record, &live), CompactionDecision::Keep);
+        assert_eq!(
+            filter.filter(1, &entry, &live[..8]),
+            CompactionDecision::Keep
+        );
+        clock.set(2_000);
+        assert_eq!(filter.filter(1, &record, &live), CompactionDecision::Remove);
+        assert_eq!(
+            filter.filter(1, &entry, &live[..8]),
+            CompactionDecision::Remove
+        );
+        assert_eq!(
+            filter.filter(1, &record, &forever),
+            CompactionDecision::Keep
+        );
+        assert_eq!(filter.filter(1, b"other", b""), CompactionDecision::Keep);
+    }
+}
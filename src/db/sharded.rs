@@ -0,0 +1,530 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::batch::WriteBatch;
+use crate::db::{ApproximateSize, Range, WickDB, DB};
+use crate::iterator::{EmptyIterator, Iterator, MergingIterator};
+use crate::options::{Options, ReadOptions, SizeApproximationOptions, WriteOptions};
+use crate::statistics::StatisticsSnapshot;
+use crate::storage::do_write_string_to_file;
+use crate::util::hash::hash;
+use crate::util::slice::Slice;
+use crate::util::status::{Result, Status, WickErr};
+use std::sync::Arc;
+
+// The seed of the hash used by `HashSharding`
+const SHARD_HASH_SEED: u32 = 0x5bd1e995;
+
+/// `ShardFunction` decides which shard of a `ShardedDB` a user key belongs to.
+///
+/// A key must always be mapped to the same shard, so the function and the number
+/// of the shards can never be changed once a `ShardedDB` is created. The name and
+/// the number are saved in the `SHARDING` file of the db and checked at opening.
+pub trait ShardFunction: Send + Sync {
+    /// Returns the name of the function
+    fn name(&self) -> &str;
+
+    /// Returns the shard of `key` in `[0, num_shards)`
+    fn shard(&self, key: &[u8], num_shards: usize) -> usize;
+}
+
+/// Spreads the keys across the shards evenly by their hashes. The keys adjacent
+/// to each other are usually in different shards, so every shard takes part in
+/// a range scan.
+#[derive(Default)]
+pub struct HashSharding;
+
+impl ShardFunction for HashSharding {
+    fn name(&self) -> &str {
+        "wickdb.HashSharding"
+    }
+
+    fn shard(&self, key: &[u8], num_shards: usize) -> usize {
+        hash(key, SHARD_HASH_SEED) as usize % num_shards
+    }
+}
+
+/// Splits the key space by the sorted `boundaries` compared bytewise. Shard `i`
+/// holds the keys in `[boundaries[i - 1], boundaries[i])`, so `boundaries.len() + 1`
+/// shards are expected and a range scan only touches the shards overlapping it.
+pub struct RangeSharding {
+    boundaries: Vec<Vec<u8>>,
+}
+
+impl RangeSharding {
+    pub fn new(boundaries: Vec<Vec<u8>>) -> Self {
+        assert!(
+            boundaries.windows(2).all(|w| w[0] < w[1]),
+            "[sharding] the boundaries must be sorted without duplicates"
+        );
+        Self { boundaries }
+    }
+
+    /// Returns the number of the shards the boundaries split the key space into
+    pub fn num_shards(&self) -> usize {
+        self.boundaries.len() + 1
+    }
+}
+
+impl ShardFunction for RangeSharding {
+    fn name(&self) -> &str {
+        "wickdb.RangeSharding"
+    }
+
+    fn shard(&self, key: &[u8], num_shards: usize) -> usize {
+        let i = self
+            .boundaries
+            .iter()
+            .take_while(|b| b.as_slice() <= key)
+            .count();
+        i.min(num_shards - 1)
+    }
+}
+
+/// A db partitioning the keys across several `WickDB`s in one process by a
+/// `ShardFunction`, so the writes and the compactions of the shards run in
+/// parallel on their own write queues and background threads. Every shard is a
+/// complete db in the `shard-<N>` directory under the db, which may be given its
+/// own `Options`, e.g. a storage on a different disk.
+///
+/// The reads and the writes of a key go to its shard, and the iterators merge
+/// all the shards. A `WriteBatch` is split by the shards and each part is
+/// written atomically, but the batch as a whole is not, and a `Snapshot` of a
+/// shard can't be used for the whole db.
+pub struct ShardedDB {
+    db_name: String,
+    shards: Vec<WickDB>,
+    sharding: Arc<dyn ShardFunction>,
+}
+
+impl ShardedDB {
+    /// Opens the db at `db_name` with `num_shards` shards, where the `Options` of
+    /// shard `i` is given by `options(i)`. The `Options::env` of shard 0 keeps the
+    /// `SHARDING` file.
+    pub fn open(
+        db_name: String,
+        num_shards: usize,
+        sharding: Arc<dyn ShardFunction>,
+        mut options: impl FnMut(usize) -> Options,
+    ) -> Result<Self> {
+        if num_shards == 0 {
+            return Err(WickErr::new(
+                Status::InvalidArgument,
+                Some("a sharded db needs at least one shard"),
+            ));
+        }
+        let mut shards = Vec::with_capacity(num_shards);
+        for i in 0..num_shards {
+            let opts = options(i);
+            if i == 0 {
+                Self::check_sharding(&opts, &db_name, num_shards, sharding.as_ref())?;
+            }
+            shards.push(WickDB::open_db(opts, format!("{}/shard-{}", db_name, i))?);
+        }
+        Ok(Self {
+            db_name,
+            shards,
+            sharding,
+        })
+    }
+
+    // Saves the sharding of a new db or checks it against the one of an existing db
+    fn check_sharding(
+        options: &Options,
+        db_name: &str,
+        num_shards: usize,
+        sharding: &dyn ShardFunction,
+    ) -> Result<()> {
+        let env = options.env.clone();
+        let name = format!("{}/SHARDING", db_name);
+        let expected = format!("{}\n{}\n", sharding.name(), num_shards);
+        if !env.exists(name.as_str()) {
+            env.mkdir_all(db_name)?;
+            return do_write_string_to_file(env, expected, name.as_str(), true);
+        }
+        let mut file = env.open(name.as_str())?;
+        let mut buf = vec![];
+        file.read_all(&mut buf)?;
+        if buf != expected.as_bytes() {
+            return Err(WickErr::new_from_raw(
+                Status::InvalidArgument,
+                Some("the sharding mismatches the existing db"),
+                format!(
+                    "{} has {:?} but {:?} is given",
+                    name,
+                    String::from_utf8_lossy(&buf),
+                    expected
+                )
+                .into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the number of the shards
+    #[inline]
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the shard `i` for the operations not covered by `ShardedDB`
+    #[inline]
+    pub fn shard(&self, i: usize) -> &WickDB {
+        &self.shards[i]
+    }
+
+    /// Returns the index of the shard `key` belongs to
+    #[inline]
+    pub fn shard_of(&self, key: &[u8]) -> usize {
+        self.sharding.shard(key, self.shards.len())
+    }
+
+    /// Sets the value for the given key in its shard
+    pub fn put(&self, write_opt: WriteOptions, key: Slice, value: Slice) -> Result<()> {
+        self.shards[self.shard_of(key.as_slice())].put(write_opt, key, value)
+    }
+
+    /// Gets the value for the given key from its shard
+    pub fn get(&self, read_opt: ReadOptions, key: Slice) -> Result<Option<Vec<u8>>> {
+        Self::check_read_options(&read_opt)?;
+        self.shards[self.shard_of(key.as_slice())].get(read_opt, key)
+    }
+
+    /// Gets the values for all the given keys by one `multi_get` on each shard
+    /// involved. The values are in the same order as the `keys`.
+    pub fn multi_get(&self, read_opt: ReadOptions, keys: &[Slice]) -> Result<Vec<Option<Vec<u8>>>> {
+        Self::check_read_options(&read_opt)?;
+        let mut shard_keys = vec![vec![]; self.shards.len()];
+        let mut positions = vec![vec![]; self.shards.len()];
+        for (pos, key) in keys.iter().enumerate() {
+            let i = self.shard_of(key.as_slice());
+            shard_keys[i].push(key.clone());
+            positions[i].push(pos);
+        }
+        let mut values = vec![None; keys.len()];
+        for (i, shard) in self.shards.iter().enumerate() {
+            if shard_keys[i].is_empty() {
+                continue;
+            }
+            let shard_values = shard.multi_get(read_opt.clone(), &shard_keys[i])?;
+            for (pos, value) in positions[i].iter().zip(shard_values) {
+                values[*pos] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Deletes the given key in its shard
+    pub fn delete(&self, write_opt: WriteOptions, key: Slice) -> Result<()> {
+        self.shards[self.shard_of(key.as_slice())].delete(write_opt, key)
+    }
+
+    /// Splits `batch` by the shards and writes each part to its shard in order of
+    /// the shards. Each part is applied atomically but the whole batch is not: the
+    /// parts written before a failure are kept.
+    pub fn write(&self, write_opt: WriteOptions, batch: WriteBatch) -> Result<()> {
+        let batches = batch.partition(self.shards.len(), |key| self.shard_of(key))?;
+        let (sync, disable_wal) = (write_opt.sync, write_opt.disable_wal);
+        let mut write_opt = Some(write_opt);
+        for (shard, batch) in self.shards.iter().zip(batches) {
+            if batch.is_empty() {
+                continue;
+            }
+            let opt = write_opt
+                .take()
+                .unwrap_or(WriteOptions { sync, disable_wal });
+            shard.write(opt, batch)?;
+        }
+        Ok(())
+    }
+
+    /// Returns an iterator merging all the shards in order of the comparator of
+    /// shard 0, which all the shards must share
+    pub fn iter(&self, read_opt: ReadOptions) -> Box<dyn Iterator> {
+        if let Err(e) = Self::check_read_options(&read_opt) {
+            return Box::new(EmptyIterator::new_with_err(e));
+        }
+        let iters = self
+            .shards
+            .iter()
+            .map(|shard| shard.iter(read_opt.clone()))
+            .collect();
+        Box::new(MergingIterator::from_iters(
+            self.shards[0].comparator(),
+            iters,
+        ))
+    }
+
+    /// Returns the sum of `DB::estimate_keys_in_range` of all the shards
+    pub fn estimate_keys_in_range(&self, start: Option<Slice>, end: Option<Slice>) -> Result<u64> {
+        let mut total = 0;
+        for shard in self.shards.iter() {
+            total += shard.estimate_keys_in_range(start.clone(), end.clone())?;
+        }
+        Ok(total)
+    }
+
+    /// Returns the sum of `WickDB::get_approximate_sizes` of all the shards
+    pub fn get_approximate_sizes(
+        &self,
+        ranges: &[Range],
+        options: SizeApproximationOptions,
+    ) -> Result<Vec<ApproximateSize>> {
+        let mut sizes = vec![ApproximateSize::default(); ranges.len()];
+        for shard in self.shards.iter() {
+            for (size, s) in sizes
+                .iter_mut()
+                .zip(shard.get_approximate_sizes(ranges, options)?)
+            {
+                size.on_disk_bytes += s.on_disk_bytes;
+                size.live_bytes += s.live_bytes;
+                size.memtable_bytes += s.memtable_bytes;
+            }
+        }
+        Ok(sizes)
+    }
+
+    /// Returns the sum of the property of all the shards if it's a number, or the
+    /// values of the shards each headed by `shard <N>:` otherwise. Returns `None`
+    /// if the `property` is not understood.
+    pub fn get_property(&self, property: &str) -> Option<String> {
+        let values = self
+            .shards
+            .iter()
+            .map(|shard| shard.get_property(property))
+            .collect::<Option<Vec<_>>>()?;
+        let numbers = values
+            .iter()
+            .map(|v| v.parse::<u64>())
+            .collect::<std::result::Result<Vec<_>, _>>();
+        match numbers {
+            Ok(numbers) => Some(numbers.iter().sum::<u64>().to_string()),
+            Err(_) => Some(
+                values
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| format!("shard {}:\n{}", i, v))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+        }
+    }
+
+    /// Returns the sum of the `Options::statistics` of the shards, or `None` if
+    /// none of them collects the statistics. A `Statistics` shared by several
+    /// shards is counted once.
+    pub fn statistics(&self) -> Option<StatisticsSnapshot> {
+        let mut seen = vec![];
+        let mut total: Option<StatisticsSnapshot> = None;
+        for shard in self.shards.iter() {
+            if let Some(stats) = shard.inner.options.statistics.as_ref() {
+                if seen.iter().any(|s| Arc::ptr_eq(s, stats)) {
+                    continue;
+                }
+                seen.push(stats.clone());
+                match total.as_mut() {
+                    Some(t) => t.merge(&stats.snapshot()),
+                    None => total = Some(stats.snapshot()),
+                }
+            }
+        }
+        total
+    }
+
+    /// Closes all the shards. Every shard is closed even if some fail, and the
+    /// first error is returned.
+    pub fn close(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for shard in self.shards.iter_mut() {
+            let r = shard.close();
+            if result.is_ok() {
+                result = r;
+            }
+        }
+        result
+    }
+
+    /// Destroys all the shards and removes the db directory
+    pub fn destroy(&mut self) -> Result<()> {
+        for shard in self.shards.iter_mut() {
+            shard.destroy()?;
+        }
+        self.shards[0]
+            .inner
+            .options
+            .env
+            .remove_dir(self.db_name.as_str(), true)
+    }
+
+    // A snapshot belongs to one of the shards and can't be used for the whole db
+    fn check_read_options(read_opt: &ReadOptions) -> Result<()> {
+        if read_opt.snapshot.is_some() {
+            return Err(WickErr::new(
+                Status::InvalidArgument,
+                Some("a snapshot is not supported by a sharded db"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::statistics::{Statistics, Ticker};
+    use crate::storage::mem::MemStorage;
+    use crate::storage::Storage;
+
+    fn open(
+        env: &Arc<MemStorage>,
+        num_shards: usize,
+        sharding: Arc<dyn ShardFunction>,
+        stats: Option<Arc<Statistics>>,
+    ) -> Result<ShardedDB> {
+        ShardedDB::open("sharded".to_owned(), num_shards, sharding, |_| {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.statistics = stats.clone();
+            options
+        })
+    }
+
+    fn key(i: usize) -> String {
+        format!("key{:04}", i)
+    }
+
+    #[test]
+    fn test_hash_sharding() {
+        let env = Arc::new(MemStorage::default());
+        let stats = Arc::new(Statistics::new());
+        let mut db = open(&env, 4, Arc::new(HashSharding), Some(stats.clone())).unwrap();
+        for i in 0..100 {
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key(i).as_str()),
+                Slice::from(key(i).as_str()),
+            )
+            .unwrap();
+        }
+        // every shard gets some of the keys
+        for i in 0..4 {
+            let mut iter = db.shard(i).iter(ReadOptions::default());
+            iter.seek_to_first();
+            assert!(iter.valid());
+        }
+        let mut batch = WriteBatch::new();
+        for i in 0..50 {
+            batch.delete(key(i).as_bytes());
+        }
+        batch.put(b"key0000", b"again");
+        db.write(WriteOptions::default(), batch).unwrap();
+
+        let mut iter = db.iter(ReadOptions::default());
+        iter.seek_to_first();
+        let mut keys = vec![];
+        while iter.valid() {
+            keys.push(String::from_utf8(iter.key().as_slice().to_vec()).unwrap());
+            iter.next();
+        }
+        let mut expected = vec![key(0)];
+        expected.extend((50..100).map(key));
+        assert_eq!(keys, expected);
+
+        let keys = [
+            Slice::from("key0000"),
+            Slice::from("key0001"),
+            Slice::from("key0099"),
+        ];
+        assert_eq!(
+            db.multi_get(ReadOptions::default(), &keys).unwrap(),
+            vec![Some(b"again".to_vec()), None, Some(b"key0099".to_vec())]
+        );
+        assert_eq!(
+            db.get(ReadOptions::default(), Slice::from("key0050"))
+                .unwrap(),
+            Some(b"key0050".to_vec())
+        );
+        let read_opt = ReadOptions {
+            snapshot: Some(db.shard(0).snapshot().as_ref().clone()),
+            ..ReadOptions::default()
+        };
+        assert_eq!(
+            db.get(read_opt, Slice::from("key0050"))
+                .unwrap_err()
+                .status(),
+            Status::InvalidArgument
+        );
+
+        // the statistics shared by all the shards are counted once
+        for i in 0..4 {
+            db.shard(i).flush_memtable().unwrap();
+        }
+        let snapshot = db.statistics().unwrap();
+        assert!(snapshot.get_ticker_count(Ticker::FlushBytesWritten) > 0);
+        assert_eq!(snapshot.to_string(), stats.to_string());
+        assert_eq!(
+            db.get_property("wickdb.num-running-compactions"),
+            Some("0".to_owned())
+        );
+        assert!(db
+            .get_property("wickdb.stats")
+            .unwrap()
+            .contains("shard 3:"));
+        db.close().unwrap();
+
+        // the sharding can't be changed
+        assert_eq!(
+            open(&env, 3, Arc::new(HashSharding), None)
+                .err()
+                .unwrap()
+                .status(),
+            Status::InvalidArgument
+        );
+        let db = open(&env, 4, Arc::new(HashSharding), None).unwrap();
+        assert_eq!(
+            db.get(ReadOptions::default(), Slice::from("key0099"))
+                .unwrap(),
+            Some(b"key0099".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_range_sharding() {
+        let env = Arc::new(MemStorage::default());
+        let sharding = RangeSharding::new(vec![b"b".to_vec(), b"d".to_vec()]);
+        assert_eq!(sharding.num_shards(), 3);
+        let mut db = open(&env, 3, Arc::new(sharding), None).unwrap();
+        for (k, shard) in &[("a", 0), ("b", 1), ("c", 1), ("d", 2), ("z", 2)] {
+            assert_eq!(db.shard_of(k.as_bytes()), *shard);
+            db.put(WriteOptions::default(), Slice::from(*k), Slice::from(*k))
+                .unwrap();
+            assert_eq!(
+                db.shard(*shard)
+                    .get(ReadOptions::default(), Slice::from(*k))
+                    .unwrap(),
+                Some(k.as_bytes().to_vec())
+            );
+        }
+        let mut iter = db.iter(ReadOptions::default());
+        iter.seek_to_last();
+        let mut keys = vec![];
+        while iter.valid() {
+            keys.push(iter.key().as_slice().to_vec());
+            iter.prev();
+        }
+        assert_eq!(keys, vec![b"z", b"d", b"c", b"b", b"a"]);
+        drop(iter);
+        db.destroy().unwrap();
+        assert!(!env.exists("sharded"));
+    }
+}
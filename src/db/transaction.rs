@@ -0,0 +1,234 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::batch::WriteBatch;
+use crate::batch_with_index::WriteBatchWithIndex;
+use crate::db::{WickDB, DB};
+use crate::options::{Options, ReadOptions, WriteOptions};
+use crate::snapshot::Snapshot;
+use crate::util::slice::Slice;
+use crate::util::status::{Result, Status, WickErr};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// A `WickDB` supporting optimistic transactions.
+///
+/// An `OptimisticTransaction` takes no lock while running. It remembers the keys it
+/// reads and writes, and at commit time checks whether any of them has been written
+/// since the transaction began by comparing the sequence numbers of their newest
+/// entries with the one of the transaction's snapshot. The transaction is aborted
+/// with `Status::Busy` on a conflict and is able to be retried by the caller.
+///
+/// This is cheaper than locking when the conflicts are rare. The commits and the
+/// writes through `OptimisticTransactionDB` are serialized with each other, but the
+/// writes made through `db()` directly are not detected as conflicts.
+pub struct OptimisticTransactionDB {
+    db: WickDB,
+    // Serializes the validation and the write of each commit
+    commit_lock: Mutex<()>,
+}
+
+impl OptimisticTransactionDB {
+    /// Opens the db at `db_name` for optimistic transactions
+    pub fn open(options: Options, db_name: String) -> Result<Self> {
+        Ok(Self::new(WickDB::open_db(options, db_name)?))
+    }
+
+    /// Wraps an opened `WickDB` for optimistic transactions
+    pub fn new(db: WickDB) -> Self {
+        Self {
+            db,
+            commit_lock: Mutex::new(()),
+        }
+    }
+
+    /// Returns the underlying `WickDB` for the non-transactional reads
+    pub fn db(&self) -> &WickDB {
+        &self.db
+    }
+
+    /// Begins a transaction reading the current state of the db
+    pub fn begin(&self) -> OptimisticTransaction<'_> {
+        OptimisticTransaction {
+            txn_db: self,
+            snapshot: self.db.snapshot(),
+            tracked_keys: HashSet::new(),
+            writes: WriteBatchWithIndex::new(self.db.inner.options.comparator.clone()),
+        }
+    }
+
+    /// Sets the value for the given key outside any transaction
+    pub fn put(&self, write_opt: WriteOptions, key: Slice, value: Slice) -> Result<()> {
+        let _lock = self.commit_lock.lock().unwrap();
+        self.db.put(write_opt, key, value)
+    }
+
+    /// Deletes the given key outside any transaction
+    pub fn delete(&self, write_opt: WriteOptions, key: Slice) -> Result<()> {
+        let _lock = self.commit_lock.lock().unwrap();
+        self.db.delete(write_opt, key)
+    }
+
+    /// Applies the `WriteBatch` outside any transaction
+    pub fn write(&self, write_opt: WriteOptions, batch: WriteBatch) -> Result<()> {
+        let _lock = self.commit_lock.lock().unwrap();
+        self.db.write(write_opt, batch)
+    }
+}
+
+/// A transaction started by `OptimisticTransactionDB::begin`. The reads see the
+/// snapshot taken at the beginning plus the transaction's own writes, and the
+/// writes are buffered until `commit`. Dropping a transaction without committing
+/// discards it.
+pub struct OptimisticTransaction<'a> {
+    txn_db: &'a OptimisticTransactionDB,
+    snapshot: Arc<Snapshot>,
+    // The keys read or written which are validated at commit time
+    tracked_keys: HashSet<Vec<u8>>,
+    // The buffered writes
+    writes: WriteBatchWithIndex,
+}
+
+impl OptimisticTransaction<'_> {
+    /// Gets the value for the given key. It returns `None` if the key does not
+    /// exist in the snapshot of the transaction or is deleted by the transaction.
+    pub fn get(&mut self, key: Slice) -> Result<Option<Vec<u8>>> {
+        let read_opt = ReadOptions {
+            snapshot: Some(self.snapshot.as_ref().clone()),
+            ..Default::default()
+        };
+        let value = self
+            .writes
+            .get_from_batch_and_db(&self.txn_db.db, read_opt, key.as_slice())?;
+        self.tracked_keys.insert(key.as_slice().to_vec());
+        Ok(value)
+    }
+
+    /// Sets the value for the given key in the transaction
+    pub fn put(&mut self, key: Slice, value: Slice) {
+        self.tracked_keys.insert(key.as_slice().to_vec());
+        self.writes.put(key.as_slice(), value.as_slice());
+    }
+
+    /// Deletes the given key in the transaction
+    pub fn delete(&mut self, key: Slice) {
+        self.tracked_keys.insert(key.as_slice().to_vec());
+        self.writes.delete(key.as_slice());
+    }
+
+    /// Returns the sequence number of the snapshot the transaction reads
+    pub fn sequence(&self) -> u64 {
+        self.snapshot.sequence()
+    }
+
+    /// Writes the buffered writes into the db atomically if none of the keys read
+    /// or written by the transaction has been changed since the transaction began.
+    /// Otherwise nothing is written and `Status::Busy` is returned.
+    pub fn commit(self, write_opt: WriteOptions) -> Result<()> {
+        let _lock = self.txn_db.commit_lock.lock().unwrap();
+        for key in self.tracked_keys.iter() {
+            if let Some(seq) = self.txn_db.db.inner.latest_sequence(key)? {
+                if seq > self.snapshot.sequence() {
+                    return Err(WickErr::new(
+                        Status::Busy,
+                        Some("transaction conflicts with a newer write"),
+                    ));
+                }
+            }
+        }
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        self.txn_db.db.write(write_opt, self.writes.into_batch())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem::MemStorage;
+
+    fn new_test_db(name: &str) -> OptimisticTransactionDB {
+        let mut options = Options::default();
+        options.env = Arc::new(MemStorage::default());
+        OptimisticTransactionDB::open(options, name.to_owned()).expect("could not open db")
+    }
+
+    fn get(txn_db: &OptimisticTransactionDB, key: &str) -> Option<Vec<u8>> {
+        txn_db
+            .db()
+            .get(ReadOptions::default(), Slice::from(key))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_commit_without_conflicts() {
+        let txn_db = new_test_db("test_commit_without_conflicts");
+        txn_db
+            .put(WriteOptions::default(), Slice::from("a"), Slice::from("1"))
+            .unwrap();
+        let mut txn = txn_db.begin();
+        assert_eq!(txn.get(Slice::from("a")).unwrap(), Some(b"1".to_vec()));
+        txn.put(Slice::from("a"), Slice::from("2"));
+        txn.put(Slice::from("b"), Slice::from("3"));
+        txn.delete(Slice::from("b"));
+        // Reads its own writes
+        assert_eq!(txn.get(Slice::from("a")).unwrap(), Some(b"2".to_vec()));
+        assert_eq!(txn.get(Slice::from("b")).unwrap(), None);
+        // Writes to the keys untracked by the transaction are not conflicts
+        txn_db
+            .put(WriteOptions::default(), Slice::from("c"), Slice::from("4"))
+            .unwrap();
+        assert_eq!(get(&txn_db, "a"), Some(b"1".to_vec()));
+        txn.commit(WriteOptions::default()).unwrap();
+        assert_eq!(get(&txn_db, "a"), Some(b"2".to_vec()));
+        assert_eq!(get(&txn_db, "b"), None);
+        assert_eq!(get(&txn_db, "c"), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn test_commit_with_conflicts() {
+        let txn_db = new_test_db("test_commit_with_conflicts");
+        // Read-write conflict
+        let mut txn = txn_db.begin();
+        assert_eq!(txn.get(Slice::from("a")).unwrap(), None);
+        txn.put(Slice::from("b"), Slice::from("1"));
+        txn_db
+            .put(WriteOptions::default(), Slice::from("a"), Slice::from("2"))
+            .unwrap();
+        let res = txn.commit(WriteOptions::default());
+        assert_eq!(res.unwrap_err().status(), Status::Busy);
+        assert_eq!(get(&txn_db, "b"), None);
+
+        // Write-write conflict between transactions
+        let mut txn1 = txn_db.begin();
+        let mut txn2 = txn_db.begin();
+        txn1.put(Slice::from("a"), Slice::from("3"));
+        txn2.delete(Slice::from("a"));
+        txn1.commit(WriteOptions::default()).unwrap();
+        let res = txn2.commit(WriteOptions::default());
+        assert_eq!(res.unwrap_err().status(), Status::Busy);
+        assert_eq!(get(&txn_db, "a"), Some(b"3".to_vec()));
+
+        // A deletion is a conflict as well
+        let mut txn = txn_db.begin();
+        assert_eq!(txn.get(Slice::from("a")).unwrap(), Some(b"3".to_vec()));
+        txn_db
+            .delete(WriteOptions::default(), Slice::from("a"))
+            .unwrap();
+        txn.put(Slice::from("a"), Slice::from("4"));
+        let res = txn.commit(WriteOptions::default());
+        assert_eq!(res.unwrap_err().status(), Status::Busy);
+        assert_eq!(get(&txn_db, "a"), None);
+    }
+}
@@ -19,6 +19,9 @@ use crate::filter::FilterPolicy;
 use crate::util::hash::hash;
 use crate::util::slice::Slice;
 
+// The name of every `BloomFilter` regardless of the bits per key
+pub(crate) const BLOOM_FILTER_NAME: &str = "leveldb.BuiltinBloomFilter";
+
 pub struct BloomFilter {
     // the hash count for a key
     k: usize,
@@ -46,14 +49,14 @@ impl BloomFilter {
 
 impl FilterPolicy for BloomFilter {
     fn name(&self) -> &str {
-        "leveldb.BuiltinBloomFilter"
+        BLOOM_FILTER_NAME
     }
 
     fn may_contain(&self, filter: &[u8], key: &Slice) -> bool {
-        let n = filter.len() - 1; // exclude the k
-        if filter.is_empty() || n < 1 {
+        if filter.len() < 2 {
             return false;
         };
+        let n = filter.len() - 1; // exclude the k
         let bits = n * 8;
 
         // Use the encoded k so that we can read filters generated by
@@ -31,7 +31,7 @@ pub mod bloom;
 /// these filters, the `FilterPolicy` name at the time of writing must equal the
 /// name at the time of reading. If they do not match, the filters will be
 /// ignored, which will not affect correctness but may affect performance.
-pub trait FilterPolicy {
+pub trait FilterPolicy: Send + Sync {
     /// Return the name of this policy.  Note that if the filter encoding
     /// changes in an incompatible way, the name returned by this method
     /// must be changed.  Otherwise, old incompatible filters may be
@@ -47,3 +47,51 @@ pub trait FilterPolicy {
     // TODO: use another type instead of &[Vec<u8>]
     fn create_filter(&self, keys: &[Vec<u8>]) -> Vec<u8>;
 }
+
+/// `PrefixExtractor` maps the user keys to their prefixes, which are added
+/// into the filters of the tables besides the keys so that a seek bounded
+/// within a prefix is able to skip the tables without it.
+///
+/// All the keys sorting between two keys of a same prefix must have the prefix
+/// too. Like `FilterPolicy`, the name is written to the tables and the prefixes
+/// in a table are ignored if the name of the current extractor is different.
+pub trait PrefixExtractor: Send + Sync {
+    /// Returns the name of the extractor
+    fn name(&self) -> &str;
+
+    /// Returns whether `key` has a prefix
+    fn in_domain(&self, key: &[u8]) -> bool;
+
+    /// Returns the prefix of a `key` in the domain
+    fn prefix<'a>(&self, key: &'a [u8]) -> &'a [u8];
+}
+
+/// Takes the first `n` bytes of the keys as the prefixes. The keys shorter
+/// than `n` have no prefix.
+pub struct FixedPrefixExtractor {
+    n: usize,
+    name: String,
+}
+
+impl FixedPrefixExtractor {
+    pub fn new(n: usize) -> Self {
+        Self {
+            n,
+            name: format!("wickdb.FixedPrefix.{}", n),
+        }
+    }
+}
+
+impl PrefixExtractor for FixedPrefixExtractor {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn in_domain(&self, key: &[u8]) -> bool {
+        key.len() >= self.n
+    }
+
+    fn prefix<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        &key[..self.n]
+    }
+}
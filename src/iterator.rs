@@ -17,7 +17,7 @@
 
 use crate::util::comparator::Comparator;
 use crate::util::slice::Slice;
-use crate::util::status::{Result, WickErr};
+use crate::util::status::{Result, Status, WickErr};
 use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::mem;
@@ -68,70 +68,70 @@ pub trait Iterator {
 
     /// If an error has occurred, return it.  Else return an ok status.
     fn status(&mut self) -> Result<()>;
-}
-
-/// An special iterator calls all `tasks` before dropping
-pub struct IterWithCleanup {
-    inner_iter: Box<dyn Iterator>,
-    tasks: Vec<Box<dyn FnMut()>>,
-}
-
-impl IterWithCleanup {
-    pub fn new(iter: Box<dyn Iterator>) -> Self {
-        Self {
-            inner_iter: iter,
-            tasks: vec![],
-        }
-    }
 
-    pub fn register_task(&mut self, task: Box<dyn FnMut()>) {
-        self.tasks.push(task)
+    /// Return the size of the value for the current entry, which is available
+    /// even if the value itself is skipped by `ReadOptions::value_size_only`.
+    /// REQUIRES: `valid()`
+    fn value_size(&self) -> usize {
+        self.value().size()
     }
-}
 
-impl Drop for IterWithCleanup {
-    fn drop(&mut self) {
-        for mut t in self.tasks.drain(..) {
-            t()
-        }
+    /// Re-pins the iterator to the latest state of the source and repositions it
+    /// at the first key at or past the current one, so that a long-lived scan
+    /// stops holding the old state. An invalid iterator stays invalid until the
+    /// next seek.
+    /// Returns `Status::NotSupported` unless the iterator knows how to rebuild itself.
+    fn refresh(&mut self) -> Result<()> {
+        Err(WickErr::new(
+            Status::NotSupported,
+            Some("the iterator is not refreshable"),
+        ))
     }
 }
 
-impl Iterator for IterWithCleanup {
+impl<I: Iterator + ?Sized> Iterator for Box<I> {
     fn valid(&self) -> bool {
-        self.inner_iter.valid()
+        (**self).valid()
     }
 
     fn seek_to_first(&mut self) {
-        self.inner_iter.seek_to_first()
+        (**self).seek_to_first()
     }
 
     fn seek_to_last(&mut self) {
-        self.inner_iter.seek_to_last()
+        (**self).seek_to_last()
     }
 
     fn seek(&mut self, target: &Slice) {
-        self.inner_iter.seek(target)
+        (**self).seek(target)
     }
 
     fn next(&mut self) {
-        self.inner_iter.next()
+        (**self).next()
     }
 
     fn prev(&mut self) {
-        self.inner_iter.prev()
+        (**self).prev()
     }
 
     fn key(&self) -> Slice {
-        self.inner_iter.key()
+        (**self).key()
     }
 
     fn value(&self) -> Slice {
-        self.inner_iter.value()
+        (**self).value()
     }
 
     fn status(&mut self) -> Result<()> {
-        self.inner_iter.status()
+        (**self).status()
+    }
+
+    fn value_size(&self) -> usize {
+        (**self).value_size()
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        (**self).refresh()
     }
 }
 
@@ -198,12 +198,22 @@ pub struct ConcatenateIterator {
     derived: Option<Box<dyn Iterator>>,
     prev_derived_value: Vec<u8>,
     err: Option<WickErr>,
+    // The comparator of the keys and the lower (inclusive) and upper (exclusive)
+    // bounds the origin stops stepping beyond
+    bounds: Option<(Arc<dyn Comparator>, Option<Vec<u8>>, Option<Vec<u8>>)>,
 }
 
 /// A factory that takes value from the origin and
 pub trait DerivedIterFactory {
     /// Create a new `Iterator` based on value yield by original `Iterator`
     fn derive(&self, value: &Slice) -> Result<Box<dyn Iterator>>;
+
+    /// Returns false if a seek to `target` is known to find no wanted key in the
+    /// derived `Iterator` of `value` or after it, which stops the seek without
+    /// deriving the `Iterator`.
+    fn seek_may_match(&self, _value: &Slice, _target: &Slice) -> bool {
+        true
+    }
 }
 
 impl ConcatenateIterator {
@@ -214,6 +224,43 @@ impl ConcatenateIterator {
             derived: None,
             prev_derived_value: vec![],
             err: None,
+            bounds: None,
+        }
+    }
+
+    /// Makes `next` and `prev` stop stepping into the derived iterators whose keys
+    /// are all not less than `upper` or less than `lower`. The seeks are not bounded
+    /// so a seek still finds the first key not less than the target. The key of the
+    /// origin must be the largest key of its derived iterator, as in the index of a table.
+    pub fn with_bounds(
+        mut self,
+        cmp: Arc<dyn Comparator>,
+        lower: Option<Vec<u8>>,
+        upper: Option<Vec<u8>>,
+    ) -> Self {
+        if lower.is_some() || upper.is_some() {
+            self.bounds = Some((cmp, lower, upper));
+        }
+        self
+    }
+
+    // Whether the keys after the current derived iterator are all beyond the upper bound
+    fn reach_upper_bound(&self) -> bool {
+        match &self.bounds {
+            Some((cmp, _, Some(upper))) => {
+                cmp.compare(self.origin.key().as_slice(), upper.as_slice()) != Ordering::Less
+            }
+            _ => false,
+        }
+    }
+
+    // Whether the keys of the current derived iterator are all below the lower bound
+    fn below_lower_bound(&self) -> bool {
+        match &self.bounds {
+            Some((cmp, Some(lower), _)) => {
+                cmp.compare(self.origin.key().as_slice(), lower.as_slice()) == Ordering::Less
+            }
+            _ => false,
         }
     }
 
@@ -259,10 +306,10 @@ impl ConcatenateIterator {
     }
 
     // Skip invalid results util finding a valid derived iter by `next()`
-    // If found, set derived iter to the first
-    fn skip_forward(&mut self) {
+    // If found, set derived iter to the first. Stops at the upper bound if `bounded`.
+    fn skip_forward(&mut self, bounded: bool) {
         while self.derived.is_none() || !self.derived.as_ref().unwrap().valid() {
-            if !self.origin.valid() {
+            if !self.origin.valid() || (bounded && self.reach_upper_bound()) {
                 self.set_derived(None);
                 break;
             } else {
@@ -277,14 +324,18 @@ impl ConcatenateIterator {
     }
 
     // Skip invalid results util finding a valid derived iter by `prev()`
-    // If found, set derived iter to the last
-    fn skip_backward(&mut self) {
+    // If found, set derived iter to the last. Stops at the lower bound if `bounded`.
+    fn skip_backward(&mut self, bounded: bool) {
         while self.derived.is_none() || !self.derived.as_ref().unwrap().valid() {
             if !self.origin.valid() {
                 self.set_derived(None);
                 break;
             } else {
                 self.origin.prev();
+                if bounded && self.origin.valid() && self.below_lower_bound() {
+                    self.set_derived(None);
+                    break;
+                }
                 self.init_derived_iter();
                 if let Some(i) = &mut self.derived {
                     // init to the last
@@ -319,38 +370,62 @@ impl Iterator for ConcatenateIterator {
             di.seek_to_first()
         }
         // scan forward util finding the first valid entry
-        self.skip_forward();
+        self.skip_forward(false);
     }
 
     fn seek_to_last(&mut self) {
+        // positions at the last key before the upper bound, which keeps the
+        // direction switches of a `MergingIterator` right after a seek stopped
+        // by `DerivedIterFactory::seek_may_match`
+        if let Some((_, _, Some(upper))) = &self.bounds {
+            let upper = Slice::from(upper.as_slice());
+            self.origin.seek(&upper);
+            if self.origin.valid() {
+                self.init_derived_iter();
+                if let Some(di) = self.derived.as_mut() {
+                    di.seek(&upper);
+                    if di.valid() {
+                        di.prev();
+                    } else {
+                        di.seek_to_last();
+                    }
+                }
+                self.skip_backward(false);
+                return;
+            }
+        }
         self.origin.seek_to_last();
         self.init_derived_iter();
         if let Some(di) = self.derived.as_mut() {
             di.seek_to_last()
         }
         // scan backward util finding the first valid entry
-        self.skip_backward();
+        self.skip_backward(false);
     }
 
     fn seek(&mut self, target: &Slice) {
         self.origin.seek(target);
+        if self.origin.valid() && !self.factory.seek_may_match(&self.origin.value(), target) {
+            self.set_derived(None);
+            return;
+        }
         self.init_derived_iter();
         if let Some(di) = self.derived.as_mut() {
             di.seek(target)
         }
-        self.skip_forward();
+        self.skip_forward(false);
     }
 
     fn next(&mut self) {
         self.valid_or_panic();
         self.derived.as_mut().map_or((), |di| di.next());
-        self.skip_forward();
+        self.skip_forward(true);
     }
 
     fn prev(&mut self) {
         self.valid_or_panic();
         self.derived.as_mut().map_or((), |di| di.prev());
-        self.skip_backward();
+        self.skip_backward(true);
     }
 
     fn key(&self) -> Slice {
@@ -388,7 +463,8 @@ pub enum IterDirection {
 /// `children[0..n-1]` with the correct order.
 /// This iterator performs just like a `merge sort` to its children.
 /// The result does no duplicate suppression.  I.e., if a particular
-/// key is present in K child iterators, it will be yielded K times.
+/// key is present in K child iterators, it will be yielded K times, and
+/// the entry of the earlier child comes first when iterating forward.
 pub struct MergingIterator {
     cmp: Arc<dyn Comparator>,
     direction: IterDirection,
@@ -409,6 +485,18 @@ impl MergingIterator {
         }
     }
 
+    /// Creates a `MergingIterator` over `iters` ordered by `cmp`, which must be the
+    /// comparator the keys of all the `iters` are sorted by. For example, the
+    /// iterator of a `WickDB` can be merged with an overlay of the caller by
+    /// `WickDB::comparator`.
+    pub fn from_iters(cmp: Arc<dyn Comparator>, iters: Vec<Box<dyn Iterator>>) -> Self {
+        let children = iters
+            .into_iter()
+            .map(|iter| Rc::new(RefCell::new(iter)))
+            .collect();
+        Self::new(cmp, children)
+    }
+
     fn valid_or_panic(&self) {
         assert!(self.current.is_some())
     }
@@ -558,29 +646,8 @@ mod tests {
     use crate::util::status::Result;
     use std::cell::RefCell;
     use std::cmp::Ordering;
-    use std::mem;
     use std::rc::Rc;
 
-    struct TestCleanup {
-        results: Vec<usize>,
-    }
-
-    #[test]
-    fn test_iter_with_cleanup() {
-        let test_cleaned_up = Rc::new(RefCell::new(TestCleanup { results: vec![] }));
-
-        let mut iter = IterWithCleanup::new(Box::new(EmptyIterator::new()));
-        for i in 0..100 {
-            let cloned = test_cleaned_up.clone();
-            iter.register_task(Box::new(move || cloned.borrow_mut().results.push(i)));
-        }
-        mem::drop(iter);
-        assert_eq!(100, test_cleaned_up.borrow().results.len());
-        for i in 0..100 {
-            assert_eq!(i, test_cleaned_up.borrow().results[i]);
-        }
-    }
-
     // Divide given ordered `src` into `n` lists and then construct a `MergingIterator` with them
     fn new_test_merging_iter(mut src: Vec<String>, n: usize) -> MergingIterator {
         let mut children = vec![];
@@ -806,6 +873,50 @@ mod tests {
         assert!(!iter.valid());
     }
 
+    #[test]
+    fn test_concatenated_iterator_bounds() {
+        // inner: [a, aa, aaa, b, bb, bbb, c, cc, ccc, d, dd, ddd]
+        let mut iter = ConcatenateIterator::new(
+            TestSimpleArrayIter::box_new(vec![
+                "aaa".to_owned(),
+                "bbb".to_owned(),
+                "ccc".to_owned(),
+                "ddd".to_owned(),
+            ]),
+            Box::new(SimpleDeriveFactory::new()),
+        )
+        .with_bounds(
+            Arc::new(BytewiseComparator::new()),
+            Some(b"b".to_vec()),
+            Some(b"cc".to_vec()),
+        );
+        // stops at the end of the chunk containing the upper bound
+        let mut keys = vec![];
+        iter.seek_to_first();
+        while iter.valid() {
+            keys.push(iter.key().as_str().to_owned());
+            iter.next();
+        }
+        assert_eq!(
+            keys,
+            vec!["a", "aa", "aaa", "b", "bb", "bbb", "c", "cc", "ccc"]
+        );
+
+        // starts at the last key before the upper bound and stops at the start
+        // of the chunk containing the lower bound
+        keys.clear();
+        iter.seek_to_last();
+        while iter.valid() {
+            keys.push(iter.key().as_str().to_owned());
+            iter.prev();
+        }
+        assert_eq!(keys, vec!["c", "bbb", "bb", "b"]);
+
+        // seeks are not bounded
+        iter.seek(&Slice::from("d"));
+        assert_eq!(iter.key().as_str(), "d");
+    }
+
     #[test]
     fn test_merging_iterator() {
         let mut input = vec![];
@@ -21,7 +21,6 @@ extern crate libc;
 extern crate log;
 #[macro_use]
 extern crate lazy_static;
-extern crate crc;
 extern crate crossbeam_channel;
 extern crate crossbeam_utils;
 extern crate rand;
@@ -30,32 +29,68 @@ extern crate snap;
 #[macro_use]
 mod util;
 pub mod batch;
+mod batch_with_index;
 pub mod cache;
 mod compaction;
 pub mod db;
 pub mod filter;
 mod iterator;
+pub mod listener;
 mod logger;
 mod mem;
 pub mod options;
+pub mod rate_limiter;
 mod record;
+pub mod remote_compaction;
 mod snapshot;
+pub mod sst_file_reader;
+pub mod sst_file_writer;
 mod sstable;
+pub mod statistics;
 pub mod storage;
 mod table_cache;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod version;
+pub mod write_hook;
 
 pub use batch::WriteBatch;
+pub use batch_with_index::WriteBatchWithIndex;
 pub use cache::{Cache, HandleRef};
-pub use compaction::ManualCompaction;
-pub use db::{WickDB, DB};
+pub use compaction::{CompactionDecision, CompactionFilter, ManualCompaction};
+pub use db::iterator::{IteratorPool, PooledIterator, TailingIterator};
+pub use db::lease::LockLease;
+pub use db::secondary_index::{IndexCompactionFilter, IndexExtractor, SecondaryIndexDB};
+pub use db::sharded::{HashSharding, RangeSharding, ShardFunction, ShardedDB};
+pub use db::transaction::{OptimisticTransaction, OptimisticTransactionDB};
+pub use db::{
+    ApproximateSize, KvSizeHistograms, LevelTombstoneStats, Range, RangeDigest, WickDB, DB,
+};
 pub use filter::bloom::BloomFilter;
-pub use iterator::Iterator;
+pub use filter::{FixedPrefixExtractor, PrefixExtractor};
+pub use iterator::{Iterator, MergingIterator};
+pub use listener::EventListener;
 pub use log::{LevelFilter, Log};
-pub use options::{CompressionType, Options, ReadOptions, WriteOptions};
+pub use options::{
+    CancellationToken, ChecksumType, CompressionType, ConsistencyCheckLevel, LevelTableOptions,
+    MemtableRepType, MutableOptions, Options, ReadOptions, SizeApproximationOptions, SyncPolicy,
+    WriteOptions,
+};
+pub use rate_limiter::RateLimiter;
+pub use remote_compaction::CompactionService;
+pub use sst_file_reader::SstFileReader;
+pub use sst_file_writer::{ExternalSorter, SstFileWriter};
 pub use sstable::block::Block;
-pub use storage::{File, Storage};
+pub use sstable::dictionary::DictionaryCompressor;
+pub use sstable::encryption::BlockCipher;
+pub use sstable::properties::TableProperties;
+pub use sstable::{LATEST_FORMAT_VERSION, LEGACY_FORMAT_VERSION};
+pub use statistics::{HistogramType, LevelTicker, Statistics, StatisticsSnapshot, Ticker};
+pub use storage::{AsyncFile, File, LockKind, Storage};
+pub use util::clock::{Clock, MockClock, SystemClock};
 pub use util::comparator::Comparator;
+pub use util::histogram::Histogram;
 pub use util::slice::Slice;
-pub use util::status::{Result, Status, WickErr};
+pub use util::status::{CorruptionReport, Result, Status, WickErr};
 pub use util::varint::*;
+pub use write_hook::{KeyClassifier, PreWriteHook, WriteDecision};
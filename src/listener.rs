@@ -0,0 +1,223 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::util::histogram::Histogram;
+
+/// Information of a finished memtable flush
+#[derive(Clone, Debug)]
+pub struct FlushJobInfo {
+    pub db_name: String,
+    /// The number of the generated level0 file. The file may be
+    /// picked to a higher level by `Version::pick_level_for_memtable_output`.
+    pub file_number: u64,
+    /// The level where the generated file is placed at
+    pub level: usize,
+    /// The size of the generated file. Zero means the memtable is empty
+    /// and no file is created.
+    pub file_size: u64,
+    /// The duration of the flush in micros
+    pub micros: u64,
+}
+
+/// Information of a finished major compaction
+#[derive(Clone, Debug)]
+pub struct CompactionJobInfo {
+    pub db_name: String,
+    /// The level of the source input files
+    pub level: usize,
+    /// The level of the output files
+    pub output_level: usize,
+    pub input_files: Vec<u64>,
+    pub output_files: Vec<u64>,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// The duration of the compaction in micros
+    pub micros: u64,
+    /// Whether the compaction moves a single file to the next level
+    /// without rewriting it
+    pub is_trivial_move: bool,
+    /// Whether the compaction has been applied to the current version
+    pub succeeded: bool,
+    /// The number of the versions of each user key in the inputs, which tells how
+    /// heavily the keys are overwritten. Empty for a trivial move.
+    pub versions_per_key: Histogram,
+    /// The number of the entries dropped since they are shadowed by the newer
+    /// versions of their user keys
+    pub dropped_versions: u64,
+    /// What triggers the compaction
+    pub reason: CompactionReason,
+}
+
+/// The trigger of a major compaction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionReason {
+    /// A level exceeds its size limit or level0 has too many files
+    LevelSize,
+    /// A file runs out of its allowed seeks, see `Options::seek_compaction`
+    Seek,
+    /// Requested by a manual compaction
+    Manual,
+    /// The level0 files are compacted since the db is idle
+    Idle,
+    /// A bottommost file has too many deletions, see `Options::bottommost_deletion_ratio`
+    BottommostDeletions,
+    /// A file is older than `Options::periodic_compaction_seconds`
+    Periodic,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableFileCreationReason {
+    Flush,
+    Compaction,
+}
+
+/// Information of a newly created table file
+#[derive(Clone, Debug)]
+pub struct TableFileCreationInfo {
+    pub db_name: String,
+    pub file_number: u64,
+    pub file_size: u64,
+    pub reason: TableFileCreationReason,
+}
+
+/// Information of a deleted table file
+#[derive(Clone, Debug)]
+pub struct TableFileDeletionInfo {
+    pub db_name: String,
+    pub file_number: u64,
+    /// The full path of the deleted file
+    pub file_path: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteStallCause {
+    /// The number of level0 files reaches `l0_slowdown_writes_threshold`
+    Level0Slowdown,
+    /// The number of level0 files reaches `l0_stop_writes_threshold`
+    Level0Stop,
+    /// The memtable is full while the immutable memtable is still being flushed
+    MemtableLimit,
+    /// The pending compaction bytes reach `soft_pending_compaction_bytes_limit`
+    PendingCompactionBytesSlowdown,
+    /// The pending compaction bytes reach `hard_pending_compaction_bytes_limit`
+    PendingCompactionBytesStop,
+}
+
+/// Information of a write stall
+#[derive(Clone, Debug)]
+pub struct WriteStallInfo {
+    pub db_name: String,
+    pub cause: WriteStallCause,
+}
+
+/// A table file added to a level by a `VersionEditInfo`
+#[derive(Clone, Debug)]
+pub struct NewTableFileInfo {
+    pub level: usize,
+    pub file_number: u64,
+    pub file_size: u64,
+    /// The smallest user key in the file
+    pub smallest_key: Vec<u8>,
+    /// The largest user key in the file
+    pub largest_key: Vec<u8>,
+}
+
+/// Information of a version edit which is persisted in the MANIFEST and
+/// applied to the current version. Together with the files listed by
+/// `WickDB::get_property("wickdb.sstables")` on start, the edits describe
+/// every change of the files making up the db.
+#[derive(Clone, Debug)]
+pub struct VersionEditInfo {
+    pub db_name: String,
+    /// The number of the current WAL file
+    pub log_number: u64,
+    pub prev_log_number: u64,
+    pub next_file_number: u64,
+    pub last_sequence: u64,
+    pub new_files: Vec<NewTableFileInfo>,
+    /// The removed files in (level, file number) ordered by level and number
+    pub deleted_files: Vec<(usize, u64)>,
+}
+
+/// Information of the table files deleted in the FIFO mode.
+/// See `Options::fifo_max_table_files_size` for details.
+#[derive(Clone, Debug)]
+pub struct FifoDeletionInfo {
+    pub db_name: String,
+    pub deleted_files: Vec<u64>,
+    pub deleted_bytes: u64,
+    /// The total size of the remaining table files
+    pub total_size: u64,
+    /// The largest number of the deleted files. All the data flushed into the
+    /// level 0 files numbered no greater than the watermark has been dropped.
+    pub watermark: u64,
+}
+
+/// `EventListener` is notified when the background flushes and compactions
+/// make progress. All the callbacks are invoked in the background threads,
+/// some of which hold the lock of the db, so implementations should return
+/// quickly and must not call back into the db.
+/// A data block of an input file made obsolete by a compaction
+#[derive(Clone, Debug)]
+pub struct ObsoleteBlock {
+    pub file_number: u64,
+    /// The offset of the block in the file
+    pub offset: u64,
+    /// The size of the block excluding the trailer
+    pub size: u64,
+    /// The user keys of the entries in the block fall in `[smallest_key, largest_key]`.
+    /// The bounds come from the index block so they might be looser than the actual keys.
+    pub smallest_key: Vec<u8>,
+    pub largest_key: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompactionObsoleteBlocksInfo {
+    pub db_name: String,
+    /// The level of the source input files
+    pub level: usize,
+    /// The level of the output files
+    pub output_level: usize,
+    /// The blocks of all the input files ordered by the file number and the offset
+    pub blocks: Vec<ObsoleteBlock>,
+}
+
+pub trait EventListener: Send + Sync {
+    /// Called after the immutable memtable is flushed and the new file is
+    /// installed into the current version.
+    fn on_flush_completed(&self, _info: &FlushJobInfo) {}
+
+    /// Called after a major compaction finishes, no matter it succeeds or not.
+    fn on_compaction_completed(&self, _info: &CompactionJobInfo) {}
+
+    /// Called after a table file is built by a flush or a compaction.
+    fn on_table_file_created(&self, _info: &TableFileCreationInfo) {}
+
+    /// Called after an obsolete table file is removed.
+    fn on_table_file_deleted(&self, _info: &TableFileDeletionInfo) {}
+
+    /// Called when a write is going to be delayed or stopped.
+    fn on_write_stall(&self, _info: &WriteStallInfo) {}
+
+    /// Called after a version edit is logged to the MANIFEST and the new
+    /// version is installed, in the same order as the edits are applied.
+    fn on_version_edit_applied(&self, _info: &VersionEditInfo) {}
+
+    /// Called after the oldest table files are deleted in the FIFO mode.
+    fn on_fifo_deletion(&self, _info: &FifoDeletionInfo) {}
+
+    /// Called after a compaction rewriting its input files succeeds, with all the data
+    /// blocks of the input files, so that the caches built above the database could
+    /// invalidate exactly the entries read from these blocks.
+    fn on_compaction_obsoleted_blocks(&self, _info: &CompactionObsoleteBlocksInfo) {}
+}
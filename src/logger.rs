@@ -12,24 +12,27 @@
 // limitations under the License.
 
 use crate::storage::File;
+use crate::util::clock::{format_micros, Clock};
 use log::{LevelFilter, Log, Metadata, Record};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 /// A simple file based Logger
 // TODO: maybe use slog-rs instead
 pub struct Logger {
     file: Mutex<Box<dyn File>>,
     level: LevelFilter,
+    clock: Arc<dyn Clock>,
 }
 
 unsafe impl Send for Logger {}
 unsafe impl Sync for Logger {}
 
 impl Logger {
-    pub fn new(file: Box<dyn File>, level: LevelFilter) -> Self {
+    pub fn new(file: Box<dyn File>, level: LevelFilter, clock: Arc<dyn Clock>) -> Self {
         Self {
             file: Mutex::new(file),
             level,
+            clock,
         }
     }
 }
@@ -42,10 +45,13 @@ impl Log for Logger {
     #[allow(unused_must_use)]
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            self.file
-                .lock()
-                .unwrap()
-                .write(format!("[{}] : {} \n", record.level(), record.args()).as_bytes());
+            let line = format!(
+                "{} [{}] : {} \n",
+                format_micros(self.clock.now_micros()),
+                record.level(),
+                record.args()
+            );
+            self.file.lock().unwrap().write(line.as_bytes());
         }
     }
 
@@ -13,6 +13,7 @@
 
 use std::cell::RefCell;
 use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::{mem, ptr};
 
 const BLOCK_SIZE: usize = 4096;
@@ -25,8 +26,61 @@ pub trait Arena {
     /// NOTE: the implementation is aligned with usize ( 32 or 64)
     fn allocate_aligned(&self, aligned: usize) -> *mut u8;
 
-    /// Return the size of memory that has been allocated.
+    /// Return the size of memory that has been handed out by the allocations,
+    /// including the paddings for alignment.
     fn memory_used(&self) -> usize;
+
+    /// Return the size of memory that has been reserved from the system, which
+    /// includes the unused tail of the current block.
+    fn memory_allocated(&self) -> usize;
+}
+
+// The memory of a block held by the arena, which is only accessed by the pointers
+#[allow(dead_code)]
+enum Block {
+    Heap(Vec<u8>),
+    #[cfg(target_os = "linux")]
+    HugePages(HugePageBlock),
+}
+
+// A block mapped from the huge pages, unmapped when dropped
+#[cfg(target_os = "linux")]
+struct HugePageBlock {
+    ptr: *mut u8,
+    size: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl HugePageBlock {
+    fn map(size: usize) -> Option<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            None
+        } else {
+            Some(Self {
+                ptr: ptr as *mut u8,
+                size,
+            })
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for HugePageBlock {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.size);
+        }
+    }
 }
 
 /// `BlockArena` is a memory pool for allocating and handling Node memory dynamically.
@@ -40,45 +94,84 @@ pub trait Arena {
 pub struct BlockArena {
     pub(super) ptr: AtomicPtr<u8>,
     pub(super) bytes_remaining: AtomicUsize,
-    pub(super) blocks: RefCell<Vec<Vec<u8>>>,
-    // Total memory usage of the arena.
-    pub(super) memory_usage: AtomicUsize,
+    blocks: RefCell<Vec<Block>>,
+    // Total memory of the blocks.
+    pub(super) memory_allocated: AtomicUsize,
+    block_size: usize,
+    // Allocates the full blocks from the huge pages if positive
+    huge_page_size: usize,
 }
 
 impl BlockArena {
-    /// Create an AggressiveArena with given cap.
-    /// This function will allocate a cap size memory block directly for further usage
+    /// Create a `BlockArena` allocating blocks of the default size.
     pub fn new() -> BlockArena {
+        Self::with_block_size(BLOCK_SIZE, 0)
+    }
+
+    /// Create a `BlockArena` allocating blocks of `block_size` bytes. If `huge_page_size`
+    /// is positive, the blocks are mapped from the huge pages of the size and the block
+    /// size is rounded up to a multiple of it. It falls back to the heap if no huge
+    /// page is available.
+    pub fn with_block_size(block_size: usize, huge_page_size: usize) -> BlockArena {
+        let block_size = if huge_page_size > 0 {
+            block_size.div_ceil(huge_page_size).max(1) * huge_page_size
+        } else {
+            block_size.max(1)
+        };
         BlockArena {
             ptr: AtomicPtr::new(ptr::null_mut()),
             bytes_remaining: AtomicUsize::new(0),
             blocks: RefCell::new(vec![]),
-            memory_usage: AtomicUsize::new(0),
+            memory_allocated: AtomicUsize::new(0),
+            block_size,
+            huge_page_size,
         }
     }
 
+    #[inline]
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
     pub(super) fn allocate_fallback(&self, size: usize) -> *mut u8 {
-        if size > BLOCK_SIZE / 4 {
+        if size > self.block_size / 4 {
             // Object is more than a quarter of our block size.  Allocate it separately
             // to avoid wasting too much space in leftover bytes.
             return self.allocate_new_block(size);
         }
         // create a new full block
-        let new_block_ptr = self.allocate_new_block(BLOCK_SIZE);
+        let new_block_ptr = self.allocate_full_block();
         unsafe {
             let ptr = new_block_ptr.add(size);
             self.ptr.store(ptr, Ordering::Release);
         };
         self.bytes_remaining
-            .store(BLOCK_SIZE - size, Ordering::Release);
+            .store(self.block_size - size, Ordering::Release);
         new_block_ptr
     }
 
+    fn allocate_full_block(&self) -> *mut u8 {
+        #[cfg(target_os = "linux")]
+        {
+            if self.huge_page_size > 0 {
+                if let Some(block) = HugePageBlock::map(self.block_size) {
+                    let p = block.ptr;
+                    self.blocks.borrow_mut().push(Block::HugePages(block));
+                    self.memory_allocated
+                        .fetch_add(self.block_size, Ordering::Relaxed);
+                    return p;
+                }
+            }
+        }
+        self.allocate_new_block(self.block_size)
+    }
+
     pub(super) fn allocate_new_block(&self, block_bytes: usize) -> *mut u8 {
         let mut new_block = vec![0; block_bytes];
         let p = new_block.as_mut_ptr();
-        self.blocks.borrow_mut().push(new_block);
-        self.memory_usage.fetch_add(block_bytes, Ordering::Relaxed);
+        self.blocks.borrow_mut().push(Block::Heap(new_block));
+        self.memory_allocated
+            .fetch_add(block_bytes, Ordering::Relaxed);
         p
     }
 }
@@ -139,7 +232,39 @@ impl Arena for BlockArena {
 
     #[inline]
     fn memory_used(&self) -> usize {
-        self.memory_usage.load(Ordering::Acquire)
+        // The separately allocated blocks are used up while only the unused
+        // tail of the current full block is excluded
+        self.memory_allocated
+            .load(Ordering::Acquire)
+            .saturating_sub(self.bytes_remaining.load(Ordering::Acquire))
+    }
+
+    #[inline]
+    fn memory_allocated(&self) -> usize {
+        self.memory_allocated.load(Ordering::Acquire)
+    }
+}
+
+// Lets several skiplists share one arena
+impl<A: Arena + ?Sized> Arena for Arc<A> {
+    #[inline]
+    fn allocate(&self, chunk: usize) -> *mut u8 {
+        self.as_ref().allocate(chunk)
+    }
+
+    #[inline]
+    fn allocate_aligned(&self, chunk: usize) -> *mut u8 {
+        self.as_ref().allocate_aligned(chunk)
+    }
+
+    #[inline]
+    fn memory_used(&self) -> usize {
+        self.as_ref().memory_used()
+    }
+
+    #[inline]
+    fn memory_allocated(&self) -> usize {
+        self.as_ref().memory_allocated()
     }
 }
 
@@ -193,10 +318,42 @@ mod tests {
     fn test_allocate_fallback() {
         let a = BlockArena::new();
         a.allocate_fallback(1);
-        assert_eq!(a.memory_used(), BLOCK_SIZE);
+        assert_eq!(a.memory_allocated(), BLOCK_SIZE);
+        assert_eq!(a.memory_used(), 1);
         assert_eq!(a.bytes_remaining.load(Ordering::Acquire), BLOCK_SIZE - 1);
         a.allocate_fallback(BLOCK_SIZE / 4 + 1);
-        assert_eq!(a.memory_used(), BLOCK_SIZE + BLOCK_SIZE / 4 + 1);
+        assert_eq!(a.memory_allocated(), BLOCK_SIZE + BLOCK_SIZE / 4 + 1);
+        assert_eq!(a.memory_used(), 1 + BLOCK_SIZE / 4 + 1);
+    }
+
+    #[test]
+    fn test_block_size() {
+        let block_size = 64 << 10;
+        let a = BlockArena::with_block_size(block_size, 0);
+        for _ in 0..100 {
+            a.allocate(100);
+        }
+        // the usage grows with the allocations instead of the blocks
+        assert_eq!(a.memory_used(), 100 * 100);
+        assert_eq!(a.memory_allocated(), block_size);
+        // a large allocation exceeding the remaining gets its own block
+        let large = block_size - 100 * 100 + 1;
+        a.allocate_aligned(large);
+        assert_eq!(a.blocks.borrow().len(), 2);
+        assert_eq!(a.memory_used(), 100 * 100 + large);
+        assert_eq!(a.memory_allocated(), block_size + large);
+
+        // the block size is rounded up to the huge pages
+        let huge_page_size = 2 << 20;
+        let a = BlockArena::with_block_size(block_size, huge_page_size);
+        assert_eq!(a.block_size(), huge_page_size);
+        // falls back to the heap if there is no huge page
+        let p = a.allocate(10);
+        unsafe {
+            ptr::write_bytes(p, 1, 10);
+        }
+        assert_eq!(a.memory_used(), 10);
+        assert_eq!(a.memory_allocated(), huge_page_size);
     }
 
     #[test]
@@ -16,12 +16,14 @@
 // found in the LICENSE file.
 
 mod arena;
+mod rep;
 mod skiplist;
 
 use crate::db::format::{InternalKeyComparator, LookupKey, ValueType};
 use crate::iterator::Iterator;
-use crate::mem::arena::BlockArena;
-use crate::mem::skiplist::{Skiplist, SkiplistIterator};
+pub use crate::mem::arena::BlockArena;
+pub use crate::mem::rep::{HashSkiplistRep, MemtableRep, SkiplistRep, VectorRep};
+use crate::options::MemtableRepType;
 use crate::util::coding::{decode_fixed_64, put_fixed_64};
 use crate::util::comparator::Comparator;
 use crate::util::slice::Slice;
@@ -29,6 +31,7 @@ use crate::util::status::Status;
 use crate::util::status::{Result, WickErr};
 use crate::util::varint::VarintU32;
 use std::cmp::Ordering;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use std::sync::Arc;
 
 pub trait MemoryTable {
@@ -36,9 +39,19 @@ pub trait MemoryTable {
     /// data structure. It is safe to call when MemTable is being modified.
     fn approximate_memory_usage(&self) -> usize;
 
+    /// Returns the number of bytes reserved by this data structure, which
+    /// also counts the memory reserved in advance for the later entries.
+    fn approximate_memory_allocated(&self) -> usize;
+
     /// Return an iterator that yields the contents of the memtable.
     fn iter(&self) -> Box<dyn Iterator>;
 
+    /// Returns the number of the entries added, including the deletions
+    fn num_entries(&self) -> u64;
+
+    /// Returns the number of the deletions added
+    fn num_deletions(&self) -> u64;
+
     /// Add an entry into memtable that maps key to value at the
     /// specified sequence number and with the specified type.
     /// Typically value will be empty if the type is `Deletion`.
@@ -104,27 +117,91 @@ impl Comparator for KeyComparator {
 }
 
 /// In-memory write buffer
-pub struct MemTable {
+pub struct MemTable<R: MemtableRep + ?Sized = dyn MemtableRep> {
     cmp: Arc<KeyComparator>,
-    table: Arc<Skiplist>,
+    rep: Arc<R>,
+    counts: Arc<EntryCounts>,
+}
+
+// The numbers of the entries and the deletions added into a memtable
+#[derive(Default)]
+struct EntryCounts {
+    entries: AtomicU64,
+    deletions: AtomicU64,
+}
+
+impl<R: MemtableRep + ?Sized> Clone for MemTable<R> {
+    fn clone(&self) -> Self {
+        Self {
+            cmp: self.cmp.clone(),
+            rep: self.rep.clone(),
+            counts: self.counts.clone(),
+        }
+    }
 }
 
 impl MemTable {
     pub fn new(icmp: Arc<InternalKeyComparator>) -> Self {
-        let arena = BlockArena::new();
+        Self::with_rep_type(icmp, MemtableRepType::Skiplist, BlockArena::new())
+    }
+
+    /// Creates a `MemTable` backed by the rep of the given type allocating from `arena`
+    pub fn with_rep_type(
+        icmp: Arc<InternalKeyComparator>,
+        rep_type: MemtableRepType,
+        arena: BlockArena,
+    ) -> Self {
+        let kcmp = Arc::new(KeyComparator { icmp });
+        let rep: Arc<dyn MemtableRep> = match rep_type {
+            MemtableRepType::Skiplist => Arc::new(SkiplistRep::new(kcmp.clone(), arena)),
+            MemtableRepType::HashSkiplist { bucket_count } => {
+                Arc::new(HashSkiplistRep::new(kcmp.clone(), bucket_count, arena))
+            }
+            MemtableRepType::Vector => Arc::new(VectorRep::new(kcmp.clone(), arena)),
+        };
+        Self {
+            cmp: kcmp,
+            rep,
+            counts: Arc::new(EntryCounts::default()),
+        }
+    }
+}
+
+impl<R: MemtableRep> MemTable<R> {
+    /// Creates a `MemTable` with the rep built from the comparator of the encoded entries
+    pub fn with_rep<F: FnOnce(Arc<dyn Comparator>) -> R>(
+        icmp: Arc<InternalKeyComparator>,
+        f: F,
+    ) -> Self {
         let kcmp = Arc::new(KeyComparator { icmp });
-        let table = Arc::new(Skiplist::new(kcmp.clone(), Box::new(arena)));
-        Self { cmp: kcmp, table }
+        let rep = Arc::new(f(kcmp.clone()));
+        Self {
+            cmp: kcmp,
+            rep,
+            counts: Arc::new(EntryCounts::default()),
+        }
     }
 }
 
-impl MemoryTable for MemTable {
+impl<R: MemtableRep + ?Sized> MemoryTable for MemTable<R> {
     fn approximate_memory_usage(&self) -> usize {
-        self.table.arena.memory_used()
+        self.rep.memory_usage()
+    }
+
+    fn approximate_memory_allocated(&self) -> usize {
+        self.rep.memory_allocated()
     }
 
     fn iter(&self) -> Box<dyn Iterator> {
-        Box::new(MemTableIterator::new(self.table.clone()))
+        Box::new(MemTableIterator::new(self.rep.clone().iter()))
+    }
+
+    fn num_entries(&self) -> u64 {
+        self.counts.entries.load(AtomicOrdering::Relaxed)
+    }
+
+    fn num_deletions(&self) -> u64 {
+        self.counts.deletions.load(AtomicOrdering::Relaxed)
     }
 
     fn add(&self, seq_number: u64, val_type: ValueType, key: &[u8], value: &[u8]) {
@@ -135,20 +212,23 @@ impl MemoryTable for MemTable {
         buf.extend_from_slice(key);
         put_fixed_64(&mut buf, (seq_number << 8) | val_type as u64);
         VarintU32::put_varint_prefixed_slice(&mut buf, value);
-        self.table.insert(buf);
+        self.rep.insert(buf);
+        self.counts.entries.fetch_add(1, AtomicOrdering::Relaxed);
+        if val_type == ValueType::Deletion {
+            self.counts.deletions.fetch_add(1, AtomicOrdering::Relaxed);
+        }
     }
 
     fn get(&self, key: &LookupKey) -> Option<Result<Slice>> {
-        let mk = key.mem_key();
-        // internal key
-        let mut iter = self.iter();
-        iter.seek(&mk);
+        let user_key = key.user_key();
+        let mut iter = MemTableIterator::new(self.rep.clone().lookup_iter(user_key.as_slice()));
+        iter.seek(&key.internal_key());
         if iter.valid() {
             let internal_key = iter.key();
             // only check the user key here
             match self.cmp.icmp.user_comparator.compare(
                 Slice::new(internal_key.as_ptr(), internal_key.size() - 8).as_slice(),
-                key.user_key().as_slice(),
+                user_key.as_slice(),
             ) {
                 Ordering::Equal => {
                     let tag = decode_fixed_64(&internal_key.as_slice()[internal_key.size() - 8..]);
@@ -167,18 +247,20 @@ impl MemoryTable for MemTable {
     }
 }
 
-pub struct MemTableIterator {
-    iter: SkiplistIterator,
+/// Iteration over a `MemTable` decoding the entries yielded by the iterator of the rep
+pub struct MemTableIterator<I: Iterator = Box<dyn Iterator>> {
+    iter: I,
+    // The buffer of the encoded seek target
+    tmp: Vec<u8>,
 }
 
-impl MemTableIterator {
-    pub fn new(table: Arc<Skiplist>) -> Self {
-        let iter = SkiplistIterator::new(table);
-        Self { iter }
+impl<I: Iterator> MemTableIterator<I> {
+    pub fn new(iter: I) -> Self {
+        Self { iter, tmp: vec![] }
     }
 }
 
-impl Iterator for MemTableIterator {
+impl<I: Iterator> Iterator for MemTableIterator<I> {
     fn valid(&self) -> bool {
         self.iter.valid()
     }
@@ -191,8 +273,12 @@ impl Iterator for MemTableIterator {
         self.iter.seek_to_last()
     }
 
+    // `target` is an internal key while the entries in the rep start
+    // with the length prefixed internal keys
     fn seek(&mut self, target: &Slice) {
-        self.iter.seek(target)
+        self.tmp.clear();
+        VarintU32::put_varint_prefixed_slice(&mut self.tmp, target.as_slice());
+        self.iter.seek(&Slice::from(self.tmp.as_slice()))
     }
 
     fn next(&mut self) {
@@ -216,7 +302,7 @@ impl Iterator for MemTableIterator {
     }
 
     fn status(&mut self) -> Result<()> {
-        Ok(())
+        self.iter.status()
     }
 }
 
@@ -232,16 +318,30 @@ fn extract_varint32_encoded_slice(origin: &mut Slice) -> Slice {
 #[cfg(test)]
 mod tests {
     use crate::db::format::{InternalKeyComparator, LookupKey, ParsedInternalKey, ValueType};
-    use crate::mem::{MemTable, MemoryTable};
+    use crate::mem::{BlockArena, MemTable, MemoryTable};
+    use crate::options::MemtableRepType;
     use crate::util::comparator::BytewiseComparator;
     use crate::util::status::Status;
     use std::sync::Arc;
 
-    fn new_mem_table() -> MemTable {
+    fn new_mem_tables() -> Vec<MemTable> {
         let icmp = Arc::new(InternalKeyComparator::new(Arc::new(
             BytewiseComparator::new(),
         )));
-        MemTable::new(icmp)
+        vec![
+            MemTable::new(icmp.clone()),
+            MemTable::with_rep_type(
+                icmp.clone(),
+                MemtableRepType::HashSkiplist { bucket_count: 1 },
+                BlockArena::new(),
+            ),
+            MemTable::with_rep_type(
+                icmp.clone(),
+                MemtableRepType::HashSkiplist { bucket_count: 16 },
+                BlockArena::new(),
+            ),
+            MemTable::with_rep_type(icmp, MemtableRepType::Vector, BlockArena::new()),
+        ]
     }
 
     fn add_test_data_set(memtable: &MemTable) -> Vec<(&str, &str)> {
@@ -262,7 +362,12 @@ mod tests {
 
     #[test]
     fn test_memtable_add_get() {
-        let memtable = new_mem_table();
+        for memtable in new_mem_tables() {
+            check_memtable_add_get(&memtable);
+        }
+    }
+
+    fn check_memtable_add_get(memtable: &MemTable) {
         memtable.add(1, ValueType::Value, b"foo", b"val1");
         memtable.add(2, ValueType::Value, b"foo", b"val2");
         memtable.add(3, ValueType::Deletion, b"foo", b"");
@@ -285,10 +390,19 @@ mod tests {
 
     #[test]
     fn test_memtable_iter() {
-        let memtable = new_mem_table();
+        for (i, memtable) in new_mem_tables().iter().enumerate() {
+            // Only the skiplist iterator sees the entries added after it's created
+            check_memtable_iter(memtable, i != 0);
+        }
+    }
+
+    fn check_memtable_iter(memtable: &MemTable, snapshot: bool) {
         let mut iter = memtable.iter();
         assert!(!iter.valid());
         let entries = add_test_data_set(&memtable);
+        if snapshot {
+            iter = memtable.iter();
+        }
         // Forward scan
         iter.seek_to_first();
         assert!(iter.valid());
@@ -334,5 +448,59 @@ mod tests {
             iter.prev();
         }
         assert!(!iter.valid());
+
+        // Seek by internal keys
+        for (target, seq, expected) in [
+            ("foo", 3, Some(("foo", 3))),
+            ("bz", 9, Some(("foo", 4))),
+            ("g", 9, None),
+        ]
+        .iter()
+        {
+            let ikey = LookupKey::new(target.as_bytes(), *seq);
+            iter.seek(&ikey.internal_key());
+            match expected {
+                Some((key, seq)) => {
+                    let pkey = ParsedInternalKey::decode_from(iter.key()).unwrap();
+                    assert_eq!(pkey.user_key.as_str(), *key);
+                    assert_eq!(pkey.seq, *seq);
+                }
+                None => assert!(!iter.valid()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_memtable_rep_snapshot_iter() {
+        let icmp = Arc::new(InternalKeyComparator::new(Arc::new(
+            BytewiseComparator::new(),
+        )));
+        let memtable = MemTable::with_rep_type(icmp, MemtableRepType::Vector, BlockArena::new());
+        memtable.add(1, ValueType::Value, b"b", b"b");
+        memtable.add(2, ValueType::Value, b"a", b"a");
+        let mut iter = memtable.iter();
+        memtable.add(3, ValueType::Value, b"c", b"c");
+        iter.seek_to_first();
+        let mut keys = vec![];
+        while iter.valid() {
+            keys.push(
+                ParsedInternalKey::decode_from(iter.key())
+                    .unwrap()
+                    .user_key
+                    .as_str()
+                    .to_owned(),
+            );
+            iter.next();
+        }
+        assert_eq!(keys, vec!["a", "b"]);
+        assert_eq!(
+            b"c",
+            memtable
+                .get(&LookupKey::new(b"c", 3))
+                .unwrap()
+                .unwrap()
+                .as_slice()
+        );
+        assert!(memtable.approximate_memory_usage() > 0);
     }
 }
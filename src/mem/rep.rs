@@ -0,0 +1,290 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::arena::{Arena, BlockArena};
+use super::extract_varint32_encoded_slice;
+use super::skiplist::{Skiplist, SkiplistIterator};
+use crate::iterator::{EmptyIterator, Iterator};
+use crate::util::comparator::Comparator;
+use crate::util::hash::hash;
+use crate::util::slice::Slice;
+use crate::util::status::Result;
+use std::cmp::Ordering;
+use std::ptr::copy_nonoverlapping;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// `MemtableRep` is the underlying structure holding the encoded entries of a `MemTable`.
+///
+/// The entries are compared by the comparator given when the rep is created.
+/// Only one thread inserts at a time while others may read concurrently.
+pub trait MemtableRep {
+    /// Inserts the encoded entry. The entry must be unique.
+    fn insert(&self, entry: Vec<u8>);
+
+    /// Returns the bytes allocated for the entries
+    fn memory_usage(&self) -> usize;
+
+    /// Returns the bytes reserved by the rep, including the unused memory
+    fn memory_allocated(&self) -> usize;
+
+    /// Returns an iterator that yields all the entries in order
+    fn iter(self: Arc<Self>) -> Box<dyn Iterator>;
+
+    /// Returns an iterator that yields at least all the entries of `user_key` in order.
+    /// It's used by the point lookups so the rep can search only a part of the entries.
+    fn lookup_iter(self: Arc<Self>, _user_key: &[u8]) -> Box<dyn Iterator> {
+        self.iter()
+    }
+}
+
+// Copies the entry into the arena and returns the slice pointing to the copy
+fn allocate_entry(arena: &dyn Arena, entry: &[u8]) -> Slice {
+    let p = arena.allocate(entry.len());
+    unsafe {
+        copy_nonoverlapping(entry.as_ptr(), p, entry.len());
+    }
+    Slice::new(p as *const u8, entry.len())
+}
+
+/// The default rep backed by a single skiplist
+pub struct SkiplistRep {
+    table: Arc<Skiplist>,
+}
+
+impl SkiplistRep {
+    pub fn new(cmp: Arc<dyn Comparator>, arena: BlockArena) -> Self {
+        Self {
+            table: Arc::new(Skiplist::new(cmp, Box::new(arena))),
+        }
+    }
+}
+
+impl MemtableRep for SkiplistRep {
+    fn insert(&self, entry: Vec<u8>) {
+        self.table.insert(entry)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.table.arena.memory_used()
+    }
+
+    fn memory_allocated(&self) -> usize {
+        self.table.arena.memory_allocated()
+    }
+
+    fn iter(self: Arc<Self>) -> Box<dyn Iterator> {
+        Box::new(SkiplistIterator::new(self.table.clone()))
+    }
+}
+
+/// A rep hashing the entries into buckets by the user key and each bucket is a
+/// skiplist. A point lookup only searches the bucket of the key but a full
+/// iteration has to sort the entries of all the buckets first.
+pub struct HashSkiplistRep {
+    cmp: Arc<dyn Comparator>,
+    // All the buckets share the arena
+    arena: Arc<BlockArena>,
+    buckets: Vec<OnceLock<Arc<Skiplist>>>,
+}
+
+impl HashSkiplistRep {
+    pub fn new(cmp: Arc<dyn Comparator>, bucket_count: usize, arena: BlockArena) -> Self {
+        let bucket_count = bucket_count.max(1);
+        let mut buckets = Vec::with_capacity(bucket_count);
+        buckets.resize_with(bucket_count, OnceLock::new);
+        Self {
+            cmp,
+            arena: Arc::new(arena),
+            buckets,
+        }
+    }
+
+    fn bucket_of(&self, user_key: &[u8]) -> &OnceLock<Arc<Skiplist>> {
+        &self.buckets[hash(user_key, 0) as usize % self.buckets.len()]
+    }
+}
+
+impl MemtableRep for HashSkiplistRep {
+    fn insert(&self, entry: Vec<u8>) {
+        let internal_key = extract_varint32_encoded_slice(&mut Slice::from(entry.as_slice()));
+        let user_key = &internal_key.as_slice()[..internal_key.size() - 8];
+        self.bucket_of(user_key)
+            .get_or_init(|| {
+                Arc::new(Skiplist::new(
+                    self.cmp.clone(),
+                    Box::new(self.arena.clone()),
+                ))
+            })
+            .insert(entry)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.arena.memory_used()
+    }
+
+    fn memory_allocated(&self) -> usize {
+        self.arena.memory_allocated()
+    }
+
+    fn iter(self: Arc<Self>) -> Box<dyn Iterator> {
+        let mut entries = vec![];
+        for bucket in self.buckets.iter() {
+            if let Some(table) = bucket.get() {
+                let mut iter = SkiplistIterator::new(table.clone());
+                iter.seek_to_first();
+                while iter.valid() {
+                    entries.push(iter.key());
+                    iter.next();
+                }
+            }
+        }
+        let cmp = self.cmp.clone();
+        entries.sort_by(|a, b| cmp.compare(a.as_slice(), b.as_slice()));
+        Box::new(SortedEntriesIterator::new(self, cmp, Arc::new(entries)))
+    }
+
+    fn lookup_iter(self: Arc<Self>, user_key: &[u8]) -> Box<dyn Iterator> {
+        match self.bucket_of(user_key).get() {
+            Some(table) => Box::new(SkiplistIterator::new(table.clone())),
+            None => Box::new(EmptyIterator::new()),
+        }
+    }
+}
+
+/// A rep appending the entries to a vector which is sorted when the rep is read.
+/// It's the cheapest for the pure bulk loads that don't read the memtable
+/// until it's flushed.
+pub struct VectorRep {
+    cmp: Arc<dyn Comparator>,
+    arena: BlockArena,
+    // The entries point to the arena and whether they are sorted
+    entries: Mutex<(Arc<Vec<Slice>>, bool)>,
+}
+
+impl VectorRep {
+    pub fn new(cmp: Arc<dyn Comparator>, arena: BlockArena) -> Self {
+        Self {
+            cmp,
+            arena,
+            entries: Mutex::new((Arc::new(vec![]), true)),
+        }
+    }
+}
+
+impl MemtableRep for VectorRep {
+    fn insert(&self, entry: Vec<u8>) {
+        let slice = allocate_entry(&self.arena, &entry);
+        let mut entries = self.entries.lock().unwrap();
+        // Copies the pointers if there are iterators using the current entries
+        Arc::make_mut(&mut entries.0).push(slice);
+        entries.1 = false;
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.arena.memory_used()
+    }
+
+    fn memory_allocated(&self) -> usize {
+        self.arena.memory_allocated()
+    }
+
+    fn iter(self: Arc<Self>) -> Box<dyn Iterator> {
+        let snapshot = {
+            let mut entries = self.entries.lock().unwrap();
+            if !entries.1 {
+                let cmp = self.cmp.clone();
+                Arc::make_mut(&mut entries.0)
+                    .sort_by(|a, b| cmp.compare(a.as_slice(), b.as_slice()));
+                entries.1 = true;
+            }
+            entries.0.clone()
+        };
+        let cmp = self.cmp.clone();
+        Box::new(SortedEntriesIterator::new(self, cmp, snapshot))
+    }
+}
+
+/// Iteration over a sorted snapshot of the entries. The rep is held to keep
+/// the memory of the entries alive.
+struct SortedEntriesIterator {
+    _rep: Arc<dyn MemtableRep>,
+    cmp: Arc<dyn Comparator>,
+    entries: Arc<Vec<Slice>>,
+    // `entries.len()` means invalid
+    pos: usize,
+}
+
+impl SortedEntriesIterator {
+    fn new(rep: Arc<dyn MemtableRep>, cmp: Arc<dyn Comparator>, entries: Arc<Vec<Slice>>) -> Self {
+        let pos = entries.len();
+        Self {
+            _rep: rep,
+            cmp,
+            entries,
+            pos,
+        }
+    }
+}
+
+impl Iterator for SortedEntriesIterator {
+    fn valid(&self) -> bool {
+        self.pos < self.entries.len()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.pos = 0;
+    }
+
+    fn seek_to_last(&mut self) {
+        self.pos = if self.entries.is_empty() {
+            0
+        } else {
+            self.entries.len() - 1
+        };
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        let cmp = &self.cmp;
+        self.pos = self
+            .entries
+            .partition_point(|e| cmp.compare(e.as_slice(), target.as_slice()) == Ordering::Less);
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid());
+        self.pos += 1;
+    }
+
+    fn prev(&mut self) {
+        assert!(self.valid());
+        self.pos = if self.pos == 0 {
+            self.entries.len()
+        } else {
+            self.pos - 1
+        };
+    }
+
+    fn key(&self) -> Slice {
+        assert!(self.valid());
+        self.entries[self.pos].clone()
+    }
+
+    /// Should not be used
+    fn value(&self) -> Slice {
+        unimplemented!()
+    }
+
+    fn status(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
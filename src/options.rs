@@ -17,21 +17,35 @@
 
 use crate::cache::lru::SharedLRUCache;
 use crate::cache::Cache;
-use crate::db::filename::{generate_filename, FileType};
-use crate::filter::FilterPolicy;
+use crate::compaction::CompactionFilter;
+use crate::db::filename::{generate_filename, FileType, TableFileNaming};
+use crate::db::format::{InternalKey, MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK};
+use crate::filter::bloom::BloomFilter;
+use crate::filter::{FilterPolicy, PrefixExtractor};
+use crate::listener::EventListener;
 use crate::logger::Logger;
 use crate::options::CompressionType::{NoCompression, SnappyCompression, Unknown};
+use crate::rate_limiter::RateLimiter;
+use crate::remote_compaction::CompactionService;
 use crate::snapshot::Snapshot;
 use crate::sstable::block::Block;
+use crate::sstable::dictionary::DictionaryCompressor;
+use crate::sstable::encryption::BlockCipher;
+use crate::sstable::{LATEST_FORMAT_VERSION, TABLE_MAGIC_NUMBER};
+use crate::statistics::Statistics;
 use crate::storage::file::FileStorage;
-use crate::storage::Storage;
+use crate::storage::{File, Storage};
+use crate::util::clock::{Clock, SystemClock};
 use crate::util::comparator::{BytewiseComparator, Comparator};
+use crate::util::slice::Slice;
+use crate::util::status::{Result, Status, WickErr};
+use crate::write_hook::{KeyClassifier, PreWriteHook};
 use crate::LevelFilter;
 use crate::Log;
-use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CompressionType {
     NoCompression = 0,
     SnappyCompression = 1,
@@ -48,6 +62,63 @@ impl From<u8> for CompressionType {
     }
 }
 
+/// The checksum stored in the trailer of every block of the tables.
+///
+/// The type is recorded in the block type of the trailer so that the tables
+/// built with different types could be read by the same db. The blocks with
+/// `Crc32c` are laid out as before the type is recorded and stay readable by
+/// the older versions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumType {
+    /// The masked CRC32C, accelerated by SSE4.2 on x86-64 and the CRC extension
+    /// on ARM when available
+    Crc32c = 0,
+    /// The low 32 bits of the xxHash64, which beats the CRC32C computed without
+    /// the hardware acceleration
+    XxHash64 = 1,
+}
+
+/// The structure holding the entries of a memtable
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemtableRepType {
+    /// A skiplist suits most workloads
+    Skiplist,
+    /// Hashes the user keys into `bucket_count` skiplists. The point lookups only
+    /// search the bucket of the key but the iterators have to sort all the entries
+    /// first so it suits the workloads dominated by point lookups.
+    HashSkiplist { bucket_count: usize },
+    /// Appends the entries to a vector which is sorted when the memtable is read.
+    /// It suits the pure bulk loads that rarely read until the memtable is flushed.
+    Vector,
+}
+
+/// When the files of a class are synced to the storage
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Syncs after every write into the file
+    Always,
+    /// Syncs once the file is finished
+    OnClose,
+    /// Never syncs, which leaves the durability to the storage or the replication
+    Never,
+}
+
+/// How thoroughly the table files of a db are validated when it's opened.
+/// Every level checks that the table files named by the MANIFEST exist.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsistencyCheckLevel {
+    /// No more checks
+    Off,
+    /// Checks the footers and the magic numbers of the most recently written
+    /// tables, which are the most likely to be torn by a crash
+    Quick,
+    /// Checks the footers of all the tables and their sizes against the MANIFEST
+    Standard,
+    /// Like `Standard` plus the checksums of all the blocks, which reads every
+    /// table entirely
+    Full,
+}
+
 /// Options to control the behavior of a database (passed to `DB::Open`)
 pub struct Options {
     // -------------------
@@ -73,8 +144,18 @@ pub struct Options {
     /// become unreadable or for the entire DB to become unopenable.
     pub paranoid_checks: bool,
 
+    /// The validation of the table files on opening the db, which trades the
+    /// startup time for the assurance after a suspicious crash. A failed check
+    /// refuses to open the db with `Status::Corruption`.
+    /// Default: `ConsistencyCheckLevel::Off`
+    pub consistency_check_level: ConsistencyCheckLevel,
+
     /// Use the specified object to interact with the environment,
     pub env: Arc<dyn Storage>,
+
+    /// The source of time used for the timestamps, durations and sleeps of the DB.
+    /// Default: `SystemClock`
+    pub clock: Arc<dyn Clock>,
     // -------------------
     // Parameters that affect compaction:
     /// The max number of levels except L)
@@ -91,11 +172,47 @@ pub struct Options {
     /// threshold is reached.
     pub l0_stop_writes_threshold: usize,
 
+    /// How long a write is delayed when a soft limit (`l0_slowdown_writes_threshold`
+    /// or `soft_pending_compaction_bytes_limit`) is reached. A single write is
+    /// delayed at most once.
+    pub write_slowdown_micros: u64,
+
+    /// Soft limit on the estimated bytes compactions need to rewrite to bring
+    /// every level under its target size. Writes are slowed down when this
+    /// threshold is reached. Zero disables the limit.
+    pub soft_pending_compaction_bytes_limit: u64,
+
+    /// Hard limit on the estimated pending compaction bytes. Writes are stopped
+    /// when this threshold is reached. Zero disables the limit.
+    pub hard_pending_compaction_bytes_limit: u64,
+
+    /// If non-zero, the db runs in the FIFO mode capping the total size of the
+    /// table files for bounded-disk deployments. The memtables are always flushed
+    /// to level 0, no major compaction runs and no write is stalled by the level 0
+    /// files or the pending compaction bytes. Instead, the oldest table files are
+    /// deleted whenever the total size exceeds the cap, which drops the oldest data.
+    /// Default: 0
+    pub fifo_max_table_files_size: u64,
+
     /// The maximum number of bytes for L1. The maximum number of bytes for other
     /// levels is computed dynamically based on this value. When the maximum
     /// number of bytes for a level is exceeded, compaction is requested.
     pub l1_max_bytes: u64,
 
+    /// If true, the target sizes of the levels are derived from the size of the
+    /// largest level instead of `l1_max_bytes`, as RocksDB does. The last level is
+    /// sized to hold the largest level and every level above it is 10 times
+    /// smaller, up to the base level whose target is the first one under
+    /// `l1_max_bytes * 10`. The levels above the base level are kept empty, so the
+    /// data is always compacted into the last level first and the space
+    /// amplification stays around 1.1 no matter how large the db grows.
+    ///
+    /// It could be switched on or off for an existing db at any open. The files
+    /// in the levels above the base level are then compacted down level by level,
+    /// mostly by moving the files, until the shape of the levels fits the targets.
+    /// Default: false
+    pub level_compaction_dynamic_level_bytes: bool,
+
     /// Maximum level to which a new compacted memtable is pushed if it
     /// does not create overlap.  We try to push to level 2 to avoid the
     /// relatively expensive level 0=>1 compactions and to avoid some
@@ -104,26 +221,177 @@ pub struct Options {
     /// space if the same key space is being repeatedly overwritten.
     pub max_mem_compact_level: usize,
 
-    /// Approximate gap in bytes between samples of data read during iteration
+    /// If true, a file is compacted after too many reads pass through it to
+    /// the deeper levels, counted by the seeks of `get` and the read samples
+    /// of iterators. This reduces the read amplification of the key ranges
+    /// read frequently even when the size triggers don't fire.
+    /// Default: true
+    pub seek_compaction: bool,
+
+    /// A new file is allowed `file_size / bytes_per_seek` seeks, but at least
+    /// `min_allowed_seeks`, before being compacted. LevelDB assumes a seek
+    /// costs approximately the same as the compaction of 40KB data, and is
+    /// a little conservative by charging a seek for every 16KB.
+    /// Default: 16KB
+    pub bytes_per_seek: u64,
+
+    /// See `bytes_per_seek`
+    /// Default: 100
+    pub min_allowed_seeks: usize,
+
+    /// If positive, a file no deeper level overlaps is compacted into the next
+    /// level once the ratio of the deletions to all the entries in its table
+    /// properties reaches it, which drops the tombstones and the data they
+    /// shadow so that the deletes reclaim space even if no more writes land
+    /// on the key range. The files in the last level are never picked.
+    /// Default: 0
+    pub bottommost_deletion_ratio: f64,
+
+    /// If positive, a file is compacted once it's older than this many seconds by
+    /// the creation time in its table properties, even if no level exceeds its
+    /// size limit, so that `compaction_filter` eventually sees the cold data too.
+    /// A file in the last level is rewritten in place. The files are checked
+    /// whenever a compaction is considered and at least every
+    /// `min(periodic_compaction_seconds, 3600)` seconds. The files without the
+    /// creation time are never picked.
+    /// Default: 0
+    pub periodic_compaction_seconds: u64,
+
+    /// Approximate gap in bytes between samples of data read during iteration.
+    /// Zero disables the read sampling.
     pub read_bytes_period: u64,
 
+    /// Maximum number of concurrent background jobs. Memtable flushes and
+    /// compactions share these workers, and compactions whose inputs or
+    /// output key ranges overlap never run at the same time.
+    pub max_background_compactions: usize,
+
+    /// Maximum number of threads a single level 0 compaction is split into.
+    /// The key range of the compaction is divided into disjoint subranges
+    /// compacted in parallel, and all the outputs are installed at once.
+    pub max_subcompactions: usize,
+
+    /// If positive, the write rate is checked every this many micros and the db is
+    /// considered idle when it's below `idle_write_bytes_per_sec`. An idle db with
+    /// no other background work compacts all the level0 files into level1 even
+    /// below `l0_compaction_trigger`, and opens the tables of the current version
+    /// in the table cache to load their index and filter blocks, so the compaction
+    /// debt is paid in the quiet periods instead of the busy ones.
+    /// Default: 0 (no idle detection)
+    pub idle_check_interval_micros: u64,
+
+    /// The write rate in bytes per second below which the db is considered idle.
+    /// See `idle_check_interval_micros`.
+    /// Default: 64KB
+    pub idle_write_bytes_per_sec: u64,
+
     // -------------------
     // Parameters that affect performance:
     /// Amount of data to build up in memory (backed by an unsorted log
     /// on disk) before converting to a sorted on-disk file.
     ///
     /// Larger values increase performance, especially during bulk loads.
-    /// Up to two write buffers may be held in memory at the same time,
-    /// so you may wish to adjust this parameter to control memory usage.
+    /// Up to `max_write_buffer_number` write buffers may be held in memory at the
+    /// same time, so you may wish to adjust this parameter to control memory usage.
     /// Also, a larger write buffer will result in a longer recovery time
     /// the next time the database is opened.
     pub write_buffer_size: usize,
 
+    /// The maximum number of the write buffers held in memory, including the one
+    /// being written. A full write buffer becomes immutable and waits for the flush,
+    /// and the writes stall only when all the others are waiting too, so a larger
+    /// number absorbs the bursts of writes while a slow flush is running.
+    /// Default: 2
+    pub max_write_buffer_number: usize,
+
+    /// The structure of the memtables.
+    /// Default: `MemtableRepType::Skiplist`
+    pub memtable_rep: MemtableRepType,
+
+    /// The size of the blocks the memtable arena reserves at a time. The memory
+    /// usage compared with `write_buffer_size` only counts the allocated bytes so
+    /// a larger block just reserves memory in advance.
+    /// Default: 4KB
+    pub arena_block_size: usize,
+
+    /// If positive, the memtable arena maps its blocks from the huge pages of this
+    /// size (Linux only) and `arena_block_size` is rounded up to a multiple of it.
+    /// The huge pages must be reserved in the system otherwise the heap is used.
+    /// Default: 0 (disabled)
+    pub memtable_huge_page_size: usize,
+
+    /// If positive, the WAL fsync of a sync write group is delayed for up to this
+    /// many micros so that the write groups arriving meanwhile are written into the
+    /// WAL and synced together by a single fsync. This reduces the fsyncs under
+    /// highly concurrent sync writes at the cost of the latency of a lone writer.
+    /// Default: 0 (every sync write group is synced on its own)
+    pub wal_sync_max_delay_micros: u64,
+
+    /// If positive, the write group leader also syncs the WAL once this many bytes
+    /// have been written into it since the last sync, even if no write in the group
+    /// asks for `WriteOptions::sync`. This bounds the writes a machine crash could
+    /// lose without paying a fsync for every write.
+    /// Default: 0 (only the sync writes sync the WAL)
+    pub wal_bytes_per_sync: u64,
+
+    /// If positive, a background thread syncs the WAL every this many micros if
+    /// anything has been written into it since the last sync.
+    /// Default: 0 (no periodic WAL sync)
+    pub wal_sync_interval_micros: u64,
+
+    /// When the WAL is synced:
+    /// * `Always` - after every write group as if every write sets `WriteOptions::sync`
+    /// * `OnClose` - when the WAL is switched or the db is dropped, and as asked by
+    ///   `WriteOptions::sync`, `wal_bytes_per_sync` and `wal_sync_interval_micros`
+    /// * `Never` - only by `WickDB::flush_wal`
+    ///
+    /// Default: `SyncPolicy::OnClose`
+    pub wal_sync_policy: SyncPolicy,
+
+    /// When the MANIFEST and the CURRENT are synced:
+    /// * `Always` - after every version edit is logged
+    /// * `OnClose` - when a new MANIFEST is installed and when the db is dropped,
+    ///   so the edits in between might be lost by a machine crash
+    /// * `Never` - never
+    ///
+    /// Default: `SyncPolicy::Always`
+    pub manifest_sync_policy: SyncPolicy,
+
+    /// When the table files are synced:
+    /// * `Always` - after every data block is written and when the table is finished
+    /// * `OnClose` - when the table is finished
+    /// * `Never` - never, e.g. when the tables are able to be rebuilt from the replicas
+    ///
+    /// Default: `SyncPolicy::OnClose`
+    pub table_sync_policy: SyncPolicy,
+
+    /// If true, `DB::close` flushes the memtables into table files before stopping
+    /// the background work, so that the next open has no log to replay and the
+    /// writes with `WriteOptions::disable_wal` survive. Otherwise the memtables
+    /// are abandoned and recovered from the WAL by the next open.
+    /// Default: false
+    pub flush_on_close: bool,
+
     /// Number of open files that can be used by the DB.  You may need to
     /// increase this if your database has a large working set (budget
     /// one open file per 2MB of working set).
     pub max_open_files: usize,
 
+    /// If true, the table files are opened by `Storage::open_mmap` so that the
+    /// block reads are served from the memory mapped files without syscalls.
+    /// It falls back to the normal reads where mmap is unavailable, like on the
+    /// 32-bit platforms. The WAL and MANIFEST files are always written by the
+    /// normal buffered writes.
+    /// Default: false
+    pub use_mmap_reads: bool,
+
+    /// If true, the table files written by flushes and compactions and the
+    /// inputs read by compactions bypass the OS page cache by `Storage::create_direct`
+    /// and `Storage::open_direct` (e.g. `O_DIRECT` on Linux), so that a large
+    /// compaction doesn't evict the hot pages of the files being read by users.
+    /// Default: false
+    pub use_direct_io_for_flush_and_compaction: bool,
+
     // -------------------
     // Control over blocks (user data is stored in a set of blocks, and
     // a block is the unit of reading from disk).
@@ -145,6 +413,26 @@ pub struct Options {
     /// leave this parameter alone.
     pub block_restart_interval: usize,
 
+    /// Same as `block_restart_interval` but for the index blocks.
+    /// A larger value makes the index block smaller at the cost of a
+    /// slower binary search in it.
+    pub index_block_restart_interval: usize,
+
+    /// Number of adjacent data blocks sharing one index entry.
+    /// A larger value makes the index block smaller but a point lookup may
+    /// have to read several data blocks in a group before finding the key.
+    /// The blocks are never grouped in the tables of `LEGACY_FORMAT_VERSION`,
+    /// see `format_version`.
+    pub blocks_per_index_entry: usize,
+
+    /// Overrides the block layout parameters above together with
+    /// `bloom_bits_per_key` and `whole_key_filtering` for tables in the
+    /// level at the same position. Levels beyond the end of this vector use
+    /// the options of the db.
+    ///
+    /// Tables generated by flushing a memtable always use the layout of level 0.
+    pub per_level_table_options: Vec<LevelTableOptions>,
+
     /// The DB will write up to this amount of bytes to a file before
     /// switching to a new one.
     /// Most clients should leave this parameter alone.  However if your
@@ -155,10 +443,83 @@ pub struct Options {
     /// initially populating a large database.
     pub max_file_size: u64,
 
+    /// The naming scheme of the table files, see `TableFileNaming`.
+    /// The scheme can be changed between the openings of a db.
+    pub table_file_naming: TableFileNaming,
+
     /// Compress blocks using the specified compression algorithm.  This
     /// parameter can be changed dynamically. Default is SnappyCompression.
     pub compression: CompressionType,
 
+    /// The checksum of the blocks written into the tables. The blocks of any
+    /// checksum type are readable regardless of this option. Only CRC32C is
+    /// used by the tables of `LEGACY_FORMAT_VERSION`.
+    /// Default: Crc32c
+    pub checksum_type: ChecksumType,
+
+    /// The minimum ratio of the raw size to the compressed size for a block to
+    /// be stored compressed. A block compressed worse than this is stored raw
+    /// so that reading it doesn't pay for the decompression. A large block is
+    /// sampled first and not compressed at all if the samples are already
+    /// incompressible, which saves the CPU on compressed payloads like images.
+    /// Zero keeps every compressed block.
+    /// Default: 0.0
+    pub min_compression_ratio: f64,
+
+    /// If set, the data blocks of the tables built by the compactions are
+    /// compressed by this compressor with a dictionary trained from the first
+    /// `compression_dict_train_bytes` of every output, instead of by `compression`.
+    /// The dictionary is saved in the meta block of the table and reading the
+    /// table requires the same compressor. The memtable flushes are never delayed
+    /// by the training and use `compression`.
+    /// Default: None
+    pub dictionary_compressor: Option<Arc<dyn DictionaryCompressor>>,
+
+    /// The maximum size of a compression dictionary.
+    /// See `dictionary_compressor`.
+    /// Default: 16KB
+    pub compression_dict_max_bytes: usize,
+
+    /// The bytes of the raw entries buffered to train the compression dictionary
+    /// of a table. The buffered entries are written after the training so a larger
+    /// value trains a better dictionary but holds more memory per compaction.
+    /// See `dictionary_compressor`.
+    /// Default: 256KB
+    pub compression_dict_train_bytes: usize,
+
+    /// If true, the histograms of the key sizes and the value sizes added to every
+    /// table are recorded in its meta block, and `WickDB::kv_size_histograms`
+    /// aggregates them over the tables, which helps tune the block size and the
+    /// filters with the real distribution of the data. The key sizes of the tables
+    /// built by the DB are the sizes of the user keys.
+    /// Default: false
+    pub record_kv_size_histograms: bool,
+
+    /// If set, the contents of the data blocks written by flushes and compactions
+    /// are encrypted by this cipher after the compression, with a tweak derived from
+    /// the file number and the offset of the block. The other blocks are left plain.
+    /// Reading the encrypted blocks requires the same cipher. Compactions are never
+    /// delegated to `compaction_service` since the output file numbers are unknown
+    /// to it.
+    /// Default: None
+    pub block_cipher: Option<Arc<dyn BlockCipher>>,
+
+    /// The magic number written into the footer of every table file and required
+    /// when a table is opened. An application deriving its own table format could
+    /// set its own magic number so that its tables and the default ones are never
+    /// opened by mistake by each other, while sharing the same reader and writer.
+    /// Changing it makes all the existing tables of the db unreadable.
+    /// Default: the magic number of LevelDB tables
+    pub table_magic_number: u64,
+
+    /// The format version written into the footer of every table file. The
+    /// tables of `LEGACY_FORMAT_VERSION` are readable by LevelDB, and ignore
+    /// `checksum_type`, `dictionary_compressor` and `blocks_per_index_entry`
+    /// when they are written. The tables of any version up to
+    /// `LATEST_FORMAT_VERSION` are readable regardless of this option.
+    /// Default: `LATEST_FORMAT_VERSION`
+    pub format_version: u32,
+
     /// If true, append to existing MANIFEST and log files when a database is opened.
     /// This can significantly speed up open.
     pub reuse_logs: bool,
@@ -166,7 +527,112 @@ pub struct Options {
     /// If non-null, use the specified filter policy to reduce disk reads.
     /// Many applications will benefit from passing the result of
     /// NewBloomFilterPolicy() here.
-    pub filter_policy: Option<Rc<dyn FilterPolicy>>,
+    pub filter_policy: Option<Arc<dyn FilterPolicy>>,
+
+    /// If not 0 and `filter_policy` is not set, the tables are filtered by a
+    /// bloom filter with this many bits per key. The levels could have their
+    /// own bits by `per_level_table_options`, which applies to any bloom
+    /// filter policy since the bloom filters with different bits read each
+    /// other.
+    /// Default: 0
+    pub bloom_bits_per_key: usize,
+
+    /// If true, the user keys are added into the filters so that the point
+    /// lookups skip the data blocks without the keys. Turning it off together
+    /// with a `prefix_extractor` leaves only the prefixes in the filters, which
+    /// makes them smaller but only serve the prefix seeks.
+    /// Default: true
+    pub whole_key_filtering: bool,
+
+    /// If true, the tables built by the compactions whose outputs are the
+    /// bottommost data of their key range carry no filters. It saves the space
+    /// of the largest filters when the point lookups mostly hit existing keys,
+    /// since a lookup reaching the bottommost tables is about to find its key
+    /// anyway.
+    /// Default: false
+    pub skip_bottommost_filters: bool,
+
+    /// If set together with `filter_policy`, the prefixes of the user keys are
+    /// added into the filters, and a seek whose keys up to
+    /// `ReadOptions::iterate_upper_bound` all share the prefix of the target
+    /// skips the tables and the levels without the prefix.
+    /// Default: None
+    pub prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+
+    /// If non-null, the filters generated for single data blocks are kept in
+    /// this cache. A compaction rewriting an identical data block copies
+    /// the cached filter instead of hashing all the keys again.
+    pub filter_reuse_cache: Option<Arc<dyn Cache<Vec<u8>>>>,
+
+    /// If not 0, the filters of a table are cut into partitions of about this
+    /// many bytes, each filtering a range of the data blocks, plus an index of
+    /// the partitions. Only the index is loaded on opening a table and the
+    /// partitions are read through `block_cache` when needed, which saves the
+    /// memory of the filters of large tables.
+    /// Default: 0
+    pub filter_partition_size: usize,
+
+    /// If non-null, the counters and latency histograms of block reads, block cache,
+    /// compactions, flushes and write stalls are recorded into it.
+    /// Default: None
+    pub statistics: Option<Arc<Statistics>>,
+
+    /// If non-null, the writes of table files built by flushes and compactions
+    /// and the WAL records of sync writes request tokens from it before being
+    /// issued, so the background I/O will not starve the foreground reads.
+    /// Default: None
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+
+    /// The listeners notified by the flushes, compactions, table file
+    /// creations and deletions and write stalls.
+    /// Default: empty
+    pub listeners: Vec<Arc<dyn EventListener>>,
+
+    /// If set, the major compactions are delegated to the service and only
+    /// fall back to running locally when the service fails.
+    /// See `remote_compaction` for details.
+    /// Default: None
+    pub compaction_service: Option<Arc<dyn CompactionService>>,
+
+    /// If set, the filter is applied on the key/value pairs during the major
+    /// compactions. See `CompactionFilter` for details.
+    /// The compactions always run locally when a filter is set since it can't
+    /// be shipped to the `compaction_service`.
+    /// Default: None
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+
+    /// If set, the keys of a write are grouped by the tenant prefixes it gives
+    /// before being passed to `pre_write_hooks`.
+    /// Default: None
+    pub key_classifier: Option<Arc<dyn KeyClassifier>>,
+
+    /// The hooks deciding whether a write is accepted, delayed or rejected
+    /// before it's queued. See `write_hook` for details.
+    /// Default: empty
+    pub pre_write_hooks: Vec<Arc<dyn PreWriteHook>>,
+
+    /// How often a secondary instance opened by `WickDB::open_as_secondary`
+    /// catches up with the primary in micros. Zero disables the periodic
+    /// catching up and `WickDB::try_catch_up_with_primary` has to be called
+    /// manually.
+    /// Default: 1s
+    pub secondary_catch_up_interval_micros: u64,
+
+    /// If non-zero, the owner of the db saves a lease of the lock in the `LOCK.lease`
+    /// file and renews it every third of this in micros. `WickDB::open_with_takeover`
+    /// takes over the lock whose lease isn't renewed for longer than this.
+    /// See `LockLease` for details.
+    /// Default: 0
+    pub lock_lease_expiry_micros: u64,
+
+    /// If true, a db opened by `WickDB::open_read_only` locks the `LOCK` file
+    /// shared until closed, so that it fails with `Status::Busy` if a writer has
+    /// opened the db, and the writers fail to open the db with `Status::Busy`
+    /// while any such read-only instance is open. It requires the `Storage` to
+    /// support `File::lock_region`, which the `FileStorage` does on Linux and
+    /// Windows only.
+    /// Default: false
+    pub lock_read_only: bool,
 
     /// The underlying logger default to a `LOG` file
     pub logger: Option<Box<dyn Log>>,
@@ -176,6 +642,20 @@ pub struct Options {
 }
 
 impl Options {
+    /// Returns the table parameters for tables in the given level
+    pub(crate) fn table_options_for_level(&self, level: usize) -> LevelTableOptions {
+        match self.per_level_table_options.get(level) {
+            Some(o) => *o,
+            None => LevelTableOptions {
+                block_restart_interval: self.block_restart_interval,
+                index_block_restart_interval: self.index_block_restart_interval,
+                blocks_per_index_entry: self.blocks_per_index_entry,
+                bloom_bits_per_key: self.bloom_bits_per_key,
+                whole_key_filtering: self.whole_key_filtering,
+            },
+        }
+    }
+
     /// Maximum number of bytes in all compacted files.  We avoid expanding
     /// the lower level file set of a compaction if it would make the
     /// total compaction cover more than this many bytes.
@@ -203,32 +683,56 @@ impl Options {
         result
     }
 
+    /// Creates a table file written by a flush or a compaction
+    pub(crate) fn create_table_file(&self, name: &str) -> Result<Box<dyn File>> {
+        if self.use_direct_io_for_flush_and_compaction {
+            self.env.create_direct(name)
+        } else {
+            self.env.create(name)
+        }
+    }
+
     /// Reserve `non_table_cache_files` files or so for other uses and give the rest to TableCache
     pub(crate) fn table_cache_size(&self) -> usize {
         self.max_open_files - self.non_table_cache_files
     }
 
     /// Initialize Options by limiting ranges of some flags, applying customized Logger and etc.
-    pub(crate) fn initialize(&mut self, db_name: String) {
+    // Sanitizes the options and sets up the logger. The default `LOG` file
+    // is created in `log_dir` or not created at all if `log_dir` is `None`.
+    pub(crate) fn initialize(&mut self, log_dir: Option<&str>) {
         self.max_open_files =
             Self::clip_range(self.max_open_files, 64 + self.non_table_cache_files, 50000);
         self.write_buffer_size = Self::clip_range(self.write_buffer_size, 64 << 10, 1 << 30);
+        self.arena_block_size = Self::clip_range(self.arena_block_size, 4 << 10, 1 << 30);
+        self.max_write_buffer_number = Self::clip_range(self.max_write_buffer_number, 2, 64);
         self.max_file_size = Self::clip_range(self.max_file_size, 1 << 20, 1 << 30);
         self.block_size = Self::clip_range(self.block_size, 1 << 10, 4 << 20);
+        self.max_background_compactions = Self::clip_range(self.max_background_compactions, 1, 64);
+        self.max_subcompactions = Self::clip_range(self.max_subcompactions, 1, 64);
+        self.bytes_per_seek = self.bytes_per_seek.max(1);
+        self.min_allowed_seeks = self.min_allowed_seeks.max(1);
 
-        if self.logger.is_none() {
-            let _ = self.env.mkdir_all(&db_name);
+        if let (None, Some(log_dir)) = (&self.logger, log_dir) {
+            let _ = self.env.mkdir_all(log_dir);
             if let Ok(f) = self
                 .env
-                .create(generate_filename(&db_name, FileType::InfoLog, 0).as_str())
+                .create(generate_filename(log_dir, FileType::InfoLog, 0).as_str())
             {
-                self.logger = Some(Box::new(Logger::new(f, self.logger_level)))
+                self.logger = Some(Box::new(Logger::new(
+                    f,
+                    self.logger_level,
+                    self.clock.clone(),
+                )))
             }
         }
         self.apply_logger();
         if self.block_cache.is_none() {
             self.block_cache = Some(Arc::new(SharedLRUCache::new(8 << 20)))
         }
+        if self.filter_policy.is_none() && self.bloom_bits_per_key > 0 {
+            self.filter_policy = Some(Arc::new(BloomFilter::new(self.bloom_bits_per_key)))
+        }
     }
     #[allow(unused_must_use)]
     fn apply_logger(&mut self) {
@@ -259,31 +763,121 @@ impl Default for Options {
             create_if_missing: true,
             error_if_exists: false,
             paranoid_checks: false,
+            consistency_check_level: ConsistencyCheckLevel::Off,
             env: Arc::new(FileStorage {}),
+            clock: Arc::new(SystemClock::new()),
             max_levels: 7,
             l0_compaction_threshold: 4,
             l0_slowdown_writes_threshold: 8,
             l0_stop_writes_threshold: 12,
+            write_slowdown_micros: 1000,
+            soft_pending_compaction_bytes_limit: 64 << 30,
+            hard_pending_compaction_bytes_limit: 256 << 30,
+            fifo_max_table_files_size: 0,
             l1_max_bytes: 64 * 1024 * 1024, // 64MB
+            level_compaction_dynamic_level_bytes: false,
             max_mem_compact_level: 2,
+            seek_compaction: true,
+            bytes_per_seek: 16 * 1024,
+            min_allowed_seeks: 100,
+            bottommost_deletion_ratio: 0.0,
+            periodic_compaction_seconds: 0,
             read_bytes_period: 1048576,
-            write_buffer_size: 4 * 1024 * 1024, // 4MB
+            max_background_compactions: 1,
+            max_subcompactions: 1,
+            idle_check_interval_micros: 0,
+            idle_write_bytes_per_sec: 64 * 1024, // 64KB
+            write_buffer_size: 4 * 1024 * 1024,  // 4MB
+            max_write_buffer_number: 2,
+            memtable_rep: MemtableRepType::Skiplist,
+            arena_block_size: 4 * 1024, // 4KB
+            memtable_huge_page_size: 0,
+            wal_sync_max_delay_micros: 0,
+            wal_bytes_per_sync: 0,
+            wal_sync_policy: SyncPolicy::OnClose,
+            manifest_sync_policy: SyncPolicy::Always,
+            table_sync_policy: SyncPolicy::OnClose,
+            flush_on_close: false,
+            wal_sync_interval_micros: 0,
             max_open_files: 500,
+            use_mmap_reads: false,
+            use_direct_io_for_flush_and_compaction: false,
             block_cache: Some(Arc::new(SharedLRUCache::new(8 << 20))),
             non_table_cache_files: 10,
             block_size: 4 * 1024, // 4KB
             block_restart_interval: 16,
+            index_block_restart_interval: 1,
+            blocks_per_index_entry: 1,
+            per_level_table_options: vec![],
             max_file_size: 2 * 1024 * 1024, // 2MB
+            table_file_naming: TableFileNaming::default(),
             compression: SnappyCompression,
+            checksum_type: ChecksumType::Crc32c,
+            min_compression_ratio: 0.0,
+            dictionary_compressor: None,
+            compression_dict_max_bytes: 16 * 1024,
+            compression_dict_train_bytes: 256 * 1024,
+            record_kv_size_histograms: false,
+            block_cipher: None,
+            table_magic_number: TABLE_MAGIC_NUMBER,
+            format_version: LATEST_FORMAT_VERSION,
             reuse_logs: true,
             filter_policy: None,
+            bloom_bits_per_key: 0,
+            whole_key_filtering: true,
+            skip_bottommost_filters: false,
+            prefix_extractor: None,
+            filter_reuse_cache: None,
+            filter_partition_size: 0,
+            statistics: None,
+            listeners: vec![],
+            compaction_service: None,
+            compaction_filter: None,
+            key_classifier: None,
+            pre_write_hooks: vec![],
+            secondary_catch_up_interval_micros: 1_000_000,
+            lock_lease_expiry_micros: 0,
+            lock_read_only: false,
+            rate_limiter: None,
             logger: None,
             logger_level: LevelFilter::Info,
         }
     }
 }
 
+/// The block layout and the filter parameters of the tables in a level.
+/// See `Options::per_level_table_options` for details.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LevelTableOptions {
+    /// See `Options::block_restart_interval`
+    pub block_restart_interval: usize,
+
+    /// See `Options::index_block_restart_interval`
+    pub index_block_restart_interval: usize,
+
+    /// See `Options::blocks_per_index_entry`
+    pub blocks_per_index_entry: usize,
+
+    /// See `Options::bloom_bits_per_key`. 0 keeps the bits of `Options::filter_policy`.
+    pub bloom_bits_per_key: usize,
+
+    /// See `Options::whole_key_filtering`
+    pub whole_key_filtering: bool,
+}
+
+/// The options able to be changed on an opened db by `WickDB::set_options`,
+/// which take effect on the following operations immediately
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MutableOptions {
+    /// If true, all the reads ignore the filter blocks of the tables like
+    /// `ReadOptions::ignore_filters` does. It's turned on to confirm a suspected
+    /// filter corruption or a false negative of the filter policy without
+    /// reopening the db.
+    pub ignore_filters: bool,
+}
+
 /// Options that control read operations
+#[derive(Clone)]
 pub struct ReadOptions {
     /// If true, all data read from underlying storage will be
     /// verified against corresponding checksums.
@@ -298,6 +892,45 @@ pub struct ReadOptions {
     /// not have been released).  If `snapshot` is `None`, use an implicit
     /// snapshot of the state at the beginning of this read operation.
     pub snapshot: Option<Snapshot>,
+
+    /// If set, the read gives up with `Status::Cancelled` at its next check
+    /// point once the token is cancelled. A `get` checks it before probing
+    /// each table and a scan checks it every `cancel_check_interval` entries
+    /// it steps over, so a stuck read never blocks the caller's shutdown.
+    pub cancel_token: Option<CancellationToken>,
+
+    /// The number of the internal entries (including the overwritten and the
+    /// deleted ones) a scan steps over between two checks of `cancel_token`.
+    pub cancel_check_interval: usize,
+
+    /// If set, an iterator never yields the user keys less than the bound
+    /// (inclusive). The table and level iterators stop stepping backward into
+    /// the blocks and files below it.
+    pub iterate_lower_bound: Option<Vec<u8>>,
+
+    /// If set, an iterator never yields the user keys greater than or equal to
+    /// the bound (exclusive). The table and level iterators stop stepping forward
+    /// into the blocks and files beyond it.
+    pub iterate_upper_bound: Option<Vec<u8>>,
+
+    /// If true, `DB::iter` returns a `TailingIterator` that sees the data written
+    /// after it's created once it seeks again, which suits the consumers following
+    /// the newest writes like a log. The `snapshot` is ignored.
+    pub tailing: bool,
+
+    /// If true, the values are never copied by an iterator, whose `value` is always
+    /// empty. It suits the scans only interested in the keys like building an index.
+    pub keys_only: bool,
+
+    /// If true, an iterator only keeps the sizes of the values, which are returned
+    /// by `Iterator::value_size`, and its `value` is always empty.
+    pub value_size_only: bool,
+
+    /// If true, the read never consults the filter blocks of the tables and
+    /// searches the data blocks as if the tables had no filters. Comparing the
+    /// results with and without it tells whether a filter loses keys.
+    /// See also `MutableOptions::ignore_filters`.
+    pub ignore_filters: bool,
 }
 
 impl Default for ReadOptions {
@@ -306,10 +939,85 @@ impl Default for ReadOptions {
             verify_checksums: false,
             fill_cache: true,
             snapshot: None,
+            cancel_token: None,
+            cancel_check_interval: 1024,
+            iterate_lower_bound: None,
+            iterate_upper_bound: None,
+            tailing: false,
+            keys_only: false,
+            value_size_only: false,
+            ignore_filters: false,
         }
     }
 }
 
+impl ReadOptions {
+    // Returns `Status::Cancelled` if `cancel_token` has been cancelled
+    pub(crate) fn check_cancelled(&self) -> Result<()> {
+        match &self.cancel_token {
+            Some(token) if token.is_cancelled() => Err(WickErr::new(
+                Status::Cancelled,
+                Some("the read is cancelled"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Returns the iterate bounds as the internal keys sorting before all the
+    // entries of the bound user keys
+    pub(crate) fn internal_key_bounds(&self) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+        let to_internal = |bound: &Option<Vec<u8>>| {
+            bound.as_ref().map(|key| {
+                InternalKey::new(
+                    &Slice::from(key.as_slice()),
+                    MAX_KEY_SEQUENCE,
+                    VALUE_TYPE_FOR_SEEK,
+                )
+                .data()
+                .to_vec()
+            })
+        };
+        (
+            to_internal(&self.iterate_lower_bound),
+            to_internal(&self.iterate_upper_bound),
+        )
+    }
+}
+
+/// A flag shared between a caller and the foreground operations started with it.
+/// Cloning the token shares the flag, so the caller is able to keep a clone and
+/// cancel the operations from another thread.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels all the operations holding this token. This can't be undone.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// Options that control `WickDB::get_approximate_sizes`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SizeApproximationOptions {
+    /// If true, the bytes still live after the deletions in the tables are estimated
+    /// as well. Every table overlapping the ranges is scanned for its deletions once,
+    /// and the result is cached until the table is deleted.
+    pub estimate_live_bytes: bool,
+
+    /// If true, the bytes of the entries in the memtables are counted as well by
+    /// walking the memtables through the ranges.
+    pub include_memtables: bool,
+}
+
 /// Options that control write operations
 #[derive(Default)]
 pub struct WriteOptions {
@@ -327,4 +1035,13 @@ pub struct WriteOptions {
     /// with sync==true has similar crash semantics to a "write()"
     /// system call followed by "fsync()".
     pub sync: bool,
+
+    /// If true, the write is not appended to the WAL at all and is lost if the
+    /// process crashes before the memtable holding it is flushed. This is meant for
+    /// the bulk loads whose data the application is able to replay.
+    /// `WickDB::unlogged_sequence_range` tells which writes are still at risk and
+    /// `WickDB::flush_memtable` makes them durable.
+    ///
+    /// Must not be combined with `sync`.
+    pub disable_wal: bool,
 }
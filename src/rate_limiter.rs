@@ -0,0 +1,215 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::util::clock::{Clock, SystemClock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A token bucket limiting the bytes written per second.
+///
+/// The bucket is refilled with `bytes_per_second * refill_period` tokens
+/// every `refill_period` and holds at most one refill of tokens, so the burst
+/// size is bounded by a single period. A request larger than the burst is
+/// served piece by piece across several periods.
+pub struct RateLimiter {
+    bytes_per_second: AtomicU64,
+    refill_period: Duration,
+    clock: Arc<dyn Clock>,
+    state: Mutex<BucketState>,
+    total_bytes_through: AtomicU64,
+    total_requests: AtomicU64,
+}
+
+struct BucketState {
+    available: u64,
+    // in monotonic micros of the clock
+    last_refill: u64,
+}
+
+impl RateLimiter {
+    /// Creates a `RateLimiter` refilled every 100ms
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self::with_refill_period(bytes_per_second, Duration::from_millis(100))
+    }
+
+    /// # Panics
+    ///
+    /// * `bytes_per_second` or `refill_period` is zero
+    pub fn with_refill_period(bytes_per_second: u64, refill_period: Duration) -> Self {
+        Self::with_clock(
+            bytes_per_second,
+            refill_period,
+            Arc::new(SystemClock::new()),
+        )
+    }
+
+    /// Creates a `RateLimiter` measuring the time and waiting by the given `Clock`
+    ///
+    /// # Panics
+    ///
+    /// * `bytes_per_second` or `refill_period` is zero
+    pub fn with_clock(
+        bytes_per_second: u64,
+        refill_period: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        assert!(
+            bytes_per_second > 0,
+            "[rate limiter] bytes_per_second should be greater than 0"
+        );
+        assert!(
+            refill_period > Duration::from_micros(0),
+            "[rate limiter] refill_period should be greater than 0"
+        );
+        let limiter = Self {
+            bytes_per_second: AtomicU64::new(bytes_per_second),
+            refill_period,
+            state: Mutex::new(BucketState {
+                available: 0,
+                last_refill: clock.monotonic_micros(),
+            }),
+            clock,
+            total_bytes_through: AtomicU64::new(0),
+            total_requests: AtomicU64::new(0),
+        };
+        limiter.state.lock().unwrap().available = limiter.bytes_per_refill();
+        limiter
+    }
+
+    /// Changes the limit dynamically. The new limit takes effect since the next refill.
+    pub fn set_bytes_per_second(&self, bytes_per_second: u64) {
+        assert!(
+            bytes_per_second > 0,
+            "[rate limiter] bytes_per_second should be greater than 0"
+        );
+        self.bytes_per_second
+            .store(bytes_per_second, Ordering::Release);
+    }
+
+    #[inline]
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second.load(Ordering::Acquire)
+    }
+
+    /// Returns the total bytes granted by the limiter
+    #[inline]
+    pub fn total_bytes_through(&self) -> u64 {
+        self.total_bytes_through.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total number of `request` calls
+    #[inline]
+    pub fn total_requests(&self) -> u64 {
+        self.total_requests.load(Ordering::Relaxed)
+    }
+
+    /// Blocks the current thread until `bytes` tokens are granted
+    pub fn request(&self, bytes: usize) {
+        self.total_requests.fetch_add(1, Ordering::Relaxed);
+        let mut remaining = bytes as u64;
+        while remaining > 0 {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                self.refill(&mut state);
+                if state.available > 0 {
+                    let granted = remaining.min(state.available);
+                    state.available -= granted;
+                    remaining -= granted;
+                    self.total_bytes_through
+                        .fetch_add(granted, Ordering::Relaxed);
+                    continue;
+                }
+                (state.last_refill + self.refill_period.as_micros() as u64)
+                    .saturating_sub(self.clock.monotonic_micros())
+            };
+            self.clock.sleep_micros(wait);
+        }
+    }
+
+    #[inline]
+    fn bytes_per_refill(&self) -> u64 {
+        let bytes = self.bytes_per_second() as u128 * self.refill_period.as_micros() / 1_000_000;
+        (bytes as u64).max(1)
+    }
+
+    fn refill(&self, state: &mut BucketState) {
+        let refill_period = self.refill_period.as_micros() as u64;
+        let elapsed = self
+            .clock
+            .monotonic_micros()
+            .saturating_sub(state.last_refill);
+        let periods = elapsed / refill_period;
+        if periods > 0 {
+            let bytes_per_refill = self.bytes_per_refill();
+            // the bucket never holds more than a single refill
+            state.available =
+                bytes_per_refill.min(state.available + bytes_per_refill.saturating_mul(periods));
+            state.last_refill += refill_period * periods;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::clock::MockClock;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_request_within_burst() {
+        let limiter = RateLimiter::new(1 << 20);
+        let now = Instant::now();
+        limiter.request(1024);
+        limiter.request(1024);
+        assert!(now.elapsed() < Duration::from_millis(50));
+        assert_eq!(limiter.total_bytes_through(), 2048);
+        assert_eq!(limiter.total_requests(), 2);
+    }
+
+    #[test]
+    fn test_request_is_throttled() {
+        // 1000 bytes per 10ms
+        let limiter = Arc::new(RateLimiter::with_refill_period(
+            100_000,
+            Duration::from_millis(10),
+        ));
+        let now = Instant::now();
+        let mut handles = vec![];
+        for _ in 0..2 {
+            let l = limiter.clone();
+            handles.push(thread::spawn(move || l.request(2500)));
+        }
+        for h in handles {
+            h.join().unwrap();
+        }
+        // the first 1000 bytes are granted immediately and the rest 4000 bytes
+        // need at least 4 refills
+        assert!(now.elapsed() >= Duration::from_millis(40));
+        assert_eq!(limiter.total_bytes_through(), 5000);
+    }
+
+    #[test]
+    fn test_request_with_mock_clock() {
+        let clock = Arc::new(MockClock::new(0));
+        // 1000 bytes per 10ms
+        let limiter = RateLimiter::with_clock(100_000, Duration::from_millis(10), clock.clone());
+        limiter.request(1000);
+        assert_eq!(clock.now_micros(), 0);
+        // sleeping on the mock clock only moves it forward by 3 refills
+        limiter.request(2500);
+        assert_eq!(clock.now_micros(), 30_000);
+        assert_eq!(limiter.total_bytes_through(), 3500);
+    }
+}
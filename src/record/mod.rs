@@ -65,7 +65,7 @@ mod tests {
     use crate::util::coding::encode_fixed_32;
     use crate::util::crc32::{mask, value};
     use crate::util::slice::Slice;
-    use crate::util::status::{Result, Status, WickErr};
+    use crate::util::status::{CorruptionReport, Result, Status, WickErr};
     use rand::Rng;
     use std::cell::RefCell;
     use std::cmp::min;
@@ -184,6 +184,7 @@ mod tests {
     struct ReportCollector {
         dropped_bytes: Rc<RefCell<u64>>,
         message: Rc<RefCell<String>>,
+        reports: Rc<RefCell<Vec<CorruptionReport>>>,
     }
 
     impl Reporter for ReportCollector {
@@ -191,6 +192,11 @@ mod tests {
             *self.dropped_bytes.borrow_mut() += bytes;
             self.message.borrow_mut().push_str(reason);
         }
+
+        fn corruption_with_report(&mut self, report: CorruptionReport) {
+            self.corruption(report.size, report.reason.as_str());
+            self.reports.borrow_mut().push(report);
+        }
     }
 
     impl ReportCollector {
@@ -198,6 +204,7 @@ mod tests {
             Self {
                 dropped_bytes: Rc::new(RefCell::new(0)),
                 message: Rc::new(RefCell::new(String::default())),
+                reports: Rc::new(RefCell::new(vec![])),
             }
         }
     }
@@ -586,6 +593,12 @@ mod tests {
         assert_eq!(EOF, log.read());
         assert_eq!(10, log.dropped_bytes());
         assert!(log.match_error("checksum mismatch"));
+        let reports = log.reporter.reports.borrow();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].offset, 0);
+        assert_eq!(reports[0].size, 10);
+        assert!(reports[0].expected_checksum.is_some());
+        assert_ne!(reports[0].expected_checksum, reports[0].actual_checksum);
     }
 
     #[test]
@@ -20,6 +20,7 @@ use crate::record::{RecordType, BLOCK_SIZE, HEADER_SIZE};
 use crate::storage::File;
 use crate::util::coding::decode_fixed_32;
 use crate::util::crc32::{unmask, value};
+use crate::util::status::CorruptionReport;
 use std::error::Error;
 use std::io::SeekFrom;
 
@@ -48,6 +49,12 @@ pub trait Reporter {
     /// Some corruption was detected.  "bytes" is the approximate number
     /// of bytes dropped due to the corruption.
     fn corruption(&mut self, bytes: u64, reason: &str);
+
+    /// Same as `corruption` but with the detail of the dropped bytes.
+    /// Forwards to `corruption` by default.
+    fn corruption_with_report(&mut self, report: CorruptionReport) {
+        self.corruption(report.size, report.reason.as_str())
+    }
 }
 
 /// A `Reader` is used for reading records from log file.
@@ -298,7 +305,14 @@ impl Reader {
                 if expected != actual {
                     let drop_size = self.buf_length;
                     self.clear_buf();
-                    self.report_drop(drop_size as u64, "checksum mismatch");
+                    let report = self
+                        .new_report(drop_size as u64, "checksum mismatch")
+                        .with_checksums(expected, actual)
+                        .with_context(format!(
+                            "record type {}, record length {}",
+                            record_type, data_length
+                        ));
+                    self.report_corruption(report);
                     return Err(BadRecord);
                 }
             }
@@ -325,17 +339,30 @@ impl Reader {
 
     // report record dropping to the `reporter`
     fn report_drop(&mut self, bytes: u64, reason: &str) {
+        let report = self.new_report(bytes, reason);
+        self.report_corruption(report)
+    }
+
+    fn report_corruption(&mut self, report: CorruptionReport) {
         if let Some(reporter) = self.reporter.as_mut() {
             // make sure the bytes not overflows 'the initial_offset'
             // and a special case is that we got a read error when we first read a block
             if self.end_of_buffer_offset == 0
-                || self.end_of_buffer_offset - bytes >= self.initial_offset
+                || self.end_of_buffer_offset - report.size >= self.initial_offset
             {
-                reporter.corruption(bytes, reason);
+                reporter.corruption_with_report(report);
             }
         }
     }
 
+    // Creates a report for the `bytes` dropped just before the unread part of `buf`
+    fn new_report(&self, bytes: u64, reason: &str) -> CorruptionReport {
+        let offset = self
+            .end_of_buffer_offset
+            .saturating_sub(self.buf_length as u64 + bytes);
+        CorruptionReport::new(reason, offset, bytes)
+    }
+
     // clear `buf` and reset `buf_length`
     fn clear_buf(&mut self) {
         self.buf = vec![0; BLOCK_SIZE];
@@ -31,6 +31,8 @@ pub struct Writer {
     // pre-computed to reduce the overhead of computing the crc of the
     // record type stored in the header.
     crc_cache: [u32; (RecordType::Last as usize + 1) as usize],
+    // The bytes written since the last sync
+    unsynced_bytes: u64,
 }
 
 impl Writer {
@@ -45,6 +47,7 @@ impl Writer {
             dest,
             block_offset: 0,
             crc_cache: cache,
+            unsynced_bytes: 0,
         }
     }
 
@@ -68,6 +71,7 @@ impl Writer {
                 if leftover != 0 {
                     // fill the rest of the block with zero
                     self.dest.write(&[0; 6][..leftover])?;
+                    self.unsynced_bytes += leftover as u64;
                 }
                 self.block_offset = 0; // use a new block
             };
@@ -104,7 +108,15 @@ impl Writer {
     /// Sync the underlying file
     #[inline]
     pub fn sync(&mut self) -> Result<()> {
-        self.dest.flush()
+        self.dest.sync()?;
+        self.unsynced_bytes = 0;
+        Ok(())
+    }
+
+    /// Returns the number of the bytes written since the last sync
+    #[inline]
+    pub fn unsynced_bytes(&self) -> u64 {
+        self.unsynced_bytes
     }
 
     // create formatted bytes and write into the file
@@ -138,6 +150,7 @@ impl Writer {
         self.dest.flush()?;
         // update block_offset
         self.block_offset += HEADER_SIZE + size;
+        self.unsynced_bytes += (HEADER_SIZE + size) as u64;
         Ok(())
     }
 }
@@ -0,0 +1,465 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Offloading compactions to external workers.
+//!
+//! When `Options::compaction_service` is set, the DB describes every major
+//! compaction that needs rewriting as a `CompactionJob` and hands it to the
+//! service instead of merging the inputs itself. The service is free to ship
+//! the encoded job to another process or machine, which runs it by
+//! `run_compaction_job` against the same storage and returns the
+//! `CompactionJobResult`. The DB then moves the output tables into the db
+//! and installs them like a local compaction.
+//!
+//! If the service fails, the compaction falls back to running locally.
+
+use crate::db::format::{InternalKeyComparator, ParsedInternalKey, ValueType};
+use crate::iterator::{Iterator, MergingIterator};
+use crate::options::{CompressionType, LevelTableOptions, Options, ReadOptions};
+use crate::sstable::table::{new_table_iterator, Table, TableBuilder};
+use crate::util::status::{Result, Status, WickErr};
+use crate::util::varint::VarintU64;
+use std::cell::RefCell;
+use std::cmp::Ordering as CmpOrdering;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// A `CompactionService` runs the compaction jobs delegated by the DB.
+pub trait CompactionService: Send + Sync {
+    /// Runs the given job and returns the output tables.
+    ///
+    /// The outputs must be written under `job.output_dir` in the storage of the
+    /// DB. This is called in a background compaction thread and blocks it
+    /// until the job is done.
+    fn compact(&self, job: &CompactionJob) -> Result<CompactionJobResult>;
+}
+
+/// An input table of a `CompactionJob`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactionInput {
+    pub level: usize,
+    /// The path of the table in the storage of the DB
+    pub path: String,
+    pub number: u64,
+    pub file_size: u64,
+}
+
+/// A compaction to be run by a `CompactionService`
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionJob {
+    /// Unique among the jobs of the DB
+    pub job_id: u64,
+    /// The name of the DB where the input tables live
+    pub db_name: String,
+    /// The name of the user comparator
+    pub comparator: String,
+    pub level: usize,
+    pub output_level: usize,
+    pub inputs: Vec<CompactionInput>,
+    /// The directory where the output tables should be written to
+    pub output_dir: String,
+    /// The sequence number of the oldest snapshot alive. The overwritten
+    /// entries invisible to this snapshot can be dropped.
+    pub smallest_snapshot: u64,
+    /// Whether no level deeper than `output_level` contains the keys of the
+    /// inputs so the obsolete deletions can be dropped
+    pub bottommost: bool,
+    /// Whether the output tables are built without the filters. See
+    /// `Options::skip_bottommost_filters`.
+    pub skip_filters: bool,
+    /// See `Options::paranoid_checks`
+    pub paranoid_checks: bool,
+    /// See `Options::max_file_size`
+    pub max_file_size: u64,
+    /// See `Options::block_size`
+    pub block_size: usize,
+    /// See `Options::compression`
+    pub compression: CompressionType,
+    /// See `Options::min_compression_ratio`
+    pub min_compression_ratio: f64,
+    /// The block layout of the output level
+    pub table_options: LevelTableOptions,
+}
+
+/// An output table of a `CompactionJob`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactionOutput {
+    /// The path of the table in the storage of the DB
+    pub path: String,
+    pub file_size: u64,
+    /// The smallest internal key in the table
+    pub smallest: Vec<u8>,
+    /// The largest internal key in the table
+    pub largest: Vec<u8>,
+}
+
+/// The result of a `CompactionJob`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactionJobResult {
+    /// The output tables in key order
+    pub outputs: Vec<CompactionOutput>,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+fn bad_job(msg: &'static str) -> WickErr {
+    WickErr::new(Status::Corruption, Some(msg))
+}
+
+// Decoding helpers consuming the front of `s`
+
+fn get_u64(s: &mut &[u8]) -> Option<u64> {
+    let (v, n) = VarintU64::read(s)?;
+    *s = &s[n..];
+    Some(v)
+}
+
+fn get_usize(s: &mut &[u8]) -> Option<usize> {
+    get_u64(s).map(|v| v as usize)
+}
+
+fn get_u8(s: &mut &[u8]) -> Option<u8> {
+    let (first, rest) = s.split_first()?;
+    *s = rest;
+    Some(*first)
+}
+
+fn get_bytes(s: &mut &[u8]) -> Option<Vec<u8>> {
+    let len = get_usize(s)?;
+    if s.len() < len {
+        return None;
+    }
+    let (bytes, rest) = s.split_at(len);
+    *s = rest;
+    Some(bytes.to_vec())
+}
+
+fn get_string(s: &mut &[u8]) -> Option<String> {
+    get_bytes(s).and_then(|b| String::from_utf8(b).ok())
+}
+
+impl CompactionJob {
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        VarintU64::put_varint(dst, self.job_id);
+        VarintU64::put_varint_prefixed_slice(dst, self.db_name.as_bytes());
+        VarintU64::put_varint_prefixed_slice(dst, self.comparator.as_bytes());
+        VarintU64::put_varint(dst, self.level as u64);
+        VarintU64::put_varint(dst, self.output_level as u64);
+        VarintU64::put_varint(dst, self.inputs.len() as u64);
+        for input in self.inputs.iter() {
+            VarintU64::put_varint(dst, input.level as u64);
+            VarintU64::put_varint_prefixed_slice(dst, input.path.as_bytes());
+            VarintU64::put_varint(dst, input.number);
+            VarintU64::put_varint(dst, input.file_size);
+        }
+        VarintU64::put_varint_prefixed_slice(dst, self.output_dir.as_bytes());
+        VarintU64::put_varint(dst, self.smallest_snapshot);
+        dst.push(self.bottommost as u8);
+        dst.push(self.skip_filters as u8);
+        dst.push(self.paranoid_checks as u8);
+        VarintU64::put_varint(dst, self.max_file_size);
+        VarintU64::put_varint(dst, self.block_size as u64);
+        dst.push(self.compression as u8);
+        VarintU64::put_varint(dst, self.min_compression_ratio.to_bits());
+        VarintU64::put_varint(dst, self.table_options.block_restart_interval as u64);
+        VarintU64::put_varint(dst, self.table_options.index_block_restart_interval as u64);
+        VarintU64::put_varint(dst, self.table_options.blocks_per_index_entry as u64);
+        VarintU64::put_varint(dst, self.table_options.bloom_bits_per_key as u64);
+        dst.push(self.table_options.whole_key_filtering as u8);
+    }
+
+    pub fn decoded_from(src: &[u8]) -> Result<Self> {
+        let mut s = src;
+        Self::decode(&mut s).ok_or_else(|| bad_job("bad compaction job"))
+    }
+
+    fn decode(s: &mut &[u8]) -> Option<Self> {
+        let job_id = get_u64(s)?;
+        let db_name = get_string(s)?;
+        let comparator = get_string(s)?;
+        let level = get_usize(s)?;
+        let output_level = get_usize(s)?;
+        let n = get_usize(s)?;
+        let mut inputs = Vec::with_capacity(n.min(1024));
+        for _ in 0..n {
+            inputs.push(CompactionInput {
+                level: get_usize(s)?,
+                path: get_string(s)?,
+                number: get_u64(s)?,
+                file_size: get_u64(s)?,
+            });
+        }
+        let output_dir = get_string(s)?;
+        let smallest_snapshot = get_u64(s)?;
+        let bottommost = get_u8(s)? != 0;
+        let skip_filters = get_u8(s)? != 0;
+        let paranoid_checks = get_u8(s)? != 0;
+        let max_file_size = get_u64(s)?;
+        let block_size = get_usize(s)?;
+        let compression = CompressionType::from(get_u8(s)?);
+        let min_compression_ratio = f64::from_bits(get_u64(s)?);
+        let table_options = LevelTableOptions {
+            block_restart_interval: get_usize(s)?,
+            index_block_restart_interval: get_usize(s)?,
+            blocks_per_index_entry: get_usize(s)?,
+            bloom_bits_per_key: get_usize(s)?,
+            whole_key_filtering: get_u8(s)? != 0,
+        };
+        Some(Self {
+            job_id,
+            db_name,
+            comparator,
+            level,
+            output_level,
+            inputs,
+            output_dir,
+            smallest_snapshot,
+            bottommost,
+            skip_filters,
+            paranoid_checks,
+            max_file_size,
+            block_size,
+            compression,
+            min_compression_ratio,
+            table_options,
+        })
+    }
+}
+
+impl CompactionJobResult {
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        VarintU64::put_varint(dst, self.outputs.len() as u64);
+        for output in self.outputs.iter() {
+            VarintU64::put_varint_prefixed_slice(dst, output.path.as_bytes());
+            VarintU64::put_varint(dst, output.file_size);
+            VarintU64::put_varint_prefixed_slice(dst, output.smallest.as_slice());
+            VarintU64::put_varint_prefixed_slice(dst, output.largest.as_slice());
+        }
+        VarintU64::put_varint(dst, self.bytes_read);
+        VarintU64::put_varint(dst, self.bytes_written);
+    }
+
+    pub fn decoded_from(src: &[u8]) -> Result<Self> {
+        let mut s = src;
+        Self::decode(&mut s).ok_or_else(|| bad_job("bad compaction job result"))
+    }
+
+    fn decode(s: &mut &[u8]) -> Option<Self> {
+        let n = get_usize(s)?;
+        let mut outputs = Vec::with_capacity(n.min(1024));
+        for _ in 0..n {
+            outputs.push(CompactionOutput {
+                path: get_string(s)?,
+                file_size: get_u64(s)?,
+                smallest: get_bytes(s)?,
+                largest: get_bytes(s)?,
+            });
+        }
+        Some(Self {
+            outputs,
+            bytes_read: get_u64(s)?,
+            bytes_written: get_u64(s)?,
+        })
+    }
+}
+
+// Finishes the current output table and records its size
+fn finish_output(
+    builder: &mut Option<TableBuilder>,
+    result: &mut CompactionJobResult,
+) -> Result<()> {
+    if let Some(mut b) = builder.take() {
+        b.finish(true)?;
+        let output = result.outputs.last_mut().unwrap();
+        output.file_size = b.file_size();
+        result.bytes_written += output.file_size;
+    }
+    Ok(())
+}
+
+/// Runs a `CompactionJob` on the worker side.
+///
+/// `options` should be the same as the DB's except the ones serialized in
+/// the job, which take precedence. The input tables are read from and the
+/// outputs are written to `options.env`.
+pub fn run_compaction_job(
+    job: &CompactionJob,
+    mut options: Options,
+) -> Result<CompactionJobResult> {
+    if options.comparator.name() != job.comparator {
+        return Err(WickErr::new(
+            Status::InvalidArgument,
+            Some("the comparator doesn't match the compaction job"),
+        ));
+    }
+    options.paranoid_checks = job.paranoid_checks;
+    options.max_file_size = job.max_file_size;
+    options.block_size = job.block_size;
+    options.compression = job.compression;
+    options.min_compression_ratio = job.min_compression_ratio;
+    options.per_level_table_options = vec![job.table_options; job.output_level + 1];
+    let options = Arc::new(options);
+    let env = options.env.clone();
+    let icmp = Arc::new(InternalKeyComparator::new(options.comparator.clone()));
+    let read_options = Rc::new(ReadOptions {
+        verify_checksums: options.paranoid_checks,
+        fill_cache: false,
+        ..ReadOptions::default()
+    });
+    let mut result = CompactionJobResult::default();
+    let mut children = Vec::with_capacity(job.inputs.len());
+    for input in job.inputs.iter() {
+        let file = env.open(input.path.as_str())?;
+        let table =
+            Table::open_with_comparator(file, input.file_size, options.clone(), icmp.clone())?
+                .with_file_number(input.number);
+        children.push(Rc::new(RefCell::new(new_table_iterator(
+            Arc::new(table),
+            read_options.clone(),
+        ))));
+        result.bytes_read += input.file_size;
+    }
+    let mut iter = MergingIterator::new(icmp.clone(), children);
+    env.mkdir_all(job.output_dir.as_str())?;
+
+    let ucmp = icmp.user_comparator.clone();
+    let mut builder: Option<TableBuilder> = None;
+    let mut current_ukey: Option<Vec<u8>> = None;
+    let mut last_sequence_for_key = u64::MAX;
+    iter.seek_to_first();
+    while iter.valid() {
+        let ikey = iter.key();
+        if let Some(key) = ParsedInternalKey::decode_from(ikey.clone()) {
+            let first_occurrence = match &current_ukey {
+                Some(k) => ucmp.compare(k, key.user_key.as_slice()) != CmpOrdering::Equal,
+                None => true,
+            };
+            if first_occurrence {
+                current_ukey = Some(key.user_key.as_slice().to_vec());
+                last_sequence_for_key = u64::MAX;
+            }
+            // Same as the local compaction except that the deeper levels are
+            // only known by `bottommost`
+            let drop = last_sequence_for_key <= job.smallest_snapshot
+                || (key.value_type == ValueType::Deletion
+                    && key.seq <= job.smallest_snapshot
+                    && job.bottommost);
+            last_sequence_for_key = key.seq;
+            if !drop {
+                if builder.is_none() {
+                    let path = format!("{}/{:06}.out", job.output_dir, result.outputs.len());
+                    let file = env.create(path.as_str())?;
+                    let mut b = TableBuilder::new_with_comparator(
+                        file,
+                        options.clone(),
+                        icmp.clone(),
+                        job.output_level,
+                    )
+                    .with_internal_keys()
+                    .with_compression_dictionary();
+                    if job.skip_filters {
+                        b = b.without_filters();
+                    }
+                    builder = Some(b);
+                    result.outputs.push(CompactionOutput {
+                        path,
+                        file_size: 0,
+                        smallest: ikey.as_slice().to_vec(),
+                        largest: vec![],
+                    });
+                }
+                let b = builder.as_mut().unwrap();
+                b.add(ikey.as_slice(), iter.value().as_slice())?;
+                result.outputs.last_mut().unwrap().largest = ikey.as_slice().to_vec();
+                if b.file_size() >= options.max_file_size {
+                    finish_output(&mut builder, &mut result)?;
+                }
+            }
+        } else {
+            current_ukey = None;
+            last_sequence_for_key = u64::MAX;
+        }
+        iter.next();
+    }
+    iter.status()?;
+    finish_output(&mut builder, &mut result)?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_job() -> CompactionJob {
+        CompactionJob {
+            job_id: 42,
+            db_name: "db".to_owned(),
+            comparator: "leveldb.BytewiseComparator".to_owned(),
+            level: 0,
+            output_level: 1,
+            inputs: vec![
+                CompactionInput {
+                    level: 0,
+                    path: "db/000007.sst".to_owned(),
+                    number: 7,
+                    file_size: 1024,
+                },
+                CompactionInput {
+                    level: 1,
+                    path: "db/000003.sst".to_owned(),
+                    number: 3,
+                    file_size: 4096,
+                },
+            ],
+            output_dir: "db/compaction-42".to_owned(),
+            smallest_snapshot: 100,
+            bottommost: true,
+            skip_filters: false,
+            paranoid_checks: false,
+            max_file_size: 2 << 20,
+            block_size: 4096,
+            compression: CompressionType::SnappyCompression,
+            min_compression_ratio: 1.5,
+            table_options: LevelTableOptions {
+                block_restart_interval: 16,
+                index_block_restart_interval: 1,
+                blocks_per_index_entry: 1,
+                bloom_bits_per_key: 10,
+                whole_key_filtering: false,
+            },
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_job() {
+        let job = new_job();
+        let mut buf = vec![];
+        job.encode_to(&mut buf);
+        assert_eq!(CompactionJob::decoded_from(&buf).unwrap(), job);
+        assert!(CompactionJob::decoded_from(&buf[..buf.len() - 1]).is_err());
+
+        let result = CompactionJobResult {
+            outputs: vec![CompactionOutput {
+                path: "db/compaction-42/000000.out".to_owned(),
+                file_size: 2048,
+                smallest: b"a\x01\x00\x00\x00\x00\x00\x00\x00".to_vec(),
+                largest: b"z\x01\x00\x00\x00\x00\x00\x00\x00".to_vec(),
+            }],
+            bytes_read: 5120,
+            bytes_written: 2048,
+        };
+        buf.clear();
+        result.encode_to(&mut buf);
+        assert_eq!(CompactionJobResult::decoded_from(&buf).unwrap(), result);
+    }
+}
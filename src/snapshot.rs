@@ -21,6 +21,7 @@ use std::sync::Arc;
 /// Abstract handle to particular state of a DB.
 /// A `Snapshot` is an immutable object and can therefore be safely
 /// accessed from multiple threads without any external synchronization.
+#[derive(Clone)]
 pub struct Snapshot {
     // The sequence number pointing to the view of db
     sequence_number: u64,
@@ -71,7 +72,7 @@ impl SnapshotList {
     pub fn snapshot(&mut self, seq: u64) -> Arc<Snapshot> {
         let last_seq = self.last_seq();
         assert!(seq >= last_seq, "[snapshot] the sequence number shouldn't be monotonically decreasing : [new: {}], [last: {}]", seq, last_seq);
-        if last_seq == seq {
+        if !self.is_empty() && last_seq == seq {
             self.snapshots.back().unwrap().clone()
         } else {
             let s = Arc::new(Snapshot {
@@ -125,11 +126,11 @@ mod tests {
     #[test]
     pub fn test_append_new_snapshot() {
         let mut s = SnapshotList::new();
-        for i in [1, 1, 2, 3].iter() {
+        for i in [0, 1, 1, 2, 3].iter() {
             let s = s.snapshot(*i);
             assert_eq!(s.sequence(), *i);
         }
-        assert_eq!(1, s.oldest().sequence());
+        assert_eq!(0, s.oldest().sequence());
         assert_eq!(3, s.newest().sequence());
     }
 }
@@ -0,0 +1,592 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reading a set of table files outside of a db, e.g. to post-process the outputs
+//! of a compaction or the files built by an `SstFileWriter` in an offline job.
+//!
+//! An `SstFileReader` opens any table files of the db format through
+//! `Options::env` and merges them by the given comparator of the user keys. The
+//! files may overlap each other, and the entries of a user key are resolved by
+//! their sequence numbers like a db does.
+
+use crate::db::format::{
+    InternalKey, InternalKeyComparator, ParsedInternalKey, ValueType, MAX_KEY_SEQUENCE,
+    VALUE_TYPE_FOR_SEEK,
+};
+use crate::iterator::{Iterator, MergingIterator};
+use crate::options::{Options, ReadOptions};
+use crate::sstable::table::{new_table_iterator, Table};
+use crate::util::comparator::Comparator;
+use crate::util::slice::Slice;
+use crate::util::status::{Result, Status, WickErr};
+use std::cmp::Ordering;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// A read-only view over a set of table files. See the module level documents.
+pub struct SstFileReader {
+    ucmp: Arc<dyn Comparator>,
+    icmp: Arc<InternalKeyComparator>,
+    tables: Vec<Arc<Table>>,
+}
+
+impl SstFileReader {
+    /// Opens the table files at `file_paths` by `options.env`, whose user keys are
+    /// ordered by `cmp` instead of `options.comparator`. The other options, e.g.
+    /// the `block_cipher`, must match the ones the files are built with.
+    pub fn open(options: Options, cmp: Arc<dyn Comparator>, file_paths: &[&str]) -> Result<Self> {
+        let mut options = options;
+        options.comparator = cmp.clone();
+        let options = Arc::new(options);
+        let icmp = Arc::new(InternalKeyComparator::new(cmp.clone()));
+        let mut tables = Vec::with_capacity(file_paths.len());
+        for path in file_paths {
+            let file = if options.use_mmap_reads {
+                options.env.open_mmap(path)?
+            } else {
+                options.env.open(path)?
+            };
+            let size = file.len()?;
+            // the file number decides the tweaks of the encrypted blocks
+            let file_number = options
+                .table_file_naming
+                .parse_table_number(path)
+                .unwrap_or(0);
+            let table = Table::open_with_comparator(file, size, options.clone(), icmp.clone())
+                .map(|t| t.with_file_number(file_number))
+                .map_err(|e| e.with_corrupted_file(path))?;
+            tables.push(Arc::new(table));
+        }
+        Ok(Self {
+            ucmp: cmp,
+            icmp,
+            tables,
+        })
+    }
+
+    /// Like `open` but reads the files through the `AsyncFile`s opened by
+    /// `Storage::open_async`, so that a backend completing the reads out of the
+    /// calling thread (e.g. io_uring) never blocks it.
+    ///
+    /// The returned reader serves the point lookups by `get_async` only, and its
+    /// iterators fail on reading the data blocks.
+    pub async fn open_async(
+        options: Options,
+        cmp: Arc<dyn Comparator>,
+        file_paths: &[&str],
+    ) -> Result<Self> {
+        let mut options = options;
+        options.comparator = cmp.clone();
+        let options = Arc::new(options);
+        let icmp = Arc::new(InternalKeyComparator::new(cmp.clone()));
+        let mut tables = Vec::with_capacity(file_paths.len());
+        for path in file_paths {
+            let file = options.env.open_async(path)?;
+            let size = file.len().await?;
+            let file_number = options
+                .table_file_naming
+                .parse_table_number(path)
+                .unwrap_or(0);
+            let table =
+                Table::open_async_with_comparator(file, size, options.clone(), icmp.clone())
+                    .await
+                    .map(|t| t.with_file_number(file_number))
+                    .map_err(|e| e.with_corrupted_file(path))?;
+            tables.push(Arc::new(table));
+        }
+        Ok(Self {
+            ucmp: cmp,
+            icmp,
+            tables,
+        })
+    }
+
+    /// Gets the newest value of the user `key` in all the files of a reader opened
+    /// by `open_async`. Returns `None` if the key is missing or deleted.
+    pub async fn get_async(&self, read_opt: ReadOptions, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let read_opt = Rc::new(read_opt);
+        let lookup = InternalKey::new(&Slice::from(key), MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK);
+        // the sequence, the value type and the value of the newest entry
+        let mut newest: Option<(u64, ValueType, Vec<u8>)> = None;
+        for table in self.tables.iter() {
+            let (ikey, value) = match table
+                .internal_get_async(read_opt.clone(), lookup.data())
+                .await?
+            {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let parsed = match ParsedInternalKey::decode_from(Slice::from(ikey.as_slice())) {
+                Some(k) => k,
+                None => {
+                    return Err(WickErr::new(
+                        Status::Corruption,
+                        Some("corrupted internal key in table"),
+                    ))
+                }
+            };
+            if self.ucmp.compare(parsed.user_key.as_slice(), key) != Ordering::Equal {
+                continue;
+            }
+            if newest.as_ref().is_none_or(|(seq, _, _)| parsed.seq > *seq) {
+                newest = Some((parsed.seq, parsed.value_type, value));
+            }
+        }
+        Ok(newest.and_then(|(_, t, value)| {
+            if t == ValueType::Value {
+                Some(value)
+            } else {
+                None
+            }
+        }))
+    }
+
+    /// Returns the number of the opened files
+    #[inline]
+    pub fn num_files(&self) -> usize {
+        self.tables.len()
+    }
+
+    /// Returns an iterator over the newest value of every user key in all the
+    /// files. The deleted keys are skipped.
+    pub fn iter(&self, read_opt: ReadOptions) -> Box<dyn Iterator> {
+        Box::new(SstSetIterator {
+            ucmp: self.ucmp.clone(),
+            inner: self.raw_iter(read_opt),
+            key: vec![],
+            value: vec![],
+            valid: false,
+            forward: true,
+            err: None,
+        })
+    }
+
+    /// Returns an iterator over all the entries in all the files, whose keys are
+    /// the internal keys carrying the sequence numbers and the value types. The
+    /// versions of a user key are ordered from the newest to the oldest.
+    pub fn raw_iter(&self, read_opt: ReadOptions) -> Box<dyn Iterator> {
+        let read_opt = Rc::new(read_opt);
+        let iters = self
+            .tables
+            .iter()
+            .map(|t| new_table_iterator(t.clone(), read_opt.clone()))
+            .collect();
+        Box::new(MergingIterator::from_iters(self.icmp.clone(), iters))
+    }
+}
+
+// Yields the newest version of every user key of the internal iterator `inner`
+// unless it's a deletion.
+//
+// Moving forward, `inner` is positioned at the first entry after the current
+// user key. Moving backward, `inner` is positioned at the last entry before the
+// current user key.
+struct SstSetIterator {
+    ucmp: Arc<dyn Comparator>,
+    inner: Box<dyn Iterator>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    valid: bool,
+    forward: bool,
+    err: Option<WickErr>,
+}
+
+impl SstSetIterator {
+    // Parses the internal key `inner` is positioned at
+    fn parse_inner(&mut self) -> Option<ParsedInternalKey> {
+        let parsed = ParsedInternalKey::decode_from(self.inner.key());
+        if parsed.is_none() {
+            self.err = Some(WickErr::new(
+                Status::Corruption,
+                Some("corrupted internal key in table"),
+            ));
+            self.valid = false;
+        }
+        parsed
+    }
+
+    // Moves `inner` to the first entry after all the entries of the user key
+    fn skip_forward_past(&mut self, user_key: &[u8]) {
+        while self.inner.valid() {
+            match self.parse_inner() {
+                Some(k)
+                    if self.ucmp.compare(k.user_key.as_slice(), user_key) == Ordering::Equal =>
+                {
+                    self.inner.next()
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn find_next(&mut self) {
+        self.valid = false;
+        while self.inner.valid() {
+            let parsed = match self.parse_inner() {
+                Some(k) => k,
+                None => return,
+            };
+            let user_key = parsed.user_key.as_slice().to_vec();
+            let live = parsed.value_type == ValueType::Value;
+            if live {
+                self.value = self.inner.value().as_slice().to_vec();
+            }
+            self.skip_forward_past(&user_key);
+            if live {
+                self.key = user_key;
+                self.valid = self.err.is_none();
+                return;
+            }
+        }
+    }
+
+    fn find_prev(&mut self) {
+        self.valid = false;
+        while self.inner.valid() {
+            let parsed = match self.parse_inner() {
+                Some(k) => k,
+                None => return,
+            };
+            let user_key = parsed.user_key.as_slice().to_vec();
+            // the last entry of the user key met backward is the newest one
+            let mut live = false;
+            while self.inner.valid() {
+                match self.parse_inner() {
+                    Some(k)
+                        if self.ucmp.compare(k.user_key.as_slice(), &user_key)
+                            == Ordering::Equal =>
+                    {
+                        live = k.value_type == ValueType::Value;
+                        if live {
+                            self.value = self.inner.value().as_slice().to_vec();
+                        }
+                        self.inner.prev();
+                    }
+                    Some(_) => break,
+                    None => return,
+                }
+            }
+            if live {
+                self.key = user_key;
+                self.valid = true;
+                return;
+            }
+        }
+    }
+
+    // Positions `inner` at the newest entry of the current user key
+    fn seek_inner_to_current(&mut self) {
+        let target = InternalKey::new(
+            &Slice::from(self.key.as_slice()),
+            MAX_KEY_SEQUENCE,
+            VALUE_TYPE_FOR_SEEK,
+        );
+        self.inner.seek(&Slice::from(target.data()));
+    }
+}
+
+impl Iterator for SstSetIterator {
+    fn valid(&self) -> bool {
+        self.valid
+    }
+
+    fn seek_to_first(&mut self) {
+        self.forward = true;
+        self.inner.seek_to_first();
+        self.find_next();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.forward = false;
+        self.inner.seek_to_last();
+        self.find_prev();
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        self.forward = true;
+        let target = InternalKey::new(target, MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK);
+        self.inner.seek(&Slice::from(target.data()));
+        self.find_next();
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid);
+        if !self.forward {
+            self.forward = true;
+            self.seek_inner_to_current();
+            let key = std::mem::take(&mut self.key);
+            self.skip_forward_past(&key);
+        }
+        self.find_next();
+    }
+
+    fn prev(&mut self) {
+        assert!(self.valid);
+        if self.forward {
+            self.forward = false;
+            self.seek_inner_to_current();
+            if self.inner.valid() {
+                self.inner.prev();
+            } else {
+                self.inner.seek_to_last();
+            }
+        }
+        self.find_prev();
+    }
+
+    fn key(&self) -> Slice {
+        assert!(self.valid);
+        Slice::from(self.key.as_slice())
+    }
+
+    fn value(&self) -> Slice {
+        assert!(self.valid);
+        Slice::from(self.value.as_slice())
+    }
+
+    fn status(&mut self) -> Result<()> {
+        if let Some(e) = self.err.take() {
+            return Err(e);
+        }
+        self.inner.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sstable::table::TableBuilder;
+    use crate::storage::file::FileStorage;
+    use crate::storage::mem::MemStorage;
+    use crate::storage::Storage;
+    use crate::util::comparator::BytewiseComparator;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    // A comparator ordering the keys bytewise in reverse
+    struct ReverseComparator(BytewiseComparator);
+
+    impl Comparator for ReverseComparator {
+        fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+            self.0.compare(b, a)
+        }
+
+        fn name(&self) -> &str {
+            "ReverseComparator"
+        }
+
+        fn separator(&self, a: &[u8], _b: &[u8]) -> Vec<u8> {
+            a.to_vec()
+        }
+
+        fn successor(&self, key: &[u8]) -> Vec<u8> {
+            key.to_vec()
+        }
+    }
+
+    // Builds a table of the given (user key, sequence, value) entries, where a
+    // `None` value is a deletion
+    fn build_table(
+        options: &Options,
+        cmp: Arc<dyn Comparator>,
+        path: &str,
+        entries: &[(&str, u64, Option<&str>)],
+    ) {
+        let icmp = Arc::new(InternalKeyComparator::new(cmp));
+        let file = options.env.create(path).unwrap();
+        let mut opt = Options::default();
+        opt.env = options.env.clone();
+        let mut tb = TableBuilder::new_with_comparator(file, Arc::new(opt), icmp, 0);
+        for (key, seq, value) in entries {
+            let t = if value.is_some() {
+                ValueType::Value
+            } else {
+                ValueType::Deletion
+            };
+            let ikey = InternalKey::new(&Slice::from(*key), *seq, t);
+            tb.add(ikey.data(), value.unwrap_or("").as_bytes()).unwrap();
+        }
+        tb.finish(true).unwrap();
+    }
+
+    // Polls a future served by the files of `FileStorage`, which is always ready
+    fn poll_ready<T>(mut f: Pin<Box<dyn Future<Output = T> + '_>>) -> T {
+        fn noop_raw_waker() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker {
+                noop_raw_waker()
+            }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+        match f.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(v) => v,
+            Poll::Pending => panic!("the future should be ready"),
+        }
+    }
+
+    fn collect_forward(iter: &mut dyn Iterator) -> Vec<(String, String)> {
+        let mut entries = vec![];
+        iter.seek_to_first();
+        while iter.valid() {
+            entries.push((
+                iter.key().as_str().to_owned(),
+                iter.value().as_str().to_owned(),
+            ));
+            iter.next();
+        }
+        iter.status().unwrap();
+        entries
+    }
+
+    #[test]
+    fn test_sst_file_reader() {
+        let mut options = Options::default();
+        options.env = Arc::new(MemStorage::default());
+        let cmp: Arc<dyn Comparator> = Arc::new(BytewiseComparator {});
+        build_table(
+            &options,
+            cmp.clone(),
+            "000001.sst",
+            &[
+                ("a", 1, Some("a1")),
+                ("b", 2, Some("b2")),
+                ("c", 3, Some("c3")),
+                ("e", 4, Some("e4")),
+            ],
+        );
+        build_table(
+            &options,
+            cmp.clone(),
+            "000002.sst",
+            &[
+                ("b", 6, None),
+                ("c", 7, Some("c7")),
+                ("d", 5, Some("d5")),
+                ("e", 8, None),
+            ],
+        );
+        let reader = SstFileReader::open(options, cmp, &["000001.sst", "000002.sst"]).unwrap();
+        assert_eq!(reader.num_files(), 2);
+
+        let mut raw = reader.raw_iter(ReadOptions::default());
+        raw.seek_to_first();
+        let mut n = 0;
+        while raw.valid() {
+            n += 1;
+            raw.next();
+        }
+        assert_eq!(n, 8);
+
+        let mut iter = reader.iter(ReadOptions::default());
+        let expected = vec![
+            ("a".to_owned(), "a1".to_owned()),
+            ("c".to_owned(), "c7".to_owned()),
+            ("d".to_owned(), "d5".to_owned()),
+        ];
+        assert_eq!(collect_forward(iter.as_mut()), expected);
+
+        // backward
+        let mut entries = vec![];
+        iter.seek_to_last();
+        while iter.valid() {
+            entries.push((
+                iter.key().as_str().to_owned(),
+                iter.value().as_str().to_owned(),
+            ));
+            iter.prev();
+        }
+        entries.reverse();
+        assert_eq!(entries, expected);
+
+        // switching the directions
+        iter.seek(&Slice::from("b"));
+        assert_eq!(iter.key().as_str(), "c");
+        iter.next();
+        assert_eq!(iter.key().as_str(), "d");
+        iter.prev();
+        assert_eq!(iter.key().as_str(), "c");
+        assert_eq!(iter.value().as_str(), "c7");
+        iter.prev();
+        assert_eq!(iter.key().as_str(), "a");
+        iter.next();
+        assert_eq!(iter.key().as_str(), "c");
+        iter.seek(&Slice::from("e"));
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn test_sst_file_reader_with_comparator() {
+        let mut options = Options::default();
+        options.env = Arc::new(MemStorage::default());
+        let cmp: Arc<dyn Comparator> = Arc::new(ReverseComparator(BytewiseComparator {}));
+        build_table(
+            &options,
+            cmp.clone(),
+            "a.sst",
+            &[("c", 1, Some("c1")), ("a", 2, Some("a2"))],
+        );
+        build_table(&options, cmp.clone(), "b.sst", &[("b", 3, Some("b3"))]);
+        let reader = SstFileReader::open(options, cmp, &["a.sst", "b.sst"]).unwrap();
+        let mut iter = reader.iter(ReadOptions::default());
+        let keys: Vec<String> = collect_forward(iter.as_mut())
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(keys, vec!["c", "b", "a"]);
+    }
+
+    #[test]
+    fn test_sst_file_reader_get_async() {
+        let dir = "test_sst_file_reader_get_async";
+        let env: Arc<dyn Storage> = Arc::new(FileStorage);
+        env.mkdir_all(dir).unwrap();
+        let mut options = Options::default();
+        options.env = env.clone();
+        let cmp: Arc<dyn Comparator> = Arc::new(BytewiseComparator {});
+        let (p1, p2) = (format!("{}/000001.sst", dir), format!("{}/000002.sst", dir));
+        build_table(
+            &options,
+            cmp.clone(),
+            &p1,
+            &[
+                ("a", 1, Some("a1")),
+                ("b", 2, Some("b2")),
+                ("c", 3, Some("c3")),
+            ],
+        );
+        build_table(
+            &options,
+            cmp.clone(),
+            &p2,
+            &[("b", 5, None), ("c", 4, Some("c4")), ("d", 6, Some("d6"))],
+        );
+        let paths = [p1.as_str(), p2.as_str()];
+        let reader = poll_ready(Box::pin(SstFileReader::open_async(options, cmp, &paths))).unwrap();
+        assert_eq!(reader.num_files(), 2);
+        let get = |key: &str| {
+            poll_ready(Box::pin(
+                reader.get_async(ReadOptions::default(), key.as_bytes()),
+            ))
+            .unwrap()
+        };
+        assert_eq!(get("a"), Some(b"a1".to_vec()));
+        assert_eq!(get("b"), None);
+        assert_eq!(get("c"), Some(b"c4".to_vec()));
+        assert_eq!(get("d"), Some(b"d6".to_vec()));
+        assert_eq!(get("e"), None);
+        env.remove_dir(dir, true).unwrap();
+    }
+}
@@ -16,13 +16,14 @@
 // found in the LICENSE file. See the AUTHORS file for names of contributors.
 
 use crate::iterator::Iterator;
+use crate::statistics::{Statistics, Ticker};
 use crate::util::coding::{decode_fixed_32, put_fixed_32};
 use crate::util::comparator::Comparator;
 use crate::util::slice::Slice;
 use crate::util::status::{Result, Status, WickErr};
 use crate::util::varint::VarintU32;
 use std::cmp::{min, Ordering};
-use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use std::sync::Arc;
 
 // TODO: remove all magic number
@@ -44,7 +45,7 @@ use std::sync::Arc;
 ///
 #[derive(Clone, Debug)]
 pub struct Block {
-    data: Rc<Vec<u8>>,
+    data: Arc<Vec<u8>>,
     // offset in data of restart array
     restart_offset: u32,
 }
@@ -64,7 +65,7 @@ impl Block {
             // make sure the size is enough for restarts
             if restarts_len <= max_restarts_allowed {
                 return Ok(Self {
-                    data: Rc::new(data),
+                    data: Arc::new(data),
                     restart_offset: (size - (1 + restarts_len) * 4) as u32,
                 });
             }
@@ -75,15 +76,37 @@ impl Block {
         ))
     }
 
+    /// Creates a `Block` holding the raw contents of a block which is never
+    /// iterated, like a filter partition kept in the block cache.
+    pub(crate) fn raw(data: Vec<u8>) -> Self {
+        Self {
+            data: Arc::new(data),
+            restart_offset: 0,
+        }
+    }
+
+    /// Returns the contents of the block
+    #[inline]
+    pub(crate) fn contents(&self) -> &[u8] {
+        self.data.as_slice()
+    }
+
     /// Create a BlockIterator for current block.
     pub fn iter(&self, cmp: Arc<dyn Comparator>) -> Box<dyn Iterator> {
+        self.iter_with_telemetry(cmp, None)
+    }
+
+    /// Like `iter` but the seeks of the iterator are recorded into `telemetry`
+    pub(crate) fn iter_with_telemetry(
+        &self,
+        cmp: Arc<dyn Comparator>,
+        telemetry: Option<SeekTelemetry>,
+    ) -> Box<dyn Iterator> {
         let num_restarts = Self::restarts_len(self.data.as_slice());
-        Box::new(BlockIterator::new(
-            cmp,
-            self.data.clone(),
-            self.restart_offset,
-            num_restarts,
-        ))
+        let mut iter =
+            BlockIterator::new(cmp, self.data.clone(), self.restart_offset, num_restarts);
+        iter.telemetry = telemetry;
+        Box::new(iter)
     }
 
     // decoded the restarts length from block data
@@ -97,12 +120,112 @@ impl Block {
 impl Default for Block {
     fn default() -> Self {
         Self {
-            data: Rc::new(vec![]),
+            data: Arc::new(vec![]),
             restart_offset: 0,
         }
     }
 }
 
+/// The kinds of blocks whose seeks are recorded separately
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockKind {
+    Index,
+    Data,
+}
+
+impl BlockKind {
+    // The tickers of seeks, search steps and scanned entries
+    fn tickers(self) -> (Ticker, Ticker, Ticker) {
+        match self {
+            BlockKind::Index => (
+                Ticker::IndexBlockSeek,
+                Ticker::IndexBlockSearchSteps,
+                Ticker::IndexBlockScanEntries,
+            ),
+            BlockKind::Data => (
+                Ticker::DataBlockSeek,
+                Ticker::DataBlockSearchSteps,
+                Ticker::DataBlockScanEntries,
+            ),
+        }
+    }
+}
+
+/// The positioning work done by the seeks in a kind of blocks.
+///
+/// A seek first binary searches the restart points for the last one with a key
+/// less than the target, and then scans the entries after it linearly. Deep
+/// searches suggest a larger block or restart interval would not hurt much while
+/// long scans suggest a smaller restart interval.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockSeekReport {
+    /// Number of seeks
+    pub seeks: u64,
+    /// Total steps of the binary searches over restart points
+    pub search_steps: u64,
+    /// Total entries skipped by the linear scans before reaching the target
+    pub scanned_entries: u64,
+}
+
+impl BlockSeekReport {
+    /// Returns the average depth of the binary searches
+    pub fn avg_search_steps(&self) -> f64 {
+        if self.seeks == 0 {
+            return 0.0;
+        }
+        self.search_steps as f64 / self.seeks as f64
+    }
+
+    /// Returns the average distance between the restart point and the target
+    pub fn avg_scanned_entries(&self) -> f64 {
+        if self.seeks == 0 {
+            return 0.0;
+        }
+        self.scanned_entries as f64 / self.seeks as f64
+    }
+}
+
+// The accumulated `BlockSeekReport` shared by the iterators of a table
+#[derive(Default)]
+pub(crate) struct SeekCounters {
+    seeks: AtomicU64,
+    search_steps: AtomicU64,
+    scanned_entries: AtomicU64,
+}
+
+impl SeekCounters {
+    pub(crate) fn report(&self) -> BlockSeekReport {
+        BlockSeekReport {
+            seeks: self.seeks.load(AtomicOrdering::Relaxed),
+            search_steps: self.search_steps.load(AtomicOrdering::Relaxed),
+            scanned_entries: self.scanned_entries.load(AtomicOrdering::Relaxed),
+        }
+    }
+}
+
+/// Where a `BlockIterator` records its seeks
+#[derive(Clone)]
+pub(crate) struct SeekTelemetry {
+    pub(crate) kind: BlockKind,
+    pub(crate) counters: Arc<SeekCounters>,
+    pub(crate) statistics: Arc<Statistics>,
+}
+
+impl SeekTelemetry {
+    fn record(&self, search_steps: u64, scanned_entries: u64) {
+        let c = &self.counters;
+        c.seeks.fetch_add(1, AtomicOrdering::Relaxed);
+        c.search_steps
+            .fetch_add(search_steps, AtomicOrdering::Relaxed);
+        c.scanned_entries
+            .fetch_add(scanned_entries, AtomicOrdering::Relaxed);
+        let (seek, steps, scanned) = self.kind.tickers();
+        self.statistics.record_tick(seek, 1);
+        self.statistics.record_tick(steps, search_steps);
+        self.statistics.record_tick(scanned, scanned_entries);
+    }
+}
+
 /// Iterator for every entry in the block
 pub struct BlockIterator {
     cmp: Arc<dyn Comparator>,
@@ -110,7 +233,7 @@ pub struct BlockIterator {
     err: Option<WickErr>,
     // underlying block data
     // should never be modified in iterator
-    data: Rc<Vec<u8>>,
+    data: Arc<Vec<u8>>,
     /*
       restarts
     */
@@ -133,12 +256,14 @@ pub struct BlockIterator {
     //     could be formed by multiple segments which means we should
     //     maintain predictable amount of offsets for each key.
     key: Vec<u8>, // buffer for a completed key
+
+    telemetry: Option<SeekTelemetry>,
 }
 
 impl BlockIterator {
     pub fn new(
         cmp: Arc<dyn Comparator>,
-        data: Rc<Vec<u8>>,
+        data: Arc<Vec<u8>>,
         restarts: u32,
         restarts_len: u32,
     ) -> Self {
@@ -156,6 +281,7 @@ impl BlockIterator {
             value_len: 0,
             key_offset: 0,
             key: vec![],
+            telemetry: None,
         }
     }
 
@@ -180,6 +306,12 @@ impl BlockIterator {
     // mark as corrupted when the current entry tail overflows the starting offset of restarts
     fn parse_block_entry(&mut self) -> bool {
         let offset = self.current;
+        if offset >= self.restarts {
+            // No more entries to return. Mark as invalid.
+            self.current = self.restarts;
+            self.restart_index = self.restarts_len;
+            return false;
+        }
         let src = &self.data[offset as usize..];
         let (shared, n0) = VarintU32::common_read(src);
         let (not_shared, n1) = VarintU32::common_read(&src[n0 as usize..]);
@@ -255,7 +387,9 @@ impl Iterator for BlockIterator {
         // binary search in restart array to find the last restart point with a key < target
         let mut left = 0;
         let mut right = self.restarts_len - 1;
+        let mut search_steps = 0;
         while left < right {
+            search_steps += 1;
             let mid = (left + right + 1) / 2;
             let region_offset = self.get_restart_point(mid);
             let src = &self.data[region_offset as usize..];
@@ -280,16 +414,17 @@ impl Iterator for BlockIterator {
         // if all the keys > target, we seek to the start
         // if all the keys < target, we seek to the last
         self.seek_to_restart_point(left);
-        loop {
-            if !self.parse_block_entry() {
-                return;
-            }
-            match self.cmp.compare(self.key.as_slice(), target.as_slice()) {
-                Ordering::Less => {}
-                _ => return,
+        let mut scanned_entries = 0;
+        while self.parse_block_entry() {
+            if self.cmp.compare(self.key.as_slice(), target.as_slice()) != Ordering::Less {
+                break;
             }
+            scanned_entries += 1;
             self.current = self.next_entry_offset();
         }
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.record(search_steps, scanned_entries);
+        }
     }
 
     fn next(&mut self) {
@@ -0,0 +1,45 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::util::status::Result;
+
+/// The bit set in the block type (the compression type byte of the block
+/// trailer) of a data block compressed with the dictionary of its table
+pub(crate) const DICTIONARY_BLOCK_FLAG: u8 = 0x40;
+
+/// A `DictionaryCompressor` compresses the data blocks of a table with a
+/// dictionary trained from the table's own data, like the dictionary API of zstd.
+///
+/// The small blocks of small values compress poorly alone since every block
+/// starts from an empty history. A dictionary trained from the samples of the
+/// blocks primes the history with the common substrings so that even a single
+/// small block compresses well.
+///
+/// The compaction outputs buffer their first data blocks to train the dictionary
+/// by `train`, which is saved in a meta block of the table and passed to
+/// `decompress` when a data block is read.
+pub trait DictionaryCompressor: Send + Sync {
+    /// The name of the compressor, which is only used for the logging
+    fn name(&self) -> &str;
+
+    /// Trains a dictionary of at most `max_dict_bytes` bytes from the given samples
+    /// of the raw data blocks. An empty dictionary disables the dictionary
+    /// compression of the table.
+    fn train(&self, samples: &[Vec<u8>], max_dict_bytes: usize) -> Result<Vec<u8>>;
+
+    /// Compresses `data` with the dictionary
+    fn compress(&self, dict: &[u8], data: &[u8]) -> Result<Vec<u8>>;
+
+    /// Decompresses `data` compressed by `compress` with the same dictionary
+    fn decompress(&self, dict: &[u8], data: &[u8]) -> Result<Vec<u8>>;
+}
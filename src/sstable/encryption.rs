@@ -0,0 +1,54 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::util::coding::put_fixed_64;
+use crate::util::status::Result;
+
+/// The size of the tweak passed to a `BlockCipher`
+pub const BLOCK_TWEAK_SIZE: usize = 16;
+
+/// The bit set in the block type (the compression type byte of the block
+/// trailer) of an encrypted data block
+pub(crate) const ENCRYPTED_BLOCK_FLAG: u8 = 0x80;
+
+/// A `BlockCipher` encrypts the data blocks of the tables.
+///
+/// Only the (compressed) contents of the data blocks are encrypted. The filter,
+/// meta and index blocks, the block trailers and the footer are left plain so
+/// that the layout of a table could still be inspected without the key.
+///
+/// Every block is encrypted with a tweak unique in the db (see `block_tweak`) so
+/// that identical blocks never produce identical ciphertexts. The cipher must be
+/// length preserving, like AES-XTS or AES-CTR, since the block handles are
+/// decided by the lengths of the contents.
+pub trait BlockCipher: Send + Sync {
+    /// The name of the cipher, which is only used for the logging
+    fn name(&self) -> &str;
+
+    /// Encrypts `data` in place with the given tweak
+    fn encrypt(&self, tweak: &[u8; BLOCK_TWEAK_SIZE], data: &mut [u8]) -> Result<()>;
+
+    /// Decrypts `data` in place with the given tweak
+    fn decrypt(&self, tweak: &[u8; BLOCK_TWEAK_SIZE], data: &mut [u8]) -> Result<()>;
+}
+
+/// Returns the tweak of the block at `offset` of the table `file_number`, which
+/// is the fixed 64-bit encoded file number followed by the encoded offset.
+pub fn block_tweak(file_number: u64, offset: u64) -> [u8; BLOCK_TWEAK_SIZE] {
+    let mut buf = Vec::with_capacity(BLOCK_TWEAK_SIZE);
+    put_fixed_64(&mut buf, file_number);
+    put_fixed_64(&mut buf, offset);
+    let mut tweak = [0; BLOCK_TWEAK_SIZE];
+    tweak.copy_from_slice(buf.as_slice());
+    tweak
+}
@@ -15,10 +15,15 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file. See the AUTHORS file for names of contributors.
 
+use crate::cache::Cache;
 use crate::filter::FilterPolicy;
-use crate::util::coding::{decode_fixed_32, put_fixed_32};
+use crate::sstable::BlockHandle;
+use crate::util::coding::{decode_fixed_32, put_fixed_32, put_fixed_64};
 use crate::util::slice::Slice;
-use std::rc::Rc;
+use crate::util::varint::{VarintU32, VarintU64};
+use sha2::{Digest, Sha256};
+use std::mem;
+use std::sync::Arc;
 
 const FILTER_BASE_LG: usize = 11;
 const FILTER_BASE: usize = 1 << FILTER_BASE_LG; // 2KiB
@@ -28,7 +33,7 @@ const FILTER_META_LENGTH: usize = 5; // 4bytes filter offsets length + 1bytes ba
 /// particular Table.  It generates a single string which is stored as
 /// a special block in the Table.
 pub struct FilterBlockBuilder {
-    policy: Rc<dyn FilterPolicy>,
+    policy: Arc<dyn FilterPolicy>,
     // key contents
     // reused by every block
     keys: Vec<Vec<u8>>,
@@ -37,18 +42,74 @@ pub struct FilterBlockBuilder {
     data: Vec<u8>,
     // the offset of every filter in the data
     filter_offsets: Vec<u32>,
+    // the cache for sharing filters of identical data blocks between tables
+    reuse_cache: Option<Arc<dyn Cache<Vec<u8>>>>,
+    // the number of data blocks whose keys are in `keys`
+    pending_blocks: usize,
+    // the size and checksum of the last data block recorded
+    last_block: (u64, u32),
+    // the filters are cut into partitions of at least this many bytes if not 0
+    partition_size: usize,
+    // the finished partitions, each with the offset of the first data block it filters
+    partitions: Vec<(u64, Vec<u8>)>,
+    // the number of the filters in `partitions`
+    partition_base: usize,
 }
 
 impl FilterBlockBuilder {
-    pub fn new(policy: Rc<dyn FilterPolicy>) -> Self {
+    pub fn new(policy: Arc<dyn FilterPolicy>) -> Self {
         Self {
             policy,
             keys: vec![],
             filter_offsets: vec![],
             data: vec![],
+            reuse_cache: None,
+            pending_blocks: 0,
+            last_block: (0, 0),
+            partition_size: 0,
+            partitions: vec![],
+            partition_base: 0,
         }
     }
 
+    /// Cuts the filters into partitions of about `partition_size` bytes, each of
+    /// which is a standalone filter block of a range of the data blocks. The
+    /// partitions are taken by `finish_partitions` instead of `finish`.
+    /// A `partition_size` of 0 keeps all the filters in one block.
+    pub fn with_partition_size(mut self, partition_size: usize) -> Self {
+        self.partition_size = partition_size;
+        self
+    }
+
+    /// Returns true if the filters are cut into partitions
+    #[inline]
+    pub fn is_partitioned(&self) -> bool {
+        self.partition_size > 0
+    }
+
+    /// Creates a `FilterBlockBuilder` which shares the filters with other builders by `cache`.
+    ///
+    /// When all the keys of a filter come from a single data block and a data block
+    /// with the same size, checksum and keys has been filtered before, the filter is
+    /// copied from the cache instead of being computed from the keys again. This
+    /// happens when a compaction rewrites blocks untouched. The keys are compared by
+    /// their SHA-256 digest, so the blocks of different keys never share a filter.
+    pub fn new_with_reuse_cache(
+        policy: Arc<dyn FilterPolicy>,
+        cache: Arc<dyn Cache<Vec<u8>>>,
+    ) -> Self {
+        let mut b = Self::new(policy);
+        b.reuse_cache = Some(cache);
+        b
+    }
+
+    /// Records a data block whose keys have been added by `add_key`.
+    /// `size` is the block size and `checksum` is the crc in the block trailer.
+    pub fn add_block(&mut self, size: u64, checksum: u32) {
+        self.pending_blocks += 1;
+        self.last_block = (size, checksum);
+    }
+
     /// Adds the given key into the builder
     pub fn add_key(&mut self, key: &Slice) {
         let key = Vec::from(key.as_slice());
@@ -61,7 +122,7 @@ impl FilterBlockBuilder {
         // the filter with the index i filters the block data
         // from i* FILTER_BASE ~ (i + 1) * FILTER_BASE
         let filter_index = block_offset / FILTER_BASE as u64;
-        let filters_len = self.filter_offsets.len() as u64;
+        let filters_len = (self.partition_base + self.filter_offsets.len()) as u64;
         assert!(
             filter_index >= filters_len,
             "[filter block builder] the filter block index {} should larger than built filters {}",
@@ -69,9 +130,17 @@ impl FilterBlockBuilder {
             filters_len,
         );
         // the loop here is a little tricky
-        while filter_index > self.filter_offsets.len() as u64 {
+        while filter_index > (self.partition_base + self.filter_offsets.len()) as u64 {
             self.generate_filter();
         }
+        // the keys of the new block go to a new partition
+        if self.is_partitioned()
+            && self.keys.is_empty()
+            && !self.filter_offsets.is_empty()
+            && self.data.len() >= self.partition_size
+        {
+            self.cut_partition();
+        }
     }
 
     /// Appends the trailer of filter block and returns the filter block data in bytes
@@ -80,6 +149,34 @@ impl FilterBlockBuilder {
             // clean up the remaining keys
             self.generate_filter();
         };
+        self.append_trailer();
+        self.data.as_slice()
+    }
+
+    /// Finishes the partitions and returns them in order, each with the offset
+    /// of the first data block it filters. See `with_partition_size`.
+    pub fn finish_partitions(&mut self) -> Vec<(u64, Vec<u8>)> {
+        if !self.keys.is_empty() {
+            self.generate_filter();
+        }
+        if !self.filter_offsets.is_empty() {
+            self.cut_partition();
+        }
+        mem::take(&mut self.partitions)
+    }
+
+    // Finishes the filters generated so far as a partition
+    fn cut_partition(&mut self) {
+        self.append_trailer();
+        let first_offset = (self.partition_base as u64) << FILTER_BASE_LG;
+        self.partition_base += self.filter_offsets.len();
+        self.filter_offsets.clear();
+        self.partitions
+            .push((first_offset, mem::take(&mut self.data)));
+    }
+
+    // Appends the offsets of the filters and the trailer to `data`
+    fn append_trailer(&mut self) {
         // append per-filter offsets
         for i in 0..self.filter_offsets.len() {
             put_fixed_32(&mut self.data, self.filter_offsets[i]);
@@ -88,89 +185,208 @@ impl FilterBlockBuilder {
         put_fixed_32(&mut self.data, self.filter_offsets.len() as u32);
         // append the 1byte base lg
         self.data.push(FILTER_BASE_LG as u8);
-        self.data.as_slice()
     }
 
     // convert 'keys' to the filter by 'policy'
     fn generate_filter(&mut self) {
         let num_keys = self.keys.len();
+        self.filter_offsets.push(self.data.len() as u32);
         if num_keys == 0 {
             // fast path if there are no keys
-            self.filter_offsets.push(self.data.len() as u32)
+            self.pending_blocks = 0;
+            return;
         };
-        let filter = self.policy.create_filter(self.keys.as_slice());
-        self.filter_offsets.push(self.data.len() as u32);
-        self.data.extend(filter);
+        let reuse_key = self.reuse_key();
+        let cached = match (&self.reuse_cache, &reuse_key) {
+            (Some(cache), Some(key)) => cache.look_up(key).map(|h| {
+                let filter = h.value().unwrap();
+                cache.release(h);
+                filter
+            }),
+            _ => None,
+        };
+        match cached {
+            Some(filter) => self.data.extend(filter),
+            None => {
+                let filter = self.policy.create_filter(self.keys.as_slice());
+                if let (Some(cache), Some(key)) = (&self.reuse_cache, reuse_key) {
+                    let charge = filter.len();
+                    let h = cache.insert(key, filter.clone(), charge, None);
+                    cache.release(h);
+                }
+                self.data.extend(filter);
+            }
+        }
         // clear the keys
         self.keys.clear();
+        self.pending_blocks = 0;
+    }
+
+    // Returns the key in the reuse cache for current keys iff they are from exactly one data block.
+    // The keys are identified by the SHA-256 digest of their number and every key prefixed
+    // with its length instead of being copied into the cache.
+    fn reuse_key(&self) -> Option<Vec<u8>> {
+        if self.reuse_cache.is_none() || self.pending_blocks != 1 {
+            return None;
+        }
+        let mut digest = Sha256::new();
+        let mut buf = vec![];
+        put_fixed_32(&mut buf, self.keys.len() as u32);
+        digest.update(&buf);
+        for key in self.keys.iter() {
+            buf.clear();
+            VarintU32::put_varint(&mut buf, key.len() as u32);
+            digest.update(&buf);
+            digest.update(key);
+        }
+        let mut key = Vec::from(self.policy.name().as_bytes());
+        put_fixed_64(&mut key, self.last_block.0);
+        put_fixed_32(&mut key, self.last_block.1);
+        key.extend_from_slice(digest.finalize().as_slice());
+        Some(key)
     }
 }
 
 pub struct FilterBlockReader {
-    policy: Rc<dyn FilterPolicy>,
-    // all filter block data without filter meta
-    // | ----- filter data ----- | ----- filter offsets ----|
-    //                                   num * 4 bytes
+    policy: Arc<dyn FilterPolicy>,
+    // the whole filter block including the filter meta
+    // | ----- filter data ----- | ----- filter offsets ----| -- meta -- |
+    //                                   num * 4 bytes          5 bytes
     data: Vec<u8>,
-    // the amount of filter data
-    num: usize,
-    base_lg: usize,
 }
 
 impl FilterBlockReader {
-    pub fn new(policy: Rc<dyn FilterPolicy>, mut filter_block: Vec<u8>) -> Self {
-        let mut r = FilterBlockReader {
+    pub fn new(policy: Arc<dyn FilterPolicy>, filter_block: Vec<u8>) -> Self {
+        FilterBlockReader {
             policy,
-            data: vec![],
-            num: 0,
-            base_lg: 0,
-        };
-        let n = filter_block.len();
-        if n < FILTER_META_LENGTH {
-            return r;
-        }
-        r.num = decode_fixed_32(&filter_block[n - FILTER_META_LENGTH..n - 1]) as usize;
-        // invalid filter offsets length
-        if r.num * 4 + FILTER_META_LENGTH > n {
-            return r;
+            data: filter_block,
         }
-        r.base_lg = filter_block[n - 1] as usize;
-        filter_block.truncate(n - FILTER_META_LENGTH);
-        r.data = filter_block;
-        r
     }
 
     /// Returns iff the given key is probably contained in the given `block_offset` block
     pub fn key_may_match(&self, block_offset: u64, key: &Slice) -> bool {
-        let i = block_offset as usize >> self.base_lg; // a >> b == a / (1 << b)
-        if i < self.num {
-            let (filter, offsets) = self
-                .data
-                .as_slice()
-                .split_at(self.data.len() - self.num * 4);
-            let start = decode_fixed_32(&offsets[i * 4..i * 4 + 4]) as usize;
-            let end = {
-                if i + 1 >= self.num {
-                    // this is the last filter
-                    filter.len()
-                } else {
-                    decode_fixed_32(&offsets[i * 4 + 4..i * 4 + 8]) as usize
-                }
-            };
-            let filter = &self.data[start..end];
-            return self.policy.may_contain(filter, key);
+        filter_may_match(self.policy.as_ref(), &self.data, block_offset, key)
+    }
+}
+
+/// Returns iff the given key is probably contained in the data block on
+/// `block_offset` according to the encoded `filter_block`, which is either a
+/// whole filter block or a partition of it. The `block_offset` of a partition
+/// is relative to the first data block it filters.
+pub(crate) fn filter_may_match(
+    policy: &dyn FilterPolicy,
+    filter_block: &[u8],
+    block_offset: u64,
+    key: &Slice,
+) -> bool {
+    let n = filter_block.len();
+    // errors are treated as potential matches
+    // so the iterator will look up the block
+    if n < FILTER_META_LENGTH {
+        return true;
+    }
+    let num = decode_fixed_32(&filter_block[n - FILTER_META_LENGTH..n - 1]) as usize;
+    // invalid filter offsets length
+    if num * 4 + FILTER_META_LENGTH > n {
+        return true;
+    }
+    let base_lg = filter_block[n - 1] as u32;
+    let i = match block_offset.checked_shr(base_lg) {
+        Some(i) => i as usize, // a >> b == a / (1 << b)
+        None => return true,
+    };
+    if i >= num {
+        return true;
+    }
+    let offsets_start = n - FILTER_META_LENGTH - num * 4;
+    let offsets = &filter_block[offsets_start..n - FILTER_META_LENGTH];
+    let start = decode_fixed_32(&offsets[i * 4..i * 4 + 4]) as usize;
+    let end = if i + 1 >= num {
+        // this is the last filter
+        offsets_start
+    } else {
+        decode_fixed_32(&offsets[i * 4 + 4..i * 4 + 8]) as usize
+    };
+    if start > end || end > offsets_start {
+        return true;
+    }
+    policy.may_contain(&filter_block[start..end], key)
+}
+
+/// The index of the filter partitions of a table, ordered by the offsets of the
+/// first data blocks they filter.
+///
+/// # Encoding
+///
+/// ```text
+/// +--------------------------------------+
+/// | first offset (varint64) | handle     |  x num
+/// +--------------------------------------+
+/// | num (fixed32)                        |
+/// +--------------------------------------+
+/// ```
+pub struct FilterPartitionIndex {
+    partitions: Vec<(u64, BlockHandle)>,
+}
+
+impl FilterPartitionIndex {
+    /// Encodes the partitions written at the given handles
+    pub fn encode(partitions: &[(u64, BlockHandle)]) -> Vec<u8> {
+        let mut dst = vec![];
+        for (first_offset, handle) in partitions.iter() {
+            VarintU64::put_varint(&mut dst, *first_offset);
+            dst.extend_from_slice(handle.encoded().as_slice());
         }
-        // errors are treated as potential matches
-        // so the iterator will look up the block
-        true
+        put_fixed_32(&mut dst, partitions.len() as u32);
+        dst
+    }
+
+    /// Decodes the index encoded by `encode`. Returns `None` if it's corrupted.
+    pub fn decode(mut src: &[u8]) -> Option<Self> {
+        if src.len() < 4 {
+            return None;
+        }
+        let num = decode_fixed_32(&src[src.len() - 4..]) as usize;
+        src = &src[..src.len() - 4];
+        let mut partitions = Vec::with_capacity(num.min(src.len()));
+        for _ in 0..num {
+            let (first_offset, n) = VarintU64::read(src)?;
+            let (handle, m) = BlockHandle::decode_from(&src[n..]).ok()?;
+            src = &src[n + m..];
+            partitions.push((first_offset, handle));
+        }
+        if !src.is_empty() {
+            return None;
+        }
+        Some(Self { partitions })
+    }
+
+    /// Returns the handles of all the partitions
+    pub fn handles(&self) -> impl std::iter::Iterator<Item = &BlockHandle> {
+        self.partitions.iter().map(|(_, handle)| handle)
+    }
+
+    /// Returns the partition filtering the data block on `block_offset` with the
+    /// offset of the first data block it filters
+    pub fn partition_of(&self, block_offset: u64) -> Option<(u64, &BlockHandle)> {
+        let i = self
+            .partitions
+            .partition_point(|(first_offset, _)| *first_offset <= block_offset);
+        if i == 0 {
+            return None;
+        }
+        let (first_offset, handle) = &self.partitions[i - 1];
+        Some((*first_offset, handle))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cache::lru::SharedLRUCache;
     use crate::filter::FilterPolicy;
     use crate::util::hash::hash;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     struct TestHashFilter {}
 
@@ -202,10 +418,10 @@ mod tests {
     }
 
     fn new_test_builder() -> FilterBlockBuilder {
-        FilterBlockBuilder::new(Rc::new(TestHashFilter {}))
+        FilterBlockBuilder::new(Arc::new(TestHashFilter {}))
     }
     fn new_test_reader(block: Vec<u8>) -> FilterBlockReader {
-        FilterBlockReader::new(Rc::new(TestHashFilter {}), block)
+        FilterBlockReader::new(Arc::new(TestHashFilter {}), block)
     }
 
     #[test]
@@ -283,4 +499,122 @@ mod tests {
         assert_eq!(r.key_may_match(9000, &Slice::from("bar")), false);
         assert_eq!(r.key_may_match(9000, &Slice::from("hello")), true);
     }
+
+    // A `TestHashFilter` counting the keys it hashes
+    struct CountingFilter {
+        inner: TestHashFilter,
+        hashed: AtomicUsize,
+    }
+
+    impl FilterPolicy for CountingFilter {
+        fn name(&self) -> &str {
+            self.inner.name()
+        }
+
+        fn may_contain(&self, filter: &[u8], key: &Slice) -> bool {
+            self.inner.may_contain(filter, key)
+        }
+
+        fn create_filter(&self, keys: &[Vec<u8>]) -> Vec<u8> {
+            self.hashed.fetch_add(keys.len(), Ordering::SeqCst);
+            self.inner.create_filter(keys)
+        }
+    }
+
+    #[test]
+    fn test_reuse_filter_of_identical_block() {
+        let policy = Arc::new(CountingFilter {
+            inner: TestHashFilter {},
+            hashed: AtomicUsize::new(0),
+        });
+        let cache: Arc<dyn Cache<Vec<u8>>> = Arc::new(SharedLRUCache::new(1 << 20));
+        let build = |blocks: &[(&[&str], u32)]| {
+            let mut b = FilterBlockBuilder::new_with_reuse_cache(policy.clone(), cache.clone());
+            let mut offset = 0;
+            b.start_block(offset);
+            for (keys, checksum) in blocks.iter() {
+                for k in keys.iter() {
+                    b.add_key(&Slice::from(*k));
+                }
+                b.add_block(3000, *checksum);
+                offset += 3000;
+                b.start_block(offset);
+            }
+            Vec::from(b.finish())
+        };
+        let first = build(&[(&["a", "b"], 1), (&["c", "d"], 2)]);
+        assert_eq!(policy.hashed.load(Ordering::SeqCst), 4);
+        // the second block is rewritten untouched
+        let second = build(&[(&["a", "bb"], 3), (&["c", "d"], 2)]);
+        assert_eq!(policy.hashed.load(Ordering::SeqCst), 6);
+        let r = FilterBlockReader::new(policy.clone(), second.clone());
+        assert!(r.key_may_match(0, &Slice::from("bb")));
+        assert!(!r.key_may_match(0, &Slice::from("b")));
+        assert!(r.key_may_match(3000, &Slice::from("c")));
+        assert!(r.key_may_match(3000, &Slice::from("d")));
+        // a different checksum means a different block
+        build(&[(&["c", "d"], 4)]);
+        assert_eq!(policy.hashed.load(Ordering::SeqCst), 8);
+        assert_ne!(first, second);
+        // the blocks of the same size, checksum and boundary keys
+        build(&[(&["x", "m", "z"], 5)]);
+        assert_eq!(policy.hashed.load(Ordering::SeqCst), 11);
+        let collided = build(&[(&["x", "n", "z"], 5)]);
+        assert_eq!(policy.hashed.load(Ordering::SeqCst), 14);
+        let r = FilterBlockReader::new(policy.clone(), collided);
+        assert!(r.key_may_match(0, &Slice::from("n")));
+        assert!(!r.key_may_match(0, &Slice::from("m")));
+    }
+
+    #[test]
+    fn test_partitioned_filters() {
+        // every partition holds at least 2 keys of the `TestHashFilter`
+        let mut b = new_test_builder().with_partition_size(8);
+        assert!(b.is_partitioned());
+        let blocks: &[(u64, &[&str])] = &[
+            (0, &["a"]),
+            (3000, &["b", "c"]),
+            (6000, &["d"]),
+            (9000, &["e"]),
+            (12000, &["f"]),
+        ];
+        for (offset, keys) in blocks.iter() {
+            b.start_block(*offset);
+            for k in keys.iter() {
+                b.add_key(&Slice::from(*k));
+            }
+        }
+        let partitions = b.finish_partitions();
+        let first_offsets: Vec<u64> = partitions.iter().map(|(o, _)| *o).collect();
+        assert_eq!(first_offsets, vec![0, 4096, 10240]);
+
+        let handles: Vec<(u64, BlockHandle)> = partitions
+            .iter()
+            .enumerate()
+            .map(|(i, (o, p))| (*o, BlockHandle::new(i as u64 * 100, p.len() as u64)))
+            .collect();
+        let index = FilterPartitionIndex::decode(&FilterPartitionIndex::encode(&handles)).unwrap();
+        assert_eq!(index.partitions.len(), 3);
+        let policy = TestHashFilter {};
+        let may_match = |offset: u64, key: &str| {
+            let (first_offset, handle) = index.partition_of(offset).unwrap();
+            let partition = &partitions[(handle.offset / 100) as usize].1;
+            filter_may_match(&policy, partition, offset - first_offset, &Slice::from(key))
+        };
+        for (offset, keys) in blocks.iter() {
+            for k in keys.iter() {
+                assert!(may_match(*offset, k));
+            }
+            assert!(!may_match(*offset, "missing"));
+        }
+        assert!(!may_match(0, "b"));
+        assert!(!may_match(3000, "a"));
+        assert!(!may_match(12000, "e"));
+        // the corrupted index is refused
+        let encoded = FilterPartitionIndex::encode(&handles);
+        let mut truncated = encoded[..encoded.len() - 5].to_vec();
+        truncated.extend_from_slice(&encoded[encoded.len() - 4..]);
+        assert!(FilterPartitionIndex::decode(&truncated).is_none());
+        assert!(FilterPartitionIndex::decode(&[]).is_none());
+    }
 }
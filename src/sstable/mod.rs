@@ -22,9 +22,10 @@
 /// a metaindex block, an index block and a table footer. Metaindex block
 /// is a special block used to keep parameters of the table, such as filter
 /// block name and its block handle. Index block is a special block used to
-/// keep record of data blocks offset and length, index block use one as
-/// restart interval. The key used by index block are the last key of preceding
-/// block, shorter separator of adjacent blocks or shorter successor of the
+/// keep record of data blocks offset and length, index block use
+/// `index_block_restart_interval` (default one) as restart interval. The
+/// key used by index block are the last key of preceding block, shorter
+/// separator of adjacent blocks or shorter successor of the
 /// last key of the last block. Filter block is an optional block contains
 /// sequence of filter data generated by a filter generator.
 ///
@@ -49,8 +50,14 @@
 ///     | compression type (1-byte) | checksum (4-byte) |
 ///     +---------------------------+-------------------+
 ///
-///     The checksum is a CRC-32 computed using Castagnoli's polynomial. Compression
-///     type also included in the checksum.
+///     The checksum is a masked CRC-32 computed using Castagnoli's polynomial, or the
+///     low 32 bits of the xxHash64 if bits 4-5 of the compression type are 1 (see
+///     `Options::checksum_type`). Compression type also included in the checksum.
+///
+///     The highest bit of the compression type is set if the block contents are
+///     encrypted by `Options::block_cipher`, and the checksum covers the ciphertext.
+///     The bit 6 is set if the block contents are compressed with the dictionary of
+///     the table by `Options::dictionary_compressor`.
 ///
 /// ```
 ///
@@ -58,14 +65,19 @@
 ///
 /// ```text
 ///
-///       +------------------- 40-bytes -------------------+
-///      /                                                  \
-///     +------------------------+--------------------+------+-----------------+
-///     | metaindex block handle / index block handle / ---- | magic (8-bytes) |
-///     +------------------------+--------------------+------+-----------------+
+///       +------------------------- 40-bytes -------------------------+
+///      /                                                              \
+///     +------------------------+--------------------+------+--------------------------+-----------------+
+///     | metaindex block handle / index block handle / ---- | format version (4-bytes) | magic (8-bytes) |
+///     +------------------------+--------------------+------+--------------------------+-----------------+
 ///
 ///     The magic are first 64-bit of SHA-1 sum of "http://code.google.com/p/leveldb/".
 ///
+///     The format version is zero in the legacy footers, which are padded by zeros
+///     after the two handles as LevelDB does, so a table written by LevelDB is of
+///     version 0. A reader only honors the features of the blocks allowed by the
+///     version of the table, see `Options::format_version`.
+///
 /// ```
 ///
 /// NOTE: All fixed-length integer are little-endian.
@@ -184,36 +196,69 @@
 ///
 /// ```
 ///
+/// If `blocks_per_index_entry` is larger than one, the value of an index entry is a group of
+/// adjacent block handles and the separator key is the one after the last data block in the group.
+/// Only the tables of format version 1 or later group the block handles.
+///
 /// NOTE: All fixed-length integer are little-endian.
 ///
 /// # Meta block
 ///
 /// This meta block contains a bunch of stats. The key is the name of the statistic. The value contains the statistic.
-/// For the current implementation, the meta block only contains the filter meta data:
+/// For the current implementation, the meta block contains the handle of the compression
+/// dictionary if the data blocks are compressed with one, the filter meta data and the
+/// handle of the properties block holding the `TableProperties`:
 ///
 /// ```text
 ///
-///     +-------------+---------------------+
-///     |     key     |        value        |
-///     +-------------+---------------------+
-///     | filter name | filter block handle |
-///     +-------------+---------------------+
+///     +------------------------+-------------------------+
+///     |          key           |          value          |
+///     +------------------------+-------------------------+
+///     | compression.dictionary | dictionary block handle |
+///     +------------------------+-------------------------+
+///     | filter name            | filter block handle     |
+///     +------------------------+-------------------------+
+///     | wickdb.properties      | properties block handle |
+///     +------------------------+-------------------------+
 ///
 /// ```
 ///
 /// NOTE: All fixed-length integer are little-endian.
 pub mod block;
+pub mod dictionary;
+pub mod encryption;
 mod filter_block;
+pub mod properties;
 pub mod table;
 
-use crate::util::coding::{decode_fixed_64, put_fixed_64};
+use crate::util::coding::{decode_fixed_32, decode_fixed_64, put_fixed_32, put_fixed_64};
 use crate::util::status::{Status, WickErr};
 use crate::util::varint::{VarintU64, MAX_VARINT_LEN_U64};
 
-const TABLE_MAGIC_NUMBER: u64 = 0xdb4775248b80fb57;
+pub(crate) const TABLE_MAGIC_NUMBER: u64 = 0xdb4775248b80fb57;
 
 // 1byte compression type + 4bytes cyc
-const BLOCK_TRAILER_SIZE: usize = 5;
+pub(crate) const BLOCK_TRAILER_SIZE: usize = 5;
+
+// The bits of the block type (the first byte of the block trailer) holding the
+// `ChecksumType` of the block
+pub(crate) const CHECKSUM_TYPE_MASK: u8 = 0x30;
+pub(crate) const CHECKSUM_TYPE_SHIFT: u8 = 4;
+
+/// The format version of the tables written by LevelDB. The tables of this
+/// version only use the CRC32C checksums and carry no compression dictionary.
+pub const LEGACY_FORMAT_VERSION: u32 = 0;
+
+/// The latest format version of the tables. Since this version the blocks could
+/// be checksummed by any `ChecksumType`, the data blocks could be compressed with
+/// a dictionary and an index entry could point a group of data blocks, see
+/// `Options::blocks_per_index_entry`.
+pub const LATEST_FORMAT_VERSION: u32 = 1;
+
+// The first format version whose index entries could group several data blocks.
+// The readers before it take only the first block of an entry, so grouping in a
+// table of an older version must never happen.
+pub(crate) const GROUPED_INDEX_FORMAT_VERSION: u32 = 1;
 
 // Maximum encoding length of a BlockHandle
 const MAX_BLOCK_HANDLE_ENCODE_LENGTH: usize = 2 * MAX_VARINT_LEN_U64;
@@ -223,6 +268,9 @@ const MAX_BLOCK_HANDLE_ENCODE_LENGTH: usize = 2 * MAX_VARINT_LEN_U64;
 // of two block handles and a magic number.
 const FOOTER_ENCODED_LENGTH: usize = 2 * MAX_BLOCK_HANDLE_ENCODE_LENGTH + 8;
 
+// The format version takes the last 4 bytes of the padding after the handles
+const FOOTER_VERSION_OFFSET: usize = 2 * MAX_BLOCK_HANDLE_ENCODE_LENGTH - 4;
+
 /// `BlockHandle` is a pointer to the extent of a file that stores a data
 /// block or a meta block.
 #[derive(Eq, PartialEq, Debug, Clone)]
@@ -247,6 +295,16 @@ impl BlockHandle {
         self.size = size
     }
 
+    #[inline]
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
     /// Appends varint encoded offset and size into given `dst`
     #[inline]
     pub fn encoded_to(&self, dst: &mut Vec<u8>) {
@@ -279,6 +337,22 @@ impl BlockHandle {
             Err(WickErr::new(Status::Corruption, Some("bad block handle")))
         }
     }
+
+    /// Decodes all the BlockHandles encoded one by one in `src`.
+    /// An index entry may point to several adjacent data blocks
+    /// (see `Options::blocks_per_index_entry`).
+    pub fn decode_all_from(mut src: &[u8]) -> Result<Vec<Self>, WickErr> {
+        let mut handles = vec![];
+        while !src.is_empty() {
+            let (h, n) = Self::decode_from(src)?;
+            handles.push(h);
+            src = &src[n..];
+        }
+        if handles.is_empty() {
+            return Err(WickErr::new(Status::Corruption, Some("bad block handle")));
+        }
+        Ok(handles)
+    }
 }
 
 /// `Footer` encapsulates the fixed information stored at the tail
@@ -287,6 +361,8 @@ impl BlockHandle {
 pub struct Footer {
     meta_index_handle: BlockHandle,
     index_handle: BlockHandle,
+    format_version: u32,
+    magic: u64,
 }
 
 impl Footer {
@@ -295,41 +371,94 @@ impl Footer {
         Self {
             meta_index_handle,
             index_handle,
+            format_version: LATEST_FORMAT_VERSION,
+            magic: TABLE_MAGIC_NUMBER,
         }
     }
 
+    /// Sets the format version to be encoded. See `Options::format_version`.
+    #[inline]
+    pub fn with_format_version(mut self, format_version: u32) -> Self {
+        self.format_version = format_version;
+        self
+    }
+
+    /// Returns the format version of the table
+    #[inline]
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
+
+    /// Sets the magic number to be encoded. See `Options::table_magic_number`.
+    #[inline]
+    pub fn with_magic(mut self, magic: u64) -> Self {
+        self.magic = magic;
+        self
+    }
+
     /// Decodes a `Footer` from the given `src` bytes and returns the decoded length
     ///
     /// # Error
     ///
     /// Returns `Status::Corruption` when decoding meta index or index handle fails
     ///
+    #[cfg(test)]
     pub fn decode_from(src: &[u8]) -> Result<(Self, usize), WickErr> {
+        Self::decode_with_magic(src, TABLE_MAGIC_NUMBER)
+    }
+
+    /// Like `decode_from` but the footer must carry the given magic number.
+    /// A table of the default format decoded with another magic number is
+    /// reported as a table of another format rather than a corrupted one.
+    pub fn decode_with_magic(src: &[u8], expected_magic: u64) -> Result<(Self, usize), WickErr> {
         let magic = decode_fixed_64(&src[FOOTER_ENCODED_LENGTH - 8..]);
-        if magic != TABLE_MAGIC_NUMBER {
-            return Err(WickErr::new(
-                Status::Corruption,
-                Some("not an sstable (bad magic number)"),
-            ));
+        if magic != expected_magic {
+            let msg = if magic == TABLE_MAGIC_NUMBER {
+                "the sstable is of another format (mismatched magic number)"
+            } else {
+                "not an sstable (bad magic number)"
+            };
+            return Err(WickErr::new(Status::Corruption, Some(msg)));
         };
         let (meta_index_handle, n) = BlockHandle::decode_from(src)?;
         let (index_handle, m) = BlockHandle::decode_from(&src[n..])?;
+        // A legacy footer whose handles run into the version field is of version 0
+        let format_version = if m + n <= FOOTER_VERSION_OFFSET {
+            decode_fixed_32(&src[FOOTER_VERSION_OFFSET..])
+        } else {
+            LEGACY_FORMAT_VERSION
+        };
         Ok((
             Self {
                 meta_index_handle,
                 index_handle,
+                format_version,
+                magic,
             },
             m + n,
         ))
     }
 
     /// Encodes footer and returns the encoded bytes
+    ///
+    /// # Panics
+    ///
+    /// Panics if the handles don't leave room for a non-legacy format version,
+    /// which requires a table much larger than any file system allows.
     pub fn encoded(&self) -> Vec<u8> {
         let mut v = vec![];
         self.meta_index_handle.encoded_to(&mut v);
         self.index_handle.encoded_to(&mut v);
+        if self.format_version != LEGACY_FORMAT_VERSION {
+            assert!(
+                v.len() <= FOOTER_VERSION_OFFSET,
+                "[footer] no room for the format version"
+            );
+            v.resize(FOOTER_VERSION_OFFSET, 0);
+            put_fixed_32(&mut v, self.format_version);
+        }
         v.resize(2 * MAX_BLOCK_HANDLE_ENCODE_LENGTH, 0);
-        put_fixed_64(&mut v, TABLE_MAGIC_NUMBER);
+        put_fixed_64(&mut v, self.magic);
         assert_eq!(
             v.len(),
             FOOTER_ENCODED_LENGTH,
@@ -343,7 +472,7 @@ impl Footer {
 
 #[cfg(test)]
 mod test_footer {
-    use crate::sstable::{BlockHandle, Footer};
+    use crate::sstable::{BlockHandle, Footer, LATEST_FORMAT_VERSION, LEGACY_FORMAT_VERSION};
     use crate::util::status::Status;
     use std::error::Error;
 
@@ -368,6 +497,52 @@ mod test_footer {
         assert_eq!(footer.index_handle, BlockHandle::new(401, 1000));
         assert_eq!(footer.meta_index_handle, BlockHandle::new(300, 100));
     }
+
+    #[test]
+    fn test_footer_format_version() {
+        let footer = Footer::new(BlockHandle::new(300, 100), BlockHandle::new(401, 1000));
+        let (decoded, _) = Footer::decode_from(&footer.encoded()).unwrap();
+        assert_eq!(decoded.format_version(), LATEST_FORMAT_VERSION);
+
+        // A legacy footer is padded by zeros
+        let legacy = Footer::new(BlockHandle::new(300, 100), BlockHandle::new(401, 1000))
+            .with_format_version(LEGACY_FORMAT_VERSION)
+            .encoded();
+        assert!(legacy[8..40].iter().all(|b| *b == 0));
+        let (decoded, _) = Footer::decode_from(&legacy).unwrap();
+        assert_eq!(decoded.format_version(), LEGACY_FORMAT_VERSION);
+        assert_eq!(decoded.index_handle, BlockHandle::new(401, 1000));
+
+        // The handles of a legacy footer may take the whole padding
+        let huge = BlockHandle::new(u64::MAX, u64::MAX);
+        let legacy = Footer::new(huge.clone(), huge.clone())
+            .with_format_version(LEGACY_FORMAT_VERSION)
+            .encoded();
+        let (decoded, _) = Footer::decode_from(&legacy).unwrap();
+        assert_eq!(decoded.format_version(), LEGACY_FORMAT_VERSION);
+        assert_eq!(decoded.meta_index_handle, huge);
+    }
+
+    #[test]
+    fn test_footer_with_magic() {
+        let magic = 0x1234_5678_9abc_def0;
+        let footer = Footer::new(BlockHandle::new(300, 100), BlockHandle::new(401, 1000));
+        let custom = footer.with_magic(magic).encoded();
+        let (footer, _) = Footer::decode_with_magic(&custom, magic).unwrap();
+        assert_eq!(footer.index_handle, BlockHandle::new(401, 1000));
+        assert_eq!(
+            Footer::decode_from(&custom).unwrap_err().description(),
+            "not an sstable (bad magic number)"
+        );
+        let vanilla =
+            Footer::new(BlockHandle::new(300, 100), BlockHandle::new(401, 1000)).encoded();
+        assert_eq!(
+            Footer::decode_with_magic(&vanilla, magic)
+                .unwrap_err()
+                .description(),
+            "the sstable is of another format (mismatched magic number)"
+        );
+    }
 }
 
 #[cfg(test)]
@@ -501,7 +676,7 @@ mod tests {
         fn finish(&mut self, options: Arc<Options>, data: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
             let file_name = "test_table";
             let file = options.env.create(file_name)?;
-            let mut builder = TableBuilder::new(file, options.clone());
+            let mut builder = TableBuilder::new(file, options.clone(), 0);
             for (key, value) in data {
                 builder
                     .add(key.as_slice(), value.as_slice())
@@ -555,7 +730,7 @@ mod tests {
 
         fn seek(&mut self, target: &Slice) {
             let lkey = LookupKey::new(target.as_slice(), MAX_KEY_SEQUENCE);
-            self.inner.seek(&lkey.mem_key());
+            self.inner.seek(&lkey.internal_key());
         }
 
         fn next(&mut self) {
@@ -1025,6 +1200,14 @@ mod tests {
         for (t, reverse_cmp, restart_interval) in tests.drain(..) {
             results.push(TestHarness::new(t, reverse_cmp, restart_interval));
         }
+        // Tables with several data blocks sharing one index entry
+        for reverse_cmp in [false, true].iter() {
+            let mut harness = TestHarness::new(TestType::Table, *reverse_cmp, 16);
+            let options = Arc::get_mut(&mut harness.options).unwrap();
+            options.index_block_restart_interval = 4;
+            options.blocks_per_index_entry = 3;
+            results.push(harness);
+        }
         results
     }
 
@@ -0,0 +1,170 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::iterator::Iterator;
+use crate::sstable::block::{Block, BlockBuilder};
+use crate::util::comparator::BytewiseComparator;
+use crate::util::status::{Result, Status, WickErr};
+use crate::util::varint::VarintU64;
+use std::sync::Arc;
+
+const COMPARATOR: &str = "wickdb.comparator";
+const CREATION_TIME: &str = "wickdb.creation.time";
+const FILTER_SIZE: &str = "wickdb.filter.size";
+const NEWEST_SEQUENCE: &str = "wickdb.newest.sequence";
+const NUM_DATA_BLOCKS: &str = "wickdb.num.data.blocks";
+const NUM_DELETIONS: &str = "wickdb.num.deletions";
+const NUM_ENTRIES: &str = "wickdb.num.entries";
+const OLDEST_SEQUENCE: &str = "wickdb.oldest.sequence";
+const RAW_KEY_SIZE: &str = "wickdb.raw.key.size";
+const RAW_VALUE_SIZE: &str = "wickdb.raw.value.size";
+
+/// The properties of a table collected by the `TableBuilder` and saved in the
+/// properties block of the table.
+///
+/// The properties block is a block mapping the property names to the values,
+/// which are varint64s except the comparator name. The unknown names are ignored
+/// when reading so that new properties could be added without breaking the old
+/// readers, and the properties missing in an old table are left as default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TableProperties {
+    /// The number of the entries
+    pub num_entries: u64,
+    /// The number of the deletions among the entries. Only counted for the tables
+    /// of internal keys.
+    pub num_deletions: u64,
+    /// The total bytes of the keys before compression, excluding the sequence
+    /// numbers and the value types of internal keys
+    pub raw_key_size: u64,
+    /// The total bytes of the values before compression
+    pub raw_value_size: u64,
+    /// The number of the data blocks
+    pub num_data_blocks: u64,
+    /// The size of the filter block, or 0 without `Options::filter_policy`
+    pub filter_size: u64,
+    /// The name of the comparator ordering the user keys
+    pub comparator_name: String,
+    /// When the table is built in seconds since the epoch by `Options::clock`
+    pub creation_time: u64,
+    /// The smallest sequence number of the entries. Only recorded for the tables
+    /// of internal keys.
+    pub oldest_sequence: u64,
+    /// The largest sequence number of the entries. Only recorded for the tables
+    /// of internal keys.
+    pub newest_sequence: u64,
+}
+
+impl TableProperties {
+    /// Encodes the properties into a block
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut entries = vec![(COMPARATOR, self.comparator_name.as_bytes().to_vec())];
+        for (name, value) in [
+            (CREATION_TIME, self.creation_time),
+            (FILTER_SIZE, self.filter_size),
+            (NEWEST_SEQUENCE, self.newest_sequence),
+            (NUM_DATA_BLOCKS, self.num_data_blocks),
+            (NUM_DELETIONS, self.num_deletions),
+            (NUM_ENTRIES, self.num_entries),
+            (OLDEST_SEQUENCE, self.oldest_sequence),
+            (RAW_KEY_SIZE, self.raw_key_size),
+            (RAW_VALUE_SIZE, self.raw_value_size),
+        ]
+        .iter()
+        {
+            let mut buf = vec![];
+            VarintU64::put_varint(&mut buf, *value);
+            entries.push((*name, buf));
+        }
+        // the names must be added in order
+        entries.sort();
+        let mut builder = BlockBuilder::new(1, Arc::new(BytewiseComparator::new()));
+        for (name, value) in entries.iter() {
+            builder.add(name.as_bytes(), value.as_slice());
+        }
+        builder.finish().to_vec()
+    }
+
+    /// Decodes the properties from the contents of a properties block
+    pub(crate) fn decode(contents: Vec<u8>) -> Result<Self> {
+        let block = Block::new(contents)?;
+        let mut iter = block.iter(Arc::new(BytewiseComparator::new()));
+        let mut properties = Self::default();
+        iter.seek_to_first();
+        while iter.valid() {
+            let key = iter.key();
+            let value = iter.value();
+            let name = match std::str::from_utf8(key.as_slice()) {
+                Ok(name) => name,
+                Err(_) => {
+                    iter.next();
+                    continue;
+                }
+            };
+            if name == COMPARATOR {
+                properties.comparator_name = String::from_utf8_lossy(value.as_slice()).into_owned();
+                iter.next();
+                continue;
+            }
+            let field = match name {
+                CREATION_TIME => &mut properties.creation_time,
+                FILTER_SIZE => &mut properties.filter_size,
+                NEWEST_SEQUENCE => &mut properties.newest_sequence,
+                NUM_DATA_BLOCKS => &mut properties.num_data_blocks,
+                NUM_DELETIONS => &mut properties.num_deletions,
+                NUM_ENTRIES => &mut properties.num_entries,
+                OLDEST_SEQUENCE => &mut properties.oldest_sequence,
+                RAW_KEY_SIZE => &mut properties.raw_key_size,
+                RAW_VALUE_SIZE => &mut properties.raw_value_size,
+                _ => {
+                    iter.next();
+                    continue;
+                }
+            };
+            *field = match VarintU64::read(value.as_slice()) {
+                Some((v, _)) => v,
+                None => return Err(WickErr::new(Status::Corruption, Some("bad table property"))),
+            };
+            iter.next();
+        }
+        iter.status()?;
+        Ok(properties)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let properties = TableProperties {
+            num_entries: 100,
+            num_deletions: 7,
+            raw_key_size: 1000,
+            raw_value_size: 20000,
+            num_data_blocks: 5,
+            filter_size: 128,
+            comparator_name: "leveldb.BytewiseComparator".to_owned(),
+            creation_time: 1_600_000_000,
+            oldest_sequence: 3,
+            newest_sequence: 109,
+        };
+        let decoded = TableProperties::decode(properties.encode()).unwrap();
+        assert_eq!(decoded, properties);
+        assert_eq!(
+            TableProperties::decode(TableProperties::default().encode()).unwrap(),
+            TableProperties::default()
+        );
+        assert!(TableProperties::decode(vec![1, 2]).is_err());
+    }
+}
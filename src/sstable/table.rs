@@ -15,33 +15,96 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file. See the AUTHORS file for names of contributors.
 
+use crate::db::format::ValueType;
+use crate::filter::bloom::{BloomFilter, BLOOM_FILTER_NAME};
+use crate::filter::FilterPolicy;
 use crate::iterator::{ConcatenateIterator, DerivedIterFactory, Iterator};
-use crate::options::{CompressionType, Options, ReadOptions};
-use crate::sstable::block::{Block, BlockBuilder};
-use crate::sstable::filter_block::{FilterBlockBuilder, FilterBlockReader};
-use crate::sstable::{BlockHandle, Footer, BLOCK_TRAILER_SIZE, FOOTER_ENCODED_LENGTH};
-use crate::storage::File;
-use crate::util::coding::{decode_fixed_32, put_fixed_32, put_fixed_64};
-use crate::util::comparator::Comparator;
+use crate::options::{ChecksumType, CompressionType, Options, ReadOptions, SyncPolicy};
+use crate::sstable::block::{
+    Block, BlockBuilder, BlockKind, BlockSeekReport, SeekCounters, SeekTelemetry,
+};
+use crate::sstable::dictionary::{DictionaryCompressor, DICTIONARY_BLOCK_FLAG};
+use crate::sstable::encryption::{block_tweak, BlockCipher, ENCRYPTED_BLOCK_FLAG};
+use crate::sstable::filter_block::{
+    filter_may_match, FilterBlockBuilder, FilterBlockReader, FilterPartitionIndex,
+};
+use crate::sstable::properties::TableProperties;
+use crate::sstable::{
+    BlockHandle, Footer, BLOCK_TRAILER_SIZE, CHECKSUM_TYPE_MASK, CHECKSUM_TYPE_SHIFT,
+    FOOTER_ENCODED_LENGTH, GROUPED_INDEX_FORMAT_VERSION, LATEST_FORMAT_VERSION,
+    LEGACY_FORMAT_VERSION,
+};
+use crate::statistics::{record_tick, record_user_read, HistogramType, StopWatch, Ticker};
+use crate::storage::{read_exact_at_async, AsyncFile, File, ReadRequest};
+use crate::util::coding::{decode_fixed_32, decode_fixed_64, put_fixed_32, put_fixed_64};
+use crate::util::comparator::{BytewiseComparator, Comparator};
 use crate::util::crc32::{extend, mask, unmask, value};
+use crate::util::histogram::Histogram;
 use crate::util::slice::Slice;
-use crate::util::status::{Result, Status, WickErr};
+use crate::util::status::{CorruptionReport, Result, Status, WickErr};
+use crate::util::varint::VarintU32;
 use snap::max_compress_len;
 use std::cmp::Ordering;
+use std::mem;
 use std::rc::Rc;
 use std::sync::Arc;
+use xxhash_rust::xxh64::{xxh64, Xxh64};
 
 /// A `Table` is a sorted map from strings to strings.  Tables are
 /// immutable and persistent.  A Table may be safely accessed from
 /// multiple threads without external synchronization.
 pub struct Table {
     options: Arc<Options>,
-    file: Box<dyn File>,
+    // the comparator of the keys in data blocks and index block
+    cmp: Arc<dyn Comparator>,
+    file: TableFile,
     cache_id: u64,
     filter_reader: Option<FilterBlockReader>,
+    // the index of the filter partitions if the filters are partitioned, in
+    // which case `filter_reader` is None
+    filter_partitions: Option<FilterPartitionIndex>,
+    // whether the filters hold the prefixes given by `Options::prefix_extractor`
+    prefix_filtered: bool,
+    // the length of the suffix stripped from the keys looked up in the filters
+    // if the filters hold the user keys
+    whole_key_suffix: Option<usize>,
     // None iff we fail to read meta block
     meta_block_handle: Option<BlockHandle>,
+    // the handle of the meta block in the footer, which might be not read yet
+    meta_index_handle: BlockHandle,
     index_block: Block,
+    // the format version in the footer
+    format_version: u32,
+    // Seeks in the index block and the data blocks
+    index_seeks: Arc<SeekCounters>,
+    data_seeks: Arc<SeekCounters>,
+    // the tweaks of the encrypted data blocks are derived from it
+    file_number: u64,
+    // the handle of the compression dictionary found in the meta block
+    compression_dict_handle: Option<BlockHandle>,
+    // the dictionary of the data blocks compressed by `Options::dictionary_compressor`
+    compression_dict: Vec<u8>,
+}
+
+/// The seek telemetry of a `Table` accumulated since it's opened. It's only
+/// collected when `Options::statistics` is set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TableSeekReport {
+    /// Seeks in the index block
+    pub index: BlockSeekReport,
+    /// Seeks in the data blocks
+    pub data: BlockSeekReport,
+}
+
+// The underlying sst file of a `Table`.
+//
+// A table opened by `Table::open_async_with_comparator` only serves the
+// `*_async` methods since the blocking ones would have to wait for the async file.
+enum TableFile {
+    // Only used during opening
+    Closed,
+    Sync(Box<dyn File>),
+    Async(Box<dyn AsyncFile>),
 }
 
 // Common methods
@@ -49,73 +112,413 @@ impl Table {
     /// Attempt to open the table that is stored in bytes `[0..size)`
     /// of `file`, and read the metadata entries necessary to allow
     /// retrieving data from the table.
+    #[cfg(test)]
     pub fn open(file: Box<dyn File>, size: u64, options: Arc<Options>) -> Result<Self> {
-        if size < FOOTER_ENCODED_LENGTH as u64 {
-            return Err(WickErr::new(
-                Status::Corruption,
-                Some("file is too short to be an sstable"),
-            ));
+        let cmp = options.comparator.clone();
+        Self::open_with_comparator(file, size, options, cmp)
+    }
+
+    /// Like `open` but the keys in the table are ordered by `cmp` instead of
+    /// `options.comparator`. The DB uses this to open the tables of internal keys.
+    pub fn open_with_comparator(
+        file: Box<dyn File>,
+        size: u64,
+        options: Arc<Options>,
+        cmp: Arc<dyn Comparator>,
+    ) -> Result<Self> {
+        let footer = read_footer(file.as_ref(), size, options.table_magic_number)?;
+        let read_meta = footer.meta_index_handle.size > 0
+            && (options.filter_policy.is_some() || options.dictionary_compressor.is_some());
+        // Read the index block together with the meta block if needed
+        let (index_block_contents, meta_block_contents) = if read_meta {
+            let handles = [
+                footer.index_handle.clone(),
+                footer.meta_index_handle.clone(),
+            ];
+            match read_blocks(file.as_ref(), &handles, options.paranoid_checks) {
+                Ok(mut blocks) => {
+                    let meta = blocks.pop().unwrap();
+                    (blocks.pop().unwrap(), Ok(meta))
+                }
+                // Read them separately to tell which one fails
+                Err(_) => (
+                    read_block(file.as_ref(), &footer.index_handle, options.paranoid_checks)?,
+                    read_block(
+                        file.as_ref(),
+                        &footer.meta_index_handle,
+                        options.paranoid_checks,
+                    ),
+                ),
+            }
+        } else {
+            let index = read_block(file.as_ref(), &footer.index_handle, options.paranoid_checks)?;
+            (index, Err(WickErr::new(Status::NotFound, None)))
         };
+        let mut t = Self::new(options.clone(), cmp, index_block_contents)?;
+        t.format_version = footer.format_version();
+        t.meta_index_handle = footer.meta_index_handle.clone();
+        // Read meta block
+        if read_meta {
+            // ignore the reading errors since meta info is not needed for operation
+            if let Ok(meta_block_contents) = meta_block_contents {
+                if let Some((filter_handle, partitioned)) =
+                    t.read_meta_block(meta_block_contents, footer.meta_index_handle)
+                {
+                    if let Ok(filter_block) =
+                        read_block(file.as_ref(), &filter_handle, options.paranoid_checks)
+                    {
+                        t.set_filter_block(filter_block, partitioned);
+                    }
+                }
+            }
+        }
+        // the data blocks are unreadable without the dictionary
+        if let Some(handle) = &t.compression_dict_handle {
+            t.compression_dict = read_block(file.as_ref(), handle, options.paranoid_checks)?;
+        }
+        t.file = TableFile::Sync(file);
+        Ok(t)
+    }
+
+    /// Like `open_with_comparator` but reads the table through an `AsyncFile`.
+    ///
+    /// The returned table serves point lookups by `internal_get_async` and
+    /// blocks by `block_reader_async`.
+    pub async fn open_async_with_comparator(
+        file: Box<dyn AsyncFile>,
+        size: u64,
+        options: Arc<Options>,
+        cmp: Arc<dyn Comparator>,
+    ) -> Result<Self> {
+        check_table_size(size)?;
         // Read footer
         let mut footer_space = vec![0; FOOTER_ENCODED_LENGTH];
-        file.read_exact_at(
+        read_exact_at_async(
+            file.as_ref(),
             footer_space.as_mut_slice(),
             size - FOOTER_ENCODED_LENGTH as u64,
-        )?;
-        let (footer, _) = Footer::decode_from(footer_space.as_slice())?;
+        )
+        .await?;
+        let footer = decode_footer(footer_space.as_slice(), size, options.table_magic_number)?;
         // Read the index block
         let index_block_contents =
-            read_block(file.as_ref(), &footer.index_handle, options.paranoid_checks)?;
+            read_block_async(file.as_ref(), &footer.index_handle, options.paranoid_checks).await?;
+        let mut t = Self::new(options.clone(), cmp, index_block_contents)?;
+        t.format_version = footer.format_version();
+        t.meta_index_handle = footer.meta_index_handle.clone();
+        // Read meta block
+        if footer.meta_index_handle.size > 0
+            && (options.filter_policy.is_some() || options.dictionary_compressor.is_some())
+        {
+            // ignore the reading errors since meta info is not needed for operation
+            if let Ok(meta_block_contents) = read_block_async(
+                file.as_ref(),
+                &footer.meta_index_handle,
+                options.paranoid_checks,
+            )
+            .await
+            {
+                if let Some((filter_handle, partitioned)) =
+                    t.read_meta_block(meta_block_contents, footer.meta_index_handle)
+                {
+                    if let Ok(filter_block) =
+                        read_block_async(file.as_ref(), &filter_handle, options.paranoid_checks)
+                            .await
+                    {
+                        t.set_filter_block(filter_block, partitioned);
+                    }
+                }
+            }
+        }
+        if let Some(handle) = &t.compression_dict_handle {
+            t.compression_dict =
+                read_block_async(file.as_ref(), handle, options.paranoid_checks).await?;
+        }
+        t.file = TableFile::Async(file);
+        Ok(t)
+    }
+
+    // Creates a `Table` with the given index block. The `file` is set by the caller.
+    fn new(
+        options: Arc<Options>,
+        cmp: Arc<dyn Comparator>,
+        index_block_contents: Vec<u8>,
+    ) -> Result<Self> {
         let index_block = Block::new(index_block_contents)?;
         let cache_id = if let Some(cache) = &options.block_cache {
             cache.new_id()
         } else {
             0
         };
-        let mut t = Self {
-            options: options.clone(),
-            file,
+        Ok(Self {
+            options,
+            cmp,
+            file: TableFile::Closed,
             cache_id,
             filter_reader: None,
+            filter_partitions: None,
+            prefix_filtered: false,
+            whole_key_suffix: None,
             meta_block_handle: None,
+            meta_index_handle: BlockHandle::new(0, 0),
             index_block,
+            format_version: LEGACY_FORMAT_VERSION,
+            index_seeks: Arc::new(SeekCounters::default()),
+            data_seeks: Arc::new(SeekCounters::default()),
+            file_number: 0,
+            compression_dict_handle: None,
+            compression_dict: vec![],
+        })
+    }
+
+    /// Returns the format version in the footer of the table
+    #[cfg(test)]
+    #[inline]
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
+
+    /// Sets the file number of the table, which is required to read the data
+    /// blocks encrypted by `Options::block_cipher`.
+    pub fn with_file_number(mut self, file_number: u64) -> Self {
+        self.file_number = file_number;
+        self
+    }
+
+    /// Returns the file number set by `with_file_number`
+    #[inline]
+    pub fn file_number(&self) -> u64 {
+        self.file_number
+    }
+
+    // Returns the cipher of the encrypted data blocks and the file number
+    #[inline]
+    fn cipher(&self) -> Option<(&dyn BlockCipher, u64)> {
+        self.options
+            .block_cipher
+            .as_deref()
+            .map(|c| (c, self.file_number))
+    }
+
+    // Returns the compressor and the dictionary of the dictionary compressed data blocks
+    #[inline]
+    fn dictionary(&self) -> Option<(&dyn DictionaryCompressor, &[u8])> {
+        if self.compression_dict.is_empty() {
+            return None;
+        }
+        self.options
+            .dictionary_compressor
+            .as_deref()
+            .map(|c| (c, self.compression_dict.as_slice()))
+    }
+
+    // Records the meta block and the handle of the compression dictionary in it,
+    // and returns the handle of the filter block in it if any, with whether it's
+    // the index of the filter partitions
+    fn read_meta_block(
+        &mut self,
+        meta_block_contents: Vec<u8>,
+        meta_block_handle: BlockHandle,
+    ) -> Option<(BlockHandle, bool)> {
+        let meta_block = Block::new(meta_block_contents).ok()?;
+        self.meta_block_handle = Some(meta_block_handle);
+        // the keys in meta block are always ordered bytewise
+        let mut iter = meta_block.iter(Arc::new(BytewiseComparator::new()));
+        // a legacy table never carries a dictionary
+        if self.format_version != LEGACY_FORMAT_VERSION
+            && self.options.dictionary_compressor.is_some()
+        {
+            iter.seek(&Slice::from(COMPRESSION_DICT_KEY));
+            if iter.valid() && iter.key().as_slice() == COMPRESSION_DICT_KEY.as_bytes() {
+                if let Ok((handle, _)) = BlockHandle::decode_from(iter.value().as_slice()) {
+                    self.compression_dict_handle = Some(handle);
+                }
+            }
+        }
+        let policy_name = if let Some(fp) = &self.options.filter_policy {
+            fp.name().to_owned()
+        } else {
+            return None;
         };
-        // Read meta block
-        if footer.meta_index_handle.size > 0 && options.filter_policy.is_some() {
-            // ignore the reading errors since meta info is not needed for operation
-            if let Ok(meta_block_contents) = read_block(
-                t.file.as_ref(),
-                &footer.meta_index_handle,
-                options.paranoid_checks,
-            ) {
-                if let Ok(meta_block) = Block::new(meta_block_contents) {
-                    t.meta_block_handle = Some(footer.meta_index_handle);
-                    let mut iter = meta_block.iter(options.comparator.clone());
-                    let filter_key = if let Some(fp) = &options.filter_policy {
-                        "filter.".to_owned() + fp.name()
-                    } else {
-                        String::from("")
-                    };
-                    // Read filter block
-                    iter.seek(&Slice::from(filter_key.as_bytes()));
-                    if iter.valid() && iter.key().as_str() == filter_key.as_str() {
-                        if let Ok((filter_handle, _)) =
-                            BlockHandle::decode_from(iter.value().as_slice())
-                        {
-                            if let Ok(filter_block) =
-                                read_block(t.file.as_ref(), &filter_handle, options.paranoid_checks)
-                            {
-                                t.filter_reader = Some(FilterBlockReader::new(
-                                    t.options.filter_policy.clone().unwrap(),
-                                    filter_block,
-                                ));
-                            }
-                        }
-                    }
+        if let Some(extractor) = &self.options.prefix_extractor {
+            let prefix_key = PREFIX_EXTRACTOR_KEY_PREFIX.to_owned() + extractor.name();
+            iter.seek(&Slice::from(prefix_key.as_bytes()));
+            self.prefix_filtered = iter.valid() && iter.key().as_slice() == prefix_key.as_bytes();
+        }
+        iter.seek(&Slice::from(WHOLE_KEY_FILTER_KEY));
+        if iter.valid() && iter.key().as_slice() == WHOLE_KEY_FILTER_KEY.as_bytes() {
+            self.whole_key_suffix = iter.value().as_slice().first().map(|n| *n as usize);
+        }
+        // Read filter block
+        for (key_prefix, partitioned) in [
+            (FILTER_KEY_PREFIX, false),
+            (PARTITIONED_FILTER_KEY_PREFIX, true),
+        ]
+        .iter()
+        {
+            let filter_key = key_prefix.to_string() + &policy_name;
+            iter.seek(&Slice::from(filter_key.as_bytes()));
+            if iter.valid() && iter.key().as_str() == filter_key.as_str() {
+                if let Ok((filter_handle, _)) = BlockHandle::decode_from(iter.value().as_slice()) {
+                    return Some((filter_handle, *partitioned));
                 }
             }
         }
-        Ok(t)
+        None
+    }
+
+    /// Reads the histograms of the key sizes and the value sizes recorded by
+    /// `Options::record_kv_size_histograms`, or returns `None` if the table doesn't
+    /// record them.
+    pub(crate) fn read_kv_size_histograms(&self) -> Result<Option<(Histogram, Histogram)>> {
+        let contents = match self.read_meta_entry_block(KV_SIZE_HISTOGRAMS_KEY)? {
+            Some(contents) => contents,
+            None => return Ok(None),
+        };
+        let mut src = Slice::from(contents.as_slice());
+        let corrupted = || WickErr::new(Status::Corruption, Some("bad kv size histograms"));
+        let mut next = || {
+            VarintU32::get_varint_prefixed_slice(&mut src)
+                .and_then(|h| Histogram::decode_from(h.as_slice()))
+                .ok_or_else(corrupted)
+        };
+        let key_sizes = next()?;
+        let value_sizes = next()?;
+        Ok(Some((key_sizes, value_sizes)))
+    }
+
+    /// Reads the `TableProperties` saved in the properties block, or returns `None`
+    /// if the table is built before the properties block is introduced.
+    pub(crate) fn read_properties(&self) -> Result<Option<TableProperties>> {
+        match self.read_meta_entry_block(PROPERTIES_KEY)? {
+            Some(contents) => TableProperties::decode(contents).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Reads the block the entry `key` of the meta block points to
+    fn read_meta_entry_block(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let file = match &self.file {
+            TableFile::Sync(file) => file,
+            _ => {
+                return Err(WickErr::new(
+                    Status::NotSupported,
+                    Some("table is opened by an async file"),
+                ))
+            }
+        };
+        if self.meta_index_handle.size == 0 {
+            return Ok(None);
+        }
+        let contents = read_block(
+            file.as_ref(),
+            &self.meta_index_handle,
+            self.options.paranoid_checks,
+        )?;
+        let meta_block = Block::new(contents)?;
+        let mut iter = meta_block.iter(Arc::new(BytewiseComparator::new()));
+        iter.seek(&Slice::from(key));
+        if !iter.valid() || iter.key().as_slice() != key.as_bytes() {
+            return Ok(None);
+        }
+        let (handle, _) = BlockHandle::decode_from(iter.value().as_slice())?;
+        read_block(file.as_ref(), &handle, self.options.paranoid_checks).map(Some)
+    }
+
+    /// Returns the seek telemetry of the table
+    pub fn seek_report(&self) -> TableSeekReport {
+        TableSeekReport {
+            index: self.index_seeks.report(),
+            data: self.data_seeks.report(),
+        }
+    }
+
+    // Returns where the iterators of the given kind of blocks record their seeks
+    fn seek_telemetry(&self, kind: BlockKind) -> Option<SeekTelemetry> {
+        let statistics = self.options.statistics.clone()?;
+        let counters = match kind {
+            BlockKind::Index => self.index_seeks.clone(),
+            BlockKind::Data => self.data_seeks.clone(),
+        };
+        Some(SeekTelemetry {
+            kind,
+            counters,
+            statistics,
+        })
+    }
+
+    fn index_iter(&self) -> Box<dyn Iterator> {
+        self.index_block
+            .iter_with_telemetry(self.cmp.clone(), self.seek_telemetry(BlockKind::Index))
+    }
+
+    fn set_filter_block(&mut self, filter_block: Vec<u8>, partitioned: bool) {
+        if partitioned {
+            self.filter_partitions = FilterPartitionIndex::decode(&filter_block);
+        } else {
+            self.filter_reader = Some(FilterBlockReader::new(
+                self.options.filter_policy.clone().unwrap(),
+                filter_block,
+            ));
+        }
+    }
+
+    #[inline]
+    fn has_filter(&self) -> bool {
+        self.filter_reader.is_some() || self.filter_partitions.is_some()
+    }
+
+    // Returns iff the given key is probably contained in the data block on
+    // `block_offset` according to the filters. The partition of a partitioned
+    // filter is read through the block cache, and any failure is treated as a
+    // potential match.
+    fn key_may_match(&self, block_offset: u64, key: &Slice) -> bool {
+        if let Some(filter) = &self.filter_reader {
+            return filter.key_may_match(block_offset, key);
+        }
+        let partition = self
+            .filter_partitions
+            .as_ref()
+            .and_then(|index| index.partition_of(block_offset));
+        let (first_offset, handle) = match partition {
+            Some(p) => p,
+            None => return true,
+        };
+        match (
+            self.options.filter_policy.as_deref(),
+            self.filter_partition(handle),
+        ) {
+            (Some(policy), Ok(block)) => {
+                filter_may_match(policy, block.contents(), block_offset - first_offset, key)
+            }
+            _ => true,
+        }
+    }
+
+    // Returns the filter partition on `handle` from the block cache, or reads it
+    // from the file and caches it
+    fn filter_partition(&self, handle: &BlockHandle) -> Result<Arc<Block>> {
+        if let Some(b) = self.cached_block(handle) {
+            return Ok(b);
+        }
+        let file = match &self.file {
+            TableFile::Sync(file) => file,
+            _ => {
+                return Err(WickErr::new(
+                    Status::NotSupported,
+                    Some("table is opened by an async file"),
+                ))
+            }
+        };
+        let data = read_block(file.as_ref(), handle, self.options.paranoid_checks)?;
+        self.record_block_read(&data);
+        let charge = data.len();
+        let b = Arc::new(Block::raw(data));
+        if let Some(cache) = &self.options.block_cache {
+            let h = cache.insert(self.block_cache_key(handle), b.clone(), charge, None);
+            cache.release(h);
+        }
+        Ok(b)
     }
 
     /// Converts an BlockHandle into an iterator over the contents of the corresponding block.
@@ -124,72 +527,219 @@ impl Table {
         data_block_handle: BlockHandle,
         options: Rc<ReadOptions>,
     ) -> Result<Box<dyn Iterator>> {
-        let block = if let Some(cache) = &self.options.block_cache {
-            let mut cache_key_buffer = vec![0; 16];
-            put_fixed_64(&mut cache_key_buffer, self.cache_id);
-            put_fixed_64(&mut cache_key_buffer, data_block_handle.offset);
-            if let Some(cache_handle) = cache.look_up(&cache_key_buffer.as_slice()) {
-                let b = cache_handle.value().unwrap().clone();
-                cache.release(cache_handle);
-                b
-            } else {
-                let data = read_block(
-                    self.file.as_ref(),
-                    &data_block_handle,
-                    options.verify_checksums,
-                )?;
-                let charge = data.len();
-                let new_block = Block::new(data)?;
-                let b = Arc::new(new_block);
-                if options.fill_cache {
-                    // TODO: avoid clone
-                    cache.insert(cache_key_buffer, b.clone(), charge, None);
-                }
-                b
+        let file = match &self.file {
+            TableFile::Sync(file) => file,
+            _ => {
+                return Err(WickErr::new(
+                    Status::NotSupported,
+                    Some("table is opened by an async file"),
+                ))
+            }
+        };
+        let block = match self.cached_block(&data_block_handle) {
+            Some(b) => b,
+            None => {
+                let data = {
+                    let _w = StopWatch::new(
+                        self.options.clock.as_ref(),
+                        self.options.statistics.as_deref(),
+                        HistogramType::BlockReadMicros,
+                    );
+                    read_encrypted_block(
+                        file.as_ref(),
+                        &data_block_handle,
+                        options.verify_checksums,
+                        self.cipher(),
+                        self.dictionary(),
+                    )?
+                };
+                self.record_block_read(&data);
+                self.insert_block(&data_block_handle, data, options.fill_cache)?
+            }
+        };
+        Ok(block.iter_with_telemetry(self.cmp.clone(), self.seek_telemetry(BlockKind::Data)))
+    }
+
+    /// The async version of `block_reader`
+    pub async fn block_reader_async(
+        &self,
+        data_block_handle: BlockHandle,
+        options: Rc<ReadOptions>,
+    ) -> Result<Box<dyn Iterator>> {
+        let block = match self.cached_block(&data_block_handle) {
+            Some(b) => b,
+            None => {
+                let w = StopWatch::new(
+                    self.options.clock.as_ref(),
+                    self.options.statistics.as_deref(),
+                    HistogramType::BlockReadMicros,
+                );
+                let data = match &self.file {
+                    TableFile::Sync(file) => read_encrypted_block(
+                        file.as_ref(),
+                        &data_block_handle,
+                        options.verify_checksums,
+                        self.cipher(),
+                        self.dictionary(),
+                    )?,
+                    TableFile::Async(file) => {
+                        read_encrypted_block_async(
+                            file.as_ref(),
+                            &data_block_handle,
+                            options.verify_checksums,
+                            self.cipher(),
+                            self.dictionary(),
+                        )
+                        .await?
+                    }
+                    TableFile::Closed => {
+                        return Err(WickErr::new(
+                            Status::Unexpected,
+                            Some("table is not opened"),
+                        ))
+                    }
+                };
+                mem::drop(w);
+                self.record_block_read(&data);
+                self.insert_block(&data_block_handle, data, options.fill_cache)?
+            }
+        };
+        Ok(block.iter_with_telemetry(self.cmp.clone(), self.seek_telemetry(BlockKind::Data)))
+    }
+
+    fn block_cache_key(&self, handle: &BlockHandle) -> Vec<u8> {
+        let mut cache_key_buffer = vec![0; 16];
+        put_fixed_64(&mut cache_key_buffer, self.cache_id);
+        put_fixed_64(&mut cache_key_buffer, handle.offset);
+        cache_key_buffer
+    }
+
+    // Looks up the block cache for the block pointed by `handle`
+    fn cached_block(&self, handle: &BlockHandle) -> Option<Arc<Block>> {
+        let cache = self.options.block_cache.as_ref()?;
+        let cache_handle = match cache.look_up(self.block_cache_key(handle).as_slice()) {
+            Some(h) => h,
+            None => {
+                record_tick(&self.options.statistics, Ticker::BlockCacheMiss, 1);
+                return None;
             }
-        } else {
-            let data = read_block(
-                self.file.as_ref(),
-                &data_block_handle,
-                options.verify_checksums,
-            )?;
-            let b = Block::new(data)?;
-            Arc::new(b)
         };
-        Ok(block.iter(self.options.comparator.clone()))
+        record_tick(&self.options.statistics, Ticker::BlockCacheHit, 1);
+        let b = cache_handle.value().unwrap().clone();
+        cache.release(cache_handle);
+        Some(b)
+    }
+
+    fn record_block_read(&self, data: &[u8]) {
+        record_tick(&self.options.statistics, Ticker::BlockRead, 1);
+        record_tick(
+            &self.options.statistics,
+            Ticker::BlockReadBytes,
+            data.len() as u64,
+        );
+        record_user_read(&self.options.statistics, data.len() as u64);
+    }
+
+    // Builds a `Block` by the data read from file and inserts it into the block cache
+    // if `fill_cache` is true
+    fn insert_block(
+        &self,
+        handle: &BlockHandle,
+        data: Vec<u8>,
+        fill_cache: bool,
+    ) -> Result<Arc<Block>> {
+        let charge = data.len();
+        let b = Arc::new(Block::new(data)?);
+        if let Some(cache) = &self.options.block_cache {
+            if fill_cache {
+                // TODO: avoid clone
+                cache.insert(self.block_cache_key(handle), b.clone(), charge, None);
+            }
+        }
+        Ok(b)
     }
 
-    /// Gets the first entry with the key equal or greater than target.
+    /// Gets a copy of the first entry with the key equal or greater than target.
     /// The given `key` is a user key
     pub fn internal_get(
         &self,
         options: Rc<ReadOptions>,
         key: &[u8],
-    ) -> Result<Option<(Slice, Slice)>> {
-        let mut index_iter = self.index_block.iter(self.options.comparator.clone());
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut index_iter = self.index_iter();
         // seek to the first 'last key' bigger than 'key'
         index_iter.seek(&Slice::from(key));
         if index_iter.valid() {
-            // It's called 'maybe_contained' not only because the filter policy may report the falsy result,
-            // but also even if we've found a block with the last key bigger than the target
-            // the key may not be contained if the block is the first block of the sstable.
-            let mut maybe_contained = true;
-
-            let handle_val = index_iter.value();
-            // check the filter block
-            if let Some(filter) = &self.filter_reader {
-                if let Ok((handle, _)) = BlockHandle::decode_from(handle_val.as_slice()) {
-                    if !filter.key_may_match(handle.offset, &Slice::from(key)) {
-                        maybe_contained = false;
-                    }
+            for data_block_handle in self.candidate_blocks(&options, &index_iter.value(), key)? {
+                let mut block_iter = self.block_reader(data_block_handle, options.clone())?;
+                block_iter.seek(&Slice::from(key));
+                if block_iter.valid() {
+                    // the key is decoded into the buffer of the iterator so copy it out
+                    return Ok(Some((block_iter.key().copy(), block_iter.value().copy())));
                 }
+                block_iter.status()?;
+            }
+        }
+        index_iter.status()?;
+        Ok(None)
+    }
+
+    /// The batched version of `internal_get` for `keys` in order. The filters are
+    /// consulted for the whole batch before reading any data block, so a table
+    /// containing none of the keys is skipped without touching its data blocks.
+    /// Returns `None` in place of every result if the table is skipped.
+    pub fn internal_multi_get(
+        &self,
+        options: Rc<ReadOptions>,
+        keys: &[&[u8]],
+    ) -> Result<Option<Vec<Option<(Vec<u8>, Vec<u8>)>>>> {
+        let mut index_iter = self.index_iter();
+        let mut candidates = Vec::with_capacity(keys.len());
+        for key in keys {
+            index_iter.seek(&Slice::from(*key));
+            if index_iter.valid() {
+                candidates.push(self.candidate_blocks(&options, &index_iter.value(), key)?);
+            } else {
+                candidates.push(vec![]);
             }
-            if maybe_contained {
-                let (data_block_handle, _) = BlockHandle::decode_from(handle_val.as_slice())?;
-                let mut block_iter = self.block_reader(data_block_handle, options)?;
+        }
+        index_iter.status()?;
+        if candidates.iter().all(|handles| handles.is_empty()) {
+            return Ok(None);
+        }
+        let mut results = Vec::with_capacity(keys.len());
+        for (key, handles) in keys.iter().zip(candidates) {
+            let mut result = None;
+            for data_block_handle in handles {
+                let mut block_iter = self.block_reader(data_block_handle, options.clone())?;
+                block_iter.seek(&Slice::from(*key));
+                if block_iter.valid() {
+                    result = Some((block_iter.key().copy(), block_iter.value().copy()));
+                    break;
+                }
+                block_iter.status()?;
+            }
+            results.push(result);
+        }
+        Ok(Some(results))
+    }
+
+    /// The async version of `internal_get`
+    pub async fn internal_get_async(
+        &self,
+        options: Rc<ReadOptions>,
+        key: &[u8],
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut index_iter = self.index_iter();
+        index_iter.seek(&Slice::from(key));
+        if index_iter.valid() {
+            for data_block_handle in self.candidate_blocks(&options, &index_iter.value(), key)? {
+                let mut block_iter = self
+                    .block_reader_async(data_block_handle, options.clone())
+                    .await?;
                 block_iter.seek(&Slice::from(key));
                 if block_iter.valid() {
-                    return Ok(Some((block_iter.key(), block_iter.value())));
+                    return Ok(Some((block_iter.key().copy(), block_iter.value().copy())));
                 }
                 block_iter.status()?;
             }
@@ -198,16 +748,103 @@ impl Table {
         Ok(None)
     }
 
+    // Returns the handles of data blocks in the given index value which may contain the `key`
+    // according to the filter block.
+    //
+    // It's called 'may contain' not only because the filter policy may report the falsy result,
+    // but also even if we've found a block with the last key bigger than the target
+    // the key may not be contained if the block is the first block of the sstable.
+    fn candidate_blocks(
+        &self,
+        options: &ReadOptions,
+        handle_val: &Slice,
+        key: &[u8],
+    ) -> Result<Vec<BlockHandle>> {
+        let mut handles = self.data_block_handles(handle_val.as_slice())?;
+        match self.whole_key_suffix {
+            Some(suffix) if self.has_filter() && !options.ignore_filters && key.len() >= suffix => {
+                let user_key = Slice::from(&key[..key.len() - suffix]);
+                handles.retain(|h| self.key_may_match(h.offset, &user_key));
+            }
+            _ => {}
+        }
+        Ok(handles)
+    }
+
+    // Decodes the handles of the data blocks in the index entry value `handle_val`
+    fn data_block_handles(&self, handle_val: &[u8]) -> Result<Vec<BlockHandle>> {
+        decode_index_value(handle_val, self.format_version)
+    }
+
+    // Returns the prefix a seek to the internal key `target` is able to check in
+    // the filters, which is shared by all the user keys from the target to
+    // `iterate_upper_bound` so skipping the blocks without it changes no result.
+    fn seek_prefix<'a>(&self, options: &ReadOptions, target: &'a [u8]) -> Option<&'a [u8]> {
+        if !self.prefix_filtered || !self.has_filter() || options.ignore_filters || target.len() < 8
+        {
+            return None;
+        }
+        let extractor = self.options.prefix_extractor.as_ref()?;
+        let upper = options.iterate_upper_bound.as_ref()?;
+        let user_key = &target[..target.len() - 8];
+        if !extractor.in_domain(user_key) {
+            return None;
+        }
+        let prefix = extractor.prefix(user_key);
+        let upper_in_prefix = extractor.in_domain(upper) && extractor.prefix(upper) == prefix;
+        // the bytewise keys in [prefix, upper) all start with the prefix
+        let upper_after_prefix = self.options.comparator.name() == BytewiseComparator::new().name()
+            && next_prefix(prefix).as_deref() == Some(upper.as_slice());
+        if upper_in_prefix || upper_after_prefix {
+            Some(prefix)
+        } else {
+            None
+        }
+    }
+
+    /// Returns false if the filters tell a seek to the internal key `target` in
+    /// the data blocks of the index value `handle_val` finds no key before
+    /// `ReadOptions::iterate_upper_bound`. See `Options::prefix_extractor`.
+    pub(crate) fn prefix_may_match(
+        &self,
+        options: &ReadOptions,
+        handle_val: &Slice,
+        target: &[u8],
+    ) -> bool {
+        let prefix = match self.seek_prefix(options, target) {
+            Some(prefix) => Slice::from(prefix),
+            None => return true,
+        };
+        let may_match = match self.data_block_handles(handle_val.as_slice()) {
+            Ok(handles) => handles
+                .iter()
+                .any(|h| self.key_may_match(h.offset, &prefix)),
+            Err(_) => true,
+        };
+        if !may_match {
+            record_tick(&self.options.statistics, Ticker::PrefixSeekSkipped, 1);
+        }
+        may_match
+    }
+
+    /// Like `prefix_may_match` but finds the data blocks of `target` in the index
+    pub(crate) fn seek_may_match(&self, options: &ReadOptions, target: &[u8]) -> bool {
+        if self.seek_prefix(options, target).is_none() {
+            return true;
+        }
+        let mut index_iter = self.index_iter();
+        index_iter.seek(&Slice::from(target));
+        !index_iter.valid() || self.prefix_may_match(options, &index_iter.value(), target)
+    }
+
     /// Given a key, return an approximate byte offset in the file where
     /// the data for that key begins (or would begin if the key were
     /// present in the file).  The returned value is in terms of file
     /// bytes, and so includes effects like compression of the underlying data.
     /// E.g., the approximate offset of the last key in the table will
     /// be close to the file length.
-    /// Temporary only used in tests.
-    #[allow(dead_code)]
     pub(crate) fn approximate_offset_of(&self, key: &[u8]) -> u64 {
-        let mut index_iter = self.index_block.iter(self.options.comparator.clone());
+        let mut index_iter = self.index_block.iter(self.cmp.clone());
         index_iter.seek(&Slice::from(key));
         if index_iter.valid() {
             let val = index_iter.value();
@@ -215,11 +852,89 @@ impl Table {
                 return h.offset;
             }
         }
-        if let Some(meta) = &self.meta_block_handle {
-            return meta.offset;
+        self.data_end_offset()
+    }
+
+    /// Erases the data blocks of the table from the block cache. Returns the number
+    /// of the blocks erased.
+    pub(crate) fn erase_cached_blocks(&self) -> Result<u64> {
+        let cache = match &self.options.block_cache {
+            Some(cache) => cache,
+            None => return Ok(0),
+        };
+        let mut erased = 0;
+        for (handle, _) in self.data_blocks()? {
+            let key = self.block_cache_key(&handle);
+            if let Some(h) = cache.look_up(key.as_slice()) {
+                cache.release(h);
+                cache.erase(key.as_slice());
+                erased += 1;
+            }
+        }
+        Ok(erased)
+    }
+
+    /// Returns the handles of all the data blocks in order, each with the index key
+    /// of the index entry pointing to it, which is no less than the keys in the block.
+    pub(crate) fn data_blocks(&self) -> Result<Vec<(BlockHandle, Vec<u8>)>> {
+        let mut index_iter = self.index_block.iter(self.cmp.clone());
+        let mut blocks = vec![];
+        index_iter.seek_to_first();
+        while index_iter.valid() {
+            for h in self.data_block_handles(index_iter.value().as_slice())? {
+                blocks.push((h, index_iter.key().copy()));
+            }
+            index_iter.next();
+        }
+        index_iter.status()?;
+        Ok(blocks)
+    }
+
+    /// Returns the offset where the data blocks end
+    pub(crate) fn data_end_offset(&self) -> u64 {
+        let mut index_iter = self.index_block.iter(self.cmp.clone());
+        index_iter.seek_to_last();
+        if index_iter.valid() {
+            if let Ok(handles) = self.data_block_handles(index_iter.value().as_slice()) {
+                if let Some(h) = handles.last() {
+                    return h.offset + h.size + BLOCK_TRAILER_SIZE as u64;
+                }
+            }
         }
         0
     }
+
+    /// Returns the number of the entries per byte of the data blocks sampled from
+    /// the data block in the middle of the table, which interpolates the number of
+    /// the entries between two offsets given by `approximate_offset_of`.
+    pub(crate) fn sample_entries_per_byte(&self, options: Rc<ReadOptions>) -> Result<f64> {
+        let mut index_iter = self.index_block.iter(self.cmp.clone());
+        let mut num_blocks = 0;
+        index_iter.seek_to_first();
+        while index_iter.valid() {
+            num_blocks += 1;
+            index_iter.next();
+        }
+        if num_blocks == 0 {
+            return Ok(0.0);
+        }
+        index_iter.seek_to_first();
+        for _ in 0..num_blocks / 2 {
+            index_iter.next();
+        }
+        let (handle, _) = BlockHandle::decode_from(index_iter.value().as_slice())?;
+        let size = handle.size;
+        let mut block_iter = self.block_reader(handle, options)?;
+        let mut num_entries = 0;
+        block_iter.seek_to_first();
+        while block_iter.valid() {
+            num_entries += 1;
+            block_iter.next();
+        }
+        block_iter.status()?;
+        // count the block trailer in as the offsets do
+        Ok(num_entries as f64 / (size + BLOCK_TRAILER_SIZE as u64) as f64)
+    }
 }
 
 pub struct TableIterFactory {
@@ -227,23 +942,177 @@ pub struct TableIterFactory {
     table: Arc<Table>,
 }
 impl DerivedIterFactory for TableIterFactory {
+    fn seek_may_match(&self, value: &Slice, target: &Slice) -> bool {
+        self.table
+            .prefix_may_match(&self.options, value, target.as_slice())
+    }
+
     fn derive(&self, value: &Slice) -> Result<Box<dyn Iterator>> {
-        BlockHandle::decode_from(value.as_slice())
-            .and_then(|(handle, _)| self.table.block_reader(handle, self.options.clone()))
+        let mut handles = self.table.data_block_handles(value.as_slice())?;
+        if handles.len() == 1 {
+            self.table
+                .block_reader(handles.pop().unwrap(), self.options.clone())
+        } else {
+            Ok(Box::new(BlockGroupIterator::new(
+                self.table.clone(),
+                self.options.clone(),
+                handles,
+            )))
+        }
+    }
+}
+
+/// An iterator over adjacent data blocks sharing a same index entry.
+/// The blocks are read lazily one by one.
+struct BlockGroupIterator {
+    table: Arc<Table>,
+    options: Rc<ReadOptions>,
+    handles: Vec<BlockHandle>,
+    // index of the current block in `handles`
+    current: usize,
+    block_iter: Option<Box<dyn Iterator>>,
+    err: Option<WickErr>,
+}
+
+impl BlockGroupIterator {
+    fn new(table: Arc<Table>, options: Rc<ReadOptions>, handles: Vec<BlockHandle>) -> Self {
+        Self {
+            table,
+            options,
+            handles,
+            current: 0,
+            block_iter: None,
+            err: None,
+        }
+    }
+
+    // Opens the `i`th block in the group. Returns false if an error occurs.
+    fn open_block(&mut self, i: usize) -> bool {
+        if let Some(iter) = &mut self.block_iter {
+            if let Err(e) = iter.status() {
+                self.err.get_or_insert(e);
+            }
+        }
+        self.current = i;
+        match self
+            .table
+            .block_reader(self.handles[i].clone(), self.options.clone())
+        {
+            Ok(iter) => {
+                self.block_iter = Some(iter);
+                true
+            }
+            Err(e) => {
+                self.block_iter = None;
+                self.err.get_or_insert(e);
+                false
+            }
+        }
+    }
+
+    fn skip_forward(&mut self) {
+        while !self.valid() && self.current + 1 < self.handles.len() {
+            if !self.open_block(self.current + 1) {
+                return;
+            }
+            self.block_iter.as_mut().unwrap().seek_to_first();
+        }
+    }
+
+    fn skip_backward(&mut self) {
+        while !self.valid() && self.current > 0 {
+            if !self.open_block(self.current - 1) {
+                return;
+            }
+            self.block_iter.as_mut().unwrap().seek_to_last();
+        }
     }
 }
 
+impl Iterator for BlockGroupIterator {
+    fn valid(&self) -> bool {
+        self.block_iter.as_ref().is_some_and(|i| i.valid())
+    }
+
+    fn seek_to_first(&mut self) {
+        if self.open_block(0) {
+            self.block_iter.as_mut().unwrap().seek_to_first();
+            self.skip_forward();
+        }
+    }
+
+    fn seek_to_last(&mut self) {
+        if self.open_block(self.handles.len() - 1) {
+            self.block_iter.as_mut().unwrap().seek_to_last();
+            self.skip_backward();
+        }
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        for i in 0..self.handles.len() {
+            if !self.open_block(i) {
+                return;
+            }
+            self.block_iter.as_mut().unwrap().seek(target);
+            if self.valid() {
+                return;
+            }
+        }
+    }
+
+    fn next(&mut self) {
+        self.block_iter.as_mut().unwrap().next();
+        self.skip_forward();
+    }
+
+    fn prev(&mut self) {
+        self.block_iter.as_mut().unwrap().prev();
+        self.skip_backward();
+    }
+
+    fn key(&self) -> Slice {
+        self.block_iter.as_ref().unwrap().key()
+    }
+
+    fn value(&self) -> Slice {
+        self.block_iter.as_ref().unwrap().value()
+    }
+
+    fn status(&mut self) -> Result<()> {
+        if let Some(e) = &self.err {
+            return Err(e.clone());
+        }
+        match &mut self.block_iter {
+            Some(iter) => iter.status(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Returns the smallest key greater than all the keys starting with `prefix` in
+// bytewise order, or `None` if there is no such key
+fn next_prefix(prefix: &[u8]) -> Option<Vec<u8>> {
+    let end = prefix.iter().rposition(|b| *b != 0xff)?;
+    let mut next = prefix[..=end].to_vec();
+    next[end] += 1;
+    Some(next)
+}
+
 /// Create a new `ConcatenateIterator` as table iterator.
 /// This iterator is able to yield all the key/values in a `.sst` file
 ///
 /// Entry format:
 ///     key: internal key
 ///     value: value of user key
+///
+/// The iterate bounds of `options` stop it from reading the data blocks out of
+/// them.
 pub fn new_table_iterator(table: Arc<Table>, options: Rc<ReadOptions>) -> Box<dyn Iterator> {
-    let cmp = table.options.comparator.clone();
-    let index_iter = table.index_block.iter(cmp);
+    let index_iter = table.index_iter();
+    let cmp = table.cmp.clone();
+    let (lower, upper) = options.internal_key_bounds();
     let factory = Box::new(TableIterFactory { options, table });
-    Box::new(ConcatenateIterator::new(index_iter, factory))
+    Box::new(ConcatenateIterator::new(index_iter, factory).with_bounds(cmp, lower, upper))
 }
 
 /// Temporarily stores the contents of the table it is
@@ -264,6 +1133,8 @@ pub struct TableBuilder {
     last_key: Vec<u8>,
     // number of key/value pairs in the file
     num_entries: usize,
+    // the properties collected so far, saved in the properties block
+    properties: TableProperties,
     closed: bool,
     filter_block: Option<FilterBlockBuilder>,
     // Indicates whether we have to add a index to index_block
@@ -274,18 +1145,70 @@ pub struct TableBuilder {
     pending_index_entry: bool,
     // handle for current block to add to index block
     pending_handle: BlockHandle,
+    // number of data blocks sharing one index entry
+    blocks_per_index_entry: usize,
+    // encoded handles of the flushed data blocks which have not been indexed yet
+    pending_group: Vec<u8>,
+    // number of handles in `pending_group`
+    pending_group_size: usize,
+    // the tweaks of the encrypted data blocks are derived from it
+    file_number: u64,
+    // the sizes of the added keys and values if `Options::record_kv_size_histograms`
+    kv_size_histograms: Option<(Histogram, Histogram)>,
+    // the bytes of the added keys not counted in the key sizes
+    key_size_suffix: usize,
+    // the prefix added into the filter of the current data block last time
+    last_prefix: Option<Vec<u8>>,
+    // whether the user keys are added into the filters
+    whole_key_filtering: bool,
+    block_restart_interval: usize,
+    // the entries buffered to train the compression dictionary, which are added
+    // after the training
+    dict_buffer: Option<Vec<(Vec<u8>, Vec<u8>)>>,
+    // the bytes of the keys and values in `dict_buffer`
+    dict_buffer_bytes: usize,
+    // the dictionary compressing the data blocks, or empty if not used
+    compression_dict: Vec<u8>,
 }
 
 impl TableBuilder {
-    pub fn new(file: Box<dyn File>, options: Arc<Options>) -> Self {
+    /// Creates a `TableBuilder` for a table in the given `level` which decides the
+    /// block layout. See `Options::per_level_table_options` for details.
+    #[cfg(test)]
+    pub fn new(file: Box<dyn File>, options: Arc<Options>, level: usize) -> Self {
+        let cmp = options.comparator.clone();
+        Self::new_with_comparator(file, options, cmp, level)
+    }
+
+    /// Like `new` but the added keys are ordered by `cmp` instead of `options.comparator`.
+    pub fn new_with_comparator(
+        file: Box<dyn File>,
+        options: Arc<Options>,
+        cmp: Arc<dyn Comparator>,
+        level: usize,
+    ) -> Self {
         let opt = options.clone();
-        let db_builder =
-            BlockBuilder::new(options.block_restart_interval, options.comparator.clone());
-        let ib_builder =
-            BlockBuilder::new(options.block_restart_interval, options.comparator.clone());
+        let table_options = options.table_options_for_level(level);
+        let db_builder = BlockBuilder::new(table_options.block_restart_interval, cmp.clone());
+        let ib_builder = BlockBuilder::new(table_options.index_block_restart_interval, cmp.clone());
         let fb = {
-            if let Some(policy) = opt.filter_policy.clone() {
-                let mut f = FilterBlockBuilder::new(policy.clone());
+            let policy = match opt.filter_policy.clone() {
+                // the bloom filters with different bits read each other
+                Some(p)
+                    if table_options.bloom_bits_per_key > 0 && p.name() == BLOOM_FILTER_NAME =>
+                {
+                    let p: Arc<dyn FilterPolicy> =
+                        Arc::new(BloomFilter::new(table_options.bloom_bits_per_key));
+                    Some(p)
+                }
+                p => p,
+            };
+            if let Some(policy) = policy {
+                let mut f = match &opt.filter_reuse_cache {
+                    Some(cache) => FilterBlockBuilder::new_with_reuse_cache(policy, cache.clone()),
+                    None => FilterBlockBuilder::new(policy),
+                }
+                .with_partition_size(opt.filter_partition_size);
                 f.start_block(0);
                 Some(f)
             } else {
@@ -295,17 +1218,77 @@ impl TableBuilder {
         Self {
             options: opt,
             file,
-            cmp: options.comparator.clone(),
+            cmp,
             offset: 0,
             data_block: db_builder,
             index_block: ib_builder,
             last_key: vec![],
             num_entries: 0,
+            properties: TableProperties {
+                oldest_sequence: u64::MAX,
+                ..TableProperties::default()
+            },
             closed: false,
             filter_block: fb,
             pending_index_entry: false,
             pending_handle: BlockHandle::new(0, 0),
+            // the readers of the older versions would miss the grouped blocks
+            blocks_per_index_entry: if options.format_version >= GROUPED_INDEX_FORMAT_VERSION {
+                table_options.blocks_per_index_entry.max(1)
+            } else {
+                1
+            },
+            pending_group: vec![],
+            pending_group_size: 0,
+            file_number: 0,
+            kv_size_histograms: if options.record_kv_size_histograms {
+                Some((Histogram::default(), Histogram::default()))
+            } else {
+                None
+            },
+            key_size_suffix: 0,
+            last_prefix: None,
+            whole_key_filtering: table_options.whole_key_filtering,
+            block_restart_interval: table_options.block_restart_interval,
+            dict_buffer: None,
+            dict_buffer_bytes: 0,
+            compression_dict: vec![],
+        }
+    }
+
+    /// Sets the file number of the table, which decides the tweaks of the data
+    /// blocks encrypted by `Options::block_cipher`.
+    pub fn with_file_number(mut self, file_number: u64) -> Self {
+        self.file_number = file_number;
+        self
+    }
+
+    /// Marks the added keys as internal keys so that the key sizes recorded by
+    /// `Options::record_kv_size_histograms` are the sizes of the user keys, and
+    /// the keys added into the filters are the user keys.
+    pub fn with_internal_keys(mut self) -> Self {
+        self.key_size_suffix = 8;
+        self
+    }
+
+    /// Builds the table without the filters. See `Options::skip_bottommost_filters`.
+    pub fn without_filters(mut self) -> Self {
+        self.filter_block = None;
+        self
+    }
+
+    /// Compresses the data blocks with a dictionary trained from the first added
+    /// entries if `Options::dictionary_compressor` is set. The entries are buffered
+    /// until `Options::compression_dict_train_bytes` are added or the table is
+    /// finished.
+    pub fn with_compression_dictionary(mut self) -> Self {
+        if self.options.dictionary_compressor.is_some()
+            && self.options.compression_dict_max_bytes > 0
+            && self.options.format_version != LEGACY_FORMAT_VERSION
+        {
+            self.dict_buffer = Some(vec![]);
         }
+        self
     }
 
     /// Adds a key/value pair to the table being constructed.
@@ -319,6 +1302,26 @@ impl TableBuilder {
     ///
     pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
         self.assert_not_closed();
+        if let Some(buffer) = self.dict_buffer.as_mut() {
+            if let Some((last_key, _)) = buffer.last() {
+                assert_eq!(
+                    self.cmp.compare(key, last_key.as_slice()),
+                    Ordering::Greater,
+                    "[table builder] new key is inconsistent with the last key in sstable"
+                )
+            }
+            buffer.push((key.to_vec(), value.to_vec()));
+            self.dict_buffer_bytes += key.len() + value.len();
+            if self.dict_buffer_bytes >= self.options.compression_dict_train_bytes {
+                self.train_compression_dict()?;
+            }
+            return Ok(());
+        }
+        self.add_entry(key, value)
+    }
+
+    // Adds the entry to the data block
+    fn add_entry(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
         if self.num_entries > 0 {
             assert_eq!(
                 self.cmp.compare(key, self.last_key.as_slice()),
@@ -330,12 +1333,39 @@ impl TableBuilder {
         self.maybe_append_index_block(Some(key));
         // Update filter block
         if let Some(fb) = self.filter_block.as_mut() {
-            fb.add_key(&Slice::from(key))
+            let user_key = &key[..key.len().saturating_sub(self.key_size_suffix)];
+            if self.whole_key_filtering {
+                fb.add_key(&Slice::from(user_key));
+            }
+            if let Some(extractor) = &self.options.prefix_extractor {
+                if extractor.in_domain(user_key) {
+                    let prefix = extractor.prefix(user_key);
+                    if self.last_prefix.as_deref() != Some(prefix) {
+                        fb.add_key(&Slice::from(prefix));
+                        self.last_prefix = Some(prefix.to_vec());
+                    }
+                }
+            }
+        }
+        if let Some((key_sizes, value_sizes)) = self.kv_size_histograms.as_mut() {
+            key_sizes.add(key.len().saturating_sub(self.key_size_suffix) as f64);
+            value_sizes.add(value.len() as f64);
         }
         // TODO: avoid the copy
         self.last_key.resize(key.len(), 0);
         self.last_key.copy_from_slice(key);
         self.num_entries += 1;
+        let properties = &mut self.properties;
+        properties.raw_key_size += key.len().saturating_sub(self.key_size_suffix) as u64;
+        properties.raw_value_size += value.len() as u64;
+        if self.key_size_suffix == 8 && key.len() >= 8 {
+            let tag = decode_fixed_64(&key[key.len() - 8..]);
+            if tag as u8 == ValueType::Deletion as u8 {
+                properties.num_deletions += 1;
+            }
+            properties.oldest_sequence = properties.oldest_sequence.min(tag >> 8);
+            properties.newest_sequence = properties.newest_sequence.max(tag >> 8);
+        }
         // write to data block
         self.data_block.add(key, value);
 
@@ -359,60 +1389,182 @@ impl TableBuilder {
         if !self.data_block.is_empty() {
             assert!(!self.pending_index_entry, "[table builder] the index for the previous data block should never remain when flushing current block data");
             let data_block = self.data_block.finish();
-            let (compressed, compression) = compress_block(data_block, self.options.compression)?;
-            write_raw_block(
+            let (mut compressed, mut block_type) = if self.compression_dict.is_empty() {
+                let (compressed, compression) = maybe_compress_block(&self.options, data_block)?;
+                (compressed, compression as u8)
+            } else {
+                compress_block_with_dict(&self.options, &self.compression_dict, data_block)?
+            };
+            if let Some(cipher) = &self.options.block_cipher {
+                let tweak = block_tweak(self.file_number, self.offset);
+                cipher.encrypt(&tweak, compressed.as_mut_slice())?;
+                block_type |= ENCRYPTED_BLOCK_FLAG;
+            }
+            let checksum = write_raw_block(
                 self.file.as_mut(),
+                &self.options,
                 compressed.as_slice(),
-                compression,
+                block_type,
                 &mut self.pending_handle,
                 &mut self.offset,
             )?;
             self.data_block.reset();
             self.pending_index_entry = true;
+            self.properties.num_data_blocks += 1;
             if let Err(e) = self.file.flush() {
                 return Err(WickErr::new_from_raw(Status::IOError, None, Box::new(e)));
             }
+            if self.options.table_sync_policy == SyncPolicy::Always {
+                self.file.sync()?;
+            }
             if let Some(fb) = &mut self.filter_block {
-                fb.start_block(self.offset)
+                fb.add_block(self.pending_handle.size, checksum);
+                fb.start_block(self.offset);
+                // the filter of every block holds the prefixes of its own keys
+                self.last_prefix = None;
             }
         }
         Ok(())
     }
 
     /// Finishes building the table and close the relative file.
-    /// If `sync` is true, the `File::flush` will be called.
+    /// If `sync` is true, the file is synced unless `Options::table_sync_policy` is `Never`.
     ///
     /// # Panics
     ///
     /// * The table builder is closed
     ///
     pub fn finish(&mut self, sync: bool) -> Result<()> {
+        if self.dict_buffer.is_some() {
+            self.train_compression_dict()?;
+        }
         self.flush()?;
         self.assert_not_closed();
         self.closed = true;
         // write filter block
         let mut filter_block_handler = BlockHandle::new(0, 0);
         let mut has_filter_block = false;
+        let mut partitioned_filter = false;
         if let Some(fb) = &mut self.filter_block {
-            let data = fb.finish();
+            if fb.is_partitioned() {
+                // write the partitions followed by their index
+                let mut partitions = vec![];
+                for (first_offset, data) in fb.finish_partitions() {
+                    let mut handle = BlockHandle::new(0, 0);
+                    write_raw_block(
+                        self.file.as_mut(),
+                        &self.options,
+                        &data,
+                        CompressionType::NoCompression as u8,
+                        &mut handle,
+                        &mut self.offset,
+                    )?;
+                    self.properties.filter_size += data.len() as u64;
+                    partitions.push((first_offset, handle));
+                }
+                let index = FilterPartitionIndex::encode(&partitions);
+                write_raw_block(
+                    self.file.as_mut(),
+                    &self.options,
+                    &index,
+                    CompressionType::NoCompression as u8,
+                    &mut filter_block_handler,
+                    &mut self.offset,
+                )?;
+                self.properties.filter_size += index.len() as u64;
+                partitioned_filter = true;
+            } else {
+                let data = fb.finish();
+                write_raw_block(
+                    self.file.as_mut(),
+                    &self.options,
+                    data,
+                    CompressionType::NoCompression as u8,
+                    &mut filter_block_handler,
+                    &mut self.offset,
+                )?;
+                self.properties.filter_size = data.len() as u64;
+            }
+            has_filter_block = true;
+        }
+        // write the size histograms block
+        let mut histograms_block_handle = None;
+        if let Some((key_sizes, value_sizes)) = &self.kv_size_histograms {
+            let mut data = vec![];
+            for h in [key_sizes, value_sizes].iter() {
+                let mut encoded = vec![];
+                h.encode_to(&mut encoded);
+                VarintU32::put_varint_prefixed_slice(&mut data, &encoded);
+            }
+            let mut handle = BlockHandle::new(0, 0);
             write_raw_block(
                 self.file.as_mut(),
-                data,
-                CompressionType::NoCompression,
-                &mut filter_block_handler,
+                &self.options,
+                &data,
+                CompressionType::NoCompression as u8,
+                &mut handle,
                 &mut self.offset,
             )?;
-            has_filter_block = true;
+            histograms_block_handle = Some(handle);
+        }
+        // write the compression dictionary block
+        let mut dict_block_handle = None;
+        if !self.compression_dict.is_empty() {
+            let mut handle = BlockHandle::new(0, 0);
+            write_raw_block(
+                self.file.as_mut(),
+                &self.options,
+                &self.compression_dict,
+                CompressionType::NoCompression as u8,
+                &mut handle,
+                &mut self.offset,
+            )?;
+            dict_block_handle = Some(handle);
         }
+        // write the properties block
+        let mut properties = mem::take(&mut self.properties);
+        properties.num_entries = self.num_entries as u64;
+        if properties.oldest_sequence > properties.newest_sequence {
+            // no internal key is added
+            properties.oldest_sequence = 0;
+        }
+        // the user comparator is wrapped by the internal key comparator
+        properties.comparator_name = if self.key_size_suffix == 8 {
+            self.options.comparator.name().to_owned()
+        } else {
+            self.cmp.name().to_owned()
+        };
+        properties.creation_time = self.options.clock.now_micros() / 1_000_000;
+        let mut properties_block_handle = BlockHandle::new(0, 0);
+        write_raw_block(
+            self.file.as_mut(),
+            &self.options,
+            &properties.encode(),
+            CompressionType::NoCompression as u8,
+            &mut properties_block_handle,
+            &mut self.offset,
+        )?;
 
         // write meta block
         let mut meta_block_handle = BlockHandle::new(0, 0);
-        let mut meta_block_builder =
-            BlockBuilder::new(self.options.block_restart_interval, self.cmp.clone());
+        let mut meta_block_builder = BlockBuilder::new(
+            self.options.block_restart_interval,
+            Arc::new(BytewiseComparator::new()),
+        );
         let meta_block = {
+            // "compression." sorts before all the others
+            if let Some(handle) = &dict_block_handle {
+                meta_block_builder
+                    .add(COMPRESSION_DICT_KEY.as_bytes(), handle.encoded().as_slice());
+            }
             if has_filter_block {
                 let filter_key = if let Some(fp) = &self.options.filter_policy {
-                    "filter.".to_owned() + fp.name()
+                    let key_prefix = if partitioned_filter {
+                        PARTITIONED_FILTER_KEY_PREFIX
+                    } else {
+                        FILTER_KEY_PREFIX
+                    };
+                    key_prefix.to_owned() + fp.name()
                 } else {
                     String::from("")
                 };
@@ -420,7 +1572,31 @@ impl TableBuilder {
                     filter_key.as_bytes(),
                     filter_block_handler.encoded().as_slice(),
                 );
+                // "prefix." sorts after "filter." and "partitionedfilter."
+                if let Some(extractor) = &self.options.prefix_extractor {
+                    let prefix_key = PREFIX_EXTRACTOR_KEY_PREFIX.to_owned() + extractor.name();
+                    meta_block_builder.add(prefix_key.as_bytes(), b"");
+                }
+            }
+            // "stats." sorts after "filter." and "prefix."
+            if let Some(handle) = &histograms_block_handle {
+                meta_block_builder.add(
+                    KV_SIZE_HISTOGRAMS_KEY.as_bytes(),
+                    handle.encoded().as_slice(),
+                );
             }
+            // "wholekey." sorts after "stats."
+            if has_filter_block && self.whole_key_filtering {
+                meta_block_builder.add(
+                    WHOLE_KEY_FILTER_KEY.as_bytes(),
+                    &[self.key_size_suffix as u8],
+                );
+            }
+            // "wickdb." sorts after all the others
+            meta_block_builder.add(
+                PROPERTIES_KEY.as_bytes(),
+                properties_block_handle.encoded().as_slice(),
+            );
             meta_block_builder.finish()
         };
         self.write_block(meta_block, &mut meta_block_handle)?;
@@ -429,21 +1605,30 @@ impl TableBuilder {
         self.maybe_append_index_block(None); // flush the last index first
         let index_block = self.index_block.finish();
         let mut index_block_handle = BlockHandle::new(0, 0);
-        let (c_index_block, ct) = compress_block(index_block, self.options.compression)?;
+        let (c_index_block, ct) = maybe_compress_block(&self.options, index_block)?;
         write_raw_block(
             self.file.as_mut(),
+            &self.options,
             c_index_block.as_slice(),
-            ct,
+            ct as u8,
             &mut index_block_handle,
             &mut self.offset,
         )?;
         self.index_block.reset();
         // write footer
-        let footer = Footer::new(meta_block_handle, index_block_handle).encoded();
+        let footer = Footer::new(meta_block_handle, index_block_handle)
+            .with_magic(self.options.table_magic_number)
+            .with_format_version(self.options.format_version)
+            .encoded();
+        if let Some(limiter) = &self.options.rate_limiter {
+            limiter.request(footer.len());
+        }
         self.file.write(footer.as_slice())?;
         self.offset += footer.len() as u64;
         if sync {
-            self.file.flush()?;
+            if self.options.table_sync_policy != SyncPolicy::Never {
+                self.file.sync()?;
+            }
             self.file.close()?;
         }
         Ok(())
@@ -464,14 +1649,44 @@ impl TableBuilder {
     /// Returns the number of key/value added so far.
     #[inline]
     pub fn num_entries(&self) -> usize {
-        self.num_entries
+        self.num_entries + self.dict_buffer.as_ref().map_or(0, |b| b.len())
     }
 
-    /// Returns size of the file generated so far. If invoked after a successful
-    /// `Finish` call, returns the size of the final generated file.
+    /// Returns size of the file generated so far, including the raw size of the
+    /// entries buffered to train the compression dictionary. If invoked after a
+    /// successful `Finish` call, returns the size of the final generated file.
     #[inline]
     pub fn file_size(&self) -> u64 {
-        self.offset
+        self.offset + self.dict_buffer_bytes as u64
+    }
+
+    // Trains the compression dictionary from the blocks the buffered entries
+    // would be written as, and then adds the entries
+    fn train_compression_dict(&mut self) -> Result<()> {
+        let entries = self.dict_buffer.take().unwrap_or_default();
+        self.dict_buffer_bytes = 0;
+        if let Some(compressor) = self.options.dictionary_compressor.clone() {
+            let mut samples = vec![];
+            let mut block = BlockBuilder::new(self.block_restart_interval, self.cmp.clone());
+            for (key, value) in entries.iter() {
+                block.add(key, value);
+                if block.current_size_estimate() >= self.options.block_size {
+                    samples.push(block.finish().to_vec());
+                    block.reset();
+                }
+            }
+            if !block.is_empty() {
+                samples.push(block.finish().to_vec());
+            }
+            if !samples.is_empty() {
+                self.compression_dict =
+                    compressor.train(&samples, self.options.compression_dict_max_bytes)?;
+            }
+        }
+        for (key, value) in entries.iter() {
+            self.add_entry(key, value)?;
+        }
+        Ok(())
     }
 
     #[inline]
@@ -487,28 +1702,36 @@ impl TableBuilder {
         if self.pending_index_entry {
             // We've flushed a data block to the file so adding an relate index entry into index block
             assert!(self.data_block.is_empty(), "[table builder] the data block buffer is not empty after flushed, something is wrong");
+            self.pending_handle.encoded_to(&mut self.pending_group);
+            self.pending_group_size += 1;
+            self.pending_index_entry = false;
+        }
+        // The index entry of a group is emitted when the group is full or
+        // the table is finishing
+        if self.pending_group_size > 0
+            && (key.is_none() || self.pending_group_size >= self.blocks_per_index_entry)
+        {
             let s = if let Some(k) = key {
                 self.cmp.separator(self.last_key.as_slice(), k)
             } else {
                 self.cmp.successor(self.last_key.as_slice())
             };
-            // TODO: use a allocted buffer instead
-            let mut handle_encoding = vec![];
-            self.pending_handle.encoded_to(&mut handle_encoding);
             self.index_block
-                .add(s.as_slice(), handle_encoding.as_slice());
-            self.pending_index_entry = false;
+                .add(s.as_slice(), self.pending_group.as_slice());
+            self.pending_group.clear();
+            self.pending_group_size = 0;
             return true;
         }
         false
     }
 
     fn write_block(&mut self, raw_block: &[u8], handle: &mut BlockHandle) -> Result<()> {
-        let (data, compression) = compress_block(raw_block, self.options.compression)?;
+        let (data, compression) = maybe_compress_block(&self.options, raw_block)?;
         write_raw_block(
             self.file.as_mut(),
+            &self.options,
             &data,
-            compression,
+            compression as u8,
             handle,
             &mut self.offset,
         )?;
@@ -516,6 +1739,100 @@ impl TableBuilder {
     }
 }
 
+// The key in the meta block of the handle of the size histograms block
+const KV_SIZE_HISTOGRAMS_KEY: &str = "stats.kv_size_histograms";
+
+// The key in the meta block of the handle of the compression dictionary block
+const COMPRESSION_DICT_KEY: &str = "compression.dictionary";
+
+// The key in the meta block of the handle of the properties block
+const PROPERTIES_KEY: &str = "wickdb.properties";
+
+// The key in the meta block of the handle of the filter block is this prefix
+// followed by the name of the filter policy
+const FILTER_KEY_PREFIX: &str = "filter.";
+
+// Like `FILTER_KEY_PREFIX` but the handle points to the index of the filter
+// partitions
+const PARTITIONED_FILTER_KEY_PREFIX: &str = "partitionedfilter.";
+
+// The key in the meta block telling the filters hold the user keys, whose value
+// is the length of the suffix following the user keys in the table keys. The
+// tables without it may hold the whole internal keys in the filters.
+const WHOLE_KEY_FILTER_KEY: &str = "wholekey.filter";
+
+// The key in the meta block telling the filters hold the prefixes given by the
+// extractor named by the rest of the key
+const PREFIX_EXTRACTOR_KEY_PREFIX: &str = "prefix.";
+
+// Bytes sampled from a large block to estimate its compressibility
+const COMPRESSION_SAMPLE_SIZE: usize = 4096;
+// The samples are taken in pieces evenly spaced in the block
+const COMPRESSION_SAMPLE_PIECES: usize = 4;
+
+// Picks `COMPRESSION_SAMPLE_SIZE` bytes from the block
+fn sample_block(raw_block: &[u8]) -> Vec<u8> {
+    let piece = COMPRESSION_SAMPLE_SIZE / COMPRESSION_SAMPLE_PIECES;
+    let stride = raw_block.len() / COMPRESSION_SAMPLE_PIECES;
+    let mut sample = Vec::with_capacity(COMPRESSION_SAMPLE_SIZE);
+    for i in 0..COMPRESSION_SAMPLE_PIECES {
+        let start = i * stride;
+        sample.extend_from_slice(&raw_block[start..start + piece.min(stride)]);
+    }
+    sample
+}
+
+#[inline]
+fn is_good_compression_ratio(raw_size: usize, compressed_size: usize, min_ratio: f64) -> bool {
+    raw_size as f64 >= compressed_size as f64 * min_ratio
+}
+
+// Compresses the block by the configured compression type unless it
+// doesn't compress well enough
+fn maybe_compress_block(options: &Options, raw_block: &[u8]) -> Result<(Vec<u8>, CompressionType)> {
+    let ratio = options.min_compression_ratio;
+    if ratio > 0.0
+        && options.compression != CompressionType::NoCompression
+        && raw_block.len() >= 2 * COMPRESSION_SAMPLE_SIZE
+    {
+        let sample = sample_block(raw_block);
+        let (compressed, _) = compress_block(&sample, options.compression)?;
+        if !is_good_compression_ratio(sample.len(), compressed.len(), ratio) {
+            record_tick(&options.statistics, Ticker::BlockCompressionSkipped, 1);
+            return Ok((Vec::from(raw_block), CompressionType::NoCompression));
+        }
+    }
+    let (compressed, compression) = compress_block(raw_block, options.compression)?;
+    if compression != CompressionType::NoCompression
+        && !is_good_compression_ratio(raw_block.len(), compressed.len(), ratio)
+    {
+        record_tick(&options.statistics, Ticker::BlockCompressionSkipped, 1);
+        return Ok((Vec::from(raw_block), CompressionType::NoCompression));
+    }
+    Ok((compressed, compression))
+}
+
+// Compresses the block by `Options::dictionary_compressor` with the dictionary
+// unless it doesn't compress well enough. Returns the data and the block type.
+fn compress_block_with_dict(
+    options: &Options,
+    dict: &[u8],
+    raw_block: &[u8],
+) -> Result<(Vec<u8>, u8)> {
+    if let Some(compressor) = &options.dictionary_compressor {
+        let compressed = compressor.compress(dict, raw_block)?;
+        if is_good_compression_ratio(
+            raw_block.len(),
+            compressed.len(),
+            options.min_compression_ratio,
+        ) {
+            return Ok((compressed, DICTIONARY_BLOCK_FLAG));
+        }
+        record_tick(&options.statistics, Ticker::BlockCompressionSkipped, 1);
+    }
+    Ok((Vec::from(raw_block), CompressionType::NoCompression as u8))
+}
+
 // Compresses the give raw block by configured compression algorithm.
 // Returns the compressed data and compression data.
 fn compress_block(
@@ -545,14 +1862,23 @@ fn compress_block(
     }
 }
 
-// Write given block data into the file with block trailer
+// Write given block data into the file with block trailer. `block_type` is the
+// compression type with the `ENCRYPTED_BLOCK_FLAG` set if the data is encrypted,
+// or the `DICTIONARY_BLOCK_FLAG` if the data is compressed with the dictionary.
+// The `Options::checksum_type` is added into the block type, which is covered by
+// the checksum as well, unless the table is of the legacy format which is always
+// checksummed by CRC32C. Returns the stored checksum.
 fn write_raw_block(
     file: &mut dyn File,
+    options: &Options,
     data: &[u8],
-    compression: CompressionType,
+    block_type: u8,
     handle: &mut BlockHandle,
     offset: &mut u64,
-) -> Result<()> {
+) -> Result<u32> {
+    if let Some(limiter) = &options.rate_limiter {
+        limiter.request(data.len() + BLOCK_TRAILER_SIZE);
+    }
     // write block data
     file.write(data)?;
     // update the block handle
@@ -561,36 +1887,300 @@ fn write_raw_block(
     // write trailer
     // TODO: use pre-allocated buf
     let mut trailer = vec![];
-    trailer.push(compression as u8);
-    let crc = mask(extend(value(data), &[compression as u8]));
+    let checksum_type = if options.format_version == LEGACY_FORMAT_VERSION {
+        ChecksumType::Crc32c
+    } else {
+        options.checksum_type
+    };
+    let block_type = block_type | ((checksum_type as u8) << CHECKSUM_TYPE_SHIFT);
+    trailer.push(block_type);
+    let crc = block_checksum(checksum_type, data, block_type);
     put_fixed_32(&mut trailer, crc);
     assert_eq!(trailer.len(), BLOCK_TRAILER_SIZE);
     file.write(trailer.as_slice())?;
     // update offset
     *offset += (data.len() + BLOCK_TRAILER_SIZE) as u64;
-    Ok(())
+    Ok(crc)
+}
+
+// Returns the checksum of a block stored in the trailer
+fn block_checksum(checksum_type: ChecksumType, data: &[u8], block_type: u8) -> u32 {
+    match checksum_type {
+        ChecksumType::Crc32c => mask(extend(value(data), &[block_type])),
+        ChecksumType::XxHash64 => {
+            let mut h = Xxh64::new(0);
+            h.update(data);
+            h.update(&[block_type]);
+            h.digest() as u32
+        }
+    }
 }
 
 /// Read the block identified from `file` according to the given `handle`.
 /// If the read data does not match the checksum, return a error marked as `Status::Corruption`
 pub fn read_block(file: &dyn File, handle: &BlockHandle, verify_checksum: bool) -> Result<Vec<u8>> {
+    read_encrypted_block(file, handle, verify_checksum, None, None)
+}
+
+// Like `read_block` but decrypts the encrypted block by the cipher with the
+// tweak of the given file number, and decompresses the block compressed with
+// the dictionary
+fn read_encrypted_block(
+    file: &dyn File,
+    handle: &BlockHandle,
+    verify_checksum: bool,
+    cipher: Option<(&dyn BlockCipher, u64)>,
+    dictionary: Option<(&dyn DictionaryCompressor, &[u8])>,
+) -> Result<Vec<u8>> {
     let n = handle.size as usize;
     // TODO: use pre-allocated buf
     let mut buffer = vec![0; n + BLOCK_TRAILER_SIZE];
     file.read_exact_at(buffer.as_mut_slice(), handle.offset)?;
+    decode_block(buffer, handle, verify_checksum, cipher, dictionary)
+}
+
+/// Read the blocks identified by `handles` from `file` by a single vectored read.
+/// See `read_block` for details.
+pub fn read_blocks(
+    file: &dyn File,
+    handles: &[BlockHandle],
+    verify_checksum: bool,
+) -> Result<Vec<Vec<u8>>> {
+    let mut buffers: Vec<Vec<u8>> = handles
+        .iter()
+        .map(|h| vec![0; h.size as usize + BLOCK_TRAILER_SIZE])
+        .collect();
+    let mut requests: Vec<ReadRequest> = handles
+        .iter()
+        .zip(buffers.iter_mut())
+        .map(|(h, buf)| ReadRequest::new(h.offset, buf.as_mut_slice()))
+        .collect();
+    file.read_exact_at_vectored(&mut requests)?;
+    buffers
+        .into_iter()
+        .zip(handles.iter())
+        .map(|(buffer, handle)| decode_block(buffer, handle, verify_checksum, None, None))
+        .collect()
+}
+
+/// Read the block identified by `handle` from an `AsyncFile`.
+/// See `read_block` for details.
+pub async fn read_block_async(
+    file: &dyn AsyncFile,
+    handle: &BlockHandle,
+    verify_checksum: bool,
+) -> Result<Vec<u8>> {
+    read_encrypted_block_async(file, handle, verify_checksum, None, None).await
+}
+
+// The async version of `read_encrypted_block`
+async fn read_encrypted_block_async(
+    file: &dyn AsyncFile,
+    handle: &BlockHandle,
+    verify_checksum: bool,
+    cipher: Option<(&dyn BlockCipher, u64)>,
+    dictionary: Option<(&dyn DictionaryCompressor, &[u8])>,
+) -> Result<Vec<u8>> {
+    let n = handle.size as usize;
+    let mut buffer = vec![0; n + BLOCK_TRAILER_SIZE];
+    read_exact_at_async(file, buffer.as_mut_slice(), handle.offset).await?;
+    decode_block(buffer, handle, verify_checksum, cipher, dictionary)
+}
+
+/// Reads the footer of the table stored in bytes `[0..size)` of `file` and
+/// checks its magic number and format version.
+pub(crate) fn read_footer(file: &dyn File, size: u64, magic: u64) -> Result<Footer> {
+    check_table_size(size)?;
+    let mut footer_space = vec![0; FOOTER_ENCODED_LENGTH];
+    file.read_exact_at(
+        footer_space.as_mut_slice(),
+        size - FOOTER_ENCODED_LENGTH as u64,
+    )?;
+    decode_footer(footer_space.as_slice(), size, magic)
+}
+
+/// Verifies the checksums of all the blocks of the table with the given `footer`
+/// in `file`, including the data blocks, the index block, the meta block and
+/// the blocks it points to. The blocks are not decoded beyond the ones holding
+/// the handles, so neither the cipher nor the dictionary is needed.
+/// `cmp` is the comparator of the keys in the table.
+pub(crate) fn verify_table_checksums(
+    file: &dyn File,
+    footer: &Footer,
+    cmp: Arc<dyn Comparator>,
+) -> Result<()> {
+    let verify_block = |handle: &BlockHandle| -> Result<()> {
+        let mut buffer = vec![0; handle.size as usize + BLOCK_TRAILER_SIZE];
+        file.read_exact_at(buffer.as_mut_slice(), handle.offset)?;
+        verify_block_checksum(&buffer, handle)
+    };
+    let index_block = Block::new(read_block(file, &footer.index_handle, true)?)?;
+    let mut iter = index_block.iter(cmp);
+    iter.seek_to_first();
+    while iter.valid() {
+        for handle in decode_index_value(iter.value().as_slice(), footer.format_version())? {
+            verify_block(&handle)?;
+        }
+        iter.next();
+    }
+    iter.status()?;
+    if footer.meta_index_handle.size == 0 {
+        return Ok(());
+    }
+    let meta_block = Block::new(read_block(file, &footer.meta_index_handle, true)?)?;
+    let mut iter = meta_block.iter(Arc::new(BytewiseComparator::new()));
+    iter.seek_to_first();
+    while iter.valid() {
+        // some entries like the prefix marker hold no handle
+        if let Ok((handle, _)) = BlockHandle::decode_from(iter.value().as_slice()) {
+            if iter
+                .key()
+                .as_slice()
+                .starts_with(PARTITIONED_FILTER_KEY_PREFIX.as_bytes())
+            {
+                let index = read_block(file, &handle, true)?;
+                let partitions = FilterPartitionIndex::decode(&index).ok_or_else(|| {
+                    WickErr::new(Status::Corruption, Some("bad filter partition index"))
+                })?;
+                for partition in partitions.handles() {
+                    verify_block(partition)?;
+                }
+            } else {
+                verify_block(&handle)?;
+            }
+        }
+        iter.next();
+    }
+    iter.status()
+}
+
+// Decodes the footer read from the tail of a table file with `size` bytes,
+// which must carry the given magic number, and checks its format version is
+// supported
+fn decode_footer(src: &[u8], size: u64, magic: u64) -> Result<Footer> {
+    let footer = Footer::decode_with_magic(src, magic)
+        .map(|(footer, _)| footer)
+        .map_err(|e| {
+            let magic = decode_fixed_64(&src[FOOTER_ENCODED_LENGTH - 8..]);
+            e.with_report(
+                CorruptionReport::new(
+                    "bad table footer",
+                    size - FOOTER_ENCODED_LENGTH as u64,
+                    FOOTER_ENCODED_LENGTH as u64,
+                )
+                .with_context(format!("table size {}, magic number {:#x}", size, magic)),
+            )
+        })?;
+    if footer.format_version() > LATEST_FORMAT_VERSION {
+        return Err(WickErr::new(
+            Status::NotSupported,
+            Some("unsupported table format version"),
+        ));
+    }
+    Ok(footer)
+}
+
+// Decodes the handles of the data blocks in an index entry value of a table of
+// `format_version`, which only groups several blocks since `GROUPED_INDEX_FORMAT_VERSION`
+fn decode_index_value(value: &[u8], format_version: u32) -> Result<Vec<BlockHandle>> {
+    let handles = BlockHandle::decode_all_from(value)?;
+    if handles.len() > 1 && format_version < GROUPED_INDEX_FORMAT_VERSION {
+        return Err(WickErr::new(
+            Status::Corruption,
+            Some("grouped index entry in a table of an older format version"),
+        ));
+    }
+    Ok(handles)
+}
+
+fn check_table_size(size: u64) -> Result<()> {
+    if size < FOOTER_ENCODED_LENGTH as u64 {
+        return Err(WickErr::new(
+            Status::Corruption,
+            Some("file is too short to be an sstable"),
+        ));
+    };
+    Ok(())
+}
+
+// Verifies the trailer of a raw block read from file, decrypts the block contents
+// if encrypted and decompresses them
+// Verifies the checksum in the trailer of the block read into `buffer`
+fn verify_block_checksum(buffer: &[u8], handle: &BlockHandle) -> Result<()> {
+    let n = buffer.len() - BLOCK_TRAILER_SIZE;
+    let (len, block_type) = (buffer.len() as u64, buffer[n]);
+    let new_report = |reason: &str| {
+        CorruptionReport::new(reason, handle.offset, len).with_context(format!(
+            "block handle (offset: {}, size: {}), compression type {}",
+            handle.offset, handle.size, block_type
+        ))
+    };
+    let stored = decode_fixed_32(&buffer[n + 1..]);
+    // Block type is included in the checksum
+    let (crc, actual) = match (block_type & CHECKSUM_TYPE_MASK) >> CHECKSUM_TYPE_SHIFT {
+        t if t == ChecksumType::Crc32c as u8 => (unmask(stored), value(&buffer[..=n])),
+        t if t == ChecksumType::XxHash64 as u8 => (stored, xxh64(&buffer[..=n], 0) as u32),
+        _ => {
+            return Err(WickErr::new_corruption(
+                Some("unknown block checksum type"),
+                new_report("unknown block checksum type"),
+            ))
+        }
+    };
+    if crc != actual {
+        return Err(WickErr::new_corruption(
+            Some("block checksum mismatch"),
+            new_report("block checksum mismatch").with_checksums(crc, actual),
+        ));
+    }
+    Ok(())
+}
+
+fn decode_block(
+    mut buffer: Vec<u8>,
+    handle: &BlockHandle,
+    verify_checksum: bool,
+    cipher: Option<(&dyn BlockCipher, u64)>,
+    dictionary: Option<(&dyn DictionaryCompressor, &[u8])>,
+) -> Result<Vec<u8>> {
+    let n = buffer.len() - BLOCK_TRAILER_SIZE;
+    let (len, block_type) = (buffer.len() as u64, buffer[n]);
+    let new_report = |reason: &str| {
+        CorruptionReport::new(reason, handle.offset, len).with_context(format!(
+            "block handle (offset: {}, size: {}), compression type {}",
+            handle.offset, handle.size, block_type
+        ))
+    };
     if verify_checksum {
-        let crc = unmask(decode_fixed_32(&buffer.as_slice()[n + 1..]));
-        // Compression type is included in CRC checksum
-        let actual = value(&buffer.as_slice()[..=n]);
-        if crc != actual {
-            return Err(WickErr::new(
-                Status::Corruption,
-                Some("block checksum mismatch"),
-            ));
+        verify_block_checksum(&buffer, handle)?;
+    }
+    let mut compression = block_type & !CHECKSUM_TYPE_MASK;
+    if block_type & ENCRYPTED_BLOCK_FLAG != 0 {
+        match cipher {
+            Some((cipher, file_number)) => {
+                let tweak = block_tweak(file_number, handle.offset);
+                cipher.decrypt(&tweak, &mut buffer.as_mut_slice()[..n])?;
+                compression &= !ENCRYPTED_BLOCK_FLAG;
+            }
+            None => {
+                return Err(WickErr::new_corruption(
+                    Some("encrypted block without a cipher"),
+                    new_report("encrypted block without a cipher"),
+                ))
+            }
         }
     }
+    if compression & DICTIONARY_BLOCK_FLAG != 0 {
+        return match dictionary {
+            Some((compressor, dict)) => compressor.decompress(dict, &buffer.as_slice()[..n]),
+            None => Err(WickErr::new_corruption(
+                Some("dictionary compressed block without a dictionary"),
+                new_report("dictionary compressed block without a dictionary"),
+            )),
+        };
+    }
     let data = {
-        match CompressionType::from(buffer[n]) {
+        match CompressionType::from(compression) {
             CompressionType::NoCompression => {
                 buffer.truncate(buffer.len() - BLOCK_TRAILER_SIZE);
                 buffer
@@ -622,9 +2212,9 @@ pub fn read_block(file: &dyn File, handle: &BlockHandle, verify_checksum: bool)
                 decompressed
             }
             CompressionType::Unknown => {
-                return Err(WickErr::new(
-                    Status::Corruption,
+                return Err(WickErr::new_corruption(
                     Some("bad block compression type"),
+                    new_report("bad block compression type"),
                 ))
             }
         }
@@ -634,25 +2224,64 @@ pub fn read_block(file: &dyn File, handle: &BlockHandle, verify_checksum: bool)
 
 #[cfg(test)]
 mod tests {
+    use crate::cache::lru::SharedLRUCache;
+    use crate::db::format::{InternalKey, InternalKeyComparator, ValueType};
     use crate::filter::bloom::BloomFilter;
+    use crate::filter::FixedPrefixExtractor;
+    use crate::iterator::Iterator;
     use crate::sstable::block::Block;
-    use crate::sstable::table::{read_block, Table, TableBuilder};
-    use crate::sstable::BlockHandle;
+    use crate::sstable::dictionary::DictionaryCompressor;
+    use crate::sstable::encryption::{BlockCipher, BLOCK_TWEAK_SIZE};
+    use crate::sstable::table::{
+        is_good_compression_ratio, new_table_iterator, read_block, sample_block, Table,
+        TableBuilder, TableSeekReport, COMPRESSION_SAMPLE_PIECES, COMPRESSION_SAMPLE_SIZE,
+    };
+    use crate::sstable::{
+        BlockHandle, CHECKSUM_TYPE_MASK, CHECKSUM_TYPE_SHIFT, FOOTER_ENCODED_LENGTH,
+        LATEST_FORMAT_VERSION, LEGACY_FORMAT_VERSION,
+    };
+    use crate::storage::file::FileStorage;
     use crate::storage::mem::MemStorage;
     use crate::util::comparator::BytewiseComparator;
-    use crate::{Options, ReadOptions, Storage};
+    use crate::util::slice::Slice;
+    use crate::{
+        ChecksumType, CompressionType, LevelTableOptions, Options, RateLimiter, ReadOptions,
+        Result, Statistics, Status, Storage, Ticker,
+    };
+    use rand::Rng;
+    use std::future::Future;
+    use std::pin::Pin;
     use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
     use std::sync::Arc;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    // Polls a future which never pends
+    fn poll_ready<T>(mut f: Pin<Box<dyn Future<Output = T> + '_>>) -> T {
+        fn noop_raw_waker() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker {
+                noop_raw_waker()
+            }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+        match f.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(v) => v,
+            Poll::Pending => panic!("the future should be ready"),
+        }
+    }
 
     #[test]
     fn test_build_empty_table_with_meta_block() {
         let s = MemStorage::default();
         let mut o = Options::default();
         let bf = BloomFilter::new(16);
-        o.filter_policy = Some(Rc::new(bf));
+        o.filter_policy = Some(Arc::new(bf));
         let opt = Arc::new(o);
         let new_file = s.create("test").expect("");
-        let mut tb = TableBuilder::new(new_file, opt.clone());
+        let mut tb = TableBuilder::new(new_file, opt.clone(), 0);
         tb.finish(false).expect("");
         let file = s.open("test").expect("");
         let file_len = file.len().expect("");
@@ -666,7 +2295,7 @@ mod tests {
         let s = MemStorage::default();
         let new_file = s.create("test").expect("");
         let opt = Arc::new(Options::default()); // no filter block on default
-        let mut tb = TableBuilder::new(new_file, opt.clone());
+        let mut tb = TableBuilder::new(new_file, opt.clone(), 0);
         tb.finish(false).expect("");
         let file = s.open("test").expect("");
         let file_len = file.len().expect("");
@@ -674,8 +2303,260 @@ mod tests {
         assert!(table.filter_reader.is_none());
         assert!(table.meta_block_handle.is_none()); // no filter block means no meta block
         let read_opt = Rc::new(ReadOptions::default());
-        let res = table.internal_get(read_opt.clone(), b"test");
-        assert!(res.is_err());
+        let res = table
+            .internal_get(read_opt.clone(), b"test")
+            .expect("get on an empty table should work");
+        assert!(res.is_none());
+    }
+
+    #[test]
+    fn test_table_multi_get() {
+        let s = MemStorage::default();
+        let stats = Arc::new(Statistics::new());
+        let mut o = Options::default();
+        o.filter_policy = Some(Arc::new(BloomFilter::new(10)));
+        o.statistics = Some(stats.clone());
+        let opt = Arc::new(o);
+        let mut tb = TableBuilder::new(s.create("test").unwrap(), opt.clone(), 0);
+        for i in (0..1000).step_by(2) {
+            let key = format!("key{:04}", i);
+            tb.add(key.as_bytes(), key.as_bytes()).unwrap();
+        }
+        tb.finish(false).unwrap();
+        let file = s.open("test").unwrap();
+        let file_len = file.len().unwrap();
+        let table = Table::open(file, file_len, opt.clone()).unwrap();
+        let read_opt = Rc::new(ReadOptions::default());
+
+        // no data block is read if the filters rule out all the keys
+        let missing: Vec<&[u8]> = vec![b"key0001", b"key0501", b"key0999"];
+        let res = table
+            .internal_multi_get(read_opt.clone(), &missing)
+            .unwrap();
+        assert!(res.is_none());
+        assert_eq!(stats.get_ticker_count(Ticker::BlockRead), 0);
+
+        let keys: Vec<&[u8]> = vec![b"key0998", b"key0001", b"key0000"];
+        let res = table.internal_multi_get(read_opt, &keys).unwrap().unwrap();
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[0].as_ref().unwrap().1, b"key0998".to_vec());
+        // the first key greater than the missing one is returned like `internal_get`
+        assert!(res[1].is_none() || res[1].as_ref().unwrap().0 != b"key0001".to_vec());
+        assert_eq!(res[2].as_ref().unwrap().1, b"key0000".to_vec());
+    }
+
+    #[test]
+    fn test_table_partitioned_filters() {
+        let s = MemStorage::default();
+        let stats = Arc::new(Statistics::new());
+        let mut o = Options::default();
+        o.filter_policy = Some(Arc::new(BloomFilter::new(10)));
+        o.filter_partition_size = 64;
+        o.block_size = 256;
+        o.block_cache = Some(Arc::new(SharedLRUCache::new(1 << 20)));
+        o.statistics = Some(stats.clone());
+        let opt = Arc::new(o);
+        let mut tb = TableBuilder::new(s.create("test").unwrap(), opt.clone(), 0);
+        for i in (0..1000).step_by(2) {
+            let key = format!("key{:04}", i);
+            tb.add(key.as_bytes(), key.as_bytes()).unwrap();
+        }
+        tb.finish(false).unwrap();
+        let file = s.open("test").unwrap();
+        let file_len = file.len().unwrap();
+        let table = Table::open(file, file_len, opt.clone()).unwrap();
+        assert!(table.filter_reader.is_none());
+        assert!(table.filter_partitions.is_some());
+        assert!(table.read_properties().unwrap().unwrap().filter_size > 0);
+        let read_opt = Rc::new(ReadOptions::default());
+
+        // only the partitions are read if the filters rule out all the keys
+        let missing: Vec<&[u8]> = vec![b"key0001", b"key0501", b"key0999"];
+        let res = table
+            .internal_multi_get(read_opt.clone(), &missing)
+            .unwrap();
+        assert!(res.is_none());
+        let partition_reads = stats.get_ticker_count(Ticker::BlockRead);
+        assert!(partition_reads > 0 && partition_reads <= 3);
+        // the partitions are cached
+        let res = table
+            .internal_multi_get(read_opt.clone(), &missing)
+            .unwrap();
+        assert!(res.is_none());
+        assert_eq!(stats.get_ticker_count(Ticker::BlockRead), partition_reads);
+
+        for i in (0..1000).step_by(2) {
+            let key = format!("key{:04}", i);
+            let (_, value) = table
+                .internal_get(read_opt.clone(), key.as_bytes())
+                .unwrap()
+                .unwrap();
+            assert_eq!(value, key.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_table_filter_options() {
+        let s = MemStorage::default();
+        let mut o = Options::default();
+        o.filter_policy = Some(Arc::new(BloomFilter::new(10)));
+        o.prefix_extractor = Some(Arc::new(FixedPrefixExtractor::new(5)));
+        let level_options = |bloom_bits_per_key, whole_key_filtering| LevelTableOptions {
+            bloom_bits_per_key,
+            whole_key_filtering,
+            ..o.table_options_for_level(0)
+        };
+        o.per_level_table_options = vec![
+            level_options(4, true),
+            level_options(20, true),
+            level_options(0, false),
+        ];
+        let opt = Arc::new(o);
+        let build = |level: usize| {
+            let name = format!("test{}", level);
+            let mut tb = TableBuilder::new(s.create(name.as_str()).unwrap(), opt.clone(), level);
+            for i in (0..1000).step_by(2) {
+                let key = format!("key{:04}", i);
+                tb.add(key.as_bytes(), key.as_bytes()).unwrap();
+            }
+            tb.finish(false).unwrap();
+            let file = s.open(name.as_str()).unwrap();
+            let file_len = file.len().unwrap();
+            Table::open(file, file_len, opt.clone()).unwrap()
+        };
+        let read_opt = Rc::new(ReadOptions::default());
+        let missing: Vec<&[u8]> = vec![b"key0001", b"key0501", b"key0999"];
+
+        // The bits per key of the level decide the size of the filters
+        let (t0, t1) = (build(0), build(1));
+        let filter_size = |t: &Table| t.read_properties().unwrap().unwrap().filter_size;
+        assert!(filter_size(&t0) * 3 < filter_size(&t1));
+        assert_eq!(t0.whole_key_suffix, Some(0));
+        assert_eq!(t1.whole_key_suffix, Some(0));
+        assert!(t1
+            .internal_multi_get(read_opt.clone(), &missing)
+            .unwrap()
+            .is_none());
+
+        // Only the prefixes are in the filters
+        let t2 = build(2);
+        assert!(filter_size(&t2) < filter_size(&t0));
+        assert!(t2.prefix_filtered);
+        assert_eq!(t2.whole_key_suffix, None);
+        assert!(t2
+            .internal_multi_get(read_opt.clone(), &missing)
+            .unwrap()
+            .is_some());
+        let (key, _) = t2.internal_get(read_opt, b"key0998").unwrap().unwrap();
+        assert_eq!(key, b"key0998".to_vec());
+    }
+
+    #[test]
+    fn test_table_magic_number() {
+        let s = MemStorage::default();
+        let mut o = Options::default();
+        o.table_magic_number = 0x1234_5678_9abc_def0;
+        let custom = Arc::new(o);
+        let mut tb = TableBuilder::new(s.create("custom").unwrap(), custom.clone(), 0);
+        tb.add(b"k", b"v").unwrap();
+        tb.finish(false).unwrap();
+        let tb_default = Arc::new(Options::default());
+        let mut tb = TableBuilder::new(s.create("vanilla").unwrap(), tb_default.clone(), 0);
+        tb.add(b"k", b"v").unwrap();
+        tb.finish(false).unwrap();
+
+        let open = |name: &str, opt: &Arc<Options>| {
+            let file = s.open(name).unwrap();
+            let len = file.len().unwrap();
+            Table::open(file, len, opt.clone())
+        };
+        assert!(open("custom", &custom).is_ok());
+        assert!(open("vanilla", &tb_default).is_ok());
+        // The tables of different formats are never opened by each other
+        for (name, opt) in &[("custom", &tb_default), ("vanilla", &custom)] {
+            let e = open(name, opt).err().unwrap();
+            assert_eq!(e.status(), Status::Corruption);
+        }
+    }
+
+    #[test]
+    fn test_kv_size_histograms() {
+        let s = MemStorage::default();
+        let mut o = Options::default();
+        o.record_kv_size_histograms = true;
+        // the meta block holds both the filter and the histograms
+        o.filter_policy = Some(Arc::new(BloomFilter::new(10)));
+        let opt = Arc::new(o);
+        let mut tb = TableBuilder::new(s.create("recorded").unwrap(), opt.clone(), 0);
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            tb.add(key.as_bytes(), "v".repeat(i).as_bytes()).unwrap();
+        }
+        tb.finish(false).unwrap();
+        let default_opt = Arc::new(Options::default());
+        let mut tb = TableBuilder::new(s.create("vanilla").unwrap(), default_opt.clone(), 0);
+        tb.add(b"k", b"v").unwrap();
+        tb.finish(false).unwrap();
+
+        let open = |name: &str, opt: &Arc<Options>| {
+            let file = s.open(name).unwrap();
+            let len = file.len().unwrap();
+            Table::open(file, len, opt.clone()).unwrap()
+        };
+        let table = open("recorded", &opt);
+        assert!(table.filter_reader.is_some());
+        let (key_sizes, value_sizes) = table.read_kv_size_histograms().unwrap().unwrap();
+        assert_eq!(key_sizes.count(), 100);
+        assert_eq!(key_sizes.min(), 6.0);
+        assert_eq!(key_sizes.max(), 6.0);
+        assert_eq!(value_sizes.count(), 100);
+        assert_eq!(value_sizes.max(), 99.0);
+        assert_eq!(value_sizes.sum(), (0..100).sum::<i32>() as f64);
+        // the histograms are read without the filter policy
+        let table = open("recorded", &default_opt);
+        assert!(table.read_kv_size_histograms().unwrap().is_some());
+        let table = open("vanilla", &default_opt);
+        assert!(table.read_kv_size_histograms().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_table_properties() {
+        let s = MemStorage::default();
+        let mut o = Options::default();
+        o.filter_policy = Some(Arc::new(BloomFilter::new(10)));
+        let opt = Arc::new(o);
+        let icmp = Arc::new(InternalKeyComparator::new(opt.comparator.clone()));
+        let mut tb = TableBuilder::new_with_comparator(
+            s.create("test").unwrap(),
+            opt.clone(),
+            icmp.clone(),
+            0,
+        )
+        .with_internal_keys();
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            let value_type = if i % 3 == 0 {
+                ValueType::Deletion
+            } else {
+                ValueType::Value
+            };
+            let ikey = InternalKey::new(&Slice::from(key.as_str()), i, value_type);
+            tb.add(ikey.data(), b"v").unwrap();
+        }
+        tb.finish(false).unwrap();
+        let file = s.open("test").unwrap();
+        let len = file.len().unwrap();
+        let table = Table::open_with_comparator(file, len, opt, icmp).unwrap();
+        let properties = table.read_properties().unwrap().unwrap();
+        assert_eq!(properties.num_entries, 10);
+        assert_eq!(properties.num_deletions, 4);
+        assert_eq!(properties.raw_key_size, 40);
+        assert_eq!(properties.raw_value_size, 10);
+        assert_eq!(properties.num_data_blocks, 1);
+        assert!(properties.filter_size > 0);
+        assert_eq!(properties.comparator_name, "leveldb.BytewiseComparator");
+        assert_eq!(properties.oldest_sequence, 0);
+        assert_eq!(properties.newest_sequence, 9);
     }
 
     #[test]
@@ -684,7 +2565,7 @@ mod tests {
         let s = MemStorage::default();
         let new_file = s.create("test").expect("file create should work");
         let opt = Arc::new(Options::default());
-        let mut tb = TableBuilder::new(new_file, opt.clone());
+        let mut tb = TableBuilder::new(new_file, opt.clone(), 0);
         tb.add(b"222", b"").expect("");
         tb.add(b"1", b"").expect("");
     }
@@ -694,7 +2575,7 @@ mod tests {
         let s = MemStorage::default();
         let new_file = s.create("test").expect("file create should work");
         let opt = Arc::new(Options::default());
-        let mut tb = TableBuilder::new(new_file, opt.clone());
+        let mut tb = TableBuilder::new(new_file, opt.clone(), 0);
         let test_pairs = vec![("", "test"), ("aaa", "123"), ("bbb", "456"), ("ccc", "789")];
         for (key, val) in test_pairs.clone().drain(..) {
             tb.data_block.add(key.as_bytes(), val.as_bytes());
@@ -726,7 +2607,7 @@ mod tests {
         let s = MemStorage::default();
         let new_file = s.create("test").expect("file create should work");
         let opt = Arc::new(Options::default());
-        let mut tb = TableBuilder::new(new_file, opt.clone());
+        let mut tb = TableBuilder::new(new_file, opt.clone(), 0);
         let tests = vec![("", "test"), ("a", "aa"), ("b", "bb")];
         for (key, val) in tests.clone().drain(..) {
             tb.add(key.as_bytes(), val.as_bytes()).expect("");
@@ -738,18 +2619,629 @@ mod tests {
         let read_opt = Rc::new(ReadOptions {
             verify_checksums: true,
             fill_cache: true,
-            snapshot: None,
+            ..ReadOptions::default()
         });
         for (key, val) in tests.clone().drain(..) {
             assert_eq!(
-                val,
+                val.as_bytes(),
                 table
                     .internal_get(read_opt.clone(), key.as_bytes())
                     .expect("")
                     .unwrap()
                     .1
-                    .as_str()
+                    .as_slice()
+            );
+        }
+    }
+
+    // A toy cipher xoring the data with the tweak
+    struct XorCipher;
+
+    impl BlockCipher for XorCipher {
+        fn name(&self) -> &str {
+            "XorCipher"
+        }
+
+        fn encrypt(&self, tweak: &[u8; BLOCK_TWEAK_SIZE], data: &mut [u8]) -> Result<()> {
+            for (i, b) in data.iter_mut().enumerate() {
+                *b ^= tweak[i % BLOCK_TWEAK_SIZE] ^ 0x5a;
+            }
+            Ok(())
+        }
+
+        fn decrypt(&self, tweak: &[u8; BLOCK_TWEAK_SIZE], data: &mut [u8]) -> Result<()> {
+            self.encrypt(tweak, data)
+        }
+    }
+
+    #[test]
+    fn test_table_with_block_cipher() {
+        let s = MemStorage::default();
+        let mut o = Options::default();
+        o.compression = CompressionType::NoCompression;
+        o.block_cipher = Some(Arc::new(XorCipher));
+        let opt = Arc::new(o);
+        let mut tb =
+            TableBuilder::new(s.create("test").unwrap(), opt.clone(), 0).with_file_number(7);
+        let tests = [("a", "value_a"), ("b", "value_b"), ("c", "value_c")];
+        for (key, val) in tests.iter() {
+            tb.add(key.as_bytes(), val.as_bytes()).unwrap();
+        }
+        tb.finish(false).unwrap();
+        let mut data = vec![];
+        s.open("test").unwrap().read_all(&mut data).unwrap();
+        // The values are only stored in the encrypted data block
+        assert!(!data.windows(7).any(|w| w == b"value_a"));
+
+        let read_opt = Rc::new(ReadOptions {
+            verify_checksums: true,
+            fill_cache: false,
+            ..ReadOptions::default()
+        });
+        let file = s.open("test").unwrap();
+        let table = Table::open(file, data.len() as u64, opt.clone())
+            .unwrap()
+            .with_file_number(7);
+        for (key, val) in tests.iter() {
+            let (_, v) = table
+                .internal_get(read_opt.clone(), key.as_bytes())
+                .unwrap()
+                .unwrap();
+            assert_eq!(v.as_slice(), val.as_bytes());
+        }
+
+        // The async reads decrypt the data blocks as well
+        let file_name = "test_table_with_block_cipher";
+        std::fs::write(file_name, &data).unwrap();
+        let file = std::fs::File::open(file_name).unwrap();
+        let table = poll_ready(Box::pin(Table::open_async_with_comparator(
+            Box::new(file),
+            data.len() as u64,
+            opt.clone(),
+            opt.comparator.clone(),
+        )))
+        .unwrap()
+        .with_file_number(7);
+        for (key, val) in tests.iter() {
+            let (_, v) = poll_ready(Box::pin(
+                table.internal_get_async(read_opt.clone(), key.as_bytes()),
+            ))
+            .unwrap()
+            .unwrap();
+            assert_eq!(v.as_slice(), val.as_bytes());
+        }
+        std::fs::remove_file(file_name).unwrap();
+
+        // The index block is readable without the cipher but the data block isn't
+        let file = s.open("test").unwrap();
+        let table = Table::open(file, data.len() as u64, Arc::new(Options::default())).unwrap();
+        let err = table.internal_get(read_opt, b"a").unwrap_err();
+        assert_eq!(err.status(), Status::Corruption);
+    }
+
+    // A toy compressor xoring the data with a dictionary cut from the first sample
+    #[derive(Default)]
+    struct XorDictCompressor {
+        trained: AtomicUsize,
+    }
+
+    impl DictionaryCompressor for XorDictCompressor {
+        fn name(&self) -> &str {
+            "XorDictCompressor"
+        }
+
+        fn train(&self, samples: &[Vec<u8>], max_dict_bytes: usize) -> Result<Vec<u8>> {
+            self.trained.fetch_add(1, AtomicOrdering::SeqCst);
+            let first = &samples[0];
+            Ok(first[..first.len().min(max_dict_bytes)].to_vec())
+        }
+
+        fn compress(&self, dict: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+            Ok(data
+                .iter()
+                .enumerate()
+                .map(|(i, b)| b ^ dict[i % dict.len()])
+                .collect())
+        }
+
+        fn decompress(&self, dict: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+            self.compress(dict, data)
+        }
+    }
+
+    #[test]
+    fn test_table_with_compression_dictionary() {
+        let s = MemStorage::default();
+        let compressor = Arc::new(XorDictCompressor::default());
+        let mut o = Options::default();
+        o.block_size = 64;
+        o.dictionary_compressor = Some(compressor.clone());
+        o.compression_dict_max_bytes = 16;
+        o.compression_dict_train_bytes = 200;
+        let opt = Arc::new(o);
+        let mut tb = TableBuilder::new(s.create("test").unwrap(), opt.clone(), 1)
+            .with_compression_dictionary();
+        let tests: Vec<(String, String)> = (0..50)
+            .map(|i| (format!("key_{:03}", i), format!("value_{:03}", i)))
+            .collect();
+        for (i, (key, val)) in tests.iter().enumerate() {
+            tb.add(key.as_bytes(), val.as_bytes()).unwrap();
+            assert_eq!(tb.num_entries(), i + 1);
+        }
+        tb.finish(false).unwrap();
+        assert_eq!(compressor.trained.load(AtomicOrdering::SeqCst), 1);
+        let mut data = vec![];
+        s.open("test").unwrap().read_all(&mut data).unwrap();
+        assert_eq!(tb.file_size(), data.len() as u64);
+        // Both the buffered entries and the later ones are compressed
+        assert!(!data.windows(9).any(|w| w == b"value_001"));
+        assert!(!data.windows(9).any(|w| w == b"value_049"));
+
+        let read_opt = Rc::new(ReadOptions {
+            verify_checksums: true,
+            fill_cache: false,
+            ..ReadOptions::default()
+        });
+        let file = s.open("test").unwrap();
+        let table = Arc::new(Table::open(file, data.len() as u64, opt.clone()).unwrap());
+        let mut iter = new_table_iterator(table.clone(), read_opt.clone());
+        iter.seek_to_first();
+        for (key, val) in tests.iter() {
+            assert!(iter.valid());
+            assert_eq!(iter.key().as_slice(), key.as_bytes());
+            assert_eq!(iter.value().as_slice(), val.as_bytes());
+            iter.next();
+        }
+        assert!(!iter.valid());
+        iter.status().unwrap();
+
+        // The data blocks are unreadable without the compressor
+        let file = s.open("test").unwrap();
+        let table = Table::open(file, data.len() as u64, Arc::new(Options::default())).unwrap();
+        let err = table.internal_get(read_opt, b"key_000").unwrap_err();
+        assert_eq!(err.status(), Status::Corruption);
+
+        // A table builder not asking for the dictionary never trains one
+        let mut tb = TableBuilder::new(s.create("plain").unwrap(), opt, 0);
+        for (key, val) in tests.iter() {
+            tb.add(key.as_bytes(), val.as_bytes()).unwrap();
+        }
+        tb.finish(false).unwrap();
+        assert_eq!(compressor.trained.load(AtomicOrdering::SeqCst), 1);
+    }
+
+    // Builds a table with a few keys and returns a copy of it with the byte at
+    // `corrupted_offset` (counted from the end if negative) flipped
+    fn new_corrupted_table(s: &MemStorage, opt: Arc<Options>, corrupted_offset: i64) -> u64 {
+        let mut tb = TableBuilder::new(s.create("test").expect(""), opt, 0);
+        for (key, val) in &[("a", "aa"), ("b", "bb"), ("c", "cc")] {
+            tb.add(key.as_bytes(), val.as_bytes()).expect("");
+        }
+        tb.finish(false).expect("");
+        let mut data = vec![];
+        s.open("test").expect("").read_all(&mut data).expect("");
+        let i = if corrupted_offset < 0 {
+            (data.len() as i64 + corrupted_offset) as usize
+        } else {
+            corrupted_offset as usize
+        };
+        data[i] ^= 0xff;
+        s.create("corrupted").expect("").write(&data).expect("");
+        data.len() as u64
+    }
+
+    #[test]
+    fn test_corruption_report_of_bad_block() {
+        let s = MemStorage::default();
+        let opt = Arc::new(Options::default());
+        let size = new_corrupted_table(&s, opt.clone(), 1);
+        let file = s.open("corrupted").expect("");
+        let table = Table::open(file, size, opt).expect("table open should work");
+        let read_opt = Rc::new(ReadOptions {
+            verify_checksums: true,
+            fill_cache: false,
+            ..ReadOptions::default()
+        });
+        let err = table.internal_get(read_opt, b"a").unwrap_err();
+        assert_eq!(err.status(), Status::Corruption);
+        let report = err.corruption_report().expect("report should be attached");
+        assert_eq!(report.reason, "block checksum mismatch");
+        assert_eq!(report.offset, 0);
+        assert!(report.size > 0);
+        assert_ne!(report.expected_checksum, report.actual_checksum);
+        assert!(report.context.as_ref().unwrap().contains("block handle"));
+    }
+
+    #[test]
+    fn test_table_format_version() {
+        let s = MemStorage::default();
+        let read_opt = Rc::new(ReadOptions::default());
+        // The legacy tables ignore the checksum type
+        let mut o = Options::default();
+        o.checksum_type = ChecksumType::XxHash64;
+        o.format_version = LEGACY_FORMAT_VERSION;
+        let size = new_corrupted_table(&s, Arc::new(o), 1);
+        let table =
+            Table::open(s.open("test").unwrap(), size, Arc::new(Options::default())).unwrap();
+        assert_eq!(table.format_version(), LEGACY_FORMAT_VERSION);
+        let (handle, _) = table.data_blocks().unwrap().remove(0);
+        let mut block_type = [0; 1];
+        s.open("test")
+            .unwrap()
+            .read_exact_at(&mut block_type, handle.offset + handle.size)
+            .unwrap();
+        assert_eq!(block_type[0] & CHECKSUM_TYPE_MASK, 0);
+        let (_, v) = table.internal_get(read_opt, b"b").unwrap().unwrap();
+        assert_eq!(v.as_slice(), b"bb");
+
+        // The version follows the handles in the footer
+        let opt = Arc::new(Options::default());
+        let size = new_corrupted_table(&s, opt.clone(), -12);
+        let table = Table::open(s.open("test").unwrap(), size, opt.clone()).unwrap();
+        assert_eq!(table.format_version(), LATEST_FORMAT_VERSION);
+        let err = Table::open(s.open("corrupted").unwrap(), size, opt)
+            .err()
+            .unwrap();
+        assert_eq!(err.status(), Status::NotSupported);
+    }
+
+    #[test]
+    fn test_xxhash64_checksum() {
+        let s = MemStorage::default();
+        let mut o = Options::default();
+        o.checksum_type = ChecksumType::XxHash64;
+        let opt = Arc::new(o);
+        let size = new_corrupted_table(&s, opt.clone(), 1);
+        let read_opt = Rc::new(ReadOptions {
+            verify_checksums: true,
+            fill_cache: false,
+            ..ReadOptions::default()
+        });
+        // The checksum type is recorded in the trailer and readable by any option
+        let file = s.open("test").unwrap();
+        let table = Table::open(file, size, Arc::new(Options::default())).unwrap();
+        let (handle, _) = table.data_blocks().unwrap().remove(0);
+        let mut block_type = [0; 1];
+        s.open("test")
+            .unwrap()
+            .read_exact_at(&mut block_type, handle.offset + handle.size)
+            .unwrap();
+        assert_eq!(
+            (block_type[0] & CHECKSUM_TYPE_MASK) >> CHECKSUM_TYPE_SHIFT,
+            ChecksumType::XxHash64 as u8
+        );
+        for (key, val) in &[("a", "aa"), ("b", "bb"), ("c", "cc")] {
+            let (_, v) = table
+                .internal_get(read_opt.clone(), key.as_bytes())
+                .unwrap()
+                .unwrap();
+            assert_eq!(v.as_slice(), val.as_bytes());
+        }
+
+        let file = s.open("corrupted").unwrap();
+        let table = Table::open(file, size, opt).unwrap();
+        let err = table.internal_get(read_opt, b"a").unwrap_err();
+        let report = err.corruption_report().expect("report should be attached");
+        assert_eq!(report.reason, "block checksum mismatch");
+    }
+
+    #[test]
+    fn test_corruption_report_of_bad_footer() {
+        let s = MemStorage::default();
+        let opt = Arc::new(Options::default());
+        let size = new_corrupted_table(&s, opt.clone(), -1);
+        let file = s.open("corrupted").expect("");
+        let err = Table::open(file, size, opt)
+            .err()
+            .expect("bad magic should be detected");
+        let report = err.corruption_report().expect("report should be attached");
+        assert_eq!(report.offset, size - FOOTER_ENCODED_LENGTH as u64);
+        assert_eq!(report.size, FOOTER_ENCODED_LENGTH as u64);
+        assert!(report.context.as_ref().unwrap().contains("magic number"));
+    }
+
+    #[test]
+    fn test_table_builder_with_rate_limiter() {
+        let s = MemStorage::default();
+        let new_file = s.create("test").expect("file create should work");
+        let limiter = Arc::new(RateLimiter::new(1 << 30));
+        let mut o = Options::default();
+        o.block_size = 128;
+        o.rate_limiter = Some(limiter.clone());
+        let opt = Arc::new(o);
+        let mut tb = TableBuilder::new(new_file, opt.clone(), 0);
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            tb.add(key.as_bytes(), b"value").expect("");
+        }
+        tb.finish(false).expect("TableBuilder 'finish' should work");
+        // every byte of the table is requested from the limiter
+        assert_eq!(limiter.total_bytes_through(), tb.file_size());
+        assert!(limiter.total_requests() > 1);
+    }
+
+    #[test]
+    fn test_min_compression_ratio() {
+        let s = MemStorage::default();
+        let stats = Arc::new(Statistics::new());
+        let mut o = Options::default();
+        o.block_size = 16 << 10;
+        o.min_compression_ratio = 1.5;
+        o.statistics = Some(stats.clone());
+        let opt = Arc::new(o);
+        let mut rng = rand::thread_rng();
+        let random: Vec<(String, Vec<u8>)> = (0..200)
+            .map(|i| {
+                let value: Vec<u8> = (0..1000).map(|_| rng.gen()).collect();
+                (format!("key{:05}", i), value)
+            })
+            .collect();
+        let repeated: Vec<(String, Vec<u8>)> = (0..200)
+            .map(|i| (format!("key{:05}", i), b"value".repeat(200)))
+            .collect();
+        for (name, tests) in [("random", &random), ("repeated", &repeated)].iter() {
+            let skipped = stats.get_ticker_count(Ticker::BlockCompressionSkipped);
+            let mut tb = TableBuilder::new(s.create(name).unwrap(), opt.clone(), 0);
+            for (key, val) in tests.iter() {
+                tb.add(key.as_bytes(), val.as_slice()).expect("");
+            }
+            tb.finish(false).expect("TableBuilder 'finish' should work");
+            let skipped = stats.get_ticker_count(Ticker::BlockCompressionSkipped) - skipped;
+            if *name == "random" {
+                // every data block is stored raw
+                assert!(skipped >= 200 * 1000 / (16 << 10));
+                assert!(tb.file_size() > 200 * 1000);
+            } else {
+                assert!(tb.file_size() < 200 * 1000 / 10);
+            }
+            let file = s.open(name).unwrap();
+            let file_len = file.len().unwrap();
+            let table = Table::open(file, file_len, opt.clone()).expect("table open should work");
+            let read_opt = Rc::new(ReadOptions::default());
+            for (key, val) in tests.iter() {
+                let (_, v) = table
+                    .internal_get(read_opt.clone(), key.as_bytes())
+                    .expect("")
+                    .unwrap();
+                assert_eq!(v.as_slice(), val.as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_good_compression_ratio() {
+        assert!(is_good_compression_ratio(100, 200, 0.0));
+        assert!(is_good_compression_ratio(150, 100, 1.5));
+        assert!(!is_good_compression_ratio(149, 100, 1.5));
+        let block: Vec<u8> = (0..10 * COMPRESSION_SAMPLE_SIZE).map(|i| i as u8).collect();
+        let sample = sample_block(&block);
+        assert_eq!(sample.len(), COMPRESSION_SAMPLE_SIZE);
+        let piece = COMPRESSION_SAMPLE_SIZE / COMPRESSION_SAMPLE_PIECES;
+        assert_eq!(
+            &sample[piece..2 * piece],
+            &block[block.len() / 4..block.len() / 4 + piece]
+        );
+    }
+
+    #[test]
+    fn test_table_with_grouped_index_entries() {
+        let s = MemStorage::default();
+        let new_file = s.create("test").expect("file create should work");
+        let mut o = Options::default();
+        o.block_size = 64;
+        o.filter_policy = Some(Arc::new(BloomFilter::new(10)));
+        o.per_level_table_options = vec![
+            Options::default().table_options_for_level(0),
+            LevelTableOptions {
+                block_restart_interval: 4,
+                index_block_restart_interval: 2,
+                blocks_per_index_entry: 4,
+                bloom_bits_per_key: 0,
+                whole_key_filtering: true,
+            },
+        ];
+        let opt = Arc::new(o);
+        let mut tb = TableBuilder::new(new_file, opt.clone(), 1);
+        let tests: Vec<(String, String)> = (0..500)
+            .map(|i| (format!("key{:05}", i), format!("value{}", i)))
+            .collect();
+        for (key, val) in tests.iter() {
+            tb.add(key.as_bytes(), val.as_bytes()).expect("");
+        }
+        tb.finish(false).expect("TableBuilder 'finish' should work");
+        let file = s.open("test").expect("file open should work");
+        let file_len = file.len().expect("file len should work");
+        let table = Table::open(file, file_len, opt.clone()).expect("table open should work");
+        let read_opt = Rc::new(ReadOptions::default());
+        for (key, val) in tests.iter() {
+            let (_, v) = table
+                .internal_get(read_opt.clone(), key.as_bytes())
+                .expect("")
+                .unwrap();
+            assert_eq!(v.as_slice(), val.as_bytes());
+        }
+        assert!(table
+            .internal_get(read_opt.clone(), b"key99999")
+            .expect("")
+            .is_none());
+    }
+
+    #[test]
+    fn test_grouped_index_entries_format_version() {
+        let s = MemStorage::default();
+        let tests: Vec<(String, String)> = (0..200)
+            .map(|i| (format!("key{:05}", i), format!("value{}", i)))
+            .collect();
+        let build = |format_version: u32| {
+            let o = Options {
+                block_size: 64,
+                blocks_per_index_entry: 4,
+                format_version,
+                ..Options::default()
+            };
+            let mut tb = TableBuilder::new(s.create("test").unwrap(), Arc::new(o), 0);
+            for (key, val) in tests.iter() {
+                tb.add(key.as_bytes(), val.as_bytes()).unwrap();
+            }
+            tb.finish(false).unwrap();
+            let mut data = vec![];
+            s.open("test").unwrap().read_all(&mut data).unwrap();
+            data
+        };
+        let open = |data: &[u8]| {
+            s.create("grouped").unwrap().write(data).unwrap();
+            let file = s.open("grouped").unwrap();
+            Arc::new(Table::open(file, data.len() as u64, Arc::new(Options::default())).unwrap())
+        };
+        let max_group_size = |table: &Table| {
+            let mut index_iter = table.index_block.iter(table.options.comparator.clone());
+            index_iter.seek_to_first();
+            let mut max = 0;
+            while index_iter.valid() {
+                let handles = BlockHandle::decode_all_from(index_iter.value().as_slice());
+                max = max.max(handles.unwrap().len());
+                index_iter.next();
+            }
+            max
+        };
+        let read_opt = Rc::new(ReadOptions::default());
+
+        // The grouped blocks are all read through the table iterator
+        let data = build(LATEST_FORMAT_VERSION);
+        let table = open(&data);
+        assert_eq!(table.format_version(), LATEST_FORMAT_VERSION);
+        assert_eq!(max_group_size(&table), 4);
+        let mut iter = new_table_iterator(table.clone(), read_opt.clone());
+        iter.seek_to_first();
+        for (key, val) in tests.iter() {
+            assert!(iter.valid());
+            assert_eq!(iter.key().as_slice(), key.as_bytes());
+            assert_eq!(iter.value().as_slice(), val.as_bytes());
+            iter.next();
+        }
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        assert_eq!(iter.key().as_slice(), tests[199].0.as_bytes());
+        iter.seek(&Slice::from("key00101"));
+        assert_eq!(iter.value().as_slice(), b"value101");
+        iter.status().unwrap();
+
+        // The legacy tables never group the blocks
+        let table = open(&build(LEGACY_FORMAT_VERSION));
+        assert_eq!(table.format_version(), LEGACY_FORMAT_VERSION);
+        assert_eq!(max_group_size(&table), 1);
+
+        // The grouped entries are refused in a table claiming the legacy format
+        let mut data = data;
+        let version_offset = data.len() - 12;
+        data[version_offset] = LEGACY_FORMAT_VERSION as u8;
+        let table = open(&data);
+        assert_eq!(table.format_version(), LEGACY_FORMAT_VERSION);
+        let mut iter = new_table_iterator(table.clone(), read_opt.clone());
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert_eq!(iter.status().unwrap_err().status(), Status::Corruption);
+        let err = table.internal_get(read_opt, b"key00000").unwrap_err();
+        assert_eq!(err.status(), Status::Corruption);
+
+        // The tables of a newer format are not opened
+        let mut data = build(LATEST_FORMAT_VERSION);
+        let version_offset = data.len() - 12;
+        data[version_offset] = LATEST_FORMAT_VERSION as u8 + 1;
+        s.create("newer").unwrap().write(&data).unwrap();
+        let file = s.open("newer").unwrap();
+        let err = Table::open(file, data.len() as u64, Arc::new(Options::default()))
+            .err()
+            .unwrap();
+        assert_eq!(err.status(), Status::NotSupported);
+    }
+
+    #[test]
+    fn test_table_read_by_async_file() {
+        let file_name = "test_table_read_by_async_file";
+        let s = FileStorage;
+        let new_file = s.create(file_name).expect("file create should work");
+        let mut o = Options::default();
+        o.filter_policy = Some(Arc::new(BloomFilter::new(10)));
+        let opt = Arc::new(o);
+        let mut tb = TableBuilder::new(new_file, opt.clone(), 0);
+        let tests = vec![("a", "aa"), ("b", "bb"), ("c", "cc")];
+        for (key, val) in tests.clone().drain(..) {
+            tb.add(key.as_bytes(), val.as_bytes()).expect("");
+        }
+        tb.finish(true).expect("TableBuilder 'finish' should work");
+        let file = std::fs::File::open(file_name).expect("file open should work");
+        let file_len = file.metadata().expect("file len should work").len();
+        let table = poll_ready(Box::pin(Table::open_async_with_comparator(
+            Box::new(file),
+            file_len,
+            opt.clone(),
+            opt.comparator.clone(),
+        )))
+        .expect("table open should work");
+        assert!(table.filter_reader.is_some());
+        let read_opt = Rc::new(ReadOptions::default());
+        for (key, val) in tests.clone().drain(..) {
+            let res = poll_ready(Box::pin(
+                table.internal_get_async(read_opt.clone(), key.as_bytes()),
+            ))
+            .expect("")
+            .unwrap();
+            assert_eq!(val.as_bytes(), res.1.as_slice());
+        }
+        // blocking reads are not served by an async table
+        assert!(table.internal_get(read_opt.clone(), b"a").is_err());
+        s.remove(file_name).expect("");
+    }
+
+    #[test]
+    fn test_table_seek_report() {
+        let s = MemStorage::default();
+        let mut avg_scanned_entries = vec![];
+        for restart_interval in [1, 4].iter() {
+            let stats = Arc::new(Statistics::new());
+            let mut o = Options::default();
+            o.block_size = 256;
+            o.block_restart_interval = *restart_interval;
+            o.statistics = Some(stats.clone());
+            let opt = Arc::new(o);
+            let name = format!("test_table_seek_report_{}", restart_interval);
+            let mut tb = TableBuilder::new(s.create(name.as_str()).unwrap(), opt.clone(), 0);
+            for i in 0..200 {
+                let key = format!("key{:03}", i);
+                tb.add(key.as_bytes(), b"value").expect("");
+            }
+            tb.finish(false).expect("TableBuilder 'finish' should work");
+            let file = s.open(name.as_str()).unwrap();
+            let file_len = file.len().unwrap();
+            let table = Table::open(file, file_len, opt.clone()).expect("table open should work");
+            assert_eq!(table.seek_report(), TableSeekReport::default());
+            let read_opt = Rc::new(ReadOptions::default());
+            for i in 0..200 {
+                let key = format!("key{:03}", i);
+                assert!(table
+                    .internal_get(read_opt.clone(), key.as_bytes())
+                    .expect("")
+                    .is_some());
+            }
+            let report = table.seek_report();
+            assert_eq!(report.index.seeks, 200);
+            assert_eq!(report.data.seeks, 200);
+            // the index block has several restart points to search
+            assert!(report.index.avg_search_steps() >= 1.0);
+            // the scan starts from the last restart point with a key less than the target
+            assert!(report.data.scanned_entries <= 200 * *restart_interval as u64);
+            avg_scanned_entries.push(report.data.avg_scanned_entries());
+            assert_eq!(stats.get_ticker_count(Ticker::IndexBlockSeek), 200);
+            assert_eq!(
+                stats.get_ticker_count(Ticker::DataBlockSearchSteps),
+                report.data.search_steps
+            );
+            assert_eq!(
+                stats.get_ticker_count(Ticker::DataBlockScanEntries),
+                report.data.scanned_entries
             );
         }
+        assert!(avg_scanned_entries[0] < avg_scanned_entries[1]);
     }
 }
@@ -0,0 +1,691 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::util::clock::Clock;
+use crate::util::histogram::Histogram;
+use std::cell::Cell;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Counters recorded by `Statistics`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ticker {
+    /// Number of data blocks read from table files
+    BlockRead = 0,
+    /// Total bytes of data blocks read from table files
+    BlockReadBytes,
+    /// Number of data block lookups served by the block cache
+    BlockCacheHit,
+    /// Number of data block lookups missed in the block cache
+    BlockCacheMiss,
+    /// Total bytes read from the input files of major compactions
+    CompactionBytesRead,
+    /// Total bytes written into the output files of major compactions
+    CompactionBytesWritten,
+    /// Total bytes written into level0 files by memtable flushes
+    FlushBytesWritten,
+    /// Number of times a write is delayed or stopped
+    WriteStall,
+    /// Total micros writes spent waiting in `make_room_for_write`
+    WriteStallMicros,
+    /// Number of files marked for compaction after running out of allowed seeks
+    SeekCompactionTriggered,
+    /// Number of blocks stored raw since they don't compress well enough
+    BlockCompressionSkipped,
+    /// Number of seeks in index blocks
+    IndexBlockSeek,
+    /// Total steps of the binary searches over the restart points of index blocks
+    IndexBlockSearchSteps,
+    /// Total entries skipped by the linear scans after the restart point in index blocks
+    IndexBlockScanEntries,
+    /// Number of seeks in data blocks
+    DataBlockSeek,
+    /// Total steps of the binary searches over the restart points of data blocks
+    DataBlockSearchSteps,
+    /// Total entries skipped by the linear scans after the restart point in data blocks
+    DataBlockScanEntries,
+    /// Number of fsyncs of the WAL
+    WalSync,
+    /// Number of table probes skipped by `multi_get` since the filters rule out
+    /// every key of the batch in the table
+    MultiGetTableSkipped,
+    /// Number of the level0 compactions scheduled since the db is idle
+    IdleCompactionTriggered,
+    /// Number of the compactions of the bottommost files with too many deletions
+    DeletionCompactionTriggered,
+    /// Number of the seeks in tables and levels stopped by the prefix filters
+    PrefixSeekSkipped,
+    /// Number of the blocks erased from the block cache as their tables are deleted
+    BlockCacheEraseObsolete,
+    /// Number of the entries dropped by compactions since they are shadowed by the
+    /// newer versions of their user keys
+    CompactionVersionsDropped,
+    /// Number of the compactions of the files older than
+    /// `Options::periodic_compaction_seconds`
+    PeriodicCompactionTriggered,
+}
+
+impl Ticker {
+    /// All the tickers in the order of their discriminants
+    pub const ALL: [Ticker; 25] = [
+        Ticker::BlockRead,
+        Ticker::BlockReadBytes,
+        Ticker::BlockCacheHit,
+        Ticker::BlockCacheMiss,
+        Ticker::CompactionBytesRead,
+        Ticker::CompactionBytesWritten,
+        Ticker::FlushBytesWritten,
+        Ticker::WriteStall,
+        Ticker::WriteStallMicros,
+        Ticker::SeekCompactionTriggered,
+        Ticker::BlockCompressionSkipped,
+        Ticker::IndexBlockSeek,
+        Ticker::IndexBlockSearchSteps,
+        Ticker::IndexBlockScanEntries,
+        Ticker::DataBlockSeek,
+        Ticker::DataBlockSearchSteps,
+        Ticker::DataBlockScanEntries,
+        Ticker::WalSync,
+        Ticker::MultiGetTableSkipped,
+        Ticker::IdleCompactionTriggered,
+        Ticker::DeletionCompactionTriggered,
+        Ticker::PrefixSeekSkipped,
+        Ticker::BlockCacheEraseObsolete,
+        Ticker::CompactionVersionsDropped,
+        Ticker::PeriodicCompactionTriggered,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Ticker::BlockRead => "wickdb.block.read",
+            Ticker::BlockReadBytes => "wickdb.block.read.bytes",
+            Ticker::BlockCacheHit => "wickdb.block.cache.hit",
+            Ticker::BlockCacheMiss => "wickdb.block.cache.miss",
+            Ticker::CompactionBytesRead => "wickdb.compaction.bytes.read",
+            Ticker::CompactionBytesWritten => "wickdb.compaction.bytes.written",
+            Ticker::FlushBytesWritten => "wickdb.flush.bytes.written",
+            Ticker::WriteStall => "wickdb.write.stall",
+            Ticker::WriteStallMicros => "wickdb.write.stall.micros",
+            Ticker::SeekCompactionTriggered => "wickdb.seek.compaction.triggered",
+            Ticker::BlockCompressionSkipped => "wickdb.block.compression.skipped",
+            Ticker::IndexBlockSeek => "wickdb.index.block.seek",
+            Ticker::IndexBlockSearchSteps => "wickdb.index.block.search.steps",
+            Ticker::IndexBlockScanEntries => "wickdb.index.block.scan.entries",
+            Ticker::DataBlockSeek => "wickdb.data.block.seek",
+            Ticker::DataBlockSearchSteps => "wickdb.data.block.search.steps",
+            Ticker::DataBlockScanEntries => "wickdb.data.block.scan.entries",
+            Ticker::WalSync => "wickdb.wal.sync",
+            Ticker::MultiGetTableSkipped => "wickdb.multiget.table.skipped",
+            Ticker::IdleCompactionTriggered => "wickdb.idle.compaction.triggered",
+            Ticker::DeletionCompactionTriggered => "wickdb.deletion.compaction.triggered",
+            Ticker::PrefixSeekSkipped => "wickdb.prefix.seek.skipped",
+            Ticker::BlockCacheEraseObsolete => "wickdb.block.cache.erase.obsolete",
+            Ticker::CompactionVersionsDropped => "wickdb.compaction.versions.dropped",
+            Ticker::PeriodicCompactionTriggered => "wickdb.periodic.compaction.triggered",
+        }
+    }
+}
+
+/// Counters recorded by `Statistics` for every level. They only grow (until
+/// `Statistics::reset`) so that the write amplification of each level could be
+/// graphed over a long period by sampling them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LevelTicker {
+    /// Total bytes written into the level by memtable flushes
+    FlushBytesWritten = 0,
+    /// Total bytes of the input files in the level read by major compactions
+    CompactionBytesRead,
+    /// Total bytes of the output files written into the level by major compactions
+    CompactionBytesWritten,
+    /// Total bytes of the data blocks read from the table files in the level by
+    /// `DB::get`, `DB::multi_get` and the iterators
+    UserBytesRead,
+}
+
+impl LevelTicker {
+    /// All the level tickers in the order of their discriminants
+    pub const ALL: [LevelTicker; 4] = [
+        LevelTicker::FlushBytesWritten,
+        LevelTicker::CompactionBytesRead,
+        LevelTicker::CompactionBytesWritten,
+        LevelTicker::UserBytesRead,
+    ];
+
+    /// Returns the name of the ticker in `level`
+    pub fn name(self, level: usize) -> String {
+        let name = match self {
+            LevelTicker::FlushBytesWritten => "flush.bytes.written",
+            LevelTicker::CompactionBytesRead => "compaction.bytes.read",
+            LevelTicker::CompactionBytesWritten => "compaction.bytes.written",
+            LevelTicker::UserBytesRead => "user.bytes.read",
+        };
+        format!("wickdb.level{}.{}", level, name)
+    }
+}
+
+/// Distributions recorded by `Statistics`. All the values are in micros except
+/// `CompactionVersionsPerKey`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistogramType {
+    /// Latency of `DB::get`
+    GetMicros = 0,
+    /// Latency of writing a group of batches into the WAL and memtable
+    WriteMicros,
+    /// Latency of reading a data block from a table file
+    BlockReadMicros,
+    /// Duration of a major compaction
+    CompactionMicros,
+    /// Duration of flushing the immutable memtable into a level0 file
+    FlushMicros,
+    /// Duration of a single write stall
+    WriteStallMicros,
+    /// Number of the versions of each user key in the inputs of a major compaction
+    CompactionVersionsPerKey,
+}
+
+impl HistogramType {
+    /// All the histogram types in the order of their discriminants
+    pub const ALL: [HistogramType; 7] = [
+        HistogramType::GetMicros,
+        HistogramType::WriteMicros,
+        HistogramType::BlockReadMicros,
+        HistogramType::CompactionMicros,
+        HistogramType::FlushMicros,
+        HistogramType::WriteStallMicros,
+        HistogramType::CompactionVersionsPerKey,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HistogramType::GetMicros => "wickdb.get.micros",
+            HistogramType::WriteMicros => "wickdb.write.micros",
+            HistogramType::BlockReadMicros => "wickdb.block.read.micros",
+            HistogramType::CompactionMicros => "wickdb.compaction.micros",
+            HistogramType::FlushMicros => "wickdb.flush.micros",
+            HistogramType::WriteStallMicros => "wickdb.write.stall.micros",
+            HistogramType::CompactionVersionsPerKey => "wickdb.compaction.versions.per.key",
+        }
+    }
+}
+
+/// `Statistics` collects the counters and latency histograms of a db.
+/// It's shared by all the threads of the db through `Options::statistics`
+/// and could be read at any time by the user.
+pub struct Statistics {
+    tickers: Vec<AtomicU64>,
+    histograms: Vec<Mutex<Histogram>>,
+    // The `LevelTicker`s of every level, grown by the first record in a new level
+    level_tickers: RwLock<Vec<Vec<AtomicU64>>>,
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Statistics {
+    pub fn new() -> Self {
+        Self {
+            tickers: Ticker::ALL.iter().map(|_| AtomicU64::new(0)).collect(),
+            histograms: HistogramType::ALL
+                .iter()
+                .map(|_| Mutex::new(Histogram::default()))
+                .collect(),
+            level_tickers: RwLock::new(vec![]),
+        }
+    }
+
+    /// Adds `count` to the given ticker
+    #[inline]
+    pub fn record_tick(&self, ticker: Ticker, count: u64) {
+        self.tickers[ticker as usize].fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Returns the current value of the given ticker
+    #[inline]
+    pub fn get_ticker_count(&self, ticker: Ticker) -> u64 {
+        self.tickers[ticker as usize].load(Ordering::Relaxed)
+    }
+
+    /// Adds `count` to the given ticker of `level`
+    pub fn record_level_tick(&self, level: usize, ticker: LevelTicker, count: u64) {
+        if let Some(tickers) = self.level_tickers.read().unwrap().get(level) {
+            tickers[ticker as usize].fetch_add(count, Ordering::Relaxed);
+            return;
+        }
+        let mut levels = self.level_tickers.write().unwrap();
+        while levels.len() <= level {
+            levels.push(LevelTicker::ALL.iter().map(|_| AtomicU64::new(0)).collect());
+        }
+        levels[level][ticker as usize].fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Returns the current value of the given ticker of `level`
+    pub fn get_level_ticker_count(&self, level: usize, ticker: LevelTicker) -> u64 {
+        self.level_tickers
+            .read()
+            .unwrap()
+            .get(level)
+            .map_or(0, |tickers| {
+                tickers[ticker as usize].load(Ordering::Relaxed)
+            })
+    }
+
+    /// Records a value into the given histogram
+    pub fn measure_time(&self, histogram: HistogramType, micros: u64) {
+        self.histograms[histogram as usize]
+            .lock()
+            .unwrap()
+            .add(micros as f64);
+    }
+
+    /// Merges all the values recorded by `data` into the given histogram
+    pub fn merge_histogram(&self, histogram: HistogramType, data: &Histogram) {
+        self.histograms[histogram as usize]
+            .lock()
+            .unwrap()
+            .merge(data);
+    }
+
+    /// Returns a copy of the given histogram
+    pub fn histogram_data(&self, histogram: HistogramType) -> Histogram {
+        self.histograms[histogram as usize].lock().unwrap().clone()
+    }
+
+    /// Returns a copy of all the tickers and histograms. The snapshots taken at
+    /// the beginning and the end of a phase tell what happens during the phase by
+    /// `StatisticsSnapshot::delta` without resetting the shared `Statistics`.
+    pub fn snapshot(&self) -> StatisticsSnapshot {
+        StatisticsSnapshot {
+            tickers: Ticker::ALL
+                .iter()
+                .map(|t| self.get_ticker_count(*t))
+                .collect(),
+            histograms: HistogramType::ALL
+                .iter()
+                .map(|h| self.histogram_data(*h))
+                .collect(),
+            level_tickers: self
+                .level_tickers
+                .read()
+                .unwrap()
+                .iter()
+                .map(|tickers| tickers.iter().map(|t| t.load(Ordering::Relaxed)).collect())
+                .collect(),
+        }
+    }
+
+    /// Resets all the tickers and histograms
+    pub fn reset(&self) {
+        for t in self.tickers.iter() {
+            t.store(0, Ordering::Relaxed);
+        }
+        for h in self.histograms.iter() {
+            h.lock().unwrap().clear();
+        }
+        for tickers in self.level_tickers.read().unwrap().iter() {
+            for t in tickers.iter() {
+                t.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Display for Statistics {
+    fn fmt(&self, f: &mut Formatter) -> ::std::fmt::Result {
+        self.snapshot().fmt(f)
+    }
+}
+
+/// A point-in-time copy of the tickers and histograms of a `Statistics`
+#[derive(Clone, Debug)]
+pub struct StatisticsSnapshot {
+    tickers: Vec<u64>,
+    histograms: Vec<Histogram>,
+    level_tickers: Vec<Vec<u64>>,
+}
+
+impl StatisticsSnapshot {
+    /// Returns the value of the given ticker
+    #[inline]
+    pub fn get_ticker_count(&self, ticker: Ticker) -> u64 {
+        self.tickers[ticker as usize]
+    }
+
+    /// Returns the given histogram
+    #[inline]
+    pub fn histogram_data(&self, histogram: HistogramType) -> &Histogram {
+        &self.histograms[histogram as usize]
+    }
+
+    /// Returns the value of the given ticker of `level`
+    #[inline]
+    pub fn get_level_ticker_count(&self, level: usize, ticker: LevelTicker) -> u64 {
+        self.level_tickers
+            .get(level)
+            .map_or(0, |tickers| tickers[ticker as usize])
+    }
+
+    /// Returns the number of the levels having any level ticker recorded
+    #[inline]
+    pub fn num_levels(&self) -> usize {
+        self.level_tickers.len()
+    }
+
+    /// Adds the tickers and the histograms of `other` into this snapshot, which
+    /// sums up the statistics of several dbs
+    pub fn merge(&mut self, other: &StatisticsSnapshot) {
+        for (t, o) in self.tickers.iter_mut().zip(other.tickers.iter()) {
+            *t += *o;
+        }
+        for (h, o) in self.histograms.iter_mut().zip(other.histograms.iter()) {
+            h.merge(o);
+        }
+        for (level, o) in other.level_tickers.iter().enumerate() {
+            if level == self.level_tickers.len() {
+                self.level_tickers.push(vec![0; LevelTicker::ALL.len()]);
+            }
+            for (t, o) in self.level_tickers[level].iter_mut().zip(o.iter()) {
+                *t += *o;
+            }
+        }
+    }
+
+    /// Returns what's recorded between the `earlier` snapshot and this one
+    pub fn delta(&self, earlier: &StatisticsSnapshot) -> StatisticsSnapshot {
+        StatisticsSnapshot {
+            tickers: self
+                .tickers
+                .iter()
+                .zip(earlier.tickers.iter())
+                .map(|(t, e)| t.saturating_sub(*e))
+                .collect(),
+            histograms: self
+                .histograms
+                .iter()
+                .zip(earlier.histograms.iter())
+                .map(|(h, e)| {
+                    let mut h = h.clone();
+                    h.subtract(e);
+                    h
+                })
+                .collect(),
+            level_tickers: (0..self.num_levels())
+                .map(|level| {
+                    LevelTicker::ALL
+                        .iter()
+                        .map(|t| {
+                            self.get_level_ticker_count(level, *t)
+                                .saturating_sub(earlier.get_level_ticker_count(level, *t))
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Display for StatisticsSnapshot {
+    fn fmt(&self, f: &mut Formatter) -> ::std::fmt::Result {
+        for t in Ticker::ALL.iter() {
+            writeln!(f, "{} COUNT : {}", t.name(), self.get_ticker_count(*t))?;
+        }
+        for level in 0..self.num_levels() {
+            for t in LevelTicker::ALL.iter() {
+                writeln!(
+                    f,
+                    "{} COUNT : {}",
+                    t.name(level),
+                    self.get_level_ticker_count(level, *t)
+                )?;
+            }
+        }
+        for h in HistogramType::ALL.iter() {
+            let data = self.histogram_data(*h);
+            writeln!(
+                f,
+                "{} P50 : {:.2} P95 : {:.2} P99 : {:.2} MAX : {:.2} COUNT : {} SUM : {:.0}",
+                h.name(),
+                data.median(),
+                data.percentile(95.0),
+                data.percentile(99.0),
+                data.max(),
+                data.count(),
+                data.sum(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Records the elapsed micros into a histogram of `Statistics` when dropped
+pub(crate) struct StopWatch<'a> {
+    stats: Option<&'a Statistics>,
+    histogram: HistogramType,
+    // Also adds the elapsed micros to this ticker if set
+    elapsed_ticker: Option<Ticker>,
+    clock: &'a dyn Clock,
+    start: Option<u64>,
+}
+
+impl<'a> StopWatch<'a> {
+    pub(crate) fn new(
+        clock: &'a dyn Clock,
+        stats: Option<&'a Statistics>,
+        histogram: HistogramType,
+    ) -> Self {
+        // Avoid the cost of getting time if no statistics set
+        let start = stats.map(|_| clock.monotonic_micros());
+        Self {
+            stats,
+            histogram,
+            elapsed_ticker: None,
+            clock,
+            start,
+        }
+    }
+
+    pub(crate) fn with_elapsed_ticker(mut self, ticker: Ticker) -> Self {
+        self.elapsed_ticker = Some(ticker);
+        self
+    }
+
+    /// Returns the micros elapsed since the watch is started
+    pub(crate) fn elapsed_micros(&self) -> u64 {
+        self.start.map_or(0, |start| {
+            self.clock.monotonic_micros().saturating_sub(start)
+        })
+    }
+}
+
+impl<'a> Drop for StopWatch<'a> {
+    fn drop(&mut self) {
+        if let Some(stats) = self.stats {
+            let micros = self.elapsed_micros();
+            stats.measure_time(self.histogram, micros);
+            if let Some(ticker) = self.elapsed_ticker {
+                stats.record_tick(ticker, micros);
+            }
+        }
+    }
+}
+
+/// Records a tick if the `Option<Arc<Statistics>>` is set
+pub(crate) fn record_tick(stats: &Option<Arc<Statistics>>, ticker: Ticker, count: u64) {
+    if let Some(s) = stats {
+        s.record_tick(ticker, count);
+    }
+}
+
+thread_local! {
+    // The level of the table files read by the current user read, see `LevelReadScope`
+    static READ_LEVEL: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Attributes the data blocks read by the current thread to `level` as
+/// `LevelTicker::UserBytesRead` until dropped
+pub(crate) struct LevelReadScope {
+    prev: Option<usize>,
+}
+
+impl LevelReadScope {
+    pub(crate) fn new(level: usize) -> Self {
+        Self {
+            prev: READ_LEVEL.with(|l| l.replace(Some(level))),
+        }
+    }
+}
+
+impl Drop for LevelReadScope {
+    fn drop(&mut self) {
+        READ_LEVEL.with(|l| l.set(self.prev));
+    }
+}
+
+/// Records the bytes of a data block read by a user read into the level of the
+/// current `LevelReadScope` if any
+pub(crate) fn record_user_read(stats: &Option<Arc<Statistics>>, bytes: u64) {
+    if let Some(s) = stats {
+        if let Some(level) = READ_LEVEL.with(|l| l.get()) {
+            s.record_level_tick(level, LevelTicker::UserBytesRead, bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::clock::MockClock;
+
+    #[test]
+    fn test_record_ticks_and_histograms() {
+        let stats = Statistics::new();
+        stats.record_tick(Ticker::BlockCacheHit, 1);
+        stats.record_tick(Ticker::BlockCacheHit, 2);
+        stats.record_tick(Ticker::BlockReadBytes, 4096);
+        assert_eq!(stats.get_ticker_count(Ticker::BlockCacheHit), 3);
+        assert_eq!(stats.get_ticker_count(Ticker::BlockReadBytes), 4096);
+        assert_eq!(stats.get_ticker_count(Ticker::BlockCacheMiss), 0);
+
+        stats.measure_time(HistogramType::GetMicros, 10);
+        stats.measure_time(HistogramType::GetMicros, 30);
+        {
+            let clock = MockClock::new(0);
+            let _w = StopWatch::new(&clock, Some(&stats), HistogramType::WriteMicros);
+            clock.advance(40);
+        }
+        assert_eq!(stats.histogram_data(HistogramType::GetMicros).count(), 2);
+        assert_eq!(
+            stats.histogram_data(HistogramType::GetMicros).average(),
+            20.0
+        );
+        assert_eq!(stats.histogram_data(HistogramType::WriteMicros).count(), 1);
+        assert_eq!(
+            stats.histogram_data(HistogramType::WriteMicros).average(),
+            40.0
+        );
+        assert!(stats
+            .to_string()
+            .contains("wickdb.block.cache.hit COUNT : 3"));
+
+        stats.reset();
+        assert_eq!(stats.get_ticker_count(Ticker::BlockCacheHit), 0);
+        assert_eq!(stats.histogram_data(HistogramType::GetMicros).count(), 0);
+    }
+
+    #[test]
+    fn test_snapshot_delta() {
+        let stats = Statistics::new();
+        stats.record_tick(Ticker::BlockRead, 3);
+        stats.measure_time(HistogramType::GetMicros, 10);
+        let before = stats.snapshot();
+        stats.record_tick(Ticker::BlockRead, 2);
+        stats.record_tick(Ticker::WalSync, 1);
+        stats.measure_time(HistogramType::GetMicros, 30);
+        stats.measure_time(HistogramType::GetMicros, 50);
+        let after = stats.snapshot();
+        // the snapshots are not affected by the later records
+        stats.record_tick(Ticker::BlockRead, 100);
+        assert_eq!(before.get_ticker_count(Ticker::BlockRead), 3);
+        assert_eq!(after.get_ticker_count(Ticker::BlockRead), 5);
+
+        let delta = after.delta(&before);
+        assert_eq!(delta.get_ticker_count(Ticker::BlockRead), 2);
+        assert_eq!(delta.get_ticker_count(Ticker::WalSync), 1);
+        assert_eq!(delta.get_ticker_count(Ticker::BlockCacheHit), 0);
+        let get = delta.histogram_data(HistogramType::GetMicros);
+        assert_eq!(get.count(), 2);
+        assert_eq!(get.average(), 40.0);
+        assert!(delta.to_string().contains("wickdb.block.read COUNT : 2"));
+        assert_eq!(stats.get_ticker_count(Ticker::BlockRead), 105);
+    }
+
+    #[test]
+    fn test_level_tickers() {
+        let stats = Statistics::new();
+        stats.record_level_tick(2, LevelTicker::CompactionBytesWritten, 100);
+        let before = stats.snapshot();
+        assert_eq!(before.num_levels(), 3);
+        stats.record_level_tick(2, LevelTicker::CompactionBytesWritten, 50);
+        stats.record_level_tick(0, LevelTicker::FlushBytesWritten, 10);
+        let reads = Arc::new(Statistics::new());
+        {
+            let _scope = LevelReadScope::new(1);
+            record_user_read(&Some(reads.clone()), 30);
+        }
+        // no read is attributed out of a scope
+        record_user_read(&Some(reads.clone()), 20);
+        let reads = reads.snapshot();
+        assert_eq!(reads.num_levels(), 2);
+        assert_eq!(
+            reads.get_level_ticker_count(1, LevelTicker::UserBytesRead),
+            30
+        );
+
+        let after = stats.snapshot();
+        assert_eq!(
+            stats.get_level_ticker_count(2, LevelTicker::CompactionBytesWritten),
+            150
+        );
+        assert_eq!(
+            stats.get_level_ticker_count(5, LevelTicker::FlushBytesWritten),
+            0
+        );
+        let delta = after.delta(&before);
+        assert_eq!(
+            delta.get_level_ticker_count(2, LevelTicker::CompactionBytesWritten),
+            50
+        );
+        assert_eq!(
+            delta.get_level_ticker_count(0, LevelTicker::FlushBytesWritten),
+            10
+        );
+        assert!(delta
+            .to_string()
+            .contains("wickdb.level2.compaction.bytes.written COUNT : 50"));
+        let mut merged = before.clone();
+        merged.merge(&after);
+        assert_eq!(
+            merged.get_level_ticker_count(2, LevelTicker::CompactionBytesWritten),
+            250
+        );
+
+        stats.reset();
+        assert_eq!(
+            stats.get_level_ticker_count(2, LevelTicker::CompactionBytesWritten),
+            0
+        );
+    }
+}
@@ -0,0 +1,371 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::storage::{File, LockKind, ReadRequest, Storage};
+use crate::util::status::{Result, Status, WickErr};
+use rand::Rng;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// The faults injected into one kind of the file operations of a `ChaosStorage`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FaultSpec {
+    /// The latency added to every operation
+    pub latency: Duration,
+    /// The upper bound of a random latency added on top of `latency`
+    pub jitter: Duration,
+    /// The probability in `[0, 1]` of an operation failing with `Status::IOError`
+    /// before reaching the underlying file
+    pub error_rate: f64,
+}
+
+impl FaultSpec {
+    #[inline]
+    fn is_noop(&self) -> bool {
+        self.latency == Duration::default()
+            && self.jitter == Duration::default()
+            && self.error_rate <= 0.0
+    }
+}
+
+/// The faults injected by a `ChaosStorage`. The default injects nothing.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChaosOptions {
+    /// The faults of the reads, including the positional ones
+    pub reads: FaultSpec,
+    /// The faults of the writes and the flushes
+    pub writes: FaultSpec,
+    /// The faults of the fsyncs by `File::sync`
+    pub syncs: FaultSpec,
+}
+
+/// The counters of the faults injected by a `ChaosStorage`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChaosStats {
+    /// The number of the operations delayed
+    pub delayed_ops: u64,
+    /// The total time of the injected delays in microseconds
+    pub delay_micros: u64,
+    /// The number of the operations failed on purpose
+    pub injected_errors: u64,
+}
+
+struct ChaosState {
+    options: RwLock<ChaosOptions>,
+    delayed_ops: AtomicU64,
+    delay_micros: AtomicU64,
+    injected_errors: AtomicU64,
+}
+
+impl ChaosState {
+    // Sleeps and fails the operation as the spec picked from the options says
+    fn inject(&self, pick: impl Fn(&ChaosOptions) -> FaultSpec) -> Result<()> {
+        let spec = pick(&self.options.read().unwrap());
+        if spec.is_noop() {
+            return Ok(());
+        }
+        let mut rng = rand::thread_rng();
+        let mut delay = spec.latency;
+        let jitter = spec.jitter.as_micros() as u64;
+        if jitter > 0 {
+            delay += Duration::from_micros(rng.gen_range(0, jitter + 1));
+        }
+        if delay > Duration::default() {
+            thread::sleep(delay);
+            self.delayed_ops.fetch_add(1, Ordering::Relaxed);
+            self.delay_micros
+                .fetch_add(delay.as_micros() as u64, Ordering::Relaxed);
+        }
+        if spec.error_rate > 0.0 && rng.gen::<f64>() < spec.error_rate {
+            self.injected_errors.fetch_add(1, Ordering::Relaxed);
+            return Err(WickErr::new(
+                Status::IOError,
+                Some("fault injected by chaos storage"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A `Storage` wrapper injecting latency, jitter and errors into the reads, the
+/// writes and the fsyncs of the files, so that the operators could rehearse the
+/// degraded disks on a staging db serving the real traffic.
+///
+/// The faults are given by `ChaosOptions` and could be changed at any time by
+/// `set_options`, which takes effect on the opened files immediately. The
+/// operations on the directories and the file names are never affected. Note an
+/// injected error of a background write or sync stops the db from writing just
+/// like a real one does.
+pub struct ChaosStorage {
+    inner: Arc<dyn Storage>,
+    state: Arc<ChaosState>,
+}
+
+impl ChaosStorage {
+    pub fn new(inner: Arc<dyn Storage>, options: ChaosOptions) -> Self {
+        Self {
+            inner,
+            state: Arc::new(ChaosState {
+                options: RwLock::new(options),
+                delayed_ops: AtomicU64::new(0),
+                delay_micros: AtomicU64::new(0),
+                injected_errors: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Returns the current faults
+    pub fn options(&self) -> ChaosOptions {
+        *self.state.options.read().unwrap()
+    }
+
+    /// Replaces the faults of all the files. `ChaosOptions::default()` stops the
+    /// injection.
+    pub fn set_options(&self, options: ChaosOptions) {
+        *self.state.options.write().unwrap() = options;
+    }
+
+    /// Returns the counters of the faults injected so far
+    pub fn stats(&self) -> ChaosStats {
+        ChaosStats {
+            delayed_ops: self.state.delayed_ops.load(Ordering::Relaxed),
+            delay_micros: self.state.delay_micros.load(Ordering::Relaxed),
+            injected_errors: self.state.injected_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    fn wrap(&self, file: Box<dyn File>) -> Box<dyn File> {
+        Box::new(ChaosFile {
+            inner: file,
+            state: self.state.clone(),
+        })
+    }
+}
+
+impl Storage for ChaosStorage {
+    fn create(&self, name: &str) -> Result<Box<dyn File>> {
+        Ok(self.wrap(self.inner.create(name)?))
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn File>> {
+        Ok(self.wrap(self.inner.open(name)?))
+    }
+
+    fn open_mmap(&self, name: &str) -> Result<Box<dyn File>> {
+        Ok(self.wrap(self.inner.open_mmap(name)?))
+    }
+
+    fn create_direct(&self, name: &str) -> Result<Box<dyn File>> {
+        Ok(self.wrap(self.inner.create_direct(name)?))
+    }
+
+    fn open_direct(&self, name: &str) -> Result<Box<dyn File>> {
+        Ok(self.wrap(self.inner.open_direct(name)?))
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.inner.remove(name)
+    }
+
+    fn remove_dir(&self, dir: &str, recursively: bool) -> Result<()> {
+        self.inner.remove_dir(dir, recursively)
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.inner.exists(name)
+    }
+
+    fn rename(&self, old: &str, new: &str) -> Result<()> {
+        self.inner.rename(old, new)
+    }
+
+    fn mkdir_all(&self, dir: &str) -> Result<()> {
+        self.inner.mkdir_all(dir)
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<PathBuf>> {
+        self.inner.list(dir)
+    }
+}
+
+struct ChaosFile {
+    inner: Box<dyn File>,
+    state: Arc<ChaosState>,
+}
+
+impl File for ChaosFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.state.inject(|o| o.writes)?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.state.inject(|o| o.writes)?;
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.state.inject(|o| o.syncs)?;
+        self.inner.sync()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.inner.close()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.inner.seek(pos)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.state.inject(|o| o.reads)?;
+        self.inner.read(buf)
+    }
+
+    fn read_all(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        self.state.inject(|o| o.reads)?;
+        self.inner.read_all(buf)
+    }
+
+    fn len(&self) -> Result<u64> {
+        self.inner.len()
+    }
+
+    fn lock(&self) -> Result<()> {
+        self.inner.lock()
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.inner.unlock()
+    }
+
+    fn lock_region(&self, offset: u64, len: u64, kind: LockKind) -> Result<()> {
+        self.inner.lock_region(offset, len, kind)
+    }
+
+    fn unlock_region(&self, offset: u64, len: u64) -> Result<()> {
+        self.inner.unlock_region(offset, len)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        self.state.inject(|o| o.reads)?;
+        self.inner.read_at(buf, offset)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        self.state.inject(|o| o.reads)?;
+        self.inner.read_exact_at(buf, offset)
+    }
+
+    fn read_exact_at_vectored(&self, requests: &mut [ReadRequest]) -> Result<()> {
+        self.state.inject(|o| o.reads)?;
+        self.inner.read_exact_at_vectored(requests)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{WickDB, DB};
+    use crate::options::{Options, ReadOptions, WriteOptions};
+    use crate::storage::mem::MemStorage;
+    use crate::util::slice::Slice;
+    use std::time::Instant;
+
+    #[test]
+    fn test_chaos_file() {
+        let storage = ChaosStorage::new(Arc::new(MemStorage::default()), ChaosOptions::default());
+        let mut f = storage.create("test").unwrap();
+        f.write(b"hello").unwrap();
+        f.sync().unwrap();
+        assert_eq!(storage.stats(), ChaosStats::default());
+
+        // The new options take effect on the opened files
+        storage.set_options(ChaosOptions {
+            writes: FaultSpec {
+                error_rate: 1.0,
+                ..FaultSpec::default()
+            },
+            reads: FaultSpec {
+                latency: Duration::from_millis(5),
+                jitter: Duration::from_millis(5),
+                ..FaultSpec::default()
+            },
+            ..ChaosOptions::default()
+        });
+        let err = f.write(b"world").unwrap_err();
+        assert_eq!(err.status(), Status::IOError);
+        f.sync().unwrap();
+        let start = Instant::now();
+        let mut buf = [0; 5];
+        f.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert!(start.elapsed() >= Duration::from_millis(5));
+        let stats = storage.stats();
+        assert_eq!(stats.injected_errors, 1);
+        assert_eq!(stats.delayed_ops, 1);
+        assert!(stats.delay_micros >= 5_000 && stats.delay_micros <= 10_000);
+
+        storage.set_options(ChaosOptions {
+            syncs: FaultSpec {
+                error_rate: 1.0,
+                ..FaultSpec::default()
+            },
+            ..ChaosOptions::default()
+        });
+        assert!(f.sync().is_err());
+        f.write(b"world").unwrap();
+        assert_eq!(f.len().unwrap(), 10);
+    }
+
+    #[test]
+    fn test_db_on_chaos_storage() {
+        let storage = Arc::new(ChaosStorage::new(
+            Arc::new(MemStorage::default()),
+            ChaosOptions::default(),
+        ));
+        let mut options = Options::default();
+        options.env = storage.clone();
+        let db = WickDB::open_db(options, "chaos".to_owned()).unwrap();
+        db.put(
+            WriteOptions::default(),
+            Slice::from("k1"),
+            Slice::from("v1"),
+        )
+        .unwrap();
+        // The WAL refuses the writes
+        storage.set_options(ChaosOptions {
+            writes: FaultSpec {
+                error_rate: 1.0,
+                ..FaultSpec::default()
+            },
+            ..ChaosOptions::default()
+        });
+        assert!(db
+            .put(
+                WriteOptions::default(),
+                Slice::from("k2"),
+                Slice::from("v2"),
+            )
+            .is_err());
+        assert_eq!(
+            db.get(ReadOptions::default(), Slice::from("k1")).unwrap(),
+            Some(b"v1".to_vec())
+        );
+        assert!(storage.stats().injected_errors > 0);
+    }
+}
@@ -0,0 +1,364 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::storage::{File, LockKind};
+use crate::util::status::{Result, Status, WickErr};
+use fs2::FileExt;
+use std::fs::{File as SysFile, OpenOptions};
+use std::io::SeekFrom;
+use std::os::unix::fs::{FileExt as UnixFileExt, OpenOptionsExt};
+
+// The alignment of the offsets, lengths and memory addresses required by O_DIRECT
+const ALIGNMENT: usize = 4096;
+// The size of the buffer collecting the writes
+const WRITE_BUFFER_SIZE: usize = 1 << 20;
+
+#[inline]
+fn align_down(n: usize) -> usize {
+    n & !(ALIGNMENT - 1)
+}
+
+#[inline]
+fn align_up(n: usize) -> usize {
+    align_down(n + ALIGNMENT - 1)
+}
+
+// A buffer whose content starts at an aligned memory address
+struct AlignedBuffer {
+    data: Vec<u8>,
+    // the offset of the aligned start in `data`
+    start: usize,
+    capacity: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    fn new(capacity: usize) -> Self {
+        let data = vec![0; capacity + ALIGNMENT];
+        let start = data.as_ptr().align_offset(ALIGNMENT);
+        Self {
+            data,
+            start,
+            capacity,
+            len: 0,
+        }
+    }
+
+    #[inline]
+    fn as_slice(&self) -> &[u8] {
+        &self.data[self.start..self.start + self.len]
+    }
+
+    // Returns the first `n` bytes of the whole buffer
+    #[inline]
+    fn head(&self, n: usize) -> &[u8] {
+        &self.data[self.start..self.start + n]
+    }
+
+    #[inline]
+    fn head_mut(&mut self, n: usize) -> &mut [u8] {
+        &mut self.data[self.start..self.start + n]
+    }
+
+    // Appends as many bytes of `buf` as possible and returns the count
+    fn append(&mut self, buf: &[u8]) -> usize {
+        let n = buf.len().min(self.capacity - self.len);
+        let at = self.start + self.len;
+        self.data[at..at + n].copy_from_slice(&buf[..n]);
+        self.len += n;
+        n
+    }
+}
+
+/// A `File` bypassing the OS page cache by `O_DIRECT`, used for the table files
+/// written by flushes and compactions and read by compactions so that they never
+/// evict the hot pages of the other files.
+///
+/// The writes are collected in an aligned buffer and written in aligned chunks.
+/// The unaligned tail is written with zero padding and cut off by truncating the
+/// file, and is kept in the buffer to be written again with the following bytes.
+/// The reads are extended to the aligned boundaries.
+pub struct DirectFile {
+    file: SysFile,
+    // The bytes before `offset` are written and `offset` is always aligned
+    offset: u64,
+    // The bytes after `offset`
+    buf: AlignedBuffer,
+    // whether the buffer has the bytes never written
+    dirty: bool,
+    // the cursor of `read` and `seek`
+    pos: u64,
+}
+
+impl DirectFile {
+    /// Creates a file for direct IO or truncates it if it exists
+    pub fn create(name: &str) -> Result<Self> {
+        let file = w_io_result!(OpenOptions::new()
+            .write(true)
+            .read(true)
+            .create(true)
+            .truncate(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(name))?;
+        Ok(Self::new(file, 0))
+    }
+
+    /// Opens an existing file for direct IO
+    pub fn open(name: &str) -> Result<Self> {
+        let file = w_io_result!(OpenOptions::new()
+            .write(true)
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(name))?;
+        let len = w_io_result!(file.metadata())?.len();
+        let mut f = Self::new(file, align_down(len as usize) as u64);
+        // Loads the unaligned tail so that the appended bytes follow it
+        let tail = (len - f.offset) as usize;
+        if tail > 0 {
+            let mut data = vec![];
+            f.read_aligned(f.offset, ALIGNMENT, |d| data.extend_from_slice(d))?;
+            if data.len() < tail {
+                return Err(WickErr::new(
+                    Status::Corruption,
+                    Some("failed to read the tail of the direct file"),
+                ));
+            }
+            f.buf.append(&data[..tail]);
+        }
+        Ok(f)
+    }
+
+    fn new(file: SysFile, offset: u64) -> Self {
+        Self {
+            file,
+            offset,
+            buf: AlignedBuffer::new(WRITE_BUFFER_SIZE),
+            dirty: false,
+            pos: 0,
+        }
+    }
+
+    // Writes all the buffered bytes. The written aligned part leaves the buffer.
+    fn write_buffer(&mut self) -> Result<()> {
+        let len = self.buf.len;
+        if !self.dirty || len == 0 {
+            return Ok(());
+        }
+        let padded = align_up(len);
+        for b in self.buf.head_mut(padded)[len..].iter_mut() {
+            *b = 0;
+        }
+        w_io_result!(self.file.write_all_at(self.buf.head(padded), self.offset))?;
+        if padded != len {
+            w_io_result!(self.file.set_len(self.offset + len as u64))?;
+        }
+        let aligned = align_down(len);
+        if aligned > 0 {
+            let start = self.buf.start;
+            self.buf
+                .data
+                .copy_within(start + aligned..start + len, start);
+            self.buf.len = len - aligned;
+            self.offset += aligned as u64;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Reads `[offset, offset + n)` of the file where both are aligned and passes
+    // the bytes read to `f`, which might be fewer than `n` at the EOF
+    fn read_aligned<F: FnOnce(&[u8])>(&self, offset: u64, n: usize, f: F) -> Result<()> {
+        let mut buf = AlignedBuffer::new(n);
+        let mut read = 0;
+        while read < n {
+            let r = w_io_result!(UnixFileExt::read_at(
+                &self.file,
+                &mut buf.head_mut(n)[read..],
+                offset + read as u64
+            ))?;
+            if r == 0 {
+                break;
+            }
+            read += r;
+        }
+        f(buf.head(read));
+        Ok(())
+    }
+}
+
+impl Drop for DirectFile {
+    #[allow(unused_must_use)]
+    fn drop(&mut self) {
+        self.write_buffer();
+    }
+}
+
+impl File for DirectFile {
+    fn write(&mut self, mut buf: &[u8]) -> Result<usize> {
+        let total = buf.len();
+        while !buf.is_empty() {
+            let n = self.buf.append(buf);
+            buf = &buf[n..];
+            self.dirty = true;
+            if self.buf.len == self.buf.capacity {
+                self.write_buffer()?;
+            }
+        }
+        Ok(total)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write_buffer()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.write_buffer()?;
+        w_io_result!(self.file.sync_all())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.write_buffer()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let len = self.len()? as i64;
+        let new_pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::End(p) => len + p,
+            SeekFrom::Current(p) => self.pos as i64 + p,
+        };
+        if new_pos < 0 {
+            return Err(WickErr::new(
+                Status::InvalidArgument,
+                Some("invalid seek to a negative position"),
+            ));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn read_all(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        let len = self.len()?;
+        if self.pos >= len {
+            return Ok(0);
+        }
+        let start = buf.len();
+        buf.resize(start + (len - self.pos) as usize, 0);
+        self.read_exact_at(&mut buf[start..], self.pos)?;
+        let n = (len - self.pos) as usize;
+        self.pos = len;
+        Ok(n)
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.offset + self.buf.len as u64)
+    }
+
+    fn lock(&self) -> Result<()> {
+        w_io_result!(self.file.try_lock_exclusive())
+    }
+
+    fn unlock(&self) -> Result<()> {
+        w_io_result!(FileExt::unlock(&self.file))
+    }
+
+    fn lock_region(&self, offset: u64, len: u64, kind: LockKind) -> Result<()> {
+        File::lock_region(&self.file, offset, len, kind)
+    }
+
+    fn unlock_region(&self, offset: u64, len: u64) -> Result<()> {
+        File::unlock_region(&self.file, offset, len)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let len = self.len()?;
+        if buf.is_empty() || offset >= len {
+            return Ok(0);
+        }
+        let n = buf.len().min((len - offset) as usize);
+        // The buffered bytes are served from the memory
+        if offset >= self.offset {
+            let start = (offset - self.offset) as usize;
+            buf[..n].copy_from_slice(&self.buf.as_slice()[start..start + n]);
+            return Ok(n);
+        }
+        let n = n.min((self.offset - offset) as usize);
+        let aligned_offset = align_down(offset as usize);
+        let skip = offset as usize - aligned_offset;
+        let mut read = 0;
+        self.read_aligned(aligned_offset as u64, align_up(skip + n), |data| {
+            if data.len() > skip {
+                read = n.min(data.len() - skip);
+                buf[..read].copy_from_slice(&data[skip..skip + read]);
+            }
+        })?;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::remove_file;
+
+    #[test]
+    fn test_direct_file_read_write() {
+        let name = "test_direct_file_read_write";
+        let mut f = match DirectFile::create(name) {
+            Ok(f) => f,
+            // O_DIRECT is not supported by the file system (e.g. tmpfs)
+            Err(_) => return,
+        };
+        let data: Vec<u8> = (0..3 * WRITE_BUFFER_SIZE + 1234)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        for chunk in data.chunks(10000) {
+            assert_eq!(f.write(chunk).unwrap(), chunk.len());
+        }
+        assert_eq!(f.len().unwrap(), data.len() as u64);
+        f.flush().unwrap();
+        assert_eq!(
+            SysFile::open(name).unwrap().metadata().unwrap().len(),
+            data.len() as u64
+        );
+        // Reads the written and the buffered bytes at unaligned offsets
+        for (offset, n) in [
+            (0, 10),
+            (4095, 2),
+            (5000, 2 * WRITE_BUFFER_SIZE),
+            (data.len() - 100, 100),
+        ]
+        .iter()
+        {
+            let mut buf = vec![0; *n];
+            f.read_exact_at(buf.as_mut_slice(), *offset as u64).unwrap();
+            assert_eq!(buf.as_slice(), &data[*offset..*offset + *n]);
+        }
+        f.write(b"tail").unwrap();
+        f.sync().unwrap();
+        drop(f);
+
+        let mut f = DirectFile::open(name).unwrap();
+        let mut all = vec![];
+        assert_eq!(f.read_all(&mut all).unwrap(), data.len() + 4);
+        assert_eq!(&all[..data.len()], data.as_slice());
+        assert_eq!(&all[data.len()..], b"tail");
+        remove_file(name).unwrap();
+    }
+}
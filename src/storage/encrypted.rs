@@ -0,0 +1,364 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::db::filename::{parse_filename, TableFileNaming};
+use crate::storage::{File, LockKind, ReadRequest, Storage};
+use crate::util::status::{Result, Status, WickErr};
+use aes::cipher::{InnerIvInit, KeyInit, StreamCipher, StreamCipherSeek};
+use aes::Aes256;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+/// The length of the key of `EncryptedStorage`
+pub const KEY_LENGTH: usize = 32;
+
+const MAGIC: &[u8; 8] = b"wickenc1";
+const IV_LENGTH: usize = 16;
+/// The length of the plaintext header prepended to every encrypted file
+pub const HEADER_LENGTH: usize = MAGIC.len() + IV_LENGTH;
+
+/// A `Storage` wrapping another one which encrypts the contents of all the files,
+/// including the tables, the WALs and the MANIFEST, by AES-256 in CTR mode.
+///
+/// Every file starts with a plaintext header of `HEADER_LENGTH` bytes, which is
+/// hidden from the users of the `File`s, holding the initial counter of the file.
+/// The counter is a random salt followed by the file number parsed from the file
+/// name when the file is created, so that no two files share a key stream even if
+/// the numbers are reused by another db under the same key. The header travels
+/// with the file on renames.
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    cipher: Aes256,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn Storage>, key: &[u8; KEY_LENGTH]) -> Self {
+        Self {
+            inner,
+            cipher: Aes256::new(key.into()),
+        }
+    }
+
+    // Derives the initial counter of a new file
+    fn new_iv(name: &str) -> [u8; IV_LENGTH] {
+        let number = parse_filename(name)
+            .map(|(_, n)| n)
+            .or_else(|| TableFileNaming::default().parse_table_number(name))
+            .unwrap_or(0);
+        let mut iv = [0; IV_LENGTH];
+        iv[..8].copy_from_slice(&rand::random::<u64>().to_be_bytes());
+        // The low 32 bits count the blocks in the file
+        iv[8..12].copy_from_slice(&(number as u32).to_be_bytes());
+        iv
+    }
+}
+
+impl Storage for EncryptedStorage {
+    fn create(&self, name: &str) -> Result<Box<dyn File>> {
+        let mut file = self.inner.create(name)?;
+        let iv = Self::new_iv(name);
+        let mut header = Vec::with_capacity(HEADER_LENGTH);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&iv);
+        file.write(&header)?;
+        Ok(Box::new(EncryptedFile {
+            file,
+            cipher: self.cipher.clone(),
+            iv,
+            pos: 0,
+            size: 0,
+        }))
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn File>> {
+        let mut file = self.inner.open(name)?;
+        let mut header = [0; HEADER_LENGTH];
+        file.read_exact_at(&mut header, 0).map_err(|_| {
+            WickErr::new(
+                Status::Corruption,
+                Some("file is too short to be an encrypted file"),
+            )
+        })?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(WickErr::new(
+                Status::Corruption,
+                Some("bad magic number of an encrypted file"),
+            ));
+        }
+        let mut iv = [0; IV_LENGTH];
+        iv.copy_from_slice(&header[MAGIC.len()..]);
+        // The writes always append to the file
+        let size = file.seek(SeekFrom::End(0))? - HEADER_LENGTH as u64;
+        Ok(Box::new(EncryptedFile {
+            file,
+            cipher: self.cipher.clone(),
+            iv,
+            pos: 0,
+            size,
+        }))
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.inner.remove(name)
+    }
+
+    fn remove_dir(&self, dir: &str, recursively: bool) -> Result<()> {
+        self.inner.remove_dir(dir, recursively)
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.inner.exists(name)
+    }
+
+    fn rename(&self, old: &str, new: &str) -> Result<()> {
+        self.inner.rename(old, new)
+    }
+
+    fn mkdir_all(&self, dir: &str) -> Result<()> {
+        self.inner.mkdir_all(dir)
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<PathBuf>> {
+        self.inner.list(dir)
+    }
+}
+
+/// A `File` of `EncryptedStorage`. All the offsets are of the plaintext, which
+/// starts after the header of the underlying file.
+pub struct EncryptedFile {
+    file: Box<dyn File>,
+    cipher: Aes256,
+    iv: [u8; IV_LENGTH],
+    // the cursor of `read`, `read_all` and `seek`
+    pos: u64,
+    // the length of the plaintext, where `write` appends to
+    size: u64,
+}
+
+impl EncryptedFile {
+    // Encrypts or decrypts `data` at `offset` of the plaintext in place
+    fn apply_keystream(&self, data: &mut [u8], offset: u64) {
+        let core = ctr::CtrCore::inner_iv_init(self.cipher.clone(), &self.iv.into());
+        let mut c = Aes256Ctr::from_core(core);
+        c.seek(offset);
+        c.apply_keystream(data);
+    }
+}
+
+impl File for EncryptedFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut data = buf.to_vec();
+        self.apply_keystream(&mut data, self.size);
+        self.file.write(&data)?;
+        self.size += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.file.sync()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.file.close()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::End(p) => self.len()? as i64 + p,
+            SeekFrom::Current(p) => self.pos as i64 + p,
+        };
+        if target < 0 {
+            return Err(WickErr::new(
+                Status::InvalidArgument,
+                Some("invalid seek to a negative position"),
+            ));
+        }
+        self.pos = target as u64;
+        Ok(self.pos)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = buf.len().min(self.size.saturating_sub(self.pos) as usize);
+        self.read_exact_at(&mut buf[..n], self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn read_all(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        let start = buf.len();
+        let n = self.size.saturating_sub(self.pos) as usize;
+        buf.resize(start + n, 0);
+        self.read_exact_at(&mut buf[start..], self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.size)
+    }
+
+    fn lock(&self) -> Result<()> {
+        self.file.lock()
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.file.unlock()
+    }
+
+    fn lock_region(&self, offset: u64, len: u64, kind: LockKind) -> Result<()> {
+        self.file.lock_region(offset, len, kind)
+    }
+
+    fn unlock_region(&self, offset: u64, len: u64) -> Result<()> {
+        self.file.unlock_region(offset, len)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let n = self.file.read_at(buf, offset + HEADER_LENGTH as u64)?;
+        self.apply_keystream(&mut buf[..n], offset);
+        Ok(n)
+    }
+
+    fn read_exact_at_vectored(&self, requests: &mut [ReadRequest]) -> Result<()> {
+        for req in requests.iter_mut() {
+            req.offset += HEADER_LENGTH as u64;
+        }
+        let res = self.file.read_exact_at_vectored(requests);
+        for req in requests.iter_mut() {
+            req.offset -= HEADER_LENGTH as u64;
+        }
+        res?;
+        for req in requests.iter_mut() {
+            self.apply_keystream(req.buf, req.offset);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{WickDB, DB};
+    use crate::options::{Options, ReadOptions, WriteOptions};
+    use crate::storage::mem::MemStorage;
+    use crate::util::slice::Slice;
+
+    const KEY: &[u8; KEY_LENGTH] = b"0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_encrypted_file() {
+        let mem = Arc::new(MemStorage::default());
+        let s = EncryptedStorage::new(mem.clone(), KEY);
+        let data: Vec<u8> = (0..10000).map(|i| (i % 7) as u8 + b'a').collect();
+        let mut f = s.create("000007.log").unwrap();
+        for chunk in data.chunks(333) {
+            f.write(chunk).unwrap();
+        }
+        assert_eq!(f.len().unwrap(), data.len() as u64);
+        drop(f);
+
+        // The contents are not stored in plaintext
+        let mut raw = vec![];
+        mem.open("000007.log").unwrap().read_all(&mut raw).unwrap();
+        assert_eq!(raw.len(), data.len() + HEADER_LENGTH);
+        assert_ne!(&raw[HEADER_LENGTH..], data.as_slice());
+
+        s.rename("000007.log", "CURRENT").unwrap();
+        let mut f = s.open("CURRENT").unwrap();
+        for &(offset, n) in &[(0, 10), (15, 100), (9000, 1000)] {
+            let mut buf = vec![0; n];
+            f.read_exact_at(&mut buf, offset as u64).unwrap();
+            assert_eq!(buf.as_slice(), &data[offset..offset + n]);
+        }
+        let (mut a, mut b) = (vec![0; 16], vec![0; 17]);
+        let mut requests = vec![ReadRequest::new(1, &mut a), ReadRequest::new(5000, &mut b)];
+        f.read_exact_at_vectored(&mut requests).unwrap();
+        assert_eq!(a.as_slice(), &data[1..17]);
+        assert_eq!(b.as_slice(), &data[5000..5017]);
+        let mut buf = vec![0; 100];
+        assert_eq!(f.read(&mut buf).unwrap(), 100);
+        assert_eq!(buf.as_slice(), &data[..100]);
+        f.seek(SeekFrom::Start(9900)).unwrap();
+        let mut rest = vec![];
+        assert_eq!(f.read_all(&mut rest).unwrap(), 100);
+        assert_eq!(rest.as_slice(), &data[9900..]);
+        // Appends after the existing contents
+        f.write(b"tail").unwrap();
+        let mut buf = vec![0; 4];
+        f.read_exact_at(&mut buf, data.len() as u64).unwrap();
+        assert_eq!(buf.as_slice(), b"tail");
+
+        // The wrong key never reads the plaintext
+        let other = EncryptedStorage::new(mem.clone(), b"fedcba9876543210fedcba9876543210");
+        let mut buf = vec![0; 100];
+        other
+            .open("CURRENT")
+            .unwrap()
+            .read_exact_at(&mut buf, 0)
+            .unwrap();
+        assert_ne!(buf.as_slice(), &data[..100]);
+        mem.create("plain").unwrap().write(b"plain").unwrap();
+        assert!(s.open("plain").is_err());
+    }
+
+    #[test]
+    fn test_encrypted_storage_with_db() {
+        let mem: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let env: Arc<dyn Storage> = Arc::new(EncryptedStorage::new(mem.clone(), KEY));
+        let new_options = || {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.write_buffer_size = 64 << 10;
+            options
+        };
+        let name = "test_encrypted_storage_with_db".to_owned();
+        let mut db = WickDB::open_db(new_options(), name.clone()).unwrap();
+        let value = vec![b'v'; 1000];
+        for i in 0..300 {
+            let key = format!("key{:03}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(value.as_slice()),
+            )
+            .unwrap();
+        }
+        db.close().unwrap();
+
+        // Recovers from the encrypted WAL and MANIFEST
+        let db = WickDB::open_db(new_options(), name.clone()).unwrap();
+        for i in 0..300 {
+            let key = format!("key{:03}", i);
+            let got = db
+                .get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                .unwrap();
+            assert_eq!(got.as_deref(), Some(value.as_slice()));
+        }
+        for path in mem.list(name.as_str()).unwrap() {
+            let mut raw = vec![];
+            mem.open(path.to_str().unwrap())
+                .unwrap()
+                .read_all(&mut raw)
+                .unwrap();
+            assert_eq!(&raw[..MAGIC.len()], MAGIC, "{:?}", path);
+        }
+    }
+}
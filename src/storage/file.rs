@@ -15,7 +15,11 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE SysFile. See the AUTHORS SysFile for names of contributors.
 
-use crate::storage::{File, Storage};
+#[cfg(target_os = "linux")]
+use crate::storage::direct::DirectFile;
+#[cfg(all(unix, target_pointer_width = "64"))]
+use crate::storage::mmap::MmapFile;
+use crate::storage::{AsyncFile, File, FileFuture, LockKind, Storage};
 use crate::util::status::{Result, Status, WickErr};
 use fs2::FileExt;
 use std::fs::{
@@ -48,6 +52,39 @@ impl Storage for FileStorage {
         }
     }
 
+    fn open_async(&self, name: &str) -> Result<Box<dyn AsyncFile>> {
+        let file = w_io_result!(OpenOptions::new().write(true).read(true).open(name))?;
+        Ok(Box::new(file))
+    }
+
+    // The address space of a 32-bit platform is too small to map the table files
+    #[cfg(all(unix, target_pointer_width = "64"))]
+    fn open_mmap(&self, name: &str) -> Result<Box<dyn File>> {
+        let file = w_io_result!(OpenOptions::new().read(true).open(name))?;
+        match MmapFile::new(file) {
+            Ok(f) => Ok(Box::new(f)),
+            // e.g. an empty file
+            Err(_) => self.open(name),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn create_direct(&self, name: &str) -> Result<Box<dyn File>> {
+        match DirectFile::create(name) {
+            Ok(f) => Ok(Box::new(f)),
+            // e.g. O_DIRECT is not supported by tmpfs
+            Err(_) => self.create(name),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open_direct(&self, name: &str) -> Result<Box<dyn File>> {
+        match DirectFile::open(name) {
+            Ok(f) => Ok(Box::new(f)),
+            Err(_) => self.open(name),
+        }
+    }
+
     fn remove(&self, name: &str) -> Result<()> {
         let r = remove_file(name);
         w_io_result!(r)
@@ -111,6 +148,10 @@ impl File for SysFile {
         w_io_result!(Write::flush(self))
     }
 
+    fn sync(&mut self) -> Result<()> {
+        w_io_result!(SysFile::sync_all(self))
+    }
+
     fn close(&mut self) -> Result<()> {
         Ok(())
     }
@@ -146,6 +187,14 @@ impl File for SysFile {
         w_io_result!(FileExt::unlock(self))
     }
 
+    fn lock_region(&self, offset: u64, len: u64, kind: LockKind) -> Result<()> {
+        sys_lock_region(self, offset, len, Some(kind))
+    }
+
+    fn unlock_region(&self, offset: u64, len: u64) -> Result<()> {
+        sys_lock_region(self, offset, len, None)
+    }
+
     #[cfg(unix)]
     fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
         let r = std::os::unix::prelude::FileExt::read_at(self, buf, offset);
@@ -157,6 +206,122 @@ impl File for SysFile {
         w_io_result!(r)
     }
 }
+
+#[cfg(any(target_os = "linux", windows))]
+fn region_busy() -> WickErr {
+    WickErr::new(
+        Status::Busy,
+        Some("the file region is locked by another opener"),
+    )
+}
+
+// Locks the region by `fcntl`, or unlocks it if `kind` is `None`. The open file
+// description locks are owned by the opened file like `flock`.
+#[cfg(target_os = "linux")]
+fn sys_lock_region(file: &SysFile, offset: u64, len: u64, kind: Option<LockKind>) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = match kind {
+        Some(LockKind::Shared) => libc::F_RDLCK,
+        Some(LockKind::Exclusive) => libc::F_WRLCK,
+        None => libc::F_UNLCK,
+    } as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = offset as libc::off_t;
+    lock.l_len = len as libc::off_t;
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_SETLK, &lock) } == 0 {
+        return Ok(());
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EAGAIN) | Some(libc::EACCES) => Err(region_busy()),
+        _ => Err(WickErr::new_from_raw(Status::IOError, None, Box::new(e))),
+    }
+}
+
+// The traditional POSIX locks of the other unix systems are owned by the process,
+// so the openers in one process would never conflict as `File::lock_region`
+// requires. They're not supported.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn sys_lock_region(
+    _file: &SysFile,
+    _offset: u64,
+    _len: u64,
+    _kind: Option<LockKind>,
+) -> Result<()> {
+    Err(WickErr::new(
+        Status::NotSupported,
+        Some("region locks owned by the opened file are only supported on Linux"),
+    ))
+}
+
+// Locks the region by `LockFileEx`, or unlocks it if `kind` is `None`
+#[cfg(windows)]
+fn sys_lock_region(file: &SysFile, offset: u64, len: u64, kind: Option<LockKind>) -> Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+    use winapi::um::fileapi::{LockFileEx, UnlockFileEx};
+    use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY, OVERLAPPED};
+    let len = if len == 0 { u64::MAX - offset } else { len };
+    let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+    unsafe {
+        let s = overlapped.u.s_mut();
+        s.Offset = offset as u32;
+        s.OffsetHigh = (offset >> 32) as u32;
+    }
+    let handle = file.as_raw_handle() as winapi::um::winnt::HANDLE;
+    let (len_low, len_high) = (len as u32, (len >> 32) as u32);
+    let r = match kind {
+        Some(kind) => {
+            let mut flags = LOCKFILE_FAIL_IMMEDIATELY;
+            if kind == LockKind::Exclusive {
+                flags |= LOCKFILE_EXCLUSIVE_LOCK;
+            }
+            unsafe { LockFileEx(handle, flags, 0, len_low, len_high, &mut overlapped) }
+        }
+        None => unsafe { UnlockFileEx(handle, 0, len_low, len_high, &mut overlapped) },
+    };
+    if r != 0 {
+        return Ok(());
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(code) if code == ERROR_LOCK_VIOLATION as i32 => Err(region_busy()),
+        _ => Err(WickErr::new_from_raw(Status::IOError, None, Box::new(e))),
+    }
+}
+
+// `std::fs::File` completes every request on the calling thread, so the
+// returned futures are always ready once they are polled.
+impl AsyncFile for SysFile {
+    fn read_at<'a>(&'a self, buf: &'a mut [u8], offset: u64) -> FileFuture<'a, usize> {
+        Box::pin(async move { File::read_at(self, buf, offset) })
+    }
+
+    #[cfg(unix)]
+    fn write_at<'a>(&'a self, buf: &'a [u8], offset: u64) -> FileFuture<'a, usize> {
+        Box::pin(async move {
+            let r = std::os::unix::prelude::FileExt::write_at(self, buf, offset);
+            w_io_result!(r)
+        })
+    }
+    #[cfg(windows)]
+    fn write_at<'a>(&'a self, buf: &'a [u8], offset: u64) -> FileFuture<'a, usize> {
+        Box::pin(async move {
+            let r = std::os::windows::prelude::FileExt::seek_write(self, buf, offset);
+            w_io_result!(r)
+        })
+    }
+
+    fn sync(&self) -> FileFuture<'_, ()> {
+        Box::pin(async move { w_io_result!(SysFile::sync_all(self)) })
+    }
+
+    fn len(&self) -> FileFuture<'_, u64> {
+        Box::pin(async move { File::len(self) })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -188,4 +353,34 @@ mod tests {
             .expect_err("failed to fill whole buffer");
         remove_file("test").expect("");
     }
+
+    // The region locks of the opened files in one process only conflict with the
+    // open file description locks
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_lock_region() {
+        let name = "test_lock_region";
+        let storage = FileStorage;
+        let f1 = storage.create(name).unwrap();
+        let f2 = storage.open(name).unwrap();
+        f1.lock_region(0, 1, LockKind::Shared).unwrap();
+        f2.lock_region(0, 1, LockKind::Shared).unwrap();
+        let err = f2.lock_region(0, 10, LockKind::Exclusive).unwrap_err();
+        assert_eq!(err.status(), Status::Busy);
+        // the disjoint regions never conflict
+        f2.lock_region(1, 0, LockKind::Exclusive).unwrap();
+        assert_eq!(
+            f1.lock_region(100, 1, LockKind::Shared)
+                .unwrap_err()
+                .status(),
+            Status::Busy
+        );
+        f1.unlock_region(0, 1).unwrap();
+        f2.unlock_region(0, 1).unwrap();
+        f1.lock_region(0, 1, LockKind::Exclusive).unwrap();
+        // the locks are released once the file is closed
+        drop(f2);
+        f1.lock_region(1, 0, LockKind::Exclusive).unwrap();
+        remove_file(name).unwrap();
+    }
 }
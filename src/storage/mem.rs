@@ -11,88 +11,254 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::storage::{File, Storage};
+use crate::storage::{File, LockKind, ReadRequest, Storage};
 use crate::util::status::{Result, Status, WickErr};
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// An operation on a `MemStorage` recorded for asserting on the durability
+/// behavior, e.g. a file must be synced before it is renamed to `CURRENT`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MemStorageEvent {
+    Create(String),
+    Sync(String),
+    Rename(String, String),
+    Remove(String),
+}
+
+// The states shared by a `MemStorage` and its files
+#[derive(Default)]
+struct MemState {
+    // All the directories except the root ""
+    dirs: HashSet<String>,
+    events: Vec<MemStorageEvent>,
+    sync_latency: Duration,
+}
 
 /// An in memory file system based on a simple HashMap
+///
+/// The files live in the directories created by `mkdir_all`, and a `rename` moves
+/// a file atomically, replacing the existing target. Every `File::sync` is tracked,
+/// so tests could check how much of a file is synced and in which order the files
+/// are synced and renamed.
 // TODO: maybe use a trie tree instead
 #[derive(Default, Clone)]
 pub struct MemStorage {
     inner: Arc<RwLock<HashMap<String, FileNode>>>,
+    state: Arc<Mutex<MemState>>,
 }
 
-impl Storage for MemStorage {
-    fn create(&self, name: &str) -> Result<Box<dyn File>> {
-        let file_node = FileNode::new(name);
+impl MemStorage {
+    /// Returns all the creations, syncs, renames and removals of the files in order
+    pub fn events(&self) -> Vec<MemStorageEvent> {
+        self.state.lock().unwrap().events.clone()
+    }
+
+    /// Returns the length of the contents of `name` covered by the last sync,
+    /// or `None` if the file doesn't exist
+    pub fn synced_len(&self, name: &str) -> Option<u64> {
         self.inner
-            .write()
+            .read()
             .unwrap()
-            .insert(String::from(name), file_node.clone());
-        Ok(Box::new(file_node))
+            .get(name)
+            .map(|f| f.inner.read().unwrap().synced)
+    }
+
+    /// Makes every following `File::sync` take at least `latency`
+    pub fn set_sync_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().sync_latency = latency;
+    }
+
+    fn record(&self, event: MemStorageEvent) {
+        self.state.lock().unwrap().events.push(event);
+    }
+
+    // Returns an error if the parent directory of `name` doesn't exist
+    fn check_parent(&self, name: &str) -> Result<()> {
+        let parent = parent_dir(name);
+        if parent.is_empty() || self.state.lock().unwrap().dirs.contains(parent) {
+            Ok(())
+        } else {
+            Err(WickErr::new(Status::IOError, Some("Not Found")))
+        }
+    }
+}
+
+// Returns the directory containing `name`, which is "" for a name in the root
+fn parent_dir(name: &str) -> &str {
+    match name.trim_end_matches('/').rfind('/') {
+        Some(i) => &name[..i],
+        None => "",
+    }
+}
+
+impl Storage for MemStorage {
+    fn create(&self, name: &str) -> Result<Box<dyn File>> {
+        self.check_parent(name)?;
+        let mut files = self.inner.write().unwrap();
+        // truncates an existing file in place like `O_TRUNC`, which keeps its lock
+        let file_node = match files.get(name) {
+            Some(f) => {
+                f.inner.write().unwrap().truncate();
+                f.clone()
+            }
+            None => {
+                let f = FileNode::new(name, self.state.clone());
+                files.insert(String::from(name), f.clone());
+                f
+            }
+        };
+        drop(files);
+        self.record(MemStorageEvent::Create(name.to_owned()));
+        Ok(Box::new(file_node.new_handle()))
     }
 
     fn open(&self, name: &str) -> Result<Box<dyn File>> {
         match self.inner.read().unwrap().get(name) {
-            Some(f) => Ok(Box::new(f.clone())),
+            Some(f) => {
+                let mut f = f.new_handle();
+                // a newly opened file reads from the start like a new file descriptor
+                f.seek(SeekFrom::Start(0))?;
+                Ok(Box::new(f))
+            }
             None => Err(WickErr::new(Status::IOError, Some("Not Found"))),
         }
     }
 
     // If not found, still returns Ok
     fn remove(&self, name: &str) -> Result<()> {
-        self.inner.write().unwrap().remove(name);
+        if self.inner.write().unwrap().remove(name).is_some() {
+            self.record(MemStorageEvent::Remove(name.to_owned()));
+        }
         Ok(())
     }
 
-    // Should not be used
-    fn remove_dir(&self, _dir: &str, _recursively: bool) -> Result<()> {
+    fn remove_dir(&self, dir: &str, recursively: bool) -> Result<()> {
+        let dir = dir.trim_end_matches('/');
+        let prefix = format!("{}/", dir);
+        let mut files = self.inner.write().unwrap();
+        let mut state = self.state.lock().unwrap();
+        if !state.dirs.contains(dir) {
+            return Err(WickErr::new(Status::IOError, Some("Not Found")));
+        }
+        let is_empty = !files.keys().any(|name| name.starts_with(&prefix))
+            && !state.dirs.iter().any(|d| d.starts_with(&prefix));
+        if !recursively && !is_empty {
+            return Err(WickErr::new(Status::IOError, Some("Directory not empty")));
+        }
+        let removed = files
+            .keys()
+            .filter(|name| name.starts_with(&prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in removed {
+            files.remove(&name);
+            state.events.push(MemStorageEvent::Remove(name));
+        }
+        state
+            .dirs
+            .retain(|d| d.as_str() != dir && !d.starts_with(&prefix));
         Ok(())
     }
 
     fn exists(&self, name: &str) -> bool {
         self.inner.read().unwrap().contains_key(name)
+            || self
+                .state
+                .lock()
+                .unwrap()
+                .dirs
+                .contains(name.trim_end_matches('/'))
     }
 
     fn rename(&self, old: &str, new: &str) -> Result<()> {
+        self.check_parent(new)?;
         let mut map = self.inner.write().unwrap();
+        // the target is replaced under the lock so the readers see either file
         match map.remove(old) {
             Some(f) => {
                 map.insert(new.to_owned(), f);
+                self.record(MemStorageEvent::Rename(old.to_owned(), new.to_owned()));
                 Ok(())
             }
             None => Err(WickErr::new(Status::IOError, Some("Not Found"))),
         }
     }
 
-    // Should not be used
-    fn mkdir_all(&self, _dir: &str) -> Result<()> {
+    fn mkdir_all(&self, dir: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let mut dir = dir.trim_end_matches('/');
+        while !dir.is_empty() {
+            state.dirs.insert(dir.to_owned());
+            dir = parent_dir(dir);
+        }
         Ok(())
     }
 
-    // Just list all keys in HashMap
-    fn list(&self, _dir: &str) -> Result<Vec<PathBuf>> {
+    // Lists the files and the directories right in `dir`
+    fn list(&self, dir: &str) -> Result<Vec<PathBuf>> {
+        let dir = dir.trim_end_matches('/');
+        let state = self.state.lock().unwrap();
+        if !dir.is_empty() && !state.dirs.contains(dir) {
+            return Err(WickErr::new(Status::IOError, Some("Not Found")));
+        }
         let mut result = vec![];
-        for (key, _) in self.inner.read().unwrap().iter() {
-            result.push(PathBuf::from(key.clone()))
+        for key in self.inner.read().unwrap().keys() {
+            if parent_dir(key) == dir {
+                result.push(PathBuf::from(key.clone()))
+            }
+        }
+        for d in state.dirs.iter() {
+            if parent_dir(d) == dir {
+                result.push(PathBuf::from(d.clone()))
+            }
         }
         Ok(result)
     }
 }
 
+// The owner ids of the opened files holding the region locks
+static NEXT_FILE_OWNER: AtomicU64 = AtomicU64::new(1);
+
 #[derive(Clone)]
 pub struct FileNode {
     inner: Arc<RwLock<InmemFile>>,
+    state: Arc<Mutex<MemState>>,
+    // Identifies the opener owning the region locks, 0 for the node in the storage
+    owner: u64,
 }
 
 impl FileNode {
-    fn new(name: &str) -> Self {
+    fn new(name: &str, state: Arc<Mutex<MemState>>) -> Self {
         FileNode {
             inner: Arc::new(RwLock::new(InmemFile::new(name))),
+            state,
+            owner: 0,
+        }
+    }
+
+    // Returns a new opener of the file
+    fn new_handle(&self) -> Self {
+        let mut handle = self.clone();
+        handle.owner = NEXT_FILE_OWNER.fetch_add(1, Ordering::Relaxed);
+        handle
+    }
+}
+
+// Releases the region locks of the opener like closing a file descriptor
+impl Drop for FileNode {
+    fn drop(&mut self) {
+        if self.owner == 0 {
+            return;
+        }
+        if let Ok(mut f) = self.inner.write() {
+            let owner = self.owner;
+            f.region_locks.retain(|l| l.owner != owner);
         }
     }
 }
@@ -107,6 +273,22 @@ impl File for FileNode {
         self.inner.write().unwrap().flush()
     }
 
+    fn sync(&mut self) -> Result<()> {
+        let latency = {
+            let mut f = self.inner.write().unwrap();
+            f.synced = f.contents.get_ref().len() as u64;
+            let mut state = self.state.lock().unwrap();
+            state
+                .events
+                .push(MemStorageEvent::Sync(f.name().to_owned()));
+            state.sync_latency
+        };
+        if latency > Duration::default() {
+            thread::sleep(latency);
+        }
+        Ok(())
+    }
+
     fn close(&mut self) -> Result<()> {
         Ok(())
     }
@@ -135,9 +317,66 @@ impl File for FileNode {
         self.inner.read().unwrap().unlock()
     }
 
+    fn lock_region(&self, offset: u64, len: u64, kind: LockKind) -> Result<()> {
+        let end = region_end(offset, len);
+        let mut f = self.inner.write().unwrap();
+        if f.region_locks.iter().any(|l| {
+            l.owner != self.owner
+                && l.start < end
+                && offset < l.end
+                && (kind == LockKind::Exclusive || l.kind == LockKind::Exclusive)
+        }) {
+            return Err(WickErr::new(
+                Status::Busy,
+                Some("the file region is locked by another opener"),
+            ));
+        }
+        f.region_locks.push(RegionLock {
+            owner: self.owner,
+            start: offset,
+            end,
+            kind,
+        });
+        Ok(())
+    }
+
+    fn unlock_region(&self, offset: u64, len: u64) -> Result<()> {
+        let end = region_end(offset, len);
+        let owner = self.owner;
+        self.inner
+            .write()
+            .unwrap()
+            .region_locks
+            .retain(|l| l.owner != owner || l.start != offset || l.end != end);
+        Ok(())
+    }
+
     fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
         self.inner.read().unwrap().read_at(buf, offset)
     }
+
+    fn read_exact_at_vectored(&self, requests: &mut [ReadRequest]) -> Result<()> {
+        // Serves all the requests under a single acquisition of the lock
+        self.inner.read().unwrap().read_exact_at_vectored(requests)
+    }
+}
+
+// A lock on the bytes `[start, end)` of a file held by an opener
+struct RegionLock {
+    owner: u64,
+    start: u64,
+    end: u64,
+    kind: LockKind,
+}
+
+// Returns the exclusive end of a region where a `len` of 0 means to the end of file
+#[inline]
+fn region_end(offset: u64, len: u64) -> u64 {
+    if len == 0 {
+        u64::MAX
+    } else {
+        offset.saturating_add(len)
+    }
 }
 
 /// `File` implementation based on memory
@@ -145,7 +384,10 @@ impl File for FileNode {
 pub struct InmemFile {
     name: String,
     lock: AtomicBool,
+    region_locks: Vec<RegionLock>,
     contents: Cursor<Vec<u8>>,
+    // the length of the contents covered by the last sync
+    synced: u64,
 }
 
 impl InmemFile {
@@ -153,7 +395,9 @@ impl InmemFile {
         Self {
             name: name.to_owned(),
             lock: AtomicBool::new(false),
+            region_locks: vec![],
             contents: Cursor::new(vec![]),
+            synced: 0,
         }
     }
 
@@ -162,6 +406,11 @@ impl InmemFile {
         &self.name
     }
 
+    fn truncate(&mut self) {
+        self.contents = Cursor::new(vec![]);
+        self.synced = 0;
+    }
+
     #[inline]
     pub fn pos_and_data(&self) -> (u64, &[u8]) {
         (self.contents.position(), self.contents.get_ref().as_slice())
@@ -245,12 +494,14 @@ impl File for InmemFile {
 
 #[cfg(test)]
 mod tests {
-    use super::{InmemFile, MemStorage};
-    use crate::storage::{File, Storage};
+    use super::{InmemFile, MemStorage, MemStorageEvent};
+    use crate::storage::{File, ReadRequest, Storage};
     use crate::util::coding::put_fixed_32;
     use crate::util::status::Status;
     use hashbrown::HashSet;
     use std::error::Error;
+    use std::path::PathBuf;
+    use std::time::Duration;
 
     #[test]
     fn test_mem_file_read_write() {
@@ -330,6 +581,30 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_mem_file_read_exact_at_vectored() {
+        let env = MemStorage::default();
+        let mut f = env.create("test").expect("'create' should work");
+        f.write(b"hello world|hello wickdb").expect("");
+        let (mut a, mut b, mut c) = (vec![0u8; 5], vec![0u8; 6], vec![0u8; 1]);
+        let mut requests = vec![
+            ReadRequest::new(18, b.as_mut_slice()),
+            ReadRequest::new(0, a.as_mut_slice()),
+            ReadRequest::new(11, c.as_mut_slice()),
+        ];
+        f.read_exact_at_vectored(&mut requests)
+            .expect("vectored read should work");
+        assert_eq!(a.as_slice(), b"hello");
+        assert_eq!(b.as_slice(), b"wickdb");
+        assert_eq!(c.as_slice(), b"|");
+        // fails if any request reaches the EOF
+        let mut requests = vec![
+            ReadRequest::new(0, a.as_mut_slice()),
+            ReadRequest::new(20, b.as_mut_slice()),
+        ];
+        assert!(f.read_exact_at_vectored(&mut requests).is_err());
+    }
+
     #[test]
     fn test_memory_storage_basic() {
         let env = MemStorage::default();
@@ -375,4 +650,61 @@ mod tests {
             assert!(tmp_names.contains(name.to_str().unwrap()))
         }
     }
+
+    #[test]
+    fn test_memory_storage_dirs() {
+        let env = MemStorage::default();
+        assert!(env.create("db/000001.log").is_err());
+        env.mkdir_all("db/shard/").expect("'mkdir_all' should work");
+        assert!(env.exists("db") && env.exists("db/shard"));
+        env.create("db/000001.log").expect("'create' should work");
+        env.create("db/shard/000002.ldb")
+            .expect("'create' should work");
+        env.create("top").expect("'create' should work");
+        let mut list = env.list("db").expect("'list' should work");
+        list.sort();
+        assert_eq!(
+            list,
+            vec![PathBuf::from("db/000001.log"), PathBuf::from("db/shard")]
+        );
+        assert!(env.list("missing").is_err());
+        assert!(env.rename("db/000001.log", "missing/000001.log").is_err());
+
+        assert!(env.remove_dir("db", false).is_err());
+        env.remove_dir("db", true)
+            .expect("'remove_dir' should work");
+        assert!(!env.exists("db") && !env.exists("db/shard/000002.ldb"));
+        assert_eq!(env.list("").unwrap(), vec![PathBuf::from("top")]);
+    }
+
+    #[test]
+    fn test_memory_storage_sync_tracking() {
+        let env = MemStorage::default();
+        env.set_sync_latency(Duration::from_millis(10));
+        let mut f = env.create("tmp").expect("'create' should work");
+        f.write(b"hello").expect("");
+        assert_eq!(env.synced_len("tmp"), Some(0));
+        let start = std::time::Instant::now();
+        f.sync().expect("'sync' should work");
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        f.write(b" world").expect("");
+        assert_eq!(env.synced_len("tmp"), Some(5));
+
+        // the target is replaced and the sync state moves with the file
+        env.create("CURRENT").expect("'create' should work");
+        env.rename("tmp", "CURRENT").expect("'rename' should work");
+        assert_eq!(env.synced_len("CURRENT"), Some(5));
+        assert_eq!(env.synced_len("tmp"), None);
+        env.remove("CURRENT").expect("'remove' should work");
+        assert_eq!(
+            env.events(),
+            vec![
+                MemStorageEvent::Create("tmp".to_owned()),
+                MemStorageEvent::Sync("tmp".to_owned()),
+                MemStorageEvent::Create("CURRENT".to_owned()),
+                MemStorageEvent::Rename("tmp".to_owned(), "CURRENT".to_owned()),
+                MemStorageEvent::Remove("CURRENT".to_owned()),
+            ]
+        );
+    }
 }
@@ -0,0 +1,192 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::storage::{File, LockKind};
+use crate::util::status::{Result, Status, WickErr};
+use fs2::FileExt;
+use std::fs::File as SysFile;
+use std::io::SeekFrom;
+use std::os::unix::io::AsRawFd;
+use std::{ptr, slice};
+
+/// A read-only `File` mapping the whole file into memory.
+///
+/// The reads are served by copying from the mapped memory without any syscall.
+/// The file is expected to be immutable while mapped, like a table file.
+pub struct MmapFile {
+    file: SysFile,
+    ptr: *mut libc::c_void,
+    len: usize,
+    // the cursor of `read` and `seek`
+    pos: u64,
+}
+
+// The mapped memory is never written and the positional reads share nothing
+unsafe impl Send for MmapFile {}
+unsafe impl Sync for MmapFile {}
+
+impl MmapFile {
+    /// Maps the opened `file` into memory. Empty files could not be mapped.
+    pub fn new(file: SysFile) -> Result<Self> {
+        let len = w_io_result!(file.metadata())?.len() as usize;
+        if len == 0 {
+            return Err(WickErr::new(
+                Status::NotSupported,
+                Some("could not map an empty file"),
+            ));
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            let e = std::io::Error::last_os_error();
+            return Err(WickErr::new_from_raw(Status::IOError, None, Box::new(e)));
+        }
+        Ok(Self {
+            file,
+            ptr,
+            len,
+            pos: 0,
+        })
+    }
+
+    #[inline]
+    fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for MmapFile {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+fn read_only_err() -> WickErr {
+    WickErr::new(Status::NotSupported, Some("mmap file is read only"))
+}
+
+impl File for MmapFile {
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(read_only_err())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Err(read_only_err())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(p) => (self.len as i64).checked_add(p).map(|p| p as u64),
+            SeekFrom::Current(p) => (self.pos as i64).checked_add(p).map(|p| p as u64),
+        };
+        match new_pos {
+            Some(p) if (p as i64) >= 0 => {
+                self.pos = p;
+                Ok(p)
+            }
+            _ => Err(WickErr::new(
+                Status::InvalidArgument,
+                Some("invalid seek to a negative position"),
+            )),
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn read_all(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        let start = (self.pos as usize).min(self.len);
+        buf.extend_from_slice(&self.data()[start..]);
+        self.pos = self.len as u64;
+        Ok(self.len - start)
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.len as u64)
+    }
+
+    fn lock(&self) -> Result<()> {
+        w_io_result!(self.file.try_lock_exclusive())
+    }
+
+    fn unlock(&self) -> Result<()> {
+        w_io_result!(FileExt::unlock(&self.file))
+    }
+
+    fn lock_region(&self, offset: u64, len: u64, kind: LockKind) -> Result<()> {
+        File::lock_region(&self.file, offset, len, kind)
+    }
+
+    fn unlock_region(&self, offset: u64, len: u64) -> Result<()> {
+        File::unlock_region(&self.file, offset, len)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        if offset >= self.len as u64 {
+            return Ok(0);
+        }
+        let offset = offset as usize;
+        let n = buf.len().min(self.len - offset);
+        buf[..n].copy_from_slice(&self.data()[offset..offset + n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::remove_file;
+    use std::io::Write;
+
+    #[test]
+    fn test_mmap_file_read() {
+        let name = "test_mmap_file_read";
+        let mut f = SysFile::create(name).expect("");
+        f.write_all(b"hello world").expect("");
+        f.sync_all().expect("");
+        let mut mf = MmapFile::new(SysFile::open(name).expect("")).expect("");
+        assert_eq!(mf.len().unwrap(), 11);
+        let mut buf = vec![0u8; 5];
+        mf.read_exact_at(buf.as_mut_slice(), 6).expect("");
+        assert_eq!(buf.as_slice(), b"world");
+        assert!(mf.read_exact_at(buf.as_mut_slice(), 8).is_err());
+        assert_eq!(mf.read(buf.as_mut_slice()).unwrap(), 5);
+        assert_eq!(buf.as_slice(), b"hello");
+        let mut rest = vec![];
+        assert_eq!(mf.read_all(&mut rest).unwrap(), 6);
+        assert_eq!(rest.as_slice(), b" world");
+        assert!(mf.write(b"x").is_err());
+        drop(mf);
+        let empty = SysFile::create(name).expect("");
+        assert!(MmapFile::new(empty).is_err());
+        remove_file(name).expect("");
+    }
+}
@@ -15,13 +15,26 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file. See the AUTHORS file for names of contributors.
 
+pub mod chaos;
+#[cfg(target_os = "linux")]
+pub mod direct;
+#[cfg(feature = "encryption")]
+pub mod encrypted;
 pub mod file;
 pub mod mem;
+#[cfg(all(unix, target_pointer_width = "64"))]
+pub mod mmap;
+#[cfg(feature = "object-store")]
+pub mod object_store;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub mod uring;
 
 use crate::util::status::{Result, Status, WickErr};
+use std::future::Future;
 use std::io;
 use std::io::SeekFrom;
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::sync::Arc;
 
 /// `Storage` is a namespace for files.
@@ -37,6 +50,39 @@ pub trait Storage: Send + Sync {
     /// Open a file for writing and reading
     fn open(&self, name: &str) -> Result<Box<dyn File>>;
 
+    /// Open a file for reading only by mapping it into memory, which saves the
+    /// syscall of every read. The file should never be modified while opened.
+    ///
+    /// The default implementation falls back to `open`.
+    fn open_mmap(&self, name: &str) -> Result<Box<dyn File>> {
+        self.open(name)
+    }
+
+    /// Like `create` but the file bypasses the OS page cache (e.g. by `O_DIRECT`)
+    /// so that writing it never evicts the cached pages of other files.
+    ///
+    /// The default implementation falls back to `create`.
+    fn create_direct(&self, name: &str) -> Result<Box<dyn File>> {
+        self.create(name)
+    }
+
+    /// Like `open` but the file bypasses the OS page cache.
+    ///
+    /// The default implementation falls back to `open`.
+    fn open_direct(&self, name: &str) -> Result<Box<dyn File>> {
+        self.open(name)
+    }
+
+    /// Open a file for the async reading and writing, see `AsyncFile`.
+    ///
+    /// The default implementation returns `Status::NotSupported`.
+    fn open_async(&self, _name: &str) -> Result<Box<dyn AsyncFile>> {
+        Err(WickErr::new(
+            Status::NotSupported,
+            Some("async files are not supported by the storage"),
+        ))
+    }
+
     /// Delete the named file
     fn remove(&self, name: &str) -> Result<()>;
 
@@ -58,10 +104,43 @@ pub trait Storage: Send + Sync {
     fn list(&self, dir: &str) -> Result<Vec<PathBuf>>;
 }
 
+/// The kind of a lock on a region of a file, see `File::lock_region`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockKind {
+    /// Held by any number of openers at the same time, e.g. the readers
+    Shared,
+    /// Held by only one opener, e.g. the writer
+    Exclusive,
+}
+
+/// A positional read served by `File::read_exact_at_vectored`
+pub struct ReadRequest<'a> {
+    /// The offset in the file to read from
+    pub offset: u64,
+    /// The buffer to fill
+    pub buf: &'a mut [u8],
+}
+
+impl<'a> ReadRequest<'a> {
+    pub fn new(offset: u64, buf: &'a mut [u8]) -> Self {
+        Self { offset, buf }
+    }
+}
+
 /// A file abstraction for IO operations
+///
+/// The positional reads (`read_at` and the methods built on it) take `&self` and
+/// never touch the cursor used by `read` and `seek`, so an implementation should
+/// allow them to run concurrently. This lets all the block reads of a `Table`
+/// share one opened file without any external locking.
 pub trait File {
     fn write(&mut self, buf: &[u8]) -> Result<usize>;
     fn flush(&mut self) -> Result<()>;
+    /// Makes sure all the written data has reached the underlying device.
+    /// The default implementation only flushes.
+    fn sync(&mut self) -> Result<()> {
+        self.flush()
+    }
     fn close(&mut self) -> Result<()>;
     fn seek(&mut self, pos: SeekFrom) -> Result<u64>;
     fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
@@ -79,6 +158,36 @@ pub trait File {
     fn lock(&self) -> Result<()>;
     fn unlock(&self) -> Result<()>;
 
+    /// Locks the `len` bytes of the file from `offset` without blocking, where a
+    /// `len` of 0 locks to the end of the file however it grows. The region locks
+    /// are owned by the opened file, so the openers of a same file in one process
+    /// conflict with each other like the ones in different processes, and they're
+    /// released when the file is dropped. A region held by this file should be
+    /// unlocked before being locked again.
+    ///
+    /// Fails with `Status::Busy` if the region overlaps an exclusive lock of
+    /// another opener, or an exclusive lock is requested on a region overlapping
+    /// any lock of another opener.
+    ///
+    /// The default implementation returns `Status::NotSupported`.
+    fn lock_region(&self, _offset: u64, _len: u64, _kind: LockKind) -> Result<()> {
+        Err(WickErr::new(
+            Status::NotSupported,
+            Some("region locks are not supported by the file"),
+        ))
+    }
+
+    /// Releases the region lock of this file taken by `lock_region` with the same
+    /// `offset` and `len`.
+    ///
+    /// The default implementation returns `Status::NotSupported`.
+    fn unlock_region(&self, _offset: u64, _len: u64) -> Result<()> {
+        Err(WickErr::new(
+            Status::NotSupported,
+            Some("region locks are not supported by the file"),
+        ))
+    }
+
     /// Reads bytes from an offset in this source into a buffer, returning how
     /// many bytes were read.
     ///
@@ -126,6 +235,77 @@ pub trait File {
             Ok(())
         }
     }
+
+    /// Fills the buffers of all the `requests` from their offsets like `read_exact_at`.
+    /// It fails if any of the requests fails, and the contents of the buffers are
+    /// unspecified then.
+    ///
+    /// The default implementation serves the requests one by one. A backend able to
+    /// submit several reads at once (e.g. by `preadv2`, io_uring or a batched remote
+    /// request) could override this to save the round trips.
+    fn read_exact_at_vectored(&self, requests: &mut [ReadRequest]) -> Result<()> {
+        for r in requests.iter_mut() {
+            self.read_exact_at(r.buf, r.offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// The future returned by the operations of an `AsyncFile`
+pub type FileFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + 'a>>;
+
+/// An asynchronous variant of `File` for backends which complete IO out of
+/// the calling thread, like a network storage or io_uring.
+///
+/// All the operations are positional so that a single `AsyncFile` can serve
+/// several in-flight requests without sharing a seek cursor.
+pub trait AsyncFile {
+    /// Reads bytes from an `offset` into `buf`, resolving to how many bytes were read.
+    ///
+    /// Like `File::read_at`, this may yield fewer bytes than the size of `buf`.
+    fn read_at<'a>(&'a self, buf: &'a mut [u8], offset: u64) -> FileFuture<'a, usize>;
+
+    /// Writes `buf` at an `offset`, resolving to how many bytes were written.
+    fn write_at<'a>(&'a self, buf: &'a [u8], offset: u64) -> FileFuture<'a, usize>;
+
+    /// Makes sure all the written data has reached the underlying device
+    fn sync(&self) -> FileFuture<'_, ()>;
+
+    /// Resolves to the length of the file
+    fn len(&self) -> FileFuture<'_, u64>;
+
+    /// Resolves to true if the file is empty
+    fn is_empty(&self) -> FileFuture<'_, bool> {
+        Box::pin(async move { Ok(self.len().await? == 0) })
+    }
+}
+
+/// Reads the exact number of bytes required to fill `buf` from an `offset` of
+/// the given `AsyncFile`.
+///
+/// Errors if the "EOF" is encountered before filling the buffer.
+pub async fn read_exact_at_async(
+    file: &dyn AsyncFile,
+    mut buf: &mut [u8],
+    mut offset: u64,
+) -> Result<()> {
+    while !buf.is_empty() {
+        match file.read_at(buf, offset).await {
+            Ok(0) => break,
+            Ok(n) => {
+                let tmp = buf;
+                buf = &mut tmp[n..];
+                offset += n as u64;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if !buf.is_empty() {
+        let e = io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer");
+        Err(WickErr::new_from_raw(Status::IOError, None, Box::new(e)))
+    } else {
+        Ok(())
+    }
 }
 
 /// Write given `data` into underlying `env` file and flush file iff `should_sync` is true
@@ -138,7 +318,7 @@ pub fn do_write_string_to_file(
     let mut file = env.create(file_name)?;
     file.write(data.as_bytes())?;
     if should_sync {
-        file.flush()?;
+        file.sync()?;
     }
     if file.close().is_err() {
         env.remove(file_name)?;
@@ -0,0 +1,520 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::db::filename::TableFileNaming;
+use crate::storage::{File, Storage};
+use crate::util::status::{Result, Status, WickErr};
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// A client of an object store like S3, which stores immutable objects by keys.
+///
+/// wickdb doesn't depend on any SDK, so the applications implement this with the
+/// client of their choice. All the methods are blocking.
+pub trait ObjectStore: Send + Sync {
+    /// Uploads the whole object, replacing the existing one
+    fn put(&self, key: &str, data: Vec<u8>) -> Result<()>;
+
+    /// Downloads `len` bytes of the object at `offset` by a ranged GET
+    fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>>;
+
+    /// Returns the size of the object or `None` if it doesn't exist
+    fn head(&self, key: &str) -> Result<Option<u64>>;
+
+    /// Deletes the object. Deleting a missing object is not an error.
+    fn delete(&self, key: &str) -> Result<()>;
+
+    /// Lists the keys of all the objects starting with `prefix`
+    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+}
+
+/// A `Storage` keeping the table files in an `ObjectStore` and all the other files,
+/// like the WALs, the MANIFEST and the info logs, in a local `Storage`.
+///
+/// A table file is stored as the object keyed by `prefix` followed by its path. It's
+/// buffered in memory while being written and uploaded as a whole by `File::sync`
+/// or `File::close`, which are always called on a finished table. The reads of a
+/// table map onto ranged GETs, so the block cache should be sized to cover the hot
+/// blocks. The objects have no rename, so renaming a table copies the object.
+///
+/// The table files are told apart by the `TableFileNaming` given by
+/// `with_table_file_naming`, which should be the `Options::table_file_naming` of
+/// the db.
+pub struct ObjectStoreStorage {
+    store: Arc<dyn ObjectStore>,
+    local: Arc<dyn Storage>,
+    prefix: String,
+    table_file_naming: TableFileNaming,
+}
+
+impl ObjectStoreStorage {
+    pub fn new(store: Arc<dyn ObjectStore>, local: Arc<dyn Storage>, prefix: &str) -> Self {
+        Self {
+            store,
+            local,
+            prefix: prefix.to_owned(),
+            table_file_naming: TableFileNaming::default(),
+        }
+    }
+
+    /// Sets the naming scheme of the table files stored as the objects.
+    /// Default: `TableFileNaming::default()`
+    pub fn with_table_file_naming(mut self, naming: TableFileNaming) -> Self {
+        self.table_file_naming = naming;
+        self
+    }
+
+    // Returns the object key of `name` if it's a table file
+    fn object_key(&self, name: &str) -> Option<String> {
+        self.table_file_naming
+            .parse_table_number(name)
+            .map(|_| format!("{}{}", self.prefix, name))
+    }
+
+    fn open_object(&self, key: String) -> Result<Box<dyn File>> {
+        match self.store.head(key.as_str())? {
+            Some(size) => Ok(Box::new(ObjectFile {
+                store: self.store.clone(),
+                key,
+                size,
+                pos: 0,
+            })),
+            None => Err(WickErr::new(Status::IOError, Some("Not Found"))),
+        }
+    }
+}
+
+impl Storage for ObjectStoreStorage {
+    fn create(&self, name: &str) -> Result<Box<dyn File>> {
+        match self.object_key(name) {
+            Some(key) => Ok(Box::new(ObjectWriter {
+                store: self.store.clone(),
+                key,
+                buf: vec![],
+                size: 0,
+                uploaded: false,
+            })),
+            None => self.local.create(name),
+        }
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn File>> {
+        match self.object_key(name) {
+            Some(key) => self.open_object(key),
+            None => self.local.open(name),
+        }
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        match self.object_key(name) {
+            Some(key) => self.store.delete(key.as_str()),
+            None => self.local.remove(name),
+        }
+    }
+
+    fn remove_dir(&self, dir: &str, recursively: bool) -> Result<()> {
+        if recursively {
+            let prefix = format!("{}{}/", self.prefix, dir.trim_end_matches('/'));
+            for key in self.store.list(prefix.as_str())? {
+                self.store.delete(key.as_str())?;
+            }
+        }
+        self.local.remove_dir(dir, recursively)
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        match self.object_key(name) {
+            Some(key) => matches!(self.store.head(key.as_str()), Ok(Some(_))),
+            None => self.local.exists(name),
+        }
+    }
+
+    fn rename(&self, old: &str, new: &str) -> Result<()> {
+        match (self.object_key(old), self.object_key(new)) {
+            (None, None) => self.local.rename(old, new),
+            (_, new_key) => {
+                // copies the file across the stores
+                let mut data = vec![];
+                self.open(old)?.read_all(&mut data)?;
+                match new_key {
+                    Some(key) => self.store.put(key.as_str(), data)?,
+                    None => {
+                        let mut f = self.local.create(new)?;
+                        f.write(data.as_slice())?;
+                        f.sync()?;
+                        f.close()?;
+                    }
+                }
+                self.remove(old)
+            }
+        }
+    }
+
+    fn mkdir_all(&self, dir: &str) -> Result<()> {
+        self.local.mkdir_all(dir)
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<PathBuf>> {
+        let dir = dir.trim_end_matches('/');
+        let mut result = self.local.list(dir)?;
+        let prefix = format!("{}{}/", self.prefix, dir);
+        for key in self.store.list(prefix.as_str())? {
+            // only the objects right in `dir`
+            if !key[prefix.len()..].contains('/') {
+                result.push(PathBuf::from(&key[self.prefix.len()..]));
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// A table file being written, which is uploaded when synced or closed
+struct ObjectWriter {
+    store: Arc<dyn ObjectStore>,
+    key: String,
+    buf: Vec<u8>,
+    // the number of the bytes written, which are moved out of `buf` by the upload
+    size: u64,
+    uploaded: bool,
+}
+
+impl ObjectWriter {
+    fn upload(&mut self) -> Result<()> {
+        if !self.uploaded {
+            self.store
+                .put(self.key.as_str(), std::mem::take(&mut self.buf))?;
+            self.uploaded = true;
+        }
+        Ok(())
+    }
+}
+
+impl File for ObjectWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.uploaded {
+            return Err(WickErr::new(
+                Status::NotSupported,
+                Some("could not write an uploaded object"),
+            ));
+        }
+        self.buf.extend_from_slice(buf);
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.upload()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.upload()
+    }
+
+    fn seek(&mut self, _pos: SeekFrom) -> Result<u64> {
+        Err(WickErr::new(
+            Status::NotSupported,
+            Some("could not seek an object being written"),
+        ))
+    }
+
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize> {
+        Err(WickErr::new(
+            Status::NotSupported,
+            Some("could not read an object being written"),
+        ))
+    }
+
+    fn read_all(&mut self, _buf: &mut Vec<u8>) -> Result<usize> {
+        Err(WickErr::new(
+            Status::NotSupported,
+            Some("could not read an object being written"),
+        ))
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.size)
+    }
+
+    fn lock(&self) -> Result<()> {
+        Err(WickErr::new(
+            Status::NotSupported,
+            Some("could not lock an object"),
+        ))
+    }
+
+    fn unlock(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_at(&self, _buf: &mut [u8], _offset: u64) -> Result<usize> {
+        Err(WickErr::new(
+            Status::NotSupported,
+            Some("could not read an object being written"),
+        ))
+    }
+}
+
+/// An uploaded table file read by ranged GETs
+struct ObjectFile {
+    store: Arc<dyn ObjectStore>,
+    key: String,
+    size: u64,
+    pos: u64,
+}
+
+impl File for ObjectFile {
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(WickErr::new(
+            Status::NotSupported,
+            Some("could not write an uploaded object"),
+        ))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::End(p) => self.size as i64 + p,
+            SeekFrom::Current(p) => self.pos as i64 + p,
+        };
+        if target < 0 {
+            return Err(WickErr::new(
+                Status::InvalidArgument,
+                Some("invalid seek to a negative position"),
+            ));
+        }
+        self.pos = target as u64;
+        Ok(self.pos)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn read_all(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        let data = self
+            .store
+            .get_range(self.key.as_str(), 0, self.size as usize)?;
+        buf.extend_from_slice(data.as_slice());
+        self.pos = self.size;
+        Ok(data.len())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.size)
+    }
+
+    fn lock(&self) -> Result<()> {
+        Err(WickErr::new(
+            Status::NotSupported,
+            Some("could not lock an object"),
+        ))
+    }
+
+    fn unlock(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        if offset >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let n = buf.len().min((self.size - offset) as usize);
+        let data = self.store.get_range(self.key.as_str(), offset, n)?;
+        if data.len() != n {
+            return Err(WickErr::new(
+                Status::IOError,
+                Some("short ranged read of an object"),
+            ));
+        }
+        buf[..n].copy_from_slice(data.as_slice());
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{WickDB, DB};
+    use crate::options::{Options, ReadOptions, WriteOptions};
+    use crate::storage::mem::MemStorage;
+    use crate::util::slice::Slice;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MemObjectStore {
+        objects: Mutex<BTreeMap<String, Vec<u8>>>,
+        ranged_gets: AtomicUsize,
+    }
+
+    impl ObjectStore for MemObjectStore {
+        fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+            self.objects.lock().unwrap().insert(key.to_owned(), data);
+            Ok(())
+        }
+
+        fn get_range(&self, key: &str, offset: u64, len: usize) -> Result<Vec<u8>> {
+            self.ranged_gets.fetch_add(1, Ordering::SeqCst);
+            match self.objects.lock().unwrap().get(key) {
+                Some(data) if offset as usize + len <= data.len() => {
+                    Ok(data[offset as usize..offset as usize + len].to_vec())
+                }
+                Some(_) => Err(WickErr::new(Status::IOError, Some("invalid range"))),
+                None => Err(WickErr::new(Status::IOError, Some("Not Found"))),
+            }
+        }
+
+        fn head(&self, key: &str) -> Result<Option<u64>> {
+            Ok(self
+                .objects
+                .lock()
+                .unwrap()
+                .get(key)
+                .map(|data| data.len() as u64))
+        }
+
+        fn delete(&self, key: &str) -> Result<()> {
+            self.objects.lock().unwrap().remove(key);
+            Ok(())
+        }
+
+        fn list(&self, prefix: &str) -> Result<Vec<String>> {
+            Ok(self
+                .objects
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|k| k.starts_with(prefix))
+                .cloned()
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_object_store_storage_files() {
+        let store = Arc::new(MemObjectStore::default());
+        let local = MemStorage::default();
+        let s = ObjectStoreStorage::new(store.clone(), Arc::new(local.clone()), "bucket/");
+        s.mkdir_all("db").unwrap();
+        let mut f = s.create("db/000005.sst").unwrap();
+        f.write(b"hello ").unwrap();
+        f.write(b"object").unwrap();
+        assert!(!s.exists("db/000005.sst"));
+        f.sync().unwrap();
+        assert!(f.write(b"more").is_err());
+        assert!(s.exists("db/000005.sst"));
+        assert!(!local.exists("db/000005.sst"));
+
+        let f = s.open("db/000005.sst").unwrap();
+        assert_eq!(f.len().unwrap(), 12);
+        let mut buf = vec![0; 6];
+        f.read_exact_at(&mut buf, 6).unwrap();
+        assert_eq!(buf.as_slice(), b"object");
+        assert!(f.read_exact_at(&mut buf, 10).is_err());
+
+        // the other files stay local
+        s.create("db/MANIFEST-000001").unwrap().write(b"m").unwrap();
+        s.create("db/000006.log").unwrap().write(b"l").unwrap();
+        assert!(local.exists("db/MANIFEST-000001") && local.exists("db/000006.log"));
+        let mut list = s.list("db").unwrap();
+        list.sort();
+        assert_eq!(
+            list,
+            vec![
+                PathBuf::from("db/000005.sst"),
+                PathBuf::from("db/000006.log"),
+                PathBuf::from("db/MANIFEST-000001"),
+            ]
+        );
+
+        s.rename("db/000005.sst", "db/000007.sst").unwrap();
+        assert!(!s.exists("db/000005.sst"));
+        let mut data = vec![];
+        s.open("db/000007.sst")
+            .unwrap()
+            .read_all(&mut data)
+            .unwrap();
+        assert_eq!(data.as_slice(), b"hello object");
+        s.remove_dir("db", true).unwrap();
+        assert!(store.list("bucket/").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_object_store_storage_with_db() {
+        let store = Arc::new(MemObjectStore::default());
+        let local = MemStorage::default();
+        let naming = TableFileNaming {
+            extension: "ldb".to_owned(),
+            ..TableFileNaming::default()
+        };
+        let env: Arc<dyn Storage> = Arc::new(
+            ObjectStoreStorage::new(store.clone(), Arc::new(local.clone()), "bucket/")
+                .with_table_file_naming(naming.clone()),
+        );
+        let new_options = || {
+            let mut options = Options::default();
+            options.env = env.clone();
+            options.write_buffer_size = 64 << 10;
+            options.table_file_naming = naming.clone();
+            options
+        };
+        let name = "test_object_store_storage_with_db".to_owned();
+        let mut db = WickDB::open_db(new_options(), name.clone()).unwrap();
+        let value = vec![b'v'; 1000];
+        for i in 0..500 {
+            let key = format!("key{:03}", i);
+            db.put(
+                WriteOptions::default(),
+                Slice::from(key.as_bytes()),
+                Slice::from(value.as_slice()),
+            )
+            .unwrap();
+        }
+        db.close().unwrap();
+
+        WickDB::compact_offline(new_options(), name.clone()).unwrap();
+        let tables = store.list("bucket/").unwrap();
+        assert!(!tables.is_empty());
+        assert!(tables.iter().all(|key| key.ends_with(".ldb")));
+        // no table is left in the local storage
+        for path in local.list(name.as_str()).unwrap() {
+            assert!(naming.parse_table_number(&path).is_none());
+        }
+        let db = WickDB::open_db(new_options(), name.clone()).unwrap();
+        let gets = store.ranged_gets.load(Ordering::SeqCst);
+        for i in 0..500 {
+            let key = format!("key{:03}", i);
+            let got = db
+                .get(ReadOptions::default(), Slice::from(key.as_bytes()))
+                .unwrap();
+            assert_eq!(got.as_deref(), Some(value.as_slice()));
+        }
+        assert!(store.ranged_gets.load(Ordering::SeqCst) > gets);
+    }
+}
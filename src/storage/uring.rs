@@ -0,0 +1,611 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::storage::file::FileStorage;
+use crate::storage::{AsyncFile, File, FileFuture, LockKind, ReadRequest, Storage};
+use crate::util::status::{Result, Status, WickErr};
+use fs2::FileExt;
+use hashbrown::HashMap;
+use io_uring::{opcode, squeue, types, IoUring};
+use std::fs::{File as SysFile, OpenOptions};
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+
+/// The default number of the submission queue entries of a ring
+pub const DEFAULT_RING_ENTRIES: u32 = 256;
+
+// The user data of the `Nop` waking the reaper up to stop
+const SHUTDOWN_TOKEN: u64 = u64::MAX;
+
+// The state of a submitted operation
+#[derive(Default)]
+struct Slot {
+    // the result of the completion, which is a negative errno on failure
+    result: Option<i32>,
+    // wakes the future polling the operation
+    waker: Option<Waker>,
+    // The buffer of an async operation. It's owned by the slot until the
+    // completion so that dropping the future never frees the memory being
+    // written by the kernel.
+    buf: Option<Vec<u8>>,
+    // the future has been dropped and the reaper should release the slot
+    abandoned: bool,
+}
+
+struct RingInner {
+    ring: IoUring,
+    // serializes the pushes into the submission queue
+    sq_lock: Mutex<()>,
+    slots: Mutex<HashMap<u64, Slot>>,
+    // notifies the blocking operations of the completions
+    completed: Condvar,
+    next_token: AtomicU64,
+    shutting_down: AtomicBool,
+}
+
+impl RingInner {
+    fn push(&self, entry: squeue::Entry) -> io::Result<()> {
+        let _guard = self.sq_lock.lock().unwrap();
+        loop {
+            // The submission queue is only touched under `sq_lock`
+            let pushed = unsafe { self.ring.submission_shared().push(&entry).is_ok() };
+            if pushed {
+                break;
+            }
+            // The queue is full, hand the entries over to the kernel first
+            self.ring.submit()?;
+        }
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    // Reaps the completions until shutting down and no operation is in flight
+    fn reap(&self) {
+        loop {
+            if let Err(e) = self.ring.submit_and_wait(1) {
+                if e.raw_os_error() != Some(libc::EINTR) && e.raw_os_error() != Some(libc::EBUSY) {
+                    self.fail_all(e);
+                    return;
+                }
+            }
+            let mut slots = self.slots.lock().unwrap();
+            // The completion queue is only touched by the reaper
+            for cqe in unsafe { self.ring.completion_shared() } {
+                let token = cqe.user_data();
+                if token == SHUTDOWN_TOKEN {
+                    continue;
+                }
+                let abandoned = match slots.get_mut(&token) {
+                    Some(slot) => {
+                        slot.result = Some(cqe.result());
+                        if let Some(waker) = slot.waker.take() {
+                            waker.wake();
+                        }
+                        slot.abandoned
+                    }
+                    None => false,
+                };
+                if abandoned {
+                    slots.remove(&token);
+                }
+            }
+            self.completed.notify_all();
+            if self.shutting_down.load(Ordering::Acquire) && slots.is_empty() {
+                return;
+            }
+        }
+    }
+
+    // Fails all the operations in flight if the ring is broken
+    fn fail_all(&self, e: io::Error) {
+        error!("io_uring reaper stopped: {:?}", e);
+        let errno = e.raw_os_error().unwrap_or(libc::EIO);
+        let mut slots = self.slots.lock().unwrap();
+        slots.retain(|_, slot| !slot.abandoned);
+        for slot in slots.values_mut() {
+            slot.result = Some(-errno);
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        }
+        self.completed.notify_all();
+    }
+}
+
+/// An io_uring instance shared by the files of a `UringStorage`.
+///
+/// The operations are pushed into the submission queue by the calling threads
+/// and the completions are reaped by a background thread, which wakes up the
+/// blocked callers of `File` and the futures of `AsyncFile`.
+pub struct Ring {
+    inner: Arc<RingInner>,
+    reaper: Option<JoinHandle<()>>,
+}
+
+impl Ring {
+    /// Creates a ring with `entries` submission queue entries
+    pub fn new(entries: u32) -> Result<Self> {
+        let ring = w_io_result!(IoUring::new(entries))?;
+        let inner = Arc::new(RingInner {
+            ring,
+            sq_lock: Mutex::new(()),
+            slots: Mutex::new(HashMap::new()),
+            completed: Condvar::new(),
+            next_token: AtomicU64::new(0),
+            shutting_down: AtomicBool::new(false),
+        });
+        let r = inner.clone();
+        let reaper = w_io_result!(thread::Builder::new()
+            .name("wickdb-uring".to_owned())
+            .spawn(move || r.reap()))?;
+        Ok(Self {
+            inner,
+            reaper: Some(reaper),
+        })
+    }
+
+    // Submits the operation of `entry`. `buf` is the buffer owned by an async
+    // operation, which must be the one `entry` points to.
+    fn submit(&self, entry: squeue::Entry, buf: Option<Vec<u8>>) -> Result<u64> {
+        let token = self.inner.next_token.fetch_add(1, Ordering::Relaxed);
+        self.inner.slots.lock().unwrap().insert(
+            token,
+            Slot {
+                buf,
+                ..Slot::default()
+            },
+        );
+        if let Err(e) = self.inner.push(entry.user_data(token)) {
+            self.inner.slots.lock().unwrap().remove(&token);
+            return Err(WickErr::new_from_raw(Status::IOError, None, Box::new(e)));
+        }
+        Ok(token)
+    }
+
+    // Blocks until the operation `token` completes
+    fn wait(&self, token: u64) -> Result<usize> {
+        let mut slots = self.inner.slots.lock().unwrap();
+        loop {
+            if let Some(res) = slots.get(&token).and_then(|s| s.result) {
+                slots.remove(&token);
+                return to_result(res);
+            }
+            slots = self.inner.completed.wait(slots).unwrap();
+        }
+    }
+
+    // Submits the operation and blocks until it completes. The memory pointed by
+    // `entry` must be alive until then, which is guaranteed by the caller blocking.
+    fn run(&self, entry: squeue::Entry) -> Result<usize> {
+        let token = self.submit(entry, None)?;
+        self.wait(token)
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        self.inner.shutting_down.store(true, Ordering::Release);
+        let nop = opcode::Nop::new().build().user_data(SHUTDOWN_TOKEN);
+        if self.inner.push(nop).is_ok() {
+            if let Some(reaper) = self.reaper.take() {
+                let _ = reaper.join();
+            }
+        }
+    }
+}
+
+fn to_result(res: i32) -> Result<usize> {
+    if res < 0 {
+        let e = io::Error::from_raw_os_error(-res);
+        Err(WickErr::new_from_raw(Status::IOError, None, Box::new(e)))
+    } else {
+        Ok(res as usize)
+    }
+}
+
+// Resolves to the result and the owned buffer of an async operation
+struct Completion {
+    ring: Arc<Ring>,
+    token: u64,
+    done: bool,
+}
+
+impl Future for Completion {
+    type Output = (Result<usize>, Option<Vec<u8>>);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let ring = self.ring.clone();
+        let mut slots = ring.inner.slots.lock().unwrap();
+        let slot = slots.get_mut(&self.token).unwrap();
+        if slot.result.is_none() {
+            slot.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let slot = slots.remove(&self.token).unwrap();
+        self.done = true;
+        Poll::Ready((to_result(slot.result.unwrap()), slot.buf))
+    }
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let mut slots = self.ring.inner.slots.lock().unwrap();
+        let completed = match slots.get_mut(&self.token) {
+            Some(slot) => {
+                slot.abandoned = true;
+                slot.result.is_some()
+            }
+            None => false,
+        };
+        if completed {
+            slots.remove(&self.token);
+        }
+    }
+}
+
+/// A `File` whose reads, writes and syncs are submitted through a `Ring`.
+/// It also implements `AsyncFile` by the completion driven futures.
+pub struct UringFile {
+    file: SysFile,
+    ring: Arc<Ring>,
+    // the cursor of `read`, `write` and `seek`
+    pos: u64,
+}
+
+impl UringFile {
+    pub fn new(file: SysFile, ring: Arc<Ring>) -> Self {
+        Self { file, ring, pos: 0 }
+    }
+
+    #[inline]
+    fn fd(&self) -> types::Fd {
+        types::Fd(self.file.as_raw_fd())
+    }
+
+    fn write_all_at(&self, mut buf: &[u8], mut offset: u64) -> Result<()> {
+        while !buf.is_empty() {
+            let entry = opcode::Write::new(self.fd(), buf.as_ptr(), buf.len() as u32)
+                .offset(offset)
+                .build();
+            let n = self.ring.run(entry)?;
+            if n == 0 {
+                let e = io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer");
+                return Err(WickErr::new_from_raw(Status::IOError, None, Box::new(e)));
+            }
+            buf = &buf[n..];
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    // Submits an async operation over the owned `buf`
+    fn submit_owned(&self, entry: squeue::Entry, buf: Vec<u8>) -> Result<Completion> {
+        let token = self.ring.submit(entry, Some(buf))?;
+        Ok(Completion {
+            ring: self.ring.clone(),
+            token,
+            done: false,
+        })
+    }
+}
+
+impl File for UringFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.write_all_at(buf, self.pos)?;
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let entry = opcode::Fsync::new(self.fd()).build();
+        self.ring.run(entry).map(|_| ())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let len = File::len(self)? as i64;
+        let new_pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::End(p) => len + p,
+            SeekFrom::Current(p) => self.pos as i64 + p,
+        };
+        if new_pos < 0 {
+            return Err(WickErr::new(
+                Status::InvalidArgument,
+                Some("invalid seek to a negative position"),
+            ));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = File::read_at(self, buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn read_all(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        let len = File::len(self)?;
+        if self.pos >= len {
+            return Ok(0);
+        }
+        let start = buf.len();
+        let n = (len - self.pos) as usize;
+        buf.resize(start + n, 0);
+        self.read_exact_at(&mut buf[start..], self.pos)?;
+        self.pos = len;
+        Ok(n)
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(w_io_result!(self.file.metadata())?.len())
+    }
+
+    fn lock(&self) -> Result<()> {
+        w_io_result!(self.file.try_lock_exclusive())
+    }
+
+    fn unlock(&self) -> Result<()> {
+        w_io_result!(FileExt::unlock(&self.file))
+    }
+
+    fn lock_region(&self, offset: u64, len: u64, kind: LockKind) -> Result<()> {
+        File::lock_region(&self.file, offset, len, kind)
+    }
+
+    fn unlock_region(&self, offset: u64, len: u64) -> Result<()> {
+        File::unlock_region(&self.file, offset, len)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let entry = opcode::Read::new(self.fd(), buf.as_mut_ptr(), buf.len() as u32)
+            .offset(offset)
+            .build();
+        self.ring.run(entry)
+    }
+
+    // All the reads are submitted before waiting for any of them
+    fn read_exact_at_vectored(&self, requests: &mut [ReadRequest]) -> Result<()> {
+        let mut tokens = Vec::with_capacity(requests.len());
+        let mut status = Ok(());
+        for req in requests.iter_mut() {
+            let entry = opcode::Read::new(self.fd(), req.buf.as_mut_ptr(), req.buf.len() as u32)
+                .offset(req.offset)
+                .build();
+            match self.ring.submit(entry, None) {
+                Ok(token) => tokens.push(token),
+                Err(e) => {
+                    status = Err(e);
+                    break;
+                }
+            }
+        }
+        // Every submitted read must complete before the buffers are released
+        let results: Vec<Result<usize>> = tokens.into_iter().map(|t| self.ring.wait(t)).collect();
+        status?;
+        for (req, res) in requests.iter_mut().zip(results) {
+            let n = res?;
+            if n < req.buf.len() {
+                self.read_exact_at(&mut req.buf[n..], req.offset + n as u64)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsyncFile for UringFile {
+    fn read_at<'a>(&'a self, buf: &'a mut [u8], offset: u64) -> FileFuture<'a, usize> {
+        Box::pin(async move {
+            let mut owned = vec![0; buf.len()];
+            let entry = opcode::Read::new(self.fd(), owned.as_mut_ptr(), owned.len() as u32)
+                .offset(offset)
+                .build();
+            let (res, owned) = self.submit_owned(entry, owned)?.await;
+            let n = res?;
+            buf[..n].copy_from_slice(&owned.unwrap()[..n]);
+            Ok(n)
+        })
+    }
+
+    fn write_at<'a>(&'a self, buf: &'a [u8], offset: u64) -> FileFuture<'a, usize> {
+        Box::pin(async move {
+            let owned = buf.to_vec();
+            let entry = opcode::Write::new(self.fd(), owned.as_ptr(), owned.len() as u32)
+                .offset(offset)
+                .build();
+            self.submit_owned(entry, owned)?.await.0
+        })
+    }
+
+    fn sync(&self) -> FileFuture<'_, ()> {
+        Box::pin(async move {
+            let entry = opcode::Fsync::new(self.fd()).build();
+            self.submit_owned(entry, vec![])?.await.0.map(|_| ())
+        })
+    }
+
+    fn len(&self) -> FileFuture<'_, u64> {
+        Box::pin(async move { File::len(self) })
+    }
+}
+
+/// A `Storage` on the local file system whose files submit the block reads and
+/// the WAL writes through a shared io_uring `Ring`. The namespace operations are
+/// the same as `FileStorage`.
+pub struct UringStorage {
+    ring: Arc<Ring>,
+}
+
+impl UringStorage {
+    /// Creates a `UringStorage` with a ring of `DEFAULT_RING_ENTRIES`. Errors if
+    /// io_uring is unavailable, like on the kernels older than 5.6.
+    pub fn new() -> Result<Self> {
+        Self::with_ring_entries(DEFAULT_RING_ENTRIES)
+    }
+
+    /// Creates a `UringStorage` with a ring of the given submission queue entries
+    pub fn with_ring_entries(entries: u32) -> Result<Self> {
+        Ok(Self {
+            ring: Arc::new(Ring::new(entries)?),
+        })
+    }
+}
+
+impl Storage for UringStorage {
+    fn create(&self, name: &str) -> Result<Box<dyn File>> {
+        let file = w_io_result!(OpenOptions::new()
+            .write(true)
+            .read(true)
+            .create(true)
+            .truncate(true)
+            .open(name))?;
+        Ok(Box::new(UringFile::new(file, self.ring.clone())))
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn File>> {
+        let file = w_io_result!(OpenOptions::new().write(true).read(true).open(name))?;
+        Ok(Box::new(UringFile::new(file, self.ring.clone())))
+    }
+
+    fn open_async(&self, name: &str) -> Result<Box<dyn AsyncFile>> {
+        let file = w_io_result!(OpenOptions::new().write(true).read(true).open(name))?;
+        Ok(Box::new(UringFile::new(file, self.ring.clone())))
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        FileStorage.remove(name)
+    }
+
+    fn remove_dir(&self, dir: &str, recursively: bool) -> Result<()> {
+        FileStorage.remove_dir(dir, recursively)
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        FileStorage.exists(name)
+    }
+
+    fn rename(&self, old: &str, new: &str) -> Result<()> {
+        FileStorage.rename(old, new)
+    }
+
+    fn mkdir_all(&self, dir: &str) -> Result<()> {
+        FileStorage.mkdir_all(dir)
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<PathBuf>> {
+        FileStorage.list(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::read_exact_at_async;
+    use std::fs::remove_file;
+    use std::task::{RawWaker, RawWakerVTable};
+
+    // A waker unparking the thread `t` points to, which is an `Arc<Thread>`
+    fn thread_raw_waker(t: *const ()) -> RawWaker {
+        fn clone(t: *const ()) -> RawWaker {
+            let t = unsafe { Arc::from_raw(t as *const thread::Thread) };
+            let cloned = Arc::into_raw(t.clone());
+            std::mem::forget(t);
+            thread_raw_waker(cloned as *const ())
+        }
+        fn wake(t: *const ()) {
+            unsafe { Arc::from_raw(t as *const thread::Thread) }.unpark();
+        }
+        fn wake_by_ref(t: *const ()) {
+            unsafe { &*(t as *const thread::Thread) }.unpark();
+        }
+        fn drop(t: *const ()) {
+            unsafe { Arc::from_raw(t as *const thread::Thread) };
+        }
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
+        RawWaker::new(t, &VTABLE)
+    }
+
+    // Blocks the current thread until the future is ready
+    fn block_on<T>(mut f: Pin<Box<dyn Future<Output = T> + '_>>) -> T {
+        let t = Arc::into_raw(Arc::new(thread::current())) as *const ();
+        let waker = unsafe { Waker::from_raw(thread_raw_waker(t)) };
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match f.as_mut().poll(&mut cx) {
+                Poll::Ready(v) => return v,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_uring_file() {
+        let s = match UringStorage::new() {
+            Ok(s) => s,
+            // io_uring is unavailable in this environment
+            Err(_) => return,
+        };
+        let name = "test_uring_file";
+        let mut f = s.create(name).unwrap();
+        f.write(b"hello ").unwrap();
+        f.write(b"world").unwrap();
+        f.sync().unwrap();
+        assert_eq!(f.len().unwrap(), 11);
+        let mut buf = vec![0; 5];
+        f.read_exact_at(buf.as_mut_slice(), 6).unwrap();
+        assert_eq!(buf.as_slice(), b"world");
+        let (mut a, mut b) = (vec![0; 5], vec![0; 3]);
+        let mut requests = vec![ReadRequest::new(0, &mut a), ReadRequest::new(8, &mut b)];
+        f.read_exact_at_vectored(&mut requests).unwrap();
+        assert_eq!((a.as_slice(), b.as_slice()), (&b"hello"[..], &b"rld"[..]));
+        f.seek(SeekFrom::Start(0)).unwrap();
+        let mut all = vec![];
+        assert_eq!(f.read_all(&mut all).unwrap(), 11);
+        assert_eq!(all.as_slice(), b"hello world");
+
+        let af = s.open_async(name).unwrap();
+        assert_eq!(block_on(af.write_at(b"W", 6)).unwrap(), 1);
+        block_on(af.sync()).unwrap();
+        let mut buf = vec![0; 11];
+        block_on(Box::pin(read_exact_at_async(af.as_ref(), &mut buf, 0))).unwrap();
+        assert_eq!(buf.as_slice(), b"hello World");
+        assert_eq!(block_on(af.read_at(&mut buf, 11)).unwrap(), 0);
+        // Dropping a submitted future never breaks the later operations
+        {
+            let t = Arc::into_raw(Arc::new(thread::current())) as *const ();
+            let waker = unsafe { Waker::from_raw(thread_raw_waker(t)) };
+            let mut f = af.read_at(&mut buf, 0);
+            let _ = f.as_mut().poll(&mut Context::from_waker(&waker));
+        }
+        assert_eq!(block_on(af.len()).unwrap(), 11);
+        remove_file(name).unwrap();
+    }
+}
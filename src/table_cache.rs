@@ -16,61 +16,425 @@
 // found in the LICENSE file.
 
 use crate::cache::lru::SharedLRUCache;
-use crate::cache::{Cache, HandleRef};
-use crate::db::filename::{generate_filename, FileType};
-use crate::iterator::{EmptyIterator, IterWithCleanup, Iterator};
+use crate::cache::Cache;
+use crate::db::format::{InternalKeyComparator, ParsedInternalKey, ValueType};
+use crate::iterator::{EmptyIterator, Iterator};
 use crate::options::{Options, ReadOptions};
-use crate::sstable::table::{new_table_iterator, Table};
+use crate::sstable::properties::TableProperties;
+use crate::sstable::table::{new_table_iterator, Table, TableSeekReport};
+use crate::sstable::BlockHandle;
+use crate::statistics::{record_tick, Ticker};
 use crate::storage::Storage;
+use crate::util::histogram::Histogram;
 use crate::util::slice::Slice;
-use crate::util::status::Result;
+use crate::util::status::{Result, Status, WickErr};
 use crate::util::varint::VarintU64;
+use std::collections::HashMap;
+use std::hint::spin_loop;
+use std::mem;
+use std::ptr;
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
-/// A `TableCache` is the cache for the sst files and the sstable in them
+/// The deletions in a table file, see `TableCache::tombstone_stats`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TableTombstoneStats {
+    pub num_entries: u64,
+    pub num_deletions: u64,
+    // The smallest and the largest user keys of the deletions
+    pub deleted_range: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+// The times a writer spins for the readers to leave a slot before deferring the
+// drop of the table swapped out
+const RELEASE_SPINS: usize = 1 << 10;
+
+// A slot of the lock-free index over the open tables. The readers only count
+// themselves in `readers` while cloning the table so a hit never blocks. A writer
+// waits a bounded time for the readers to leave before it drops the table
+// swapped out, otherwise the table is deferred and dropped by whoever sees the
+// slot without readers later, so a slot under a steady stream of readers never
+// holds up the writer.
+struct TableSlot {
+    // the pointer from `Arc::into_raw` or null
+    table: AtomicPtr<Table>,
+    readers: AtomicUsize,
+    // the tables swapped out while some readers were in the slot
+    deferred: Mutex<Vec<Arc<Table>>>,
+    has_deferred: AtomicBool,
+}
+
+impl TableSlot {
+    fn new() -> Self {
+        Self {
+            table: AtomicPtr::new(ptr::null_mut()),
+            readers: AtomicUsize::new(0),
+            deferred: Mutex::new(vec![]),
+            has_deferred: AtomicBool::new(false),
+        }
+    }
+
+    // Returns the table in the slot if it's the table `file_number`
+    fn load(&self, file_number: u64) -> Option<Arc<Table>> {
+        self.readers.fetch_add(1, Ordering::SeqCst);
+        let p = self.table.load(Ordering::SeqCst);
+        let res = if !p.is_null() && unsafe { (*p).file_number() } == file_number {
+            unsafe {
+                Arc::increment_strong_count(p);
+                Some(Arc::from_raw(p))
+            }
+        } else {
+            None
+        };
+        // the last reader leaving drops the deferred tables
+        if self.readers.fetch_sub(1, Ordering::SeqCst) == 1
+            && self.has_deferred.load(Ordering::SeqCst)
+        {
+            self.drop_deferred();
+        }
+        res
+    }
+
+    fn store(&self, table: Arc<Table>) {
+        let old = self
+            .table
+            .swap(Arc::into_raw(table) as *mut Table, Ordering::SeqCst);
+        self.release(old);
+    }
+
+    // Empties the slot if it holds `table`
+    fn clear(&self, table: &Arc<Table>) {
+        let p = Arc::as_ptr(table) as *mut Table;
+        if self
+            .table
+            .compare_exchange(p, ptr::null_mut(), Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            self.release(p);
+        }
+    }
+
+    // Drops the table swapped out once no reader could be cloning it, or defers
+    // it if the readers don't leave in time
+    fn release(&self, p: *mut Table) {
+        if p.is_null() {
+            return;
+        }
+        let table = unsafe { Arc::from_raw(p) };
+        for _ in 0..RELEASE_SPINS {
+            if self.readers.load(Ordering::SeqCst) == 0 {
+                drop(table);
+                return;
+            }
+            spin_loop();
+        }
+        {
+            let mut deferred = self.deferred.lock().unwrap();
+            deferred.push(table);
+            self.has_deferred.store(true, Ordering::SeqCst);
+        }
+        // the last reader may have left before seeing the deferred table
+        if self.readers.load(Ordering::SeqCst) == 0 {
+            self.drop_deferred();
+        }
+    }
+
+    // Drops the deferred tables if there is no reader in the slot. They are all
+    // swapped out before being deferred, so the readers coming later never see
+    // them.
+    fn drop_deferred(&self) {
+        let tables = {
+            let mut deferred = self.deferred.lock().unwrap();
+            if self.readers.load(Ordering::SeqCst) != 0 {
+                return;
+            }
+            self.has_deferred.store(false, Ordering::SeqCst);
+            mem::take(&mut *deferred)
+        };
+        drop(tables);
+    }
+}
+
+impl Drop for TableSlot {
+    fn drop(&mut self) {
+        let p = *self.table.get_mut();
+        if !p.is_null() {
+            unsafe { drop(Arc::from_raw(p)) }
+        }
+    }
+}
+
+/// A `TableCache` is the cache for the sst files and the sstable in them.
+///
+/// The tables in the cache are also indexed by file number in a set of slots which
+/// is searched without any lock, so the concurrent reads hitting the open tables
+/// don't serialize on the mutexes of the cache. A hit in the slots doesn't refresh
+/// the table in the LRU list, so the tables are evicted roughly in the order they
+/// were opened and then reopened on the next miss. The slot of a table is emptied
+/// when it's evicted from the cache.
 pub struct TableCache {
     env: Arc<dyn Storage>,
     db_name: String,
     options: Arc<Options>,
+    // the tables contain internal keys
+    icmp: Arc<InternalKeyComparator>,
     // the key of cache is the file number
     cache: Arc<dyn Cache<Arc<Table>>>,
+    // the tables in `cache` indexed by `file_number & (slots.len() - 1)`
+    slots: Arc<Vec<TableSlot>>,
+    // the paths of the tables not named by `options.table_file_naming`
+    relocated: Mutex<HashMap<u64, String>>,
+    // the deletions counted in the tables, which never change
+    tombstone_stats: Mutex<HashMap<u64, TableTombstoneStats>>,
+    // the entries per byte sampled from the tables
+    entry_densities: Mutex<HashMap<u64, f64>>,
+    // the key and value size histograms recorded in the tables
+    kv_size_histograms: Mutex<HashMap<u64, Option<(Histogram, Histogram)>>>,
+    // the properties of the tables
+    properties: Mutex<HashMap<u64, TableProperties>>,
 }
 
 impl TableCache {
     pub fn new(db_name: String, options: Arc<Options>, size: usize) -> Self {
         let cache = Arc::new(SharedLRUCache::<Arc<Table>>::new(size));
+        let mut slots = vec![];
+        slots.resize_with(size.max(1).next_power_of_two(), TableSlot::new);
         Self {
             env: options.env.clone(),
             db_name,
+            icmp: Arc::new(InternalKeyComparator::new(options.comparator.clone())),
             options,
             cache,
+            slots: Arc::new(slots),
+            relocated: Mutex::new(HashMap::new()),
+            tombstone_stats: Mutex::new(HashMap::new()),
+            entry_densities: Mutex::new(HashMap::new()),
+            kv_size_histograms: Mutex::new(HashMap::new()),
+            properties: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the path of the table file `file_number`
+    pub fn table_file_name(&self, file_number: u64) -> String {
+        match self.relocated.lock().unwrap().get(&file_number) {
+            Some(path) => path.clone(),
+            None => self
+                .options
+                .table_file_naming
+                .table_file_name(self.db_name.as_str(), file_number),
         }
     }
 
+    /// Records the path of a table file named by another naming scheme
+    pub fn relocate(&self, file_number: u64, path: String) {
+        self.relocated.lock().unwrap().insert(file_number, path);
+    }
+
+    fn slot(&self, file_number: u64) -> &TableSlot {
+        &self.slots[file_number as usize & (self.slots.len() - 1)]
+    }
+
     // Try to find the sst file from cache. If not found, try to find the file from storage and insert it into the cache
-    fn find_table(&self, file_number: u64, file_size: u64) -> Result<HandleRef<Arc<Table>>> {
+    pub(crate) fn find_table(&self, file_number: u64, file_size: u64) -> Result<Arc<Table>> {
+        let slot = self.slot(file_number);
+        if let Some(table) = slot.load(file_number) {
+            return Ok(table);
+        }
         let mut key = vec![];
         VarintU64::put_varint(&mut key, file_number);
-        match self.cache.look_up(key.as_slice()) {
-            Some(handle) => Ok(handle),
+        let handle = match self.cache.look_up(key.as_slice()) {
+            Some(handle) => handle,
             None => {
-                let filename =
-                    generate_filename(self.db_name.as_str(), FileType::Table, file_number);
-                let table_file = self.env.open(filename.as_str())?;
-                let table = Table::open(table_file, file_size, self.options.clone())?;
-                Ok(self.cache.insert(key, Arc::new(table), 1, None))
+                let filename = self.table_file_name(file_number);
+                let table_file = if self.options.use_mmap_reads {
+                    self.env.open_mmap(filename.as_str())?
+                } else {
+                    self.env.open(filename.as_str())?
+                };
+                let table = Table::open_with_comparator(
+                    table_file,
+                    file_size,
+                    self.options.clone(),
+                    self.icmp.clone(),
+                )
+                .map(|t| t.with_file_number(file_number))
+                .map_err(|e| e.with_corrupted_file(filename.as_str()))?;
+                let slots = self.slots.clone();
+                let deleter = Box::new(move |_: &[u8], table: Arc<Table>| {
+                    slots[table.file_number() as usize & (slots.len() - 1)].clear(&table)
+                });
+                self.cache.insert(key, Arc::new(table), 1, Some(deleter))
             }
-        }
+        };
+        // every value should be valid so unwrap is safe here
+        let table = handle.value().unwrap();
+        // The table is published before the handle is released so that the deleter
+        // always runs after it once the table is evicted
+        slot.store(table.clone());
+        self.cache.release(handle);
+        Ok(table)
     }
 
-    /// Evict any entry for the specified file number
+    /// Evict any entry for the specified file number. This is called when
+    /// the file is deleted.
+    ///
+    /// The data blocks of the table are erased from the block cache as well if the
+    /// table is open, which frees the capacity for the live tables at once instead
+    /// of waiting for the blocks to be evicted by the LRU. The blocks of a table
+    /// not open are left since it can't be told which of them are cached.
     pub fn evict(&self, file_number: u64) {
+        let table = self.slot(file_number).load(file_number).or_else(|| {
+            let mut key = vec![];
+            VarintU64::put_varint(&mut key, file_number);
+            let handle = self.cache.look_up(key.as_slice())?;
+            let table = handle.value().unwrap().clone();
+            self.cache.release(handle);
+            Some(table)
+        });
+        if let Some(table) = table {
+            match table.erase_cached_blocks() {
+                Ok(erased) => record_tick(
+                    &self.options.statistics,
+                    Ticker::BlockCacheEraseObsolete,
+                    erased,
+                ),
+                Err(e) => warn!(
+                    "[table cache] Fail erasing the blocks of table #{}: {}",
+                    file_number, e
+                ),
+            }
+        }
+        self.relocated.lock().unwrap().remove(&file_number);
+        self.tombstone_stats.lock().unwrap().remove(&file_number);
+        self.entry_densities.lock().unwrap().remove(&file_number);
+        self.kv_size_histograms.lock().unwrap().remove(&file_number);
+        self.properties.lock().unwrap().remove(&file_number);
         let mut key = vec![];
         VarintU64::put_varint(&mut key, file_number);
         self.cache.erase(key.as_slice());
     }
 
+    /// Returns the seek telemetry of the table `file_number` if it's in the cache.
+    /// The telemetry is lost once the table is evicted.
+    pub fn seek_report(&self, file_number: u64) -> Option<TableSeekReport> {
+        if let Some(table) = self.slot(file_number).load(file_number) {
+            return Some(table.seek_report());
+        }
+        let mut key = vec![];
+        VarintU64::put_varint(&mut key, file_number);
+        let handle = self.cache.look_up(key.as_slice())?;
+        let report = handle.value().unwrap().seek_report();
+        self.cache.release(handle);
+        Some(report)
+    }
+
+    /// Returns the deletions in the table `file_number`. The table is scanned without
+    /// filling the block cache on the first call, and the result is kept until the
+    /// table is evicted.
+    pub fn tombstone_stats(&self, file_number: u64, file_size: u64) -> Result<TableTombstoneStats> {
+        if let Some(stats) = self.tombstone_stats.lock().unwrap().get(&file_number) {
+            return Ok(stats.clone());
+        }
+        let read_opt = ReadOptions {
+            fill_cache: false,
+            ..ReadOptions::default()
+        };
+        let mut iter = self.new_iter(Rc::new(read_opt), file_number, file_size);
+        let mut stats = TableTombstoneStats::default();
+        iter.seek_to_first();
+        while iter.valid() {
+            let key = match ParsedInternalKey::decode_from(iter.key()) {
+                Some(key) => key,
+                None => return Err(WickErr::new(Status::Corruption, Some("bad internal key"))),
+            };
+            stats.num_entries += 1;
+            if key.value_type == ValueType::Deletion {
+                stats.num_deletions += 1;
+                let ukey = key.user_key.as_slice().to_vec();
+                match stats.deleted_range.as_mut() {
+                    Some((_, largest)) => *largest = ukey,
+                    None => stats.deleted_range = Some((ukey.clone(), ukey)),
+                }
+            }
+            iter.next();
+        }
+        iter.status()?;
+        self.tombstone_stats
+            .lock()
+            .unwrap()
+            .insert(file_number, stats.clone());
+        Ok(stats)
+    }
+
+    /// Returns the handles of the data blocks in the table `file_number` with the
+    /// index keys bounding them, read from the index block only.
+    pub fn data_blocks(
+        &self,
+        file_number: u64,
+        file_size: u64,
+    ) -> Result<Vec<(BlockHandle, Vec<u8>)>> {
+        let res = self.find_table(file_number, file_size)?.data_blocks();
+        res.map_err(|e| e.with_corrupted_file(self.table_file_name(file_number).as_str()))
+    }
+
+    /// Opens the table `file_number` in the cache if it's not there, which loads its
+    /// index block and filter block
+    pub fn warm(&self, file_number: u64, file_size: u64) -> Result<()> {
+        self.find_table(file_number, file_size).map(|_| ())
+    }
+
+    /// Returns the histograms of the key sizes and the value sizes recorded in the table
+    /// `file_number`, or `None` if it doesn't record them. The result is kept until
+    /// the table is evicted.
+    pub fn kv_size_histograms(
+        &self,
+        file_number: u64,
+        file_size: u64,
+    ) -> Result<Option<(Histogram, Histogram)>> {
+        if let Some(histograms) = self.kv_size_histograms.lock().unwrap().get(&file_number) {
+            return Ok(histograms.clone());
+        }
+        let res = self
+            .find_table(file_number, file_size)?
+            .read_kv_size_histograms();
+        let histograms =
+            res.map_err(|e| e.with_corrupted_file(self.table_file_name(file_number).as_str()))?;
+        self.kv_size_histograms
+            .lock()
+            .unwrap()
+            .insert(file_number, histograms.clone());
+        Ok(histograms)
+    }
+
+    /// Returns the `TableProperties` of the table `file_number`. Only the entries of a
+    /// table without the properties block are counted by `tombstone_stats` instead.
+    /// The result is kept until the table is evicted.
+    pub fn properties(&self, file_number: u64, file_size: u64) -> Result<TableProperties> {
+        if let Some(properties) = self.properties.lock().unwrap().get(&file_number) {
+            return Ok(properties.clone());
+        }
+        let res = self.find_table(file_number, file_size)?.read_properties();
+        let properties = match res
+            .map_err(|e| e.with_corrupted_file(self.table_file_name(file_number).as_str()))?
+        {
+            Some(properties) => properties,
+            None => {
+                let stats = self.tombstone_stats(file_number, file_size)?;
+                TableProperties {
+                    num_entries: stats.num_entries,
+                    num_deletions: stats.num_deletions,
+                    ..TableProperties::default()
+                }
+            }
+        };
+        self.properties
+            .lock()
+            .unwrap()
+            .insert(file_number, properties.clone());
+        Ok(properties)
+    }
+
     /// Returns the result of a seek to internal key `key` in specified file
     pub fn get(
         &self,
@@ -78,20 +442,92 @@ impl TableCache {
         key: &Slice,
         file_number: u64,
         file_size: u64,
-    ) -> Result<Option<(Slice, Slice)>> {
-        let handle = self.find_table(file_number, file_size)?;
-        // every value should be valid so unwrap is safe here
-        let res = handle
-            .value()
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let res = self
+            .find_table(file_number, file_size)?
+            .internal_get(options, key.as_slice());
+        res.map_err(|e| e.with_corrupted_file(self.table_file_name(file_number).as_str()))
+    }
+
+    /// Returns the results of the seeks to internal keys `keys` in specified file,
+    /// or `None` if the filters of the file rule out all the keys
+    pub fn multi_get(
+        &self,
+        options: Rc<ReadOptions>,
+        keys: &[&[u8]],
+        file_number: u64,
+        file_size: u64,
+    ) -> Result<Option<Vec<Option<(Vec<u8>, Vec<u8>)>>>> {
+        let res = self
+            .find_table(file_number, file_size)?
+            .internal_multi_get(options, keys);
+        res.map_err(|e| e.with_corrupted_file(self.table_file_name(file_number).as_str()))
+    }
+
+    /// Returns the approximate bytes of the data blocks in the table `file_number` from
+    /// internal key `start` to `end`, where `None` stands for the boundary of the table,
+    /// along with the bytes of all its data blocks.
+    pub fn approximate_size(
+        &self,
+        file_number: u64,
+        file_size: u64,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<(u64, u64)> {
+        let table = self.find_table(file_number, file_size)?;
+        let data_size = table.data_end_offset();
+        let start_offset = start.map_or(0, |k| table.approximate_offset_of(k));
+        let end_offset = end.map_or(data_size, |k| table.approximate_offset_of(k));
+        Ok((end_offset.saturating_sub(start_offset), data_size))
+    }
+
+    /// Returns the approximate number of the entries in the table `file_number` from
+    /// internal key `start` to `end`, where `None` stands for the boundary of the table.
+    /// The number is interpolated by the offsets the keys fall into in the index
+    /// block and the density of the entries sampled from a data block, which is
+    /// kept until the table is evicted.
+    pub fn estimate_entries(
+        &self,
+        file_number: u64,
+        file_size: u64,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<u64> {
+        let table = self.find_table(file_number, file_size)?;
+        let cached = self
+            .entry_densities
+            .lock()
             .unwrap()
-            .internal_get(options, key.as_slice())?;
-        self.cache.release(handle);
-        Ok(res)
+            .get(&file_number)
+            .copied();
+        let density = match cached {
+            Some(density) => Ok(density),
+            None => {
+                let read_opt = ReadOptions {
+                    fill_cache: false,
+                    ..ReadOptions::default()
+                };
+                table.sample_entries_per_byte(Rc::new(read_opt))
+            }
+        };
+        let start_offset = start.map_or(0, |k| table.approximate_offset_of(k));
+        let end_offset = end.map_or_else(
+            || table.data_end_offset(),
+            |k| table.approximate_offset_of(k),
+        );
+        let density = density
+            .map_err(|e| e.with_corrupted_file(self.table_file_name(file_number).as_str()))?;
+        self.entry_densities
+            .lock()
+            .unwrap()
+            .insert(file_number, density);
+        Ok((end_offset.saturating_sub(start_offset) as f64 * density).round() as u64)
     }
 
     /// Create an iterator for the specified `file_number` (the corresponding
     /// file length must be exactly `file_size` bytes).
-    /// The table referenced by returning Iterator will be released after the Iterator is dropped.
+    /// The table referenced by returning Iterator is kept open until the Iterator is dropped
+    /// even if it's evicted.
     ///
     /// Entry format:
     ///     key: internal key
@@ -103,14 +539,138 @@ impl TableCache {
         file_size: u64,
     ) -> Box<dyn Iterator> {
         match self.find_table(file_number, file_size) {
-            Ok(h) => {
-                let table = h.value().unwrap();
-                let mut iter = IterWithCleanup::new(new_table_iterator(table, options));
-                let cache = self.cache.clone();
-                iter.register_task(Box::new(move || cache.release(h.clone())));
-                Box::new(iter)
-            }
+            Ok(table) => new_table_iterator(table, options),
             Err(e) => Box::new(EmptyIterator::new_with_err(e)),
         }
     }
+
+    /// Create an iterator for the compaction inputs. If
+    /// `use_direct_io_for_flush_and_compaction` is set, the table is opened by
+    /// `Storage::open_direct` out of the cache so that reading the inputs
+    /// never pollutes the OS page cache. Otherwise it's the same as `new_iter`.
+    pub fn new_compaction_iter(
+        &self,
+        options: Rc<ReadOptions>,
+        file_number: u64,
+        file_size: u64,
+    ) -> Box<dyn Iterator> {
+        if !self.options.use_direct_io_for_flush_and_compaction {
+            return self.new_iter(options, file_number, file_size);
+        }
+        let filename = self.table_file_name(file_number);
+        let table = self
+            .env
+            .open_direct(filename.as_str())
+            .and_then(|file| {
+                Table::open_with_comparator(
+                    file,
+                    file_size,
+                    self.options.clone(),
+                    self.icmp.clone(),
+                )
+                .map(|t| t.with_file_number(file_number))
+            })
+            .map_err(|e| e.with_corrupted_file(filename.as_str()));
+        match table {
+            Ok(t) => new_table_iterator(Arc::new(t), options),
+            Err(e) => Box::new(EmptyIterator::new_with_err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::format::InternalKey;
+    use crate::sstable::table::TableBuilder;
+    use crate::storage::mem::MemStorage;
+
+    fn new_table_cache(file_numbers: &[u64], size: usize) -> (TableCache, u64) {
+        let mut options = Options::default();
+        options.env = Arc::new(MemStorage::default());
+        options.env.mkdir_all("db").unwrap();
+        let options = Arc::new(options);
+        let table_cache = TableCache::new("db".to_owned(), options.clone(), size);
+        let mut file_size = 0;
+        for number in file_numbers {
+            let file = options
+                .env
+                .create(table_cache.table_file_name(*number).as_str())
+                .unwrap();
+            let mut builder = TableBuilder::new_with_comparator(
+                file,
+                options.clone(),
+                table_cache.icmp.clone(),
+                0,
+            );
+            let key = InternalKey::new(&Slice::from("key"), 1, ValueType::Value);
+            builder.add(key.data(), b"value").unwrap();
+            builder.finish(false).unwrap();
+            file_size = builder.file_size();
+        }
+        (table_cache, file_size)
+    }
+
+    #[test]
+    fn test_find_table_in_slots() {
+        let (table_cache, file_size) = new_table_cache(&[1, 2, 3], 2);
+        let t1 = table_cache.find_table(1, file_size).unwrap();
+        assert!(Arc::ptr_eq(
+            &t1,
+            &table_cache.find_table(1, file_size).unwrap()
+        ));
+        assert!(Arc::ptr_eq(&t1, &table_cache.slot(1).load(1).unwrap()));
+
+        // the table 3 takes the slot of the table 1 but both are still in the cache
+        let t3 = table_cache.find_table(3, file_size).unwrap();
+        assert!(table_cache.slot(1).load(1).is_none());
+        assert!(Arc::ptr_eq(
+            &t1,
+            &table_cache.find_table(1, file_size).unwrap()
+        ));
+        assert!(Arc::ptr_eq(
+            &t3,
+            &table_cache.find_table(3, file_size).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_evict_clears_slot() {
+        let (table_cache, file_size) = new_table_cache(&[1], 10);
+        let t1 = table_cache.find_table(1, file_size).unwrap();
+        table_cache.evict(1);
+        assert!(table_cache.slot(1).load(1).is_none());
+        assert!(table_cache.seek_report(1).is_none());
+        // the evicted table is still readable by the holder
+        let key = InternalKey::new(&Slice::from("key"), 1, ValueType::Value);
+        let (_, value) = t1
+            .internal_get(Rc::new(ReadOptions::default()), key.data())
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, b"value".to_vec());
+        let reopened = table_cache.find_table(1, file_size).unwrap();
+        assert!(!Arc::ptr_eq(&t1, &reopened));
+    }
+
+    #[test]
+    fn test_release_deferred_by_readers() {
+        let (table_cache, file_size) = new_table_cache(&[1, 2], 10);
+        let t1 = table_cache.find_table(1, file_size).unwrap();
+        let t2 = table_cache.find_table(2, file_size).unwrap();
+        let slot = TableSlot::new();
+        slot.store(t1.clone());
+        // a reader staying in the slot never holds up the writer
+        slot.readers.fetch_add(1, Ordering::SeqCst);
+        slot.store(t2.clone());
+        assert_eq!(slot.deferred.lock().unwrap().len(), 1);
+        assert!(Arc::ptr_eq(&t2, &slot.load(2).unwrap()));
+        // held by this test, the table cache, its slot and the deferred list
+        let count = Arc::strong_count(&t1);
+        assert_eq!(count, 4);
+        // the last reader leaving drops the deferred table
+        slot.readers.fetch_sub(1, Ordering::SeqCst);
+        assert!(slot.load(1).is_none());
+        assert!(slot.deferred.lock().unwrap().is_empty());
+        assert_eq!(Arc::strong_count(&t1), count - 1);
+    }
 }
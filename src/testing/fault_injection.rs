@@ -0,0 +1,317 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Copyright (c) 2011 The LevelDB Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file. See the AUTHORS file for names of contributors.
+
+use crate::storage::{File, LockKind, ReadRequest, Storage};
+use crate::util::status::{Result, Status, WickErr};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+// The written and synced length of a file created by the storage
+#[derive(Clone, Copy, Default)]
+struct FileState {
+    pos: u64,
+    synced: u64,
+}
+
+/// A `Storage` wrapper which tracks the data not synced by `File::sync` and
+/// simulates a process crash by dropping them.
+///
+/// After `crash`, the storage and all the files opened through it refuse any
+/// modification just like the process has gone, while the returned storage
+/// serves the data surviving the crash.
+pub struct FaultInjectionStorage {
+    inner: Arc<dyn Storage>,
+    files: Arc<Mutex<HashMap<String, FileState>>>,
+    active: Arc<AtomicBool>,
+}
+
+impl FaultInjectionStorage {
+    pub fn new(inner: Arc<dyn Storage>) -> Self {
+        Self {
+            inner,
+            files: Arc::new(Mutex::new(HashMap::new())),
+            active: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// Simulates a crash. All the unsynced data of the files created by this
+    /// storage are dropped and this storage becomes inactive forever.
+    ///
+    /// Returns a new storage over the surviving files.
+    pub fn crash(&self) -> Result<FaultInjectionStorage> {
+        let mut files = self.files.lock().unwrap();
+        self.active.store(false, Ordering::Release);
+        for (name, state) in files.iter_mut() {
+            if state.synced < state.pos && self.inner.exists(name) {
+                let mut data = vec![0; state.synced as usize];
+                self.inner
+                    .open(name)?
+                    .read_exact_at(data.as_mut_slice(), 0)?;
+                let mut f = self.inner.create(name)?;
+                f.write(data.as_slice())?;
+                f.sync()?;
+                f.close()?;
+            }
+            state.pos = state.synced;
+        }
+        Ok(Self {
+            inner: self.inner.clone(),
+            files: Arc::new(Mutex::new(files.clone())),
+            active: Arc::new(AtomicBool::new(true)),
+        })
+    }
+
+    /// Returns true if `crash` has not been called and the storage is not
+    /// deactivated by `set_active`
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Makes the storage and all the files opened through it refuse any
+    /// modification like a crashed one, or accept them again. No data is
+    /// dropped, so it simulates a transient failure of the filesystem.
+    pub fn set_active(&self, active: bool) {
+        let _files = self.files.lock().unwrap();
+        self.active.store(active, Ordering::Release);
+    }
+
+    /// Returns the total bytes written but not synced in all files
+    pub fn unsynced_bytes(&self) -> u64 {
+        self.files
+            .lock()
+            .unwrap()
+            .values()
+            .map(|s| s.pos - s.synced)
+            .sum()
+    }
+
+    fn wrap(&self, name: &str, file: Box<dyn File>) -> Box<dyn File> {
+        Box::new(FaultInjectionFile {
+            name: name.to_owned(),
+            inner: file,
+            files: self.files.clone(),
+            active: self.active.clone(),
+        })
+    }
+}
+
+fn inactive_err() -> WickErr {
+    WickErr::new(Status::IOError, Some("fault injection storage has crashed"))
+}
+
+impl Storage for FaultInjectionStorage {
+    fn create(&self, name: &str) -> Result<Box<dyn File>> {
+        let mut files = self.files.lock().unwrap();
+        if !self.is_active() {
+            return Err(inactive_err());
+        }
+        let f = self.inner.create(name)?;
+        files.insert(name.to_owned(), FileState::default());
+        Ok(self.wrap(name, f))
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn File>> {
+        let mut files = self.files.lock().unwrap();
+        let f = self.inner.open(name)?;
+        if !files.contains_key(name) {
+            // the existing data are considered as synced
+            let len = f.len()?;
+            files.insert(
+                name.to_owned(),
+                FileState {
+                    pos: len,
+                    synced: len,
+                },
+            );
+        }
+        Ok(self.wrap(name, f))
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        if !self.is_active() {
+            return Err(inactive_err());
+        }
+        self.inner.remove(name)?;
+        files.remove(name);
+        Ok(())
+    }
+
+    fn remove_dir(&self, dir: &str, recursively: bool) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        if !self.is_active() {
+            return Err(inactive_err());
+        }
+        self.inner.remove_dir(dir, recursively)?;
+        if recursively {
+            files.retain(|name, _| !name.starts_with(dir));
+        }
+        Ok(())
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.inner.exists(name)
+    }
+
+    fn rename(&self, old: &str, new: &str) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        if !self.is_active() {
+            return Err(inactive_err());
+        }
+        self.inner.rename(old, new)?;
+        match files.remove(old) {
+            Some(state) => files.insert(new.to_owned(), state),
+            None => files.remove(new),
+        };
+        Ok(())
+    }
+
+    fn mkdir_all(&self, dir: &str) -> Result<()> {
+        if !self.is_active() {
+            return Err(inactive_err());
+        }
+        self.inner.mkdir_all(dir)
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<PathBuf>> {
+        self.inner.list(dir)
+    }
+}
+
+struct FaultInjectionFile {
+    name: String,
+    inner: Box<dyn File>,
+    files: Arc<Mutex<HashMap<String, FileState>>>,
+    active: Arc<AtomicBool>,
+}
+
+impl File for FaultInjectionFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut files = self.files.lock().unwrap();
+        if !self.active.load(Ordering::Acquire) {
+            return Err(inactive_err());
+        }
+        let n = self.inner.write(buf)?;
+        if let Some(state) = files.get_mut(&self.name) {
+            state.pos += n as u64;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.active.load(Ordering::Acquire) {
+            return Err(inactive_err());
+        }
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        if !self.active.load(Ordering::Acquire) {
+            return Err(inactive_err());
+        }
+        self.inner.sync()?;
+        if let Some(state) = files.get_mut(&self.name) {
+            state.synced = state.pos;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.inner.close()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.inner.seek(pos)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.inner.read(buf)
+    }
+
+    fn read_all(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        self.inner.read_all(buf)
+    }
+
+    fn len(&self) -> Result<u64> {
+        self.inner.len()
+    }
+
+    fn lock(&self) -> Result<()> {
+        self.inner.lock()
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.inner.unlock()
+    }
+
+    fn lock_region(&self, offset: u64, len: u64, kind: LockKind) -> Result<()> {
+        self.inner.lock_region(offset, len, kind)
+    }
+
+    fn unlock_region(&self, offset: u64, len: u64) -> Result<()> {
+        self.inner.unlock_region(offset, len)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        self.inner.read_at(buf, offset)
+    }
+
+    fn read_exact_at_vectored(&self, requests: &mut [ReadRequest]) -> Result<()> {
+        self.inner.read_exact_at_vectored(requests)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem::MemStorage;
+
+    #[test]
+    fn test_drop_unsynced_data() {
+        let s = FaultInjectionStorage::new(Arc::new(MemStorage::default()));
+        let mut f = s.create("a").unwrap();
+        f.write(b"synced").unwrap();
+        f.sync().unwrap();
+        f.write(b"unsynced").unwrap();
+        f.flush().unwrap();
+        let mut g = s.create("b").unwrap();
+        g.write(b"tmp").unwrap();
+        g.sync().unwrap();
+        s.rename("b", "c").unwrap();
+        assert_eq!(s.unsynced_bytes(), 8);
+
+        let recovered = s.crash().unwrap();
+        assert!(!s.is_active());
+        assert!(f.write(b"more").is_err());
+        assert!(s.create("d").is_err());
+        assert!(s.remove("a").is_err());
+
+        let mut buf = vec![];
+        recovered.open("a").unwrap().read_all(&mut buf).unwrap();
+        assert_eq!(buf.as_slice(), b"synced");
+        buf.clear();
+        recovered.open("c").unwrap().read_all(&mut buf).unwrap();
+        assert_eq!(buf.as_slice(), b"tmp");
+        assert_eq!(recovered.unsynced_bytes(), 0);
+        assert!(recovered.create("d").is_ok());
+    }
+}
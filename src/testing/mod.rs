@@ -0,0 +1,347 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Utilities for testing wickdb and the applications embedding it.
+//! Enabled by the `testing` feature.
+
+mod fault_injection;
+
+pub use fault_injection::FaultInjectionStorage;
+
+use crate::batch::WriteBatch;
+use crate::db::{WickDB, DB};
+use crate::options::{Options, ReadOptions, WriteOptions};
+use crate::util::slice::Slice;
+use crate::util::status::{Result, Status, WickErr};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Options for a `CrashTest`
+#[derive(Clone, Debug)]
+pub struct CrashTestOptions {
+    /// The number of distinct keys to write
+    pub key_space: usize,
+    /// The max size of a value
+    pub max_value_size: usize,
+    /// The max number of entries in one `WriteBatch`
+    pub max_batch_size: usize,
+    /// The probability of an entry in batch being a deletion
+    pub delete_ratio: f64,
+    /// The probability of a write being synced
+    pub sync_ratio: f64,
+    /// The seed of the random generator
+    pub seed: u64,
+}
+
+impl Default for CrashTestOptions {
+    fn default() -> Self {
+        Self {
+            key_space: 1000,
+            max_value_size: 100,
+            max_batch_size: 4,
+            delete_ratio: 0.2,
+            sync_ratio: 0.1,
+            seed: 0,
+        }
+    }
+}
+
+// A write batch kept in the model, `None` value means a deletion
+type ModelBatch = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+/// A golden-state crash-recovery test.
+///
+/// `CrashTest` drives a db with randomized write batches on a
+/// `FaultInjectionStorage`, simulates crashes which drop all the unsynced data
+/// and verifies the recovered db against an in-memory model:
+///
+/// * Every sync write acknowledged before the crash must be recovered.
+/// * The recovered db must be the state after some prefix of all the writes
+///   since the last sync write, i.e. the writes are recovered in order and
+///   a write batch is recovered atomically.
+///
+/// # Example
+///
+/// ```ignore
+/// use wickdb::testing::{CrashTest, CrashTestOptions};
+/// use wickdb::Options;
+///
+/// let mut test = CrashTest::new("crash_test", Options::default, CrashTestOptions::default())?;
+/// // 10 crashes after every 500 writes
+/// test.run(10, 500)?;
+/// ```
+pub struct CrashTest {
+    db_name: String,
+    options: CrashTestOptions,
+    make_options: Box<dyn Fn() -> Options>,
+    storage: Arc<FaultInjectionStorage>,
+    db: WickDB,
+    rng: StdRng,
+    // the state containing all the acknowledged sync writes
+    durable: HashMap<Vec<u8>, Vec<u8>>,
+    // the write batches after `durable` in order
+    pending: Vec<ModelBatch>,
+    crashes: usize,
+}
+
+impl CrashTest {
+    /// Creates a `CrashTest` on a new db named `db_name`.
+    ///
+    /// `make_options` is called for every opening of the db. The `env` of the
+    /// first created options is wrapped as the `FaultInjectionStorage`.
+    pub fn new<F: Fn() -> Options + 'static>(
+        db_name: &str,
+        make_options: F,
+        options: CrashTestOptions,
+    ) -> Result<Self> {
+        let env = make_options().env;
+        let storage = Arc::new(FaultInjectionStorage::new(env));
+        let db = Self::open(db_name, &make_options, storage.clone())?;
+        Ok(Self {
+            db_name: db_name.to_owned(),
+            rng: StdRng::seed_from_u64(options.seed),
+            options,
+            make_options: Box::new(make_options),
+            storage,
+            db,
+            durable: HashMap::new(),
+            pending: vec![],
+            crashes: 0,
+        })
+    }
+
+    fn open(
+        db_name: &str,
+        make_options: &dyn Fn() -> Options,
+        storage: Arc<FaultInjectionStorage>,
+    ) -> Result<WickDB> {
+        let mut options = make_options();
+        options.env = storage;
+        WickDB::open_db(options, db_name.to_owned())
+    }
+
+    /// Returns the db under test
+    #[inline]
+    pub fn db(&self) -> &WickDB {
+        &self.db
+    }
+
+    /// Returns the storage of the db under test
+    #[inline]
+    pub fn storage(&self) -> &FaultInjectionStorage {
+        &self.storage
+    }
+
+    /// Returns the number of the crashes simulated
+    #[inline]
+    pub fn crashes(&self) -> usize {
+        self.crashes
+    }
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:08}", i).into_bytes()
+    }
+
+    /// Applies `n` random write batches to the db and the model
+    pub fn write_random(&mut self, n: usize) -> Result<()> {
+        for _ in 0..n {
+            let mut batch = WriteBatch::new();
+            let mut model_batch = vec![];
+            let size = self
+                .rng
+                .gen_range(1, self.options.max_batch_size.max(1) + 1);
+            for _ in 0..size {
+                let key = Self::key(self.rng.gen_range(0, self.options.key_space.max(1)));
+                if self.rng.gen::<f64>() < self.options.delete_ratio {
+                    batch.delete(key.as_slice());
+                    model_batch.push((key, None));
+                } else {
+                    let len = self
+                        .rng
+                        .gen_range(1, self.options.max_value_size.max(1) + 1);
+                    let value = (0..len)
+                        .map(|_| self.rng.gen_range(b'a', b'z' + 1))
+                        .collect::<Vec<u8>>();
+                    batch.put(key.as_slice(), value.as_slice());
+                    model_batch.push((key, Some(value)));
+                }
+            }
+            let sync = self.rng.gen::<f64>() < self.options.sync_ratio;
+            self.db.write(
+                WriteOptions {
+                    sync,
+                    ..WriteOptions::default()
+                },
+                batch,
+            )?;
+            self.pending.push(model_batch);
+            if sync {
+                // all the writes before a sync write are durable now
+                for batch in self.pending.drain(..) {
+                    Self::apply(&mut self.durable, batch);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(state: &mut HashMap<Vec<u8>, Vec<u8>>, batch: ModelBatch) {
+        for (key, value) in batch {
+            match value {
+                Some(v) => state.insert(key, v),
+                None => state.remove(&key),
+            };
+        }
+    }
+
+    // Reads all the keys in the key space from the db
+    fn read_db(&self) -> Result<HashMap<Vec<u8>, Vec<u8>>> {
+        let mut state = HashMap::new();
+        for i in 0..self.options.key_space {
+            let key = Self::key(i);
+            if let Some(v) = self
+                .db
+                .get(ReadOptions::default(), Slice::from(key.as_slice()))?
+            {
+                state.insert(key, v);
+            }
+        }
+        Ok(state)
+    }
+
+    /// Verifies the db contains exactly all the writes applied
+    pub fn verify(&self) -> Result<()> {
+        let mut expected = self.durable.clone();
+        for batch in self.pending.iter() {
+            Self::apply(&mut expected, batch.clone());
+        }
+        if self.read_db()? != expected {
+            return Err(WickErr::new(
+                Status::Corruption,
+                Some("the db doesn't match the applied writes"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Simulates a crash, reopens the db and verifies the recovered state.
+    /// See `CrashTest` for the rules of the verification.
+    pub fn crash_and_recover(&mut self) -> Result<()> {
+        let storage = Arc::new(self.storage.crash()?);
+        // release the lock of the db file as the process has gone, which fails
+        // to sync anything on the crashed storage
+        let _ = self.db.close();
+        self.db = Self::open(&self.db_name, self.make_options.as_ref(), storage.clone())?;
+        self.storage = storage;
+        self.crashes += 1;
+
+        let recovered = self.read_db()?;
+        let mut expected = self.durable.clone();
+        let mut matched = recovered == expected;
+        let mut pending = self.pending.drain(..);
+        while !matched {
+            match pending.next() {
+                Some(batch) => {
+                    Self::apply(&mut expected, batch);
+                    matched = recovered == expected;
+                }
+                None => break,
+            }
+        }
+        // the writes not recovered are lost
+        drop(pending);
+        if !matched {
+            let lost = self
+                .durable
+                .iter()
+                .filter(|(k, v)| recovered.get(*k) != Some(*v))
+                .count();
+            return Err(WickErr::new(
+                Status::Corruption,
+                Some(Box::leak(
+                    format!(
+                        "[crash test] recovered state after crash {} matches none of the expected states ({} synced entries lost or changed)",
+                        self.crashes, lost
+                    )
+                    .into_boxed_str(),
+                )),
+            ));
+        }
+        self.durable = recovered;
+        Ok(())
+    }
+
+    /// Runs `rounds` rounds of `writes_per_round` random writes followed by
+    /// a crash and recovery
+    pub fn run(&mut self, rounds: usize, writes_per_round: usize) -> Result<()> {
+        for _ in 0..rounds {
+            self.write_random(writes_per_round)?;
+            self.crash_and_recover()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem::MemStorage;
+
+    fn new_options(storage: Arc<MemStorage>) -> impl Fn() -> Options {
+        move || {
+            let mut options = Options::default();
+            options.env = storage.clone();
+            options.write_buffer_size = 64 << 10;
+            options.l1_max_bytes = 128 << 10;
+            options.max_file_size = 32 << 10;
+            options
+        }
+    }
+
+    #[test]
+    fn test_crash_recovery() {
+        let storage = Arc::new(MemStorage::default());
+        let mut t = CrashTest::new(
+            "crash_test",
+            new_options(storage),
+            CrashTestOptions::default(),
+        )
+        .unwrap();
+        t.write_random(100).unwrap();
+        t.verify().unwrap();
+        t.run(5, 1000).unwrap();
+        assert_eq!(t.crashes(), 5);
+        t.verify().unwrap();
+    }
+
+    #[test]
+    fn test_crash_loses_unsynced_writes_only() {
+        let storage = Arc::new(MemStorage::default());
+        let mut t = CrashTest::new(
+            "crash_test_unsynced",
+            new_options(storage),
+            CrashTestOptions {
+                sync_ratio: 0.0,
+                ..CrashTestOptions::default()
+            },
+        )
+        .unwrap();
+        t.write_random(50).unwrap();
+        assert!(t.storage().unsynced_bytes() > 0);
+        t.crash_and_recover().unwrap();
+        // nothing is synced so the db is empty
+        assert!(t.read_db().unwrap().is_empty());
+    }
+}
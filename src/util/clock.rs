@@ -0,0 +1,159 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A `Clock` is the source of time of a DB. Every timestamp, duration and
+/// sleep in the DB goes through the `Clock` set in `Options` so that the
+/// tests and simulations could control the time.
+pub trait Clock: Send + Sync {
+    /// Returns the wall clock time in micros since the UNIX epoch
+    fn now_micros(&self) -> u64;
+
+    /// Returns the micros since an arbitrary fixed point. The result never
+    /// decreases so it's suitable for measuring durations.
+    fn monotonic_micros(&self) -> u64 {
+        self.now_micros()
+    }
+
+    /// Blocks the current thread for at least the given micros
+    fn sleep_micros(&self, micros: u64);
+}
+
+/// The `Clock` backed by the operating system
+pub struct SystemClock {
+    base: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            base: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_micros(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64)
+    }
+
+    fn monotonic_micros(&self) -> u64 {
+        self.base.elapsed().as_micros() as u64
+    }
+
+    fn sleep_micros(&self, micros: u64) {
+        thread::sleep(Duration::from_micros(micros))
+    }
+}
+
+/// A `Clock` only moving forward when told to. Sleeping on a `MockClock`
+/// returns immediately after advancing the clock by the sleep time.
+pub struct MockClock {
+    now: AtomicU64,
+}
+
+impl MockClock {
+    /// Creates a `MockClock` starting at the given micros since the UNIX epoch
+    pub fn new(now_micros: u64) -> Self {
+        Self {
+            now: AtomicU64::new(now_micros),
+        }
+    }
+
+    /// Moves the clock forward by `micros`
+    pub fn advance(&self, micros: u64) {
+        self.now.fetch_add(micros, Ordering::SeqCst);
+    }
+
+    /// Sets the time of the clock. The time is not allowed to go back.
+    pub fn set(&self, now_micros: u64) {
+        self.now.fetch_max(now_micros, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_micros(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+
+    fn sleep_micros(&self, micros: u64) {
+        self.advance(micros)
+    }
+}
+
+/// Formats the micros since the UNIX epoch as `yyyy/mm/dd-hh:mm:ss.uuuuuu` in UTC
+pub fn format_micros(micros: u64) -> String {
+    let secs = micros / 1_000_000;
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}/{:02}/{:02}-{:02}:{:02}:{:02}.{:06}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        micros % 1_000_000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(100);
+        assert_eq!(clock.now_micros(), 100);
+        clock.advance(50);
+        assert_eq!(clock.monotonic_micros(), 150);
+        clock.sleep_micros(1_000_000_000);
+        assert_eq!(clock.now_micros(), 1_000_000_150);
+        clock.set(10);
+        assert_eq!(clock.now_micros(), 1_000_000_150);
+    }
+
+    #[test]
+    fn test_format_micros() {
+        assert_eq!(format_micros(0), "1970/01/01-00:00:00.000000");
+        assert_eq!(
+            format_micros(1_567_296_000_000_042),
+            "2019/09/01-00:00:00.000042"
+        );
+        assert_eq!(
+            format_micros(951_827_696_123_456),
+            "2000/02/29-12:34:56.123456"
+        );
+    }
+}
@@ -0,0 +1,383 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Copyright (c) 2011 The LevelDB Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file. See the AUTHORS file for names of contributors.
+
+use crate::util::coding::{decode_fixed_64, put_fixed_64};
+use crate::util::varint::{VarintU32, VarintU64};
+use std::fmt::{Display, Formatter};
+
+lazy_static! {
+    // The upper limits of the buckets: 1, 2, ..., 10, 12, 14, ..., 90, 100, 120, ...
+    static ref BUCKET_LIMITS: Vec<f64> = {
+        let mut limits: Vec<f64> = (1..=10).map(f64::from).collect();
+        let steps = [
+            1.2, 1.4, 1.6, 1.8, 2.0, 2.5, 3.0, 3.5, 4.0, 4.5, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0,
+        ];
+        let mut base = 10.0;
+        while base < 1e20 {
+            for step in steps.iter() {
+                limits.push(base * step);
+            }
+            base *= 10.0;
+        }
+        limits.push(f64::MAX);
+        limits
+    };
+}
+
+/// A `Histogram` records the distribution of values (e.g. latencies in micros)
+/// in exponentially growing buckets.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    min: f64,
+    max: f64,
+    num: f64,
+    sum: f64,
+    sum_squares: f64,
+    buckets: Vec<f64>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            min: BUCKET_LIMITS[BUCKET_LIMITS.len() - 1],
+            max: 0.0,
+            num: 0.0,
+            sum: 0.0,
+            sum_squares: 0.0,
+            buckets: vec![0.0; BUCKET_LIMITS.len()],
+        }
+    }
+}
+
+impl Histogram {
+    /// Resets the histogram to the empty state
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Records a value
+    pub fn add(&mut self, value: f64) {
+        let mut b = 0;
+        while b < BUCKET_LIMITS.len() - 1 && BUCKET_LIMITS[b] <= value {
+            b += 1;
+        }
+        self.buckets[b] += 1.0;
+        if self.min > value {
+            self.min = value;
+        }
+        if self.max < value {
+            self.max = value;
+        }
+        self.num += 1.0;
+        self.sum += value;
+        self.sum_squares += value * value;
+    }
+
+    /// Merges all the values recorded by `other` into self
+    pub fn merge(&mut self, other: &Histogram) {
+        if other.min < self.min {
+            self.min = other.min;
+        }
+        if other.max > self.max {
+            self.max = other.max;
+        }
+        self.num += other.num;
+        self.sum += other.sum;
+        self.sum_squares += other.sum_squares;
+        for (b, o) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *b += *o;
+        }
+    }
+
+    /// Removes the values recorded by `earlier` from self, where `earlier` is a
+    /// copy of self taken before. The min and max of the values left are only
+    /// estimated by the bounds of their buckets.
+    pub fn subtract(&mut self, earlier: &Histogram) {
+        self.num -= earlier.num;
+        self.sum -= earlier.sum;
+        self.sum_squares -= earlier.sum_squares;
+        for (b, e) in self.buckets.iter_mut().zip(earlier.buckets.iter()) {
+            *b -= *e;
+        }
+        let first = self.buckets.iter().position(|c| *c > 0.0);
+        let last = self.buckets.iter().rposition(|c| *c > 0.0);
+        match (first, last) {
+            (Some(first), Some(last)) => {
+                let lower = if first == 0 {
+                    0.0
+                } else {
+                    BUCKET_LIMITS[first - 1]
+                };
+                self.min = self.min.max(lower);
+                self.max = self.max.min(BUCKET_LIMITS[last]);
+            }
+            _ => self.clear(),
+        }
+    }
+
+    /// Returns the number of values recorded
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.num as u64
+    }
+
+    /// Returns the sum of all the values recorded
+    #[inline]
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// Returns the minimum value recorded or 0 if the histogram is empty
+    #[inline]
+    pub fn min(&self) -> f64 {
+        if self.num == 0.0 {
+            0.0
+        } else {
+            self.min
+        }
+    }
+
+    /// Returns the maximum value recorded
+    #[inline]
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
+    #[inline]
+    pub fn median(&self) -> f64 {
+        self.percentile(50.0)
+    }
+
+    /// Returns the estimated value at the given percentile `p` (0 ~ 100)
+    pub fn percentile(&self, p: f64) -> f64 {
+        let threshold = self.num * (p / 100.0);
+        let mut sum = 0.0;
+        for (b, count) in self.buckets.iter().enumerate() {
+            sum += count;
+            if sum >= threshold {
+                // Scale linearly within this bucket
+                let left_point = if b == 0 { 0.0 } else { BUCKET_LIMITS[b - 1] };
+                let right_point = BUCKET_LIMITS[b];
+                let left_sum = sum - count;
+                let pos = if *count == 0.0 {
+                    0.0
+                } else {
+                    (threshold - left_sum) / count
+                };
+                let mut r = left_point + (right_point - left_point) * pos;
+                if r < self.min {
+                    r = self.min
+                }
+                if r > self.max {
+                    r = self.max
+                }
+                return r;
+            }
+        }
+        self.max
+    }
+
+    #[inline]
+    pub fn average(&self) -> f64 {
+        if self.num == 0.0 {
+            0.0
+        } else {
+            self.sum / self.num
+        }
+    }
+
+    pub fn standard_deviation(&self) -> f64 {
+        if self.num == 0.0 {
+            return 0.0;
+        }
+        let variance = (self.sum_squares * self.num - self.sum * self.sum) / (self.num * self.num);
+        variance.max(0.0).sqrt()
+    }
+
+    /// Appends the encoded histogram to `dst`. Only the non-empty buckets are encoded.
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        for v in [self.min, self.max, self.num, self.sum, self.sum_squares].iter() {
+            put_fixed_64(dst, v.to_bits());
+        }
+        let used = self.buckets.iter().filter(|c| **c > 0.0).count();
+        VarintU32::put_varint(dst, used as u32);
+        for (b, count) in self.buckets.iter().enumerate() {
+            if *count > 0.0 {
+                VarintU32::put_varint(dst, b as u32);
+                VarintU64::put_varint(dst, *count as u64);
+            }
+        }
+    }
+
+    /// Decodes a histogram encoded by `encode_to`. Returns `None` if `src` is malformed.
+    pub fn decode_from(src: &[u8]) -> Option<Self> {
+        if src.len() < 5 * 8 {
+            return None;
+        }
+        let mut fields = [0.0; 5];
+        for (i, field) in fields.iter_mut().enumerate() {
+            *field = f64::from_bits(decode_fixed_64(&src[i * 8..]));
+        }
+        let mut h = Histogram {
+            min: fields[0],
+            max: fields[1],
+            num: fields[2],
+            sum: fields[3],
+            sum_squares: fields[4],
+            ..Histogram::default()
+        };
+        let mut src = &src[5 * 8..];
+        let (used, n) = VarintU32::read(src)?;
+        src = &src[n..];
+        for _ in 0..used {
+            let (b, n) = VarintU32::read(src)?;
+            src = &src[n..];
+            let (count, n) = VarintU64::read(src)?;
+            src = &src[n..];
+            *h.buckets.get_mut(b as usize)? = count as f64;
+        }
+        Some(h)
+    }
+}
+
+impl Display for Histogram {
+    fn fmt(&self, f: &mut Formatter) -> ::std::fmt::Result {
+        writeln!(
+            f,
+            "Count: {:.0}  Average: {:.4}  StdDev: {:.2}",
+            self.num,
+            self.average(),
+            self.standard_deviation()
+        )?;
+        writeln!(
+            f,
+            "Min: {:.4}  Median: {:.4}  Max: {:.4}",
+            self.min(),
+            self.median(),
+            self.max
+        )?;
+        writeln!(f, "------------------------------------------------------")?;
+        let mult = if self.num == 0.0 {
+            0.0
+        } else {
+            100.0 / self.num
+        };
+        let mut sum = 0.0;
+        for (b, count) in self.buckets.iter().enumerate() {
+            if *count <= 0.0 {
+                continue;
+            }
+            sum += count;
+            let left = if b == 0 { 0.0 } else { BUCKET_LIMITS[b - 1] };
+            // Add hash marks based on percentage; 20 marks for 100%.
+            let marks = (20.0 * (count / self.num) + 0.5) as usize;
+            writeln!(
+                f,
+                "[ {:>7.0}, {:>7.0} ) {:>7.0} {:>7.3}% {:>7.3}% {}",
+                left,
+                BUCKET_LIMITS[b],
+                count,
+                mult * count,
+                mult * sum,
+                "#".repeat(marks)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_histogram() {
+        let h = Histogram::default();
+        assert_eq!(h.count(), 0);
+        assert_eq!(h.min(), 0.0);
+        assert_eq!(h.max(), 0.0);
+        assert_eq!(h.average(), 0.0);
+        assert_eq!(h.median(), 0.0);
+    }
+
+    #[test]
+    fn test_add_and_percentile() {
+        let mut h = Histogram::default();
+        for i in 1..=100 {
+            h.add(f64::from(i));
+        }
+        assert_eq!(h.count(), 100);
+        assert_eq!(h.min(), 1.0);
+        assert_eq!(h.max(), 100.0);
+        assert_eq!(h.average(), 50.5);
+        let median = h.median();
+        assert!(median > 40.0 && median < 60.0, "median {}", median);
+        let p99 = h.percentile(99.0);
+        assert!(p99 > 90.0 && p99 <= 100.0, "p99 {}", p99);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = Histogram::default();
+        let mut b = Histogram::default();
+        a.add(1.0);
+        b.add(1000.0);
+        b.add(3.0);
+        a.merge(&b);
+        assert_eq!(a.count(), 3);
+        assert_eq!(a.min(), 1.0);
+        assert_eq!(a.max(), 1000.0);
+        assert_eq!(a.sum(), 1004.0);
+        a.clear();
+        assert_eq!(a.count(), 0);
+    }
+
+    #[test]
+    fn test_subtract() {
+        let mut h = Histogram::default();
+        h.add(1.0);
+        h.add(1000.0);
+        let earlier = h.clone();
+        h.add(3.0);
+        h.add(5.0);
+        h.subtract(&earlier);
+        assert_eq!(h.count(), 2);
+        assert_eq!(h.sum(), 8.0);
+        assert_eq!(h.min(), 3.0);
+        assert_eq!(h.max(), 6.0);
+        h.subtract(&h.clone());
+        assert_eq!(h.count(), 0);
+        assert_eq!(h.max(), 0.0);
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut h = Histogram::default();
+        for i in 1..=100 {
+            h.add(f64::from(i * i));
+        }
+        let mut encoded = vec![];
+        h.encode_to(&mut encoded);
+        let decoded = Histogram::decode_from(&encoded).unwrap();
+        assert_eq!(decoded.count(), 100);
+        assert_eq!(decoded.min(), 1.0);
+        assert_eq!(decoded.max(), 10000.0);
+        assert_eq!(decoded.sum(), h.sum());
+        assert_eq!(decoded.median(), h.median());
+        assert!(Histogram::decode_from(&encoded[..encoded.len() - 1]).is_none());
+    }
+}
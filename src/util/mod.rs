@@ -12,12 +12,14 @@
 // limitations under the License.
 
 pub mod byte;
+pub mod clock;
 pub mod coding;
 pub mod comparator;
 pub mod crc32;
 #[macro_use]
 pub mod status;
 pub mod hash;
+pub mod histogram;
 pub mod reporter;
 pub mod slice;
 pub mod varint;
@@ -12,7 +12,7 @@
 // limitations under the License.
 
 use crate::record::reader::Reporter;
-use crate::util::status::{Result, Status, WickErr};
+use crate::util::status::{CorruptionReport, Result, Status, WickErr};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -24,14 +24,19 @@ pub struct LogReporter {
 struct LogReporterInner {
     ok: bool,
     reason: String,
+    // the file being read
+    file: String,
+    report: Option<CorruptionReport>,
 }
 
 impl LogReporter {
-    pub fn new() -> Self {
+    pub fn new(file: &str) -> Self {
         Self {
             inner: Rc::new(RefCell::new(LogReporterInner {
                 ok: true,
                 reason: "".to_owned(),
+                file: file.to_owned(),
+                report: None,
             })),
         }
     }
@@ -41,7 +46,13 @@ impl LogReporter {
         if inner.ok {
             Ok(())
         } else {
-            Err(WickErr::new(Status::Corruption, Some(static_reasons)))
+            match &inner.report {
+                Some(report) => Err(WickErr::new_corruption(
+                    Some(static_reasons),
+                    report.clone(),
+                )),
+                None => Err(WickErr::new(Status::Corruption, Some(static_reasons))),
+            }
         }
     }
 }
@@ -51,4 +62,11 @@ impl Reporter for LogReporter {
         self.inner.borrow_mut().ok = false;
         self.inner.borrow_mut().reason = reason.to_owned();
     }
+
+    fn corruption_with_report(&mut self, mut report: CorruptionReport) {
+        self.corruption(report.size, report.reason.as_str());
+        let mut inner = self.inner.borrow_mut();
+        report.file = Some(inner.file.clone());
+        inner.report = Some(report);
+    }
 }
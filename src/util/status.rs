@@ -17,9 +17,10 @@
 
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::io;
 use std::mem;
-use std::rc::Rc;
 use std::result;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Status {
@@ -29,6 +30,10 @@ pub enum Status {
     InvalidArgument,
     CompressionError,
     IOError,
+    // The operation conflicts with a concurrent one and is allowed to be retried
+    Busy,
+    // The operation is given up by a cancelled `CancellationToken`
+    Cancelled,
 
     Unexpected,
     Default, // used for default
@@ -43,35 +48,150 @@ impl Status {
             Status::InvalidArgument => "InvalidArgumentError",
             Status::CompressionError => "CompressionError",
             Status::IOError => "IOError",
+            Status::Busy => "BusyError",
+            Status::Cancelled => "CancelledError",
             Status::Unexpected => "UnexpectedError",
             _ => "",
         }
     }
 }
 
+/// `CorruptionReport` describes where and how the data is corrupted in detail.
+/// It's attached to the `WickErr` with `Status::Corruption` raised when reading
+/// table blocks, table footers, WAL and MANIFEST records.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CorruptionReport {
+    /// The corrupted file if known
+    pub file: Option<String>,
+    /// The offset of the corrupted bytes in the file
+    pub offset: u64,
+    /// The length of the corrupted bytes
+    pub size: u64,
+    /// The checksum stored in the file
+    pub expected_checksum: Option<u32>,
+    /// The checksum calculated from the bytes read
+    pub actual_checksum: Option<u32>,
+    /// What is corrupted
+    pub reason: String,
+    /// Information about the surrounding structure like the block handle
+    pub context: Option<String>,
+}
+
+impl CorruptionReport {
+    pub fn new(reason: &str, offset: u64, size: u64) -> Self {
+        Self {
+            offset,
+            size,
+            reason: reason.to_owned(),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_checksums(mut self, expected: u32, actual: u32) -> Self {
+        self.expected_checksum = Some(expected);
+        self.actual_checksum = Some(actual);
+        self
+    }
+
+    pub fn with_context(mut self, context: String) -> Self {
+        self.context = Some(context);
+        self
+    }
+}
+
+impl Display for CorruptionReport {
+    fn fmt(&self, f: &mut Formatter) -> ::std::fmt::Result {
+        write!(
+            f,
+            "{} in {} at [{}, {})",
+            self.reason,
+            self.file.as_ref().map_or("unknown file", |f| f.as_str()),
+            self.offset,
+            self.offset + self.size
+        )?;
+        if let (Some(expected), Some(actual)) = (self.expected_checksum, self.actual_checksum) {
+            write!(
+                f,
+                ", expected checksum {:#x}, actual {:#x}",
+                expected, actual
+            )?;
+        }
+        if let Some(context) = &self.context {
+            write!(f, ", {}", context)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 // TODO: use String instead
 pub struct WickErr {
     t: Status,
     msg: Option<&'static str>,
-    raw: Option<Rc<Box<dyn Error>>>,
+    raw: Option<Arc<Box<dyn Error + Send + Sync>>>,
+    report: Option<Arc<CorruptionReport>>,
 }
 
 impl WickErr {
     pub fn new(t: Status, msg: Option<&'static str>) -> Self {
-        Self { t, msg, raw: None }
+        Self {
+            t,
+            msg,
+            raw: None,
+            report: None,
+        }
     }
 
-    pub fn new_from_raw(t: Status, msg: Option<&'static str>, raw: Box<dyn Error>) -> Self {
+    pub fn new_from_raw(
+        t: Status,
+        msg: Option<&'static str>,
+        raw: Box<dyn Error + Send + Sync>,
+    ) -> Self {
         Self {
             t,
             msg,
-            raw: Some(Rc::new(raw)),
+            raw: Some(Arc::new(raw)),
+            report: None,
+        }
+    }
+
+    /// Creates a `Status::Corruption` error with the detail report
+    pub fn new_corruption(msg: Option<&'static str>, report: CorruptionReport) -> Self {
+        Self {
+            t: Status::Corruption,
+            msg,
+            raw: None,
+            report: Some(Arc::new(report)),
+        }
+    }
+
+    /// Returns the detail of the corruption if this is a `Status::Corruption`
+    /// error with a report attached
+    #[inline]
+    pub fn corruption_report(&self) -> Option<&CorruptionReport> {
+        self.report.as_ref().map(|r| r.as_ref())
+    }
+
+    /// Attaches the `report` if self is a `Status::Corruption` error without a report
+    pub(crate) fn with_report(mut self, report: CorruptionReport) -> Self {
+        if self.t == Status::Corruption && self.report.is_none() {
+            self.report = Some(Arc::new(report));
+        }
+        self
+    }
+
+    /// Fills the file name of the attached report if it's unknown
+    pub(crate) fn with_corrupted_file(mut self, file: &str) -> Self {
+        if let Some(report) = self.report.as_mut() {
+            if report.file.is_none() {
+                Arc::make_mut(report).file = Some(file.to_owned());
+            }
         }
+        self
     }
 
     #[inline]
-    pub fn take_raw(&mut self) -> Option<Rc<Box<dyn Error>>> {
+    pub fn take_raw(&mut self) -> Option<Arc<Box<dyn Error + Send + Sync>>> {
         mem::replace(&mut self.raw, None)
     }
 
@@ -81,9 +201,6 @@ impl WickErr {
     }
 }
 
-unsafe impl Send for WickErr {}
-unsafe impl Sync for WickErr {}
-
 pub type Result<T> = result::Result<T, WickErr>;
 
 // Convert `IOResult` to standard `Result` used in wickdb
@@ -103,6 +220,7 @@ impl Clone for WickErr {
             t: self.t.clone(),
             msg: self.msg,
             raw: self.raw.clone(),
+            report: self.report.clone(),
         }
     }
 }
@@ -113,41 +231,24 @@ impl Default for WickErr {
             t: Status::Default,
             msg: None,
             raw: None,
+            report: None,
         }
     }
 }
 
 impl Display for WickErr {
     fn fmt(&self, f: &mut Formatter) -> ::std::fmt::Result {
-        match self.msg {
-            Some(m) => match &self.raw {
-                Some(e) => {
-                    return write!(
-                        f,
-                        "WickDB error [{}] : {} , raw : {}",
-                        self.t.as_str(),
-                        m,
-                        e.description()
-                    );
-                }
-                None => {
-                    return write!(f, "WickDB error [{}] : {}", self.t.as_str(), m);
-                }
-            },
-            None => match &self.raw {
-                Some(e) => {
-                    return write!(
-                        f,
-                        "WickDB error [{}] : {}",
-                        self.t.as_str(),
-                        e.description()
-                    );
-                }
-                None => {
-                    return write!(f, "WickDB error [{}]", self.t.as_str());
-                }
-            },
+        write!(f, "WickDB error [{}]", self.t.as_str())?;
+        match (self.msg, &self.raw) {
+            (Some(m), Some(e)) => write!(f, " : {} , raw : {}", m, e.description())?,
+            (Some(m), None) => write!(f, " : {}", m)?,
+            (None, Some(e)) => write!(f, " : {}", e.description())?,
+            (None, None) => {}
         }
+        if let Some(report) = &self.report {
+            write!(f, " ({})", report)?;
+        }
+        Ok(())
     }
 }
 
@@ -161,4 +262,139 @@ impl ::std::error::Error for WickErr {
             },
         }
     }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.raw {
+            Some(e) => Some(e.as_ref().as_ref()),
+            None => None,
+        }
+    }
+}
+
+impl From<io::Error> for WickErr {
+    /// Unwraps the `WickErr` carried by the `io::Error` if it was converted from one,
+    /// otherwise keeps the `io::Error` as the cause
+    fn from(e: io::Error) -> Self {
+        let is_wick_err = match e.get_ref() {
+            Some(inner) => inner.is::<WickErr>(),
+            None => false,
+        };
+        if is_wick_err {
+            // `get_ref` has just checked there is an inner `WickErr`
+            return *e.into_inner().unwrap().downcast::<WickErr>().unwrap();
+        }
+        let t = match e.kind() {
+            io::ErrorKind::NotFound => Status::NotFound,
+            io::ErrorKind::InvalidInput => Status::InvalidArgument,
+            io::ErrorKind::InvalidData => Status::Corruption,
+            _ => Status::IOError,
+        };
+        WickErr::new_from_raw(t, None, Box::new(e))
+    }
+}
+
+impl From<Box<dyn Error + Send + Sync>> for WickErr {
+    /// Converts the boxed errors like the ones from `anyhow::Error::into()`.
+    /// `WickErr` and `io::Error` are recovered as themselves and others are
+    /// kept as the cause of a `Status::Unexpected`.
+    fn from(e: Box<dyn Error + Send + Sync>) -> Self {
+        let e = match e.downcast::<WickErr>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        match e.downcast::<io::Error>() {
+            Ok(e) => WickErr::from(*e),
+            Err(e) => WickErr::new_from_raw(Status::Unexpected, None, e),
+        }
+    }
+}
+
+impl From<WickErr> for io::Error {
+    /// Wraps the `WickErr` as the inner error so that the status, the message and
+    /// the cause chain can be got back by `WickErr::from`
+    fn from(e: WickErr) -> Self {
+        let raw_kind = e
+            .raw
+            .as_ref()
+            .and_then(|r| r.downcast_ref::<io::Error>())
+            .map(|r| r.kind());
+        let kind = match raw_kind {
+            Some(kind) => kind,
+            None => match e.t {
+                Status::NotFound => io::ErrorKind::NotFound,
+                Status::InvalidArgument => io::ErrorKind::InvalidInput,
+                Status::Corruption | Status::CompressionError => io::ErrorKind::InvalidData,
+                Status::NotSupported => io::ErrorKind::Unsupported,
+                Status::Busy => io::ErrorKind::WouldBlock,
+                _ => io::ErrorKind::Other,
+            },
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_io_error() {
+        let e = WickErr::from(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        assert_eq!(e.status(), Status::NotFound);
+        let source = e.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::NotFound);
+
+        let e = WickErr::from(io::Error::new(io::ErrorKind::BrokenPipe, "broken"));
+        assert_eq!(e.status(), Status::IOError);
+    }
+
+    #[test]
+    fn test_io_error_round_trip() {
+        let report = CorruptionReport::new("bad block", 10, 20);
+        let e = WickErr::new_corruption(Some("corrupted"), report.clone());
+        let io_err = io::Error::from(e);
+        assert_eq!(io_err.kind(), io::ErrorKind::InvalidData);
+        let e = WickErr::from(io_err);
+        assert_eq!(e.status(), Status::Corruption);
+        assert_eq!(e.corruption_report(), Some(&report));
+        assert!(e.to_string().contains("corrupted"));
+
+        // The kind of the original io error is kept
+        let e = WickErr::from(io::Error::new(io::ErrorKind::TimedOut, "timeout"));
+        let io_err = io::Error::from(e);
+        assert_eq!(io_err.kind(), io::ErrorKind::TimedOut);
+        let source = io_err.get_ref().unwrap().source().unwrap();
+        assert_eq!(source.to_string(), "timeout");
+    }
+
+    #[test]
+    fn test_from_boxed_error() {
+        let boxed: Box<dyn Error + Send + Sync> =
+            Box::new(WickErr::new(Status::Busy, Some("busy")));
+        assert_eq!(WickErr::from(boxed).status(), Status::Busy);
+
+        let boxed: Box<dyn Error + Send + Sync> =
+            Box::new(io::Error::new(io::ErrorKind::NotFound, "missing"));
+        assert_eq!(WickErr::from(boxed).status(), Status::NotFound);
+
+        let boxed: Box<dyn Error + Send + Sync> = "something wrong".into();
+        let e = WickErr::from(boxed);
+        assert_eq!(e.status(), Status::Unexpected);
+        assert_eq!(e.source().unwrap().to_string(), "something wrong");
+    }
+
+    #[test]
+    fn test_question_mark_conversion() {
+        fn open_missing() -> Result<()> {
+            Err(io::Error::new(io::ErrorKind::NotFound, "missing"))?;
+            Ok(())
+        }
+        fn embed() -> io::Result<()> {
+            open_missing()?;
+            Ok(())
+        }
+        let e = embed().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert_eq!(WickErr::from(e).status(), Status::NotFound);
+    }
 }
@@ -16,11 +16,12 @@
 // found in the LICENSE file.
 
 use crate::db::format::{
-    InternalKey, InternalKeyComparator, LookupKey, ParsedInternalKey, ValueType,
+    InternalKey, InternalKeyComparator, LookupKey, ParsedInternalKey, ValueType, MAX_KEY_SEQUENCE,
     VALUE_TYPE_FOR_SEEK,
 };
 use crate::iterator::Iterator;
 use crate::options::{Options, ReadOptions};
+use crate::statistics::{LevelReadScope, Ticker};
 use crate::table_cache::TableCache;
 use crate::util::coding::put_fixed_64;
 use crate::util::comparator::Comparator;
@@ -29,11 +30,11 @@ use crate::util::status::{Result, Status, WickErr};
 use crate::version::version_edit::FileMetaData;
 use crate::version::version_set::VersionSet;
 use std::cell::RefCell;
-use std::cmp::Ordering as CmpOrdering;
+use std::cmp::{self, Ordering as CmpOrdering};
 use std::mem;
 use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
 pub mod version_edit;
 pub mod version_set;
@@ -72,11 +73,35 @@ pub struct Version {
     file_to_compact: RwLock<Option<Arc<FileMetaData>>>,
     file_to_compact_level: AtomicUsize,
 
+    // The (level, file) of the bottommost files with too many deletions, which
+    // is collected from the table properties on the first call of
+    // `bottommost_deletion_files`
+    bottommost_deletion_files: Mutex<Option<Vec<(usize, Arc<FileMetaData>)>>>,
+
+    // The (level, file, creation time) of the files with the creation time in
+    // their table properties ordered from the oldest, which is collected on the
+    // first call of `periodic_compaction_files`
+    file_creation_times: Mutex<Option<Vec<(usize, Arc<FileMetaData>, u64)>>>,
+
     // level that should be compacted next and its compaction score
     // score < 1 means compaction is not strictly needed.
     // These fields are initialized by `finalize`
     compaction_score: f32,
     compaction_level: usize,
+    // the compaction score of every level. The last level always scores 0
+    // since it can't be compacted into a deeper level
+    compaction_scores: Vec<f32>,
+    // the target size of every level except level0, which is 0 for the levels
+    // above the base level with `level_compaction_dynamic_level_bytes`.
+    // Also initialized by `finalize`
+    level_max_bytes: Vec<u64>,
+
+    // the smallest and largest user keys of the files in each level
+    // or None if the level is empty. This is also initialized by `finalize`
+    level_fences: Vec<Option<(Vec<u8>, Vec<u8>)>>,
+
+    // estimated bytes to be compacted. Also initialized by `finalize`
+    pending_compaction_bytes: u64,
 }
 /// A helper for representing the file has been seeked
 pub struct SeekStats {
@@ -108,35 +133,58 @@ impl Version {
             files,
             file_to_compact: RwLock::new(None),
             file_to_compact_level: AtomicUsize::new(0),
+            bottommost_deletion_files: Mutex::new(None),
+            file_creation_times: Mutex::new(None),
             compaction_score: 0f32,
             compaction_level: 0,
+            compaction_scores: vec![0f32; max_levels],
+            level_max_bytes: vec![0; max_levels],
+            level_fences: vec![None; max_levels],
+            pending_compaction_bytes: 0,
         }
     }
 
+    /// Creates a `Version` of the given files by level, which only serves the
+    /// lookups of the files without being finalized
+    pub fn with_files(
+        options: Arc<Options>,
+        icmp: Arc<InternalKeyComparator>,
+        files: Vec<Vec<Arc<FileMetaData>>>,
+    ) -> Self {
+        let mut v = Self::new(options, icmp);
+        v.files = files;
+        v
+    }
+
     /// Search the value by the given key in sstables level by level
     pub fn get(
         &self,
         options: ReadOptions,
         key: LookupKey,
         table_cache: Arc<TableCache>,
-    ) -> Result<(Option<Slice>, SeekStats)> {
+    ) -> Result<(Option<Vec<u8>>, SeekStats)> {
         let opt = Rc::new(options);
         let ikey = key.internal_key();
         let ukey = key.user_key();
         let ucmp = self.icmp.user_comparator.as_ref();
         let mut files_to_seek = vec![];
         let mut seek_stats = SeekStats::new();
+        // the last file we've read and its level
+        let mut last_file_read: Option<(usize, Arc<FileMetaData>)> = None;
         for (level, files) in self.files.iter().enumerate() {
-            if files.is_empty() {
+            if !self.level_may_contain(level, ukey.as_slice()) {
+                // skip the level without touching the table cache
                 continue;
             }
+            let _scope = LevelReadScope::new(level);
+            files_to_seek.clear();
             if level == 0 {
                 // Level-0 files may overlap each other. Find all files that
                 // overlap user_key and process them in order from newest to oldest because
                 // the last level-0 file always has the newest entries.
                 for f in files.iter().rev() {
-                    if ucmp.compare(ukey.as_slice(), f.largest.data()) != CmpOrdering::Greater
-                        && ucmp.compare(ukey.as_slice(), f.smallest.data()) != CmpOrdering::Less
+                    if ucmp.compare(ukey.as_slice(), f.largest.user_key()) != CmpOrdering::Greater
+                        && ucmp.compare(ukey.as_slice(), f.smallest.user_key()) != CmpOrdering::Less
                     {
                         files_to_seek.push(f.clone());
                     }
@@ -150,19 +198,28 @@ impl Version {
                 } else {
                     let target = files[index].clone();
                     // if what we found is just the first file, it could still not includes the target
-                    if ucmp.compare(ukey.as_slice(), target.smallest.data()) != CmpOrdering::Less {
+                    if ucmp.compare(ukey.as_slice(), target.smallest.user_key())
+                        != CmpOrdering::Less
+                    {
                         files_to_seek = vec![target];
                     }
                 }
             }
 
             for file in files_to_seek.iter() {
-                seek_stats.seek_file_level = Some(level);
-                seek_stats.seek_file = Some(file.clone());
+                // charge the first file we've read if we have to read more than one file
+                if seek_stats.seek_file.is_none() {
+                    if let Some((l, f)) = last_file_read.take() {
+                        seek_stats.seek_file_level = Some(l);
+                        seek_stats.seek_file = Some(f);
+                    }
+                }
+                opt.check_cancelled()?;
+                last_file_read = Some((level, file.clone()));
                 match table_cache.get(opt.clone(), &ikey, file.number, file.file_size)? {
                     None => continue, // keep searching
                     Some((encoded_key, value)) => {
-                        match ParsedInternalKey::decode_from(encoded_key) {
+                        match ParsedInternalKey::decode_from(Slice::from(&encoded_key)) {
                             None => {
                                 return Err(WickErr::new(
                                     Status::Corruption,
@@ -190,22 +247,271 @@ impl Version {
         Ok((None, seek_stats))
     }
 
+    /// Looks up `keys` like calling `get` for each of them, but probes every table
+    /// once for all the keys that might be in it so that the filters rule out the
+    /// whole batch before any data block is read. Like `get`, a key probed in more
+    /// than one file charges the first file it's probed in, and the `SeekStats` of
+    /// all the charged files are returned to be applied by `update_stats`.
+    pub fn multi_get(
+        &self,
+        options: ReadOptions,
+        keys: &[LookupKey],
+        table_cache: Arc<TableCache>,
+    ) -> Result<(Vec<Option<Vec<u8>>>, Vec<SeekStats>)> {
+        let opt = Rc::new(options);
+        let ucmp = self.icmp.user_comparator.as_ref();
+        let mut results = vec![None; keys.len()];
+        let mut resolved = vec![false; keys.len()];
+        let mut seek_stats = vec![];
+        // The first file each key is probed in, which is taken once it's charged
+        let mut first_file_read: Vec<Option<(usize, Arc<FileMetaData>)>> = vec![None; keys.len()];
+        let mut charged = vec![false; keys.len()];
+        // The indexes of the unresolved keys in the order of their user keys so that
+        // the keys falling into the same file are adjacent
+        let mut pending: Vec<usize> = (0..keys.len()).collect();
+        pending.sort_by(|a, b| {
+            ucmp.compare(
+                keys[*a].user_key().as_slice(),
+                keys[*b].user_key().as_slice(),
+            )
+        });
+        for (level, files) in self.files.iter().enumerate() {
+            pending.retain(|i| !resolved[*i]);
+            if pending.is_empty() {
+                break;
+            }
+            let _scope = LevelReadScope::new(level);
+            // The files to probe in the level and the keys to probe in each of them
+            let mut probes: Vec<(Arc<FileMetaData>, Vec<usize>)> = vec![];
+            if level == 0 {
+                // Level-0 files may overlap each other so the newest file goes first
+                let mut newest_first: Vec<&Arc<FileMetaData>> = files.iter().collect();
+                newest_first.sort_by_key(|f| cmp::Reverse(f.number));
+                for f in newest_first {
+                    let in_file = pending
+                        .iter()
+                        .copied()
+                        .filter(|i| {
+                            let ukey = keys[*i].user_key();
+                            ucmp.compare(ukey.as_slice(), f.smallest.user_key())
+                                != CmpOrdering::Less
+                                && ucmp.compare(ukey.as_slice(), f.largest.user_key())
+                                    != CmpOrdering::Greater
+                        })
+                        .collect::<Vec<_>>();
+                    if !in_file.is_empty() {
+                        probes.push((f.clone(), in_file));
+                    }
+                }
+            } else {
+                for i in pending.iter().copied() {
+                    let ukey = keys[i].user_key();
+                    if !self.level_may_contain(level, ukey.as_slice()) {
+                        continue;
+                    }
+                    let index = Self::find_file(
+                        self.icmp.clone(),
+                        files.as_slice(),
+                        &keys[i].internal_key(),
+                    );
+                    if index >= files.len()
+                        || ucmp.compare(ukey.as_slice(), files[index].smallest.user_key())
+                            == CmpOrdering::Less
+                    {
+                        continue;
+                    }
+                    match probes.last_mut() {
+                        Some((f, in_file)) if f.number == files[index].number => in_file.push(i),
+                        _ => probes.push((files[index].clone(), vec![i])),
+                    }
+                }
+            }
+            for (file, in_file) in probes {
+                // skip the keys found in a newer level-0 file
+                let in_file: Vec<usize> = in_file.into_iter().filter(|i| !resolved[*i]).collect();
+                if in_file.is_empty() {
+                    continue;
+                }
+                for &i in in_file.iter() {
+                    if charged[i] {
+                        continue;
+                    }
+                    match first_file_read[i].take() {
+                        Some((l, f)) => {
+                            seek_stats.push(SeekStats {
+                                seek_file: Some(f),
+                                seek_file_level: Some(l),
+                            });
+                            charged[i] = true;
+                        }
+                        None => first_file_read[i] = Some((level, file.clone())),
+                    }
+                }
+                opt.check_cancelled()?;
+                let ikeys: Vec<Slice> = in_file.iter().map(|i| keys[*i].internal_key()).collect();
+                let ikeys: Vec<&[u8]> = ikeys.iter().map(|k| k.as_slice()).collect();
+                let found = match table_cache.multi_get(
+                    opt.clone(),
+                    &ikeys,
+                    file.number,
+                    file.file_size,
+                )? {
+                    Some(found) => found,
+                    None => {
+                        if let Some(s) = &self.options.statistics {
+                            s.record_tick(Ticker::MultiGetTableSkipped, 1);
+                        }
+                        continue;
+                    }
+                };
+                for (i, entry) in in_file.into_iter().zip(found) {
+                    if let Some((encoded_key, value)) = entry {
+                        let parsed_key = ParsedInternalKey::decode_from(Slice::from(&encoded_key))
+                            .ok_or_else(|| {
+                                WickErr::new(Status::Corruption, Some("bad internal key"))
+                            })?;
+                        if ucmp.compare(
+                            parsed_key.user_key.as_slice(),
+                            keys[i].user_key().as_slice(),
+                        ) == CmpOrdering::Equal
+                        {
+                            match parsed_key.value_type {
+                                ValueType::Value => {
+                                    results[i] = Some(value);
+                                    resolved[i] = true;
+                                }
+                                ValueType::Deletion => resolved[i] = true,
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok((results, seek_stats))
+    }
+
+    /// Returns false if the `ukey` is out of the key range of all the files in `level`
+    #[inline]
+    pub fn level_may_contain(&self, level: usize, ukey: &[u8]) -> bool {
+        match &self.level_fences[level] {
+            Some((smallest, largest)) => {
+                let ucmp = self.icmp.user_comparator.as_ref();
+                ucmp.compare(ukey, smallest.as_slice()) != CmpOrdering::Less
+                    && ucmp.compare(ukey, largest.as_slice()) != CmpOrdering::Greater
+            }
+            None => false,
+        }
+    }
+
     /// Update seek stats for a sstable file. If it runs out of `allow_seek`,
     /// mark it as a pending compaction file and returns true.
     pub fn update_stats(&self, stats: SeekStats) -> bool {
+        if !self.options.seek_compaction {
+            return false;
+        }
         if let Some(f) = stats.seek_file {
-            let old = f.allowed_seeks.fetch_sub(1, Ordering::SeqCst);
+            // never wraps around after the file runs out of seeks
+            let old = match f
+                .allowed_seeks
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            {
+                Ok(old) => old,
+                Err(_) => return false,
+            };
             let mut file_to_compact = self.file_to_compact.write().unwrap();
             if file_to_compact.is_none() && old == 1 {
                 *file_to_compact = Some(f);
                 self.file_to_compact_level
                     .store(stats.seek_file_level.unwrap(), Ordering::Release);
+                if let Some(s) = &self.options.statistics {
+                    s.record_tick(Ticker::SeekCompactionTriggered, 1);
+                }
                 return true;
             }
         }
         false
     }
 
+    /// Returns the (level, file) of the files whose ratio of the deletions reaches
+    /// `Options::bottommost_deletion_ratio` while no deeper level overlaps them,
+    /// so that the deletions are dropped by compacting them into the next level.
+    /// The files without readable properties are skipped.
+    pub fn bottommost_deletion_files(
+        &self,
+        table_cache: &TableCache,
+    ) -> Vec<(usize, Arc<FileMetaData>)> {
+        let ratio = self.options.bottommost_deletion_ratio;
+        if ratio <= 0.0 {
+            return vec![];
+        }
+        let mut cached = self.bottommost_deletion_files.lock().unwrap();
+        if let Some(files) = cached.as_ref() {
+            return files.clone();
+        }
+        let mut result = vec![];
+        // the files in the last level have no next level to be compacted into
+        for level in 0..self.files.len() - 1 {
+            for f in self.files[level].iter() {
+                let (smallest, largest) = (
+                    Slice::from(f.smallest.user_key()),
+                    Slice::from(f.largest.user_key()),
+                );
+                if (level + 1..self.files.len())
+                    .any(|l| self.overlap_in_level(l, &smallest, &largest))
+                {
+                    continue;
+                }
+                match table_cache.properties(f.number, f.file_size) {
+                    Ok(p)
+                        if p.num_entries > 0
+                            && p.num_deletions as f64 / p.num_entries as f64 >= ratio =>
+                    {
+                        result.push((level, f.clone()))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        *cached = Some(result.clone());
+        result
+    }
+
+    /// Returns the (level, file) of the files created at least
+    /// `Options::periodic_compaction_seconds` before `now_secs` ordered from the
+    /// oldest. The files without the creation time in their table properties are
+    /// skipped.
+    pub fn periodic_compaction_files(
+        &self,
+        table_cache: &TableCache,
+        now_secs: u64,
+    ) -> Vec<(usize, Arc<FileMetaData>)> {
+        let period = self.options.periodic_compaction_seconds;
+        if period == 0 {
+            return vec![];
+        }
+        let mut cached = self.file_creation_times.lock().unwrap();
+        let files = cached.get_or_insert_with(|| {
+            let mut files = vec![];
+            for (level, level_files) in self.files.iter().enumerate() {
+                for f in level_files.iter() {
+                    if let Ok(p) = table_cache.properties(f.number, f.file_size) {
+                        if p.creation_time > 0 {
+                            files.push((level, f.clone(), p.creation_time));
+                        }
+                    }
+                }
+            }
+            files.sort_by_key(|(_, f, creation_time)| (*creation_time, f.number));
+            files
+        });
+        files
+            .iter()
+            .take_while(|(_, _, creation_time)| creation_time.saturating_add(period) <= now_secs)
+            .map(|(level, f, _)| (*level, f.clone()))
+            .collect()
+    }
+
     /// Return a String includes number of files in every level
     pub fn level_summary(&self) -> String {
         let mut s = String::from("files[ ");
@@ -218,6 +524,31 @@ impl Version {
         s
     }
 
+    /// Returns a readable description of all the files in every level like:
+    ///
+    /// ```text
+    /// --- level 0 ---
+    ///  5:1024["a" @ 1 : Value .. "c" @ 3 : Value]
+    /// --- level 1 ---
+    /// ...
+    /// ```
+    pub fn debug_string(&self) -> String {
+        let mut s = String::new();
+        for (level, files) in self.files.iter().enumerate() {
+            s.push_str(format!("--- level {} ---\n", level).as_str());
+            for f in files.iter() {
+                s.push_str(
+                    format!(
+                        " {}:{}[{:?} .. {:?}]\n",
+                        f.number, f.file_size, f.smallest, f.largest
+                    )
+                    .as_str(),
+                );
+            }
+        }
+        s
+    }
+
     /// Binary search given files to find earliest index of index whose largest key >= ikey.
     /// If not found, returns the length of files.
     pub fn find_file(
@@ -228,7 +559,7 @@ impl Version {
         let mut left = 0;
         let mut right = files.len();
         while left < right {
-            let mid = left + right / 2;
+            let mid = (left + right) / 2;
             let f = &files[mid];
             if icmp.compare(f.largest.data(), ikey.as_slice()) == CmpOrdering::Less {
                 // Key at "mid.largest" is < "target".  Therefore all
@@ -254,12 +585,12 @@ impl Version {
         if !self.overlap_in_level(level, smallest_ukey, largest_ukey) {
             // No overlapping in level 0
             // we might directly push files to next level if there is no overlap in next level
-            let smallest_ikey = Rc::new(InternalKey::new(
+            let smallest_ikey = Arc::new(InternalKey::new(
                 smallest_ukey,
-                u64::max_value(),
+                MAX_KEY_SEQUENCE,
                 VALUE_TYPE_FOR_SEEK,
             ));
-            let largest_ikey = Rc::new(InternalKey::new(largest_ukey, 0, ValueType::Deletion));
+            let largest_ikey = Arc::new(InternalKey::new(largest_ukey, 0, ValueType::Deletion));
             while level < self.options.max_mem_compact_level {
                 if self.overlap_in_level(level + 1, smallest_ukey, largest_ukey) {
                     break;
@@ -289,7 +620,9 @@ impl Version {
         // pre-computed best level for next compaction
         let mut best_level = 0;
         let mut best_score = 0.0;
-        for level in 0..self.options.max_levels as usize {
+        let mut pending_bytes = 0;
+        self.level_max_bytes = self.compute_level_max_bytes();
+        for level in 0..self.options.max_levels as usize - 1 {
             let score = {
                 if level == 0 {
                     // We treat level-0 specially by bounding the number of files
@@ -303,12 +636,28 @@ impl Version {
                     // file size is small (perhaps because of a small write-buffer
                     // setting, or very high compression ratios, or lots of
                     // overwrites/deletions)
+                    if self.files[level].len() >= self.options.l0_compaction_threshold {
+                        // all the level0 files will be merged into level1
+                        pending_bytes += VersionSet::total_file_size(self.files[level].as_ref());
+                    }
                     self.files[level].len() as f64 / self.options.l0_compaction_threshold as f64
                 } else {
                     let level_bytes = VersionSet::total_file_size(self.files[level].as_ref());
-                    level_bytes as f64 / self.options.max_bytes_for_level(level) as f64
+                    let max_bytes = self.level_max_bytes[level];
+                    if level_bytes > max_bytes {
+                        pending_bytes += level_bytes - max_bytes;
+                    }
+                    if max_bytes > 0 {
+                        level_bytes as f64 / max_bytes as f64
+                    } else if level_bytes > 0 {
+                        // a level above the base level is drained before the others
+                        (level_bytes as f64 / self.options.l1_max_bytes.max(1) as f64).max(1.0)
+                    } else {
+                        0.0
+                    }
                 }
             };
+            self.compaction_scores[level] = score as f32;
             if score > best_score {
                 best_score = score;
                 best_level = level;
@@ -316,6 +665,83 @@ impl Version {
         }
         self.compaction_level = best_level;
         self.compaction_score = best_score as f32;
+        self.pending_compaction_bytes = pending_bytes;
+        self.update_level_fences();
+    }
+
+    // Returns the target size of every level. See `Options::level_compaction_dynamic_level_bytes`.
+    fn compute_level_max_bytes(&self) -> Vec<u64> {
+        let max_levels = self.options.max_levels as usize;
+        let mut targets = vec![0; max_levels];
+        if !self.options.level_compaction_dynamic_level_bytes {
+            for (level, target) in targets.iter_mut().enumerate().skip(1) {
+                *target = self.options.max_bytes_for_level(level);
+            }
+            return targets;
+        }
+        let base_bytes = self.options.l1_max_bytes.max(1);
+        let largest = self.files[1..]
+            .iter()
+            .map(|files| VersionSet::total_file_size(files))
+            .max()
+            .unwrap_or(0);
+        // shrink by 10 times from the last level up until the target is under
+        // `base_bytes * 10`
+        let mut base_level = max_levels - 1;
+        let mut target = largest;
+        while base_level > 1 && target / 10 >= base_bytes {
+            target /= 10;
+            base_level -= 1;
+        }
+        let mut target = target.max(base_bytes);
+        for level_target in targets.iter_mut().skip(base_level) {
+            *level_target = target;
+            target = target.saturating_mul(10);
+        }
+        targets
+    }
+
+    /// Returns the target size of `level`, which is 0 for level0 and the levels
+    /// kept empty by `Options::level_compaction_dynamic_level_bytes`
+    #[inline]
+    pub fn level_max_bytes(&self, level: usize) -> u64 {
+        self.level_max_bytes[level]
+    }
+
+    /// Returns the estimated bytes compactions need to rewrite to bring every
+    /// level under its target size: all the level0 files once a level0 compaction
+    /// is triggered plus the bytes exceeding the limit in the other levels.
+    #[inline]
+    pub fn pending_compaction_bytes(&self) -> u64 {
+        self.pending_compaction_bytes
+    }
+
+    // Re-calculates the user key range of every level
+    fn update_level_fences(&mut self) {
+        let ucmp = self.icmp.user_comparator.clone();
+        for (level, files) in self.files.iter().enumerate() {
+            self.level_fences[level] = if files.is_empty() {
+                None
+            } else if level == 0 {
+                // level0 files may overlap each other and are not sorted by keys
+                let mut smallest = files[0].smallest.user_key();
+                let mut largest = files[0].largest.user_key();
+                for f in files.iter().skip(1) {
+                    if ucmp.compare(f.smallest.user_key(), smallest) == CmpOrdering::Less {
+                        smallest = f.smallest.user_key();
+                    }
+                    if ucmp.compare(f.largest.user_key(), largest) == CmpOrdering::Greater {
+                        largest = f.largest.user_key();
+                    }
+                }
+                Some((smallest.to_vec(), largest.to_vec()))
+            } else {
+                Some((
+                    files.first().unwrap().smallest.user_key().to_vec(),
+                    files.last().unwrap().largest.user_key().to_vec(),
+                ))
+            }
+        }
     }
 
     /// Returns `icmp`
@@ -452,7 +878,7 @@ impl Version {
         let index = {
             if !smallest_ukey.is_empty() {
                 let smallest_ikey =
-                    InternalKey::new(smallest_ukey, u64::max_value(), VALUE_TYPE_FOR_SEEK);
+                    InternalKey::new(smallest_ukey, MAX_KEY_SEQUENCE, VALUE_TYPE_FOR_SEEK);
                 Self::find_file(
                     self.icmp.clone(),
                     &self.files[level],
@@ -498,8 +924,8 @@ impl Version {
     fn get_overlapping_inputs(
         &self,
         level: usize,
-        begin: Option<Rc<InternalKey>>,
-        end: Option<Rc<InternalKey>>,
+        begin: Option<Arc<InternalKey>>,
+        end: Option<Arc<InternalKey>>,
     ) -> Vec<Arc<FileMetaData>> {
         // TODO: the implementation treating level 0 files is somewhat tricky ( since we use unsafe pointer ).
         //       Consider separate this into two single functions: one for level 0, one for level > 0
@@ -578,6 +1004,16 @@ impl LevelFileNumIterator {
     fn valid_or_panic(&self) {
         assert!(self.valid(), "[level file num iterator] out of bounds")
     }
+
+    // Encode the number and size of current file into `value_buf`
+    fn fill_value_buf(&mut self) {
+        self.value_buf.clear();
+        if self.valid() {
+            let file = &self.files[self.index];
+            put_fixed_64(&mut self.value_buf, file.number);
+            put_fixed_64(&mut self.value_buf, file.file_size);
+        }
+    }
 }
 
 impl Iterator for LevelFileNumIterator {
@@ -587,6 +1023,7 @@ impl Iterator for LevelFileNumIterator {
 
     fn seek_to_first(&mut self) {
         self.index = 0;
+        self.fill_value_buf();
     }
 
     fn seek_to_last(&mut self) {
@@ -595,20 +1032,18 @@ impl Iterator for LevelFileNumIterator {
         } else {
             self.index = self.files.len() - 1;
         }
+        self.fill_value_buf();
     }
 
     fn seek(&mut self, target: &Slice) {
-        let index = Version::find_file(self.icmp.clone(), self.files.as_slice(), target);
-        self.index = index;
-        let file = &self.files[index];
-        // fill the buf
-        put_fixed_64(&mut self.value_buf, file.number);
-        put_fixed_64(&mut self.value_buf, file.file_size);
+        self.index = Version::find_file(self.icmp.clone(), self.files.as_slice(), target);
+        self.fill_value_buf();
     }
 
     fn next(&mut self) {
         self.valid_or_panic();
         self.index += 1;
+        self.fill_value_buf();
     }
 
     fn prev(&mut self) {
@@ -619,6 +1054,7 @@ impl Iterator for LevelFileNumIterator {
         } else {
             self.index -= 1;
         }
+        self.fill_value_buf();
     }
 
     // make sure the underlying data's lifetime is longer than returning Slice
@@ -637,3 +1073,137 @@ impl Iterator for LevelFileNumIterator {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem::MemStorage;
+    use crate::util::comparator::BytewiseComparator;
+
+    fn new_file(number: u64, smallest: &str, largest: &str) -> Arc<FileMetaData> {
+        let mut f = FileMetaData::default();
+        f.number = number;
+        f.file_size = 1024;
+        f.smallest = Arc::new(InternalKey::new(
+            &Slice::from(smallest),
+            100,
+            ValueType::Value,
+        ));
+        f.largest = Arc::new(InternalKey::new(
+            &Slice::from(largest),
+            100,
+            ValueType::Value,
+        ));
+        Arc::new(f)
+    }
+
+    fn new_version(options: Arc<Options>) -> Version {
+        let icmp = Arc::new(InternalKeyComparator::new(Arc::new(
+            BytewiseComparator::new(),
+        )));
+        let mut v = Version::new(options, icmp);
+        v.files[0] = vec![new_file(3, "m", "p"), new_file(4, "c", "f")];
+        v.files[1] = vec![new_file(1, "b", "d"), new_file(2, "k", "q")];
+        v.finalize();
+        v
+    }
+
+    #[test]
+    fn test_level_fences() {
+        let v = new_version(Arc::new(Options::default()));
+        assert!(!v.level_may_contain(0, b"a"));
+        assert!(v.level_may_contain(0, b"c"));
+        // inside the fence but in the gap between the files
+        assert!(v.level_may_contain(0, b"h"));
+        assert!(v.level_may_contain(0, b"p"));
+        assert!(!v.level_may_contain(0, b"pa"));
+        assert!(v.level_may_contain(1, b"b"));
+        assert!(v.level_may_contain(1, b"q"));
+        assert!(!v.level_may_contain(1, b"r"));
+        assert!(!v.level_may_contain(2, b"c"));
+    }
+
+    #[test]
+    fn test_dynamic_level_bytes() {
+        let mut options = Options::default();
+        options.level_compaction_dynamic_level_bytes = true;
+        options.l1_max_bytes = 1000;
+        let mut v = new_version(Arc::new(options));
+        // all the data goes to the last level, sized by the largest level, while
+        // the db is small
+        assert_eq!(v.level_max_bytes(6), 2048);
+        assert!((1..6).all(|level| v.level_max_bytes(level) == 0));
+        // the level1 files above the base level are pushed down
+        assert!(v.compaction_scores[1] >= 1.0);
+        assert!(v.pending_compaction_bytes() >= 2048);
+
+        v.files[4] = vec![new_file(10, "a", "z")];
+        v.files[6] = (0..100)
+            .map(|i| new_file(100 + i, &format!("k{:03}", i), &format!("k{:03}", i)))
+            .collect();
+        v.finalize();
+        assert_eq!(v.level_max_bytes(6), 102400);
+        assert_eq!(v.level_max_bytes(5), 10240);
+        assert_eq!(v.level_max_bytes(4), 1024);
+        assert_eq!(v.level_max_bytes(3), 0);
+        assert_eq!(v.compaction_scores[4], 1.0);
+        assert_eq!(v.compaction_scores[5], 0.0);
+        assert!(v.compaction_scores[1] > 2.0);
+    }
+
+    #[test]
+    fn test_pending_compaction_bytes() {
+        let v = new_version(Arc::new(Options::default()));
+        assert_eq!(v.pending_compaction_bytes(), 0);
+
+        let mut options = Options::default();
+        options.l0_compaction_threshold = 2;
+        options.l1_max_bytes = 1500;
+        let v = new_version(Arc::new(options));
+        // both the level0 files and the bytes exceeding the level1 limit
+        assert_eq!(v.pending_compaction_bytes(), 2048 + 548);
+    }
+
+    #[test]
+    fn test_pick_level_for_memtable_output() {
+        let options = Arc::new(Options::default());
+        let icmp = Arc::new(InternalKeyComparator::new(Arc::new(
+            BytewiseComparator::new(),
+        )));
+        let v = Version::new(options.clone(), icmp);
+        // nothing overlaps so the output is pushed down as far as allowed
+        assert_eq!(
+            v.pick_level_for_memtable_output(&Slice::from("a"), &Slice::from("z")),
+            options.max_mem_compact_level
+        );
+    }
+
+    #[test]
+    fn test_get_skips_levels_out_of_fences() {
+        let mut options = Options::default();
+        options.env = Arc::new(MemStorage::default());
+        let options = Arc::new(options);
+        // none of the files exists so any table cache access fails
+        let table_cache = Arc::new(TableCache::new("db".to_owned(), options.clone(), 10));
+        let v = new_version(options);
+        for key in &["a", "r", "z"] {
+            let (value, stats) = v
+                .get(
+                    ReadOptions::default(),
+                    LookupKey::new(key.as_bytes(), 200),
+                    table_cache.clone(),
+                )
+                .expect("table cache should not be touched");
+            assert!(value.is_none());
+            assert!(stats.seek_file.is_none());
+        }
+        // keys covered by a file go to the table cache
+        assert!(v
+            .get(
+                ReadOptions::default(),
+                LookupKey::new(b"c", 200),
+                table_cache
+            )
+            .is_err());
+    }
+}
@@ -26,8 +26,8 @@ use crate::version::version_edit::Tag::{
 use hashbrown::HashSet;
 use std::fmt::{Debug, Formatter};
 use std::mem;
-use std::rc::Rc;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 // Tags for the VersionEdit disk format.
 // Tag 8 is no longer used.
@@ -77,9 +77,9 @@ pub struct FileMetaData {
     // the file number
     pub number: u64,
     // Smallest internal key served by table
-    pub smallest: Rc<InternalKey>,
+    pub smallest: Arc<InternalKey>,
     // Largest internal key served by table
-    pub largest: Rc<InternalKey>,
+    pub largest: Arc<InternalKey>,
 }
 
 impl Default for FileMetaData {
@@ -88,8 +88,20 @@ impl Default for FileMetaData {
             allowed_seeks: AtomicUsize::new(0),
             file_size: 0,
             number: 0,
-            smallest: Rc::new(InternalKey::default()),
-            largest: Rc::new(InternalKey::default()),
+            smallest: Arc::new(InternalKey::default()),
+            largest: Arc::new(InternalKey::default()),
+        }
+    }
+}
+
+impl Clone for FileMetaData {
+    fn clone(&self) -> Self {
+        FileMetaData {
+            allowed_seeks: AtomicUsize::new(self.allowed_seeks.load(Ordering::Acquire)),
+            file_size: self.file_size,
+            number: self.number,
+            smallest: self.smallest.clone(),
+            largest: self.largest.clone(),
         }
     }
 }
@@ -108,11 +120,11 @@ pub struct VersionEdit {
     pub last_sequence: Option<u64>,
 
     // (level, InternalKey)
-    pub compaction_pointers: Vec<(usize, Rc<InternalKey>)>,
+    pub compaction_pointers: Vec<(usize, Arc<InternalKey>)>,
     // (level, file_number)
     pub deleted_files: HashSet<(usize, u64)>,
     // (level, FileMetaData)
-    pub new_files: Vec<(usize, Rc<FileMetaData>)>,
+    pub new_files: Vec<(usize, Arc<FileMetaData>)>,
 }
 
 impl VersionEdit {
@@ -149,12 +161,12 @@ impl VersionEdit {
         level: usize,
         file_number: u64,
         file_size: u64,
-        smallest: Rc<InternalKey>,
-        largest: Rc<InternalKey>,
+        smallest: Arc<InternalKey>,
+        largest: Arc<InternalKey>,
     ) {
         self.new_files.push((
             level,
-            Rc::new(FileMetaData {
+            Arc::new(FileMetaData {
                 allowed_seeks: AtomicUsize::new(0),
                 file_size,
                 number: file_number,
@@ -173,7 +185,7 @@ impl VersionEdit {
     #[inline]
     #[allow(dead_code)]
     pub fn add_compaction_pointer(&mut self, level: usize, key: InternalKey) {
-        self.compaction_pointers.push((level, Rc::new(key)))
+        self.compaction_pointers.push((level, Arc::new(key)))
     }
 
     #[inline]
@@ -296,7 +308,7 @@ impl VersionEdit {
                         if let Some(level) = get_level(self.max_levels, &mut s) {
                             if let Some(key) = get_internal_key(&mut s) {
                                 self.compaction_pointers
-                                    .push((level as usize, Rc::new(key)));
+                                    .push((level as usize, Arc::new(key)));
                                 continue;
                             }
                         }
@@ -321,12 +333,12 @@ impl VersionEdit {
                                         if let Some(largest) = get_internal_key(&mut s) {
                                             self.new_files.push((
                                                 level as usize,
-                                                Rc::new(FileMetaData {
+                                                Arc::new(FileMetaData {
                                                     allowed_seeks: AtomicUsize::new(0),
                                                     file_size,
                                                     number,
-                                                    smallest: Rc::new(smallest),
-                                                    largest: Rc::new(largest),
+                                                    smallest: Arc::new(smallest),
+                                                    largest: Arc::new(largest),
                                                 }),
                                             ));
                                             continue;
@@ -428,7 +440,7 @@ mod tests {
     use crate::db::format::{InternalKey, ValueType};
     use crate::util::slice::Slice;
     use crate::version::version_edit::VersionEdit;
-    use std::rc::Rc;
+    use std::sync::Arc;
 
     fn assert_encode_decode(edit: &VersionEdit) {
         let mut encoded = vec![];
@@ -450,12 +462,12 @@ mod tests {
                 3,
                 k_big + 300 + i,
                 k_big + 400 + i,
-                Rc::new(InternalKey::new(
+                Arc::new(InternalKey::new(
                     &Slice::from("foo"),
                     k_big + 500 + i,
                     ValueType::Value,
                 )),
-                Rc::new(InternalKey::new(
+                Arc::new(InternalKey::new(
                     &Slice::from("zoo"),
                     k_big + 700 + i,
                     ValueType::Deletion,
@@ -20,34 +20,38 @@ use crate::db::build_table;
 use crate::db::filename::{generate_filename, parse_filename, update_current, FileType};
 use crate::db::format::{InternalKey, InternalKeyComparator};
 use crate::iterator::{ConcatenateIterator, DerivedIterFactory, EmptyIterator, Iterator};
-use crate::options::Options;
+use crate::listener::{
+    CompactionReason, NewTableFileInfo, TableFileCreationInfo, TableFileCreationReason,
+    VersionEditInfo,
+};
+use crate::options::{Options, SyncPolicy};
 use crate::record::reader::Reader;
 use crate::record::writer::Writer;
 use crate::snapshot::{Snapshot, SnapshotList};
 use crate::sstable::table::TableBuilder;
+use crate::statistics::{HistogramType, LevelReadScope, LevelTicker, Ticker};
 use crate::table_cache::TableCache;
 use crate::util::coding::decode_fixed_64;
-use crate::util::comparator::{BytewiseComparator, Comparator};
+use crate::util::comparator::Comparator;
 use crate::util::reporter::LogReporter;
 use crate::util::slice::Slice;
 use crate::util::status::{Result, Status, WickErr};
 use crate::version::version_edit::{FileMetaData, VersionEdit};
 use crate::version::{LevelFileNumIterator, Version, FILE_META_LENGTH};
 use crate::ReadOptions;
-use hashbrown::HashSet;
+use hashbrown::{HashMap, HashSet};
 use std::cmp::Ordering as CmpOrdering;
 use std::collections::vec_deque::VecDeque;
 use std::path::MAIN_SEPARATOR;
 use std::rc::Rc;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::SystemTime;
 
 struct LevelState {
     // set of new deleted files
     deleted_files: HashSet<u64>,
     // all new added files
-    added_files: Vec<Rc<FileMetaData>>,
+    added_files: Vec<Arc<FileMetaData>>,
 }
 
 /// Summarizes the files added and deleted from a set of version edits.
@@ -96,11 +100,10 @@ impl VersionBuilder {
             // same as the compaction of 40KB of data.  We are a little
             // conservative and allow approximately one seek for every 16KB
             // of data before triggering a compaction.
-            // TODO: config 16 * 1024 as an option
-            let mut allowed_seeks = new_file.file_size as usize / (16 * 1024);
-            if allowed_seeks < 100 {
-                allowed_seeks = 100 // the min seeks allowed
-            }
+            // See `Options::bytes_per_seek` and `Options::min_allowed_seeks`.
+            let options = &self.base.options;
+            let allowed_seeks = ((new_file.file_size / options.bytes_per_seek) as usize)
+                .max(options.min_allowed_seeks);
             new_file
                 .allowed_seeks
                 .store(allowed_seeks, Ordering::Release);
@@ -112,10 +115,7 @@ impl VersionBuilder {
     /// Apply all the changes on the base Version and produce a new Version based on it
     /// same as `save_to` in C++ implementation
     pub fn apply_to_new(&mut self) -> Version {
-        // TODO: config this to the option
-        let icmp = Arc::new(InternalKeyComparator::new(Arc::new(
-            BytewiseComparator::new(),
-        )));
+        let icmp = self.base.icmp.clone();
         let mut v = Version::new(self.base.options.clone(), icmp.clone());
         for (level, (mut base_files, delta)) in self
             .base
@@ -130,6 +130,11 @@ impl VersionBuilder {
                     v.files[level].push(file)
                 }
             }
+            for file in delta.added_files.iter() {
+                if !delta.deleted_files.contains(&file.number) {
+                    v.files[level].push(Arc::new(file.as_ref().clone()))
+                }
+            }
             if level == 0 {
                 // sort by file number
                 v.files[level].sort_by(|a, b| {
@@ -160,8 +165,15 @@ pub struct VersionSet {
     pub pending_outputs: HashSet<u64>,
     // Represent a manual compaction, temporarily just for test
     pub manual_compaction: Option<ManualCompaction>,
+    // Input files of the running compactions
+    being_compacted: HashSet<u64>,
+    // The output level and the user key range of every running compaction
+    running_compactions: Vec<(usize, Vec<u8>, Vec<u8>)>,
     // WAL writer
     pub record_writer: Option<Writer>,
+    // file number of the WAL written by `record_writer`. The `log_number` is
+    // set to it after the memtables in the previous WALs are flushed.
+    pub record_writer_number: u64,
 
     // db path
     db_name: String,
@@ -183,7 +195,7 @@ pub struct VersionSet {
     versions: VecDeque<Arc<Version>>,
 
     // Indicates that every level's compaction progress of last compaction.
-    compaction_pointer: Vec<Rc<InternalKey>>,
+    compaction_pointer: Vec<Arc<InternalKey>>,
 }
 
 unsafe impl Send for VersionSet {}
@@ -199,8 +211,11 @@ impl VersionSet {
             compaction_stats,
             pending_outputs: HashSet::new(),
             manual_compaction: None,
+            being_compacted: HashSet::new(),
+            running_compactions: vec![],
             db_name,
             record_writer: None,
+            record_writer_number: 0,
             options: options.clone(),
             icmp: Arc::new(InternalKeyComparator::new(options.comparator.clone())),
             next_file_number: 0,
@@ -210,7 +225,9 @@ impl VersionSet {
             manifest_file_number: 0,
             manifest_writer: None,
             versions: VecDeque::new(),
-            compaction_pointer: vec![],
+            compaction_pointer: (0..options.max_levels)
+                .map(|_| Arc::new(InternalKey::default()))
+                .collect(),
         }
     }
     /// Returns the number of files in a certain level
@@ -220,6 +237,19 @@ impl VersionSet {
         self.versions.front().unwrap().files[level].len()
     }
 
+    /// Returns the total file size in a certain level
+    #[inline]
+    pub fn level_files_size(&self, level: usize) -> u64 {
+        assert!(level < self.options.max_levels as usize);
+        Self::total_file_size(&self.versions.front().unwrap().files[level])
+    }
+
+    /// Returns the estimated pending compaction bytes of the current version
+    #[inline]
+    pub fn pending_compaction_bytes(&self) -> u64 {
+        self.versions.front().unwrap().pending_compaction_bytes()
+    }
+
     /// Returns `prev_log_number`
     #[inline]
     pub fn prev_log_number(&self) -> u64 {
@@ -243,9 +273,12 @@ impl VersionSet {
     pub fn needs_compaction(&self) -> bool {
         if self.manual_compaction.is_some() {
             true
+        } else if self.options.fifo_max_table_files_size > 0 {
+            // Never compacts in the FIFO mode
+            false
         } else {
             let current = self.current();
-            current.compaction_score > 1.0 || current.file_to_compact.read().unwrap().is_some()
+            current.compaction_score >= 1.0 || current.file_to_compact.read().unwrap().is_some()
         }
     }
 
@@ -275,6 +308,76 @@ impl VersionSet {
         self.manifest_file_number
     }
 
+    /// Returns a JSON dump of the current version, see `DB::version_debug_json`
+    pub fn debug_json(&self) -> String {
+        let current = self.current();
+        let file_to_compact = match current.file_to_compact.read().unwrap().as_ref() {
+            Some(f) => format!(
+                "{{\"level\":{},\"number\":{}}}",
+                current.file_to_compact_level.load(Ordering::Acquire),
+                f.number
+            ),
+            None => "null".to_owned(),
+        };
+        let mut s = format!(
+            "{{\"last_sequence\":{},\"next_file_number\":{},\"log_number\":{},\
+             \"prev_log_number\":{},\"manifest_number\":{},\"compaction_level\":{},\
+             \"compaction_score\":{},\"file_to_compact\":{},\"levels\":[",
+            self.last_sequence,
+            self.next_file_number,
+            self.log_number,
+            self.prev_log_number,
+            self.manifest_file_number,
+            current.compaction_level,
+            score_json(current.compaction_score),
+            file_to_compact,
+        );
+        for (level, files) in current.files.iter().enumerate() {
+            if level > 0 {
+                s.push(',');
+            }
+            let pointer = &self.compaction_pointer[level];
+            s.push_str(
+                format!(
+                    "{{\"level\":{},\"num_files\":{},\"total_size\":{},\"max_bytes\":{},\
+                     \"compaction_score\":{},\"compaction_pointer\":{},\"files\":[",
+                    level,
+                    files.len(),
+                    Self::total_file_size(files),
+                    current.level_max_bytes(level),
+                    score_json(current.compaction_scores[level]),
+                    if pointer.is_empty() {
+                        "null".to_owned()
+                    } else {
+                        internal_key_json(pointer)
+                    },
+                )
+                .as_str(),
+            );
+            for (i, f) in files.iter().enumerate() {
+                if i > 0 {
+                    s.push(',');
+                }
+                s.push_str(
+                    format!(
+                        "{{\"number\":{},\"size\":{},\"allowed_seeks\":{},\
+                         \"being_compacted\":{},\"smallest\":{},\"largest\":{}}}",
+                        f.number,
+                        f.file_size,
+                        f.allowed_seeks.load(Ordering::Acquire),
+                        self.being_compacted.contains(&f.number),
+                        internal_key_json(&f.smallest),
+                        internal_key_json(&f.largest),
+                    )
+                    .as_str(),
+                );
+            }
+            s.push_str("]}");
+        }
+        s.push_str("]}");
+        s
+    }
+
     /// Returns the last sequence of the version set
     #[inline]
     pub fn last_sequence(&self) -> u64 {
@@ -287,6 +390,14 @@ impl VersionSet {
         self.last_sequence = new
     }
 
+    /// Syncs the MANIFEST being written if any
+    pub(crate) fn sync_manifest(&mut self) -> Result<()> {
+        match self.manifest_writer.as_mut() {
+            Some(writer) => writer.sync(),
+            None => Ok(()),
+        }
+    }
+
     /// Get the current newest version
     #[inline]
     pub fn current(&self) -> Arc<Version> {
@@ -299,31 +410,52 @@ impl VersionSet {
         self.snapshots.snapshot(self.last_sequence)
     }
 
-    /// Returns the collection of all the file iterators in current version
+    /// Returns the collection of all the file iterators in current version.
+    /// The files out of the iterate bounds of `read_opt` are skipped.
     pub fn current_iters(
         &self,
         read_opt: Rc<ReadOptions>,
         table_cache: Arc<TableCache>,
     ) -> Vec<Box<dyn Iterator>> {
         let version = self.current();
+        let ucmp = self.options.comparator.clone();
+        let in_bounds = |file: &Arc<FileMetaData>| {
+            let below_upper = read_opt.iterate_upper_bound.as_ref().is_none_or(|upper| {
+                ucmp.compare(file.smallest.user_key(), upper) == CmpOrdering::Less
+            });
+            let above_lower = read_opt.iterate_lower_bound.as_ref().is_none_or(|lower| {
+                ucmp.compare(file.largest.user_key(), lower) != CmpOrdering::Less
+            });
+            below_upper && above_lower
+        };
+        let icmp = Arc::new(InternalKeyComparator::new(self.options.comparator.clone()));
+        let (lower, upper) = read_opt.internal_key_bounds();
         let mut res = vec![];
+        // Attributes the blocks read by the iterators to their levels
+        let tag_level = |level: usize, iter: Box<dyn Iterator>| -> Box<dyn Iterator> {
+            if self.options.statistics.is_some() {
+                Box::new(LevelReadIterator { level, inner: iter })
+            } else {
+                iter
+            }
+        };
         // Merge all level zero files together since they may overlap
-        for file in version.files[0].iter() {
-            res.push(table_cache.new_iter(read_opt.clone(), file.number, file.file_size));
+        for file in version.files[0].iter().filter(|f| in_bounds(f)) {
+            let iter = table_cache.new_iter(read_opt.clone(), file.number, file.file_size);
+            res.push(tag_level(0, iter));
         }
 
         // For levels > 0, we can use a concatenating iterator that sequentially
         // walks through the non-overlapping files in the level, opening them
         // lazily
-        for files in version.files.iter().skip(1) {
+        for (level, files) in version.files.iter().enumerate().skip(1) {
+            let files: Vec<_> = files.iter().filter(|f| in_bounds(f)).cloned().collect();
             if !files.is_empty() {
-                let level_file_iter = LevelFileNumIterator::new(
-                    Arc::new(InternalKeyComparator::new(self.options.comparator.clone())),
-                    files.clone(),
-                );
+                let level_file_iter = LevelFileNumIterator::new(icmp.clone(), files);
                 let factory = FileIterFactory::new(read_opt.clone(), table_cache.clone());
-                let iter = ConcatenateIterator::new(Box::new(level_file_iter), Box::new(factory));
-                res.push(Box::new(iter));
+                let iter = ConcatenateIterator::new(Box::new(level_file_iter), Box::new(factory))
+                    .with_bounds(icmp.clone(), lower.clone(), upper.clone());
+                res.push(tag_level(level, Box::new(iter)));
             }
         }
         res
@@ -356,14 +488,12 @@ impl VersionSet {
         edit.encode_to(&mut record);
 
         let mut v = Version::new(self.options.clone(), self.icmp.clone());
+        v.files = self.current().files.clone();
         let mut builder = VersionBuilder::new(v);
         builder.accumulate(&edit, self);
         v = builder.apply_to_new();
         v.finalize();
 
-        // cleanup all the old versions
-        self.gc();
-
         // Initialize new manifest file if necessary by creating a temporary file that contains a snapshot of the current version.
         let mut new_manifest_file = String::new();
         if self.manifest_writer.is_none() {
@@ -375,12 +505,11 @@ impl VersionSet {
             //            edit.set_next_file(self.next_file_number);
             let f = self.options.env.create(new_manifest_file.as_str())?;
             let mut writer = Writer::new(f);
-            match self.write_snapshot(&mut writer) {
-                Ok(()) => self.manifest_writer = Some(writer),
-                Err(_) => {
-                    return self.options.env.remove(new_manifest_file.as_str());
-                }
+            if let Err(e) = self.write_snapshot(&mut writer) {
+                self.remove_new_manifest(new_manifest_file.as_str());
+                return Err(e);
             }
+            self.manifest_writer = Some(writer);
         }
 
         // Write to current MANIFEST
@@ -389,7 +518,14 @@ impl VersionSet {
         if let Some(writer) = self.manifest_writer.as_mut() {
             match writer.add_record(&Slice::from(record.as_slice())) {
                 Ok(()) => {
-                    match writer.sync() {
+                    // A new MANIFEST is synced before the CURRENT points to it
+                    let need_sync = match self.options.manifest_sync_policy {
+                        SyncPolicy::Always => true,
+                        SyncPolicy::OnClose => !new_manifest_file.is_empty(),
+                        SyncPolicy::Never => false,
+                    };
+                    let status = if need_sync { writer.sync() } else { Ok(()) };
+                    match status {
                         Ok(()) => {
                             // If we just created a MANIFEST file, install it by writing a
                             // new CURRENT file that points to it.
@@ -398,44 +534,90 @@ impl VersionSet {
                                     self.options.env.clone(),
                                     self.db_name.as_str(),
                                     self.manifest_file_number,
+                                    self.options.manifest_sync_policy != SyncPolicy::Never,
                                 ) {
                                     Ok(()) => {}
-                                    Err(_) => {
+                                    Err(e) => {
                                         self.manifest_writer = None;
-                                        return self.options.env.remove(new_manifest_file.as_str());
+                                        self.remove_new_manifest(new_manifest_file.as_str());
+                                        return Err(e);
                                     }
                                 }
                             }
-                            // install new version
+                            // install new version and cleanup all the old versions
                             self.versions.push_front(Arc::new(v));
+                            self.gc();
                             self.log_number = edit.log_number.unwrap();
                             self.prev_log_number = edit.prev_log_number.unwrap();
+                            if !self.options.listeners.is_empty() {
+                                let info = self.version_edit_info(edit);
+                                for listener in self.options.listeners.iter() {
+                                    listener.on_version_edit_applied(&info);
+                                }
+                            }
                         }
-                        // omit the sync error
                         Err(e) => {
                             info!("MANIFEST write: {:?}", e);
                             self.manifest_writer = None;
-                            return self.options.env.remove(new_manifest_file.as_str());
+                            self.remove_new_manifest(new_manifest_file.as_str());
+                            return Err(e);
                         }
                     }
                 }
-                Err(_) => {
+                Err(e) => {
                     self.manifest_writer = None;
-                    return self.options.env.remove(new_manifest_file.as_str());
+                    self.remove_new_manifest(new_manifest_file.as_str());
+                    return Err(e);
                 }
             }
         }
         Ok(())
     }
 
+    // Removes the MANIFEST created by a failed `log_and_apply`. Nothing is removed
+    // if the edit was written into the existing one.
+    fn remove_new_manifest(&self, name: &str) {
+        if !name.is_empty() {
+            if let Err(e) = self.options.env.remove(name) {
+                info!("Fail to remove the new MANIFEST {}: {:?}", name, e);
+            }
+        }
+    }
+
+    // Describes an applied `VersionEdit` for the listeners
+    fn version_edit_info(&self, edit: &VersionEdit) -> VersionEditInfo {
+        let new_files = edit
+            .new_files
+            .iter()
+            .map(|(level, f)| NewTableFileInfo {
+                level: *level,
+                file_number: f.number,
+                file_size: f.file_size,
+                smallest_key: f.smallest.user_key().to_vec(),
+                largest_key: f.largest.user_key().to_vec(),
+            })
+            .collect();
+        let mut deleted_files: Vec<(usize, u64)> = edit.deleted_files.iter().cloned().collect();
+        deleted_files.sort();
+        VersionEditInfo {
+            db_name: self.db_name.clone(),
+            log_number: edit.log_number.unwrap_or(self.log_number),
+            prev_log_number: edit.prev_log_number.unwrap_or(self.prev_log_number),
+            next_file_number: edit.next_file_number.unwrap_or(self.next_file_number),
+            last_sequence: edit.last_sequence.unwrap_or(self.last_sequence),
+            new_files,
+            deleted_files,
+        }
+    }
+
     /// Return a compaction object for compacting the range `[begin,end]` in
     /// the specified level.  Returns `None` if there is nothing in that
     /// level that overlaps the specified range
     pub fn compact_range(
         &mut self,
         level: usize,
-        begin: Option<Rc<InternalKey>>,
-        end: Option<Rc<InternalKey>>,
+        begin: Option<Arc<InternalKey>>,
+        end: Option<Arc<InternalKey>>,
     ) -> Option<Compaction> {
         let version = self.current();
         let mut overlapping_inputs = version.get_overlapping_inputs(level, begin, end);
@@ -457,67 +639,228 @@ impl VersionSet {
             }
         }
         let mut c = Compaction::new(self.options.clone(), level);
+        c.reason = CompactionReason::Manual;
         c.input_version = Some(version.clone());
         c.inputs[0] = overlapping_inputs;
         Some(self.setup_other_inputs(c))
     }
 
     /// Pick level and inputs for a new compaction.
-    /// Returns `None` if there is no compaction to be done.
+    /// Returns `None` if there is no compaction to be done or all the
+    /// candidates conflict with the running compactions.
     /// Otherwise returns compaction object that
     /// describes the compaction.
     pub fn pick_compaction(&mut self) -> Option<Compaction> {
-        let current = self.current();
-        let size_compaction = current.compaction_score >= 1.0;
-        let mut file_to_compact = Arc::new(FileMetaData::default());
-        let mut seek_compaction = false;
-        {
-            let guard = current.file_to_compact.read().unwrap();
-            if let Some(f) = &(*guard) {
-                file_to_compact = f.clone();
-                seek_compaction = true;
-            }
+        if self.options.fifo_max_table_files_size > 0 {
+            return None;
         }
+        let current = self.current();
         // We prefer compactions triggered by too much data in a level over
-        // the compactions triggered by seeks
-        let mut compaction = {
-            if size_compaction {
-                let level = current.compaction_level;
-                assert!(
-                    level + 1 < self.options.max_levels as usize,
-                    "[compaction] target compaction level {} should be less Lmax {} - 1",
-                    level,
-                    self.options.max_levels as usize
-                );
-                let mut compaction = Compaction::new(self.options.clone(), level);
-                // Pick the first file that comes after compact_pointer[level]
-                for file in current.files[level].iter() {
-                    if self.compaction_pointer[level].is_empty()
+        // the compactions triggered by seeks. Levels with higher scores go first.
+        let mut levels = (0..self.options.max_levels as usize - 1)
+            .filter(|level| current.compaction_scores[*level] >= 1.0)
+            .collect::<Vec<_>>();
+        levels.sort_by(|a, b| {
+            current.compaction_scores[*b]
+                .partial_cmp(&current.compaction_scores[*a])
+                .unwrap_or(CmpOrdering::Equal)
+        });
+        for level in levels {
+            let files = &current.files[level];
+            // Pick the first file that comes after compact_pointer[level] and
+            // wrap-around to the beginning of the key space
+            let start = files
+                .iter()
+                .position(|file| {
+                    self.compaction_pointer[level].is_empty()
                         || self
                             .icmp
                             .compare(file.largest.data(), self.compaction_pointer[level].data())
                             == CmpOrdering::Greater
-                    {
-                        compaction.inputs[0].push(file.clone());
-                        break;
-                    }
-                }
-                if compaction.inputs[0].is_empty() {
-                    if let Some(file) = current.files[0].first() {
-                        // Wrap-around to the beginning of the key spac
-                        compaction.inputs[0].push(file.clone())
-                    }
+                })
+                .unwrap_or(0);
+            for i in 0..files.len() {
+                let file = &files[(start + i) % files.len()];
+                if self.being_compacted.contains(&file.number) {
+                    continue;
                 }
-                compaction
-            } else if seek_compaction {
-                let level = current.file_to_compact_level.load(Ordering::Acquire);
                 let mut compaction = Compaction::new(self.options.clone(), level);
-                compaction.inputs[0].push(file_to_compact);
-                compaction
-            } else {
-                return None;
+                compaction.inputs[0].push(file.clone());
+                if let Some(c) = self.setup_compaction(&current, compaction) {
+                    return Some(c);
+                }
             }
-        };
+        }
+        let file_to_compact = current.file_to_compact.read().unwrap().clone();
+        if let Some(file) = file_to_compact {
+            let level = current.file_to_compact_level.load(Ordering::Acquire);
+            return self.pick_file_compaction(level, file, CompactionReason::Seek);
+        }
+        None
+    }
+
+    /// Picks a compaction of the given file in `level` of the current version.
+    /// Returns `None` if the file is being compacted or the compaction conflicts
+    /// with the running ones.
+    pub fn pick_file_compaction(
+        &mut self,
+        level: usize,
+        file: Arc<FileMetaData>,
+        reason: CompactionReason,
+    ) -> Option<Compaction> {
+        if self.being_compacted.contains(&file.number) {
+            return None;
+        }
+        let current = self.current();
+        let mut compaction = Compaction::new(self.options.clone(), level);
+        compaction.reason = reason;
+        compaction.inputs[0].push(file);
+        self.setup_compaction(&current, compaction)
+    }
+
+    /// Returns true if the file is an input of a running compaction
+    #[inline]
+    pub fn is_being_compacted(&self, file_number: u64) -> bool {
+        self.being_compacted.contains(&file_number)
+    }
+
+    /// Returns true if the compaction takes a file being compacted or its
+    /// output overlaps the output of a running compaction in the same level
+    pub fn is_compaction_conflicting(&self, c: &Compaction) -> bool {
+        if c.inputs.iter().any(|files| {
+            files
+                .iter()
+                .any(|f| self.being_compacted.contains(&f.number))
+        }) {
+            return true;
+        }
+        let (smallest, largest) = c.total_range(&self.icmp);
+        self.running_compactions
+            .iter()
+            .any(|(level, running_smallest, running_largest)| {
+                *level == c.output_level()
+                    && self.ranges_overlap(
+                        (smallest.user_key(), largest.user_key()),
+                        (running_smallest, running_largest),
+                    )
+            })
+    }
+
+    /// Removes the files whose key ranges are fully contained in the user key range
+    /// `[begin, end]` from the current version, except the ones being compacted.
+    /// `None` means unbounded. Returns the number of the removed files.
+    pub fn delete_files_in_range(
+        &mut self,
+        begin: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<usize> {
+        let ucmp = self.icmp.user_comparator.clone();
+        let current = self.current();
+        let mut edit = VersionEdit::new(self.options.max_levels);
+        let mut count = 0;
+        for level in 0..self.options.max_levels as usize {
+            for file in current.get_level_files(level) {
+                let contained = begin.map_or(true, |b| {
+                    ucmp.compare(file.smallest.user_key(), b) != CmpOrdering::Less
+                }) && end.map_or(true, |e| {
+                    ucmp.compare(file.largest.user_key(), e) != CmpOrdering::Greater
+                });
+                if contained && !self.being_compacted.contains(&file.number) {
+                    edit.delete_file(level, file.number);
+                    count += 1;
+                }
+            }
+        }
+        if count > 0 {
+            self.log_and_apply(&mut edit)?;
+        }
+        Ok(count)
+    }
+
+    /// Deletes the oldest table files until the total size of the table files is
+    /// under `fifo_max_table_files_size` in the FIFO mode, and returns the deleted
+    /// files in (level, number, size).
+    ///
+    /// The files in the deeper levels hold older data, so they are deleted first.
+    /// The files in the same level are deleted from the oldest created one.
+    pub fn delete_fifo_files(&mut self) -> Result<Vec<(usize, u64, u64)>> {
+        let max_size = self.options.fifo_max_table_files_size;
+        let mut deleted = vec![];
+        if max_size == 0 {
+            return Ok(deleted);
+        }
+        let current = self.current();
+        let mut total: u64 = (0..self.options.max_levels as usize)
+            .map(|level| Self::total_file_size(current.get_level_files(level)))
+            .sum();
+        let mut edit = VersionEdit::new(self.options.max_levels);
+        for level in (0..self.options.max_levels as usize).rev() {
+            let mut files = current.get_level_files(level).to_vec();
+            files.sort_by_key(|f| f.number);
+            for file in files {
+                if total <= max_size {
+                    break;
+                }
+                if self.being_compacted.contains(&file.number) {
+                    continue;
+                }
+                edit.delete_file(level, file.number);
+                total -= file.file_size;
+                deleted.push((level, file.number, file.file_size));
+            }
+        }
+        if !deleted.is_empty() {
+            self.log_and_apply(&mut edit)?;
+        }
+        Ok(deleted)
+    }
+
+    /// Marks the inputs and the output range of the given compaction as being
+    /// compacted so that the following picked compactions will not conflict with it
+    pub fn register_compaction(&mut self, c: &Compaction) {
+        for files in c.inputs.iter() {
+            for f in files.iter() {
+                self.being_compacted.insert(f.number);
+            }
+        }
+        let (smallest, largest) = c.total_range(&self.icmp);
+        self.running_compactions.push((
+            c.output_level(),
+            smallest.user_key().to_vec(),
+            largest.user_key().to_vec(),
+        ));
+    }
+
+    /// Releases the files and the output range registered by `register_compaction`
+    pub fn release_compaction(&mut self, c: &Compaction) {
+        for files in c.inputs.iter() {
+            for f in files.iter() {
+                self.being_compacted.remove(&f.number);
+            }
+        }
+        let (smallest, largest) = c.total_range(&self.icmp);
+        if let Some(i) = self.running_compactions.iter().position(|(level, s, l)| {
+            *level == c.output_level()
+                && s.as_slice() == smallest.user_key()
+                && l.as_slice() == largest.user_key()
+        }) {
+            self.running_compactions.swap_remove(i);
+        }
+    }
+
+    /// Returns the number of the running compactions
+    #[inline]
+    pub fn running_compactions_count(&self) -> usize {
+        self.running_compactions.len()
+    }
+
+    // Expands the inputs of the given compaction and returns it if it doesn't
+    // conflict with the running compactions
+    fn setup_compaction(
+        &mut self,
+        current: &Arc<Version>,
+        mut compaction: Compaction,
+    ) -> Option<Compaction> {
         compaction.input_version = Some(current.clone());
         // Files in level 0 may overlap each other, so pick up all overlapping ones
         if compaction.level == 0 {
@@ -529,20 +872,34 @@ impl VersionSet {
                 current.get_overlapping_inputs(compaction.level, Some(smallest), Some(largest));
             assert!(!compaction.inputs[0].is_empty());
         }
+        let compaction = self.setup_other_inputs(compaction);
+        if self.is_compaction_conflicting(&compaction) {
+            None
+        } else {
+            Some(compaction)
+        }
+    }
 
-        Some(self.setup_other_inputs(compaction))
+    // Returns true if the two user key ranges overlap each other
+    fn ranges_overlap(&self, a: (&[u8], &[u8]), b: (&[u8], &[u8])) -> bool {
+        let ucmp = &self.icmp.user_comparator;
+        ucmp.compare(a.0, b.1) != CmpOrdering::Greater
+            && ucmp.compare(a.1, b.0) != CmpOrdering::Less
     }
 
     /// Persistent given memtable into a single level0 file.
+    /// If `base` is given, the file could be pushed to a higher level which
+    /// doesn't overlap it. The recovery passes `None` since the files
+    /// recovered before are not in `base` yet.
     pub fn write_level0_files<'a>(
         &mut self,
         db_name: &str,
         table_cache: Arc<TableCache>,
         mem_iter: Box<dyn Iterator + 'a>,
         edit: &mut VersionEdit,
+        base: Option<Arc<Version>>,
     ) -> Result<()> {
-        let base = self.current();
-        let now = SystemTime::now();
+        let start = self.options.clock.monotonic_micros();
         let mut meta = FileMetaData::default();
         meta.number = self.inc_next_file_number();
         info!("Level-0 table #{} : started", meta.number);
@@ -553,6 +910,21 @@ impl VersionSet {
             mem_iter,
             &mut meta,
         );
+        self.add_level0_file(db_name, &meta, build_result, start, edit, base)
+    }
+
+    /// Adds the level0 file `meta` built from a memtable since `start` into `edit`.
+    /// It's the second half of `write_level0_files` for the flushes building the
+    /// file without holding the lock of the `VersionSet`.
+    pub fn add_level0_file(
+        &mut self,
+        db_name: &str,
+        meta: &FileMetaData,
+        build_result: Result<()>,
+        start: u64,
+        edit: &mut VersionEdit,
+        base: Option<Arc<Version>>,
+    ) -> Result<()> {
         info!(
             "Level-0 table #{} : {} bytes [{:?}]",
             meta.number, meta.file_size, &build_result
@@ -562,9 +934,35 @@ impl VersionSet {
         // If `file_size` is zero, the file has been deleted and
         // should not be added to the manifest
         if build_result.is_ok() && meta.file_size > 0 {
+            let info = TableFileCreationInfo {
+                db_name: db_name.to_owned(),
+                file_number: meta.number,
+                file_size: meta.file_size,
+                reason: TableFileCreationReason::Flush,
+            };
+            for listener in self.options.listeners.iter() {
+                listener.on_table_file_created(&info);
+            }
             let smallest_ukey = Slice::from(meta.smallest.user_key());
             let largest_ukey = Slice::from(meta.largest.user_key());
-            level = base.pick_level_for_memtable_output(&smallest_ukey, &largest_ukey);
+            if let (Some(base), 0) = (base, self.options.fifo_max_table_files_size) {
+                level = base.pick_level_for_memtable_output(&smallest_ukey, &largest_ukey);
+            }
+            if level > 0
+                && self
+                    .running_compactions
+                    .iter()
+                    .any(|(_, smallest, largest)| {
+                        self.ranges_overlap(
+                            (smallest_ukey.as_slice(), largest_ukey.as_slice()),
+                            (smallest, largest),
+                        )
+                    })
+            {
+                // The running compaction might produce older entries in a level
+                // above the picked one, so keep the file in level0
+                level = 0;
+            }
             edit.add_file(
                 level,
                 meta.number,
@@ -573,25 +971,51 @@ impl VersionSet {
                 meta.largest.clone(),
             );
         }
-        self.compaction_stats[level].accumulate(
-            now.elapsed().unwrap().as_micros() as u64,
-            0,
-            meta.file_size,
-        );
+        let micros = self.options.clock.monotonic_micros() - start;
+        self.compaction_stats[level].accumulate(micros, 0, meta.file_size);
+        if let Some(stats) = &self.options.statistics {
+            stats.measure_time(HistogramType::FlushMicros, micros);
+            stats.record_tick(Ticker::FlushBytesWritten, meta.file_size);
+            stats.record_level_tick(level, LevelTicker::FlushBytesWritten, meta.file_size);
+        }
         build_result
     }
 
-    /// Add all living files in all versions into the `pending_outputs` to
-    /// prevent them to be deleted
-    #[inline]
-    pub fn lock_live_files(&mut self) {
+    /// Returns the numbers of the files referenced by the current version or
+    /// any older version still in use
+    pub fn live_files(&mut self) -> HashSet<u64> {
+        self.gc();
+        let mut live = HashSet::new();
         for version in self.versions.iter() {
             for files in version.files.iter() {
                 for f in files.iter() {
-                    self.pending_outputs.insert(f.number);
+                    live.insert(f.number);
+                }
+            }
+        }
+        live
+    }
+
+    /// Returns the (number, size) of the tables no longer in the current version
+    /// but still referenced by the older versions, e.g. held by the iterators.
+    /// They're deleted once all the older versions are released.
+    pub fn obsolete_files(&mut self) -> HashMap<u64, u64> {
+        self.gc();
+        let current = self.versions.front().unwrap();
+        let in_current: HashSet<u64> = current
+            .files
+            .iter()
+            .flat_map(|files| files.iter().map(|f| f.number))
+            .collect();
+        let mut obsolete = HashMap::new();
+        for version in self.versions.iter().skip(1) {
+            for f in version.files.iter().flat_map(|files| files.iter()) {
+                if !in_current.contains(&f.number) {
+                    obsolete.insert(f.number, f.file_size);
                 }
             }
         }
+        obsolete
     }
 
     /// Calculate the total size of given files
@@ -607,9 +1031,25 @@ impl VersionSet {
         self.pending_outputs.insert(file_number);
         let mut output = FileMetaData::default();
         output.number = file_number;
-        let file_name = generate_filename(self.db_name.as_str(), FileType::Table, file_number);
-        let file = self.options.env.create(file_name.as_str())?;
-        compact.builder = Some(TableBuilder::new(file, self.options.clone()));
+        let file_name = self
+            .options
+            .table_file_naming
+            .table_file_name(self.db_name.as_str(), file_number);
+        let file = self.options.create_table_file(file_name.as_str())?;
+        let mut builder = TableBuilder::new_with_comparator(
+            file,
+            self.options.clone(),
+            self.icmp.clone(),
+            compact.output_level(),
+        )
+        .with_file_number(file_number)
+        .with_internal_keys()
+        .with_compression_dictionary();
+        if self.options.skip_bottommost_filters && compact.is_bottommost(&self.icmp) {
+            builder = builder.without_filters();
+        }
+        compact.builder = Some(builder);
+        compact.outputs.push(output);
         Ok(())
     }
 
@@ -649,7 +1089,7 @@ impl VersionSet {
         let file_length = current_manifest.len();
         let mut builder =
             VersionBuilder::new(Version::new(self.options.clone(), self.icmp.clone()));
-        let reporter = LogReporter::new();
+        let reporter = LogReporter::new(file_name.as_str());
         let mut reader = Reader::new(current_manifest, Some(Box::new(reporter.clone())), true, 0);
         let mut buf = vec![];
 
@@ -741,9 +1181,13 @@ impl VersionSet {
         }
     }
 
-    // Remove all the old versions
+    // Remove all the old versions no longer referenced except the current one
     fn gc(&mut self) {
-        self.versions.retain(|v| Arc::strong_count(v) > 1)
+        let current = self.versions.pop_front();
+        self.versions.retain(|v| Arc::strong_count(v) > 1);
+        if let Some(v) = current {
+            self.versions.push_front(v)
+        }
     }
 
     // Create snapshot of current version and persistent to manifest file.
@@ -784,11 +1228,16 @@ impl VersionSet {
     // in `c.level + 1` but the final range of the files in `c.level` should be a
     // subset of `c.level + 1`
     fn setup_other_inputs(&mut self, c: Compaction) -> Compaction {
-        let mut c = self.add_boundary_inputs(c);
+        let c = self.add_boundary_inputs(c);
+        if c.output_level() == c.level {
+            // the files in the last level are rewritten in place without any parents
+            return c;
+        }
+        let mut c = c;
         let current = &self.current();
         // re-calculate the range
         let (smallest, mut largest) = c.base_range(&self.icmp);
-        c.inputs[0] = current.get_overlapping_inputs(
+        c.inputs[1] = current.get_overlapping_inputs(
             c.level + 1,
             Some(smallest.clone()),
             Some(largest.clone()),
@@ -797,7 +1246,7 @@ impl VersionSet {
 
         // See if we can grow the number of inputs in "level" without
         // changing the number of "level+1" files we pick up.
-        if !c.inputs[0].is_empty() {
+        if !c.inputs[1].is_empty() {
             // re-count the L(n) inputs
             // We fill the compaction 'holes' left by `add_boundary_inputs` here
             let mut expanded0 = current.get_overlapping_inputs(
@@ -814,7 +1263,7 @@ impl VersionSet {
                 && inputs1_size + expanded0_size
                     <= self.options.expanded_compaction_byte_size_limit()
             {
-                let (new_smallest, new_largest) = c.base_range(&self.icmp);
+                let (new_smallest, new_largest) = self.files_range(&expanded0);
                 // TODO: use a more sufficient way to checking expanding in L(n+1) ?
                 let expanded1 = current.get_overlapping_inputs(
                     c.level + 1,
@@ -861,6 +1310,21 @@ impl VersionSet {
         c
     }
 
+    // Returns the minimal internal key range that covers all the given files
+    fn files_range(&self, files: &[Arc<FileMetaData>]) -> (Arc<InternalKey>, Arc<InternalKey>) {
+        let mut smallest = files[0].smallest.clone();
+        let mut largest = files[0].largest.clone();
+        for f in files.iter().skip(1) {
+            if self.icmp.compare(f.smallest.data(), smallest.data()) == CmpOrdering::Less {
+                smallest = f.smallest.clone();
+            }
+            if self.icmp.compare(f.largest.data(), largest.data()) == CmpOrdering::Greater {
+                largest = f.largest.clone();
+            }
+        }
+        (smallest, largest)
+    }
+
     // A helper of 'add_boundary_input_for_compact_files' for Compaction
     fn add_boundary_inputs(&self, mut c: Compaction) -> Compaction {
         self.add_boundary_inputs_for_compact_files(c.level, &mut c.inputs[0]);
@@ -968,6 +1432,8 @@ impl VersionSet {
 pub struct FileIterFactory {
     options: Rc<ReadOptions>,
     table_cache: Arc<TableCache>,
+    // whether the iterators read the inputs of a compaction
+    compaction: bool,
 }
 
 impl FileIterFactory {
@@ -975,13 +1441,37 @@ impl FileIterFactory {
         Self {
             options,
             table_cache,
+            compaction: false,
+        }
+    }
+
+    /// Creates a factory deriving the iterators by `TableCache::new_compaction_iter`
+    pub fn new_for_compaction(options: Rc<ReadOptions>, table_cache: Arc<TableCache>) -> Self {
+        Self {
+            options,
+            table_cache,
+            compaction: true,
         }
     }
 }
 
 impl DerivedIterFactory for FileIterFactory {
+    fn seek_may_match(&self, value: &Slice, target: &Slice) -> bool {
+        if self.compaction || value.size() != FILE_META_LENGTH {
+            return true;
+        }
+        let file_number = decode_fixed_64(value.as_slice());
+        let file_size = decode_fixed_64(&value.as_slice()[8..]);
+        // the errors are left to the derived iterator
+        self.table_cache
+            .find_table(file_number, file_size)
+            .map_or(true, |table| {
+                table.seek_may_match(&self.options, target.as_slice())
+            })
+    }
+
     fn derive(&self, value: &Slice) -> Result<Box<dyn Iterator>> {
-        if value.size() != 2 * FILE_META_LENGTH {
+        if value.size() != FILE_META_LENGTH {
             Ok(Box::new(EmptyIterator::new_with_err(WickErr::new(
                 Status::Corruption,
                 Some("file reader invoked with unexpected value"),
@@ -989,9 +1479,186 @@ impl DerivedIterFactory for FileIterFactory {
         } else {
             let file_number = decode_fixed_64(value.as_slice());
             let file_size = decode_fixed_64(&value.as_slice()[8..]);
-            Ok(self
-                .table_cache
-                .new_iter(self.options.clone(), file_number, file_size))
+            if self.compaction {
+                Ok(self.table_cache.new_compaction_iter(
+                    self.options.clone(),
+                    file_number,
+                    file_size,
+                ))
+            } else {
+                Ok(self
+                    .table_cache
+                    .new_iter(self.options.clone(), file_number, file_size))
+            }
         }
     }
 }
+
+// An iterator over the tables in `level` attributing the data blocks read by
+// `inner` to the level. See `LevelTicker::UserBytesRead`.
+struct LevelReadIterator {
+    level: usize,
+    inner: Box<dyn Iterator>,
+}
+
+impl Iterator for LevelReadIterator {
+    fn valid(&self) -> bool {
+        self.inner.valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        let _scope = LevelReadScope::new(self.level);
+        self.inner.seek_to_first()
+    }
+
+    fn seek_to_last(&mut self) {
+        let _scope = LevelReadScope::new(self.level);
+        self.inner.seek_to_last()
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        let _scope = LevelReadScope::new(self.level);
+        self.inner.seek(target)
+    }
+
+    fn next(&mut self) {
+        let _scope = LevelReadScope::new(self.level);
+        self.inner.next()
+    }
+
+    fn prev(&mut self) {
+        let _scope = LevelReadScope::new(self.level);
+        self.inner.prev()
+    }
+
+    fn key(&self) -> Slice {
+        self.inner.key()
+    }
+
+    fn value(&self) -> Slice {
+        self.inner.value()
+    }
+
+    fn value_size(&self) -> usize {
+        self.inner.value_size()
+    }
+
+    fn status(&mut self) -> Result<()> {
+        self.inner.status()
+    }
+}
+
+// Encodes `key` as a JSON object of its user key, sequence and value type
+fn internal_key_json(key: &InternalKey) -> String {
+    match key.parsed() {
+        Some(parsed) => format!(
+            "{{\"user_key\":{},\"sequence\":{},\"value_type\":\"{:?}\"}}",
+            bytes_json(parsed.user_key.as_slice()),
+            parsed.seq,
+            parsed.value_type
+        ),
+        None => format!("{{\"raw\":{}}}", bytes_json(key.data())),
+    }
+}
+
+// JSON has no infinity or NaN
+fn score_json(score: f32) -> String {
+    if score.is_finite() {
+        score.to_string()
+    } else {
+        "null".to_owned()
+    }
+}
+
+// Encodes `bytes` as a JSON string mapping every byte to a code point
+fn bytes_json(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() + 2);
+    s.push('"');
+    for &b in bytes {
+        match b {
+            b'"' => s.push_str("\\\""),
+            b'\\' => s.push_str("\\\\"),
+            0x20..=0x7e => s.push(b as char),
+            _ => s.push_str(format!("\\u{:04x}", b).as_str()),
+        }
+    }
+    s.push('"');
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::format::ValueType;
+
+    fn new_file(number: u64, smallest: &str, largest: &str) -> Arc<FileMetaData> {
+        let mut f = FileMetaData::default();
+        f.number = number;
+        f.file_size = 1024;
+        f.smallest = Arc::new(InternalKey::new(
+            &Slice::from(smallest),
+            100,
+            ValueType::Value,
+        ));
+        f.largest = Arc::new(InternalKey::new(
+            &Slice::from(largest),
+            100,
+            ValueType::Value,
+        ));
+        Arc::new(f)
+    }
+
+    fn new_version_set(files: Vec<(usize, Arc<FileMetaData>)>) -> VersionSet {
+        let mut options = Options::default();
+        options.l0_compaction_threshold = 2;
+        let options = Arc::new(options);
+        let mut vset = VersionSet::new("db".to_owned(), options.clone());
+        let mut v = Version::new(options, vset.icmp.clone());
+        for (level, f) in files {
+            v.files[level].push(f);
+        }
+        v.finalize();
+        vset.versions.push_front(Arc::new(v));
+        vset
+    }
+
+    #[test]
+    fn test_pick_non_conflicting_compactions() {
+        let mut vset = new_version_set(vec![
+            (0, new_file(1, "a", "c")),
+            (0, new_file(2, "x", "z")),
+            (1, new_file(3, "b", "d")),
+        ]);
+        let c1 = vset.pick_compaction().expect("should pick a compaction");
+        let numbers = |c: &Compaction| {
+            c.inputs
+                .iter()
+                .flat_map(|files| files.iter().map(|f| f.number))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(numbers(&c1), vec![1, 3]);
+        vset.register_compaction(&c1);
+        assert!(vset.is_compaction_conflicting(&c1));
+        assert_eq!(vset.running_compactions_count(), 1);
+
+        // the other level0 file doesn't overlap the running one
+        let c2 = vset.pick_compaction().expect("should pick a compaction");
+        assert_eq!(numbers(&c2), vec![2]);
+        vset.register_compaction(&c2);
+        assert_eq!(vset.running_compactions_count(), 2);
+
+        // all the level0 files are being compacted
+        assert!(vset.pick_compaction().is_none());
+
+        // no shared input file but the output range overlaps a running compaction
+        let mut c3 = Compaction::new(vset.options.clone(), 0);
+        c3.inputs[0].push(new_file(4, "c", "e"));
+        assert!(vset.is_compaction_conflicting(&c3));
+
+        vset.release_compaction(&c1);
+        vset.release_compaction(&c2);
+        assert_eq!(vset.running_compactions_count(), 0);
+        assert!(!vset.is_compaction_conflicting(&c3));
+        assert!(vset.pick_compaction().is_some());
+    }
+}
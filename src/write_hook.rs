@@ -0,0 +1,68 @@
+// Copyright 2019 Fullstop000 <fullstop1005@gmail.com>.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The hooks invoked before a write enters the write queue, so a multi-tenant
+//! service is able to enforce the per-tenant quotas inside the engine.
+//!
+//! The keys of a write are grouped by the tenant prefixes given by
+//! `Options::key_classifier`, and every `Options::pre_write_hooks` is asked
+//! once for each tenant in the write. A write without a classifier belongs to
+//! a single tenant with an empty prefix.
+
+/// The decision made by a `PreWriteHook` on a write
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WriteDecision {
+    /// Lets the write go on
+    Accept,
+    /// Sleeps for the given micros before going on
+    Delay(u64),
+    /// Fails the write with `Status::Busy` and the given reason
+    Reject(String),
+}
+
+/// The part of a write owned by a tenant
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteInfo<'a> {
+    /// The tenant prefix of the keys
+    pub tenant: &'a [u8],
+    /// The total bytes of the keys and the values
+    pub batch_size: usize,
+    /// The number of the puts and the deletions
+    pub num_keys: usize,
+}
+
+/// Classifies a key into the tenant owning it
+pub trait KeyClassifier: Send + Sync {
+    /// Returns the length of the tenant prefix of `key`, which is at most `key.len()`
+    fn tenant_prefix_len(&self, key: &[u8]) -> usize;
+}
+
+/// A classifier taking the first bytes of the keys as the tenant prefix
+pub struct FixedPrefixClassifier(pub usize);
+
+impl KeyClassifier for FixedPrefixClassifier {
+    fn tenant_prefix_len(&self, key: &[u8]) -> usize {
+        self.0.min(key.len())
+    }
+}
+
+/// A hook deciding whether a write is allowed to go on.
+/// It's called by the writing thread before the write is queued, so a delay
+/// only blocks the write itself.
+pub trait PreWriteHook: Send + Sync {
+    /// The name of the hook
+    fn name(&self) -> &str;
+
+    /// Makes a decision on the part of a write owned by `info.tenant`
+    fn before_write(&self, info: &WriteInfo) -> WriteDecision;
+}